@@ -25,6 +25,17 @@ const PACKETS: &[&[u8]] = &[
 /// Run and instance of quilkin that sends and received data
 /// from the given address.
 fn run_quilkin(port: u16, endpoint: SocketAddr) {
+    run_quilkin_with(
+        quilkin::cli::Proxy {
+            port,
+            ..<_>::default()
+        },
+        endpoint,
+    )
+}
+
+/// Runs `proxy`, sending the data it receives to `endpoint`.
+fn run_quilkin_with(proxy: quilkin::cli::Proxy, endpoint: SocketAddr) {
     std::thread::spawn(move || {
         let runtime = tokio::runtime::Runtime::new().unwrap();
         let config = Arc::new(quilkin::Config::default());
@@ -32,11 +43,6 @@ fn run_quilkin(port: u16, endpoint: SocketAddr) {
             clusters.insert_default(vec![quilkin::endpoint::Endpoint::new(endpoint.into())])
         });
 
-        let proxy = quilkin::cli::Proxy {
-            port,
-            ..<_>::default()
-        };
-
         runtime.block_on(async move {
             let (_shutdown_tx, shutdown_rx) = tokio::sync::watch::channel::<()>(());
             proxy.run(config, shutdown_rx).await.unwrap();
@@ -240,5 +246,69 @@ fn readwrite_benchmark(c: &mut Criterion) {
     stop.store(true, atomic::Ordering::Relaxed);
 }
 
-criterion_group!(benches, readwrite_benchmark, throughput_benchmark);
+const BURST_SIZE: usize = 64;
+const BATCHED_QUILKIN_PORT: u16 = 9005;
+const UNBATCHED_QUILKIN_PORT: u16 = 9006;
+
+static BURST_SERVERS_INIT: Lazy<()> = Lazy::new(|| {
+    for (port, recv_batch_size) in [(BATCHED_QUILKIN_PORT, 32), (UNBATCHED_QUILKIN_PORT, 1)] {
+        run_quilkin_with(
+            quilkin::cli::Proxy {
+                port,
+                recv_batch_size,
+                ..<_>::default()
+            },
+            FEEDBACK_LOOP_ADDR.parse().unwrap(),
+        );
+    }
+});
+
+/// Sends bursts of packets through proxies receiving a packet per system
+/// call, and many with `recvmmsg`, which is where batching pays off.
+fn burst_benchmark(c: &mut Criterion) {
+    Lazy::force(&FEEDBACK_LOOP);
+    Lazy::force(&BURST_SERVERS_INIT);
+    // Sleep to give the servers some time to warm-up.
+    std::thread::sleep(std::time::Duration::from_millis(500));
+    let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+    socket
+        .set_read_timeout(Some(time::Duration::from_secs(1)))
+        .unwrap();
+    let mut packet = [0; MESSAGE_SIZE];
+
+    let mut group = c.benchmark_group("burst");
+    let message = PACKETS[0];
+    group.throughput(criterion::Throughput::Elements(BURST_SIZE as u64));
+    for (name, port) in [
+        ("recvmmsg", BATCHED_QUILKIN_PORT),
+        ("recv_from", UNBATCHED_QUILKIN_PORT),
+    ] {
+        let addr = (Ipv4Addr::LOCALHOST, port);
+        group.bench_function(
+            BenchmarkId::new(name, format!("{BURST_SIZE} packets")),
+            |b| {
+                b.iter(|| {
+                    for _ in 0..BURST_SIZE {
+                        socket.send_to(message, addr).unwrap();
+                    }
+                    // Packets the proxies drop under load time out rather than
+                    // failing the benchmark.
+                    for _ in 0..BURST_SIZE {
+                        if socket.recv_from(&mut packet).is_err() {
+                            break;
+                        }
+                    }
+                })
+            },
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    readwrite_benchmark,
+    throughput_benchmark,
+    burst_benchmark
+);
 criterion_main!(benches);
@@ -32,18 +32,35 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         "proto/data-plane-api/envoy/service/discovery/v3/discovery.proto",
         "proto/data-plane-api/envoy/type/metadata/v3/metadata.proto",
         "proto/data-plane-api/envoy/type/tracing/v3/custom_tag.proto",
+        "proto/quilkin/authz/v1alpha1/authz.proto",
+        "proto/quilkin/filters/block_list/v1alpha1/block_list.proto",
         "proto/quilkin/filters/capture/v1alpha1/capture.proto",
         "proto/quilkin/filters/compress/v1alpha1/compress.proto",
         "proto/quilkin/filters/concatenate_bytes/v1alpha1/concatenate_bytes.proto",
         "proto/quilkin/filters/debug/v1alpha1/debug.proto",
         "proto/quilkin/filters/drop/v1alpha1/drop.proto",
+        "proto/quilkin/filters/encrypt/v1alpha1/encrypt.proto",
+        "proto/quilkin/filters/ext_authz/v1alpha1/ext_authz.proto",
         "proto/quilkin/filters/firewall/v1alpha1/firewall.proto",
+        "proto/quilkin/filters/geolocation/v1alpha1/geolocation.proto",
+        "proto/quilkin/filters/hmac/v1alpha1/hmac.proto",
         "proto/quilkin/filters/load_balancer/v1alpha1/load_balancer.proto",
         "proto/quilkin/filters/local_rate_limit/v1alpha1/local_rate_limit.proto",
         "proto/quilkin/filters/match/v1alpha1/match.proto",
         "proto/quilkin/filters/pass/v1alpha1/pass.proto",
+        "proto/quilkin/filters/rate_limit/v1alpha1/rate_limit.proto",
+        "proto/quilkin/filters/reassemble/v1alpha1/reassemble.proto",
+        "proto/quilkin/filters/reliable_control/v1alpha1/reliable_control.proto",
+        "proto/quilkin/filters/response_validation/v1alpha1/response_validation.proto",
         "proto/quilkin/filters/token_router/v1alpha1/token_router.proto",
         "proto/quilkin/filters/timestamp/v1alpha1/timestamp.proto",
+        "proto/quilkin/filters/ttl/v1alpha1/ttl.proto",
+        "proto/quilkin/filters/wasm/v1alpha1/wasm.proto",
+        "proto/quilkin/rate_limit/v1alpha1/rate_limit.proto",
+        "proto/quilkin/registration/v1alpha1/registration.proto",
+        "proto/quilkin/sampling/v1alpha1/sampling.proto",
+        "proto/quilkin/suspicion/v1alpha1/suspicion.proto",
+        "proto/quilkin/telemetry/v1alpha1/telemetry.proto",
         "proto/udpa/xds/core/v3/resource_name.proto",
     ]
     .iter()
@@ -14,19 +14,35 @@
  *  limitations under the License.
  */
 
+mod auth;
+pub(crate) mod autoscale;
+mod bind;
 mod health;
+pub(crate) mod log_level;
+mod mitigations;
+mod profile;
 
 use std::convert::Infallible;
 use std::sync::Arc;
 
+use hyper::server::conn::AddrStream;
 use hyper::service::{make_service_fn, service_fn};
 use hyper::{Body, Method, Request, Response, Server as HyperServer, StatusCode};
 
-use self::health::Health;
+use self::{auth::Auth, health::Health};
 use crate::config::Config;
 
+pub use self::bind::{AdminError, BindPolicy};
+
 pub const PORT: u16 = 8000;
 
+/// The path under which the schema of each filter's config is served, by
+/// the filter's name.
+const FILTER_SCHEMA_PATH: &str = "/schema/filters/";
+/// The largest request body that's read, so that a request can't exhaust the
+/// memory of the process.
+const MAX_BODY_SIZE: usize = 64 * 1024;
+
 /// Define which mode Quilkin is in.
 #[derive(Copy, Clone, Debug)]
 pub enum Mode {
@@ -34,46 +50,86 @@ pub enum Mode {
     Xds,
 }
 
-pub fn server(
+/// Starts the admin server, applying `bind_policy` if its address is in use.
+/// Returns `None` if the server was disabled as a result. Requests that
+/// change state must present `token` as a bearer token, or are only accepted
+/// from the same host if it's `None`.
+pub async fn server(
     mode: Mode,
     config: Arc<Config>,
     address: Option<std::net::SocketAddr>,
-) -> tokio::task::JoinHandle<Result<(), hyper::Error>> {
+    profiling: bool,
+    bind_policy: BindPolicy,
+    token: Option<String>,
+) -> Result<Option<tokio::task::JoinHandle<Result<(), hyper::Error>>>, AdminError> {
     let address = address.unwrap_or_else(|| (std::net::Ipv6Addr::UNSPECIFIED, PORT).into());
+    let Some(listener) = bind::bind(address, bind_policy).await? else {
+        return Ok(None);
+    };
+    let address = listener.local_addr().unwrap_or(address);
+    let builder = HyperServer::from_tcp(listener)?;
     let health = Health::new();
+    let auth = Auth::new(token);
     tracing::info!(address = %address, "Starting admin endpoint");
 
-    let make_svc = make_service_fn(move |_conn| {
+    let make_svc = make_service_fn(move |conn: &AddrStream| {
+        let remote = conn.remote_addr();
         let config = config.clone();
         let health = health.clone();
+        let auth = auth.clone();
         async move {
-            let config = config.clone();
-            let health = health.clone();
             Ok::<_, Infallible>(service_fn(move |req| {
                 let config = config.clone();
                 let health = health.clone();
-                async move { Ok::<_, Infallible>(handle_request(req, mode, config, health)) }
+                let authorized = auth.authorize(&req, remote);
+                async move {
+                    Ok::<_, Infallible>(match authorized {
+                        Ok(()) => handle_request(req, mode, config, health, profiling).await,
+                        Err(response) => response,
+                    })
+                }
             }))
         }
     });
 
-    tokio::spawn(HyperServer::bind(&address).serve(make_svc))
+    Ok(Some(tokio::spawn(builder.serve(make_svc))))
 }
 
-fn handle_request(
+async fn handle_request(
     request: Request<Body>,
     mode: Mode,
     config: Arc<Config>,
     health: Health,
+    profiling: bool,
 ) -> Response<Body> {
     match (request.method(), request.uri().path()) {
-        (&Method::GET, "/metrics") => collect_metrics(),
+        (&Method::GET, "/metrics") => collect_metrics(&config),
+        (&Method::GET, "/autoscale") => autoscale::response(),
+        (&Method::GET, profile::PATH) if profiling => profile::response(request.uri()).await,
         (&Method::GET, "/live" | "/livez") => health.check_healthy(),
         (&Method::GET, "/ready" | "/readyz") => match mode {
             Mode::Proxy => check_proxy_readiness(&config),
             Mode::Xds => health.check_healthy(),
         },
-        (&Method::GET, "/config") => match serde_json::to_string(&config) {
+        (&Method::GET, "/filters") => filter_reloads(&config),
+        (&Method::GET, "/schema/config") => json_response(&schemars::schema_for!(Config)),
+        (&Method::GET, path) if path.starts_with(FILTER_SCHEMA_PATH) => {
+            filter_schema(&path[FILTER_SCHEMA_PATH.len()..])
+        }
+        (&Method::POST, "/sessions/prewarm") => prewarm_session(request).await,
+        (&Method::POST, "/drain") => drain(),
+        (&Method::DELETE, "/drain") => cancel_drain(),
+        (&Method::GET, log_level::PATH) => log_level::get(),
+        (&Method::PUT, log_level::PATH) => log_level::put(request).await,
+        (&Method::GET, mitigations::PATH) => mitigations::get(&config),
+        (&Method::POST, mitigations::PATH) => mitigations::post(&config, request).await,
+        (&Method::DELETE, mitigations::PATH) => mitigations::delete(&config, request).await,
+        (&Method::GET, "/sessions/failure-domains") => {
+            json_response(&crate::proxy::failure_domain::sessions())
+        }
+        (&Method::GET, crate::proxy::decisions::PATH) => decisions(request.uri()),
+        (&Method::GET, "/xds") => json_response(&crate::xds::status::get()),
+        (&Method::GET, "/config") => match config_dump(&config) {
             Ok(body) => Response::builder()
                 .status(StatusCode::OK)
                 .header(
@@ -95,8 +151,39 @@ fn handle_request(
     }
 }
 
+/// Returns `config` as JSON, with the secrets of its filters redacted.
+fn config_dump(config: &Config) -> serde_json::Result<String> {
+    let mut dump = serde_json::to_value(config)?;
+    if let Some(filters) = dump.get_mut("filters").and_then(|f| f.as_array_mut()) {
+        for filter in filters {
+            redact_secrets(filter);
+        }
+    }
+    serde_json::to_string(&dump)
+}
+
+/// Replaces the secret fields of a serialized [`crate::config::Filter`]'s
+/// config, as listed by its factory.
+fn redact_secrets(filter: &mut serde_json::Value) {
+    let Some(factory) = filter
+        .get("name")
+        .and_then(|name| name.as_str())
+        .and_then(crate::filters::FilterRegistry::get_factory)
+    else {
+        return;
+    };
+    let Some(config) = filter.get_mut("config").and_then(|c| c.as_object_mut()) else {
+        return;
+    };
+    for field in factory.secret_fields() {
+        if let Some(value) = config.get_mut(*field) {
+            *value = serde_json::Value::String("<redacted>".into());
+        }
+    }
+}
+
 fn check_proxy_readiness(config: &Config) -> Response<Body> {
-    if config.clusters.load().endpoints().count() > 0 {
+    if !crate::proxy::drain::is_draining() && config.clusters.load().endpoints().count() > 0 {
         return Response::new("ok".into());
     }
 
@@ -105,18 +192,200 @@ fn check_proxy_readiness(config: &Config) -> Response<Body> {
     response
 }
 
-fn collect_metrics() -> Response<Body> {
+/// Returns the outcome of the latest updates to each filter's config as JSON.
+fn filter_reloads(config: &Config) -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::OK)
+        .header(
+            "Content-Type",
+            hyper::header::HeaderValue::from_static("application/json"),
+        )
+        .body(Body::from(
+            serde_json::to_string(&config.filter_reloads.snapshot()).unwrap(),
+        ))
+        .unwrap()
+}
+
+/// Starts draining the proxy, which stops once it's drained.
+fn drain() -> Response<Body> {
+    let (status, body) = match crate::proxy::drain::request() {
+        Ok(true) => (StatusCode::ACCEPTED, "draining".into()),
+        Ok(false) => (StatusCode::ACCEPTED, "already draining".into()),
+        Err(error) => (StatusCode::NOT_IMPLEMENTED, error.to_string()),
+    };
+
+    Response::builder()
+        .status(status)
+        .body(Body::from(body))
+        .unwrap()
+}
+
+/// Cancels a drain started with `POST /drain`.
+fn cancel_drain() -> Response<Body> {
+    let (status, body) = match crate::proxy::drain::cancel() {
+        Ok(()) => (StatusCode::OK, "drain cancelled".into()),
+        Err(error @ crate::proxy::drain::DrainError::Disabled) => {
+            (StatusCode::NOT_IMPLEMENTED, error.to_string())
+        }
+        Err(error) => (StatusCode::CONFLICT, error.to_string()),
+    };
+
+    Response::builder()
+        .status(status)
+        .body(Body::from(body))
+        .unwrap()
+}
+
+/// Returns the recorded decisions of the filter chain, only those on the
+/// packets of the client in the `addr` query parameter if it's set.
+fn decisions(uri: &hyper::Uri) -> Response<Body> {
+    if !crate::proxy::decisions::is_enabled() {
+        return Response::builder()
+            .status(StatusCode::NOT_IMPLEMENTED)
+            .body(Body::from(
+                "decisions aren't recorded, see `--decision-log-size`",
+            ))
+            .unwrap();
+    }
+
+    let client = url::form_urlencoded::parse(uri.query().unwrap_or_default().as_bytes())
+        .find(|(key, _)| key == "addr")
+        .map(|(_, value)| value.into_owned());
+    json_response(&crate::proxy::decisions::get(client.as_deref()))
+}
+
+/// Returns the JSON schema of the config of the filter named `name`.
+fn filter_schema(name: &str) -> Response<Body> {
+    match crate::filters::FilterRegistry::get_factory(name) {
+        Some(factory) => json_response(&factory.config_schema()),
+        None => Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(Body::from(format!("no filter named `{name}`")))
+            .unwrap(),
+    }
+}
+
+/// Reads the body of `request`, returning the response rejecting it if it
+/// can't be read or is over [`MAX_BODY_SIZE`].
+pub(crate) async fn read_body(request: Request<Body>) -> Result<Vec<u8>, Response<Body>> {
+    use hyper::body::HttpBody;
+
+    let error = |status, body: String| {
+        Response::builder()
+            .status(status)
+            .body(Body::from(body))
+            .unwrap()
+    };
+    let too_large = || {
+        error(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            format!("the request body must be at most {MAX_BODY_SIZE} bytes"),
+        )
+    };
+
+    let mut body = request.into_body();
+    if body
+        .size_hint()
+        .upper()
+        .map_or(false, |size| size > MAX_BODY_SIZE as u64)
+    {
+        return Err(too_large());
+    }
+
+    let mut bytes = Vec::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(|e| error(StatusCode::BAD_REQUEST, e.to_string()))?;
+        if bytes.len() + chunk.len() > MAX_BODY_SIZE {
+            return Err(too_large());
+        }
+        bytes.extend_from_slice(&chunk);
+    }
+
+    Ok(bytes)
+}
+
+fn json_response(value: &impl serde::Serialize) -> Response<Body> {
+    match serde_json::to_string(value) {
+        Ok(body) => Response::builder()
+            .status(StatusCode::OK)
+            .header(
+                "Content-Type",
+                hyper::header::HeaderValue::from_static("application/json"),
+            )
+            .body(Body::from(body))
+            .unwrap(),
+        Err(error) => Response::builder()
+            .status(StatusCode::INTERNAL_SERVER_ERROR)
+            .body(Body::from(format!("failed to serialize response: {error}")))
+            .unwrap(),
+    }
+}
+
+/// A session for a player that's about to connect, see
+/// [`crate::proxy::prewarm`].
+#[derive(serde::Deserialize)]
+struct PrewarmRequest {
+    /// The player's token, base64 encoded.
+    token: String,
+    endpoint: crate::endpoint::EndpointAddress,
+}
+
+/// Pre-establishes the session in the request's JSON body.
+async fn prewarm_session(request: Request<Body>) -> Response<Body> {
+    use crate::proxy::prewarm::{self, PrewarmError};
+
+    let response = |status, body: String| {
+        Response::builder()
+            .status(status)
+            .body(Body::from(body))
+            .unwrap()
+    };
+
+    let body = match read_body(request).await {
+        Ok(body) => body,
+        Err(response) => return response,
+    };
+    let request =
+        serde_json::from_slice::<PrewarmRequest>(&body).map_err(|error| error.to_string());
+    let token = request.and_then(|request| {
+        base64::decode(&request.token)
+            .map(|token| (token, request.endpoint))
+            .map_err(|error| format!("invalid token: {error}"))
+    });
+    let (token, endpoint) = match token {
+        Ok(request) => request,
+        Err(error) => return response(StatusCode::BAD_REQUEST, error),
+    };
+
+    match prewarm::prewarm(token, endpoint).await {
+        Ok(()) => response(StatusCode::CREATED, String::new()),
+        Err(error) => response(
+            match error {
+                PrewarmError::Disabled => StatusCode::NOT_IMPLEMENTED,
+                PrewarmError::UnknownEndpoint(_) | PrewarmError::NotRouted(_) => {
+                    StatusCode::NOT_FOUND
+                }
+                PrewarmError::Full(_) => StatusCode::TOO_MANY_REQUESTS,
+                PrewarmError::Connect(_) => StatusCode::BAD_GATEWAY,
+            },
+            error.to_string(),
+        ),
+    }
+}
+
+fn collect_metrics(config: &Config) -> Response<Body> {
     let mut response = Response::new(Body::empty());
     let mut buffer = vec![];
     let encoder = prometheus::TextEncoder::new();
-    let body =
-        prometheus::Encoder::encode(&encoder, &crate::metrics::registry().gather(), &mut buffer)
-            .map_err(|error| tracing::warn!(%error, "Failed to encode metrics"))
-            .and_then(|_| {
-                String::from_utf8(buffer)
-                    .map(Body::from)
-                    .map_err(|error| tracing::warn!(%error, "Failed to convert metrics to utf8"))
-            });
+    let filters = config.filters.load();
+    let metrics = crate::metrics::gather(&[filters.registry()]);
+    let body = prometheus::Encoder::encode(&encoder, &metrics, &mut buffer)
+        .map_err(|error| tracing::warn!(%error, "Failed to encode metrics"))
+        .and_then(|_| {
+            String::from_utf8(buffer)
+                .map(Body::from)
+                .map_err(|error| tracing::warn!(%error, "Failed to convert metrics to utf8"))
+        });
 
     match body {
         Ok(body) => {
@@ -138,7 +407,7 @@ mod tests {
 
     #[tokio::test]
     async fn collect_metrics() {
-        let response = super::collect_metrics();
+        let response = super::collect_metrics(&Config::default());
         assert_eq!(response.status(), hyper::StatusCode::OK);
     }
 
@@ -158,4 +427,49 @@ mod tests {
         let response = super::check_proxy_readiness(&config);
         assert_eq!(response.status(), StatusCode::OK);
     }
+
+    #[cfg(feature = "filter-encrypt")]
+    #[tokio::test]
+    async fn redacts_secrets() {
+        use crate::filters::{encrypt, StaticFilter};
+
+        let config = Config::default();
+        config.filters.store(Arc::new(
+            crate::filters::FilterChain::try_from(vec![crate::config::Filter {
+                name: encrypt::Encrypt::NAME.into(),
+                config: Some(
+                    serde_json::to_value(encrypt::Config::new([[1; encrypt::KEY_LEN]])).unwrap(),
+                ),
+            }])
+            .unwrap(),
+        ));
+
+        let dump: serde_json::Value = serde_json::from_str(&config_dump(&config).unwrap()).unwrap();
+        let filter = &dump["filters"][0]["config"];
+        assert_eq!(filter["keys"], "<redacted>");
+        assert_eq!(filter["on_read"], "DECRYPT");
+        // The config itself keeps the keys.
+        assert!(serde_json::to_string(&config).unwrap().contains("AQEB"));
+    }
+
+    #[tokio::test]
+    async fn schemas() {
+        use crate::filters::StaticFilter;
+
+        let body = |response: Response<Body>| async {
+            let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+            serde_json::from_slice::<serde_json::Value>(&body).unwrap()
+        };
+
+        let config = body(json_response(&schemars::schema_for!(Config))).await;
+        assert!(config["properties"]["clusters"].is_object());
+
+        crate::test_utils::load_test_filters();
+        let response = filter_schema(crate::test_utils::TestFilter::NAME);
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(body(response).await.is_object());
+
+        let response = filter_schema("not.found");
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
 }
@@ -0,0 +1,182 @@
+/*
+ * Copyright 2023 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Authorizes the admin server's requests that change the state of the
+//! process, so that only operators can block prefixes, drain the proxy or
+//! change its log level, while probes and metrics stay open to everyone who
+//! can reach the port.
+
+use std::net::{IpAddr, SocketAddr};
+
+use hyper::{header, Body, Method, Request, Response, StatusCode};
+
+use crate::utils::constant_time_eq;
+
+const BEARER_PREFIX: &str = "Bearer ";
+
+/// Who may send requests that change state, every request other than `GET`
+/// and `HEAD`: clients with the bearer `token` if it's set, and otherwise
+/// only clients on the same host.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Auth {
+    token: Option<String>,
+}
+
+impl Auth {
+    pub(crate) fn new(token: Option<String>) -> Self {
+        Self { token }
+    }
+
+    /// Checks that `request`, sent from `remote`, is allowed, returning the
+    /// response rejecting it if it isn't.
+    pub(crate) fn authorize(
+        &self,
+        request: &Request<Body>,
+        remote: SocketAddr,
+    ) -> Result<(), Response<Body>> {
+        if matches!(*request.method(), Method::GET | Method::HEAD) {
+            return Ok(());
+        }
+
+        let Some(token) = &self.token else {
+            if is_loopback(remote.ip()) {
+                return Ok(());
+            }
+
+            tracing::warn!(%remote, method = %request.method(), path = request.uri().path(), "rejected admin request from another host");
+            return Err(response(
+                StatusCode::FORBIDDEN,
+                "only requests from this host can change state without `--admin-token`",
+            ));
+        };
+
+        let presented = request
+            .headers()
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix(BEARER_PREFIX));
+        match presented {
+            Some(presented) if constant_time_eq(presented.as_bytes(), token.as_bytes()) => Ok(()),
+            _ => {
+                tracing::warn!(%remote, method = %request.method(), path = request.uri().path(), "rejected unauthenticated admin request");
+                let mut response = response(StatusCode::UNAUTHORIZED, "admin token required");
+                response.headers_mut().insert(
+                    header::WWW_AUTHENTICATE,
+                    header::HeaderValue::from_static("Bearer"),
+                );
+                Err(response)
+            }
+        }
+    }
+}
+
+/// Whether `ip` is a loopback address, including IPv4 loopback addresses
+/// mapped to IPv6, as dual-stack sockets receive them.
+fn is_loopback(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => ip.is_loopback(),
+        IpAddr::V6(ip) => {
+            ip.is_loopback() || ip.to_ipv4_mapped().map_or(false, |ip| ip.is_loopback())
+        }
+    }
+}
+
+fn response(status: StatusCode, body: &'static str) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .body(Body::from(body))
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(method: Method, token: Option<&str>) -> Request<Body> {
+        let mut request = Request::builder().method(method).uri("/mitigations");
+        if let Some(token) = token {
+            request = request.header(header::AUTHORIZATION, format!("Bearer {token}"));
+        }
+        request.body(Body::empty()).unwrap()
+    }
+
+    #[test]
+    fn authorize() {
+        let local: SocketAddr = "[::ffff:127.0.0.1]:1234".parse().unwrap();
+        let remote: SocketAddr = "203.0.113.7:1234".parse().unwrap();
+
+        let open = Auth::default();
+        assert!(open.authorize(&request(Method::GET, None), remote).is_ok());
+        assert!(open.authorize(&request(Method::POST, None), local).is_ok());
+        assert_eq!(
+            StatusCode::FORBIDDEN,
+            open.authorize(&request(Method::POST, None), remote)
+                .unwrap_err()
+                .status()
+        );
+
+        let auth = Auth::new(Some("secret".into()));
+        assert!(auth.authorize(&request(Method::GET, None), remote).is_ok());
+        assert!(auth
+            .authorize(&request(Method::DELETE, Some("secret")), remote)
+            .is_ok());
+        // Pre-establishing sessions opens upstream sockets, so it's only
+        // allowed to operators.
+        let prewarm = || {
+            Request::builder()
+                .method(Method::POST)
+                .uri("/sessions/prewarm")
+                .body(Body::from(
+                    r#"{"token": "YWJj", "endpoint": "10.0.0.7:7001"}"#,
+                ))
+                .unwrap()
+        };
+        assert!(open.authorize(&prewarm(), local).is_ok());
+        assert_eq!(
+            StatusCode::FORBIDDEN,
+            open.authorize(&prewarm(), remote).unwrap_err().status()
+        );
+        assert_eq!(
+            StatusCode::UNAUTHORIZED,
+            auth.authorize(&prewarm(), remote).unwrap_err().status()
+        );
+
+        // Changing the log level could fill the disk, or hide activity.
+        let log_level = |method| {
+            Request::builder()
+                .method(method)
+                .uri("/log-level")
+                .body(Body::from("trace"))
+                .unwrap()
+        };
+        assert!(open.authorize(&log_level(Method::GET), remote).is_ok());
+        assert_eq!(
+            StatusCode::FORBIDDEN,
+            open.authorize(&log_level(Method::PUT), remote)
+                .unwrap_err()
+                .status()
+        );
+
+        for token in [None, Some("wrong"), Some("secrets")] {
+            assert_eq!(
+                StatusCode::UNAUTHORIZED,
+                auth.authorize(&request(Method::POST, token), local)
+                    .unwrap_err()
+                    .status()
+            );
+        }
+    }
+}
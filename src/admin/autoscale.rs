@@ -0,0 +1,286 @@
+/*
+ * Copyright 2023 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! A smoothed utilisation score of the proxy, for autoscalers such as the
+//! Kubernetes HPA or KEDA to scale fleets of proxies on their load rather than
+//! only on their CPU usage.
+//!
+//! The score is the highest ratio between a measurement (active sessions,
+//! packets per second, CPU) and its configured target, so `1.0` means that
+//! the busiest resource is exactly at its target, and an autoscaler targeting
+//! `1.0` scales the fleet proportionally to it.
+
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use arc_swap::ArcSwapOption;
+use hyper::{Body, Response, StatusCode};
+use once_cell::sync::Lazy;
+use serde::Serialize;
+
+use crate::{utils::idle::IdleBackoff, xds::telemetry::Snapshot};
+
+/// How often the proxy is measured.
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(5);
+/// The unit of the CPU times in `/proc`, which is fixed by the kernel ABI.
+#[cfg(target_os = "linux")]
+const CLOCK_TICKS_PER_SECOND: f64 = 100.0;
+
+static LATEST: Lazy<ArcSwapOption<Recommendation>> = Lazy::new(<_>::default);
+
+static RECOMMENDATION: Lazy<prometheus::Gauge> = Lazy::new(|| {
+    prometheus::register_gauge_with_registry! {
+        prometheus::opts! {
+            "autoscale_recommendation",
+            "Smoothed utilisation of the proxy relative to its autoscaling targets, where 1 is at target",
+        },
+        crate::metrics::registry(),
+    }
+    .unwrap()
+});
+
+/// The load a single proxy is meant to handle. Measurements without a target
+/// don't contribute to the recommendation.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub(crate) struct Targets {
+    pub sessions: Option<u64>,
+    pub packets_per_second: Option<u64>,
+    /// The fraction of the machine's CPUs in use, between `0` and `1`.
+    pub cpu: Option<f64>,
+    /// How long it takes the recommendation to move about two thirds of the
+    /// way towards a sudden change in load.
+    pub smoothing: Duration,
+}
+
+impl Targets {
+    pub(crate) fn is_empty(&self) -> bool {
+        self.sessions.is_none() && self.packets_per_second.is_none() && self.cpu.is_none()
+    }
+
+    /// The unsmoothed utilisation of `sample`.
+    fn utilisation(&self, sample: &Sample) -> f64 {
+        let ratio = |value: f64, target: f64| (target > 0.0).then(|| value / target);
+
+        [
+            self.sessions
+                .and_then(|target| ratio(sample.sessions as f64, target as f64)),
+            self.packets_per_second
+                .and_then(|target| ratio(sample.packets_per_second, target as f64)),
+            self.cpu
+                .zip(sample.cpu)
+                .and_then(|(target, cpu)| ratio(cpu, target)),
+        ]
+        .into_iter()
+        .flatten()
+        .fold(0.0, f64::max)
+    }
+
+    /// The weight of a new sample taken `interval` after the last one.
+    fn smoothing_factor(&self, interval: Duration) -> f64 {
+        if self.smoothing.is_zero() {
+            return 1.0;
+        }
+
+        1.0 - (-interval.as_secs_f64() / self.smoothing.as_secs_f64()).exp()
+    }
+}
+
+/// The measurements of the proxy over a sample interval.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize)]
+struct Sample {
+    sessions: u64,
+    packets_per_second: f64,
+    /// `None` where the CPU usage of the process isn't available.
+    cpu: Option<f64>,
+}
+
+/// The latest recommendation and the sample it was last updated with.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize)]
+struct Recommendation {
+    recommendation: f64,
+    #[serde(flatten)]
+    sample: Sample,
+}
+
+/// Measures the proxy every few seconds, updating the recommendation, until
+/// dropped.
+pub(crate) struct Autoscaler(tokio::task::JoinHandle<()>);
+
+impl Autoscaler {
+    /// Spawns the autoscaler, which measures the proxy up to
+    /// `max_idle_interval` apart while it has no sessions or packets.
+    pub(crate) fn spawn(targets: Targets, max_idle_interval: Duration) -> Self {
+        Self(tokio::spawn(async move {
+            let mut backoff = IdleBackoff::new(SAMPLE_INTERVAL, max_idle_interval);
+            let mut previous = Measurement::now();
+            let mut smoothed = None;
+            loop {
+                backoff.sleep().await;
+                let current = Measurement::now();
+                let sample = current.sample(&previous);
+                let utilisation = targets.utilisation(&sample);
+                let recommendation = match smoothed {
+                    None => utilisation,
+                    Some(smoothed) => {
+                        let factor = targets.smoothing_factor(current.at - previous.at);
+                        smoothed + factor * (utilisation - smoothed)
+                    }
+                };
+
+                smoothed = Some(recommendation);
+                RECOMMENDATION.set(recommendation);
+                LATEST.store(Some(Arc::new(Recommendation {
+                    recommendation,
+                    sample,
+                })));
+                previous = current;
+                backoff.next(sample.sessions == 0 && sample.packets_per_second == 0.0);
+            }
+        }))
+    }
+}
+
+impl Drop for Autoscaler {
+    fn drop(&mut self) {
+        self.0.abort();
+        LATEST.store(None);
+    }
+}
+
+/// The counters a [`Sample`] is the difference of.
+struct Measurement {
+    at: Instant,
+    snapshot: Snapshot,
+    cpu_seconds: Option<f64>,
+}
+
+impl Measurement {
+    fn now() -> Self {
+        Self {
+            at: Instant::now(),
+            snapshot: Snapshot::gather(),
+            cpu_seconds: cpu_seconds(),
+        }
+    }
+
+    fn sample(&self, previous: &Self) -> Sample {
+        let elapsed = (self.at - previous.at).as_secs_f64();
+        if elapsed <= 0.0 {
+            return Sample::default();
+        }
+
+        Sample {
+            sessions: self.snapshot.active_sessions(),
+            packets_per_second: self
+                .snapshot
+                .packets()
+                .saturating_sub(previous.snapshot.packets()) as f64
+                / elapsed,
+            cpu: self
+                .cpu_seconds
+                .zip(previous.cpu_seconds)
+                .map(|(current, previous)| (current - previous) / elapsed / num_cpus::get() as f64),
+        }
+    }
+}
+
+/// The CPU time used by the process so far, in seconds.
+#[cfg(target_os = "linux")]
+pub(crate) fn cpu_seconds() -> Option<f64> {
+    let stat = std::fs::read_to_string("/proc/self/stat").ok()?;
+    // The command name may contain spaces, so fields are counted from the
+    // end of it. `utime` and `stime` are the 14th and 15th fields.
+    let mut fields = stat.rsplit_once(')')?.1.split_whitespace().skip(11);
+    let user = fields.next()?.parse::<f64>().ok()?;
+    let system = fields.next()?.parse::<f64>().ok()?;
+
+    Some((user + system) / CLOCK_TICKS_PER_SECOND)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn cpu_seconds() -> Option<f64> {
+    None
+}
+
+/// Returns the latest recommendation and the measurements behind it as JSON.
+pub(crate) fn response() -> Response<Body> {
+    let Some(latest) = LATEST.load_full() else {
+        return Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(Body::from("no autoscaling targets are configured"))
+            .unwrap();
+    };
+
+    Response::builder()
+        .status(StatusCode::OK)
+        .header(
+            "Content-Type",
+            hyper::header::HeaderValue::from_static("application/json"),
+        )
+        .body(Body::from(serde_json::to_string(&*latest).unwrap()))
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn utilisation() {
+        let targets = Targets {
+            sessions: Some(100),
+            packets_per_second: Some(10_000),
+            cpu: None,
+            smoothing: Duration::from_secs(60),
+        };
+        let sample = Sample {
+            sessions: 50,
+            packets_per_second: 15_000.0,
+            cpu: Some(0.9),
+        };
+
+        assert_eq!(1.5, targets.utilisation(&sample));
+        assert_eq!(
+            0.9 / 0.8,
+            Targets {
+                cpu: Some(0.8),
+                ..Targets::default()
+            }
+            .utilisation(&sample)
+        );
+        assert_eq!(0.0, Targets::default().utilisation(&sample));
+    }
+
+    #[test]
+    fn smoothing_factor() {
+        let targets = Targets {
+            smoothing: Duration::from_secs(60),
+            ..<_>::default()
+        };
+
+        let factor = targets.smoothing_factor(Duration::from_secs(60));
+        assert!((0.63..0.64).contains(&factor), "{factor}");
+        assert_eq!(1.0, Targets::default().smoothing_factor(SAMPLE_INTERVAL));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn cpu_seconds() {
+        assert!(super::cpu_seconds().unwrap() >= 0.0);
+    }
+}
@@ -0,0 +1,150 @@
+/*
+ * Copyright 2023 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Binds the admin server's address, with a policy for when it's already in
+//! use, as happens when several proxies run on the same host.
+
+use std::{
+    io,
+    net::{SocketAddr, TcpListener},
+    time::Duration,
+};
+
+/// How many times binding is attempted with [`BindPolicy::Retry`].
+const RETRIES: u32 = 5;
+/// The delay before the first retry, doubled for each one after it.
+const RETRY_BACKOFF: Duration = Duration::from_millis(100);
+/// How many of the following ports are tried with [`BindPolicy::NextPort`].
+const NEXT_PORTS: u16 = 64;
+
+/// What to do when the admin server's address is already in use.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum BindPolicy {
+    /// Fails startup.
+    #[default]
+    Fail,
+    /// Tries again with an exponential backoff, for addresses that are
+    /// about to be released, such as by a proxy that's being replaced.
+    Retry,
+    /// Serves on the next free port instead, logging which one.
+    NextPort,
+    /// Runs without the admin server.
+    Disable,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum AdminError {
+    #[error("the admin address {address} is already in use, see `--admin-bind-policy`")]
+    AddressInUse { address: SocketAddr },
+    #[error("failed to bind the admin server to {address}: {source}")]
+    Bind {
+        address: SocketAddr,
+        source: io::Error,
+    },
+    #[error("failed to start the admin server: {0}")]
+    Server(#[from] hyper::Error),
+}
+
+/// Binds `address`, applying `policy` if it's in use. Returns `None` when
+/// the admin server is disabled instead.
+pub(super) async fn bind(
+    address: SocketAddr,
+    policy: BindPolicy,
+) -> Result<Option<TcpListener>, AdminError> {
+    let error = match try_bind(address)? {
+        Some(listener) => return Ok(Some(listener)),
+        None => AdminError::AddressInUse { address },
+    };
+
+    match policy {
+        BindPolicy::Fail => Err(error),
+        BindPolicy::Disable => {
+            tracing::warn!(%address, "admin address in use, running without the admin server");
+            Ok(None)
+        }
+        BindPolicy::Retry => {
+            let mut backoff = RETRY_BACKOFF;
+            for retry in 1..=RETRIES {
+                tracing::warn!(%address, retry, ?backoff, "admin address in use, retrying");
+                tokio::time::sleep(backoff).await;
+                if let Some(listener) = try_bind(address)? {
+                    return Ok(Some(listener));
+                }
+                backoff *= 2;
+            }
+            Err(error)
+        }
+        BindPolicy::NextPort => {
+            for offset in 1..=NEXT_PORTS {
+                let Some(port) = address.port().checked_add(offset) else {
+                    break;
+                };
+                let next = SocketAddr::new(address.ip(), port);
+                if let Some(listener) = try_bind(next)? {
+                    tracing::warn!(%address, %next, "admin address in use, serving on the next free port");
+                    return Ok(Some(listener));
+                }
+            }
+            Err(error)
+        }
+    }
+}
+
+/// Binds `address`, returning `None` if it's in use.
+fn try_bind(address: SocketAddr) -> Result<Option<TcpListener>, AdminError> {
+    match TcpListener::bind(address) {
+        Ok(listener) => Ok(Some(listener)),
+        Err(error) if error.kind() == io::ErrorKind::AddrInUse => Ok(None),
+        Err(source) => Err(AdminError::Bind { address, source }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn bind() {
+        let taken = TcpListener::bind((std::net::Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let address = taken.local_addr().unwrap();
+
+        assert!(matches!(
+            super::bind(address, BindPolicy::Fail).await,
+            Err(AdminError::AddressInUse { .. })
+        ));
+        assert!(matches!(
+            super::bind(address, BindPolicy::Retry).await,
+            Err(AdminError::AddressInUse { .. })
+        ));
+        assert!(super::bind(address, BindPolicy::Disable)
+            .await
+            .unwrap()
+            .is_none());
+
+        let next = super::bind(address, BindPolicy::NextPort)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_ne!(address, next.local_addr().unwrap());
+
+        drop(taken);
+        let listener = super::bind(address, BindPolicy::Fail)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(address, listener.local_addr().unwrap());
+    }
+}
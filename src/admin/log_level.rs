@@ -0,0 +1,124 @@
+/*
+ * Copyright 2023 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Changes which events are logged while the process runs, so that debug
+//! logging can be turned on for a misbehaving proxy without restarting it
+//! and losing the issue.
+
+use hyper::{Body, Request, Response, StatusCode};
+use once_cell::sync::OnceCell;
+use tracing_subscriber::{reload, EnvFilter, Registry};
+
+/// The path the log filter is read and changed at.
+pub(crate) const PATH: &str = "/log-level";
+
+/// The handle changing the filter of the logs, set when they're enabled.
+static HANDLE: OnceCell<Handle> = OnceCell::new();
+
+pub(crate) type Handle = reload::Handle<EnvFilter, Registry>;
+
+/// Sets the handle changing the filter of the logs.
+pub(crate) fn set_handle(handle: Handle) {
+    if HANDLE.set(handle).is_err() {
+        tracing::warn!("the log filter can only be changed through the first handle set");
+    }
+}
+
+/// Returns the current filter of the logs.
+pub(crate) fn get() -> Response<Body> {
+    match HANDLE.get().map(current) {
+        Some(Ok(filter)) => response(StatusCode::OK, filter),
+        Some(Err(error)) => response(StatusCode::INTERNAL_SERVER_ERROR, error),
+        None => disabled(),
+    }
+}
+
+/// Replaces the filter of the logs with the directives in the body of
+/// `request`, such as `quilkin=debug,quilkin::proxy=trace`.
+pub(crate) async fn put(request: Request<Body>) -> Response<Body> {
+    let Some(handle) = HANDLE.get() else {
+        return disabled();
+    };
+
+    let body = match super::read_body(request).await {
+        Ok(body) => body,
+        Err(response) => return response,
+    };
+    let directives = match std::str::from_utf8(&body) {
+        Ok(directives) => directives.trim(),
+        Err(error) => return response(StatusCode::BAD_REQUEST, error.to_string()),
+    };
+
+    match set(handle, directives) {
+        Ok(filter) => {
+            tracing::info!(%filter, "changed the log filter");
+            response(StatusCode::OK, filter)
+        }
+        Err(error) => response(StatusCode::BAD_REQUEST, error),
+    }
+}
+
+/// Replaces the filter of `handle` with `directives`, returning the new
+/// filter.
+fn set(handle: &Handle, directives: &str) -> Result<String, String> {
+    if directives.is_empty() {
+        return Err("the body must hold the directives of the new filter".into());
+    }
+
+    let filter =
+        EnvFilter::try_new(directives).map_err(|error| format!("invalid filter: {error}"))?;
+    handle.reload(filter).map_err(|error| error.to_string())?;
+    current(handle)
+}
+
+fn current(handle: &Handle) -> Result<String, String> {
+    handle
+        .with_current(ToString::to_string)
+        .map_err(|error| error.to_string())
+}
+
+fn disabled() -> Response<Body> {
+    response(
+        StatusCode::NOT_IMPLEMENTED,
+        "logging is disabled, see `--quiet`".into(),
+    )
+}
+
+fn response(status: StatusCode, body: String) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .body(Body::from(body))
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn set() {
+        let (_filter, handle) = reload::Layer::<_, Registry>::new(EnvFilter::new("info"));
+        assert_eq!("info", current(&handle).unwrap());
+
+        let changed = super::set(&handle, "quilkin=debug,quilkin::proxy=trace").unwrap();
+        assert!(changed.contains("quilkin=debug"), "{changed}");
+        assert!(changed.contains("quilkin::proxy=trace"), "{changed}");
+
+        assert!(super::set(&handle, "quilkin=notalevel").is_err());
+        assert!(super::set(&handle, "").is_err());
+        assert_eq!(changed, current(&handle).unwrap());
+    }
+}
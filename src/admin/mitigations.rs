@@ -0,0 +1,216 @@
+/*
+ * Copyright 2023 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Lets external DDoS detection and scrubbing systems push the source
+//! prefixes to block or rate limit, and lift them once an attack is over.
+
+use hyper::{Body, Request, Response, StatusCode};
+use ipnetwork::IpNetwork;
+use serde::{Deserialize, Serialize};
+
+use crate::{filters::mitigation::Mitigation, Config};
+
+/// The path mitigations are listed, pushed and lifted at.
+pub(crate) const PATH: &str = "/mitigations";
+
+#[derive(Deserialize)]
+struct Push {
+    mitigations: Vec<Mitigation>,
+}
+
+#[derive(Deserialize)]
+struct Lift {
+    prefixes: Vec<IpNetwork>,
+}
+
+/// An active mitigation, and whether the filter chain has a filter that
+/// applies it.
+#[derive(Serialize)]
+struct Listed {
+    #[serde(flatten)]
+    mitigation: Mitigation,
+    enforced: bool,
+}
+
+/// Returns the active mitigations.
+pub(crate) fn get(config: &Config) -> Response<Body> {
+    let listed: Vec<_> = config
+        .mitigations
+        .list()
+        .into_iter()
+        .map(|mitigation| Listed {
+            enforced: is_enforced(config, &mitigation),
+            mitigation,
+        })
+        .collect();
+    super::json_response(&listed)
+}
+
+/// Applies the mitigations in the request's JSON body, replacing those of
+/// the same prefixes. They're rejected if the filter chain has no filter to
+/// apply one of them.
+pub(crate) async fn post(config: &Config, request: Request<Body>) -> Response<Body> {
+    let push = match body::<Push>(request).await {
+        Ok(push) => push,
+        Err(response) => return response,
+    };
+
+    if let Some(mitigation) = push
+        .mitigations
+        .iter()
+        .find(|mitigation| !is_enforced(config, mitigation))
+    {
+        return response(
+            StatusCode::CONFLICT,
+            format!(
+                "the mitigation of `{}` wouldn't be applied, the filter chain has no `{}` filter",
+                mitigation.prefix,
+                mitigation.action.filter()
+            ),
+        );
+    }
+
+    let count = push.mitigations.len();
+    match config.mitigations.apply(push.mitigations) {
+        Ok(()) => {
+            tracing::info!(count, "applied mitigations");
+            get(config)
+        }
+        Err(error) => response(StatusCode::BAD_REQUEST, error.to_string()),
+    }
+}
+
+/// Lifts the mitigations of the prefixes in the request's JSON body.
+pub(crate) async fn delete(config: &Config, request: Request<Body>) -> Response<Body> {
+    let lift = match body::<Lift>(request).await {
+        Ok(lift) => lift,
+        Err(response) => return response,
+    };
+
+    config.mitigations.remove(&lift.prefixes);
+    tracing::info!(prefixes = ?lift.prefixes, "lifted mitigations");
+    get(config)
+}
+
+/// Whether the filter chain has a filter applying `mitigation`.
+fn is_enforced(config: &Config, mitigation: &Mitigation) -> bool {
+    let name = mitigation.action.filter();
+    config
+        .filters
+        .load()
+        .iter()
+        .any(|filter| filter.name == name)
+}
+
+async fn body<T: serde::de::DeserializeOwned>(request: Request<Body>) -> Result<T, Response<Body>> {
+    let body = super::read_body(request).await?;
+    serde_json::from_slice(&body)
+        .map_err(|error| response(StatusCode::BAD_REQUEST, error.to_string()))
+}
+
+fn response(status: StatusCode, body: String) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .body(Body::from(body))
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> Config {
+        Config::from_reader(
+            "
+filters:
+  - name: quilkin.filters.firewall.v1alpha1.Firewall
+    config:
+      on_read: []
+      on_write: []
+"
+            .as_bytes(),
+        )
+        .unwrap()
+    }
+
+    async fn send(config: &Config, method: hyper::Method, body: &str) -> (StatusCode, String) {
+        let request = Request::builder()
+            .method(method.clone())
+            .uri(PATH)
+            .body(Body::from(body.to_owned()))
+            .unwrap();
+        let response = match method {
+            hyper::Method::POST => post(config, request).await,
+            _ => delete(config, request).await,
+        };
+        let status = response.status();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        (status, String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    #[tokio::test]
+    async fn push_and_lift() {
+        let config = config();
+        let (status, body) = send(
+            &config,
+            hyper::Method::POST,
+            r#"{"mitigations": [{"prefix": "2001:db8::/48", "action": "block", "ttl_secs": 60}]}"#,
+        )
+        .await;
+        assert_eq!(StatusCode::OK, status);
+        assert!(body.contains(r#""prefix":"2001:db8::/48""#), "{body}");
+        assert!(body.contains(r#""enforced":true"#), "{body}");
+
+        let (status, _) = send(
+            &config,
+            hyper::Method::POST,
+            r#"{"mitigations": [{"prefix": "2001:db8::/48", "action": "block", "ttl_secs": 0}]}"#,
+        )
+        .await;
+        assert_eq!(StatusCode::BAD_REQUEST, status);
+        let (status, _) = send(&config, hyper::Method::POST, r#"{"mitigations": [{}]}"#).await;
+        assert_eq!(StatusCode::BAD_REQUEST, status);
+
+        // Without a `RateLimit` filter, rate limits would do nothing.
+        let (status, body) = send(
+            &config,
+            hyper::Method::POST,
+            r#"{"mitigations": [{"prefix": "198.51.100.0/24", "action": "rate_limit", "packets_per_second": 1, "ttl_secs": 60}]}"#,
+        )
+        .await;
+        assert_eq!(StatusCode::CONFLICT, status);
+        assert!(body.contains("RateLimit"), "{body}");
+
+        let (status, body) = send(
+            &config,
+            hyper::Method::DELETE,
+            r#"{"prefixes": ["2001:db8::/48"]}"#,
+        )
+        .await;
+        assert_eq!(StatusCode::OK, status);
+        assert_eq!("[]", body);
+    }
+
+    #[tokio::test]
+    async fn body_size() {
+        let body = format!(
+            r#"{{"prefixes": [{}"2001:db8::/48"]}}"#,
+            r#""2001:db8::/48","#.repeat(super::super::MAX_BODY_SIZE / 16)
+        );
+        let (status, _) = send(&config(), hyper::Method::DELETE, &body).await;
+        assert_eq!(StatusCode::PAYLOAD_TOO_LARGE, status);
+    }
+}
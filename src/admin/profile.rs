@@ -0,0 +1,136 @@
+/*
+ * Copyright 2023 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! On-demand CPU profiles of the process in the [pprof] format, so hotspots
+//! in production proxies can be found without attaching external tools.
+//!
+//! [pprof]: https://github.com/google/pprof
+
+use std::time::Duration;
+
+use hyper::{Body, Response, StatusCode, Uri};
+
+/// The path profiles are served at, matching Go's `net/http/pprof`.
+pub(crate) const PATH: &str = "/debug/pprof/profile";
+
+const DEFAULT_SECONDS: u64 = 30;
+const MAX_SECONDS: u64 = 300;
+/// How many times a second the process is sampled. Not a multiple of a
+/// common timer frequency, to avoid sampling in lockstep with periodic work.
+#[cfg(target_os = "linux")]
+const FREQUENCY: i32 = 99;
+
+/// Parses the length of the profile from the `seconds` query parameter.
+fn duration(uri: &Uri) -> Result<Duration, String> {
+    let seconds = url::form_urlencoded::parse(uri.query().unwrap_or_default().as_bytes())
+        .find(|(key, _)| key == "seconds")
+        .map(|(_, value)| {
+            value
+                .parse::<u64>()
+                .map_err(|error| format!("invalid `seconds`: {error}"))
+        })
+        .transpose()?
+        .unwrap_or(DEFAULT_SECONDS);
+
+    if !(1..=MAX_SECONDS).contains(&seconds) {
+        return Err(format!("`seconds` must be between 1 and {MAX_SECONDS}"));
+    }
+
+    Ok(Duration::from_secs(seconds))
+}
+
+fn error(status: StatusCode, message: impl Into<Body>) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .body(message.into())
+        .unwrap()
+}
+
+/// Samples the process for the duration in `uri`, returning the profile as
+/// an uncompressed pprof protobuf.
+#[cfg(target_os = "linux")]
+pub(crate) async fn response(uri: &Uri) -> Response<Body> {
+    use pprof::protos::Message;
+
+    let duration = match duration(uri) {
+        Ok(duration) => duration,
+        Err(reason) => return error(StatusCode::BAD_REQUEST, reason),
+    };
+
+    // Only one profiler can run at a time, so concurrent requests fail here.
+    let guard = match pprof::ProfilerGuardBuilder::default()
+        .frequency(FREQUENCY)
+        .blocklist(&["libc", "libgcc", "pthread", "vdso"])
+        .build()
+    {
+        Ok(guard) => guard,
+        Err(err) => {
+            return error(
+                StatusCode::CONFLICT,
+                format!("failed to start profiler: {err}"),
+            )
+        }
+    };
+
+    tracing::info!(?duration, "started CPU profile");
+    tokio::time::sleep(duration).await;
+
+    match guard.report().build().and_then(|report| report.pprof()) {
+        Ok(profile) => Response::builder()
+            .status(StatusCode::OK)
+            .header(
+                "Content-Type",
+                hyper::header::HeaderValue::from_static("application/octet-stream"),
+            )
+            .body(Body::from(profile.encode_to_vec()))
+            .unwrap(),
+        Err(err) => error(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("failed to build profile: {err}"),
+        ),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) async fn response(uri: &Uri) -> Response<Body> {
+    if let Err(reason) = duration(uri) {
+        return error(StatusCode::BAD_REQUEST, reason);
+    }
+
+    error(
+        StatusCode::NOT_IMPLEMENTED,
+        "CPU profiles are only available on Linux",
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn duration() {
+        let duration = |uri: &str| super::duration(&uri.parse().unwrap());
+
+        assert_eq!(Ok(Duration::from_secs(30)), duration(PATH));
+        assert_eq!(
+            Ok(Duration::from_secs(5)),
+            duration("/debug/pprof/profile?seconds=5")
+        );
+        assert!(duration("/debug/pprof/profile?seconds=0").is_err());
+        assert!(duration("/debug/pprof/profile?seconds=301").is_err());
+        assert!(duration("/debug/pprof/profile?seconds=soon").is_err());
+    }
+}
@@ -25,17 +25,27 @@ use tokio::{signal, sync::watch};
 use crate::{admin::Mode, Config};
 
 pub use self::{
+    fmt_config::FmtConfig,
     generate_config_schema::GenerateConfigSchema,
     manage::{Manage, Providers},
     proxy::Proxy,
+    run::Run,
+    sessions::Sessions,
 };
 
+pub mod fmt_config;
 pub mod generate_config_schema;
 pub mod manage;
+#[cfg(feature = "opentelemetry")]
+pub mod otlp;
 pub mod proxy;
+pub mod run;
+pub mod sessions;
 
 const ETC_CONFIG_PATH: &str = "/etc/quilkin/quilkin.yaml";
 const PORT_ENV_VAR: &str = "QUILKIN_PORT";
+/// The default multiple counts are rounded down to with `--private-metrics`.
+const PRIVATE_METRICS_BUCKET: u64 = 10;
 
 /// The Command-Line Interface for Quilkin.
 #[derive(clap::Parser)]
@@ -51,9 +61,61 @@ pub struct Cli {
     /// The port to bind for the admin server
     #[clap(long, env = "QUILKIN_ADMIN_ADDRESS")]
     pub admin_address: Option<std::net::SocketAddr>,
+    /// What to do when the admin server's address is already in use:
+    /// `fail` startup, `retry` with a backoff, serve on the `next-port` that's
+    /// free, or `disable` the admin server.
+    #[clap(
+        long,
+        env = "QUILKIN_ADMIN_BIND_POLICY",
+        value_enum,
+        default_value_t = crate::admin::BindPolicy::Fail
+    )]
+    pub admin_bind_policy: crate::admin::BindPolicy,
+    /// Whether the admin server serves CPU profiles of the process at
+    /// `/debug/pprof/profile`.
+    #[clap(long, env = "QUILKIN_ADMIN_PROFILING")]
+    pub admin_profiling: bool,
+    /// A bearer token the admin server's requests that change state, such
+    /// as pushing mitigations or draining, must present. Without one, they're
+    /// only accepted from the same host.
+    #[clap(long, env = "QUILKIN_ADMIN_TOKEN", hide_env_values = true)]
+    pub admin_token: Option<String>,
+    /// A directory to persist the clusters and filters to on every change,
+    /// which are restored from it on startup in place of the configuration
+    /// file's, so that endpoints registered at runtime survive restarts.
+    #[clap(long, env = "QUILKIN_STATE_DIR")]
+    pub state_dir: Option<PathBuf>,
+    /// Only exports aggregated metrics, without labels identifying clients
+    /// and with counts rounded down to multiples of
+    /// `private_metrics_bucket`, for jurisdictions with strict telemetry
+    /// rules.
+    #[clap(long, env = "QUILKIN_PRIVATE_METRICS")]
+    pub private_metrics: bool,
+    /// The multiple counts are rounded down to with `private_metrics`.
+    #[clap(
+        long,
+        env = "QUILKIN_PRIVATE_METRICS_BUCKET",
+        default_value_t = PRIVATE_METRICS_BUCKET,
+        requires("private_metrics")
+    )]
+    pub private_metrics_bucket: u64,
+    /// How the addresses of IPv4 clients are represented in sessions,
+    /// cluster lookups and metrics, as `ipv4` addresses or as `ipv6-mapped`
+    /// addresses (`::ffff:a.b.c.d`), so that a client isn't counted twice
+    /// when its packets arrive in both, as on dual-stack sockets.
+    #[clap(
+        long,
+        env = "QUILKIN_IPV4_REPRESENTATION",
+        value_enum,
+        default_value_t = crate::endpoint::Ipv4Representation::Ipv4
+    )]
+    pub ipv4_representation: crate::endpoint::Ipv4Representation,
     /// Whether Quilkin will report any results to stdout/stderr.
     #[clap(short, long, env)]
     pub quiet: bool,
+    #[cfg(feature = "opentelemetry")]
+    #[command(flatten)]
+    pub otlp: otlp::Otlp,
     #[clap(subcommand)]
     pub command: Commands,
 }
@@ -64,14 +126,20 @@ pub enum Commands {
     Proxy(Proxy),
     GenerateConfigSchema(GenerateConfigSchema),
     Manage(Manage),
+    Run(Run),
+    Sessions(Sessions),
+    FmtConfig(FmtConfig),
 }
 
 impl Commands {
-    pub fn admin_mode(&self) -> Option<Mode> {
+    /// The admin server to run for the command, if any. `config` is only
+    /// used by `run`, where it depends on the roles being run.
+    pub fn admin_mode(&self, config: &Config) -> Option<Mode> {
         match self {
             Self::Proxy(_) => Some(Mode::Proxy),
             Self::Manage(_) => Some(Mode::Xds),
-            Self::GenerateConfigSchema(_) => None,
+            Self::Run(_) => Run::admin_mode(config),
+            Self::GenerateConfigSchema(_) | Self::Sessions(_) | Self::FmtConfig(_) => None,
         }
     }
 }
@@ -81,16 +149,7 @@ impl Cli {
     /// arguments.
     #[tracing::instrument(skip_all)]
     pub async fn drive(self) -> crate::Result<()> {
-        if !self.quiet {
-            let env_filter = tracing_subscriber::EnvFilter::builder()
-                .with_default_directive(tracing_subscriber::filter::LevelFilter::INFO.into())
-                .from_env_lossy();
-            tracing_subscriber::fmt()
-                .json()
-                .with_file(true)
-                .with_env_filter(env_filter)
-                .init();
-        }
+        self.init_tracing()?;
 
         tracing::info!(
             version = crate_version!(),
@@ -98,18 +157,35 @@ impl Cli {
             "Starting Quilkin"
         );
 
-        let config = Arc::new(Self::read_config(self.config)?);
-        let _admin_task = self
-            .command
-            .admin_mode()
-            .filter(|_| !self.no_admin)
-            .map(|mode| {
-                tokio::spawn(crate::admin::server(
+        crate::metrics::set_aggregation_only(
+            self.private_metrics.then_some(self.private_metrics_bucket),
+        );
+        crate::endpoint::set_ipv4_representation(self.ipv4_representation);
+
+        let (config, config_path) = Self::read_config(self.config)?;
+        let config = Arc::new(config);
+        if let Some(state_dir) = &self.state_dir {
+            tracing::info!(path = %state_dir.display(), "Persisting state");
+            config.persist(state_dir)?;
+        }
+        let _admin_task = match self.command.admin_mode(&config).filter(|_| !self.no_admin) {
+            Some(mode) => {
+                crate::admin::server(
                     mode,
                     config.clone(),
                     self.admin_address,
-                ))
-            });
+                    self.admin_profiling,
+                    self.admin_bind_policy,
+                    self.admin_token.clone(),
+                )
+                .await?
+            }
+            None => None,
+        };
+
+        let _watch_task = config_path
+            .filter(|_| matches!(&self.command, Commands::Proxy(proxy) if proxy.watches_config()))
+            .map(|path| tokio::spawn(config.clone().watch_file(path)));
 
         let (shutdown_tx, mut shutdown_rx) = watch::channel::<()>(());
 
@@ -133,6 +209,9 @@ impl Cli {
             shutdown_tx.send(()).ok();
         });
 
+        // These commands stop their own tasks on shutdown, so they're waited
+        // for rather than dropped.
+        let stops_on_shutdown = matches!(self.command, Commands::Proxy(_) | Commands::Run(_));
         let fut = tryhard::retry_fn({
             let shutdown_rx = shutdown_rx.clone();
             move || match self.command.clone() {
@@ -147,9 +226,16 @@ impl Cli {
                     let config = config.clone();
                     tokio::spawn(async move { manager.manage(config.clone()).await })
                 }
+                Commands::Run(runner) => {
+                    let config = config.clone();
+                    let shutdown_rx = shutdown_rx.clone();
+                    tokio::spawn(async move { runner.run(config, shutdown_rx).await })
+                }
                 Commands::GenerateConfigSchema(generator) => {
                     tokio::spawn(std::future::ready(generator.generate_config_schema()))
                 }
+                Commands::Sessions(sessions) => tokio::spawn(std::future::ready(sessions.run())),
+                Commands::FmtConfig(fmt) => tokio::spawn(std::future::ready(fmt.run())),
             }
         })
         .retries(3)
@@ -160,29 +246,67 @@ impl Cli {
             }
         });
 
-        tokio::select! {
-            result = fut => result?,
-            _ = shutdown_rx.changed() => Ok(())
-        }
+        let result = if stops_on_shutdown {
+            fut.await?
+        } else {
+            tokio::select! {
+                result = fut => result?,
+                _ = shutdown_rx.changed() => Ok(())
+            }
+        };
+
+        #[cfg(feature = "opentelemetry")]
+        otlp::shutdown().await;
+        result
+    }
+
+    /// Logs to stdout unless `quiet`, and exports spans over OTLP if it's
+    /// configured.
+    fn init_tracing(&self) -> crate::Result<()> {
+        use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, Layer};
+
+        let fmt = (!self.quiet).then(|| {
+            let env_filter = tracing_subscriber::EnvFilter::builder()
+                .with_default_directive(tracing_subscriber::filter::LevelFilter::INFO.into())
+                .from_env_lossy();
+            // The filter can be changed at runtime through the admin server.
+            let (env_filter, handle) = tracing_subscriber::reload::Layer::new(env_filter);
+            crate::admin::log_level::set_handle(handle);
+            tracing_subscriber::fmt::layer()
+                .json()
+                .with_file(true)
+                .with_filter(env_filter)
+        });
+
+        let registry = tracing_subscriber::registry().with(fmt);
+        #[cfg(feature = "opentelemetry")]
+        let registry = registry.with(self.otlp.layer()?);
+        registry.init();
+        Ok(())
     }
 
-    /// Searches for the configuration file, and panics if not found.
-    fn read_config<A: AsRef<Path>>(path: A) -> Result<Config, eyre::Error> {
+    /// Searches for the configuration file, returning it along with the
+    /// path it was read from, or the default configuration if there's none.
+    fn read_config<A: AsRef<Path>>(path: A) -> Result<(Config, Option<PathBuf>), eyre::Error> {
         let path = path.as_ref();
-        let from_reader = |file| Config::from_reader(file).map_err(From::from);
+        let from_reader = |file: std::fs::File, path: &Path| -> crate::Result<_> {
+            let config = Config::from_reader(file)?;
+            config.validate_metadata()?;
+            Ok((config, Some(path.to_owned())))
+        };
 
         match std::fs::File::open(path) {
-            Ok(file) => (from_reader)(file),
+            Ok(file) => (from_reader)(file, path),
             Err(error) if error.kind() == std::io::ErrorKind::NotFound => {
                 tracing::debug!(path=%path.display(), "provided path not found");
                 match cfg!(unix).then(|| std::fs::File::open(ETC_CONFIG_PATH)) {
-                    Some(Ok(file)) => (from_reader)(file),
+                    Some(Ok(file)) => (from_reader)(file, Path::new(ETC_CONFIG_PATH)),
                     Some(Err(error)) if error.kind() == std::io::ErrorKind::NotFound => {
                         tracing::debug!(path=%path.display(), "/etc path not found");
-                        Ok(Config::default())
+                        Ok((Config::default(), None))
                     }
                     Some(Err(error)) => Err(error.into()),
-                    None => Ok(Config::default()),
+                    None => Ok((Config::default(), None)),
                 }
             }
             Err(error) => Err(error.into()),
@@ -0,0 +1,191 @@
+/*
+ * Copyright 2023 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::path::PathBuf;
+
+use serde_json::Value;
+
+use crate::Config;
+
+/// Rewrites configuration files in a canonical form, with sorted clusters,
+/// localities and endpoints, and without fields set to their defaults, so
+/// that diffs of them only show meaningful changes.
+#[derive(clap::Args, Clone)]
+pub struct FmtConfig {
+    /// The configuration files to format.
+    #[clap(num_args = 1.., required = true)]
+    pub files: Vec<PathBuf>,
+    /// Fails if any of the files isn't in canonical form, rather than
+    /// rewriting them.
+    #[clap(long)]
+    pub check: bool,
+}
+
+impl FmtConfig {
+    pub fn run(&self) -> crate::Result<()> {
+        let mut unformatted = Vec::new();
+        for path in &self.files {
+            let input = std::fs::read_to_string(path)?;
+            let canonical = canonicalize(&input)
+                .map_err(|error| eyre::eyre!("failed to format {}: {error}", path.display()))?;
+
+            if canonical == input {
+                continue;
+            }
+
+            if self.check {
+                unformatted.push(path.display().to_string());
+            } else {
+                std::fs::write(path, canonical)?;
+                tracing::info!(path = %path.display(), "formatted configuration file");
+            }
+        }
+
+        if !unformatted.is_empty() {
+            return Err(eyre::eyre!(
+                "configuration files aren't in canonical form: {}",
+                unformatted.join(", ")
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+/// Returns the canonical form of the configuration in `input`.
+///
+/// Top level fields are kept when they're in `input` and aren't set to their
+/// default, except for `id`, whose default depends on the machine.
+pub fn canonicalize(input: &str) -> crate::Result<String> {
+    let config = Config::from_reader(input.as_bytes())?;
+    let present: serde_json::Map<String, Value> = serde_yaml::from_str(input)?;
+    let defaults = serde_json::to_value(Config::default())?;
+    let default_metadata = serde_json::to_value(crate::metadata::MetadataView::<
+        crate::endpoint::Metadata,
+    >::default())?;
+
+    let mut value = serde_json::to_value(&config)?;
+    if let Value::Object(fields) = &mut value {
+        fields.retain(|key, value| {
+            present.contains_key(key) && (key == "id" || defaults.get(key) != Some(value))
+        });
+
+        let localities = fields
+            .get_mut("clusters")
+            .and_then(Value::as_object_mut)
+            .into_iter()
+            .flat_map(|clusters| clusters.values_mut())
+            .filter_map(|cluster| cluster.get_mut("localities"))
+            .filter_map(Value::as_array_mut)
+            .flatten()
+            .filter_map(Value::as_object_mut);
+
+        for locality in localities {
+            if locality.get("locality").map_or(false, Value::is_null) {
+                locality.remove("locality");
+            }
+
+            let endpoints = locality
+                .get_mut("endpoints")
+                .and_then(Value::as_array_mut)
+                .into_iter()
+                .flatten()
+                .filter_map(Value::as_object_mut);
+
+            for endpoint in endpoints {
+                if endpoint.get("metadata") == Some(&default_metadata) {
+                    endpoint.remove("metadata");
+                }
+            }
+        }
+    }
+
+    sort_keys(&mut value);
+    Ok(serde_yaml::to_string(&value)?)
+}
+
+fn sort_keys(value: &mut Value) {
+    match value {
+        Value::Object(map) => {
+            let mut entries = std::mem::take(map).into_iter().collect::<Vec<_>>();
+            entries.sort_by(|a, b| a.0.cmp(&b.0));
+            for (_, value) in &mut entries {
+                sort_keys(value);
+            }
+
+            *map = entries.into_iter().collect();
+        }
+        Value::Array(values) => values.iter_mut().for_each(sort_keys),
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn canonicalize() {
+        let input = "
+version: v1alpha1
+filters: []
+clusters:
+  b:
+    localities:
+      - locality: { region: us-east-1 }
+        endpoints:
+          - address: 127.0.0.2:7777
+      - endpoints:
+          - address: 127.0.0.3:7777
+          - address: 127.0.0.1:7777
+            metadata:
+              quilkin.dev:
+                tokens: [MXg3aWp5Ng==]
+  a:
+    localities:
+      - endpoints:
+          - address: 127.0.0.1:7000
+";
+
+        let output = super::canonicalize(input).unwrap();
+        assert_eq!(output, super::canonicalize(&output).unwrap());
+
+        assert!(!output.contains("version"), "{output}");
+        assert!(!output.contains("filters"), "{output}");
+        assert!(!output.contains("id:"), "{output}");
+        assert!(!output.contains("locality: null"), "{output}");
+        assert_eq!(1, output.matches("metadata").count(), "{output}");
+        assert!(output.find("  a:").unwrap() < output.find("  b:").unwrap());
+        assert!(output.find("127.0.0.1:7777").unwrap() < output.find("127.0.0.3:7777").unwrap());
+
+        assert_eq!(
+            *Config::from_reader(input.as_bytes())
+                .unwrap()
+                .clusters
+                .load(),
+            *Config::from_reader(output.as_bytes())
+                .unwrap()
+                .clusters
+                .load(),
+        );
+    }
+
+    #[test]
+    fn keeps_id() {
+        let output = super::canonicalize("id: proxy-1\n").unwrap();
+        assert_eq!("id: proxy-1\n", output);
+    }
+}
@@ -35,6 +35,61 @@ pub struct Manage {
     /// for any provider endpoints discovered.
     #[clap(long, env = "QUILKIN_SUB_ZONE")]
     sub_zone: Option<String>,
+    /// The path to a role based access control configuration, when set
+    /// clients must present a bearer token belonging to one of its roles,
+    /// and are only served the resources their role allows.
+    #[clap(long, env = "QUILKIN_RBAC")]
+    rbac: Option<std::path::PathBuf>,
+    /// One or more other `quilkin manage` servers to peer with, exchanging
+    /// the endpoints registered by each server's own proxies. Endpoints are
+    /// exchanged in both directions, so a pair of servers only needs to be
+    /// peered on one side.
+    #[clap(long, env = "QUILKIN_PEERS", value_delimiter = ',')]
+    peer: Vec<tonic::transport::Endpoint>,
+    /// The path of a PEM bundle of the certificate authorities the peers'
+    /// certificates are verified with, in addition to the system's.
+    #[clap(long, env = "QUILKIN_PEER_CA_CERTIFICATE", requires("peer"))]
+    peer_ca_certificate: Option<std::path::PathBuf>,
+    /// The path of a PEM certificate to present to the peers, for mutual TLS.
+    #[clap(
+        long,
+        env = "QUILKIN_PEER_CLIENT_CERTIFICATE",
+        requires("peer"),
+        requires("peer_client_key")
+    )]
+    peer_client_certificate: Option<std::path::PathBuf>,
+    /// The path of the PEM private key of `peer_client_certificate`.
+    #[clap(
+        long,
+        env = "QUILKIN_PEER_CLIENT_KEY",
+        requires("peer_client_certificate")
+    )]
+    peer_client_key: Option<std::path::PathBuf>,
+    /// The name to verify the peers' certificates against and send with SNI,
+    /// rather than the host of their URL.
+    #[clap(long, env = "QUILKIN_PEER_SERVER_NAME", requires("peer"))]
+    peer_server_name: Option<String>,
+    /// A bearer token to send to the peers, which must belong to a role that
+    /// is allowed to peer in their `--rbac`, and in this server's if it has
+    /// one.
+    #[clap(
+        long,
+        env = "QUILKIN_PEER_TOKEN",
+        hide_env_values = true,
+        requires("peer")
+    )]
+    peer_token: Option<String>,
+    /// How many seconds the endpoints registered by a proxy stay registered
+    /// after it disconnects, so that they survive the proxy reconnecting, or
+    /// this server restarting with a `--registration-store`. They're removed
+    /// as soon as the proxy disconnects if `0`.
+    #[clap(long, env = "QUILKIN_REGISTRATION_TTL_SECS", default_value_t = 0)]
+    registration_ttl_secs: u64,
+    /// The path of a database to persist the endpoints registered by proxies
+    /// in, which are restored on startup unless they've expired.
+    #[cfg(feature = "registration-sled")]
+    #[clap(long, env = "QUILKIN_REGISTRATION_STORE")]
+    registration_store: Option<std::path::PathBuf>,
     /// The configuration source for a management server.
     #[clap(subcommand)]
     pub provider: Providers,
@@ -70,6 +125,53 @@ impl Manage {
                 sub_zone: self.sub_zone.clone().unwrap_or_default(),
             });
 
+        let mut control_plane =
+            crate::xds::ControlPlane::from_arc(config.clone()).with_locality(locality.clone());
+        if let Some(path) = &self.rbac {
+            let rbac = crate::xds::rbac::Rbac::from_reader(std::fs::File::open(path)?)?;
+            tracing::info!(
+                roles = rbac.roles.len(),
+                "enabling role based access control"
+            );
+            control_plane = control_plane.with_rbac(rbac);
+        }
+
+        #[cfg(feature = "registration-sled")]
+        let store: std::sync::Arc<dyn crate::xds::registration::Store> =
+            match &self.registration_store {
+                Some(path) => std::sync::Arc::new(crate::xds::registration::Sled::open(path)?),
+                None => <std::sync::Arc<crate::xds::registration::Memory>>::default(),
+            };
+        #[cfg(not(feature = "registration-sled"))]
+        let store: std::sync::Arc<dyn crate::xds::registration::Store> =
+            <std::sync::Arc<crate::xds::registration::Memory>>::default();
+        let registrations = crate::xds::registration::Registrations::new(
+            store,
+            std::time::Duration::from_secs(self.registration_ttl_secs),
+        );
+        let restored = registrations.restore(&config)?;
+        if restored > 0 {
+            tracing::info!(restored, "restored registered endpoints from store");
+        }
+        let _expiry = registrations.spawn_expiry(config.clone());
+        control_plane = control_plane.with_registrations(registrations);
+
+        let peer_auth = crate::xds::ClientAuth {
+            ca_certificate: self.peer_ca_certificate.clone(),
+            client_certificate: self.peer_client_certificate.clone(),
+            client_key: self.peer_client_key.clone(),
+            server_name: self.peer_server_name.clone(),
+            token: self.peer_token.clone(),
+        };
+        let _peers = self
+            .peer
+            .iter()
+            .map(|endpoint| {
+                tracing::info!(uri = %endpoint.uri(), "peering with management server");
+                control_plane.peer_with(endpoint.clone(), &peer_auth)
+            })
+            .collect::<crate::Result<Vec<_>>>()?;
+
         if let Some(locality) = &locality {
             config
                 .clusters
@@ -108,7 +210,7 @@ impl Manage {
         };
 
         tokio::select! {
-            result = crate::xds::server::spawn(self.port, config) => result,
+            result = crate::xds::server::serve(self.port, control_plane) => result,
             result = provider_task => result.map_err(From::from).and_then(|result| result),
         }
     }
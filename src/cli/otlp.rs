@@ -0,0 +1,134 @@
+/*
+ * Copyright 2023 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Exports the proxy's spans to an OpenTelemetry collector over OTLP, so
+//! that its xDS updates, sessions and filter chains can be correlated with
+//! the traces of game servers.
+
+use clap::crate_version;
+use opentelemetry::{
+    sdk::{
+        trace::{self, Sampler},
+        Resource,
+    },
+    KeyValue,
+};
+use opentelemetry_otlp::WithExportConfig;
+use tracing_subscriber::{registry::LookupSpan, EnvFilter, Layer};
+
+const SAMPLING_RATIO: f64 = 1.0;
+const FILTER: &str = "info";
+
+/// Where and which spans are exported over OTLP.
+#[derive(clap::Args, Clone, Debug)]
+pub struct Otlp {
+    /// The OTLP gRPC endpoint of the collector to export spans to, such as
+    /// `http://localhost:4317`. No spans are exported if unset.
+    #[clap(long = "otlp-endpoint", env = "QUILKIN_OTLP_ENDPOINT")]
+    pub endpoint: Option<String>,
+    /// The fraction of traces exported, from `0` to `1`. Spans whose parent
+    /// was sampled are always exported.
+    #[clap(
+        long = "otlp-sampling-ratio",
+        env = "QUILKIN_OTLP_SAMPLING_RATIO",
+        default_value_t = SAMPLING_RATIO
+    )]
+    pub sampling_ratio: f64,
+    /// Which spans are exported, with the same syntax as `RUST_LOG`. The
+    /// spans of filter chains are only exported at the `debug` level, as
+    /// there's one for every packet.
+    #[clap(long = "otlp-filter", env = "QUILKIN_OTLP_FILTER", default_value = FILTER)]
+    pub filter: String,
+}
+
+impl Otlp {
+    /// Returns the layer exporting spans, if an endpoint is set. Spans are
+    /// exported in batches from the Tokio runtime.
+    pub(crate) fn layer<S>(&self) -> crate::Result<Option<impl Layer<S>>>
+    where
+        S: tracing::Subscriber + for<'span> LookupSpan<'span>,
+    {
+        let Some(endpoint) = &self.endpoint else {
+            return Ok(None);
+        };
+
+        if !(0.0..=1.0).contains(&self.sampling_ratio) {
+            return Err(eyre::eyre!(
+                "`otlp_sampling_ratio` must be between 0 and 1, found {}",
+                self.sampling_ratio
+            ));
+        }
+
+        let filter = EnvFilter::try_new(&self.filter)?;
+        let tracer = opentelemetry_otlp::new_pipeline()
+            .tracing()
+            .with_exporter(
+                opentelemetry_otlp::new_exporter()
+                    .tonic()
+                    .with_endpoint(endpoint),
+            )
+            .with_trace_config(
+                trace::config()
+                    .with_sampler(Sampler::ParentBased(Box::new(Sampler::TraceIdRatioBased(
+                        self.sampling_ratio,
+                    ))))
+                    .with_resource(Resource::new([
+                        KeyValue::new("service.name", "quilkin"),
+                        KeyValue::new("service.version", crate_version!()),
+                    ])),
+            )
+            .install_batch(opentelemetry::runtime::Tokio)?;
+
+        Ok(Some(
+            tracing_opentelemetry::layer()
+                .with_tracer(tracer)
+                .with_filter(filter),
+        ))
+    }
+}
+
+/// Exports the spans that are still waiting to be, before exiting.
+pub(crate) async fn shutdown() {
+    // Shutting down blocks until the exporter has finished, which needs the
+    // runtime's other threads.
+    let _ = tokio::task::spawn_blocking(opentelemetry::global::shutdown_tracer_provider).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn layer() {
+        let mut otlp = Otlp {
+            endpoint: None,
+            sampling_ratio: SAMPLING_RATIO,
+            filter: FILTER.into(),
+        };
+        assert!(otlp
+            .layer::<tracing_subscriber::Registry>()
+            .unwrap()
+            .is_none());
+
+        otlp.endpoint = Some("http://localhost:4317".into());
+        otlp.sampling_ratio = 1.5;
+        assert!(otlp.layer::<tracing_subscriber::Registry>().is_err());
+
+        otlp.sampling_ratio = 0.5;
+        otlp.filter = "quilkin=notalevel".into();
+        assert!(otlp.layer::<tracing_subscriber::Registry>().is_err());
+    }
+}
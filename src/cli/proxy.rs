@@ -15,19 +15,37 @@
  */
 
 use std::{
-    net::{Ipv4Addr, SocketAddr, SocketAddrV4},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
     sync::Arc,
 };
 
 use tokio::{net::UdpSocket, sync::watch, time::Duration};
 use tonic::transport::Endpoint;
 
-use crate::{proxy::SessionMap, utils::net, xds::ResourceType, Config, Result};
+use crate::{
+    config::watch::configmap,
+    endpoint::EndpointAddress,
+    filters::suspicion::{self, Destination},
+    proxy::{SessionMap, Tasks},
+    xds::ResourceType,
+    Config, Result, SocketConfig, UpstreamBind,
+};
 
 #[cfg(doc)]
 use crate::filters::FilterFactory;
 
 pub const PORT: u16 = 7777;
+const BIND_ADDRESS: IpAddr = IpAddr::V4(Ipv4Addr::UNSPECIFIED);
+const SESSION_JOURNAL_MAX_BYTES: u64 = 64 * 1024 * 1024;
+const AUTOSCALE_SMOOTHING_SECS: u64 = 60;
+const WORKER_TARGET_CPU: f64 = 0.7;
+const WORKER_TARGET_QUEUE_DEPTH: usize = 64;
+const SHUTDOWN_DEADLINE_SECS: u64 = 5;
+const MAX_IDLE_SLEEP_SECS: u64 = 60;
+const PREWARM_MAX_SESSIONS: usize = 1024;
+const DNS_TTL_SECS: u64 = 30;
+const RECV_BATCH_SIZE: usize = 16;
+const WORKER_QUEUE_SIZE: usize = 1024;
 
 /// Run Quilkin as a UDP reverse proxy.
 #[derive(clap::Args, Clone)]
@@ -35,40 +53,450 @@ pub struct Proxy {
     /// One or more `quilkin manage` endpoints to listen to for config changes
     #[clap(short, long, env = "QUILKIN_MANAGEMENT_SERVER", conflicts_with("to"))]
     pub management_server: Vec<Endpoint>,
+    /// Uses the delta xDS protocol with the management server, which only
+    /// sends the resources that changed rather than every resource.
+    #[clap(long, env = "QUILKIN_DELTA_XDS", requires("management_server"))]
+    pub delta_xds: bool,
+    /// The path of a PEM bundle of certificate authorities to verify the
+    /// management servers' certificates with, enabling TLS.
+    #[clap(
+        long,
+        env = "QUILKIN_XDS_CA_CERTIFICATE",
+        requires("management_server")
+    )]
+    pub xds_ca_certificate: Option<std::path::PathBuf>,
+    /// The path of a PEM certificate to present to the management servers,
+    /// enabling mutual TLS.
+    #[clap(
+        long,
+        env = "QUILKIN_XDS_CLIENT_CERTIFICATE",
+        requires("management_server"),
+        requires("xds_client_key")
+    )]
+    pub xds_client_certificate: Option<std::path::PathBuf>,
+    /// The path of the PEM private key of `xds_client_certificate`.
+    #[clap(
+        long,
+        env = "QUILKIN_XDS_CLIENT_KEY",
+        requires("xds_client_certificate")
+    )]
+    pub xds_client_key: Option<std::path::PathBuf>,
+    /// The name to verify the management servers' certificates against and
+    /// send with SNI, rather than the host of their URL.
+    #[clap(long, env = "QUILKIN_XDS_SERVER_NAME", requires("management_server"))]
+    pub xds_server_name: Option<String>,
+    /// A bearer token to send to the management servers with every request,
+    /// such as the token of an `--rbac` role.
+    #[clap(
+        long,
+        env = "QUILKIN_XDS_TOKEN",
+        hide_env_values = true,
+        requires("management_server")
+    )]
+    pub xds_token: Option<String>,
     /// The remote URL or local file path to retrieve the Maxmind database.
+    #[cfg(feature = "filter-geolocation")]
     #[clap(long, env)]
     pub mmdb: Option<crate::maxmind_db::Source>,
     /// The port to listen on.
     #[clap(short, long, env = super::PORT_ENV_VAR, default_value_t = PORT)]
     pub port: u16,
+    /// The address to listen on, such as `::` to listen on both IPv4 and
+    /// IPv6, or a specific address of one interface. Sessions send packets
+    /// from a specific address that isn't a loopback address too, unless
+    /// `upstream_address` sets one for its address family.
+    #[clap(long, env = "QUILKIN_BIND", default_value_t = BIND_ADDRESS)]
+    pub bind_address: IpAddr,
+    /// The TCP port to accept MASQUE CONNECT-UDP requests on over HTTP/2,
+    /// their packets going through the filter chain like any other client's.
+    /// Disabled if unset.
+    #[clap(long, env = "QUILKIN_MASQUE_PORT")]
+    pub masque_port: Option<u16>,
     /// One or more socket addresses to forward packets to.
     #[clap(short, long, env = "QUILKIN_DEST")]
     pub to: Vec<SocketAddr>,
+    /// The maximum time in milliseconds the filter chain may spend processing
+    /// a single packet, before the packet is dropped.
+    #[clap(long, env = "QUILKIN_FILTER_BUDGET_MS")]
+    pub filter_budget_ms: Option<u64>,
+    /// The time in microseconds the filter chain may spend on the packets of
+    /// each client IP address per second, after which the client's packets
+    /// are dropped until it's back within its budget. Unlimited if unset.
+    #[clap(long, env = "QUILKIN_SOURCE_CPU_BUDGET_US")]
+    pub source_cpu_budget_us: Option<u64>,
+    /// The most filter chain time in microseconds a client may use at once,
+    /// defaulting to a second's worth of `source_cpu_budget_us`.
+    #[clap(
+        long,
+        env = "QUILKIN_SOURCE_CPU_BURST_US",
+        requires("source_cpu_budget_us")
+    )]
+    pub source_cpu_burst_us: Option<u64>,
+    /// How often in seconds to push coarse traffic counters to the
+    /// management server, no counters are pushed if unset.
+    #[clap(
+        long,
+        env = "QUILKIN_TELEMETRY_INTERVAL_SECS",
+        requires("management_server")
+    )]
+    pub telemetry_interval_secs: Option<u64>,
+    /// Shares the usage of `LocalRateLimit` filters with a `shared` limit
+    /// with the rest of the fleet through the management server, so that
+    /// sources are limited across every proxy rather than per proxy.
+    #[clap(long, env = "QUILKIN_SHARE_RATE_LIMITS", requires("management_server"))]
+    pub share_rate_limits: bool,
+    /// One or more addresses of endpoints, such as the game server this proxy
+    /// runs alongside, to register with the management server for as long as
+    /// the proxy is connected to it.
+    #[clap(long, env = "QUILKIN_REGISTER", requires("management_server"))]
+    pub register: Vec<SocketAddr>,
+    /// The cluster to register endpoints in, the default cluster if unset.
+    #[clap(long, env = "QUILKIN_REGISTER_CLUSTER", requires("register"))]
+    pub register_cluster: Option<String>,
+    /// The path of a local file to journal the start and end of every session
+    /// to, which can be searched with `quilkin sessions query`.
+    #[clap(long, env = "QUILKIN_SESSION_JOURNAL")]
+    pub session_journal: Option<std::path::PathBuf>,
+    /// The maximum size in bytes of the session journal, including the
+    /// rotated file holding older records.
+    #[clap(
+        long,
+        env = "QUILKIN_SESSION_JOURNAL_MAX_BYTES",
+        default_value_t = SESSION_JOURNAL_MAX_BYTES
+    )]
+    pub session_journal_max_bytes: u64,
+    /// Keeps how the filter chain handled the latest this many packets in
+    /// memory, served at `GET /debug/decisions` on the admin server. Nothing
+    /// is recorded if unset.
+    #[clap(long, env = "QUILKIN_DECISION_LOG_SIZE")]
+    pub decision_log_size: Option<usize>,
+    /// A URL to `POST` the suspicion events emitted by filters to, as JSON.
+    #[clap(long, env = "QUILKIN_SUSPICION_WEBHOOK")]
+    pub suspicion_webhook: Option<url::Url>,
+    /// A `SuspicionService` gRPC endpoint to stream the suspicion events
+    /// emitted by filters to.
+    #[clap(
+        long,
+        env = "QUILKIN_SUSPICION_GRPC",
+        conflicts_with("suspicion_webhook")
+    )]
+    pub suspicion_grpc: Option<Endpoint>,
+    /// A `SamplingService` gRPC endpoint to stream the packets sampled from
+    /// clusters with `sampling` set to.
+    #[clap(long, env = "QUILKIN_SAMPLING_GRPC")]
+    pub sampling_grpc: Option<Endpoint>,
+    /// The salt sampled addresses are hashed with, so that the same address
+    /// has the same hash across proxies. A random salt is used if unset.
+    #[clap(long, env = "QUILKIN_SAMPLING_SALT", requires("sampling_grpc"))]
+    pub sampling_salt: Option<String>,
+    /// The number of active sessions a single proxy should handle, for the
+    /// autoscaling recommendation.
+    #[clap(long, env = "QUILKIN_AUTOSCALE_TARGET_SESSIONS")]
+    pub autoscale_target_sessions: Option<u64>,
+    /// The packets per second, in both directions, a single proxy should
+    /// handle, for the autoscaling recommendation.
+    #[clap(long, env = "QUILKIN_AUTOSCALE_TARGET_PPS")]
+    pub autoscale_target_pps: Option<u64>,
+    /// The fraction of the machine's CPUs a single proxy should use, between
+    /// 0 and 1, for the autoscaling recommendation.
+    #[clap(long, env = "QUILKIN_AUTOSCALE_TARGET_CPU")]
+    pub autoscale_target_cpu: Option<f64>,
+    /// The number of seconds over which the autoscaling recommendation is
+    /// smoothed, so that short bursts of load don't cause scaling.
+    #[clap(
+        long,
+        env = "QUILKIN_AUTOSCALE_SMOOTHING_SECS",
+        default_value_t = AUTOSCALE_SMOOTHING_SECS
+    )]
+    pub autoscale_smoothing_secs: u64,
+    /// The source addresses of the sockets sending to endpoints, at most one
+    /// IPv4 and one IPv6 address, for clusters that don't set their own
+    /// `upstream_bind`.
+    #[clap(long, env = "QUILKIN_UPSTREAM_ADDRESS", value_delimiter = ',')]
+    pub upstream_address: Vec<std::net::IpAddr>,
+    /// The network interface of the sockets sending to endpoints, for
+    /// clusters that don't set their own `upstream_bind`. Only supported on
+    /// Linux.
+    #[clap(long, env = "QUILKIN_UPSTREAM_INTERFACE")]
+    pub upstream_interface: Option<String>,
+    /// The number of workers, each with their own socket, to start receiving
+    /// packets from clients with. Workers are added up to `max_workers` as
+    /// they get busy, and removed again once idle. Defaults to `max_workers`,
+    /// which doesn't scale the workers.
+    #[clap(long, env = "QUILKIN_MIN_WORKERS")]
+    pub min_workers: Option<usize>,
+    /// The most workers receiving packets from clients, the number of CPUs
+    /// if unset.
+    #[clap(long, env = "QUILKIN_MAX_WORKERS")]
+    pub max_workers: Option<usize>,
+    /// The fraction of a CPU each worker should use, above which workers are
+    /// added.
+    #[clap(long, env = "QUILKIN_WORKER_TARGET_CPU", default_value_t = WORKER_TARGET_CPU)]
+    pub worker_target_cpu: f64,
+    /// The number of packets each worker should be processing at once, above
+    /// which workers are added.
+    #[clap(
+        long,
+        env = "QUILKIN_WORKER_TARGET_QUEUE_DEPTH",
+        default_value_t = WORKER_TARGET_QUEUE_DEPTH
+    )]
+    pub worker_target_queue_depth: usize,
+    /// How packets from clients are dispatched to the workers. `reuse-port`
+    /// leaves it to the kernel, while `keyed` receives every packet on a
+    /// single socket and picks the worker from a hash of its source address,
+    /// so every packet of a client is processed by the same worker, in
+    /// order. Keyed dispatch doesn't scale the workers.
+    #[clap(
+        long,
+        env = "QUILKIN_WORKER_DISPATCH",
+        value_enum,
+        default_value_t = crate::proxy::Dispatch::ReusePort
+    )]
+    pub worker_dispatch: crate::proxy::Dispatch,
+    /// The most packets queued to each worker with `keyed` dispatch, further
+    /// packets for the worker are dropped.
+    #[clap(
+        long,
+        env = "QUILKIN_WORKER_QUEUE_SIZE",
+        default_value_t = WORKER_QUEUE_SIZE
+    )]
+    pub worker_queue_size: usize,
+    /// The number of seconds the proxy's tasks, such as its workers, sessions
+    /// and xDS streams, have to stop on shutdown, after which they're aborted
+    /// and reported.
+    #[clap(
+        long,
+        env = "QUILKIN_SHUTDOWN_DEADLINE_SECS",
+        default_value_t = SHUTDOWN_DEADLINE_SECS
+    )]
+    pub shutdown_deadline_secs: u64,
+    /// Drains the proxy for up to this many seconds on shutdown, or when
+    /// requested with `POST /drain`, which stops it accepting new sessions
+    /// while it keeps forwarding the packets of existing sessions until they
+    /// expire. The proxy stops immediately if unset.
+    #[clap(long, env = "QUILKIN_DRAIN_TIMEOUT_SECS")]
+    pub drain_timeout_secs: Option<u64>,
+    /// How many seconds sessions last, overriding the config's
+    /// `sessions.ttl_secs`. 60 by default.
+    #[clap(long, env = "QUILKIN_SESSION_TTL_SECS")]
+    pub session_ttl_secs: Option<u64>,
+    /// How often in seconds expired sessions are closed, overriding the
+    /// config's `sessions.expiry_poll_interval_secs`. 60 by default.
+    #[clap(long, env = "QUILKIN_SESSION_EXPIRY_POLL_INTERVAL_SECS")]
+    pub session_expiry_poll_interval_secs: Option<u64>,
+    /// Whether a session's TTL counts from its latest packet or from its
+    /// creation, overriding the config's `sessions.expiry`. `idle` by
+    /// default.
+    #[clap(long, env = "QUILKIN_SESSION_EXPIRY", value_enum)]
+    pub session_expiry: Option<crate::proxy::Expiry>,
+    /// The most sessions the proxy holds at once, overriding the config's
+    /// `sessions.max_sessions`. Sessions are unlimited if unset.
+    #[clap(long, env = "QUILKIN_MAX_SESSIONS")]
+    pub max_sessions: Option<usize>,
+    /// What happens to new sessions once there are `max_sessions`,
+    /// overriding the config's `sessions.eviction`. `reject` by default.
+    #[clap(long, env = "QUILKIN_SESSION_EVICTION", value_enum)]
+    pub session_eviction: Option<crate::proxy::Eviction>,
+    /// The most memory in bytes the proxy's sessions may hold, approximately.
+    /// Once it's reached, the least recently active sessions are closed to
+    /// make room for new ones. Sessions are unlimited if unset.
+    #[clap(long, env = "QUILKIN_MAX_SESSION_MEMORY_BYTES")]
+    pub max_session_memory_bytes: Option<usize>,
+    /// Records the packets, bytes, processing time and sessions of each
+    /// upstream endpoint, which adds metric series for every endpoint.
+    #[clap(long, env = "QUILKIN_ENDPOINT_METRICS")]
+    pub endpoint_metrics: bool,
+    /// The longest number of seconds periodic background tasks, such as
+    /// worker scaling and the autoscaling recommendation, sleep for while the
+    /// proxy is idle. They're woken early by the next packet, so this bounds
+    /// how stale their measurements can get, `0` keeps them at their usual
+    /// interval.
+    #[clap(
+        long,
+        env = "QUILKIN_MAX_IDLE_SLEEP_SECS",
+        default_value_t = MAX_IDLE_SLEEP_SECS
+    )]
+    pub max_idle_sleep_secs: u64,
+    /// Batches the packets each session sends upstream within this many
+    /// microseconds, at most `1000`, into one `sendmmsg` call on Linux,
+    /// trading that much latency for fewer system calls. Disabled if unset.
+    #[clap(long, env = "QUILKIN_UPSTREAM_BATCH_WINDOW_US")]
+    pub upstream_batch_window_us: Option<u64>,
+    /// The most packets each worker receives from clients with a single
+    /// `recvmmsg` call on Linux, each taking a 64KiB buffer. `1` receives a
+    /// packet per call.
+    #[clap(
+        long,
+        env = "QUILKIN_RECV_BATCH_SIZE",
+        default_value_t = RECV_BATCH_SIZE
+    )]
+    pub recv_batch_size: usize,
+    /// How often in seconds the endpoints whose address is a hostname are
+    /// resolved again. Sessions to a hostname are closed when it resolves to
+    /// a different address.
+    #[clap(long, env = "QUILKIN_DNS_TTL_SECS", default_value_t = DNS_TTL_SECS)]
+    pub dns_ttl_secs: u64,
+    /// Enables pre-establishing sessions with the admin server's
+    /// `/sessions/prewarm`, which keeps each pre-established session for this
+    /// many seconds for its player's first packet.
+    #[clap(long, env = "QUILKIN_PREWARM_TTL_SECS")]
+    pub prewarm_ttl_secs: Option<u64>,
+    /// The most sessions waiting for their player's first packet at once.
+    #[clap(
+        long,
+        env = "QUILKIN_PREWARM_MAX_SESSIONS",
+        default_value_t = PREWARM_MAX_SESSIONS
+    )]
+    pub prewarm_max_sessions: usize,
+    /// The metadata key holding the token of a packet, as captured by a
+    /// filter such as `Capture`, which pre-established sessions are matched
+    /// with.
+    #[clap(
+        long,
+        env = "QUILKIN_PREWARM_METADATA_KEY",
+        default_value = crate::filters::metadata::CAPTURED_BYTES
+    )]
+    pub prewarm_metadata_key: String,
+    /// Stops the proxy applying changes to its configuration file while it
+    /// runs. The file is only watched when the proxy has no
+    /// `management_server` or `to` addresses, which would be overwritten.
+    #[clap(long, env = "QUILKIN_NO_WATCH_CONFIG")]
+    pub no_watch_config: bool,
+    /// Loads the configuration from a provider instead of the configuration
+    /// file on startup, and applies its changes while the proxy runs. The
+    /// only provider is `k8s-configmap <namespace>/<name>`, which reads the
+    /// `quilkin.yaml` key of a Kubernetes `ConfigMap`.
+    #[clap(
+        long,
+        env = "QUILKIN_PROVIDER",
+        num_args = 2,
+        value_names = ["KIND", "NAMESPACE/NAME"],
+        value_delimiter = ' ',
+        conflicts_with_all = ["management_server", "to"]
+    )]
+    pub provider: Vec<String>,
+    /// Rejects every change to the clusters and filters once the proxy has
+    /// started, whether from management servers or the configuration file,
+    /// so that they're only changed by redeploying the proxy.
+    #[clap(long, env = "QUILKIN_FROZEN_CONFIG")]
+    pub frozen_config: bool,
+    /// Configuration applied to every socket created by the proxy, only
+    /// available when embedding Quilkin.
+    #[clap(skip)]
+    pub socket_config: SocketConfig,
 }
 
 impl Default for Proxy {
     fn default() -> Self {
         Self {
             management_server: <_>::default(),
+            delta_xds: false,
+            xds_ca_certificate: None,
+            xds_client_certificate: None,
+            xds_client_key: None,
+            xds_server_name: None,
+            xds_token: None,
+            #[cfg(feature = "filter-geolocation")]
             mmdb: <_>::default(),
             port: PORT,
+            bind_address: BIND_ADDRESS,
+            masque_port: None,
             to: <_>::default(),
+            filter_budget_ms: None,
+            source_cpu_budget_us: None,
+            source_cpu_burst_us: None,
+            telemetry_interval_secs: None,
+            share_rate_limits: false,
+            register: Vec::new(),
+            register_cluster: None,
+            session_journal: None,
+            session_journal_max_bytes: SESSION_JOURNAL_MAX_BYTES,
+            decision_log_size: None,
+            suspicion_webhook: None,
+            suspicion_grpc: None,
+            sampling_grpc: None,
+            sampling_salt: None,
+            autoscale_target_sessions: None,
+            autoscale_target_pps: None,
+            autoscale_target_cpu: None,
+            autoscale_smoothing_secs: AUTOSCALE_SMOOTHING_SECS,
+            upstream_address: Vec::new(),
+            upstream_interface: None,
+            min_workers: None,
+            max_workers: None,
+            worker_target_cpu: WORKER_TARGET_CPU,
+            worker_target_queue_depth: WORKER_TARGET_QUEUE_DEPTH,
+            worker_dispatch: <_>::default(),
+            worker_queue_size: WORKER_QUEUE_SIZE,
+            shutdown_deadline_secs: SHUTDOWN_DEADLINE_SECS,
+            drain_timeout_secs: None,
+            session_ttl_secs: None,
+            session_expiry_poll_interval_secs: None,
+            session_expiry: None,
+            max_sessions: None,
+            session_eviction: None,
+            max_session_memory_bytes: None,
+            endpoint_metrics: false,
+            max_idle_sleep_secs: MAX_IDLE_SLEEP_SECS,
+            upstream_batch_window_us: None,
+            recv_batch_size: RECV_BATCH_SIZE,
+            dns_ttl_secs: DNS_TTL_SECS,
+            prewarm_ttl_secs: None,
+            prewarm_max_sessions: PREWARM_MAX_SESSIONS,
+            prewarm_metadata_key: crate::filters::metadata::CAPTURED_BYTES.into(),
+            no_watch_config: false,
+            provider: Vec::new(),
+            frozen_config: false,
+            socket_config: <_>::default(),
         }
     }
 }
 
 impl Proxy {
+    /// Whether the proxy applies changes to its configuration file, see
+    /// [`Config::watch_file`].
+    pub fn watches_config(&self) -> bool {
+        !self.no_watch_config
+            && self.management_server.is_empty()
+            && self.to.is_empty()
+            && self.provider.is_empty()
+    }
+
+    /// The `ConfigMap` the configuration is loaded from, if any.
+    fn configmap(&self) -> crate::Result<Option<configmap::Source>> {
+        match &*self.provider {
+            [] => Ok(None),
+            [kind, source] if kind == "k8s-configmap" => source.parse().map(Some),
+            [kind, _] => Err(eyre::eyre!(
+                "unknown provider `{kind}`, the only provider is `k8s-configmap`"
+            )),
+            _ => Err(eyre::eyre!(
+                "`--provider` expects a provider and its source, such as `k8s-configmap default/quilkin`"
+            )),
+        }
+    }
+
+    /// The session policy set by the flags, which overrides the config's.
+    fn session_policy(&self) -> crate::proxy::SessionPolicy {
+        crate::proxy::SessionPolicy {
+            ttl_secs: self.session_ttl_secs,
+            expiry_poll_interval_secs: self.session_expiry_poll_interval_secs,
+            expiry: self.session_expiry,
+            max_sessions: self.max_sessions,
+            eviction: self.session_eviction,
+        }
+    }
+
     /// Start and run a proxy.
     pub async fn run(
         &self,
         config: std::sync::Arc<crate::Config>,
         mut shutdown_rx: tokio::sync::watch::Receiver<()>,
     ) -> crate::Result<()> {
-        const SESSION_TIMEOUT_SECONDS: Duration = Duration::from_secs(60);
-        const SESSION_EXPIRY_POLL_INTERVAL: Duration = Duration::from_secs(60);
-
-        let _mmdb_task = self.mmdb.clone().map(|source| {
-            tokio::spawn(async move {
+        let tasks = Tasks::default();
+        #[cfg(feature = "filter-geolocation")]
+        if let Some(source) = self.mmdb.clone() {
+            tasks.spawn("maxmind", async move {
                 use crate::config::BACKOFF_INITIAL_DELAY_MILLISECONDS;
                 while let Err(error) =
                     tryhard::retry_fn(|| crate::MaxmindDb::update(source.clone()))
@@ -80,8 +508,8 @@ impl Proxy {
                 {
                     tracing::warn!(%error, "error updating maxmind database");
                 }
-            })
-        });
+            });
+        }
 
         if !self.to.is_empty() {
             config.clusters.modify(|clusters| {
@@ -89,44 +517,254 @@ impl Proxy {
             });
         }
 
+        if let Some(source) = self.configmap()? {
+            let client = configmap::client().await?;
+            let applied = configmap::load(&client, &config, &source).await?;
+            tasks.spawn(
+                "configmap",
+                configmap::watch(client, config.clone(), source, applied),
+            );
+        }
+
         if config.clusters.load().endpoints().count() == 0 && self.management_server.is_empty() {
             return Err(eyre::eyre!(
                 "`quilkin proxy` requires at least one `to` address or `management_server` endpoint."
             ));
         }
 
+        if self.frozen_config {
+            config.frozen.freeze();
+        }
+
+        crate::proxy::checksum::register_metrics();
+        config
+            .filter_budget
+            .set(self.filter_budget_ms.map(Duration::from_millis));
+        crate::proxy::cpu_budget::install(self.source_cpu_budget_us.map(|budget| {
+            crate::proxy::cpu_budget::CpuBudget::new(
+                Duration::from_micros(budget),
+                self.source_cpu_burst_us.map(Duration::from_micros),
+            )
+        }));
+
+        if let Some(path) = &self.session_journal {
+            tracing::info!(path = %path.display(), "Journaling sessions");
+            crate::proxy::journal::install(Some(crate::proxy::journal::Journal::open(
+                path,
+                self.session_journal_max_bytes,
+            )?));
+        }
+
+        crate::proxy::decisions::install(
+            self.decision_log_size
+                .filter(|size| *size > 0)
+                .map(crate::proxy::decisions::Log::new),
+        );
+
         let id = config.id.load();
-        tracing::info!(port = self.port, proxy_id = &*id, "Starting");
+        let destination = self
+            .suspicion_webhook
+            .clone()
+            .map(Destination::Webhook)
+            .or_else(|| self.suspicion_grpc.clone().map(Destination::Grpc));
+        if let Some(destination) = destination {
+            tracing::info!(?destination, "Emitting suspicion events");
+            suspicion::install(Some(suspicion::Sink::spawn(
+                String::clone(&id),
+                destination,
+            )));
+        }
+
+        if let Some(endpoint) = &self.sampling_grpc {
+            tracing::info!(uri = %endpoint.uri(), "Sampling packets");
+            crate::proxy::sampling::install(Some(crate::proxy::sampling::Sink::spawn(
+                String::clone(&id),
+                endpoint.clone(),
+                self.sampling_salt.clone(),
+            )));
+        }
+
+        if let Some(ttl) = self.prewarm_ttl_secs {
+            tracing::info!(ttl, "Pre-establishing sessions");
+            crate::proxy::prewarm::install(Some(crate::proxy::prewarm::Prewarmer::new(
+                config.clone(),
+                Arc::new(self.session_socket_config()?),
+                self.prewarm_metadata_key.as_str().into(),
+                Duration::from_secs(ttl),
+                self.prewarm_max_sessions,
+            )));
+        }
+
+        let targets = crate::admin::autoscale::Targets {
+            sessions: self.autoscale_target_sessions,
+            packets_per_second: self.autoscale_target_pps,
+            cpu: self.autoscale_target_cpu,
+            smoothing: Duration::from_secs(self.autoscale_smoothing_secs),
+        };
+        let _autoscaler = (!targets.is_empty()).then(|| {
+            tracing::info!(?targets, "Recommending autoscaling");
+            crate::admin::autoscale::Autoscaler::spawn(
+                targets,
+                Duration::from_secs(self.max_idle_sleep_secs),
+            )
+        });
+
+        tracing::info!(
+            address = %self.bind_address,
+            port = self.port,
+            proxy_id = &*id,
+            "Starting"
+        );
 
-        let sessions = SessionMap::new(SESSION_TIMEOUT_SECONDS, SESSION_EXPIRY_POLL_INTERVAL);
+        crate::proxy::set_endpoint_metrics(self.endpoint_metrics);
+        let session_policy = self.session_policy();
+        let defaults = session_policy.or(&config.sessions.load());
+        let sessions = SessionMap::new(
+            self.scaling()?.max,
+            defaults.ttl(),
+            defaults.expiry_poll_interval(),
+        )
+        .with_tasks(tasks.clone())
+        .with_memory_limit(self.max_session_memory_bytes)
+        .with_policy(session_policy);
+        sessions.close_removed_endpoints(&config.clusters);
+        if self.source_cpu_budget_us.is_some() {
+            tasks.spawn("cpu budget", async {
+                let mut interval = tokio::time::interval(crate::proxy::cpu_budget::PRUNE_INTERVAL);
+                loop {
+                    interval.tick().await;
+                    if let Some(budget) = crate::proxy::cpu_budget::budget() {
+                        budget.prune();
+                    }
+                }
+            });
+        }
+        crate::cluster::health::spawn(config.clone(), &tasks);
+        crate::cluster::preflight::spawn(config.clone(), &tasks);
+        crate::cluster::dns::spawn(
+            config.clone(),
+            Duration::from_secs(self.dns_ttl_secs.max(1)),
+            &tasks,
+        );
 
-        let _xds_stream = if !self.management_server.is_empty() {
-            let client =
-                crate::xds::Client::connect(String::clone(&id), self.management_server.clone())
-                    .await?;
-            let mut stream = client
-                .stream({
-                    let config = config.clone();
-                    move |resource| config.apply(resource)
-                })
-                .await?;
+        if !self.management_server.is_empty() {
+            let auth = crate::xds::ClientAuth {
+                ca_certificate: self.xds_ca_certificate.clone(),
+                client_certificate: self.xds_client_certificate.clone(),
+                client_key: self.xds_client_key.clone(),
+                server_name: self.xds_server_name.clone(),
+                token: self.xds_token.clone(),
+            }
+            .or(&config.xds_auth);
+            let client = crate::xds::Client::connect_with_auth(
+                String::clone(&id),
+                self.management_server.clone(),
+                &auth,
+            )
+            .await?;
+            let mut stream = if self.delta_xds {
+                client.delta_stream_config(config.clone()).await?
+            } else {
+                client.stream_config(config.clone()).await?
+            };
 
             tokio::time::sleep(std::time::Duration::from_nanos(1)).await;
             stream.send(ResourceType::Endpoint, &[]).await?;
             tokio::time::sleep(std::time::Duration::from_nanos(1)).await;
             stream.send(ResourceType::Listener, &[]).await?;
-            Some(stream)
-        } else {
-            None
-        };
+            tokio::time::sleep(std::time::Duration::from_nanos(1)).await;
+            stream.send(ResourceType::Cluster, &[]).await?;
+            let telemetry = self
+                .telemetry_interval_secs
+                .map(|secs| client.report_telemetry(Duration::from_secs(secs)));
+            let rate_limits = self
+                .share_rate_limits
+                .then(|| client.share_rate_limits(&config));
+            let registrar = (!self.register.is_empty()).then(|| {
+                let endpoints = self
+                    .register
+                    .iter()
+                    .map(|&address| EndpointAddress::from(address))
+                    .collect::<Vec<_>>();
+                client.register(self.register_cluster.clone(), &endpoints)
+            });
+            // The streams' own tasks are aborted when they're dropped on
+            // shutdown.
+            tasks.spawn("xds", async move {
+                let _xds = (stream, telemetry, rate_limits, registrar);
+                std::future::pending::<()>().await
+            });
+        }
 
-        self.run_recv_from(&config, sessions, shutdown_rx.clone())?;
+        crate::proxy::drain::enable(self.drain_timeout_secs.is_some());
+        // The workers are stopped separately from the rest of the proxy, once
+        // it's drained, so that they keep forwarding the existing sessions'
+        // packets while it drains.
+        let (workers_tx, workers_rx) = watch::channel(());
+        if let Err(error) = self.run_recv_from(&config, sessions.clone(), workers_rx) {
+            tasks.shutdown(Duration::ZERO).await;
+            return Err(error);
+        }
+
+        if let Some(port) = self.masque_port {
+            let address = SocketAddr::new(self.bind_address, port);
+            let listener = match tokio::net::TcpListener::bind(address).await {
+                Ok(listener) => listener,
+                Err(error) => {
+                    tasks.shutdown(Duration::ZERO).await;
+                    return Err(error.into());
+                }
+            };
+            tracing::info!(%address, "Accepting MASQUE CONNECT-UDP requests");
+            tasks.spawn(
+                "masque",
+                crate::masque::serve(listener, self.local_address()),
+            );
+        }
         tracing::info!("Quilkin is ready");
 
-        shutdown_rx
-            .changed()
-            .await
-            .map_err(|error| eyre::eyre!(error))
+        let drain_timeout = self.drain_timeout_secs.map(Duration::from_secs);
+        let requested_drains = async {
+            let Some(timeout) = drain_timeout else {
+                return std::future::pending().await;
+            };
+
+            // Cancelled drains go back to waiting for the next request.
+            loop {
+                crate::proxy::drain::requested().await;
+                if crate::proxy::drain::drain(&sessions, timeout, true).await {
+                    return;
+                }
+            }
+        };
+
+        let shutdown = tokio::select! {
+            result = shutdown_rx.changed() => Some(result),
+            _ = requested_drains => None,
+        };
+
+        let result = match shutdown {
+            Some(result) => {
+                if let Some(timeout) = drain_timeout {
+                    crate::proxy::drain::drain(&sessions, timeout, false).await;
+                }
+                result.map_err(|error| eyre::eyre!(error))
+            }
+            None => Ok(()),
+        };
+        workers_tx.send_replace(());
+
+        let deadline = Duration::from_secs(self.shutdown_deadline_secs);
+        tracing::info!(tasks = tasks.len(), ?deadline, "Stopping tasks");
+        let stuck = tasks.shutdown(deadline).await;
+        if !stuck.is_empty() {
+            tracing::warn!(
+                ?stuck,
+                "Some tasks failed to stop before the shutdown deadline"
+            );
+        }
+
+        result
     }
 
     /// Spawns a background task that sits in a loop, receiving packets from the passed in socket.
@@ -140,36 +778,167 @@ impl Proxy {
         sessions: SessionMap,
         shutdown_rx: watch::Receiver<()>,
     ) -> Result<()> {
-        // The number of worker tasks to spawn. Each task gets a dedicated queue to
-        // consume packets off.
-        let num_workers = num_cpus::get();
-
-        // Contains config for each worker task.
-        let mut workers = Vec::with_capacity(num_workers);
-        for worker_id in 0..num_workers {
-            let socket = Arc::new(self.bind(self.port)?);
-            workers.push(crate::proxy::DownstreamReceiveWorkerConfig {
+        let socket_config = Arc::new(self.session_socket_config()?);
+        if self.worker_dispatch == crate::proxy::Dispatch::Keyed {
+            return self.run_keyed_dispatch(config, sessions, shutdown_rx, socket_config);
+        }
+
+        let spawn_worker = {
+            let proxy = self.clone();
+            let config = config.clone();
+            let sessions = sessions.clone();
+            move |worker_id, shutdown_rx| -> Result<Arc<UdpSocket>> {
+                let socket = Arc::new(proxy.bind()?);
+                crate::proxy::DownstreamReceiveWorkerConfig {
+                    worker_id,
+                    socket: socket.clone(),
+                    shutdown_rx,
+                    config: config.clone(),
+                    sessions: sessions.clone(),
+                    socket_config: socket_config.clone(),
+                    recv_batch_size: proxy.recv_batch_size,
+                }
+                .spawn();
+                Ok(socket)
+            }
+        };
+
+        let scaling = self.scaling()?;
+        if scaling.min < scaling.max {
+            tracing::info!(?scaling, "Scaling workers");
+            return crate::proxy::workers::spawn(scaling, sessions, shutdown_rx, spawn_worker);
+        }
+
+        // Each worker gets a dedicated socket to receive packets from, and a
+        // shard of the session map.
+        for worker_id in 0..sessions.shard_count() {
+            spawn_worker(worker_id, shutdown_rx.clone())?;
+        }
+
+        Ok(())
+    }
+
+    /// Receives every packet on a single socket, queueing each to the worker
+    /// picked from its source address, so that a client always reaches the
+    /// same worker and shard of the session map.
+    fn run_keyed_dispatch(
+        &self,
+        config: &Arc<Config>,
+        sessions: SessionMap,
+        shutdown_rx: watch::Receiver<()>,
+        socket_config: Arc<SocketConfig>,
+    ) -> Result<()> {
+        let scaling = self.scaling()?;
+        if scaling.min < scaling.max {
+            return Err(eyre::eyre!(
+                "`min_workers` can't be set below `max_workers` with keyed dispatch"
+            ));
+        }
+
+        let socket = Arc::new(self.bind()?);
+        let (senders, receivers) =
+            crate::proxy::dispatch::queues(sessions.shard_count(), self.worker_queue_size);
+        for (worker_id, queue) in receivers.into_iter().enumerate() {
+            crate::proxy::DownstreamReceiveWorkerConfig {
                 worker_id,
                 socket: socket.clone(),
                 shutdown_rx: shutdown_rx.clone(),
                 config: config.clone(),
                 sessions: sessions.clone(),
-            })
+                socket_config: socket_config.clone(),
+                recv_batch_size: self.recv_batch_size,
+            }
+            .spawn_keyed(queue);
+        }
+
+        crate::proxy::dispatch::spawn(
+            socket,
+            senders,
+            self.recv_batch_size,
+            sessions.tasks(),
+            shutdown_rx,
+        );
+        Ok(())
+    }
+
+    /// Returns the configuration of the sockets created for sessions.
+    fn session_socket_config(&self) -> Result<SocketConfig> {
+        Ok(self
+            .socket_config
+            .clone()
+            .with_upstream_bind(self.upstream_bind()?)
+            .with_upstream_batch_window(self.upstream_batch_window_us.map(Duration::from_micros)))
+    }
+
+    /// Returns the bounds the number of workers is scaled within.
+    fn scaling(&self) -> Result<crate::proxy::workers::Scaling> {
+        let max = self.max_workers.unwrap_or_else(num_cpus::get);
+        let min = self.min_workers.unwrap_or(max);
+        if min == 0 || min > max {
+            return Err(eyre::eyre!(
+                "`min_workers` must be at least one and at most `max_workers` ({max})"
+            ));
         }
 
-        // Start the worker tasks that pick up received packets from their queue
-        // and processes them.
-        for worker in workers {
-            worker.spawn();
+        Ok(crate::proxy::workers::Scaling {
+            min,
+            max,
+            target_cpu: self.worker_target_cpu,
+            target_queue_depth: self.worker_target_queue_depth,
+            max_idle_interval: Duration::from_secs(self.max_idle_sleep_secs),
+        })
+    }
+
+    /// Returns where sessions' upstream sockets are bound, from the command
+    /// line or else the [`SocketConfig`].
+    fn upstream_bind(&self) -> Result<UpstreamBind> {
+        let mut upstream_bind = UpstreamBind {
+            interface: self.upstream_interface.clone(),
+            ..<_>::default()
+        };
+
+        for address in &self.upstream_address {
+            let duplicate = match address {
+                std::net::IpAddr::V4(address) => upstream_bind.ipv4.replace(*address).is_some(),
+                std::net::IpAddr::V6(address) => upstream_bind.ipv6.replace(*address).is_some(),
+            };
+
+            if duplicate {
+                return Err(eyre::eyre!(
+                    "`upstream_address` can only be set once for each of IPv4 and IPv6"
+                ));
+            }
         }
 
-        Ok(())
+        let mut upstream_bind = upstream_bind.or(self.socket_config.upstream_bind());
+        match self.bind_address {
+            IpAddr::V4(address) if !address.is_unspecified() && !address.is_loopback() => {
+                upstream_bind.ipv4.get_or_insert(address);
+            }
+            IpAddr::V6(address) if !address.is_unspecified() && !address.is_loopback() => {
+                upstream_bind.ipv6.get_or_insert(address);
+            }
+            _ => {}
+        }
+
+        Ok(upstream_bind)
     }
 
-    /// binds the local configured port with port and address reuse applied.
-    fn bind(&self, port: u16) -> Result<UdpSocket> {
-        let addr = SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, port);
-        net::socket_with_reuse(addr.into())
+    /// The address of the proxy's own listener, as seen from this host.
+    fn local_address(&self) -> SocketAddr {
+        let address = match self.bind_address {
+            IpAddr::V4(address) if address.is_unspecified() => Ipv4Addr::LOCALHOST.into(),
+            IpAddr::V6(address) if address.is_unspecified() => Ipv6Addr::LOCALHOST.into(),
+            address => address,
+        };
+        SocketAddr::new(address, self.port)
+    }
+
+    /// binds the local configured address and port with port and address
+    /// reuse applied.
+    fn bind(&self) -> Result<UdpSocket> {
+        let addr = SocketAddr::new(self.bind_address, self.port);
+        Ok(self.socket_config.bind(addr, true, None, None)?)
     }
 }
 
@@ -331,6 +1100,8 @@ mod tests {
             config,
             sessions: <_>::default(),
             shutdown_rx,
+            socket_config: <_>::default(),
+            recv_batch_size: RECV_BATCH_SIZE,
         }
         .spawn();
 
@@ -378,4 +1149,138 @@ mod tests {
                 .unwrap()
         );
     }
+
+    #[tokio::test]
+    async fn run_keyed_dispatch() {
+        let mut t = TestHelper::default();
+        let (_shutdown_tx, shutdown_rx) = watch::channel(());
+
+        let (mut packet_rx, endpoint) = t.open_socket_and_recv_multiple_packets().await;
+        let local_addr = available_addr().await;
+        let proxy = crate::cli::Proxy {
+            port: local_addr.port(),
+            worker_dispatch: crate::proxy::Dispatch::Keyed,
+            ..<_>::default()
+        };
+
+        let config = Arc::new(crate::Config::default());
+        config
+            .clusters
+            .modify(|clusters| clusters.insert_default(vec![endpoint.local_addr().unwrap()]));
+
+        proxy
+            .run_recv_from(&config, <_>::default(), shutdown_rx)
+            .unwrap();
+
+        let socket = create_socket().await;
+        for msg in ["first", "second", "third"] {
+            socket.send_to(msg.as_bytes(), &local_addr).await.unwrap();
+            assert_eq!(
+                msg,
+                timeout(Duration::from_secs(1), packet_rx.recv())
+                    .await
+                    .expect("should receive a packet")
+                    .unwrap()
+            );
+        }
+
+        let proxy = crate::cli::Proxy {
+            worker_dispatch: crate::proxy::Dispatch::Keyed,
+            min_workers: Some(1),
+            max_workers: Some(4),
+            ..<_>::default()
+        };
+        let (_shutdown_tx, shutdown_rx) = watch::channel(());
+        assert!(proxy
+            .run_recv_from(&config, <_>::default(), shutdown_rx)
+            .is_err());
+    }
+
+    #[test]
+    fn upstream_bind() {
+        let proxy = Proxy {
+            upstream_address: vec!["10.0.0.1".parse().unwrap(), "fd00::1".parse().unwrap()],
+            socket_config: SocketConfig::default().with_upstream_bind(UpstreamBind {
+                ipv4: Some(Ipv4Addr::LOCALHOST),
+                interface: Some("eth1".into()),
+                ..<_>::default()
+            }),
+            ..<_>::default()
+        };
+
+        assert_eq!(
+            UpstreamBind {
+                ipv4: Some(Ipv4Addr::new(10, 0, 0, 1)),
+                ipv6: Some("fd00::1".parse().unwrap()),
+                interface: Some("eth1".into()),
+            },
+            proxy.upstream_bind().unwrap()
+        );
+
+        let proxy = Proxy {
+            upstream_address: vec!["10.0.0.1".parse().unwrap(), "10.0.0.2".parse().unwrap()],
+            ..<_>::default()
+        };
+        assert!(proxy.upstream_bind().is_err());
+
+        let proxy = Proxy {
+            bind_address: "192.0.2.1".parse().unwrap(),
+            upstream_address: vec!["fd00::1".parse().unwrap()],
+            ..<_>::default()
+        };
+        let upstream_bind = proxy.upstream_bind().unwrap();
+        assert_eq!(
+            (
+                Some(Ipv4Addr::new(192, 0, 2, 1)),
+                Some("fd00::1".parse().unwrap())
+            ),
+            (upstream_bind.ipv4, upstream_bind.ipv6)
+        );
+    }
+
+    #[test]
+    fn configmap() {
+        let provider = |values: &[&str]| Proxy {
+            provider: values.iter().map(|value| value.to_string()).collect(),
+            ..<_>::default()
+        };
+
+        assert!(provider(&[]).configmap().unwrap().is_none());
+        let proxy = provider(&["k8s-configmap", "games/quilkin"]);
+        assert_eq!(
+            Some("games/quilkin".parse().unwrap()),
+            proxy.configmap().unwrap()
+        );
+        assert!(!proxy.watches_config());
+
+        assert!(provider(&["consul", "games/quilkin"]).configmap().is_err());
+        assert!(provider(&["k8s-configmap", "quilkin"]).configmap().is_err());
+    }
+
+    #[test]
+    fn scaling() {
+        let proxy = Proxy {
+            min_workers: Some(1),
+            max_workers: Some(4),
+            ..<_>::default()
+        };
+        let scaling = proxy.scaling().unwrap();
+        assert_eq!((1, 4), (scaling.min, scaling.max));
+
+        let proxy = Proxy {
+            max_workers: Some(4),
+            ..<_>::default()
+        };
+        let scaling = proxy.scaling().unwrap();
+        assert_eq!((4, 4), (scaling.min, scaling.max));
+
+        for min_workers in [0, 5] {
+            let proxy = Proxy {
+                min_workers: Some(min_workers),
+                max_workers: Some(4),
+                ..<_>::default()
+            };
+            assert!(proxy.scaling().is_err());
+        }
+    }
 }
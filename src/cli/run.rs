@@ -0,0 +1,159 @@
+/*
+ * Copyright 2023 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::sync::Arc;
+
+use tokio::sync::watch;
+
+use crate::{admin::Mode, config::Role, Config};
+
+/// Runs every role in the `roles` section of the configuration file in one
+/// process, sharing the rest of the configuration between them.
+#[derive(clap::Args, Clone, Debug, Default)]
+pub struct Run {}
+
+/// Parses the arguments of a role as its command would.
+#[derive(clap::Parser)]
+struct RoleParser<T: clap::Args> {
+    #[command(flatten)]
+    args: T,
+}
+
+fn parse<T: clap::Args>(name: &str, role: &Role) -> crate::Result<T> {
+    <RoleParser<T> as clap::Parser>::try_parse_from(
+        std::iter::once(name).chain(role.args.iter().map(String::as_str)),
+    )
+    .map(|parser| parser.args)
+    .map_err(|error| eyre::eyre!("invalid arguments for the `{name}` role: {error}"))
+}
+
+impl Run {
+    /// The admin server to run for the roles in `config`, if any.
+    pub fn admin_mode(config: &Config) -> Option<Mode> {
+        let roles = &config.roles;
+        if !roles.admin || roles.is_empty() {
+            None
+        } else if roles.proxy.is_some() {
+            Some(Mode::Proxy)
+        } else {
+            Some(Mode::Xds)
+        }
+    }
+
+    /// Runs the roles in `config` until one of them exits, or `shutdown_rx`
+    /// changes and every role has stopped.
+    pub async fn run(
+        &self,
+        config: Arc<Config>,
+        shutdown_rx: watch::Receiver<()>,
+    ) -> crate::Result<()> {
+        let roles = &config.roles;
+        if roles.is_empty() {
+            return Err(eyre::eyre!(
+                "`quilkin run` requires at least one role in the `roles` section of the configuration"
+            ));
+        }
+
+        // Parse every role before starting any, so that a typo doesn't leave
+        // a half started process behind.
+        let proxy = roles
+            .proxy
+            .as_ref()
+            .map(|role| parse::<super::Proxy>("proxy", role))
+            .transpose()?;
+        let manage = roles
+            .manage
+            .as_ref()
+            .map(|role| parse::<super::Manage>("manage", role))
+            .transpose()?;
+
+        let mut tasks = Vec::new();
+        if let Some(proxy) = proxy {
+            tracing::info!(role = "proxy", "starting role");
+            let config = config.clone();
+            let shutdown_rx = shutdown_rx.clone();
+            tasks.push(tokio::spawn(
+                async move { proxy.run(config, shutdown_rx).await },
+            ));
+        }
+        if let Some(manage) = manage {
+            tracing::info!(role = "manage", "starting role");
+            let config = config.clone();
+            let mut shutdown_rx = shutdown_rx.clone();
+            tasks.push(tokio::spawn(async move {
+                tokio::select! {
+                    result = manage.manage(config) => result,
+                    _ = shutdown_rx.changed() => Ok(()),
+                }
+            }));
+        }
+
+        let (result, _, remaining) = futures::future::select_all(tasks).await;
+        // The other roles are stopping too when shutting down, so they're
+        // waited for rather than aborted.
+        let shutting_down = shutdown_rx.has_changed().unwrap_or(true);
+        for task in remaining {
+            if shutting_down {
+                task.await.ok();
+            } else {
+                task.abort();
+            }
+        }
+
+        result?
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cli::{Manage, Proxy};
+
+    #[test]
+    fn parse_roles() {
+        let config = Config::from_reader(
+            "
+version: v1alpha1
+roles:
+  proxy:
+    args: [--port, '7778']
+  manage:
+    args: [--port, '7801', file, config.yaml]
+"
+            .as_bytes(),
+        )
+        .unwrap();
+        let roles = &config.roles;
+
+        assert!(matches!(Run::admin_mode(&config), Some(Mode::Proxy)));
+        let proxy: Proxy = parse("proxy", roles.proxy.as_ref().unwrap()).unwrap();
+        assert_eq!(7778, proxy.port);
+        assert!(parse::<Manage>("manage", roles.manage.as_ref().unwrap()).is_ok());
+        let bogus = Role {
+            args: vec!["--bogus".into()],
+        };
+        assert!(parse::<Proxy>("proxy", &bogus).is_err());
+
+        let mut config = Config::default();
+        assert!(Run::admin_mode(&config).is_none());
+        // A management server needs a provider.
+        config.roles.manage = Some(Role::default());
+        assert!(parse::<Manage>("manage", config.roles.manage.as_ref().unwrap()).is_err());
+        assert!(matches!(Run::admin_mode(&config), Some(Mode::Xds)));
+        config.roles.admin = false;
+        assert!(Run::admin_mode(&config).is_none());
+    }
+}
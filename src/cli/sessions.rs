@@ -0,0 +1,90 @@
+/*
+ * Copyright 2023 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::{io::Write, path::PathBuf};
+
+use crate::proxy::journal;
+
+/// Inspect the sessions recorded in a proxy's session journal.
+#[derive(clap::Args, Clone)]
+pub struct Sessions {
+    #[clap(subcommand)]
+    pub command: SessionsCommand,
+}
+
+#[derive(Clone, clap::Subcommand)]
+pub enum SessionsCommand {
+    /// Prints the matching journal records as JSON, one per line, from the
+    /// oldest to the most recent.
+    Query(Query),
+}
+
+#[derive(clap::Args, Clone)]
+pub struct Query {
+    /// The path of the session journal.
+    #[clap(short, long, env = "QUILKIN_SESSION_JOURNAL")]
+    pub journal: PathBuf,
+    /// Only sessions from this client IP or socket address.
+    #[clap(long)]
+    pub source: Option<String>,
+    /// Only sessions to this endpoint IP or socket address.
+    #[clap(long)]
+    pub dest: Option<String>,
+    /// Only sessions to endpoints in this cluster.
+    #[clap(long)]
+    pub cluster: Option<String>,
+    /// Only records of this event.
+    #[clap(long, value_enum)]
+    pub event: Option<journal::Event>,
+    /// Only records at or after this time, in seconds since the UNIX epoch.
+    #[clap(long)]
+    pub since: Option<u64>,
+    /// Only the most recent matching records.
+    #[clap(short, long)]
+    pub limit: Option<usize>,
+}
+
+impl Sessions {
+    pub fn run(&self) -> crate::Result<()> {
+        match &self.command {
+            SessionsCommand::Query(query) => query.run(),
+        }
+    }
+}
+
+impl Query {
+    fn run(&self) -> crate::Result<()> {
+        let records = journal::query(
+            &self.journal,
+            &journal::Query {
+                source: self.source.clone(),
+                dest: self.dest.clone(),
+                cluster: self.cluster.clone(),
+                event: self.event,
+                since_ms: self.since.map(|since| since.saturating_mul(1000)),
+                limit: self.limit,
+            },
+        )?;
+
+        let mut stdout = std::io::stdout().lock();
+        for record in records {
+            serde_json::to_writer(&mut stdout, &record)?;
+            writeln!(stdout)?;
+        }
+
+        Ok(())
+    }
+}
@@ -17,13 +17,34 @@
 use once_cell::sync::Lazy;
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    sync::Arc,
+};
+use tokio::{sync::broadcast, time::Instant};
 
-use crate::endpoint::{Endpoint, EndpointAddress, Locality, LocalityEndpoints, LocalitySet};
+mod clusters;
+pub(crate) mod dns;
+pub(crate) mod health;
+pub(crate) mod preflight;
 
-const DEFAULT_CLUSTER_NAME: &str = "default";
-const SUBSYSTEM: &str = "cluster";
+pub use self::clusters::Clusters;
+pub use health::{HealthCheck, Probe};
+
+use crate::endpoint::{
+    DistanceStrategy, DuplicatePreference, Endpoint, EndpointAddress, Locality, LocalityDistance,
+    LocalityEndpoints, LocalitySet, Origin,
+};
 
+pub(crate) const DEFAULT_CLUSTER_NAME: &str = "default";
+
+/// Separates a cluster's namespace from the rest of its name, e.g.
+/// `studio-a/us-east-1` is the `us-east-1` cluster in the `studio-a` namespace.
+pub const NAMESPACE_SEPARATOR: char = '/';
+const SUBSYSTEM: &str = "cluster";
+/// The number of changes a subscriber can fall behind by before it misses
+/// changes.
+const CHANGES_CAPACITY: usize = 1024;
 pub(crate) fn active_clusters() -> &'static prometheus::IntGauge {
     static ACTIVE_CLUSTERS: Lazy<prometheus::IntGauge> = Lazy::new(|| {
         crate::metrics::register(
@@ -54,11 +75,490 @@ pub(crate) fn active_endpoints() -> &'static prometheus::IntGauge {
     &ACTIVE_ENDPOINTS
 }
 
+pub(crate) fn pinned_conflicts_total(cluster: &str) -> prometheus::IntCounter {
+    static PINNED_CONFLICTS_TOTAL: Lazy<prometheus::IntCounterVec> = Lazy::new(|| {
+        crate::metrics::register(
+            prometheus::IntCounterVec::new(
+                crate::metrics::opts(
+                    "pinned_conflicts_total",
+                    SUBSYSTEM,
+                    "Total number of management server updates ignored because they would replace a pinned cluster. Labels: cluster",
+                ),
+                &["cluster"],
+            )
+            .unwrap(),
+        )
+    });
+
+    PINNED_CONFLICTS_TOTAL.with_label_values(&[cluster])
+}
+
+pub(crate) fn duplicate_endpoints_total(cluster: &str) -> prometheus::IntCounter {
+    static DUPLICATE_ENDPOINTS_TOTAL: Lazy<prometheus::IntCounterVec> = Lazy::new(|| {
+        crate::metrics::register(
+            prometheus::IntCounterVec::new(
+                crate::metrics::opts(
+                    "duplicate_endpoints_total",
+                    SUBSYSTEM,
+                    "Total number of endpoints removed because their address was already in another locality of their cluster. Labels: cluster",
+                ),
+                &["cluster"],
+            )
+            .unwrap(),
+        )
+    });
+
+    DUPLICATE_ENDPOINTS_TOTAL.with_label_values(&[cluster])
+}
+
+pub(crate) fn invalid_endpoints_total(cluster: &str) -> prometheus::IntCounter {
+    static INVALID_ENDPOINTS_TOTAL: Lazy<prometheus::IntCounterVec> = Lazy::new(|| {
+        crate::metrics::register(
+            prometheus::IntCounterVec::new(
+                crate::metrics::opts(
+                    "invalid_endpoints_total",
+                    SUBSYSTEM,
+                    "Total number of endpoints from management servers that couldn't be converted. Labels: cluster",
+                ),
+                &["cluster"],
+            )
+            .unwrap(),
+        )
+    });
+
+    INVALID_ENDPOINTS_TOTAL.with_label_values(&[cluster])
+}
+
 #[derive(Clone, Default, Debug, Eq, PartialEq, Deserialize, Serialize, JsonSchema)]
 pub struct Cluster {
     #[serde(skip, default = "default_cluster_name")]
     pub name: String,
     pub localities: LocalitySet,
+    /// Encrypts traffic sent to the cluster's endpoints with DTLS, if set.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dtls: Option<Dtls>,
+    /// The firewall mark (`SO_MARK`) set on sockets sending to the cluster's
+    /// endpoints, for policy routing. Only supported on Linux.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fwmark: Option<u32>,
+    /// Normalises the IP headers of packets sent to the cluster's endpoints.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub normalize: Option<Normalize>,
+    /// The local address and interface of the sockets sending to the
+    /// cluster's endpoints, overriding the proxy's `--upstream-address` and
+    /// `--upstream-interface`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub upstream_bind: Option<UpstreamBind>,
+    /// Paces the packets each session sends back to its client, if set.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pacing: Option<Pacing>,
+    /// Keeps the cluster as it's configured in the config file, ignoring
+    /// updates to it from management servers.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub pinned: bool,
+    /// Limits for the sessions to the cluster's endpoints, if set.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sessions: Option<SessionSettings>,
+    /// Which locality keeps an address that appears in several of the
+    /// cluster's localities, preferring localities by default.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub duplicate_endpoints: Option<DuplicatePreference>,
+    /// Fails new sessions over from the cluster's local endpoints to those
+    /// of other localities once the local endpoints are at capacity, if set.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub failover: Option<Failover>,
+    /// Copies a share of the packets of the cluster's sessions to the
+    /// proxy's sampling sink, if set.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sampling: Option<Sampling>,
+}
+
+/// The settings for DTLS connections to a cluster's endpoints.
+#[derive(Clone, Default, Debug, Eq, PartialEq, Hash, Deserialize, Serialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct Dtls {
+    /// A PEM file of the certificate authorities trusted to sign the
+    /// endpoints' certificates, the system's trust store is used if unset.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ca_file: Option<std::path::PathBuf>,
+    /// The name the endpoints' certificates must be valid for, which is also
+    /// sent with SNI. Required unless `insecure_skip_verify` is set.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub server_name: Option<String>,
+    /// Disables validation of the endpoints' certificates, only meant for
+    /// testing.
+    #[serde(default)]
+    pub insecure_skip_verify: bool,
+}
+
+impl Dtls {
+    /// Checks that the endpoints' certificates are verified against a name,
+    /// as any certificate issued by a trusted authority, for any name, would
+    /// be accepted otherwise.
+    pub fn validate(&self) -> Result<(), &'static str> {
+        if self.server_name.is_none() && !self.insecure_skip_verify {
+            return Err("`dtls.server_name` is required unless `insecure_skip_verify` is set");
+        }
+
+        Ok(())
+    }
+}
+
+/// How the IP headers of packets sent to a cluster's endpoints are normalised,
+/// so that they don't depend on the proxy that sent them.
+#[derive(Clone, Copy, Default, Debug, Eq, PartialEq, Hash, Deserialize, Serialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct Normalize {
+    /// The TTL (IPv4) or hop limit (IPv6) of every packet, the system's
+    /// default if unset.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hop_limit: Option<u8>,
+    /// Sends IPv6 packets with a flow label of zero, rather than one derived
+    /// from each session, so routers balancing traffic across equal cost
+    /// paths hash every session on the same fields. Only supported on Linux.
+    #[serde(default)]
+    pub clear_flow_label: bool,
+    /// How UDP checksums are handled, to work around virtual NICs with
+    /// broken checksum offload.
+    #[serde(default)]
+    pub checksum: Checksum,
+}
+
+/// How the UDP checksums of packets to and from a cluster's endpoints are
+/// handled.
+#[derive(Clone, Copy, Default, Debug, Eq, PartialEq, Hash, Deserialize, Serialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Checksum {
+    /// The kernel (or the NIC it offloads to) computes and verifies
+    /// checksums as usual.
+    #[default]
+    Kernel,
+    /// Sends IPv4 packets without a checksum, so that a NIC that corrupts the
+    /// checksums it computes can't get them dropped, and accepts IPv6 packets
+    /// with a zero checksum, which the kernel otherwise drops. Only supported
+    /// on Linux.
+    Omit,
+}
+
+/// Where the sockets sending to a cluster's endpoints are bound, for hosts
+/// with several addresses or interfaces where replies are only routed back
+/// to a specific source address.
+#[derive(Clone, Default, Debug, Eq, PartialEq, Hash, Deserialize, Serialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct UpstreamBind {
+    /// The source address of packets sent to IPv4 endpoints, chosen by the
+    /// kernel if unset.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ipv4: Option<std::net::Ipv4Addr>,
+    /// The source address of packets sent to IPv6 endpoints, chosen by the
+    /// kernel if unset.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ipv6: Option<std::net::Ipv6Addr>,
+    /// The network interface (`SO_BINDTODEVICE`) packets are sent from,
+    /// which requires `CAP_NET_RAW`. Only supported on Linux.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub interface: Option<String>,
+}
+
+impl UpstreamBind {
+    /// Returns the settings of `self`, with those it doesn't set taken from
+    /// `defaults`.
+    pub fn or(&self, defaults: &Self) -> Self {
+        Self {
+            ipv4: self.ipv4.or(defaults.ipv4),
+            ipv6: self.ipv6.or(defaults.ipv6),
+            interface: self
+                .interface
+                .clone()
+                .or_else(|| defaults.interface.clone()),
+        }
+    }
+
+    /// Returns the local address to bind a socket sending to `dest` to, in
+    /// the same address family as `dest`.
+    pub fn address_for(&self, dest: std::net::SocketAddr) -> std::net::SocketAddr {
+        match dest {
+            std::net::SocketAddr::V4(_) => {
+                (self.ipv4.unwrap_or(std::net::Ipv4Addr::UNSPECIFIED), 0).into()
+            }
+            std::net::SocketAddr::V6(_) => {
+                (self.ipv6.unwrap_or(std::net::Ipv6Addr::UNSPECIFIED), 0).into()
+            }
+        }
+    }
+}
+
+/// The rate at which each session of a cluster sends packets back to its
+/// client, so that bursts from an endpoint (such as a large world snapshot)
+/// don't overflow the client's downlink.
+#[derive(Clone, Copy, Default, Debug, Eq, PartialEq, Hash, Deserialize, Serialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct Pacing {
+    /// The sustained rate of each session, in bytes per second.
+    pub bytes_per_second: u64,
+    /// The number of bytes a session can send at once before being paced,
+    /// one second's worth if unset.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub burst_bytes: Option<u64>,
+    /// The longest a packet is delayed for, packets that would be delayed for
+    /// longer are dropped.
+    #[serde(default = "default_pacing_max_delay_ms")]
+    pub max_delay_ms: u64,
+}
+
+fn default_pacing_max_delay_ms() -> u64 {
+    50
+}
+
+/// How new sessions fail over to the endpoints of other localities, such as
+/// those registered with peered management servers.
+#[derive(Clone, Default, Debug, Eq, PartialEq, Deserialize, Serialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct Failover {
+    /// The maximum number of sessions to the cluster's local endpoints, which
+    /// are those in localities without a weight, before new sessions fail
+    /// over.
+    pub capacity: u32,
+    /// The share of the failed over sessions each locality receives, keyed by
+    /// `region`, `region/zone` or `region/zone/sub_zone`, with the most
+    /// specific key taking precedence. Localities with a weight of zero are
+    /// neither local nor failed over to.
+    pub weights: BTreeMap<String, u32>,
+    /// Only fails over to the weighted localities nearest to the client, by
+    /// this distance function, if set. The weights then share the clients
+    /// between localities at the same distance.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub distance: Option<DistanceStrategy>,
+}
+
+impl Failover {
+    /// The weight of `locality`, `None` for local endpoints.
+    pub fn weight(&self, locality: Option<&Locality>) -> Option<u32> {
+        crate::endpoint::locality_keys(locality?).find_map(|key| self.weights.get(&key).copied())
+    }
+
+    /// Chooses the endpoint a new session from `source` fails over to, from
+    /// the localities with a weight, `local` being the locality of the
+    /// endpoint it would have been sent to. The choice only depends on
+    /// `source`, `local` and the available endpoints, so a client keeps
+    /// failing over to the same endpoint.
+    pub fn choose<'endpoints>(
+        &self,
+        source: &EndpointAddress,
+        local: Option<&Locality>,
+        localities: impl IntoIterator<Item = &'endpoints LocalityEndpoints>,
+    ) -> Option<&'endpoints Endpoint> {
+        // Weighted rendezvous hashing, so that each locality receives its
+        // share of clients, and few clients move when localities change.
+        let score = |locality: &Option<Locality>, weight: u32| {
+            let unit = (crate::utils::stable_hash((source, locality)) as f64 + 1.0)
+                / (u64::MAX as f64 + 2.0);
+            -unit.ln() / f64::from(weight)
+        };
+
+        let mut candidates = localities
+            .into_iter()
+            .filter(|endpoints| !endpoints.endpoints.is_empty())
+            .filter_map(|endpoints| {
+                let weight = self.weight(endpoints.locality.as_ref())?;
+                (weight > 0).then_some((weight, endpoints))
+            })
+            .collect::<Vec<_>>();
+        if let Some(strategy) = &self.distance {
+            let origin = source
+                .to_socket_addr()
+                .map(|address| Origin::new(address.ip()))
+                .unwrap_or_default()
+                .with_locality(local.cloned());
+            candidates = nearest(&*strategy.as_locality_distance(), &origin, candidates);
+        }
+
+        let (_, locality) = candidates
+            .into_iter()
+            .map(|(weight, endpoints)| (score(&endpoints.locality, weight), endpoints))
+            .min_by(|(a, _), (b, _)| a.total_cmp(b))?;
+
+        locality
+            .endpoints
+            .iter()
+            .min_by_key(|endpoint| crate::utils::stable_hash((source, &endpoint.address)))
+    }
+}
+
+/// How the packets of a cluster's sessions are sampled, which can also be set
+/// under `sampling` in the `quilkin.dev` metadata of an xDS `Cluster`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash, Deserialize, Serialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct Sampling {
+    /// Samples one in every `rate` packets, in each direction.
+    pub rate: u32,
+    /// The most bytes of each packet's payload that are copied, the rest of
+    /// the packet is only counted in the sample's `size`.
+    #[serde(default = "default_sampling_max_payload_bytes")]
+    pub max_payload_bytes: u32,
+    /// Replaces the addresses of clients and endpoints with a salted hash, so
+    /// that samples can be correlated without identifying players.
+    #[serde(default = "default_sampling_hash_addresses")]
+    pub hash_addresses: bool,
+}
+
+fn default_sampling_max_payload_bytes() -> u32 {
+    64
+}
+
+fn default_sampling_hash_addresses() -> bool {
+    true
+}
+
+impl Sampling {
+    /// Reads the sampling settings from the metadata of an xDS `Cluster`,
+    /// returning `None` if it has none.
+    pub(crate) fn from_xds(
+        cluster: &crate::xds::config::cluster::v3::Cluster,
+    ) -> crate::Result<Option<Self>> {
+        let Some(sampling) = cluster
+            .metadata
+            .as_ref()
+            .and_then(|metadata| metadata.filter_metadata.get(crate::metadata::KEY))
+            .and_then(|known| known.fields.get("sampling"))
+            .and_then(|sampling| sampling.kind.clone())
+        else {
+            return Ok(None);
+        };
+
+        let sampling: Self = serde_json::from_value(crate::prost::value_from_kind(sampling))
+            .map_err(|error| {
+                eyre::eyre!("invalid sampling for cluster `{}`: {error}", cluster.name)
+            })?;
+
+        Ok(Some(sampling))
+    }
+
+    fn to_xds(self) -> crate::xds::config::core::v3::Metadata {
+        let known = crate::prost::struct_from_json(serde_json::json!({
+            "sampling": serde_json::to_value(self).unwrap(),
+        }))
+        .unwrap_or_default();
+
+        crate::xds::config::core::v3::Metadata {
+            filter_metadata: [(crate::metadata::KEY.to_owned(), known)].into(),
+            ..<_>::default()
+        }
+    }
+}
+
+/// Keeps the `candidates` nearest to `origin` by `distance`, or all of them if
+/// none has a distance.
+fn nearest<'endpoints>(
+    distance: &dyn LocalityDistance,
+    origin: &Origin,
+    candidates: Vec<(u32, &'endpoints LocalityEndpoints)>,
+) -> Vec<(u32, &'endpoints LocalityEndpoints)> {
+    let distances = candidates
+        .iter()
+        .map(|(_, endpoints)| distance.distance(origin, endpoints.locality.as_ref()))
+        .collect::<Vec<_>>();
+    let Some(nearest) = distances.iter().flatten().copied().min_by(f64::total_cmp) else {
+        return candidates;
+    };
+
+    candidates
+        .into_iter()
+        .zip(distances)
+        .filter(|(_, distance)| *distance == Some(nearest))
+        .map(|(candidate, _)| candidate)
+        .collect()
+}
+
+/// The settings of the sessions to a cluster's endpoints, which can also be set
+/// with the `connect_timeout`, `circuit_breakers`,
+/// `common_http_protocol_options.idle_timeout` and
+/// `common_http_protocol_options.max_connection_duration` fields of an xDS
+/// `Cluster`.
+#[derive(Clone, Copy, Default, Debug, Eq, PartialEq, Hash, Deserialize, Serialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct SessionSettings {
+    /// How long a session lasts without traffic from its client, the proxy's
+    /// default if unset.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub idle_timeout_ms: Option<u64>,
+    /// How long establishing a session (such as its DTLS handshake) can take
+    /// before its packet is dropped.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub connect_timeout_ms: Option<u64>,
+    /// The maximum number of sessions to the cluster's endpoints at once,
+    /// packets from further clients are dropped until a session ends.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_sessions: Option<u32>,
+    /// How long a session keeps its upstream socket before replacing it with
+    /// a new one, so that NATs and firewalls between the proxy and the
+    /// endpoint don't expire the socket's mapping. Sockets are kept for the
+    /// whole session if unset.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_socket_age_ms: Option<u64>,
+}
+
+impl SessionSettings {
+    pub fn idle_timeout(&self) -> Option<std::time::Duration> {
+        self.idle_timeout_ms.map(std::time::Duration::from_millis)
+    }
+
+    pub fn connect_timeout(&self) -> Option<std::time::Duration> {
+        self.connect_timeout_ms
+            .map(std::time::Duration::from_millis)
+    }
+
+    pub fn max_socket_age(&self) -> Option<std::time::Duration> {
+        self.max_socket_age_ms
+            .filter(|age| *age > 0)
+            .map(std::time::Duration::from_millis)
+    }
+
+    /// Reads the settings from an xDS `Cluster`, returning `None` if it has
+    /// none of them. Only the circuit breaker thresholds of the default
+    /// priority are used, as sessions have no priority.
+    pub(crate) fn from_xds(cluster: &crate::xds::config::cluster::v3::Cluster) -> Option<Self> {
+        use crate::xds::config::core::v3::RoutingPriority;
+
+        #[allow(deprecated)]
+        let options = cluster.common_http_protocol_options.as_ref();
+
+        let settings = Self {
+            idle_timeout_ms: options
+                .and_then(|options| options.idle_timeout.as_ref())
+                .and_then(duration_ms),
+            connect_timeout_ms: cluster.connect_timeout.as_ref().and_then(duration_ms),
+            max_sessions: cluster
+                .circuit_breakers
+                .as_ref()
+                .and_then(|breakers| {
+                    breakers
+                        .thresholds
+                        .iter()
+                        .find(|thresholds| thresholds.priority == RoutingPriority::Default as i32)
+                })
+                .and_then(|thresholds| thresholds.max_connections),
+            max_socket_age_ms: options
+                .and_then(|options| options.max_connection_duration.as_ref())
+                .and_then(duration_ms),
+        };
+
+        (settings != Self::default()).then_some(settings)
+    }
+}
+
+/// Converts a protobuf duration to milliseconds, unless it's negative.
+fn duration_ms(duration: &prost_types::Duration) -> Option<u64> {
+    let seconds = u64::try_from(duration.seconds).ok()?;
+    let millis = u64::try_from(duration.nanos).ok()? / 1_000_000;
+    seconds.checked_mul(1000)?.checked_add(millis)
+}
+
+fn ms_duration(ms: u64) -> prost_types::Duration {
+    prost_types::Duration {
+        seconds: (ms / 1000) as i64,
+        nanos: ((ms % 1000) * 1_000_000) as i32,
+    }
 }
 
 impl Cluster {
@@ -67,6 +567,16 @@ impl Cluster {
         Self {
             name,
             localities: localities.into(),
+            dtls: None,
+            fwmark: None,
+            normalize: None,
+            upstream_bind: None,
+            pacing: None,
+            pinned: false,
+            sessions: None,
+            duplicate_endpoints: None,
+            failover: None,
+            sampling: None,
         }
     }
 
@@ -75,6 +585,54 @@ impl Cluster {
         Self::new("default".into(), endpoints)
     }
 
+    /// Keeps every address in only one of the cluster's localities, so that
+    /// packets aren't sent to it twice, returning the number of endpoints
+    /// removed.
+    pub fn dedup_endpoints(&mut self) -> usize {
+        let removed = self
+            .localities
+            .dedup(self.duplicate_endpoints.unwrap_or_default());
+        if removed > 0 {
+            tracing::debug!(cluster = %self.name, removed, "removed duplicate endpoints");
+            duplicate_endpoints_total(&self.name).inc_by(removed as u64);
+        }
+
+        removed
+    }
+
+    /// Whether `address` is one of the cluster's local endpoints, which are
+    /// those that aren't failed over to. Always `false` for clusters without
+    /// [`Failover`].
+    pub fn is_local_endpoint(&self, address: &EndpointAddress) -> bool {
+        let Some(failover) = &self.failover else {
+            return false;
+        };
+
+        self.localities
+            .iter()
+            .filter(|endpoints| failover.weight(endpoints.locality.as_ref()).is_none())
+            .any(|endpoints| {
+                endpoints
+                    .endpoints
+                    .iter()
+                    .any(|endpoint| endpoint.address.is_same(address))
+            })
+    }
+
+    /// Returns the locality of the cluster's endpoint with `address`, if it
+    /// has one.
+    pub fn locality_of_endpoint(&self, address: &EndpointAddress) -> Option<&Locality> {
+        self.localities
+            .iter()
+            .find(|endpoints| {
+                endpoints
+                    .endpoints
+                    .iter()
+                    .any(|endpoint| endpoint.address.is_same(address))
+            })
+            .and_then(|endpoints| endpoints.locality.as_ref())
+    }
+
     /// Adds a new set of endpoints to the cluster.
     pub fn insert(&mut self, endpoints: impl Into<LocalityEndpoints>) {
         self.localities.insert(endpoints.into());
@@ -93,12 +651,76 @@ impl Cluster {
             .iter()
             .flat_map(|locality| locality.endpoints.iter())
     }
+
+    /// Returns the namespace (or tenant) the cluster belongs to, if any.
+    pub fn namespace(&self) -> Option<&str> {
+        namespace_of(&self.name)
+    }
+}
+
+/// Returns the namespace portion of the cluster name `name`, if it has one.
+pub fn namespace_of(name: &str) -> Option<&str> {
+    name.split_once(NAMESPACE_SEPARATOR)
+        .map(|(namespace, _)| namespace)
+        .filter(|namespace| !namespace.is_empty())
 }
 
 fn default_cluster_name() -> String {
     DEFAULT_CLUSTER_NAME.into()
 }
 
+/// A change to a cluster in a [`ClusterMap`], see [`Clusters::changes`].
+/// Endpoints are compared by cluster and address, so an endpoint that moves
+/// from one cluster to another isn't removed.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ClusterChange {
+    /// A cluster that wasn't in the map was added.
+    Added(Arc<Cluster>),
+    /// A cluster was removed from the map, along with its endpoints that
+    /// aren't in any other cluster, which are `removed`.
+    Removed {
+        cluster: Arc<Cluster>,
+        removed: Vec<EndpointAddress>,
+    },
+    /// A cluster's endpoints or settings changed, `removed` being the
+    /// addresses of the endpoints that left it and aren't in any other
+    /// cluster.
+    Updated {
+        previous: Arc<Cluster>,
+        current: Arc<Cluster>,
+        removed: Vec<EndpointAddress>,
+    },
+    /// The hostname of one of a cluster's endpoints resolved to a different
+    /// address, so sessions to its previous address are stale.
+    Resolved {
+        cluster: Arc<Cluster>,
+        address: EndpointAddress,
+    },
+}
+
+impl ClusterChange {
+    /// The name of the changed cluster.
+    pub fn name(&self) -> &str {
+        match self {
+            Self::Added(cluster) | Self::Removed { cluster, .. } => &cluster.name,
+            Self::Updated { current, .. } => &current.name,
+            Self::Resolved { cluster, .. } => &cluster.name,
+        }
+    }
+
+    /// The addresses of the endpoints that are no longer in any cluster, or
+    /// that no longer resolve to the address their sessions were made with.
+    pub fn removed_endpoints(&self) -> Vec<&EndpointAddress> {
+        match self {
+            Self::Added(_) => Vec::new(),
+            Self::Resolved { address, .. } => vec![address],
+            Self::Removed { removed, .. } | Self::Updated { removed, .. } => {
+                removed.iter().collect()
+            }
+        }
+    }
+}
+
 /// Represents a full snapshot of all clusters.
 #[derive(Clone, Default, Debug, Serialize, PartialEq, Eq, JsonSchema)]
 pub struct ClusterMap(HashMap<String, Cluster>);
@@ -109,7 +731,9 @@ impl ClusterMap {
         Self::from_iter([Cluster::new_default(vec![localities.into()])])
     }
 
-    pub fn insert(&mut self, cluster: Cluster) -> Option<Cluster> {
+    /// Inserts `cluster`, removing its duplicate endpoints.
+    pub fn insert(&mut self, mut cluster: Cluster) -> Option<Cluster> {
+        cluster.dedup_endpoints();
         self.0.insert(cluster.name.clone(), cluster)
     }
 
@@ -164,6 +788,126 @@ impl ClusterMap {
             .flat_map(|locality| locality.endpoints.clone())
     }
 
+    /// Returns every endpoint, with their weights scaled by their
+    /// locality's, see [`Clusters::healthy_endpoints`] for those packets can
+    /// be routed to.
+    pub fn weighted_endpoints(&self) -> Vec<Endpoint> {
+        self.localities()
+            .flat_map(LocalityEndpoints::weighted_endpoints)
+            .collect()
+    }
+
+    /// Returns the first cluster containing an endpoint with `address`.
+    pub fn cluster_of_endpoint(&self, address: &EndpointAddress) -> Option<&Cluster> {
+        self.0.values().find(|cluster| {
+            cluster
+                .endpoints()
+                .any(|endpoint| endpoint.address.is_same(address))
+        })
+    }
+
+    /// Returns the changes that turn `previous` into `current`.
+    pub fn diff(previous: &Self, current: &Self) -> Vec<ClusterChange> {
+        let addresses: HashSet<&EndpointAddress> = current
+            .values()
+            .flat_map(Cluster::endpoints)
+            .map(|endpoint| &endpoint.address)
+            .collect();
+        // The endpoints of `cluster` that are neither in `remaining`, the
+        // current version of the cluster, nor in any other current cluster.
+        let removed = |cluster: &Cluster, remaining: Option<&Cluster>| -> Vec<EndpointAddress> {
+            cluster
+                .endpoints()
+                .map(|endpoint| &endpoint.address)
+                .filter(|address| {
+                    !addresses.contains(*address)
+                        && !remaining.map_or(false, |remaining| {
+                            remaining
+                                .endpoints()
+                                .any(|endpoint| endpoint.address == **address)
+                        })
+                })
+                .cloned()
+                .collect()
+        };
+
+        let mut changes = Vec::new();
+        for (name, cluster) in &current.0 {
+            match previous.get(name) {
+                None => changes.push(ClusterChange::Added(Arc::new(cluster.clone()))),
+                Some(previous) if previous != cluster => changes.push(ClusterChange::Updated {
+                    previous: Arc::new(previous.clone()),
+                    current: Arc::new(cluster.clone()),
+                    removed: removed(previous, Some(cluster)),
+                }),
+                Some(_) => {}
+            }
+        }
+
+        changes.extend(
+            previous
+                .iter()
+                .filter(|(name, _)| !current.contains_key(*name))
+                .map(|(_, cluster)| ClusterChange::Removed {
+                    cluster: Arc::new(cluster.clone()),
+                    removed: removed(cluster, None),
+                }),
+        );
+
+        changes
+    }
+
+    /// Sends the changes between `previous` and `current` to every
+    /// subscriber of `changes`. The maps are only compared when there are
+    /// subscribers.
+    pub(crate) fn publish_changes(
+        changes: &broadcast::Sender<ClusterChange>,
+        previous: &Self,
+        current: &Self,
+    ) {
+        if changes.receiver_count() == 0 {
+            return;
+        }
+
+        for change in Self::diff(previous, current) {
+            let _ = changes.send(change);
+        }
+    }
+
+    /// Returns when the endpoints that were `added` to the clusters before
+    /// `previous`, and those in `current` but not in `previous`, were added,
+    /// forgetting those that were removed. The endpoints of the first
+    /// clusters loaded into an empty map, such as when the proxy starts,
+    /// aren't recorded as added.
+    pub(crate) fn added_endpoints(
+        added: &HashMap<EndpointAddress, Instant>,
+        previous: &Self,
+        current: &Self,
+    ) -> HashMap<EndpointAddress, Instant> {
+        let addresses = |map: &Self| -> HashSet<EndpointAddress> {
+            map.values()
+                .flat_map(Cluster::endpoints)
+                .map(|endpoint| endpoint.address.clone())
+                .collect()
+        };
+        let (previous, current) = (addresses(previous), addresses(current));
+        if previous.is_empty() {
+            return HashMap::new();
+        }
+
+        let now = Instant::now();
+        let mut added: HashMap<_, _> = added
+            .iter()
+            .filter(|(address, _)| current.contains(*address))
+            .map(|(address, at)| (address.clone(), *at))
+            .collect();
+        for address in current.difference(&previous) {
+            added.entry(address.clone()).or_insert(now);
+        }
+
+        added
+    }
+
     pub fn contains_only_unique_endpoints(&self) -> bool {
         self.endpoints()
             .collect::<std::collections::BTreeSet<_>>()
@@ -180,7 +924,13 @@ impl<'de> Deserialize<'de> for ClusterMap {
         let mut map = HashMap::<String, Cluster>::deserialize(deserializer)?;
 
         for (key, value) in map.iter_mut() {
+            if let Some(dtls) = &value.dtls {
+                dtls.validate().map_err(|error| {
+                    serde::de::Error::custom(format!("cluster `{key}`: {error}"))
+                })?;
+            }
             value.name = key.clone();
+            value.dedup_endpoints();
         }
 
         Ok(Self(map))
@@ -210,11 +960,12 @@ impl FromIterator<Cluster> for ClusterMap {
     where
         T: IntoIterator<Item = Cluster>,
     {
-        Self(
-            iter.into_iter()
-                .map(|cluster| (cluster.name.clone(), cluster))
-                .collect(),
-        )
+        let mut map = Self::default();
+        for cluster in iter {
+            map.insert(cluster);
+        }
+
+        map
     }
 }
 
@@ -258,10 +1009,36 @@ impl From<Cluster> for crate::xds::config::endpoint::v3::ClusterLoadAssignment {
 }
 
 impl From<&'_ Cluster> for crate::xds::config::cluster::v3::Cluster {
+    // `common_http_protocol_options` is deprecated in favour of typed
+    // extension options, but remains the idle timeout control planes set.
+    #[allow(deprecated)]
     fn from(cluster: &Cluster) -> Self {
+        use crate::xds::config::{
+            cluster::v3::{circuit_breakers::Thresholds, CircuitBreakers},
+            core::v3::HttpProtocolOptions,
+        };
+
+        let sessions = cluster.sessions.unwrap_or_default();
+
         Self {
             name: cluster.name.clone(),
             load_assignment: Some(cluster.into()),
+            connect_timeout: sessions.connect_timeout_ms.map(ms_duration),
+            circuit_breakers: sessions.max_sessions.map(|max_sessions| CircuitBreakers {
+                thresholds: vec![Thresholds {
+                    max_connections: Some(max_sessions),
+                    ..<_>::default()
+                }],
+                ..<_>::default()
+            }),
+            common_http_protocol_options: (sessions.idle_timeout_ms.is_some()
+                || sessions.max_socket_age_ms.is_some())
+            .then(|| HttpProtocolOptions {
+                idle_timeout: sessions.idle_timeout_ms.map(ms_duration),
+                max_connection_duration: sessions.max_socket_age_ms.map(ms_duration),
+                ..<_>::default()
+            }),
+            metadata: cluster.sampling.map(Sampling::to_xds),
             ..Self::default()
         }
     }
@@ -281,60 +1058,510 @@ impl TryFrom<crate::xds::config::endpoint::v3::ClusterLoadAssignment> for Cluste
     type Error = eyre::Error;
 
     fn try_from(
-        mut cla: crate::xds::config::endpoint::v3::ClusterLoadAssignment,
+        cla: crate::xds::config::endpoint::v3::ClusterLoadAssignment,
     ) -> Result<Self, Self::Error> {
+        let (cluster, invalid) = Self::from_load_assignment_lossy(cla);
+        match invalid.into_iter().next() {
+            Some(invalid) => Err(invalid.into()),
+            None => Ok(cluster),
+        }
+    }
+}
+
+impl Cluster {
+    /// Converts `cla`, leaving out the endpoints that can't be converted and
+    /// returning them separately, rather than failing the whole cluster.
+    pub fn from_load_assignment_lossy(
+        mut cla: crate::xds::config::endpoint::v3::ClusterLoadAssignment,
+    ) -> (Self, Vec<InvalidEndpoint>) {
         use crate::xds::config::endpoint::v3::lb_endpoint;
 
-        let localities = cla
-            .endpoints
+        let mut invalid = Vec::new();
+        let localities = std::mem::take(&mut cla.endpoints)
             .into_iter()
-            .map(|locality| {
+            .enumerate()
+            .map(|(locality_index, locality)| {
                 let endpoints = locality
                     .lb_endpoints
                     .into_iter()
-                    .map(|endpoint| {
+                    .enumerate()
+                    .filter_map(|(index, endpoint)| {
                         let metadata = endpoint.metadata;
-                        let endpoint = match endpoint.host_identifier {
-                            Some(lb_endpoint::HostIdentifier::Endpoint(endpoint)) => Ok(endpoint),
-                            Some(lb_endpoint::HostIdentifier::EndpointName(name_reference)) => {
-                                match cla.named_endpoints.remove(&name_reference) {
-                                    Some(endpoint) => Ok(endpoint),
-                                    None => Err(eyre::eyre!(
-                                        "no endpoint found name reference {}",
-                                        name_reference
-                                    )),
+                        let endpoint = (|| -> eyre::Result<Endpoint> {
+                            let endpoint = match endpoint.host_identifier {
+                                Some(lb_endpoint::HostIdentifier::Endpoint(endpoint)) => {
+                                    Ok(endpoint)
                                 }
-                            }
-                            None => Err(eyre::eyre!("no host found for endpoint")),
-                        }?;
-
-                        // Extract the endpoint's address.
-                        let address: EndpointAddress = endpoint
-                            .address
-                            .and_then(|address| address.address)
-                            .ok_or_else(|| eyre::eyre!("No address provided."))?
-                            .try_into()?;
-
-                        let endpoint = Endpoint::with_metadata(
-                            address,
-                            metadata
-                                .map(crate::metadata::MetadataView::try_from)
-                                .transpose()?
-                                .unwrap_or_default(),
-                        );
-                        Ok(endpoint)
+                                Some(lb_endpoint::HostIdentifier::EndpointName(name_reference)) => {
+                                    match cla.named_endpoints.remove(&name_reference) {
+                                        Some(endpoint) => Ok(endpoint),
+                                        None => Err(eyre::eyre!(
+                                            "no endpoint found name reference {}",
+                                            name_reference
+                                        )),
+                                    }
+                                }
+                                None => Err(eyre::eyre!("no host found for endpoint")),
+                            }?;
+
+                            // Extract the endpoint's address.
+                            let address: EndpointAddress = endpoint
+                                .address
+                                .and_then(|address| address.address)
+                                .ok_or_else(|| eyre::eyre!("No address provided."))?
+                                .try_into()?;
+
+                            Ok(Endpoint::with_metadata(
+                                address,
+                                metadata
+                                    .map(crate::metadata::MetadataView::try_from)
+                                    .transpose()?
+                                    .unwrap_or_default(),
+                            ))
+                        })();
+
+                        endpoint
+                            .map_err(|error| {
+                                invalid.push(InvalidEndpoint {
+                                    cluster: cla.cluster_name.clone(),
+                                    locality: locality_index,
+                                    endpoint: index,
+                                    error,
+                                })
+                            })
+                            .ok()
                     })
-                    .collect::<Result<_, eyre::Error>>()?;
+                    .collect();
 
                 let locality = locality.locality.map(From::from);
 
-                Ok(LocalityEndpoints::new(endpoints).with_locality(locality))
+                LocalityEndpoints::new(endpoints).with_locality(locality)
             })
-            .collect::<Result<_, eyre::Error>>()?;
+            .collect();
 
-        Ok(Cluster {
+        let cluster = Cluster {
             name: cla.cluster_name,
             localities,
-        })
+            dtls: None,
+            fwmark: None,
+            normalize: None,
+            upstream_bind: None,
+            pacing: None,
+            pinned: false,
+            sessions: None,
+            duplicate_endpoints: None,
+            failover: None,
+            sampling: None,
+        };
+
+        (cluster, invalid)
+    }
+}
+
+/// An endpoint of a `ClusterLoadAssignment` that couldn't be converted,
+/// identified by its position in the assignment.
+#[derive(Debug, thiserror::Error)]
+#[error("invalid endpoint {endpoint} of locality {locality} in cluster `{cluster}`: {error}")]
+pub struct InvalidEndpoint {
+    pub cluster: String,
+    pub locality: usize,
+    pub endpoint: usize,
+    pub error: eyre::Error,
+}
+
+/// Every invalid endpoint of a `ClusterLoadAssignment`.
+#[derive(Debug, thiserror::Error)]
+#[error("{}", .0.iter().map(ToString::to_string).collect::<Vec<_>>().join("; "))]
+pub struct InvalidEndpoints(pub Vec<InvalidEndpoint>);
+
+/// What to do with the endpoints of a management server's
+/// `ClusterLoadAssignment` that the proxy can't convert.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum InvalidEndpointPolicy {
+    /// Rejects the whole update, keeping the cluster's previous endpoints.
+    #[default]
+    Reject,
+    /// Applies the valid endpoints, logging the invalid ones.
+    Skip,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cluster(name: &str, port: u16) -> Cluster {
+        Cluster::new(
+            name.into(),
+            vec![LocalityEndpoints::from(Endpoint::new(
+                (std::net::Ipv4Addr::LOCALHOST, port).into(),
+            ))],
+        )
+    }
+
+    #[test]
+    fn namespaces() {
+        assert_eq!(Some("studio"), namespace_of("studio/us-east-1"));
+        assert_eq!(None, namespace_of("us-east-1"));
+        assert_eq!(None, namespace_of("/us-east-1"));
+
+        assert_eq!(Some("b"), cluster("b/one", 1).namespace());
+        assert_eq!(None, cluster("default", 1).namespace());
+    }
+
+    #[test]
+    fn diff() {
+        let previous =
+            ClusterMap::from([cluster("one", 1), cluster("two", 2), cluster("three", 3)]);
+        let current = ClusterMap::from([cluster("one", 1), cluster("two", 4), cluster("four", 5)]);
+
+        let mut changes = ClusterMap::diff(&previous, &current);
+        changes.sort_by(|a, b| a.name().cmp(b.name()));
+
+        let address = |port| EndpointAddress::from((std::net::Ipv4Addr::LOCALHOST, port));
+        assert_eq!(
+            vec![
+                ClusterChange::Added(Arc::new(cluster("four", 5))),
+                ClusterChange::Removed {
+                    cluster: Arc::new(cluster("three", 3)),
+                    removed: vec![address(3)],
+                },
+                ClusterChange::Updated {
+                    previous: Arc::new(cluster("two", 2)),
+                    current: Arc::new(cluster("two", 4)),
+                    removed: vec![address(2)],
+                },
+            ],
+            changes
+        );
+
+        assert_eq!(vec![&address(2)], changes[2].removed_endpoints());
+        assert!(changes[0].removed_endpoints().is_empty());
+
+        // An endpoint moving to another cluster isn't removed.
+        let current = ClusterMap::from([cluster("one", 1), cluster("two", 3)]);
+        let mut changes = ClusterMap::diff(&previous, &current);
+        changes.sort_by(|a, b| a.name().cmp(b.name()));
+        assert_eq!(2, changes.len());
+        assert!(matches!(&changes[0], ClusterChange::Removed { .. }));
+        assert!(changes[0].removed_endpoints().is_empty());
+        assert_eq!(vec![&address(2)], changes[1].removed_endpoints());
+    }
+
+    #[tokio::test]
+    async fn changes() {
+        let config = crate::Config::default();
+        let mut changes = config.clusters.changes();
+
+        config.clusters.modify(|map| {
+            map.insert(cluster("changes/one", 1));
+        });
+        config.clusters.store(Arc::new(ClusterMap::default()));
+
+        // Other configurations' changes aren't received.
+        crate::Config::default().clusters.modify(|map| {
+            map.insert(cluster("changes/two", 2));
+        });
+
+        assert_eq!(
+            ClusterChange::Added(Arc::new(cluster("changes/one", 1))),
+            changes.recv().await.unwrap()
+        );
+        assert_eq!(
+            ClusterChange::Removed {
+                cluster: Arc::new(cluster("changes/one", 1)),
+                removed: vec![(std::net::Ipv4Addr::LOCALHOST, 1).into()],
+            },
+            changes.recv().await.unwrap()
+        );
+        assert!(changes.try_recv().is_err());
+    }
+
+    #[test]
+    fn dtls() {
+        let map: ClusterMap = serde_yaml::from_str(
+            "
+secure:
+  localities: []
+  dtls:
+    ca_file: /etc/quilkin/ca.pem
+    server_name: game.example.com
+plain:
+  localities: []
+  fwmark: 0x100
+  normalize:
+    hop_limit: 64
+    clear_flow_label: true
+    checksum: omit
+  pacing:
+    bytes_per_second: 125000
+",
+        )
+        .unwrap();
+
+        assert_eq!(
+            Some(&Dtls {
+                ca_file: Some("/etc/quilkin/ca.pem".into()),
+                server_name: Some("game.example.com".into()),
+                insecure_skip_verify: false,
+            }),
+            map.get("secure").unwrap().dtls.as_ref()
+        );
+        assert_eq!(None, map.get("plain").unwrap().dtls);
+        assert_eq!(Some(0x100), map.get("plain").unwrap().fwmark);
+        assert_eq!(
+            Some(Normalize {
+                hop_limit: Some(64),
+                clear_flow_label: true,
+                checksum: Checksum::Omit,
+            }),
+            map.get("plain").unwrap().normalize
+        );
+        assert_eq!(
+            Some(Pacing {
+                bytes_per_second: 125_000,
+                burst_bytes: None,
+                max_delay_ms: 50,
+            }),
+            map.get("plain").unwrap().pacing
+        );
+        assert!(serde_yaml::from_str::<ClusterMap>(
+            "secure: { localities: [], dtls: { verify: false } }"
+        )
+        .is_err());
+        // Without a name, any trusted certificate would be accepted.
+        assert!(serde_yaml::from_str::<ClusterMap>(
+            "secure: { localities: [], dtls: { ca_file: /etc/quilkin/ca.pem } }"
+        )
+        .is_err());
+        assert!(serde_yaml::from_str::<ClusterMap>(
+            "secure: { localities: [], dtls: { insecure_skip_verify: true } }"
+        )
+        .is_ok());
+    }
+
+    #[test]
+    fn session_settings_xds() {
+        use crate::xds::config::cluster::v3::{
+            circuit_breakers::Thresholds, CircuitBreakers, Cluster as XdsCluster,
+        };
+        use crate::xds::config::core::v3::RoutingPriority;
+
+        let mut cluster = Cluster::new_default(vec![LocalityEndpoints::from(Endpoint::new(
+            "127.0.0.1:7777".parse().unwrap(),
+        ))]);
+        let settings = SessionSettings {
+            idle_timeout_ms: Some(30_000),
+            connect_timeout_ms: Some(1500),
+            max_sessions: Some(100),
+            max_socket_age_ms: Some(600_000),
+        };
+        cluster.sessions = Some(settings);
+
+        let xds = XdsCluster::from(&cluster);
+        assert_eq!(
+            Some(prost_types::Duration {
+                seconds: 1,
+                nanos: 500_000_000
+            }),
+            xds.connect_timeout
+        );
+        assert_eq!(Some(settings), SessionSettings::from_xds(&xds));
+        assert_eq!(None, SessionSettings::from_xds(&XdsCluster::default()));
+
+        // Only the thresholds of the default priority apply to sessions.
+        let xds = XdsCluster {
+            circuit_breakers: Some(CircuitBreakers {
+                thresholds: vec![Thresholds {
+                    priority: RoutingPriority::High as i32,
+                    max_connections: Some(10),
+                    ..<_>::default()
+                }],
+                ..<_>::default()
+            }),
+            ..<_>::default()
+        };
+        assert_eq!(None, SessionSettings::from_xds(&xds));
+    }
+
+    #[test]
+    fn sampling_xds() {
+        use crate::xds::config::cluster::v3::Cluster as XdsCluster;
+
+        let mut cluster = Cluster::new_default(vec![LocalityEndpoints::from(Endpoint::new(
+            "127.0.0.1:7777".parse().unwrap(),
+        ))]);
+        let sampling = Sampling {
+            rate: 1000,
+            max_payload_bytes: 32,
+            hash_addresses: false,
+        };
+        cluster.sampling = Some(sampling);
+
+        let xds = XdsCluster::from(&cluster);
+        assert_eq!(Some(sampling), Sampling::from_xds(&xds).unwrap());
+        assert_eq!(None, Sampling::from_xds(&XdsCluster::default()).unwrap());
+
+        let mut xds = XdsCluster::default();
+        xds.metadata = Some(crate::xds::config::core::v3::Metadata {
+            filter_metadata: [(
+                crate::metadata::KEY.to_owned(),
+                crate::prost::struct_from_json(
+                    serde_json::json!({ "sampling": { "rate": "all" } }),
+                )
+                .unwrap(),
+            )]
+            .into(),
+            ..<_>::default()
+        });
+        assert!(Sampling::from_xds(&xds).is_err());
+    }
+
+    #[test]
+    fn from_load_assignment_lossy() {
+        use crate::xds::config::endpoint::v3::{ClusterLoadAssignment, LbEndpoint};
+
+        let mut cla = ClusterLoadAssignment::from(cluster("lossy", 7777));
+        cla.endpoints[0].lb_endpoints.push(LbEndpoint::default());
+
+        let (converted, invalid) = Cluster::from_load_assignment_lossy(cla.clone());
+        assert_eq!(cluster("lossy", 7777), converted);
+        assert_eq!(1, invalid.len());
+        assert_eq!(
+            "invalid endpoint 1 of locality 0 in cluster `lossy`: no host found for endpoint",
+            invalid[0].to_string()
+        );
+
+        assert_eq!(
+            invalid[0].to_string(),
+            Cluster::try_from(cla).unwrap_err().to_string()
+        );
+    }
+
+    #[test]
+    fn dedup_endpoints() {
+        let map: ClusterMap = serde_yaml::from_str(
+            "
+located:
+  localities:
+    - endpoints:
+        - address: 127.0.0.1:7777
+        - address: 127.0.0.1:7778
+    - locality: { region: us-east-1 }
+      endpoints:
+        - address: 127.0.0.1:7777
+    - locality: { region: eu-west-1 }
+      endpoints:
+        - address: 127.0.0.1:7777
+unlocated:
+  duplicate_endpoints: unlocated
+  localities:
+    - endpoints:
+        - address: 127.0.0.1:7777
+    - locality: { region: us-east-1 }
+      endpoints:
+        - address: 127.0.0.1:7777
+",
+        )
+        .unwrap();
+
+        let localities = |name: &str| {
+            let mut localities = map
+                .get(name)
+                .unwrap()
+                .localities
+                .iter()
+                .map(|locality| {
+                    (
+                        locality.locality.as_ref().map(|locality| &*locality.region),
+                        locality.endpoints.len(),
+                    )
+                })
+                .collect::<Vec<_>>();
+            localities.sort();
+            localities
+        };
+
+        assert_eq!(
+            vec![(None, 1), (Some("eu-west-1"), 1)],
+            localities("located")
+        );
+        assert_eq!(vec![(None, 1)], localities("unlocated"));
+        assert_eq!(3, map.endpoints().count());
+    }
+
+    #[test]
+    fn failover() {
+        let map: ClusterMap = serde_yaml::from_str(
+            "
+default:
+  failover:
+    capacity: 10
+    weights: { eu-west-1: 3, us-east-1/a: 1, us-east-1: 0 }
+  localities:
+    - endpoints:
+        - address: 127.0.0.1:7000
+    - locality: { region: eu-west-1 }
+      endpoints:
+        - address: 127.0.0.2:7000
+        - address: 127.0.0.2:7001
+    - locality: { region: us-east-1, zone: a }
+      endpoints:
+        - address: 127.0.0.3:7000
+    - locality: { region: us-east-1, zone: b }
+      endpoints:
+        - address: 127.0.0.4:7000
+",
+        )
+        .unwrap();
+        let cluster = map.get_default().unwrap();
+        let failover = cluster.failover.as_ref().unwrap();
+
+        assert!(cluster.is_local_endpoint(&"127.0.0.1:7000".parse().unwrap()));
+        assert!(!cluster.is_local_endpoint(&"127.0.0.2:7000".parse().unwrap()));
+        assert!(!cluster.is_local_endpoint(&"127.0.0.4:7000".parse().unwrap()));
+
+        let mut chosen = HashMap::<String, usize>::new();
+        for port in 0..1000u16 {
+            let source: EndpointAddress = (std::net::Ipv4Addr::new(10, 0, 0, 1), port).into();
+            let endpoint = failover
+                .choose(&source, None, cluster.localities.iter())
+                .unwrap();
+            assert_eq!(
+                endpoint,
+                failover
+                    .choose(&source, None, cluster.localities.iter())
+                    .unwrap()
+            );
+            *chosen.entry(endpoint.address.host.to_string()).or_default() += 1;
+        }
+
+        assert_eq!(None, chosen.get("127.0.0.1"));
+        assert_eq!(None, chosen.get("127.0.0.4"));
+        // eu-west-1 has three times the weight of us-east-1/a.
+        let (eu, us) = (chosen["127.0.0.2"], chosen["127.0.0.3"]);
+        assert!((650..850).contains(&eu), "{chosen:?}");
+        assert_eq!(1000, eu + us);
+
+        // Only the nearest weighted localities are failed over to with a
+        // distance function, here the one sharing the local region.
+        let mut nearest = failover.clone();
+        nearest.distance = Some(DistanceStrategy::ExactRegion);
+        let local = Locality {
+            region: "us-east-1".into(),
+            zone: "b".into(),
+            ..<_>::default()
+        };
+        for port in 0..100u16 {
+            let source: EndpointAddress = (std::net::Ipv4Addr::new(10, 0, 0, 1), port).into();
+            let endpoint = nearest
+                .choose(&source, Some(&local), cluster.localities.iter())
+                .unwrap();
+            assert_eq!("127.0.0.3", endpoint.address.host.to_string());
+        }
+
+        let mut without_failover = cluster.clone();
+        without_failover.failover = None;
+        assert!(!without_failover.is_local_endpoint(&"127.0.0.1:7000".parse().unwrap()));
     }
 }
@@ -0,0 +1,267 @@
+/*
+ * Copyright 2023 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::{
+    collections::{HashMap, HashSet},
+    net::SocketAddr,
+    sync::Arc,
+};
+
+use arc_swap::ArcSwap;
+use schemars::JsonSchema;
+use tokio::{sync::broadcast, time::Instant};
+
+use super::{preflight::Preflight, ClusterChange, ClusterMap};
+use crate::{
+    config::Slot,
+    endpoint::{AddressKind, Endpoint, EndpointAddress},
+};
+
+/// The clusters of a [`Config`][crate::Config], which dereferences to the
+/// [`Slot`] holding the current [`ClusterMap`], along with the state the
+/// proxy keeps about them across snapshots, such as the addresses their
+/// hostnames resolved to and the health of their endpoints. The state is
+/// shared between clones, and is neither serialized nor compared.
+#[derive(Clone)]
+pub struct Clusters {
+    slot: Slot<ClusterMap>,
+    state: Arc<State>,
+}
+
+struct State {
+    /// The addresses the hostname endpoints last resolved to.
+    resolved: ArcSwap<HashMap<EndpointAddress, SocketAddr>>,
+    /// Sends the changes made to the clusters, see [`Clusters::changes`].
+    changes: broadcast::Sender<ClusterChange>,
+    /// When the endpoints added since the clusters were first loaded were
+    /// added, until they're removed.
+    added: ArcSwap<HashMap<EndpointAddress, Instant>>,
+    /// The addresses of the endpoints failing their health check.
+    unhealthy: ArcSwap<HashSet<EndpointAddress>>,
+    /// The endpoints held until they pass their preflight.
+    preflight: Preflight,
+}
+
+impl Clusters {
+    /// Creates the clusters held by `slot`, which publishes its changes to
+    /// [`Clusters::changes`] and records when endpoints are added.
+    pub fn new(slot: Slot<ClusterMap>) -> Self {
+        let state = Arc::new(State {
+            resolved: <_>::default(),
+            changes: broadcast::channel(super::CHANGES_CAPACITY).0,
+            added: <_>::default(),
+            unhealthy: <_>::default(),
+            preflight: <_>::default(),
+        });
+        slot.on_change({
+            let state = state.clone();
+            move |previous, current| {
+                ClusterMap::publish_changes(&state.changes, previous, current);
+                state.added.store(Arc::new(ClusterMap::added_endpoints(
+                    &state.added.load(),
+                    previous,
+                    current,
+                )));
+                state.preflight.hold_added(previous, current);
+            }
+        });
+
+        Self { slot, state }
+    }
+
+    /// Returns the endpoints that packets can be routed to, skipping those
+    /// that are pending their preflight, and those failing their
+    /// [`HealthCheck`][super::HealthCheck] unless every endpoint is, with
+    /// their weights scaled by their locality's.
+    pub fn healthy_endpoints(&self) -> Vec<Endpoint> {
+        self.healthy(self.load().weighted_endpoints())
+    }
+
+    /// Removes the endpoints that packets can't be routed to from
+    /// `endpoints`, see [`Clusters::healthy_endpoints`].
+    pub(crate) fn healthy(&self, endpoints: Vec<Endpoint>) -> Vec<Endpoint> {
+        super::health::healthy(
+            endpoints,
+            &self.state.preflight.pending(),
+            &self.state.unhealthy.load(),
+        )
+    }
+
+    /// Stores the addresses of the endpoints failing their health check,
+    /// discarding the routes cached against the previous ones when they
+    /// differ.
+    pub(crate) fn store_unhealthy(&self, unhealthy: HashSet<EndpointAddress>) {
+        if **self.state.unhealthy.load() != unhealthy {
+            self.state.unhealthy.store(Arc::new(unhealthy));
+            crate::config::next_generation();
+        }
+    }
+
+    pub(crate) fn preflight(&self) -> &Preflight {
+        &self.state.preflight
+    }
+
+    /// When the endpoints added since the clusters were first loaded were
+    /// added, by their address.
+    pub(crate) fn added(&self) -> Arc<HashMap<EndpointAddress, Instant>> {
+        self.state.added.load_full()
+    }
+
+    /// Subscribes to the changes made to these clusters, so that consumers
+    /// don't have to compare every snapshot they load. Subscribers that fall
+    /// behind by more than a thousand changes receive
+    /// [`broadcast::error::RecvError::Lagged`], and should reload the whole
+    /// map.
+    pub fn changes(&self) -> broadcast::Receiver<ClusterChange> {
+        self.state.changes.subscribe()
+    }
+
+    /// Sends `change` to the subscribers of [`Clusters::changes`].
+    pub(crate) fn publish(&self, change: ClusterChange) {
+        let _ = self.state.changes.send(change);
+    }
+
+    /// Replaces the current clusters with those of `other`, if it has any.
+    pub fn try_replace(&self, other: Self) {
+        self.slot.try_replace(other.slot);
+    }
+
+    /// Returns the socket address of the endpoint at `address`, which is
+    /// the address its hostname last resolved to if it's been resolved.
+    pub fn to_socket_addr(&self, address: &EndpointAddress) -> std::io::Result<SocketAddr> {
+        if let AddressKind::Name(_) = &address.host {
+            if let Some(resolved) = self.resolved(address) {
+                return Ok(resolved);
+            }
+        }
+
+        address.to_socket_addr()
+    }
+
+    /// The address the hostname endpoint at `address` last resolved to, if
+    /// it's been resolved.
+    pub(crate) fn resolved(&self, address: &EndpointAddress) -> Option<SocketAddr> {
+        self.state.resolved.load().get(address).copied()
+    }
+
+    /// The addresses the hostname endpoints last resolved to.
+    pub(crate) fn resolutions(&self) -> Arc<HashMap<EndpointAddress, SocketAddr>> {
+        self.state.resolved.load_full()
+    }
+
+    pub(crate) fn store_resolutions(&self, resolved: HashMap<EndpointAddress, SocketAddr>) {
+        self.state.resolved.store(Arc::new(resolved));
+    }
+}
+
+impl Default for Clusters {
+    fn default() -> Self {
+        Self::new(<_>::default())
+    }
+}
+
+impl std::ops::Deref for Clusters {
+    type Target = Slot<ClusterMap>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.slot
+    }
+}
+
+impl std::fmt::Debug for Clusters {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        self.slot.fmt(f)
+    }
+}
+
+impl PartialEq for Clusters {
+    fn eq(&self, rhs: &Self) -> bool {
+        self.slot == rhs.slot
+    }
+}
+
+impl serde::Serialize for Clusters {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.slot.serialize(serializer)
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for Clusters {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Slot::deserialize(deserializer).map(Self::new)
+    }
+}
+
+impl JsonSchema for Clusters {
+    fn schema_name() -> String {
+        <Slot<ClusterMap>>::schema_name()
+    }
+
+    fn json_schema(gen: &mut schemars::gen::SchemaGenerator) -> schemars::schema::Schema {
+        <Slot<ClusterMap>>::json_schema(gen)
+    }
+
+    fn is_referenceable() -> bool {
+        <Slot<ClusterMap>>::is_referenceable()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resolved() {
+        let clusters = Clusters::default();
+        let hostname = EndpointAddress::from(("quilkin.dev".to_string(), 7777));
+        let address = SocketAddr::from(([192, 0, 2, 1], 7777));
+        assert_eq!(None, clusters.resolved(&hostname));
+
+        // Clones share the state.
+        clusters
+            .clone()
+            .store_resolutions(HashMap::from([(hostname.clone(), address)]));
+        assert_eq!(address, clusters.to_socket_addr(&hostname).unwrap());
+        assert_eq!(None, Clusters::default().resolved(&hostname));
+    }
+
+    #[tokio::test]
+    async fn added() {
+        let clusters = Clusters::default();
+        let endpoint = |port| crate::endpoint::Endpoint::new(([127, 0, 0, 1], port).into());
+
+        // The first endpoints loaded aren't added.
+        clusters.store(Arc::new(ClusterMap::new_with_default_cluster(vec![
+            endpoint(1),
+        ])));
+        assert!(clusters.added().is_empty());
+
+        clusters.store(Arc::new(ClusterMap::new_with_default_cluster(vec![
+            endpoint(1),
+            endpoint(2),
+        ])));
+        assert_eq!(
+            vec![&endpoint(2).address],
+            clusters.added().keys().collect::<Vec<_>>()
+        );
+        assert!(Clusters::default().added().is_empty());
+
+        clusters.store(Arc::new(ClusterMap::new_with_default_cluster(vec![
+            endpoint(1),
+        ])));
+        assert!(clusters.added().is_empty());
+    }
+}
@@ -0,0 +1,231 @@
+/*
+ * Copyright 2023 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Periodic resolution of the endpoints whose address is a hostname, for
+//! upstreams behind dynamic DNS or cloud load balancers whose addresses
+//! rotate.
+//!
+//! The clusters keep the hostnames, and the addresses they resolve to are
+//! cached in their [`Clusters`], so that packets aren't held up by DNS
+//! lookups. A hostname keeps its address for as long as the address is
+//! among the records the hostname resolves to, so that hostnames with
+//! several records, whose order rotates, don't move between them. When the
+//! address is no longer among them, the hostname moves to another one and a
+//! [`ClusterChange::Resolved`] is published, which closes the sessions to
+//! its previous address.
+
+use std::{
+    collections::{HashMap, HashSet},
+    net::SocketAddr,
+    sync::Arc,
+    time::Duration,
+};
+
+use once_cell::sync::Lazy;
+use tokio::time::Instant;
+
+use super::{ClusterChange, ClusterMap, Clusters};
+use crate::{
+    endpoint::{AddressKind, EndpointAddress},
+    proxy::Tasks,
+    Config,
+};
+
+fn resolution_failures_total() -> &'static prometheus::IntCounter {
+    static RESOLUTION_FAILURES_TOTAL: Lazy<prometheus::IntCounter> = Lazy::new(|| {
+        crate::metrics::register(
+            prometheus::IntCounter::with_opts(crate::metrics::opts(
+                "dns_resolution_failures_total",
+                super::SUBSYSTEM,
+                "Total number of failed resolutions of endpoint hostnames.",
+            ))
+            .unwrap(),
+        )
+    });
+
+    &RESOLUTION_FAILURES_TOTAL
+}
+
+/// Returns the addresses of the endpoints of `clusters` that are hostnames.
+fn hostnames(clusters: &ClusterMap) -> HashSet<EndpointAddress> {
+    clusters
+        .endpoints()
+        .map(|endpoint| endpoint.address)
+        .filter(|address| matches!(address.host, AddressKind::Name(_)))
+        .collect()
+}
+
+/// Returns every address `address` resolves to.
+async fn resolve(address: &EndpointAddress) -> std::io::Result<HashSet<SocketAddr>> {
+    let AddressKind::Name(name) = &address.host else {
+        return address.to_socket_addr().map(|address| HashSet::from([address]));
+    };
+
+    let records: HashSet<_> = tokio::net::lookup_host((&**name, address.port()))
+        .await?
+        .collect();
+    if records.is_empty() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::NotFound,
+            "no records found",
+        ));
+    }
+
+    Ok(records)
+}
+
+/// Returns the address a hostname resolving to `records` uses, which is
+/// `previous` while it's among them, and otherwise the lowest of them, so
+/// that the choice doesn't depend on the order of the records.
+fn choose(previous: Option<SocketAddr>, records: &HashSet<SocketAddr>) -> SocketAddr {
+    previous
+        .filter(|previous| records.contains(previous))
+        .or_else(|| records.iter().min().copied())
+        .expect("records are never empty")
+}
+
+/// Returns the hostnames whose address in `current` differs from the one in
+/// `previous`, leaving out those that were only resolved for the first time.
+fn changed(
+    previous: &HashMap<EndpointAddress, SocketAddr>,
+    current: &HashMap<EndpointAddress, SocketAddr>,
+) -> Vec<EndpointAddress> {
+    current
+        .iter()
+        .filter(|(address, resolved)| {
+            previous
+                .get(*address)
+                .map_or(false, |previous| previous != *resolved)
+        })
+        .map(|(address, _)| address.clone())
+        .collect()
+}
+
+/// Spawns the task resolving the hostnames of the endpoints of `config` as
+/// they're added, and again every `ttl`.
+pub(crate) fn spawn(config: Arc<Config>, ttl: Duration, tasks: &Tasks) {
+    let mut changes = config.clusters.changes();
+    tasks.spawn("dns resolution", async move {
+        let mut resolved_at = HashMap::<EndpointAddress, Instant>::new();
+        loop {
+            let hostnames = hostnames(&config.clusters.load());
+            resolved_at.retain(|address, _| hostnames.contains(address));
+
+            let now = Instant::now();
+            let due: Vec<_> = hostnames
+                .iter()
+                .filter(|address| {
+                    resolved_at
+                        .get(*address)
+                        .map_or(true, |at| now.duration_since(*at) >= ttl)
+                })
+                .collect();
+
+            if !due.is_empty() {
+                let results = futures::future::join_all(
+                    due.into_iter()
+                        .map(|address| async move { (address, resolve(address).await) }),
+                )
+                .await;
+
+                let previous = config.clusters.resolutions();
+                // Hostnames that fail to resolve keep their last address.
+                let mut current: HashMap<_, _> = previous
+                    .iter()
+                    .filter(|(address, _)| hostnames.contains(*address))
+                    .map(|(address, resolved)| (address.clone(), *resolved))
+                    .collect();
+                for (address, result) in results {
+                    resolved_at.insert(address.clone(), now);
+                    match result {
+                        Ok(records) => {
+                            let resolved = choose(previous.get(address).copied(), &records);
+                            current.insert(address.clone(), resolved);
+                        }
+                        Err(error) => {
+                            resolution_failures_total().inc();
+                            tracing::warn!(%address, %error, "failed to resolve hostname");
+                        }
+                    }
+                }
+
+                let changed = changed(&previous, &current);
+                config.clusters.store_resolutions(current);
+                publish(&config.clusters, changed);
+            }
+
+            // Woken early by cluster changes, to resolve added hostnames.
+            let _ = tokio::time::timeout(ttl, changes.recv()).await;
+        }
+    });
+}
+
+/// Publishes a [`ClusterChange::Resolved`] for each of the `changed`
+/// hostnames in `clusters`.
+fn publish(clusters: &Clusters, changed: Vec<EndpointAddress>) {
+    let map = clusters.load();
+    for address in changed {
+        tracing::info!(
+            %address,
+            resolved = ?clusters.resolved(&address),
+            "hostname resolved to a new address"
+        );
+        if let Some(cluster) = map.cluster_of_endpoint(&address) {
+            clusters.publish(ClusterChange::Resolved {
+                cluster: Arc::new(cluster.clone()),
+                address,
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn changed() {
+        let hostname = EndpointAddress::from(("quilkin.dev".to_string(), 7777));
+        let added = EndpointAddress::from(("added.quilkin.dev".to_string(), 7777));
+        let address = |last: u8| SocketAddr::from(([192, 0, 2, last], 7777));
+
+        let previous = HashMap::from([(hostname.clone(), address(1))]);
+        let current = HashMap::from([(hostname.clone(), address(1)), (added, address(3))]);
+        assert!(super::changed(&previous, &current).is_empty());
+
+        let current = HashMap::from([(hostname.clone(), address(2))]);
+        assert_eq!(vec![hostname], super::changed(&previous, &current));
+    }
+
+    #[test]
+    fn choose() {
+        let address = |last: u8| SocketAddr::from(([192, 0, 2, last], 7777));
+        let records = HashSet::from([address(3), address(1), address(2)]);
+
+        assert_eq!(address(1), super::choose(None, &records));
+        // Hostnames keep their address while it's among the records.
+        assert_eq!(address(2), super::choose(Some(address(2)), &records));
+        assert_eq!(address(1), super::choose(Some(address(4)), &records));
+    }
+
+    #[tokio::test]
+    async fn resolve() {
+        let localhost = EndpointAddress::from(("localhost".to_string(), 7777));
+        let records = super::resolve(&localhost).await.unwrap();
+        assert!(records.iter().all(|address| address.ip().is_loopback()));
+        assert!(records.iter().all(|address| address.port() == 7777));
+    }
+}
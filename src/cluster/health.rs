@@ -0,0 +1,398 @@
+/*
+ * Copyright 2023 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Active health checks, which periodically probe every endpoint of the
+//! clusters so that packets aren't routed to endpoints that stopped
+//! responding before their management server notices and removes them.
+
+use std::{
+    collections::{HashMap, HashSet},
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+
+use once_cell::sync::Lazy;
+use prometheus::IntGaugeVec;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use tokio::net::{TcpStream, UdpSocket};
+
+use crate::{
+    endpoint::{Endpoint, EndpointAddress},
+    proxy::Tasks,
+    Config,
+};
+
+/// How often the config is checked for health checks while they're disabled.
+const DISABLED_INTERVAL: Duration = Duration::from_secs(5);
+
+static HTTP: Lazy<hyper::Client<hyper::client::connect::HttpConnector>> =
+    Lazy::new(|| hyper::Client::builder().build_http());
+
+fn endpoint_healthy() -> &'static IntGaugeVec {
+    static ENDPOINT_HEALTHY: Lazy<IntGaugeVec> = Lazy::new(|| {
+        crate::metrics::register(
+            IntGaugeVec::new(
+                crate::metrics::opts(
+                    "endpoint_healthy",
+                    super::SUBSYSTEM,
+                    "Whether each health checked endpoint is healthy (1) or not (0). Labels: endpoint",
+                ),
+                &["endpoint"],
+            )
+            .unwrap(),
+        )
+    });
+
+    &ENDPOINT_HEALTHY
+}
+
+/// How endpoints are health checked, disabled while `probe` is unset.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct HealthCheck {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub probe: Option<Probe>,
+    /// Holds endpoints added to the clusters as pending until they pass a
+    /// probe, see [`super::preflight`]. Uses `probe`, or an empty UDP packet
+    /// while it's unset.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub preflight: bool,
+    /// The time between probes of each endpoint.
+    #[serde(default = "default_interval_ms")]
+    pub interval_ms: u64,
+    /// How long a probe waits before failing.
+    #[serde(default = "default_timeout_ms")]
+    pub timeout_ms: u64,
+    /// The number of consecutive failed probes before a healthy endpoint is
+    /// marked unhealthy.
+    #[serde(default = "default_threshold")]
+    pub unhealthy_threshold: u32,
+    /// The number of consecutive passed probes before an unhealthy endpoint
+    /// is marked healthy again.
+    #[serde(default = "default_threshold")]
+    pub healthy_threshold: u32,
+}
+
+fn default_interval_ms() -> u64 {
+    5000
+}
+
+fn default_timeout_ms() -> u64 {
+    1000
+}
+
+fn default_threshold() -> u32 {
+    3
+}
+
+impl Default for HealthCheck {
+    fn default() -> Self {
+        Self {
+            probe: None,
+            preflight: false,
+            interval_ms: default_interval_ms(),
+            timeout_ms: default_timeout_ms(),
+            unhealthy_threshold: default_threshold(),
+            healthy_threshold: default_threshold(),
+        }
+    }
+}
+
+/// How an endpoint is probed.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq, JsonSchema)]
+#[serde(tag = "protocol", rename_all = "snake_case", deny_unknown_fields)]
+pub enum Probe {
+    /// Sends `payload` to the endpoint, passing when anything is received in
+    /// response.
+    Udp {
+        #[serde(with = "crate::config::Base64Standard")]
+        #[schemars(with = "String")]
+        payload: Vec<u8>,
+    },
+    /// Opens a TCP connection to the endpoint, or to `port` of its host.
+    Tcp {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        port: Option<u16>,
+    },
+    /// Sends an HTTP `GET` request for `path` to the endpoint, or to `port`
+    /// of its host, passing when the response status is successful.
+    Http {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        port: Option<u16>,
+        #[serde(default = "default_path")]
+        path: String,
+    },
+}
+
+fn default_path() -> String {
+    "/".into()
+}
+
+impl Probe {
+    /// Probes the endpoint at `address` of `clusters`, failing after
+    /// `timeout`.
+    pub(crate) async fn probe(
+        &self,
+        clusters: &super::Clusters,
+        address: &EndpointAddress,
+        timeout: Duration,
+    ) -> crate::Result<()> {
+        let address = clusters.to_socket_addr(address)?;
+        tokio::time::timeout(timeout, self.check(address))
+            .await
+            .unwrap_or_else(|_| Err(eyre::eyre!("timed out")))
+    }
+
+    async fn check(&self, mut address: SocketAddr) -> crate::Result<()> {
+        match self {
+            Self::Udp { payload } => {
+                let local: SocketAddr = if address.is_ipv4() {
+                    (Ipv4Addr::UNSPECIFIED, 0).into()
+                } else {
+                    (Ipv6Addr::UNSPECIFIED, 0).into()
+                };
+                let socket = UdpSocket::bind(local).await?;
+                socket.connect(address).await?;
+                socket.send(payload).await?;
+                socket.recv(&mut [0; 1]).await?;
+            }
+            Self::Tcp { port } => {
+                address.set_port(port.unwrap_or(address.port()));
+                TcpStream::connect(address).await?;
+            }
+            Self::Http { port, path } => {
+                address.set_port(port.unwrap_or(address.port()));
+                let uri: hyper::Uri = format!("http://{address}{path}").parse()?;
+                let status = HTTP.get(uri).await?.status();
+                if !status.is_success() {
+                    return Err(eyre::eyre!("unsuccessful status `{status}`"));
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// The health of an endpoint, over its latest probes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct State {
+    healthy: bool,
+    /// The number of consecutive probes with the opposite result.
+    consecutive: u32,
+}
+
+impl Default for State {
+    fn default() -> Self {
+        Self {
+            healthy: true,
+            consecutive: 0,
+        }
+    }
+}
+
+impl State {
+    /// Records the result of a probe, returning whether that changed the
+    /// endpoint's health.
+    fn record(&mut self, passed: bool, check: &HealthCheck) -> bool {
+        if passed == self.healthy {
+            self.consecutive = 0;
+            return false;
+        }
+
+        self.consecutive += 1;
+        let threshold = if self.healthy {
+            check.unhealthy_threshold
+        } else {
+            check.healthy_threshold
+        };
+        if self.consecutive < threshold.max(1) {
+            return false;
+        }
+
+        self.healthy = passed;
+        self.consecutive = 0;
+        true
+    }
+}
+
+/// Removes the endpoints still `pending` their preflight from `endpoints`,
+/// and then the `unhealthy` endpoints, unless none of them are healthy, in
+/// which case they're all kept so that a failing probe doesn't stop the proxy
+/// routing any packets.
+pub(super) fn healthy(
+    mut endpoints: Vec<Endpoint>,
+    pending: &HashSet<EndpointAddress>,
+    unhealthy: &HashSet<EndpointAddress>,
+) -> Vec<Endpoint> {
+    if !pending.is_empty() {
+        endpoints.retain(|endpoint| !pending.contains(&endpoint.address));
+    }
+
+    if unhealthy.is_empty()
+        || endpoints
+            .iter()
+            .all(|endpoint| unhealthy.contains(&endpoint.address))
+    {
+        return endpoints;
+    }
+
+    endpoints.retain(|endpoint| !unhealthy.contains(&endpoint.address));
+    endpoints
+}
+
+/// Spawns the task probing the endpoints of `config` with its health checks.
+pub(crate) fn spawn(config: Arc<Config>, tasks: &Tasks) {
+    tasks.spawn("health checks", async move {
+        let mut states = HashMap::<EndpointAddress, State>::new();
+        loop {
+            let check = config.health_check.load();
+            let Some(probe) = &check.probe else {
+                if !states.is_empty() {
+                    states.clear();
+                    publish(&config.clusters, &states);
+                }
+                tokio::time::sleep(DISABLED_INTERVAL).await;
+                continue;
+            };
+
+            let addresses: HashSet<_> = config
+                .clusters
+                .load()
+                .endpoints()
+                .map(|endpoint| endpoint.address)
+                .collect();
+            states.retain(|address, _| addresses.contains(address));
+
+            let timeout = Duration::from_millis(check.timeout_ms);
+            let clusters = &config.clusters;
+            let results =
+                futures::future::join_all(addresses.into_iter().map(|address| async move {
+                    let result = probe.probe(clusters, &address, timeout).await;
+                    (address, result)
+                }))
+                .await;
+
+            for (address, result) in results {
+                if let Err(error) = &result {
+                    tracing::trace!(%address, %error, "health check failed");
+                }
+                let state = states.entry(address.clone()).or_default();
+                if state.record(result.is_ok(), &check) {
+                    tracing::info!(%address, healthy = state.healthy, "endpoint health changed");
+                }
+            }
+            publish(&config.clusters, &states);
+
+            tokio::time::sleep(Duration::from_millis(check.interval_ms)).await;
+        }
+    });
+}
+
+/// Stores the unhealthy endpoints of `states` in `clusters` and updates
+/// their gauges.
+fn publish(clusters: &super::Clusters, states: &HashMap<EndpointAddress, State>) {
+    clusters.store_unhealthy(
+        states
+            .iter()
+            .filter(|(_, state)| !state.healthy)
+            .map(|(address, _)| address.clone())
+            .collect(),
+    );
+
+    let gauges = endpoint_healthy();
+    gauges.reset();
+    for (address, state) in states {
+        gauges
+            .with_label_values(&[&address.to_string()])
+            .set(state.healthy as i64);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn record() {
+        let check = HealthCheck {
+            unhealthy_threshold: 2,
+            healthy_threshold: 1,
+            ..<_>::default()
+        };
+        let mut state = State::default();
+        assert!(!state.record(false, &check));
+        assert!(!state.record(true, &check));
+        assert!(!state.record(false, &check));
+        assert!(state.record(false, &check));
+        assert!(!state.healthy);
+        assert!(state.record(true, &check));
+        assert!(state.healthy);
+    }
+
+    #[test]
+    fn healthy_endpoints() {
+        let healthy_endpoint = Endpoint::new((Ipv4Addr::new(192, 0, 2, 1), 7000).into());
+        let unhealthy_endpoint = Endpoint::new((Ipv4Addr::new(192, 0, 2, 2), 7000).into());
+        let clusters = super::super::Clusters::default();
+        let mut states = HashMap::new();
+        states.insert(
+            unhealthy_endpoint.address.clone(),
+            State {
+                healthy: false,
+                consecutive: 0,
+            },
+        );
+        publish(&clusters, &states);
+
+        assert_eq!(
+            vec![healthy_endpoint.clone()],
+            clusters.healthy(vec![healthy_endpoint.clone(), unhealthy_endpoint.clone()])
+        );
+        // With no healthy endpoints, every endpoint is kept.
+        assert_eq!(
+            vec![unhealthy_endpoint.clone()],
+            clusters.healthy(vec![unhealthy_endpoint.clone()])
+        );
+        // The health is kept per set of clusters.
+        assert_eq!(
+            vec![healthy_endpoint.clone(), unhealthy_endpoint.clone()],
+            super::super::Clusters::default().healthy(vec![healthy_endpoint, unhealthy_endpoint])
+        );
+    }
+
+    #[tokio::test]
+    async fn probes() {
+        let mut t = crate::test_utils::TestHelper::default();
+        let echo = t.run_echo_server().await.to_socket_addr().unwrap();
+        let udp = Probe::Udp {
+            payload: b"ping".to_vec(),
+        };
+        udp.check(echo).await.unwrap();
+
+        let listener = tokio::net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0))
+            .await
+            .unwrap();
+        let tcp = Probe::Tcp {
+            port: Some(listener.local_addr().unwrap().port()),
+        };
+        tcp.check(echo).await.unwrap();
+        drop(listener);
+        assert!(tcp.check(echo).await.is_err());
+    }
+}
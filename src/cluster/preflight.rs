@@ -0,0 +1,243 @@
+/*
+ * Copyright 2023 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Connectivity preflight, which holds the endpoints added to the clusters as
+//! pending until they pass a probe, so that players aren't routed to
+//! backends a firewall is blocking.
+//!
+//! Endpoints are held as soon as they're applied, from any configuration
+//! source, and the endpoints the clusters were loaded with aren't held.
+
+use std::{
+    collections::HashSet,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use arc_swap::ArcSwap;
+use once_cell::sync::Lazy;
+use tokio::sync::Notify;
+
+use super::{ClusterMap, Probe};
+use crate::{endpoint::EndpointAddress, proxy::Tasks, Config};
+
+/// How often pending endpoints are probed again.
+const RETRY_INTERVAL: Duration = Duration::from_secs(1);
+/// How often the config is checked for preflight while nothing is pending.
+const IDLE_INTERVAL: Duration = Duration::from_secs(5);
+
+/// The preflight state of the [`Clusters`][super::Clusters] holding it.
+#[derive(Default)]
+pub(crate) struct Preflight {
+    /// Whether added endpoints are held, as last read from the config.
+    enabled: AtomicBool,
+    /// The addresses of the endpoints that haven't passed their preflight
+    /// yet.
+    pending: ArcSwap<HashSet<EndpointAddress>>,
+    /// Wakes the preflight task when endpoints are held.
+    held: Notify,
+}
+
+fn pending_endpoints() -> &'static prometheus::IntGauge {
+    static PENDING_ENDPOINTS: Lazy<prometheus::IntGauge> = Lazy::new(|| {
+        crate::metrics::register(
+            prometheus::IntGauge::with_opts(crate::metrics::opts(
+                "pending_endpoints",
+                super::SUBSYSTEM,
+                "Number of endpoints that haven't passed their preflight probe yet.",
+            ))
+            .unwrap(),
+        )
+    });
+
+    &PENDING_ENDPOINTS
+}
+
+impl Preflight {
+    /// The addresses of the endpoints that packets aren't routed to until
+    /// they pass their preflight probe.
+    pub(crate) fn pending(&self) -> Arc<HashSet<EndpointAddress>> {
+        self.pending.load_full()
+    }
+
+    fn store(&self, pending: HashSet<EndpointAddress>) {
+        pending_endpoints().set(pending.len() as i64);
+        self.pending.store(Arc::new(pending));
+        crate::config::next_generation();
+    }
+
+    /// Holds the endpoints in `current` but not in `previous` as pending
+    /// while preflight is enabled, and forgets pending endpoints that were
+    /// removed.
+    pub(crate) fn hold_added(&self, previous: &ClusterMap, current: &ClusterMap) {
+        let enabled = self.enabled.load(Ordering::Relaxed);
+        if !enabled && self.pending.load().is_empty() {
+            return;
+        }
+
+        let addresses = |map: &ClusterMap| -> HashSet<EndpointAddress> {
+            map.endpoints().map(|endpoint| endpoint.address).collect()
+        };
+        let (previous, current) = (addresses(previous), addresses(current));
+
+        let mut held = 0;
+        self.pending.rcu(|pending| {
+            let (pending, newly_held) = hold(pending, &previous, &current, enabled);
+            held = newly_held;
+            pending
+        });
+        pending_endpoints().set(self.pending.load().len() as i64);
+        crate::config::next_generation();
+
+        if held > 0 {
+            tracing::debug!(
+                held,
+                "holding added endpoints until they pass their preflight"
+            );
+            self.held.notify_one();
+        }
+    }
+}
+
+/// Returns the endpoints of `pending` still in `current`, along with those
+/// added since `previous` when `enabled`, and the number of those added.
+fn hold(
+    pending: &HashSet<EndpointAddress>,
+    previous: &HashSet<EndpointAddress>,
+    current: &HashSet<EndpointAddress>,
+    enabled: bool,
+) -> (HashSet<EndpointAddress>, usize) {
+    let mut pending: HashSet<_> = pending.intersection(current).cloned().collect();
+    let before = pending.len();
+    if enabled {
+        pending.extend(current.difference(previous).cloned());
+    }
+    let held = pending.len() - before;
+    (pending, held)
+}
+
+/// Spawns the task probing pending endpoints with the health check probe of
+/// `config`, until they pass.
+pub(crate) fn spawn(config: Arc<Config>, tasks: &Tasks) {
+    // Endpoints applied before the task first runs are held too.
+    config
+        .clusters
+        .preflight()
+        .enabled
+        .store(config.health_check.load().preflight, Ordering::Relaxed);
+    tasks.spawn("preflight", async move {
+        let preflight = config.clusters.preflight();
+        loop {
+            let check = config.health_check.load();
+            preflight.enabled.store(check.preflight, Ordering::Relaxed);
+            let pending = preflight.pending();
+            if !check.preflight || pending.is_empty() {
+                if !check.preflight && !pending.is_empty() {
+                    preflight.store(HashSet::new());
+                }
+                let _ = tokio::time::timeout(IDLE_INTERVAL, preflight.held.notified()).await;
+                continue;
+            }
+
+            let probe = check.probe.clone().unwrap_or(Probe::Udp {
+                payload: Vec::new(),
+            });
+            let timeout = Duration::from_millis(check.timeout_ms);
+            let results = futures::future::join_all(pending.iter().map(|address| {
+                let (probe, clusters) = (&probe, &config.clusters);
+                async move { (address, probe.probe(clusters, address, timeout).await) }
+            }))
+            .await;
+
+            let mut passed = HashSet::new();
+            for (address, result) in results {
+                match result {
+                    Ok(()) => {
+                        tracing::info!(%address, "endpoint passed its preflight");
+                        passed.insert(address.clone());
+                    }
+                    Err(error) => {
+                        tracing::debug!(%address, %error, "endpoint failed its preflight, retrying");
+                    }
+                }
+            }
+            if !passed.is_empty() {
+                preflight
+                    .pending
+                    .rcu(|pending| pending.difference(&passed).cloned().collect::<HashSet<_>>());
+                pending_endpoints().set(preflight.pending.load().len() as i64);
+                crate::config::next_generation();
+            }
+
+            let _ = tokio::time::timeout(RETRY_INTERVAL, preflight.held.notified()).await;
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hold() {
+        let address = |port| EndpointAddress::from((std::net::Ipv4Addr::LOCALHOST, port));
+        let set = |ports: &[u16]| {
+            ports
+                .iter()
+                .map(|&port| address(port))
+                .collect::<HashSet<_>>()
+        };
+
+        let (pending, held) = super::hold(&set(&[]), &set(&[1]), &set(&[1, 2]), false);
+        assert_eq!((set(&[]), 0), (pending, held));
+
+        let (pending, held) = super::hold(&set(&[]), &set(&[1]), &set(&[1, 2]), true);
+        assert_eq!((set(&[2]), 1), (pending, held));
+
+        // Removed endpoints are forgotten, even once preflight is disabled.
+        let (pending, held) = super::hold(&set(&[2, 3]), &set(&[1, 2, 3]), &set(&[1, 3]), false);
+        assert_eq!((set(&[3]), 0), (pending, held));
+    }
+
+    #[test]
+    fn hold_added() {
+        let map = |ports: &[u16]| {
+            ClusterMap::new_with_default_cluster(
+                ports
+                    .iter()
+                    .map(|&port| {
+                        crate::endpoint::Endpoint::new((std::net::Ipv4Addr::LOCALHOST, port).into())
+                    })
+                    .collect::<Vec<_>>(),
+            )
+        };
+
+        let preflight = Preflight::default();
+        preflight.hold_added(&map(&[1]), &map(&[1, 2]));
+        assert!(preflight.pending().is_empty());
+
+        preflight.enabled.store(true, Ordering::Relaxed);
+        preflight.hold_added(&map(&[1]), &map(&[1, 2]));
+        assert_eq!(
+            HashSet::from([EndpointAddress::from((std::net::Ipv4Addr::LOCALHOST, 2))]),
+            *preflight.pending()
+        );
+        assert!(Preflight::default().pending().is_empty());
+    }
+}
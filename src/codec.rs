@@ -0,0 +1,113 @@
+/*
+ * Copyright 2023 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! The packet transformations behind the simple filters, such as
+//! [`ConcatenateBytes`], [`Capture`] and [`TokenRouter`], without any of
+//! their configuration, metrics or IO.
+//!
+//! This module only depends on `core` and `alloc`, so that tools which need
+//! to agree with the proxy on the wire format, such as game client SDKs or
+//! eBPF userspace helpers, can copy or share it without the rest of the
+//! crate.
+//!
+//! [`ConcatenateBytes`]: crate::filters::concatenate_bytes
+//! [`Capture`]: crate::filters::capture
+//! [`TokenRouter`]: crate::filters::token_router
+
+pub mod client;
+pub mod rejection;
+
+use alloc::{collections::BTreeSet, vec::Vec};
+
+/// Adds `bytes` to the start of `packet`.
+pub fn prepend(packet: &mut Vec<u8>, bytes: &[u8]) {
+    packet.splice(..0, bytes.iter().copied());
+}
+
+/// Adds `bytes` to the end of `packet`.
+pub fn append(packet: &mut Vec<u8>, bytes: &[u8]) {
+    packet.extend_from_slice(bytes);
+}
+
+/// Returns the first `size` bytes of `packet`, removing them from the packet
+/// if `remove` is set, or `None` if the packet is shorter than `size`.
+pub fn capture_prefix(packet: &mut Vec<u8>, size: usize, remove: bool) -> Option<Vec<u8>> {
+    if packet.len() < size {
+        return None;
+    }
+
+    Some(if remove {
+        packet.drain(..size).collect()
+    } else {
+        packet[..size].to_vec()
+    })
+}
+
+/// Returns the last `size` bytes of `packet`, removing them from the packet
+/// if `remove` is set, or `None` if the packet is shorter than `size`.
+pub fn capture_suffix(packet: &mut Vec<u8>, size: usize, remove: bool) -> Option<Vec<u8>> {
+    let index = packet.len().checked_sub(size)?;
+
+    Some(if remove {
+        packet.split_off(index)
+    } else {
+        packet[index..].to_vec()
+    })
+}
+
+/// Returns whether a packet carrying `token` may be routed to an endpoint
+/// with `tokens`.
+pub fn routes_to(tokens: &BTreeSet<Vec<u8>>, token: &[u8]) -> bool {
+    tokens.contains(token)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn concatenate() {
+        let mut packet = b"abc".to_vec();
+        prepend(&mut packet, b"12");
+        append(&mut packet, b"34");
+        assert_eq!(b"12abc34", &*packet);
+    }
+
+    #[test]
+    fn capture() {
+        let mut packet = b"helloabc".to_vec();
+        assert_eq!(Some(b"abc".to_vec()), capture_suffix(&mut packet, 3, false));
+        assert_eq!(b"helloabc", &*packet);
+        assert_eq!(Some(b"abc".to_vec()), capture_suffix(&mut packet, 3, true));
+        assert_eq!(b"hello", &*packet);
+
+        assert_eq!(Some(b"he".to_vec()), capture_prefix(&mut packet, 2, false));
+        assert_eq!(b"hello", &*packet);
+        assert_eq!(Some(b"he".to_vec()), capture_prefix(&mut packet, 2, true));
+        assert_eq!(b"llo", &*packet);
+
+        assert_eq!(None, capture_prefix(&mut packet, 4, true));
+        assert_eq!(None, capture_suffix(&mut packet, 4, true));
+        assert_eq!(b"llo", &*packet);
+    }
+
+    #[test]
+    fn routing() {
+        let tokens = BTreeSet::from([b"abc".to_vec(), b"xyz".to_vec()]);
+        assert!(routes_to(&tokens, b"abc"));
+        assert!(!routes_to(&tokens, b"ab"));
+    }
+}
@@ -0,0 +1,138 @@
+/*
+ * Copyright 2023 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Helpers for game clients sending packets through a proxy, which add
+//! routing tokens to them in the format the proxy's [`Capture`] filter
+//! captures them from.
+//!
+//! ```
+//! use quilkin::codec::client::{Position, RoutingToken};
+//!
+//! let token = RoutingToken::new(b"abc".to_vec(), Position::Suffix);
+//! let mut packet = b"hello".to_vec();
+//! token.encode(&mut packet);
+//! assert_eq!(b"helloabc", &*packet);
+//! ```
+//!
+//! The proxy must be configured with a capture strategy of the same
+//! [`Position`] and [`RoutingToken::size`], with `remove` set so the token
+//! doesn't reach the game server, which is what converting a
+//! [`RoutingToken`] into a capture [`Strategy`] returns.
+//!
+//! [`Capture`]: crate::filters::capture
+//! [`Strategy`]: crate::filters::capture::Strategy
+
+use alloc::vec::Vec;
+
+/// Where in each packet a [`RoutingToken`] is placed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Position {
+    /// Before the packet's payload, for the `PREFIX` capture strategy.
+    Prefix,
+    /// After the packet's payload, for the `SUFFIX` capture strategy.
+    Suffix,
+}
+
+/// A token routing a client's packets to the endpoints with the token in
+/// their metadata.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RoutingToken {
+    token: Vec<u8>,
+    position: Position,
+}
+
+impl RoutingToken {
+    pub fn new(token: impl Into<Vec<u8>>, position: Position) -> Self {
+        Self {
+            token: token.into(),
+            position,
+        }
+    }
+
+    pub fn token(&self) -> &[u8] {
+        &self.token
+    }
+
+    pub fn position(&self) -> Position {
+        self.position
+    }
+
+    /// The number of bytes the token adds to each packet, which the capture
+    /// strategy's `size` must be set to.
+    pub fn size(&self) -> usize {
+        self.token.len()
+    }
+
+    /// Adds the token to `packet`.
+    pub fn encode(&self, packet: &mut Vec<u8>) {
+        match self.position {
+            Position::Prefix => super::prepend(packet, &self.token),
+            Position::Suffix => super::append(packet, &self.token),
+        }
+    }
+
+    /// Returns a new packet of `payload` with the token added.
+    pub fn encoded(&self, payload: &[u8]) -> Vec<u8> {
+        let mut packet = Vec::with_capacity(payload.len() + self.size());
+        packet.extend_from_slice(payload);
+        self.encode(&mut packet);
+        packet
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encode() {
+        let prefix = RoutingToken::new(*b"abc", Position::Prefix);
+        let suffix = RoutingToken::new(*b"xyz", Position::Suffix);
+        assert_eq!(3, prefix.size());
+        assert_eq!(b"abchello", &*prefix.encoded(b"hello"));
+        assert_eq!(b"helloxyz", &*suffix.encoded(b"hello"));
+
+        let mut packet = b"hello".to_vec();
+        prefix.encode(&mut packet);
+        suffix.encode(&mut packet);
+        assert_eq!(b"abchelloxyz", &*packet);
+    }
+
+    #[test]
+    #[cfg(feature = "filter-capture")]
+    fn captured_by_proxy() {
+        use crate::filters::{capture, Capture, Filter, ReadContext, StaticFilter};
+
+        for position in [Position::Prefix, Position::Suffix] {
+            let token = RoutingToken::new(*b"abc", position);
+            let capture = Capture::from_config(Some(capture::Config {
+                metadata_key: "token".into(),
+                strategy: (&token).into(),
+            }));
+
+            let mut ctx = ReadContext::new(
+                Vec::new(),
+                "127.0.0.1:80".parse().unwrap(),
+                token.encoded(b"hello"),
+            );
+            capture.read(&mut ctx).unwrap();
+
+            assert_eq!(b"hello", &*ctx.contents);
+            let captured = ctx.metadata.get(&"token".into()).unwrap();
+            assert_eq!(b"abc", &**captured.as_bytes().unwrap());
+        }
+    }
+}
@@ -0,0 +1,204 @@
+/*
+ * Copyright 2023 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! The responses a proxy sends to clients whose packets it couldn't route,
+//! when configured with the `reject` unrouted policy, so that game clients
+//! can show why they can't connect rather than timing out.
+//!
+//! A rejection is [`LEN`] bytes, with every integer in big endian:
+//!
+//! | Offset | Size | Field                                              |
+//! |--------|------|----------------------------------------------------|
+//! | 0      | 4    | [`MAGIC`], `QLKR`                                  |
+//! | 4      | 1    | [`VERSION`], currently `1`                         |
+//! | 5      | 1    | The [`Reason`]                                     |
+//! | 6      | 8    | The time it was sent, in seconds since the epoch   |
+//! | 14     | 64   | The signature                                      |
+//!
+//! The signature is the Ed25519 signature of the first [`HEADER_LEN`] bytes,
+//! with the policy's private key, so that clients only need the public key
+//! to check it, and can't sign rejections of their own. Anyone can send a
+//! client a UDP packet with the proxy's source address, so clients must
+//! check the signature, and should ignore rejections sent long before they
+//! received them, which could have been recorded and replayed.
+//!
+//! ```
+//! use quilkin::codec::rejection::{Reason, Rejection, SIGNATURE_LEN};
+//!
+//! let rejection = Rejection::new(Reason::InvalidToken, 1_700_000_000);
+//! // Computed by the proxy with Ed25519 over `signed_bytes`.
+//! let signature = [0; SIGNATURE_LEN];
+//! let packet = rejection.encode(&signature);
+//!
+//! let (decoded, received) = Rejection::decode(&packet).unwrap();
+//! assert_eq!(rejection, decoded);
+//! // The client verifies `received` over `decoded.signed_bytes()` with the
+//! // proxy's public key before trusting the rejection.
+//! assert_eq!(signature, received);
+//! ```
+//!
+//! The version only changes for changes that older clients can't read. New
+//! reasons are added without one, so clients should treat an unknown reason
+//! as a generic routing failure.
+
+use alloc::vec::Vec;
+
+/// The first bytes of every rejection.
+pub const MAGIC: [u8; 4] = *b"QLKR";
+/// The version of the format sent by this crate.
+pub const VERSION: u8 = 1;
+/// The number of bytes covered by the signature.
+pub const HEADER_LEN: usize = 14;
+/// The number of bytes of the Ed25519 signature.
+pub const SIGNATURE_LEN: usize = 64;
+/// The number of bytes of a rejection.
+pub const LEN: usize = HEADER_LEN + SIGNATURE_LEN;
+
+/// Why a packet wasn't routed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Reason {
+    /// The proxy has no endpoints to route any packet to.
+    NoEndpoint = 1,
+    /// The packet's routing token doesn't match any endpoint.
+    InvalidToken = 2,
+    /// The proxy is draining, and isn't accepting new clients.
+    Draining = 3,
+    /// A filter blocked the packet's source, such as a firewall rule.
+    Blocked = 4,
+    /// The packet's source went beyond a rate limit.
+    RateLimited = 5,
+    /// The proxy is overloaded, and ran out of time processing the packet.
+    Overloaded = 6,
+    /// Another filter dropped the packet.
+    Filtered = 7,
+}
+
+impl Reason {
+    fn from_u8(reason: u8) -> Option<Self> {
+        match reason {
+            1 => Some(Self::NoEndpoint),
+            2 => Some(Self::InvalidToken),
+            3 => Some(Self::Draining),
+            4 => Some(Self::Blocked),
+            5 => Some(Self::RateLimited),
+            6 => Some(Self::Overloaded),
+            7 => Some(Self::Filtered),
+            _ => None,
+        }
+    }
+}
+
+/// Why a packet isn't a rejection this crate can read.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DecodeError {
+    /// The packet is too short, or doesn't start with [`MAGIC`].
+    NotRejection,
+    /// The rejection was sent in a newer version of the format.
+    UnsupportedVersion(u8),
+    /// The rejection has a reason added in a later version.
+    UnknownReason(u8),
+}
+
+/// A rejection, without its signature.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Rejection {
+    pub reason: Reason,
+    /// When the rejection was sent, in seconds since the Unix epoch.
+    pub timestamp: u64,
+}
+
+impl Rejection {
+    pub fn new(reason: Reason, timestamp: u64) -> Self {
+        Self { reason, timestamp }
+    }
+
+    /// The bytes the signature is computed over.
+    pub fn signed_bytes(&self) -> [u8; HEADER_LEN] {
+        let mut bytes = [0; HEADER_LEN];
+        bytes[..4].copy_from_slice(&MAGIC);
+        bytes[4] = VERSION;
+        bytes[5] = self.reason as u8;
+        bytes[6..].copy_from_slice(&self.timestamp.to_be_bytes());
+        bytes
+    }
+
+    /// Returns the rejection as a packet, signed with `signature`.
+    pub fn encode(&self, signature: &[u8; SIGNATURE_LEN]) -> Vec<u8> {
+        let mut packet = Vec::with_capacity(LEN);
+        packet.extend_from_slice(&self.signed_bytes());
+        packet.extend_from_slice(signature);
+        packet
+    }
+
+    /// Reads a rejection and its signature from `packet`, which the caller
+    /// must verify.
+    pub fn decode(packet: &[u8]) -> Result<(Self, [u8; SIGNATURE_LEN]), DecodeError> {
+        if packet.len() < LEN || packet[..4] != MAGIC {
+            return Err(DecodeError::NotRejection);
+        }
+        if packet[4] != VERSION {
+            return Err(DecodeError::UnsupportedVersion(packet[4]));
+        }
+        let reason = Reason::from_u8(packet[5]).ok_or(DecodeError::UnknownReason(packet[5]))?;
+
+        let mut timestamp = [0; 8];
+        timestamp.copy_from_slice(&packet[6..HEADER_LEN]);
+        let mut signature = [0; SIGNATURE_LEN];
+        signature.copy_from_slice(&packet[HEADER_LEN..LEN]);
+
+        Ok((Self::new(reason, u64::from_be_bytes(timestamp)), signature))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encode_and_decode() {
+        let rejection = Rejection::new(Reason::NoEndpoint, 0x0102030405060708);
+        let packet = rejection.encode(&[0xff; SIGNATURE_LEN]);
+        assert_eq!(LEN, packet.len());
+        assert_eq!(
+            b"QLKR\x01\x01\x01\x02\x03\x04\x05\x06\x07\x08",
+            &packet[..HEADER_LEN]
+        );
+        assert_eq!(
+            Ok((rejection, [0xff; SIGNATURE_LEN])),
+            Rejection::decode(&packet)
+        );
+
+        assert_eq!(
+            Err(DecodeError::NotRejection),
+            Rejection::decode(&packet[..LEN - 1])
+        );
+        assert_eq!(Err(DecodeError::NotRejection), Rejection::decode(&[0; LEN]));
+
+        let mut newer = packet.clone();
+        newer[4] = 2;
+        assert_eq!(
+            Err(DecodeError::UnsupportedVersion(2)),
+            Rejection::decode(&newer)
+        );
+        let mut unknown = packet;
+        unknown[5] = 99;
+        assert_eq!(
+            Err(DecodeError::UnknownReason(99)),
+            Rejection::decode(&unknown)
+        );
+    }
+}
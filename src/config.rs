@@ -23,13 +23,18 @@ use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
+mod apply;
 mod config_type;
 mod error;
+mod frozen;
+mod hash;
+mod reloads;
+mod roles;
 mod slot;
 pub mod watch;
 
 use crate::{
-    cluster::{Cluster, ClusterMap},
+    cluster::{Cluster, InvalidEndpointPolicy, Sampling, SessionSettings},
     filters::prelude::*,
     xds::{
         config::{endpoint::v3::ClusterLoadAssignment, listener::v3::Listener},
@@ -38,7 +43,16 @@ use crate::{
     },
 };
 
-pub use self::{config_type::ConfigType, error::ValidationError, slot::Slot};
+pub(crate) use self::slot::{generation, next_generation};
+pub use self::{
+    config_type::ConfigType,
+    error::ValidationError,
+    frozen::{Frozen, FrozenError},
+    hash::ConfigHash,
+    reloads::{FilterReloads, FilterStatus},
+    roles::{Role, Roles},
+    slot::Slot,
+};
 
 base64_serde_type!(pub Base64Standard, base64::STANDARD);
 
@@ -56,13 +70,68 @@ pub(crate) const CONNECTION_TIMEOUT: u64 = 5;
 #[non_exhaustive]
 pub struct Config {
     #[serde(default)]
-    pub clusters: Slot<ClusterMap>,
+    pub clusters: crate::cluster::Clusters,
     #[serde(default)]
     pub filters: Slot<crate::filters::FilterChain>,
     #[serde(default = "default_proxy_id")]
     pub id: Slot<String>,
     #[serde(default)]
     pub version: Slot<Version>,
+    /// Packet rate and bandwidth quotas, keyed by cluster name or namespace.
+    #[serde(default)]
+    pub quotas: Slot<crate::quota::Quotas>,
+    /// What to do with packets that no endpoint matches.
+    #[serde(default)]
+    pub unrouted: Slot<crate::proxy::UnroutedPolicy>,
+    /// The metadata namespaces endpoints are expected to have, endpoints
+    /// from the management server that don't match are rejected.
+    #[serde(default)]
+    pub metadata_schema: Slot<crate::metadata::MetadataSchema>,
+    /// Answers packets with a magic prefix with the address they were sent
+    /// from, disabled by default.
+    #[serde(default)]
+    pub address_discovery: Slot<crate::proxy::AddressDiscovery>,
+    /// What to do with endpoints from management servers that can't be
+    /// converted, rejecting their whole cluster by default.
+    #[serde(default)]
+    pub invalid_endpoints: Slot<crate::cluster::InvalidEndpointPolicy>,
+    /// How endpoints are probed, so that packets aren't routed to endpoints
+    /// failing their probes, disabled by default.
+    #[serde(default)]
+    pub health_check: Slot<crate::cluster::HealthCheck>,
+    /// How long sessions last and how many of them the proxy holds at once,
+    /// overridden by the `--session-*` and `--max-sessions` flags.
+    #[serde(default)]
+    pub sessions: Slot<crate::proxy::SessionPolicy>,
+    /// The roles started by `quilkin run`, which other commands ignore.
+    #[serde(default, skip_serializing_if = "Roles::is_default")]
+    pub roles: Roles,
+    /// How proxies connect to their management servers, overridden by the
+    /// `--xds-*` flags. Changes are only applied when reconnecting.
+    #[serde(default, skip_serializing_if = "crate::xds::ClientAuth::is_default")]
+    pub xds_auth: crate::xds::ClientAuth,
+    /// The outcome of the latest updates to each filter's config.
+    #[serde(skip)]
+    pub filter_reloads: FilterReloads,
+    /// Whether changes to the clusters and filters from anything other than
+    /// a restart are rejected, see [`Frozen`].
+    #[serde(skip)]
+    pub frozen: Frozen,
+    /// The mitigations pushed through the admin server, applied by the
+    /// `Firewall` and `RateLimit` filters.
+    #[serde(skip)]
+    pub mitigations: crate::filters::mitigation::Mitigations,
+    /// How many rejections or responses the `reject` and `respond` unrouted
+    /// policies sent this second.
+    #[serde(skip)]
+    pub(crate) rejections: crate::proxy::RejectionLimit,
+    /// The usage of the `LocalRateLimit` filters' shared limits.
+    #[serde(skip)]
+    pub rate_limits: crate::xds::rate_limit::SharedRateLimits,
+    /// How long the filter chain may spend on each packet, set by
+    /// `--filter-budget-ms`.
+    #[serde(skip)]
+    pub filter_budget: crate::filters::ExecutionBudget,
 }
 
 impl Config {
@@ -71,6 +140,67 @@ impl Config {
         serde_yaml::from_reader(input)
     }
 
+    /// Restores the clusters and filters from `state_dir`, if they were
+    /// persisted there before, and persists every later change to them,
+    /// creating the directory if needed. See [`Slot::persist`].
+    pub fn persist(&self, state_dir: &std::path::Path) -> crate::Result<()> {
+        std::fs::create_dir_all(state_dir)?;
+        self.clusters.persist(state_dir.join("clusters.json"))?;
+        self.filters.persist(state_dir.join("filters.json"))?;
+        self.apply_metrics();
+
+        Ok(())
+    }
+
+    /// Applies the configuration file at `path` whenever it changes, so that
+    /// clusters and filters can be changed without a management server.
+    /// Files that fail to parse or validate are logged and ignored, keeping
+    /// the current configuration. Runs until dropped.
+    pub async fn watch_file(
+        self: Arc<Self>,
+        path: impl Into<std::path::PathBuf>,
+    ) -> crate::Result<()> {
+        watch::file(self, path.into()).await
+    }
+
+    /// Replaces this configuration with the one in `contents`, resetting the
+    /// settings it doesn't have to their defaults. Settings that are
+    /// unchanged aren't stored again, so they don't notify their watchers.
+    /// `origin` names where the contents come from, such as `file`.
+    pub(crate) fn reload(&self, origin: &'static str, contents: &[u8]) -> crate::Result<()> {
+        let other = Self::from_reader(contents)?;
+        other.validate_metadata()?;
+        if *other.clusters.load() != *self.clusters.load() {
+            self.frozen.check(origin, "clusters")?;
+        }
+        if *other.filters.load() != *self.filters.load() {
+            self.frozen.check(origin, "filters")?;
+        }
+
+        macro_rules! replace {
+            ($($field:ident),+) => {
+                $(self.$field.try_replace(other.$field);)+
+            }
+        }
+
+        replace!(
+            clusters,
+            filters,
+            id,
+            version,
+            quotas,
+            unrouted,
+            metadata_schema,
+            address_discovery,
+            invalid_endpoints,
+            health_check,
+            sessions
+        );
+        self.apply_metrics();
+
+        Ok(())
+    }
+
     fn update_from_json(
         &self,
         map: serde_json::Map<String, serde_json::Value>,
@@ -86,7 +216,18 @@ impl Config {
             }
         }
 
-        replace_if_present!(clusters, filters, id);
+        replace_if_present!(
+            clusters,
+            filters,
+            id,
+            quotas,
+            unrouted,
+            metadata_schema,
+            address_discovery,
+            invalid_endpoints,
+            health_check,
+            sessions
+        );
 
         if let Some(locality) = locality {
             self.clusters
@@ -121,7 +262,14 @@ impl Config {
             }
             ResourceType::Cluster => {
                 let clusters = self.clusters.load();
-                for cluster in names.iter().filter_map(|name| clusters.get(name)) {
+                // Requests without names are wildcard requests for every
+                // cluster.
+                let requested: Box<dyn Iterator<Item = &Cluster>> = if names.is_empty() {
+                    Box::new(clusters.values())
+                } else {
+                    Box::new(names.iter().filter_map(|name| clusters.get(name)))
+                };
+                for cluster in requested {
                     resources.push(resource_type.encode_to_any(
                         &crate::xds::config::cluster::v3::Cluster::try_from(cluster)?,
                     )?);
@@ -137,24 +285,117 @@ impl Config {
         })
     }
 
-    #[tracing::instrument(skip_all, fields(response = response.type_url()))]
     pub fn apply(&self, response: &Resource) -> crate::Result<()> {
-        let apply_cluster = |cluster: Cluster| {
+        self.apply_version(response, None)
+    }
+
+    /// Applies `response`, recording `version` as the version of the update
+    /// it came from in [`Config::filter_reloads`].
+    #[tracing::instrument(skip_all, fields(response = response.type_url(), name = response.name(), version = version.unwrap_or_default()))]
+    pub fn apply_version(&self, response: &Resource, version: Option<&str>) -> crate::Result<()> {
+        let timer = apply::start(response.resource_type());
+        let validated = self.validate_resource(response, version, &mut Vec::new())?;
+        self.commit(validated, version);
+        timer.observe_duration();
+        apply::applied(response);
+        self.apply_count_metrics();
+        Ok(())
+    }
+
+    /// Applies every resource of an xDS update and removes the `removed`
+    /// resources, or does nothing if any of them is invalid, so that an update
+    /// the management server is told was rejected leaves the config as it was.
+    /// The error lists every invalid resource. Returns the invalid endpoints
+    /// skipped under [`InvalidEndpointPolicy::Skip`], so that they can be
+    /// reported to the management server.
+    #[tracing::instrument(skip_all, fields(resources = resources.len(), removed = removed.len(), version = version.unwrap_or_default()))]
+    pub fn apply_all(
+        &self,
+        resources: &[Resource],
+        removed: &[(ResourceType, String)],
+        version: Option<&str>,
+    ) -> crate::Result<Vec<String>> {
+        let mut errors = Vec::new();
+        let mut skipped = Vec::new();
+        let mut validated = Vec::with_capacity(resources.len());
+        for resource in resources {
+            let started = std::time::Instant::now();
+            match self.validate_resource(resource, version, &mut skipped) {
+                Ok(valid) => validated.push((resource, valid, started.elapsed())),
+                Err(error) => errors.push(error.to_string()),
+            }
+        }
+        for (resource_type, name) in removed {
+            if let Err(error) = self.validate_removed(*resource_type) {
+                errors.push(format!("removing `{name}`: {error}"));
+            }
+        }
+
+        if !errors.is_empty() {
+            return Err(eyre::eyre!("{}", errors.join("; ")));
+        }
+
+        for (resource, valid, validation) in validated {
+            let started = std::time::Instant::now();
+            self.commit(valid, version);
+            apply::observe(resource.resource_type(), validation + started.elapsed());
+            apply::applied(resource);
+        }
+        for (resource_type, name) in removed {
+            self.commit_removed(*resource_type, name);
+        }
+
+        // Hashing the whole config for every resource would be quadratic, so
+        // the xDS client records the hash once the whole response is applied.
+        self.apply_count_metrics();
+
+        Ok(skipped)
+    }
+
+    /// Checks that `response` can be applied, doing all of the work that can
+    /// fail, without changing the config. The invalid endpoints skipped under
+    /// [`InvalidEndpointPolicy::Skip`] are added to `skipped`.
+    fn validate_resource(
+        &self,
+        response: &Resource,
+        version: Option<&str>,
+        skipped: &mut Vec<String>,
+    ) -> crate::Result<Validated> {
+        self.frozen.check(
+            "xds",
+            match response {
+                Resource::Listener(_) => "filters",
+                Resource::Cluster(_) | Resource::Endpoint(_) => "clusters",
+            },
+        )?;
+
+        let schema = self.metadata_schema.load();
+        let mut validate_load_assignment = |cla: ClusterLoadAssignment,
+                                            settings: Option<XdsClusterSettings>|
+         -> crate::Result<Validated> {
+            let (cluster, invalid) = Cluster::from_load_assignment_lossy(cla);
+            if !invalid.is_empty() {
+                crate::cluster::invalid_endpoints_total(&cluster.name).inc_by(invalid.len() as u64);
+                let invalid = crate::cluster::InvalidEndpoints(invalid);
+                match *self.invalid_endpoints.load() {
+                    InvalidEndpointPolicy::Reject => return Err(invalid.into()),
+                    InvalidEndpointPolicy::Skip => {
+                        tracing::warn!(%invalid, "skipping invalid endpoints");
+                        skipped.push(invalid.to_string());
+                    }
+                }
+            }
+
             if cluster.endpoints().count() == 0 {
-                return;
+                return Ok(Validated::Nothing);
             }
 
-            tracing::trace!(endpoints = %serde_json::to_value(&cluster).unwrap(), "applying new endpoints");
-            self.clusters.modify(|clusters| {
-                clusters.insert(cluster.clone());
-            });
+            schema.validate(&cluster)?;
+            Ok(Validated::Cluster(cluster, settings))
         };
 
-        match response {
-            Resource::Endpoint(cla) => {
-                let cluster = Cluster::try_from(*cla.clone()).unwrap();
-                (apply_cluster)(cluster)
-            }
+        Ok(match response {
+            Resource::Endpoint(cla) => validate_load_assignment(*cla.clone(), None)?,
             Resource::Listener(listener) => {
                 let chain = listener
                     .filter_chains
@@ -162,31 +403,188 @@ impl Config {
                     .map(|chain| chain.filters.clone())
                     .unwrap_or_default()
                     .into_iter()
-                    .map(Filter::try_from)
+                    .map(|filter| {
+                        let name = filter.name.clone();
+                        Filter::try_from(filter).map_err(|error| {
+                            self.filter_reloads.rejected(&name, version, None, &error);
+                            error
+                        })
+                    })
                     .collect::<Result<Vec<_>, _>>()?;
-                self.filters.store(Arc::new(chain.try_into()?));
+
+                match crate::filters::FilterChain::try_create_indexed(&chain) {
+                    Ok(filters) => Validated::Listener(chain, filters),
+                    Err((index, error)) => {
+                        // Errors that don't come from a single filter are
+                        // recorded against every filter of the chain.
+                        let rejected = match index {
+                            Some(index) => &chain[index..=index],
+                            None => &chain[..],
+                        };
+                        for filter in rejected {
+                            self.filter_reloads.rejected(
+                                &filter.name,
+                                version,
+                                filter.config.clone(),
+                                &error,
+                            );
+                        }
+                        return Err(error.into());
+                    }
+                }
             }
             Resource::Cluster(cluster) => {
-                cluster
-                    .load_assignment
-                    .clone()
-                    .map(Cluster::try_from)
-                    .transpose()?
-                    .map(apply_cluster);
+                let settings = XdsClusterSettings {
+                    sessions: SessionSettings::from_xds(cluster),
+                    sampling: Sampling::from_xds(cluster)?,
+                };
+                match cluster.load_assignment.clone() {
+                    Some(cla) => validate_load_assignment(cla, Some(settings))?,
+                    // The endpoints are sent separately, so only the settings
+                    // are applied, creating the cluster if it's new.
+                    None => Validated::ClusterSettings(cluster.name.clone(), settings),
+                }
+            }
+        })
+    }
+
+    /// Applies a resource that passed [`Config::validate_resource`].
+    fn commit(&self, validated: Validated, version: Option<&str>) {
+        match validated {
+            Validated::Nothing => {}
+            // Updates from an xDS `Cluster` replace the settings of the
+            // cluster's sessions, while updates of its endpoints keep them.
+            Validated::Cluster(cluster, settings) => {
+                tracing::trace!(endpoints = %serde_json::to_value(&cluster).unwrap(), "applying new endpoints");
+                let mut pinned = false;
+                self.clusters.modify(|clusters| {
+                    let existing = clusters.get(&cluster.name);
+                    pinned = existing.map_or(false, |cluster| cluster.pinned);
+                    if !pinned {
+                        let mut cluster = cluster.clone();
+                        let settings = settings.unwrap_or_else(|| XdsClusterSettings {
+                            sessions: existing.and_then(|cluster| cluster.sessions),
+                            sampling: existing.and_then(|cluster| cluster.sampling),
+                        });
+                        cluster.sessions = settings.sessions;
+                        cluster.sampling = settings.sampling;
+                        cluster.duplicate_endpoints =
+                            existing.and_then(|cluster| cluster.duplicate_endpoints);
+                        cluster.failover = existing.and_then(|cluster| cluster.failover.clone());
+                        cluster.upstream_bind =
+                            existing.and_then(|cluster| cluster.upstream_bind.clone());
+                        clusters.insert(cluster);
+                    }
+                });
+
+                if pinned {
+                    report_pinned_conflict(&cluster.name);
+                }
+            }
+            Validated::ClusterSettings(name, settings) => {
+                let mut pinned = false;
+                self.clusters
+                    .modify(|clusters| match clusters.get_mut(&name) {
+                        Some(existing) if existing.pinned => pinned = true,
+                        Some(existing) => {
+                            existing.sessions = settings.sessions;
+                            existing.sampling = settings.sampling;
+                        }
+                        None => {
+                            let mut new =
+                                Cluster::new(name.clone(), crate::endpoint::LocalitySet::default());
+                            new.sessions = settings.sessions;
+                            new.sampling = settings.sampling;
+                            clusters.insert(new);
+                        }
+                    });
+
+                if pinned {
+                    report_pinned_conflict(&name);
+                }
+            }
+            Validated::Listener(chain, filters) => {
+                for filter in &chain {
+                    self.filter_reloads.accepted(&filter.name, version);
+                }
+                self.filters.store(Arc::new(filters));
             }
         }
+    }
 
-        self.apply_metrics();
+    /// Removes the resource of `resource_type` called `name`, as removed by a
+    /// delta xDS response. Removing a cluster, or its endpoints, removes the
+    /// cluster unless it's pinned, while removing the listener clears the
+    /// filter chain.
+    #[tracing::instrument(skip(self))]
+    pub fn apply_removed(&self, resource_type: ResourceType, name: &str) -> crate::Result<()> {
+        self.validate_removed(resource_type)?;
+        self.commit_removed(resource_type, name);
+        self.apply_count_metrics();
+        Ok(())
+    }
+
+    /// Checks that resources of `resource_type` can be removed.
+    fn validate_removed(&self, resource_type: ResourceType) -> crate::Result<()> {
+        match resource_type {
+            ResourceType::Endpoint | ResourceType::Cluster => {
+                self.frozen.check("xds", "clusters")?
+            }
+            ResourceType::Listener => self.frozen.check("xds", "filters")?,
+            resource => return Err(eyre::eyre!("Unsupported resource {}", resource.type_url())),
+        }
 
         Ok(())
     }
 
+    /// Removes a resource that passed [`Config::validate_removed`].
+    fn commit_removed(&self, resource_type: ResourceType, name: &str) {
+        match resource_type {
+            ResourceType::Endpoint | ResourceType::Cluster => {
+                match self.clusters.load().get(name) {
+                    None => return,
+                    Some(cluster) if cluster.pinned => {
+                        report_pinned_conflict(name);
+                        return;
+                    }
+                    Some(_) => {}
+                }
+
+                self.clusters.modify(|clusters| {
+                    clusters.remove(name);
+                });
+            }
+            ResourceType::Listener => self.filters.store(<_>::default()),
+            _ => {}
+        }
+    }
+
+    /// Validates the metadata of every cluster's endpoints against
+    /// [`Config::metadata_schema`].
+    pub fn validate_metadata(&self) -> Result<(), crate::metadata::SchemaError> {
+        let schema = self.metadata_schema.load();
+        self.clusters
+            .load()
+            .values()
+            .try_for_each(|cluster| schema.validate(cluster))
+    }
+
     pub fn apply_metrics(&self) {
+        self.apply_count_metrics();
+        self.hash().record();
+    }
+
+    fn apply_count_metrics(&self) {
         let clusters = self.clusters.load();
 
         crate::cluster::active_clusters().set(clusters.len() as i64);
         crate::cluster::active_endpoints().set(clusters.endpoints().count() as i64);
     }
+
+    /// Returns the hash of the currently applied clusters and filters.
+    pub fn hash(&self) -> ConfigHash {
+        ConfigHash::new(self.clusters.load().values(), &self.filters.load())
+    }
 }
 
 impl Default for Config {
@@ -196,16 +594,65 @@ impl Default for Config {
             filters: <_>::default(),
             id: default_proxy_id(),
             version: Slot::with_default(),
+            quotas: <_>::default(),
+            unrouted: <_>::default(),
+            metadata_schema: <_>::default(),
+            address_discovery: <_>::default(),
+            invalid_endpoints: <_>::default(),
+            health_check: <_>::default(),
+            sessions: <_>::default(),
+            roles: <_>::default(),
+            xds_auth: <_>::default(),
+            filter_reloads: <_>::default(),
+            frozen: <_>::default(),
+            mitigations: <_>::default(),
+            rejections: <_>::default(),
+            rate_limits: <_>::default(),
+            filter_budget: <_>::default(),
         }
     }
 }
 
+/// Logs and counts an update from a management server to a pinned cluster,
+/// which was ignored.
+fn report_pinned_conflict(cluster: &str) {
+    tracing::warn!(%cluster, "ignoring update from management server to pinned cluster");
+    crate::cluster::pinned_conflicts_total(cluster).inc();
+}
+
+/// An xDS resource that passed validation, which can be applied without
+/// failing.
+enum Validated {
+    /// A cluster without any endpoints, which is ignored.
+    Nothing,
+    Cluster(Cluster, Option<XdsClusterSettings>),
+    /// The settings of a cluster whose endpoints are sent separately.
+    ClusterSettings(String, XdsClusterSettings),
+    Listener(Vec<Filter>, crate::filters::FilterChain),
+}
+
+/// The settings of a cluster that are only set by xDS `Cluster` resources,
+/// rather than those of its endpoints.
+#[derive(Clone, Copy)]
+struct XdsClusterSettings {
+    sessions: Option<SessionSettings>,
+    sampling: Option<Sampling>,
+}
+
 impl PartialEq for Config {
     fn eq(&self, rhs: &Self) -> bool {
         self.id == rhs.id
             && self.clusters == rhs.clusters
             && self.filters == rhs.filters
             && self.version == rhs.version
+            && self.quotas == rhs.quotas
+            && self.unrouted == rhs.unrouted
+            && self.metadata_schema == rhs.metadata_schema
+            && self.address_discovery == rhs.address_discovery
+            && self.invalid_endpoints == rhs.invalid_endpoints
+            && self.health_check == rhs.health_check
+            && self.sessions == rhs.sessions
+            && self.roles == rhs.roles
     }
 }
 
@@ -308,7 +755,10 @@ mod tests {
     use serde_json::json;
 
     use super::*;
-    use crate::endpoint::{Endpoint, Metadata};
+    use crate::{
+        cluster::ClusterMap,
+        endpoint::{Endpoint, Metadata},
+    };
 
     fn parse_config(yaml: &str) -> Config {
         Config::from_reader(yaml.as_bytes()).unwrap()
@@ -437,6 +887,294 @@ id: server-proxy
         );
     }
 
+    #[test]
+    fn apply_validates_metadata() {
+        let config = parse_config(
+            "
+metadata_schema:
+  studio.example:
+    keys:
+      game_mode: { type: string, required: true }
+",
+        );
+
+        let endpoint = |metadata: serde_json::Value| {
+            Endpoint::with_metadata(
+                "127.0.0.1:7777".parse().unwrap(),
+                crate::metadata::MetadataView::with_unknown(
+                    Metadata::default(),
+                    serde_json::from_value(metadata).unwrap(),
+                ),
+            )
+        };
+        let resource = |endpoint: Endpoint| {
+            Resource::Endpoint(Box::new(ClusterLoadAssignment::from(Cluster::new_default(
+                vec![crate::endpoint::LocalityEndpoints::from(endpoint)],
+            ))))
+        };
+
+        let error = config
+            .apply(&resource(endpoint(
+                json!({ "studio.example": { "game_mode": 1 } }),
+            )))
+            .unwrap_err();
+        assert_eq!(
+            "endpoint `127.0.0.1:7777` in cluster `default` has invalid metadata: `studio.example.game_mode` must be a string, found number",
+            error.to_string()
+        );
+        assert!(config.clusters.load().is_empty());
+
+        config
+            .apply(&resource(endpoint(
+                json!({ "studio.example": { "game_mode": "ranked" } }),
+            )))
+            .unwrap();
+        assert_eq!(1, config.clusters.load().endpoints().count());
+        config.validate_metadata().unwrap();
+    }
+
+    #[test]
+    fn apply_keeps_pinned_clusters() {
+        let config = parse_config(
+            "
+clusters:
+  pinned:
+    pinned: true
+    localities:
+      - endpoints:
+          - address: 127.0.0.1:7777
+",
+        );
+
+        let resource = |name: &str| {
+            Resource::Endpoint(Box::new(ClusterLoadAssignment::from(Cluster::new(
+                name.into(),
+                vec![crate::endpoint::LocalityEndpoints::from(Endpoint::new(
+                    "127.0.0.1:8888".parse().unwrap(),
+                ))],
+            ))))
+        };
+
+        config.apply(&resource("pinned")).unwrap();
+        config.apply(&resource("managed")).unwrap();
+
+        let clusters = config.clusters.load();
+        let pinned = clusters.get("pinned").unwrap();
+        assert!(pinned.pinned);
+        assert_eq!(
+            vec!["127.0.0.1:7777"],
+            pinned
+                .endpoints()
+                .map(|endpoint| endpoint.address.to_string())
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(1, clusters.get("managed").unwrap().endpoints().count());
+        assert_eq!(1, crate::cluster::pinned_conflicts_total("pinned").get());
+    }
+
+    #[test]
+    fn apply_keeps_session_settings() {
+        let config = Config::default();
+        let settings = SessionSettings {
+            max_sessions: Some(10),
+            ..<_>::default()
+        };
+
+        let mut cluster = Cluster::new_default(vec![crate::endpoint::LocalityEndpoints::from(
+            Endpoint::new("127.0.0.1:7777".parse().unwrap()),
+        )]);
+        let sampling = Sampling {
+            rate: 100,
+            max_payload_bytes: 16,
+            hash_addresses: true,
+        };
+        cluster.sessions = Some(settings);
+        cluster.sampling = Some(sampling);
+        let mut xds = crate::xds::config::cluster::v3::Cluster::from(&cluster);
+        xds.load_assignment = None;
+
+        config
+            .apply(&Resource::Cluster(Box::new(xds.clone())))
+            .unwrap();
+        assert_eq!(
+            Some(settings),
+            config.clusters.load().get_default().unwrap().sessions
+        );
+
+        cluster.sessions = None;
+        cluster.sampling = None;
+        config
+            .apply(&Resource::Endpoint(Box::new(cluster.into())))
+            .unwrap();
+        let clusters = config.clusters.load();
+        let default = clusters.get_default().unwrap();
+        assert_eq!(Some(settings), default.sessions);
+        assert_eq!(Some(sampling), default.sampling);
+        assert_eq!(1, default.endpoints().count());
+    }
+
+    #[test]
+    fn apply_skips_invalid_endpoints() {
+        let resource = || {
+            let mut cla = ClusterLoadAssignment::from(Cluster::new_default(vec![
+                crate::endpoint::LocalityEndpoints::from(Endpoint::new(
+                    "127.0.0.1:7777".parse().unwrap(),
+                )),
+            ]));
+            cla.endpoints[0]
+                .lb_endpoints
+                .push(crate::xds::config::endpoint::v3::LbEndpoint::default());
+            Resource::Endpoint(Box::new(cla))
+        };
+
+        let config = Config::default();
+        assert!(config.apply(&resource()).is_err());
+        assert!(config.clusters.load().is_empty());
+
+        let config = parse_config("invalid_endpoints: skip");
+        config.apply(&resource()).unwrap();
+        assert_eq!(1, config.clusters.load().endpoints().count());
+
+        // Skipped endpoints are returned to be reported to the server.
+        let config = parse_config("invalid_endpoints: skip");
+        let skipped = config.apply_all(&[resource()], &[], Some("1")).unwrap();
+        assert_eq!(1, skipped.len());
+        assert_eq!(1, config.clusters.load().endpoints().count());
+    }
+
+    #[test]
+    fn apply_all_rejects_the_whole_update() {
+        let endpoint = |cluster: &str, address: &str| {
+            let cluster = Cluster::new(
+                cluster.into(),
+                vec![crate::endpoint::LocalityEndpoints::from(Endpoint::new(
+                    address.parse().unwrap(),
+                ))],
+            );
+            Resource::Endpoint(Box::new(cluster.into()))
+        };
+        let mut invalid =
+            ClusterLoadAssignment::from(Cluster::new("invalid".into(), <_>::default()));
+        invalid.endpoints.push(<_>::default());
+        invalid.endpoints[0]
+            .lb_endpoints
+            .push(crate::xds::config::endpoint::v3::LbEndpoint::default());
+
+        let config = Config::default();
+        config
+            .apply_all(&[endpoint("first", "127.0.0.1:7777")], &[], Some("1"))
+            .unwrap();
+
+        let error = config
+            .apply_all(
+                &[
+                    endpoint("second", "127.0.0.1:8888"),
+                    Resource::Endpoint(Box::new(invalid)),
+                ],
+                &[(ResourceType::Endpoint, "first".into())],
+                Some("2"),
+            )
+            .unwrap_err();
+        assert_eq!(
+            "invalid endpoint 0 of locality 0 in cluster `invalid`: no host found for endpoint",
+            error.to_string()
+        );
+
+        // Neither the valid resource nor the removal was applied.
+        let clusters = config.clusters.load();
+        assert!(clusters.get("first").is_some());
+        assert!(clusters.get("second").is_none());
+    }
+
+    #[test]
+    fn frozen_rejects_changes() {
+        let resource = |address: &str| {
+            Resource::Endpoint(Box::new(
+                Cluster::new_default(vec![crate::endpoint::LocalityEndpoints::from(
+                    Endpoint::new(address.parse().unwrap()),
+                )])
+                .into(),
+            ))
+        };
+
+        let config = Config::default();
+        config.apply(&resource("127.0.0.1:7777")).unwrap();
+        config.frozen.freeze();
+
+        let error = config.apply(&resource("127.0.0.1:8888")).unwrap_err();
+        assert_eq!(
+            "the config is frozen, its clusters can only be changed by redeploying",
+            error.to_string()
+        );
+        assert!(config
+            .apply_removed(ResourceType::Cluster, "default")
+            .is_err());
+        assert!(config
+            .reload("file", b"version: v1alpha1\nclusters: {}")
+            .is_err());
+        assert_eq!(
+            "127.0.0.1:7777",
+            config
+                .clusters
+                .load()
+                .endpoints()
+                .next()
+                .unwrap()
+                .address
+                .to_string()
+        );
+    }
+
+    #[test]
+    #[cfg(all(feature = "filter-capture", feature = "filter-debug"))]
+    fn apply_records_filter_reloads() {
+        use crate::filters::{Capture, Debug};
+        use crate::xds::config::listener::v3::{
+            Filter as XdsFilter, FilterChain as XdsFilterChain,
+        };
+
+        let listener = |names: &[&str]| {
+            Resource::Listener(Box::new(Listener {
+                filter_chains: vec![XdsFilterChain {
+                    filters: names
+                        .iter()
+                        .map(|name| XdsFilter {
+                            name: (*name).into(),
+                            config_type: None,
+                        })
+                        .collect(),
+                    ..<_>::default()
+                }],
+                ..<_>::default()
+            }))
+        };
+
+        let config = Config::default();
+        config
+            .apply_version(&listener(&[Debug::NAME]), Some("1"))
+            .unwrap();
+        assert!(config
+            .apply_version(&listener(&[Debug::NAME, Capture::NAME]), Some("2"))
+            .is_err());
+        assert_eq!(1, config.filters.load().len());
+
+        let statuses = config.filter_reloads.snapshot();
+        let debug = &statuses[Debug::NAME];
+        assert_eq!(Some("1"), debug.accepted_version.as_deref());
+        assert_eq!((1, 0), (debug.accepted_total, debug.rejected_total));
+
+        let capture = &statuses[Capture::NAME];
+        assert_eq!(None, capture.accepted_version);
+        assert_eq!(Some("2"), capture.rejected_version.as_deref());
+        assert_eq!(
+            Some(format!(
+                "filter `{}` requires configuration, but none provided",
+                Capture::NAME
+            )),
+            capture.rejection_reason
+        );
+    }
+
     #[test]
     fn deny_unused_fields() {
         let configs = vec![
@@ -0,0 +1,178 @@
+/*
+ * Copyright 2023 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Metrics of the xDS resources applied to the configuration, how long they
+//! took and how large they were, so that slow convergence with the
+//! management server can be diagnosed at scale.
+
+use std::{sync::Arc, time::Instant};
+
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use prometheus::{
+    core::{Collector, Desc},
+    proto::MetricFamily,
+    Gauge, Histogram, HistogramVec,
+};
+
+use crate::xds::{Resource, ResourceType};
+
+const TYPE_LABEL: &str = "type";
+
+static DURATION: Lazy<HistogramVec> = Lazy::new(|| {
+    prometheus::register_histogram_vec_with_registry! {
+        prometheus::histogram_opts! {
+            "config_apply_duration_seconds",
+            "How long applying each xDS resource to the configuration took",
+            prometheus::exponential_buckets(
+                crate::metrics::BUCKET_START,
+                crate::metrics::BUCKET_FACTOR,
+                crate::metrics::BUCKET_COUNT,
+            )
+            .unwrap(),
+        },
+        &[TYPE_LABEL],
+        crate::metrics::registry(),
+    }
+    .unwrap()
+});
+
+static ENDPOINTS: Lazy<HistogramVec> = Lazy::new(|| {
+    prometheus::register_histogram_vec_with_registry! {
+        prometheus::histogram_opts! {
+            "config_apply_endpoints",
+            "The number of endpoints in each xDS resource applied to the configuration",
+            prometheus::exponential_buckets(1.0, 4.0, 8).unwrap(),
+        },
+        &[TYPE_LABEL],
+        crate::metrics::registry(),
+    }
+    .unwrap()
+});
+
+static FILTERS: Lazy<Histogram> = Lazy::new(|| {
+    prometheus::register_histogram_with_registry! {
+        prometheus::histogram_opts! {
+            "config_apply_filters",
+            "The number of filters in each listener applied to the configuration",
+            vec![0.0, 1.0, 2.0, 4.0, 8.0, 16.0, 32.0],
+        },
+        crate::metrics::registry(),
+    }
+    .unwrap()
+});
+
+static LAST_APPLIED_AGE: Lazy<LastAppliedAge> =
+    Lazy::new(|| crate::metrics::register(LastAppliedAge::new()));
+
+/// Reports the age of the last successful apply when gathered, and nothing
+/// until a resource has been applied.
+#[derive(Clone)]
+struct LastAppliedAge {
+    gauge: Gauge,
+    applied: Arc<Mutex<Option<Instant>>>,
+}
+
+impl LastAppliedAge {
+    fn new() -> Self {
+        Self {
+            gauge: Gauge::new(
+                "config_last_apply_age_seconds",
+                "The number of seconds since an xDS resource was last applied to the configuration successfully",
+            )
+            .unwrap(),
+            applied: <_>::default(),
+        }
+    }
+
+    fn applied(&self, at: Instant) {
+        *self.applied.lock() = Some(at);
+    }
+}
+
+impl Collector for LastAppliedAge {
+    fn desc(&self) -> Vec<&Desc> {
+        self.gauge.desc()
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        let Some(applied) = *self.applied.lock() else {
+            return Vec::new();
+        };
+
+        self.gauge.set(applied.elapsed().as_secs_f64());
+        self.gauge.collect()
+    }
+}
+
+/// Starts timing how long applying a resource of `resource_type` takes.
+pub(super) fn start(resource_type: ResourceType) -> prometheus::HistogramTimer {
+    DURATION
+        .with_label_values(&[resource_type.type_url()])
+        .start_timer()
+}
+
+/// Records that applying a resource of `resource_type` took `duration`.
+pub(super) fn observe(resource_type: ResourceType, duration: std::time::Duration) {
+    DURATION
+        .with_label_values(&[resource_type.type_url()])
+        .observe(duration.as_secs_f64());
+}
+
+/// Records the size of `resource`, which was applied successfully.
+pub(super) fn applied(resource: &Resource) {
+    LAST_APPLIED_AGE.applied(Instant::now());
+
+    let endpoints = match resource {
+        Resource::Endpoint(cla) => Some(&**cla),
+        Resource::Cluster(cluster) => cluster.load_assignment.as_ref(),
+        Resource::Listener(listener) => {
+            let filters = listener
+                .filter_chains
+                .get(0)
+                .map_or(0, |chain| chain.filters.len());
+            FILTERS.observe(filters as f64);
+            None
+        }
+    };
+
+    if let Some(cla) = endpoints {
+        let count: usize = cla
+            .endpoints
+            .iter()
+            .map(|locality| locality.lb_endpoints.len())
+            .sum();
+        ENDPOINTS
+            .with_label_values(&[resource.type_url()])
+            .observe(count as f64);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn last_applied_age() {
+        let age = LastAppliedAge::new();
+        assert!(age.collect().is_empty());
+
+        age.applied(Instant::now() - std::time::Duration::from_secs(5));
+        let families = age.collect();
+        let age = families[0].get_metric()[0].get_gauge().get_value();
+        assert!((5.0..6.0).contains(&age), "{age}");
+    }
+}
@@ -86,6 +86,54 @@ impl ConfigType {
         }
     }
 
+    /// Upgrades a static config written for an earlier version of
+    /// `filter_name`, see [`ConfigType::upgrade_value`]. Dynamic configs are
+    /// left as they are, as renaming a protobuf field keeps its number.
+    pub fn upgrade(
+        self,
+        filter_name: &str,
+        renamed_fields: &[(&str, &str)],
+    ) -> Result<Self, Error> {
+        match self {
+            Self::Static(mut config) => {
+                Self::upgrade_value(filter_name, renamed_fields, &mut config)?;
+                Ok(Self::Static(config))
+            }
+            Self::Dynamic(_) => Ok(self),
+        }
+    }
+
+    /// Moves each field of `config` at an old path of `renamed_fields` to
+    /// its new path with a warning. See
+    /// [`StaticFilter::RENAMED_FIELDS`][crate::filters::StaticFilter::RENAMED_FIELDS].
+    pub(crate) fn upgrade_value(
+        filter_name: &str,
+        renamed_fields: &[(&str, &str)],
+        config: &mut serde_json::Value,
+    ) -> Result<(), Error> {
+        for (old, new) in renamed_fields {
+            let Some(value) = take_path(config, old) else {
+                continue;
+            };
+
+            tracing::warn!(
+                filter = filter_name,
+                old,
+                new,
+                "upgrading deprecated filter config field"
+            );
+            crate::metrics::filter_config_upgrades_total(filter_name, old).inc();
+            if !insert_path(config, new, value) {
+                return Err(Error::FieldInvalid {
+                    field: (*old).into(),
+                    reason: format!("can't be set along with its new name `{new}`"),
+                });
+            }
+        }
+
+        Ok(())
+    }
+
     // Returns an equivalent json value for the passed in config.
     fn get_json_config<T>(filter_name: &str, config: &T) -> Result<serde_json::Value, Error>
     where
@@ -99,6 +147,47 @@ impl ConfigType {
     }
 }
 
+/// Removes and returns the value at the dotted `path` of `value`, if any.
+fn take_path(value: &mut serde_json::Value, path: &str) -> Option<serde_json::Value> {
+    let (parent, field) = match path.rsplit_once('.') {
+        Some((parent, field)) => (
+            parent
+                .split('.')
+                .try_fold(value, |value, field| value.get_mut(field))?,
+            field,
+        ),
+        None => (value, path),
+    };
+
+    parent.as_object_mut()?.remove(field)
+}
+
+/// Inserts `field_value` at the dotted `path` of `value`, creating any missing
+/// objects along the way, returning `false` if something is already there.
+fn insert_path(value: &mut serde_json::Value, path: &str, field_value: serde_json::Value) -> bool {
+    let mut fields = path.split('.').peekable();
+    let mut value = value;
+    while let Some(field) = fields.next() {
+        let Some(object) = value.as_object_mut() else {
+            return false;
+        };
+
+        if fields.peek().is_none() {
+            if object.contains_key(field) {
+                return false;
+            }
+            object.insert(field.into(), field_value);
+            return true;
+        }
+
+        value = object
+            .entry(field)
+            .or_insert_with(|| serde_json::Value::Object(<_>::default()));
+    }
+
+    false
+}
+
 impl<'de> serde::Deserialize<'de> for ConfigType {
     fn deserialize<D>(de: D) -> Result<Self, D::Error>
     where
@@ -133,6 +222,37 @@ mod tests {
     use super::ConfigType;
     use serde::{Deserialize, Serialize};
 
+    #[test]
+    fn upgrade() {
+        let renamed = [("strategy", "on_read"), ("old.size", "new.nested.size")];
+        let upgrade =
+            |config: serde_json::Value| ConfigType::Static(config).upgrade("my-filter", &renamed);
+
+        assert_eq!(
+            Ok(ConfigType::Static(serde_json::json!({
+                "on_read": "APPEND",
+                "old": {},
+                "new": { "nested": { "size": 3 } },
+                "bytes": "abc",
+            }))),
+            upgrade(serde_json::json!({
+                "strategy": "APPEND",
+                "old": { "size": 3 },
+                "bytes": "abc",
+            }))
+        );
+
+        let current = serde_json::json!({ "on_read": "APPEND" });
+        assert_eq!(Ok(ConfigType::Static(current.clone())), upgrade(current));
+
+        assert!(
+            upgrade(serde_json::json!({ "strategy": "APPEND", "on_read": "PREPEND" })).is_err()
+        );
+
+        let dynamic = ConfigType::Dynamic(prost_types::Any::default());
+        assert_eq!(Ok(dynamic.clone()), dynamic.upgrade("my-filter", &renamed));
+    }
+
     #[test]
     fn get_json_config() {
         #[derive(Debug, Eq, PartialEq, Clone, Serialize, Deserialize)]
@@ -0,0 +1,101 @@
+/*
+ * Copyright 2023 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Frozen configurations, whose clusters and filters are only changed by
+//! redeploying, for deployments where every change must go through review.
+
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+
+use once_cell::sync::Lazy;
+use prometheus::IntCounterVec;
+
+const SOURCE_LABEL: &str = "source";
+const RESOURCE_LABEL: &str = "resource";
+
+static FROZEN_REJECTIONS_TOTAL: Lazy<IntCounterVec> = Lazy::new(|| {
+    prometheus::register_int_counter_vec_with_registry! {
+        prometheus::opts! {
+            "config_frozen_rejections_total",
+            "Total number of changes rejected because the config is frozen",
+        },
+        &[SOURCE_LABEL, RESOURCE_LABEL],
+        crate::metrics::registry(),
+    }
+    .unwrap()
+});
+
+/// A change rejected because the config is frozen.
+#[derive(Debug, thiserror::Error)]
+#[error("the config is frozen, its {resource} can only be changed by redeploying")]
+pub struct FrozenError {
+    /// What tried to change the config, such as `xds`.
+    pub origin: &'static str,
+    pub resource: &'static str,
+}
+
+/// Whether a config's clusters and filters are frozen, shared between its
+/// clones.
+#[derive(Clone, Debug, Default)]
+pub struct Frozen(Arc<AtomicBool>);
+
+impl Frozen {
+    /// Rejects every later change to the clusters and filters.
+    pub fn freeze(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_frozen(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    /// Checks whether `origin` may change `resource`, either `clusters` or
+    /// `filters`, counting and logging the change when it's rejected.
+    pub(crate) fn check(
+        &self,
+        origin: &'static str,
+        resource: &'static str,
+    ) -> Result<(), FrozenError> {
+        if !self.is_frozen() {
+            return Ok(());
+        }
+
+        tracing::warn!(origin, resource, "rejecting change to frozen config");
+        FROZEN_REJECTIONS_TOTAL
+            .with_label_values(&[origin, resource])
+            .inc();
+        Err(FrozenError { origin, resource })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check() {
+        let frozen = Frozen::default();
+        assert!(frozen.check("xds", "clusters").is_ok());
+
+        frozen.clone().freeze();
+        let rejections = FROZEN_REJECTIONS_TOTAL.with_label_values(&["test", "filters"]);
+        let before = rejections.get();
+        assert!(frozen.check("test", "filters").is_err());
+        assert_eq!(before + 1, rejections.get());
+    }
+}
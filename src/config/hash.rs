@@ -0,0 +1,211 @@
+/*
+ * Copyright 2023 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Stable hashes of the applied configuration, which proxies report to their
+//! management server so that it can detect updates that were only partially
+//! applied, or never applied at all.
+
+use std::{fmt, str::FromStr};
+
+use once_cell::sync::Lazy;
+use prometheus::IntGaugeVec;
+
+use crate::{cluster::Cluster, filters::FilterChain, xds::config::core::v3::Node};
+
+/// The key of the config hash in the metadata of xDS nodes.
+pub(crate) const NODE_METADATA_KEY: &str = "quilkin.dev/config_hash";
+
+const HASH_LABEL: &str = "hash";
+const FNV_OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+
+static CONFIG_HASH: Lazy<IntGaugeVec> = Lazy::new(|| {
+    prometheus::register_int_gauge_vec_with_registry! {
+        prometheus::opts! {
+            "config_hash",
+            "Set to 1 for the hash of the currently applied clusters and filters",
+        },
+        &[HASH_LABEL],
+        crate::metrics::registry(),
+    }
+    .unwrap()
+});
+
+/// The hash of a set of clusters and a filter chain, which is the same for
+/// equal configurations regardless of the order clusters, localities and
+/// endpoints were added in.
+///
+/// Clusters without any endpoints aren't included, as proxies don't apply
+/// them, nor are fields that are only set locally (such as DTLS settings).
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct ConfigHash {
+    pub clusters: u64,
+    pub filters: u64,
+}
+
+impl ConfigHash {
+    /// Hashes `clusters` and `filters`.
+    pub fn new<'cluster>(
+        clusters: impl IntoIterator<Item = &'cluster Cluster>,
+        filters: &FilterChain,
+    ) -> Self {
+        let mut clusters = clusters
+            .into_iter()
+            .filter(|cluster| cluster.endpoints().next().is_some())
+            .map(|cluster| {
+                let mut localities = serde_json::to_value(&cluster.localities).unwrap();
+                canonicalize(&mut localities);
+                (&*cluster.name, localities)
+            })
+            .collect::<Vec<_>>();
+        clusters.sort_by(|a, b| a.0.cmp(b.0));
+
+        Self {
+            clusters: fnv1a(&serde_json::to_vec(&clusters).unwrap()),
+            filters: fnv1a(&serde_json::to_vec(filters).unwrap()),
+        }
+    }
+
+    /// Reads the hash a proxy reported in the metadata of `node`, if any.
+    pub(crate) fn from_node(node: &Node) -> Option<Self> {
+        use prost_types::value::Kind;
+
+        match node.metadata.as_ref()?.fields.get(NODE_METADATA_KEY)?.kind {
+            Some(Kind::StringValue(ref hash)) => hash.parse().ok(),
+            _ => None,
+        }
+    }
+
+    /// Returns node metadata containing this hash.
+    pub(crate) fn to_node_metadata(self) -> prost_types::Struct {
+        prost_types::Struct {
+            fields: [(
+                NODE_METADATA_KEY.into(),
+                prost_types::Value {
+                    kind: Some(prost_types::value::Kind::StringValue(self.to_string())),
+                },
+            )]
+            .into_iter()
+            .collect(),
+        }
+    }
+
+    /// Exports this hash as the currently applied configuration.
+    pub(crate) fn record(self) {
+        CONFIG_HASH.reset();
+        CONFIG_HASH.with_label_values(&[&self.to_string()]).set(1);
+    }
+}
+
+impl fmt::Display for ConfigHash {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:016x}-{:016x}", self.clusters, self.filters)
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, thiserror::Error)]
+#[error("invalid config hash `{0}`")]
+pub struct InvalidConfigHash(String);
+
+impl FromStr for ConfigHash {
+    type Err = InvalidConfigHash;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        let invalid = || InvalidConfigHash(input.into());
+        let (clusters, filters) = input.split_once('-').ok_or_else(invalid)?;
+
+        Ok(Self {
+            clusters: u64::from_str_radix(clusters, 16).map_err(|_| invalid())?,
+            filters: u64::from_str_radix(filters, 16).map_err(|_| invalid())?,
+        })
+    }
+}
+
+/// Sorts every array within `value`, as localities and endpoints are
+/// unordered sets. Object keys are already sorted by `serde_json`.
+fn canonicalize(value: &mut serde_json::Value) {
+    match value {
+        serde_json::Value::Array(values) => {
+            values.iter_mut().for_each(canonicalize);
+            values.sort_by_cached_key(|value| value.to_string());
+        }
+        serde_json::Value::Object(map) => map.values_mut().for_each(canonicalize),
+        _ => {}
+    }
+}
+
+/// The 64 bit FNV-1a hash of `bytes`, which unlike the standard library's
+/// hashers is guaranteed to be the same across builds and platforms.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(FNV_OFFSET_BASIS, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(FNV_PRIME)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::endpoint::{Endpoint, Locality, LocalityEndpoints};
+
+    fn cluster(name: &str, localities: &[(&str, u16)]) -> Cluster {
+        Cluster::new(
+            name.into(),
+            localities
+                .iter()
+                .map(|(region, port)| {
+                    LocalityEndpoints::from(Endpoint::new(
+                        (std::net::Ipv4Addr::LOCALHOST, *port).into(),
+                    ))
+                    .with_locality(Locality {
+                        region: (*region).into(),
+                        ..<_>::default()
+                    })
+                })
+                .collect::<Vec<_>>(),
+        )
+    }
+
+    #[test]
+    fn stable() {
+        let filters = FilterChain::default();
+        let a = [
+            cluster("a", &[("us-east", 1), ("eu-west", 2)]),
+            cluster("b", &[("us-east", 3)]),
+        ];
+        let b = [
+            cluster("b", &[("us-east", 3)]),
+            cluster("a", &[("eu-west", 2), ("us-east", 1)]),
+        ];
+
+        let hash = ConfigHash::new(&a, &filters);
+        assert_eq!(hash, ConfigHash::new(&b, &filters));
+        assert_ne!(hash, ConfigHash::new(&a[..1], &filters));
+        assert_eq!(
+            hash,
+            ConfigHash::new(a.iter().chain([&cluster("empty", &[])]), &filters)
+        );
+
+        assert_eq!(Ok(hash), hash.to_string().parse());
+        assert!("abc".parse::<ConfigHash>().is_err());
+
+        let node = Node {
+            metadata: Some(hash.to_node_metadata()),
+            ..<_>::default()
+        };
+        assert_eq!(Some(hash), ConfigHash::from_node(&node));
+        assert_eq!(None, ConfigHash::from_node(&Node::default()));
+    }
+}
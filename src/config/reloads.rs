@@ -0,0 +1,95 @@
+/*
+ * Copyright 2023 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::{
+    collections::BTreeMap,
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use parking_lot::Mutex;
+use serde::Serialize;
+
+/// The outcome of the latest updates to a filter's config from management
+/// servers.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct FilterStatus {
+    /// The version of the last update the filter's config was accepted in.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub accepted_version: Option<String>,
+    /// When the filter's config was last accepted, in milliseconds since the
+    /// UNIX epoch.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub accepted_at_ms: Option<u64>,
+    pub accepted_total: u64,
+    /// The version of the last update the filter's config was rejected in.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rejected_version: Option<String>,
+    /// The rejected config, if it could be decoded.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rejected_config: Option<serde_json::Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rejection_reason: Option<String>,
+    /// When the filter's config was last rejected, in milliseconds since the
+    /// UNIX epoch.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rejected_at_ms: Option<u64>,
+    pub rejected_total: u64,
+}
+
+/// The [`FilterStatus`] of every filter that has been updated, keyed by the
+/// filter's name.
+#[derive(Clone, Debug, Default)]
+pub struct FilterReloads(Arc<Mutex<BTreeMap<String, FilterStatus>>>);
+
+impl FilterReloads {
+    pub(crate) fn accepted(&self, filter: &str, version: Option<&str>) {
+        let mut statuses = self.0.lock();
+        let status = statuses.entry(filter.into()).or_default();
+        status.accepted_version = version.map(From::from);
+        status.accepted_at_ms = Some(now_ms());
+        status.accepted_total += 1;
+    }
+
+    pub(crate) fn rejected(
+        &self,
+        filter: &str,
+        version: Option<&str>,
+        config: Option<serde_json::Value>,
+        reason: &dyn std::fmt::Display,
+    ) {
+        tracing::warn!(%filter, version, %reason, "rejected filter config");
+        let mut statuses = self.0.lock();
+        let status = statuses.entry(filter.into()).or_default();
+        status.rejected_version = version.map(From::from);
+        status.rejected_config = config;
+        status.rejection_reason = Some(reason.to_string());
+        status.rejected_at_ms = Some(now_ms());
+        status.rejected_total += 1;
+    }
+
+    /// Returns the status of every filter that has been updated.
+    pub fn snapshot(&self) -> BTreeMap<String, FilterStatus> {
+        self.0.lock().clone()
+    }
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
@@ -0,0 +1,73 @@
+/*
+ * Copyright 2023 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+/// The roles `quilkin run` starts in a single process, sharing the rest of
+/// the configuration.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct Roles {
+    /// Runs a proxy, as `quilkin proxy` would.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub proxy: Option<Role>,
+    /// Runs a management server, as `quilkin manage` would.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub manage: Option<Role>,
+    /// Serves the admin endpoints, unless `--no-admin` is set. The readiness
+    /// of a proxy is reported when it runs, and that of the management
+    /// server otherwise.
+    #[serde(default = "default_admin")]
+    pub admin: bool,
+}
+
+fn default_admin() -> bool {
+    true
+}
+
+impl Default for Roles {
+    fn default() -> Self {
+        Self {
+            proxy: None,
+            manage: None,
+            admin: default_admin(),
+        }
+    }
+}
+
+impl Roles {
+    /// Returns whether no role other than the admin server is set.
+    pub fn is_empty(&self) -> bool {
+        self.proxy.is_none() && self.manage.is_none()
+    }
+
+    pub(crate) fn is_default(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+/// A role started by `quilkin run`.
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct Role {
+    /// The command line arguments of the role's command, such as
+    /// `["--port", "7777"]` for a proxy. Options that aren't set fall back to
+    /// their environment variables and defaults, as they would for the
+    /// command on its own.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub args: Vec<String>,
+}
@@ -14,19 +14,47 @@
  * limitations under the License.
  */
 
-use std::sync::Arc;
+use std::{
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
 
 use arc_swap::ArcSwapOption;
+use parking_lot::Mutex;
 use schemars::JsonSchema;
 
 use crate::filters::prelude::*;
 
+/// Incremented every time the data in any slot changes.
+static GENERATION: AtomicU64 = AtomicU64::new(0);
+
+/// Returns a number that changes whenever the data in any [`Slot`] changes,
+/// allowing values derived from configuration to be cached until the next
+/// configuration update.
+pub(crate) fn generation() -> u64 {
+    GENERATION.load(Ordering::Acquire)
+}
+
+/// Changes the number returned by [`generation`], for the state outside of
+/// any slot that values derived from configuration also depend on, such as
+/// the health of endpoints.
+pub(crate) fn next_generation() {
+    GENERATION.fetch_add(1, Ordering::AcqRel);
+}
+
 /// A mutable memory location with atomic storage rules.
 #[derive(Clone)]
 pub struct Slot<T> {
     inner: Arc<ArcSwapOption<T>>,
     #[allow(clippy::type_complexity)]
     watcher: Arc<ArcSwapOption<Box<dyn Fn(&T) + Send + Sync>>>,
+    #[allow(clippy::type_complexity)]
+    on_change: Arc<ArcSwapOption<Box<dyn Fn(&T, &T) + Send + Sync>>>,
+    #[allow(clippy::type_complexity)]
+    persister: Arc<ArcSwapOption<Box<dyn Fn(Arc<T>) + Send + Sync>>>,
 }
 
 impl<T> Slot<T> {
@@ -35,6 +63,8 @@ impl<T> Slot<T> {
         Self {
             inner: Arc::new(ArcSwapOption::new(value.into().map(Arc::new))),
             watcher: <_>::default(),
+            on_change: <_>::default(),
+            persister: <_>::default(),
         }
     }
 
@@ -50,6 +80,12 @@ impl<T> Slot<T> {
         self.watcher.store(Some(Arc::new(Box::new(watcher))));
     }
 
+    /// Sets a function called with the previous and the new value whenever
+    /// the slot's value changes, for computing what changed between them.
+    pub fn on_change(&self, on_change: impl Fn(&T, &T) + Send + Sync + 'static) {
+        self.on_change.store(Some(Arc::new(Box::new(on_change))));
+    }
+
     /// Returns whether any data is present in the slot.
     pub fn is_some(&self) -> bool {
         self.inner.load().is_some()
@@ -70,6 +106,21 @@ impl<T: Default> Slot<T> {
         }
     }
 
+    /// Writes the slot's value to its file, if it's persisted.
+    fn call_persister(&self) {
+        if let Some(persister) = &*self.persister.load() {
+            (persister)(self.load());
+        }
+    }
+
+    /// Triggers the `on_change` function, if present. An empty slot is
+    /// treated as holding the default instance of `T`.
+    fn call_on_change(&self, previous: Option<Arc<T>>, current: Option<Arc<T>>) {
+        if let Some(on_change) = &*self.on_change.load() {
+            (on_change)(&previous.unwrap_or_default(), &current.unwrap_or_default());
+        }
+    }
+
     /// Provides a reference to the underlying data.
     pub fn load(&self) -> Arc<T> {
         self.inner.load_full().unwrap_or_default()
@@ -77,8 +128,11 @@ impl<T: Default> Slot<T> {
 
     fn store_opt(&self, value: Option<Arc<T>>) {
         tracing::trace!("storing new value");
-        self.inner.store(value);
+        let previous = self.inner.swap(value.clone());
+        next_generation();
         self.call_watcher();
+        self.call_persister();
+        self.call_on_change(previous, value);
     }
 
     /// Replaces the data in the slot with `value`.
@@ -112,19 +166,115 @@ impl<T: Default + PartialEq> Slot<T> {
     }
 }
 
+impl<T> Slot<T>
+where
+    T: Default + serde::Serialize + serde::de::DeserializeOwned + Send + Sync + 'static,
+{
+    /// Restores the slot's value from the JSON file at `path`, if it exists,
+    /// and writes every later value back to it, so that the value survives
+    /// the process restarting. Each value is written to a temporary file
+    /// that then replaces `path`, so a crash never leaves a partial value.
+    /// Within a tokio runtime, later values are written on its blocking
+    /// threads, in the order they were stored.
+    pub fn persist(&self, path: impl Into<PathBuf>) -> crate::Result<()> {
+        let path = path.into();
+        match std::fs::read(&path) {
+            Ok(contents) => {
+                let value = serde_json::from_slice::<Option<T>>(&contents).map_err(|error| {
+                    eyre::eyre!("failed to restore `{}`: {error}", path.display())
+                })?;
+                tracing::info!(path = %path.display(), "restoring persisted state");
+                self.store_opt(value.map(Arc::new));
+            }
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => {
+                write_atomically(&path, &*self.inner.load())?;
+            }
+            Err(error) => return Err(error.into()),
+        }
+
+        let persister = Arc::new(Persister {
+            path,
+            stored: AtomicU64::new(0),
+            written: Mutex::new(0),
+        });
+        self.persister
+            .store(Some(Arc::new(Box::new(move |value: Arc<T>| {
+                let seq = persister.stored.fetch_add(1, Ordering::AcqRel) + 1;
+                let persister = persister.clone();
+                let write = move || persister.write(seq, &*value);
+                match tokio::runtime::Handle::try_current() {
+                    Ok(handle) => drop(handle.spawn_blocking(write)),
+                    Err(_) => write(),
+                }
+            }))));
+
+        Ok(())
+    }
+}
+
+/// Writes the values of a persisted slot to its file.
+struct Persister {
+    path: PathBuf,
+    /// The sequence number of the last value stored.
+    stored: AtomicU64,
+    /// The sequence number of the last value written, held while writing so
+    /// that a value is never overwritten by an older one.
+    written: Mutex<u64>,
+}
+
+impl Persister {
+    fn write(&self, seq: u64, value: &impl serde::Serialize) {
+        let mut written = self.written.lock();
+        if *written >= seq {
+            return;
+        }
+
+        match write_atomically(&self.path, value) {
+            Ok(()) => *written = seq,
+            Err(error) => {
+                tracing::warn!(path = %self.path.display(), %error, "failed to persist state")
+            }
+        }
+    }
+}
+
+/// Writes `value` as JSON to a uniquely named temporary file next to `path`,
+/// and then moves it over `path`.
+fn write_atomically(path: &Path, value: &impl serde::Serialize) -> crate::Result<()> {
+    use std::io::Write;
+
+    let dir = path
+        .parent()
+        .filter(|dir| !dir.as_os_str().is_empty())
+        .unwrap_or_else(|| Path::new("."));
+    let mut file = tempfile::NamedTempFile::new_in(dir)?;
+    serde_json::to_writer(&mut file, value)?;
+    file.flush()?;
+    file.as_file().sync_all()?;
+    file.persist(path).map_err(|error| error.error)?;
+
+    Ok(())
+}
+
 impl<T: Clone + Default> Slot<T> {
     /// Provides a view into a mutable reference of the current data in the
     /// slot. Any changes made will update the value in the slot.
     pub fn modify(&self, mut modify: impl FnMut(&mut T)) {
-        self.inner.rcu(|value| {
+        let mut modified = None;
+        let previous = self.inner.rcu(|value| {
             let mut current = value
                 .as_deref()
                 .map(|value| T::clone(value))
                 .unwrap_or_default();
             (modify)(&mut current);
-            Some(Arc::new(current))
+            let current = Arc::new(current);
+            modified = Some(current.clone());
+            Some(current)
         });
+        next_generation();
         self.call_watcher();
+        self.call_persister();
+        self.call_on_change(previous, modified);
     }
 }
 
@@ -133,6 +283,8 @@ impl<T: Default> Default for Slot<T> {
         Self {
             inner: Arc::new(ArcSwapOption::new(Some(Default::default()))),
             watcher: <_>::default(),
+            on_change: <_>::default(),
+            persister: <_>::default(),
         }
     }
 }
@@ -197,9 +349,17 @@ impl<T: crate::filters::Filter + Default> crate::filters::Filter for Slot<T> {
         self.load().read(ctx)
     }
 
+    fn read_batch(&self, ctxs: &mut [ReadContext]) -> Vec<Option<()>> {
+        self.load().read_batch(ctxs)
+    }
+
     fn write(&self, ctx: &mut WriteContext) -> Option<()> {
         self.load().write(ctx)
     }
+
+    fn has_write(&self) -> bool {
+        self.load().has_write()
+    }
 }
 
 #[cfg(test)]
@@ -223,4 +383,82 @@ mod tests {
             *slot.load()
         );
     }
+
+    #[test]
+    fn on_change() {
+        let changes = Arc::new(parking_lot::Mutex::new(Vec::new()));
+        let slot = Slot::new(1);
+
+        slot.on_change({
+            let changes = changes.clone();
+            move |previous, current| changes.lock().push((*previous, *current))
+        });
+
+        slot.store(Arc::new(2));
+        slot.modify(|value| *value += 1);
+        slot.remove();
+
+        assert_eq!(vec![(1, 2), (2, 3), (3, 0)], *changes.lock());
+    }
+
+    #[test]
+    fn persist() {
+        let dir = tempdir::TempDir::new("slot").unwrap();
+        let path = dir.path().join("value.json");
+
+        let slot = Slot::new(1);
+        slot.persist(&path).unwrap();
+        assert_eq!("1", std::fs::read_to_string(&path).unwrap());
+
+        slot.store(Arc::new(2));
+        slot.modify(|value| *value += 1);
+        assert_eq!("3", std::fs::read_to_string(&path).unwrap());
+
+        // A new process restores the last value.
+        let restored = Slot::new(1);
+        restored.persist(&path).unwrap();
+        assert_eq!(3, *restored.load());
+
+        std::fs::write(&path, "{").unwrap();
+        assert!(Slot::<u32>::new(1).persist(&path).is_err());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn persist_in_background() {
+        let dir = tempdir::TempDir::new("slot").unwrap();
+        let path = dir.path().join("value.json");
+
+        let slot = Slot::new(0);
+        slot.persist(&path).unwrap();
+        for value in 1..=100 {
+            slot.store(Arc::new(value));
+        }
+
+        // The writes finish on the blocking threads, with the last value
+        // written last.
+        let mut contents = String::new();
+        for _ in 0..100 {
+            contents = std::fs::read_to_string(&path).unwrap();
+            if contents == "100" {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        assert_eq!("100", contents);
+        // No temporary file is left behind.
+        assert_eq!(1, std::fs::read_dir(dir.path()).unwrap().count());
+    }
+
+    #[test]
+    fn generation_changes_on_update() {
+        let slot = Slot::new(1);
+
+        let before = generation();
+        slot.store(Arc::new(2));
+        assert!(generation() > before);
+
+        let before = generation();
+        slot.modify(|value| *value += 1);
+        assert!(generation() > before);
+    }
 }
@@ -15,6 +15,8 @@
  */
 
 pub mod agones;
+pub mod configmap;
 mod fs;
 
+pub(crate) use self::fs::watch_file as file;
 pub use self::{agones::watch as agones, fs::watch as fs};
@@ -0,0 +1,189 @@
+/*
+ * Copyright 2023 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Loads a proxy's configuration from a Kubernetes `ConfigMap`, and applies
+//! its changes while the proxy runs, so that the configuration of proxies on
+//! Kubernetes can be managed without mounting it as a file or running a
+//! management server.
+
+use std::{sync::Arc, time::Duration};
+
+use futures::TryStreamExt;
+use k8s_openapi::api::core::v1::ConfigMap;
+use kube::runtime::watcher::Event;
+
+use crate::Config;
+
+/// The key of the `ConfigMap`'s data holding the configuration.
+pub const DATA_KEY: &str = "quilkin.yaml";
+/// The origin of changes, as rejected by a frozen configuration.
+const ORIGIN: &str = "configmap";
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long to wait before watching again once the watch failed.
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// The `ConfigMap` the configuration is loaded from, as
+/// `<namespace>/<name>`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Source {
+    pub namespace: String,
+    pub name: String,
+}
+
+impl std::str::FromStr for Source {
+    type Err = eyre::Error;
+
+    fn from_str(source: &str) -> Result<Self, Self::Err> {
+        match source.split_once('/') {
+            Some((namespace, name))
+                if !namespace.is_empty() && !name.is_empty() && !name.contains('/') =>
+            {
+                Ok(Self {
+                    namespace: namespace.into(),
+                    name: name.into(),
+                })
+            }
+            _ => Err(eyre::eyre!(
+                "invalid configmap `{source}`, expected `<namespace>/<name>`"
+            )),
+        }
+    }
+}
+
+impl std::fmt::Display for Source {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.namespace, self.name)
+    }
+}
+
+/// Applies the `ConfigMap` at `source` to `config`, failing if it doesn't
+/// exist or is invalid, so that the proxy doesn't start without its
+/// configuration. Returns the contents that were applied.
+pub(crate) async fn load(
+    client: &kube::Client,
+    config: &Config,
+    source: &Source,
+) -> crate::Result<String> {
+    let configmap = api(client, source).get(&source.name).await?;
+    let contents = contents(&configmap)?;
+    config.reload(ORIGIN, contents.as_bytes())?;
+    tracing::info!(%source, "loaded configuration from configmap");
+    Ok(contents)
+}
+
+/// Connects to the Kubernetes API server of the cluster the proxy runs in.
+pub(crate) async fn client() -> crate::Result<kube::Client> {
+    Ok(tokio::time::timeout(CONNECT_TIMEOUT, kube::Client::try_default()).await??)
+}
+
+/// Applies every change to the `ConfigMap` at `source` to `config`, until
+/// dropped. `applied` is the contents that are already applied. Invalid
+/// contents are logged and ignored, as is the `ConfigMap` being deleted,
+/// keeping the current configuration.
+pub(crate) async fn watch(
+    client: kube::Client,
+    config: Arc<Config>,
+    source: Source,
+    mut applied: String,
+) {
+    let params = kube::api::ListParams::default().fields(&format!("metadata.name={}", source.name));
+
+    loop {
+        let stream = kube::runtime::watcher(api(&client, &source), params.clone());
+        tokio::pin!(stream);
+
+        loop {
+            let configmap = match stream.try_next().await {
+                Ok(Some(Event::Applied(configmap))) => configmap,
+                Ok(Some(Event::Restarted(configmaps))) => match configmaps.into_iter().next() {
+                    Some(configmap) => configmap,
+                    None => {
+                        tracing::warn!(%source, "configmap not found, keeping the current configuration");
+                        continue;
+                    }
+                },
+                Ok(Some(Event::Deleted(_))) => {
+                    tracing::warn!(%source, "configmap deleted, keeping the current configuration");
+                    continue;
+                }
+                Ok(None) => break,
+                Err(error) => {
+                    tracing::warn!(%error, %source, "configmap watch failed, retrying");
+                    break;
+                }
+            };
+
+            let contents = match contents(&configmap) {
+                Ok(contents) => contents,
+                Err(error) => {
+                    tracing::warn!(%error, %source, "ignoring invalid configmap");
+                    continue;
+                }
+            };
+            if contents == applied {
+                continue;
+            }
+
+            match config.reload(ORIGIN, contents.as_bytes()) {
+                Ok(()) => tracing::info!(%source, "configmap changed, applied"),
+                Err(error) => tracing::warn!(%error, %source, "ignoring invalid configmap"),
+            }
+            applied = contents;
+        }
+
+        tokio::time::sleep(RETRY_DELAY).await;
+    }
+}
+
+fn api(client: &kube::Client, source: &Source) -> kube::Api<ConfigMap> {
+    kube::Api::namespaced(client.clone(), &source.namespace)
+}
+
+/// Returns the configuration held by `configmap`.
+fn contents(configmap: &ConfigMap) -> crate::Result<String> {
+    configmap
+        .data
+        .as_ref()
+        .and_then(|data| data.get(DATA_KEY))
+        .cloned()
+        .ok_or_else(|| eyre::eyre!("the configmap has no `{DATA_KEY}` key"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_source() {
+        let source: Source = "games/quilkin".parse().unwrap();
+        assert_eq!("games", source.namespace);
+        assert_eq!("quilkin", source.name);
+        assert_eq!("games/quilkin", source.to_string());
+
+        for invalid in ["quilkin", "/quilkin", "games/", "games/quilkin/proxy"] {
+            assert!(invalid.parse::<Source>().is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn contents() {
+        let mut configmap = ConfigMap::default();
+        assert!(super::contents(&configmap).is_err());
+
+        configmap.data = Some([(DATA_KEY.to_owned(), "version: v1alpha1".to_owned())].into());
+        assert_eq!("version: v1alpha1", super::contents(&configmap).unwrap());
+    }
+}
@@ -14,12 +14,20 @@
  *  limitations under the License.
  */
 
-use std::sync::Arc;
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
 
 use notify::Watcher;
 
 use crate::Config;
 
+/// How long a file has to stop changing before it's read, as editors write
+/// files in several steps.
+const SETTLE_DELAY: Duration = Duration::from_millis(100);
+
 pub async fn watch(
     config: Arc<Config>,
     path: impl Into<std::path::PathBuf>,
@@ -62,6 +70,61 @@ pub async fn watch(
     Err(eyre::eyre!("filesystem watch unexpectedly stopped"))
 }
 
+/// Reloads `config` from the file at `path` whenever it changes, see
+/// [`Config::watch_file`]. The file's directory is watched rather than the
+/// file, so that a file replaced by renaming another over it, as editors and
+/// Kubernetes `ConfigMap` volumes do, keeps being watched.
+pub(crate) async fn watch_file(config: Arc<Config>, path: PathBuf) -> crate::Result<()> {
+    let directory = path
+        .parent()
+        .filter(|directory| !directory.as_os_str().is_empty())
+        .unwrap_or_else(|| Path::new("."));
+    let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+    let mut watcher = notify::RecommendedWatcher::new(
+        move |res| {
+            tx.send(res).ok();
+        },
+        <_>::default(),
+    )?;
+
+    watcher.watch(directory, notify::RecursiveMode::NonRecursive)?;
+    tracing::info!(path = %path.display(), "watching configuration file");
+
+    // Other files in the directory can change too, so the file is only
+    // applied when its contents differ from the last applied contents.
+    let mut applied = tokio::fs::read(&path).await.ok();
+    while let Some(event) = rx.recv().await {
+        if let Err(error) = event {
+            tracing::warn!(%error, "configuration file watch error");
+            continue;
+        }
+
+        tokio::time::sleep(SETTLE_DELAY).await;
+        while rx.try_recv().is_ok() {}
+
+        let contents = match tokio::fs::read(&path).await {
+            Ok(contents) => contents,
+            Err(error) => {
+                tracing::warn!(%error, path = %path.display(), "failed to read configuration file");
+                continue;
+            }
+        };
+        if applied.as_ref() == Some(&contents) {
+            continue;
+        }
+
+        match config.reload("file", &contents) {
+            Ok(()) => tracing::info!(path = %path.display(), "configuration file changed, applied"),
+            Err(error) => {
+                tracing::warn!(%error, path = %path.display(), "ignoring invalid configuration file")
+            }
+        }
+        applied = Some(contents);
+    }
+
+    Err(eyre::eyre!("configuration file watch unexpectedly stopped"))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -98,4 +161,59 @@ mod tests {
 
         assert_eq!(source, dest);
     }
+
+    #[tokio::test]
+    async fn watch_file() {
+        let config = Arc::new(crate::Config::default());
+        let tmp_dir = tempdir::TempDir::new("watch_file").unwrap();
+        let file_path = tmp_dir.path().join("quilkin.yaml");
+        tokio::fs::write(&file_path, "version: v1alpha1\nid: before\n")
+            .await
+            .unwrap();
+        let _handle = tokio::spawn(config.clone().watch_file(file_path.clone()));
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+
+        let wait_for_id = |id: &'static str| {
+            let config = config.clone();
+            tokio::time::timeout(std::time::Duration::from_secs(5), async move {
+                while *config.id.load() != id {
+                    tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+                }
+            })
+        };
+
+        // Replaced by a rename, as editors do.
+        let temporary = tmp_dir.path().join("quilkin.yaml.tmp");
+        tokio::fs::write(
+            &temporary,
+            "
+version: v1alpha1
+id: after
+clusters:
+  default:
+    localities:
+      - endpoints:
+          - address: 127.0.0.1:4321
+",
+        )
+        .await
+        .unwrap();
+        tokio::fs::rename(&temporary, &file_path).await.unwrap();
+        wait_for_id("after").await.unwrap();
+        assert_eq!(1, config.clusters.load().endpoints().count());
+
+        // Invalid files keep the current configuration.
+        tokio::fs::write(&file_path, "version: v1alpha1\nid: [invalid\n")
+            .await
+            .unwrap();
+        tokio::time::sleep(std::time::Duration::from_millis(300)).await;
+        assert_eq!("after", &*config.id.load());
+
+        // Unset settings are reset.
+        tokio::fs::write(&file_path, "version: v1alpha1\nid: reset\n")
+            .await
+            .unwrap();
+        wait_for_id("reset").await.unwrap();
+        assert_eq!(0, config.clusters.load().endpoints().count());
+    }
 }
@@ -17,6 +17,7 @@
 //! Types representing where the data is the sent.
 
 mod address;
+mod distance;
 mod locality;
 
 use serde::{Deserialize, Serialize};
@@ -24,8 +25,14 @@ use serde::{Deserialize, Serialize};
 use crate::xds::config::endpoint::v3::{lb_endpoint::HostIdentifier, Endpoint as EnvoyEndpoint};
 
 pub use self::{
-    address::EndpointAddress,
-    locality::{Locality, LocalityEndpoints, LocalitySet},
+    address::{set_ipv4_representation, AddressKind, EndpointAddress, Ipv4Representation},
+    distance::{Coordinates, DistanceStrategy, LocalityDistance, Origin},
+    locality::{DuplicatePreference, Locality, LocalityEndpoints, LocalitySet},
+};
+
+pub(crate) use self::{
+    address::{canonical_ip, for_socket, normalize_socket_addr, other_ipv4_representation},
+    distance::keys as locality_keys,
 };
 
 type EndpointMetadata = crate::metadata::MetadataView<Metadata>;
@@ -39,6 +46,21 @@ pub struct Endpoint {
     pub address: EndpointAddress,
     #[serde(default)]
     pub metadata: EndpointMetadata,
+    /// The share of packets the endpoint receives relative to the other
+    /// endpoints with weighted load balancing, where `0` sends it none.
+    #[serde(default = "default_weight", skip_serializing_if = "is_default_weight")]
+    pub weight: u32,
+}
+
+/// The weight of endpoints with no weight set.
+pub const DEFAULT_WEIGHT: u32 = 1;
+
+fn default_weight() -> u32 {
+    DEFAULT_WEIGHT
+}
+
+fn is_default_weight(weight: &u32) -> bool {
+    *weight == DEFAULT_WEIGHT
 }
 
 impl Endpoint {
@@ -58,6 +80,12 @@ impl Endpoint {
             ..<_>::default()
         }
     }
+
+    /// Sets the weight of the endpoint.
+    pub fn with_weight(mut self, weight: u32) -> Self {
+        self.weight = weight;
+        self
+    }
 }
 
 impl Default for Endpoint {
@@ -65,6 +93,7 @@ impl Default for Endpoint {
         Self {
             address: EndpointAddress::UNSPECIFIED,
             metadata: <_>::default(),
+            weight: DEFAULT_WEIGHT,
         }
     }
 }
@@ -88,6 +117,7 @@ impl From<Endpoint> for crate::xds::config::endpoint::v3::LbEndpoint {
                 ..<_>::default()
             })),
             metadata: Some(endpoint.metadata.into()),
+            load_balancing_weight: (endpoint.weight != DEFAULT_WEIGHT).then_some(endpoint.weight),
             ..<_>::default()
         }
     }
@@ -111,7 +141,7 @@ impl TryFrom<crate::xds::config::endpoint::v3::LbEndpoint> for Endpoint {
                 .map(crate::metadata::MetadataView::try_from)
                 .transpose()?
                 .unwrap_or_default(),
-            ..<_>::default()
+            weight: endpoint.load_balancing_weight.unwrap_or(DEFAULT_WEIGHT),
         })
     }
 }
@@ -280,6 +310,18 @@ mod tests {
         );
     }
 
+    #[test]
+    fn weight() {
+        let endpoint: Endpoint = serde_yaml::from_str("address: 127.0.0.1:7001").unwrap();
+        assert_eq!(DEFAULT_WEIGHT, endpoint.weight);
+        assert!(!serde_yaml::to_string(&endpoint).unwrap().contains("weight"));
+
+        let endpoint = endpoint.with_weight(0);
+        let lb_endpoint = crate::xds::config::endpoint::v3::LbEndpoint::from(endpoint.clone());
+        assert_eq!(Some(0), lb_endpoint.load_balancing_weight);
+        assert_eq!(endpoint, Endpoint::try_from(lb_endpoint).unwrap());
+    }
+
     #[test]
     fn parse_dns_endpoints() {
         let localhost = "address: localhost:80";
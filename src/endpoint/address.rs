@@ -19,6 +19,7 @@ use std::{
     fmt,
     net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs},
     str::FromStr,
+    sync::atomic::{AtomicBool, Ordering},
 };
 
 use serde::{Deserialize, Serialize};
@@ -57,7 +58,10 @@ impl EndpointAddress {
     }
 
     /// Returns the socket address for the endpoint, resolving any DNS entries
-    /// if present.
+    /// if present. See [`Clusters::to_socket_addr`] for the endpoints of
+    /// clusters, whose hostnames a proxy resolves periodically.
+    ///
+    /// [`Clusters::to_socket_addr`]: crate::cluster::Clusters::to_socket_addr
     pub fn to_socket_addr(&self) -> std::io::Result<SocketAddr> {
         // These unwraps after `to_socket_addr` are guarenteed not to panic as
         // all the types we use provide either one address or error.
@@ -80,6 +84,97 @@ impl EndpointAddress {
     }
 }
 
+impl EndpointAddress {
+    /// Returns the address with IPv4 addresses in the representation set with
+    /// [`set_ipv4_representation`].
+    pub fn normalized(mut self) -> Self {
+        if let AddressKind::Ip(ip) = &mut self.host {
+            *ip = normalize_ip(*ip);
+        }
+        self
+    }
+
+    /// Whether both addresses are the same, whether their IPv4 addresses are
+    /// IPv4-mapped IPv6 addresses or not.
+    pub fn is_same(&self, other: &Self) -> bool {
+        self.port == other.port
+            && match (&self.host, &other.host) {
+                (AddressKind::Ip(ip), AddressKind::Ip(other)) => {
+                    canonical_ip(*ip) == canonical_ip(*other)
+                }
+                (host, other) => host == other,
+            }
+    }
+}
+
+/// How IPv4 addresses are represented in the proxy's sessions, cluster lookups
+/// and metrics. Sockets bound to a dual-stack IPv6 address, such as `[::]`,
+/// receive packets from IPv4 clients from IPv4-mapped IPv6 addresses
+/// (`::ffff:a.b.c.d`), which would otherwise count as different clients than
+/// the same addresses received as IPv4 addresses.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum Ipv4Representation {
+    /// IPv4-mapped IPv6 addresses are converted to IPv4 addresses.
+    #[default]
+    Ipv4,
+    /// IPv4 addresses are converted to IPv4-mapped IPv6 addresses.
+    Ipv6Mapped,
+}
+
+static IPV6_MAPPED: AtomicBool = AtomicBool::new(false);
+
+/// Sets how the IPv4 addresses packets are received from are represented.
+/// Set before any packet is received.
+pub fn set_ipv4_representation(representation: Ipv4Representation) {
+    IPV6_MAPPED.store(
+        representation == Ipv4Representation::Ipv6Mapped,
+        Ordering::Relaxed,
+    );
+}
+
+/// Returns `ip` in the representation set with [`set_ipv4_representation`].
+pub(crate) fn normalize_ip(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V4(ip) if IPV6_MAPPED.load(Ordering::Relaxed) => IpAddr::V6(ip.to_ipv6_mapped()),
+        ip => canonical_ip(ip),
+    }
+}
+
+/// Returns `address` in the representation set with
+/// [`set_ipv4_representation`].
+pub(crate) fn normalize_socket_addr(address: SocketAddr) -> SocketAddr {
+    SocketAddr::new(normalize_ip(address.ip()), address.port())
+}
+
+/// Returns `ip` as an IPv4 address if it's an IPv4-mapped IPv6 address.
+pub(crate) fn canonical_ip(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(ip) => ip.to_ipv4_mapped().map_or(IpAddr::V6(ip), IpAddr::V4),
+        ip => ip,
+    }
+}
+
+/// Returns the other representation of `ip` if it's an IPv4 address, either
+/// as an IPv4 or an IPv4-mapped IPv6 address.
+pub(crate) fn other_ipv4_representation(ip: IpAddr) -> Option<IpAddr> {
+    match ip {
+        IpAddr::V4(ip) => Some(IpAddr::V6(ip.to_ipv6_mapped())),
+        IpAddr::V6(ip) => ip.to_ipv4_mapped().map(IpAddr::V4),
+    }
+}
+
+/// Returns `address` in the address family of a socket, converting IPv4
+/// addresses to IPv4-mapped IPv6 addresses for IPv6 sockets, and back for
+/// IPv4 sockets, so that they can be sent to whatever their representation.
+pub(crate) fn for_socket(address: SocketAddr, ipv6: bool) -> SocketAddr {
+    let ip = match (address.ip(), ipv6) {
+        (IpAddr::V4(ip), true) => IpAddr::V6(ip.to_ipv6_mapped()),
+        (IpAddr::V6(ip), false) => ip.to_ipv4_mapped().map_or(IpAddr::V6(ip), IpAddr::V4),
+        (ip, _) => ip,
+    };
+    SocketAddr::new(ip, address.port())
+}
+
 /// Forwards the deserialisation to use [`std::net::ToSocketAddrs`] instead of
 /// [`FromStr`] for validation which allows us to resolve DNS hostnames such as
 /// `localhost` or container network names at parse-time.
@@ -319,3 +414,29 @@ impl Serialize for EndpointAddress {
         serializer.serialize_str(&self.to_string())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ipv4_mapped_addresses() {
+        let ipv4 = EndpointAddress::from(([10, 0, 0, 1], 7000));
+        let mapped = EndpointAddress::from((Ipv4Addr::new(10, 0, 0, 1).to_ipv6_mapped(), 7000));
+        assert_ne!(ipv4, mapped);
+        assert!(ipv4.is_same(&mapped));
+        assert!(!ipv4.is_same(&EndpointAddress::from(([10, 0, 0, 1], 7001))));
+        assert_eq!(ipv4, mapped.normalized());
+
+        let ipv6 = EndpointAddress::from((Ipv6Addr::LOCALHOST, 7000));
+        assert_eq!(ipv6, ipv6.clone().normalized());
+        assert!(!ipv6.is_same(&ipv4));
+
+        let ipv4 = SocketAddr::from(([10, 0, 0, 1], 7000));
+        let mapped = for_socket(ipv4, true);
+        assert_eq!(mapped.ip(), Ipv4Addr::new(10, 0, 0, 1).to_ipv6_mapped());
+        assert_eq!(ipv4, for_socket(mapped, false));
+        assert_eq!(Some(mapped.ip()), other_ipv4_representation(ipv4.ip()));
+        assert_eq!(None, other_ipv4_representation(Ipv6Addr::LOCALHOST.into()));
+    }
+}
@@ -0,0 +1,345 @@
+/*
+ * Copyright 2023 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Pluggable distance functions, which order the localities a client prefers
+//! to be sent to.
+
+use std::{collections::BTreeMap, net::IpAddr, sync::Arc};
+
+use once_cell::unsync::OnceCell;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use super::{Locality, LocalityEndpoints};
+
+const EARTH_RADIUS_KM: f64 = 6371.0;
+
+/// Measures how far a locality is from a client. Lower distances are
+/// preferred, and localities without a distance are only used when no other
+/// locality is available.
+pub trait LocalityDistance: Send + Sync {
+    /// Returns the distance between `origin` and `locality`, which is `None`
+    /// for endpoints without a locality.
+    fn distance(&self, origin: &Origin, locality: Option<&Locality>) -> Option<f64>;
+
+    /// Sorts `localities` from the nearest to the furthest from `origin`,
+    /// keeping the existing order of localities at the same distance.
+    fn order(&self, origin: &Origin, localities: &mut Vec<&LocalityEndpoints>) {
+        let mut distances = localities
+            .drain(..)
+            .map(|endpoints| {
+                (
+                    self.distance(origin, endpoints.locality.as_ref()),
+                    endpoints,
+                )
+            })
+            .collect::<Vec<_>>();
+
+        distances.sort_by(|(a, _), (b, _)| match (a, b) {
+            (Some(a), Some(b)) => a.total_cmp(b),
+            (a, b) => b.is_some().cmp(&a.is_some()),
+        });
+        localities.extend(distances.into_iter().map(|(_, endpoints)| endpoints));
+    }
+}
+
+/// The client that localities are measured from.
+#[derive(Debug, Default)]
+pub struct Origin {
+    /// The address of the client.
+    pub address: Option<IpAddr>,
+    /// The locality the client is in, such as the locality of the proxy
+    /// when failing over between regions.
+    pub locality: Option<Locality>,
+    coordinates: OnceCell<Option<Coordinates>>,
+}
+
+impl Origin {
+    /// Creates an origin for a client at `address`.
+    pub fn new(address: IpAddr) -> Self {
+        Self {
+            address: Some(address),
+            ..<_>::default()
+        }
+    }
+
+    /// Sets the locality of the client.
+    pub fn with_locality(mut self, locality: impl Into<Option<Locality>>) -> Self {
+        self.locality = locality.into();
+        self
+    }
+
+    /// Sets the coordinates of the client, rather than looking them up.
+    pub fn with_coordinates(self, coordinates: Coordinates) -> Self {
+        let _ = self.coordinates.set(Some(coordinates));
+        self
+    }
+
+    /// Returns the coordinates of the client, looking up its address in the
+    /// Maxmind database the first time they're needed. Builds without the
+    /// `filter-geolocation` feature have no database, so only coordinates set
+    /// with [`Origin::with_coordinates`] are known.
+    pub fn coordinates(&self) -> Option<Coordinates> {
+        #[cfg(feature = "filter-geolocation")]
+        let lookup = || self.address.and_then(crate::MaxmindDb::lookup_coordinates);
+        #[cfg(not(feature = "filter-geolocation"))]
+        let lookup = || None;
+
+        *self.coordinates.get_or_init(lookup)
+    }
+}
+
+/// A point on the Earth's surface, in degrees.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct Coordinates {
+    pub latitude: f64,
+    pub longitude: f64,
+}
+
+impl Coordinates {
+    /// The great-circle distance to `other` in kilometres.
+    pub fn distance_km(&self, other: &Self) -> f64 {
+        let (lat_a, lat_b) = (self.latitude.to_radians(), other.latitude.to_radians());
+        let delta_lat = lat_b - lat_a;
+        let delta_long = (other.longitude - self.longitude).to_radians();
+
+        let a = (delta_lat / 2.0).sin().powi(2)
+            + lat_a.cos() * lat_b.cos() * (delta_long / 2.0).sin().powi(2);
+        2.0 * EARTH_RADIUS_KM * a.sqrt().asin()
+    }
+}
+
+/// The built-in distance functions, selected by `kind` in configuration.
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq, JsonSchema)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum DistanceStrategy {
+    /// Prefers localities matching more of the client's region, zone and
+    /// sub-zone, in that order.
+    #[default]
+    ExactRegion,
+    /// Prefers the localities nearest to the client's coordinates in the
+    /// Maxmind database.
+    GeoDistance {
+        /// The coordinates of each locality, keyed by `region`,
+        /// `region/zone` or `region/zone/sub_zone`, with the most specific
+        /// key taking precedence.
+        localities: BTreeMap<String, Coordinates>,
+    },
+    /// Prefers localities in the order they are listed, regardless of the
+    /// client. Each entry is a `region`, `region/zone` or
+    /// `region/zone/sub_zone`.
+    Priority { order: Vec<String> },
+}
+
+// Coordinates are only compared to tell whether configuration changed.
+impl Eq for DistanceStrategy {}
+
+impl DistanceStrategy {
+    /// Creates the distance function for this strategy.
+    pub fn as_locality_distance(&self) -> Arc<dyn LocalityDistance> {
+        match self {
+            Self::ExactRegion => Arc::new(ExactRegion),
+            Self::GeoDistance { localities } => Arc::new(GeoDistance {
+                localities: localities.clone(),
+            }),
+            Self::Priority { order } => Arc::new(Priority {
+                order: order.clone(),
+            }),
+        }
+    }
+}
+
+struct ExactRegion;
+
+impl LocalityDistance for ExactRegion {
+    fn distance(&self, origin: &Origin, locality: Option<&Locality>) -> Option<f64> {
+        let locality = locality?;
+        let Some(origin) = &origin.locality else {
+            return Some(3.0);
+        };
+
+        let matching = [
+            (&origin.region, &locality.region),
+            (&origin.zone, &locality.zone),
+            (&origin.sub_zone, &locality.sub_zone),
+        ]
+        .into_iter()
+        .take_while(|(a, b)| a == b)
+        .count();
+
+        Some((3 - matching) as f64)
+    }
+}
+
+struct GeoDistance {
+    localities: BTreeMap<String, Coordinates>,
+}
+
+impl LocalityDistance for GeoDistance {
+    fn distance(&self, origin: &Origin, locality: Option<&Locality>) -> Option<f64> {
+        let coordinates = keys(locality?).find_map(|key| self.localities.get(&key))?;
+
+        Some(
+            origin
+                .coordinates()
+                .map_or(f64::MAX, |origin| origin.distance_km(coordinates)),
+        )
+    }
+}
+
+struct Priority {
+    order: Vec<String>,
+}
+
+impl LocalityDistance for Priority {
+    fn distance(&self, _: &Origin, locality: Option<&Locality>) -> Option<f64> {
+        keys(locality?)
+            .find_map(|key| self.order.iter().position(|entry| *entry == key))
+            .map(|position| position as f64)
+    }
+}
+
+/// The keys of `locality` in configuration, from the most to the least
+/// specific.
+pub(crate) fn keys(locality: &Locality) -> impl Iterator<Item = String> {
+    [
+        (!locality.sub_zone.is_empty()).then(|| {
+            format!(
+                "{}/{}/{}",
+                locality.region, locality.zone, locality.sub_zone
+            )
+        }),
+        (!locality.zone.is_empty()).then(|| format!("{}/{}", locality.region, locality.zone)),
+        Some(locality.region.clone()),
+    ]
+    .into_iter()
+    .flatten()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::endpoint::Endpoint;
+
+    fn locality(region: &str, zone: &str) -> Locality {
+        Locality {
+            region: region.into(),
+            zone: zone.into(),
+            ..<_>::default()
+        }
+    }
+
+    fn regions(localities: &[&LocalityEndpoints]) -> Vec<String> {
+        localities
+            .iter()
+            .map(|endpoints| {
+                endpoints
+                    .locality
+                    .as_ref()
+                    .map(|locality| format!("{}/{}", locality.region, locality.zone))
+                    .unwrap_or_default()
+            })
+            .collect()
+    }
+
+    fn endpoints() -> Vec<LocalityEndpoints> {
+        [
+            Some(locality("eu-west", "a")),
+            None,
+            Some(locality("us-east", "b")),
+            Some(locality("us-east", "a")),
+        ]
+        .into_iter()
+        .map(|locality| LocalityEndpoints::from(Endpoint::default()).with_locality(locality))
+        .collect()
+    }
+
+    #[test]
+    fn exact_region() {
+        let endpoints = endpoints();
+        let mut localities = endpoints.iter().collect();
+        let origin = Origin::default().with_locality(locality("us-east", "a"));
+
+        DistanceStrategy::ExactRegion
+            .as_locality_distance()
+            .order(&origin, &mut localities);
+        assert_eq!(
+            vec!["us-east/a", "us-east/b", "eu-west/a", ""],
+            regions(&localities)
+        );
+    }
+
+    #[test]
+    fn priority() {
+        let endpoints = endpoints();
+        let mut localities = endpoints.iter().collect();
+        let strategy: DistanceStrategy = serde_yaml::from_str(
+            "
+kind: priority
+order: [us-east/b, eu-west]
+",
+        )
+        .unwrap();
+
+        strategy
+            .as_locality_distance()
+            .order(&Origin::default(), &mut localities);
+        assert_eq!(
+            vec!["us-east/b", "eu-west/a", "", "us-east/a"],
+            regions(&localities)
+        );
+    }
+
+    #[test]
+    fn geo_distance() {
+        let london = Coordinates {
+            latitude: 51.5072,
+            longitude: -0.1276,
+        };
+        let paris = Coordinates {
+            latitude: 48.8566,
+            longitude: 2.3522,
+        };
+        let distance = london.distance_km(&paris);
+        assert!((340.0..350.0).contains(&distance), "{distance}");
+
+        let endpoints = endpoints();
+        let mut localities = endpoints.iter().collect();
+        let strategy = DistanceStrategy::GeoDistance {
+            localities: [
+                ("eu-west".into(), paris),
+                (
+                    "us-east".into(),
+                    Coordinates {
+                        latitude: 38.9072,
+                        longitude: -77.0369,
+                    },
+                ),
+            ]
+            .into_iter()
+            .collect(),
+        };
+
+        strategy
+            .as_locality_distance()
+            .order(&Origin::default().with_coordinates(london), &mut localities);
+        assert_eq!(
+            vec!["eu-west/a", "us-east/b", "us-east/a", ""],
+            regions(&localities)
+        );
+    }
+}
@@ -18,7 +18,7 @@ use std::collections::BTreeSet;
 
 use serde::{Deserialize, Serialize};
 
-use super::Endpoint;
+use super::{Endpoint, EndpointAddress};
 use crate::xds::config::endpoint::v3::LocalityLbEndpoints;
 
 /// The location of an [`Endpoint`].
@@ -63,6 +63,10 @@ pub struct Locality {
 pub struct LocalityEndpoints {
     pub locality: Option<Locality>,
     pub endpoints: BTreeSet<Endpoint>,
+    /// Scales the weights of every endpoint in the locality, relative to the
+    /// other localities, when set.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub weight: Option<u32>,
 }
 
 impl LocalityEndpoints {
@@ -77,10 +81,26 @@ impl LocalityEndpoints {
         self
     }
 
+    /// Sets the weight of the locality.
+    pub fn with_weight(mut self, weight: impl Into<Option<u32>>) -> Self {
+        self.weight = weight.into();
+        self
+    }
+
     /// Removes an endpoint.
     pub fn remove(&mut self, endpoint: &Endpoint) {
         self.endpoints.remove(endpoint);
     }
+
+    /// Returns the endpoints with their weights scaled by the locality's.
+    pub fn weighted_endpoints(&self) -> impl Iterator<Item = Endpoint> + '_ {
+        self.endpoints.iter().map(|endpoint| match self.weight {
+            Some(weight) => endpoint
+                .clone()
+                .with_weight(endpoint.weight.saturating_mul(weight)),
+            None => endpoint.clone(),
+        })
+    }
 }
 
 impl From<Endpoint> for LocalityEndpoints {
@@ -149,6 +169,7 @@ impl TryFrom<LocalityLbEndpoints> for LocalityEndpoints {
                 .map(TryFrom::try_from)
                 .collect::<Result<_, Self::Error>>()?,
             locality: value.locality.map(From::from),
+            weight: value.load_balancing_weight,
         })
     }
 }
@@ -176,6 +197,7 @@ impl From<LocalityEndpoints> for LocalityLbEndpoints {
         Self {
             lb_endpoints: value.endpoints.into_iter().map(From::from).collect(),
             locality: value.locality.map(From::from),
+            load_balancing_weight: value.weight,
             ..Self::default()
         }
     }
@@ -196,6 +218,7 @@ impl LocalitySet {
     pub fn insert(&mut self, mut locality: LocalityEndpoints) {
         let mut entry = self.0.entry(locality.locality.clone()).or_default();
         entry.locality = locality.locality;
+        entry.weight = locality.weight.or(entry.weight);
         entry.endpoints.append(&mut locality.endpoints);
     }
 
@@ -218,6 +241,59 @@ impl LocalitySet {
     pub fn iter_mut(&mut self) -> impl Iterator<Item = &mut LocalityEndpoints> + '_ {
         self.0.values_mut()
     }
+
+    /// Keeps every address in only one locality, chosen by `preference`,
+    /// returning the number of endpoints removed. Localities left without
+    /// endpoints are removed.
+    pub fn dedup(&mut self, preference: DuplicatePreference) -> usize {
+        let mut keys = self.0.keys().cloned().collect::<Vec<_>>();
+        keys.sort_by(|a, b| preference.compare(a, b));
+
+        let mut seen = BTreeSet::<EndpointAddress>::new();
+        let mut removed = 0;
+        for key in keys {
+            let locality = self.0.get_mut(&key).unwrap();
+            let len = locality.endpoints.len();
+            locality
+                .endpoints
+                .retain(|endpoint| seen.insert(endpoint.address.clone()));
+
+            if locality.endpoints.len() < len {
+                removed += len - locality.endpoints.len();
+                if locality.endpoints.is_empty() {
+                    self.0.remove(&key);
+                }
+            }
+        }
+
+        removed
+    }
+}
+
+/// Which locality keeps an address that appears in several localities of a
+/// cluster. Ties between localities are broken by their region, zone and
+/// sub-zone, in that order, so the choice doesn't depend on the order the
+/// localities were received in.
+#[derive(
+    Clone, Copy, Debug, Default, Eq, PartialEq, Hash, Deserialize, Serialize, schemars::JsonSchema,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum DuplicatePreference {
+    /// Prefers a locality over endpoints without a locality.
+    #[default]
+    Located,
+    /// Prefers endpoints without a locality.
+    Unlocated,
+}
+
+impl DuplicatePreference {
+    fn compare(self, a: &Option<Locality>, b: &Option<Locality>) -> std::cmp::Ordering {
+        match (self, a, b) {
+            (Self::Located, None, Some(_)) => std::cmp::Ordering::Greater,
+            (Self::Located, Some(_), None) => std::cmp::Ordering::Less,
+            _ => a.cmp(b),
+        }
+    }
 }
 
 impl Serialize for LocalitySet {
@@ -225,7 +301,10 @@ impl Serialize for LocalitySet {
     where
         S: serde::Serializer,
     {
-        self.0.values().collect::<Vec<_>>().serialize(serializer)
+        // Sorted by locality, so that equal sets serialize identically.
+        let mut localities = self.0.values().collect::<Vec<_>>();
+        localities.sort_by(|a, b| a.locality.cmp(&b.locality));
+        localities.serialize(serializer)
     }
 }
 
@@ -19,58 +19,182 @@
 mod chain;
 mod error;
 mod factory;
-mod metadata;
+pub(crate) mod metadata;
 mod read;
 mod registry;
+#[cfg(any(feature = "filter-encrypt", feature = "filter-hmac"))]
+mod replay;
 mod set;
+#[cfg(any(feature = "filter-block-list", feature = "filter-ext-authz"))]
+mod verdict_cache;
 mod write;
 
+#[cfg(feature = "filter-block-list")]
+pub mod block_list;
+#[cfg(feature = "filter-capture")]
 pub mod capture;
+#[cfg(feature = "filter-compress")]
 pub mod compress;
+#[cfg(feature = "filter-concatenate-bytes")]
 pub mod concatenate_bytes;
+#[cfg(feature = "filter-debug")]
 pub mod debug;
+#[cfg(feature = "filter-drop")]
 pub mod drop;
+#[cfg(feature = "filter-encrypt")]
+pub mod encrypt;
+#[cfg(feature = "filter-ext-authz")]
+pub mod ext_authz;
+#[cfg(feature = "filter-firewall")]
 pub mod firewall;
+#[cfg(feature = "filter-geolocation")]
+pub mod geolocation;
+#[cfg(feature = "filter-hmac")]
+pub mod hmac;
+#[cfg(feature = "filter-load-balancer")]
 pub mod load_balancer;
+#[cfg(feature = "filter-local-rate-limit")]
 pub mod local_rate_limit;
+#[cfg(feature = "filter-match")]
 pub mod r#match;
+pub mod mitigation;
+#[cfg(feature = "filter-pass")]
 pub mod pass;
+#[cfg(feature = "filter-rate-limit")]
+pub mod rate_limit;
+#[cfg(feature = "filter-reassemble")]
+pub mod reassemble;
+#[cfg(feature = "filter-reliable-control")]
+pub mod reliable_control;
+#[cfg(feature = "filter-response-validation")]
+pub mod response_validation;
+pub mod suspicion;
+#[cfg(feature = "filter-timestamp")]
 pub mod timestamp;
+#[cfg(feature = "filter-token-router")]
 pub mod token_router;
+#[cfg(feature = "filter-ttl")]
+pub mod ttl;
+#[cfg(feature = "filter-wasm")]
+pub mod wasm;
 
 /// Prelude containing all types and traits required to implement [`Filter`] and
-/// [`FilterFactory`].
+/// [`FilterFactory`], and to share dynamic metadata with the other filters of a
+/// chain.
 pub mod prelude {
     pub use super::{
         ConvertProtoConfigError, CreateFilterArgs, Error, Filter, FilterInstance, ReadContext,
         StaticFilter, WriteContext,
     };
+    pub use crate::metadata::{DynamicMetadata, MetadataExt};
 }
 
 // Core Filter types
 #[doc(inline)]
 pub use self::{
-    capture::Capture,
-    compress::Compress,
-    concatenate_bytes::ConcatenateBytes,
-    debug::Debug,
-    drop::Drop,
     error::{ConvertProtoConfigError, Error},
     factory::{CreateFilterArgs, DynFilterFactory, FilterFactory, FilterInstance},
-    firewall::Firewall,
-    load_balancer::LoadBalancer,
-    local_rate_limit::LocalRateLimit,
-    pass::Pass,
-    r#match::Match,
     read::ReadContext,
     registry::FilterRegistry,
     set::{FilterMap, FilterSet},
-    timestamp::Timestamp,
-    token_router::TokenRouter,
     write::WriteContext,
 };
 
-pub use self::chain::FilterChain;
+pub(crate) use self::read::Dropped;
+
+#[cfg(feature = "filter-block-list")]
+#[doc(inline)]
+pub use self::block_list::BlockList;
+
+#[cfg(feature = "filter-capture")]
+#[doc(inline)]
+pub use self::capture::Capture;
+
+#[cfg(feature = "filter-compress")]
+#[doc(inline)]
+pub use self::compress::Compress;
+
+#[cfg(feature = "filter-concatenate-bytes")]
+#[doc(inline)]
+pub use self::concatenate_bytes::ConcatenateBytes;
+
+#[cfg(feature = "filter-debug")]
+#[doc(inline)]
+pub use self::debug::Debug;
+
+#[cfg(feature = "filter-drop")]
+#[doc(inline)]
+pub use self::drop::Drop;
+
+#[cfg(feature = "filter-encrypt")]
+#[doc(inline)]
+pub use self::encrypt::Encrypt;
+
+#[cfg(feature = "filter-ext-authz")]
+#[doc(inline)]
+pub use self::ext_authz::ExtAuthz;
+
+#[cfg(feature = "filter-firewall")]
+#[doc(inline)]
+pub use self::firewall::Firewall;
+
+#[cfg(feature = "filter-geolocation")]
+#[doc(inline)]
+pub use self::geolocation::Geolocation;
+
+#[cfg(feature = "filter-hmac")]
+#[doc(inline)]
+pub use self::hmac::Hmac;
+
+#[cfg(feature = "filter-load-balancer")]
+#[doc(inline)]
+pub use self::load_balancer::LoadBalancer;
+
+#[cfg(feature = "filter-local-rate-limit")]
+#[doc(inline)]
+pub use self::local_rate_limit::LocalRateLimit;
+
+#[cfg(feature = "filter-pass")]
+#[doc(inline)]
+pub use self::pass::Pass;
+
+#[cfg(feature = "filter-match")]
+#[doc(inline)]
+pub use self::r#match::Match;
+
+#[cfg(feature = "filter-rate-limit")]
+#[doc(inline)]
+pub use self::rate_limit::RateLimit;
+
+#[cfg(feature = "filter-reassemble")]
+#[doc(inline)]
+pub use self::reassemble::Reassemble;
+
+#[cfg(feature = "filter-reliable-control")]
+#[doc(inline)]
+pub use self::reliable_control::ReliableControl;
+
+#[cfg(feature = "filter-response-validation")]
+#[doc(inline)]
+pub use self::response_validation::ResponseValidation;
+
+#[cfg(feature = "filter-timestamp")]
+#[doc(inline)]
+pub use self::timestamp::Timestamp;
+
+#[cfg(feature = "filter-token-router")]
+#[doc(inline)]
+pub use self::token_router::TokenRouter;
+
+#[cfg(feature = "filter-ttl")]
+#[doc(inline)]
+pub use self::ttl::Ttl;
+
+#[cfg(feature = "filter-wasm")]
+#[doc(inline)]
+pub use self::wasm::Wasm;
+
+pub use self::chain::{ExecutionBudget, FilterChain, MAX_REPROCESSES};
 
 /// Statically safe version of [`Filter`], if you're writing a Rust filter, you
 /// should implement [`StaticFilter`] in addition to [`Filter`], as
@@ -128,6 +252,20 @@ where
         + Sync
         + Sized;
 
+    /// The fields of [`Self::Configuration`] that earlier versions of the
+    /// filter named differently, as `(old, new)` pairs of dotted paths such
+    /// as `("on_read.old", "on_read.new")`. Static configs using an old name
+    /// are upgraded to the new one with a warning, so that a control plane
+    /// can push the same config to proxies of different versions during a
+    /// rolling upgrade.
+    const RENAMED_FIELDS: &'static [(&'static str, &'static str)] = &[];
+
+    /// The top-level fields of [`Self::Configuration`] holding secrets, such
+    /// as keys. They're redacted when the config is shown through the admin
+    /// server's `/config` endpoint, and only there, so that the filter can
+    /// still be configured over xDS and restored from a persisted config.
+    const SECRET_FIELDS: &'static [&'static str] = &[];
+
     /// Instantiates a new [`StaticFilter`] from the given configuration, if any.
     /// # Errors
     /// If the provided configuration is invalid.
@@ -185,11 +323,24 @@ where
 ///   `read` implementation to execute.
 ///   * Labels
 ///     * `filter` The name of the filter being executed.
+///     * `position` The index of the filter in the chain.
 ///
 /// * `filter_write_duration_seconds` The duration it took for a `filter`'s
 ///   `write` implementation to execute.
 ///   * Labels
 ///     * `filter` The name of the filter being executed.
+///     * `position` The index of the filter in the chain.
+///
+/// * `filter_packets_total` and `filter_packets_dropped_total` The number of
+///   packets a `filter` processed, and of those it dropped.
+///   * Labels
+///     * `event` The direction of the packets, `read` or `write`.
+///     * `filter` The name of the filter being executed.
+///     * `position` The index of the filter in the chain.
+///
+/// * `filter_errors_total` The number of packets the chain dropped because of
+///   a `filter`, with the same labels, and a `reason` of either
+///   `budget_exceeded` or `reprocess_limit`.
 pub trait Filter: Send + Sync {
     /// [`Filter::read`] is invoked when the proxy receives data from a
     /// downstream connection on the listening port.
@@ -201,6 +352,17 @@ pub trait Filter: Send + Sync {
         Some(())
     }
 
+    /// [`Filter::read_batch`] is invoked instead of [`Filter::read`] when the
+    /// proxy has received several packets at once, returning whether each
+    /// packet should proceed in the same order as `ctxs`.
+    ///
+    /// Filters that can amortize work across packets (such as compression or
+    /// cryptography) can override this, by default each context is passed to
+    /// [`Filter::read`] in turn.
+    fn read_batch(&self, ctxs: &mut [ReadContext]) -> Vec<Option<()>> {
+        ctxs.iter_mut().map(|ctx| self.read(ctx)).collect()
+    }
+
     /// [`Filter::write`] is invoked when the proxy is about to send data to a
     /// downstream connection via the listening port after receiving it via one
     /// of the upstream Endpoints.
@@ -210,4 +372,13 @@ pub trait Filter: Send + Sync {
     fn write(&self, _: &mut WriteContext) -> Option<()> {
         Some(())
     }
+
+    /// Whether [`Filter::write`] may change or drop packets. Filters that let
+    /// every packet from upstream through untouched can return `false`, so
+    /// that the proxy skips the write chain altogether when none of its
+    /// filters need to see those packets. Defaults to `true`, as the proxy
+    /// can't tell whether `write` was overridden.
+    fn has_write(&self) -> bool {
+        true
+    }
 }
@@ -0,0 +1,466 @@
+/*
+ * Copyright 2023 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+mod config;
+mod metrics;
+mod store;
+
+crate::include_proto!("quilkin.filters.block_list.v1alpha1");
+
+use std::{io, sync::Arc, time::Duration};
+
+use tokio::sync::mpsc;
+
+use crate::{filters::prelude::*, metadata};
+
+use self::{metrics::Metrics, quilkin::filters::block_list::v1alpha1 as proto, store::Connection};
+use super::verdict_cache::{VerdictCache, PURGE_INTERVAL, RETRY_INTERVAL};
+
+pub use self::config::{Config, FailurePolicy, Protocol, Store};
+
+/// The number of keys that can be waiting to be looked up, further keys are
+/// looked up once a later packet finds room in the queue.
+const LOOKUP_QUEUE_SIZE: usize = 1024;
+/// The longest key memcached accepts.
+const MEMCACHED_MAX_KEY_LENGTH: usize = 250;
+/// The length of the hex encoded SHA-256 digest memcached keys end with.
+const MEMCACHED_DIGEST_LENGTH: usize = 64;
+
+/// Filter that drops packets whose source IP address or token is present in
+/// an external key-value store, so that bans pushed to the store by other
+/// systems (such as anti-cheat) apply to proxies in near real time.
+///
+/// Keys are looked up in the background, and results are cached, so packets
+/// never wait on the store.
+pub struct BlockList {
+    metadata_key: Option<metadata::Key>,
+    key_prefix: String,
+    protocol: Protocol,
+    failure_policy: FailurePolicy,
+    /// Whether each key is blocked.
+    cache: Arc<VerdictCache>,
+    lookups: mpsc::Sender<String>,
+    metrics: Arc<Metrics>,
+}
+
+impl BlockList {
+    fn new(config: Config, metrics: Metrics) -> Result<Self, Error> {
+        if config.store.address.is_empty() {
+            return Err(Error::FieldInvalid {
+                field: "store.address".into(),
+                reason: "an address is required".into(),
+            });
+        }
+
+        if config.store.protocol == Protocol::Memcached {
+            if config
+                .key_prefix
+                .chars()
+                .any(|c| c.is_whitespace() || c.is_control())
+            {
+                return Err(Error::FieldInvalid {
+                    field: "key_prefix".into(),
+                    reason: "memcached keys can't contain whitespace or control characters".into(),
+                });
+            }
+
+            if config.key_prefix.len() + MEMCACHED_DIGEST_LENGTH > MEMCACHED_MAX_KEY_LENGTH {
+                return Err(Error::FieldInvalid {
+                    field: "key_prefix".into(),
+                    reason: format!(
+                        "memcached key prefixes can be at most {} bytes",
+                        MEMCACHED_MAX_KEY_LENGTH - MEMCACHED_DIGEST_LENGTH
+                    ),
+                });
+            }
+        }
+
+        let protocol = config.store.protocol;
+        let cache = Arc::new(VerdictCache::default());
+        let metrics = Arc::new(metrics);
+        let (lookups, keys) = mpsc::channel(LOOKUP_QUEUE_SIZE);
+        tokio::spawn(
+            Lookup {
+                timeout: Duration::from_millis(config.timeout_ms),
+                cache_ttl: Duration::from_secs(config.cache_ttl),
+                negative_cache_ttl: Duration::from_secs(config.negative_cache_ttl),
+                store: config.store,
+                cache: cache.clone(),
+                metrics: metrics.clone(),
+            }
+            .run(keys),
+        );
+
+        Ok(Self {
+            metadata_key: config.metadata_key,
+            key_prefix: config.key_prefix,
+            protocol,
+            failure_policy: config.failure_policy,
+            cache,
+            lookups,
+            metrics,
+        })
+    }
+
+    /// Returns the key to look up for the packet, which is the source IP
+    /// address, or the token in the metadata encoded as base64.
+    fn key(&self, ctx: &ReadContext) -> Option<String> {
+        let key = match self.metadata_key {
+            None => ctx.source.host.to_string(),
+            Some(metadata_key) => match ctx.metadata.get(&metadata_key)? {
+                metadata::Value::Bytes(token) => base64::encode(token),
+                metadata::Value::String(token) => token.clone(),
+                _ => return None,
+            },
+        };
+
+        Some(self.store_key(&key))
+    }
+
+    /// Returns the key `key` is stored under. Memcached keys are the hex
+    /// encoded SHA-256 digest of `key`, as memcached's text protocol can't
+    /// carry whitespace or control characters in keys, nor keys longer than
+    /// 250 bytes, and the key comes from clients.
+    fn store_key(&self, key: &str) -> String {
+        match self.protocol {
+            Protocol::Redis => format!("{}{key}", self.key_prefix),
+            Protocol::Memcached => {
+                let digest = openssl::sha::sha256(key.as_bytes())
+                    .iter()
+                    .map(|byte| format!("{byte:02x}"))
+                    .collect::<String>();
+                format!("{}{digest}", self.key_prefix)
+            }
+        }
+    }
+
+    /// Returns whether `key` is blocked, or `None` if it isn't known yet.
+    /// Missing and expired entries are queued to be looked up.
+    fn is_blocked(&self, key: String) -> Option<bool> {
+        self.cache
+            .get(key, |key| self.lookups.try_send(key).is_ok())
+    }
+}
+
+impl Filter for BlockList {
+    #[cfg_attr(feature = "instrument", tracing::instrument(skip(self, ctx)))]
+    fn read(&self, ctx: &mut ReadContext) -> Option<()> {
+        let Some(key) = self.key(ctx) else {
+            tracing::trace!(source = %ctx.source, "Dropping packet, no token was found");
+            self.metrics.packets_dropped_no_token.inc();
+            return None;
+        };
+
+        match self.is_blocked(key) {
+            Some(false) => Some(()),
+            Some(true) => {
+                tracing::trace!(source = %ctx.source, "Dropping packet from blocked client");
+                self.metrics.packets_dropped_blocked.inc();
+                None
+            }
+            None => match self.failure_policy {
+                FailurePolicy::Open => Some(()),
+                FailurePolicy::Closed => {
+                    self.metrics.packets_dropped_unknown.inc();
+                    None
+                }
+            },
+        }
+    }
+
+    fn has_write(&self) -> bool {
+        false
+    }
+}
+
+impl StaticFilter for BlockList {
+    const NAME: &'static str = "quilkin.filters.block_list.v1alpha1.BlockList";
+    type Configuration = Config;
+    type BinaryConfiguration = proto::BlockList;
+
+    fn try_from_config(config: Option<Self::Configuration>) -> Result<Self, Error> {
+        Self::new(Self::ensure_config_exists(config)?, Metrics::new()?)
+    }
+}
+
+/// The background task looking up keys queued by the filter, until the
+/// filter is dropped.
+struct Lookup {
+    store: Store,
+    timeout: Duration,
+    cache_ttl: Duration,
+    negative_cache_ttl: Duration,
+    cache: Arc<VerdictCache>,
+    metrics: Arc<Metrics>,
+}
+
+impl Lookup {
+    async fn run(self, mut keys: mpsc::Receiver<String>) {
+        let mut connection = None;
+        let mut purge = tokio::time::interval(PURGE_INTERVAL);
+
+        loop {
+            let key = tokio::select! {
+                key = keys.recv() => match key {
+                    Some(key) => key,
+                    None => return,
+                },
+                _ = purge.tick() => {
+                    self.cache.purge();
+                    continue;
+                }
+            };
+
+            let result = tokio::time::timeout(self.timeout, self.contains(&mut connection, &key))
+                .await
+                .unwrap_or_else(|_| Err(io::ErrorKind::TimedOut.into()));
+
+            let (blocked, ttl) = match result {
+                Ok(true) => {
+                    self.metrics.lookups_blocked.inc();
+                    (Some(true), self.cache_ttl)
+                }
+                Ok(false) => {
+                    self.metrics.lookups_allowed.inc();
+                    (Some(false), self.negative_cache_ttl)
+                }
+                Err(error) => {
+                    tracing::debug!(%error, address = %self.store.address, "block list lookup failed");
+                    self.metrics.lookups_failed.inc();
+                    (None, RETRY_INTERVAL)
+                }
+            };
+
+            self.cache.accept(&key, blocked, ttl);
+        }
+    }
+
+    /// Looks up `key`, connecting to the store first if needed. The
+    /// connection is discarded if the lookup fails or times out, as it may
+    /// have been left midway through a reply.
+    async fn contains(&self, connection: &mut Option<Connection>, key: &str) -> io::Result<bool> {
+        let mut current = match connection.take() {
+            Some(current) => current,
+            None => Connection::connect(&self.store).await?,
+        };
+
+        let contains = current.contains(key).await?;
+        *connection = Some(current);
+        Ok(contains)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use tokio::{
+        io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+        net::TcpListener,
+    };
+
+    use super::*;
+    use crate::{endpoint::Endpoint, test_utils::assert_write_no_change};
+
+    /// Spawns a store which contains `keys`, and returns its address.
+    async fn store(protocol: Protocol, keys: Vec<String>) -> String {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let keys = Arc::new(keys);
+
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let keys = keys.clone();
+                tokio::spawn(async move {
+                    let mut stream = BufReader::new(stream);
+                    // Redis commands are five lines, memcached's `get` is one.
+                    let lines = match protocol {
+                        Protocol::Redis => 5,
+                        Protocol::Memcached => 1,
+                    };
+
+                    loop {
+                        let mut line = String::new();
+                        for _ in 0..lines {
+                            line.clear();
+                            if stream.read_line(&mut line).await.unwrap() == 0 {
+                                return;
+                            }
+                        }
+
+                        let key = line.trim_end().trim_start_matches("get ");
+                        let reply = match (protocol, keys.iter().any(|stored| stored == key)) {
+                            (Protocol::Redis, true) => ":1\r\n".to_owned(),
+                            (Protocol::Redis, false) => ":0\r\n".to_owned(),
+                            (Protocol::Memcached, true) => {
+                                format!("VALUE {key} 0 1\r\n1\r\nEND\r\n")
+                            }
+                            (Protocol::Memcached, false) => "END\r\n".to_owned(),
+                        };
+                        stream.get_mut().write_all(reply.as_bytes()).await.unwrap();
+                    }
+                });
+            }
+        });
+
+        address
+    }
+
+    fn config(protocol: Protocol, address: String) -> Config {
+        serde_yaml::from_str::<Config>(&format!(
+            "
+store:
+  protocol: {}
+  address: '{address}'
+key_prefix: 'ban:'
+",
+            serde_yaml::to_string(&protocol).unwrap().trim()
+        ))
+        .unwrap()
+    }
+
+    fn read(filter: &BlockList, source: [u8; 4]) -> Option<()> {
+        let mut ctx = ReadContext::new(
+            vec![Endpoint::new((Ipv4Addr::LOCALHOST, 8080).into())],
+            (source, 9000).into(),
+            vec![],
+        );
+        filter.read(&mut ctx)
+    }
+
+    /// Waits until the lookup of `source` has completed.
+    async fn resolved(filter: &BlockList, source: [u8; 4]) {
+        let key = filter.store_key(&Ipv4Addr::from(source).to_string());
+        filter.cache.resolved(&key).await;
+    }
+
+    #[test]
+    fn convert_proto_config() {
+        let config = Config {
+            store: Store {
+                protocol: Protocol::Memcached,
+                address: "memcached:11211".into(),
+            },
+            metadata_key: Some("quilkin.dev/captured".into()),
+            key_prefix: "ban:".into(),
+            cache_ttl: 10,
+            negative_cache_ttl: 1,
+            timeout_ms: 20,
+            failure_policy: FailurePolicy::Closed,
+        };
+        assert_eq!(
+            config,
+            Config::try_from(proto::BlockList::from(config.clone())).unwrap()
+        );
+
+        let defaults = Config::try_from(proto::BlockList {
+            store: Some(<_>::default()),
+            ..<_>::default()
+        })
+        .unwrap();
+        assert_eq!(Protocol::Redis, defaults.store.protocol);
+        assert_eq!(FailurePolicy::Open, defaults.failure_policy);
+        assert_eq!(
+            (60, 5, 100),
+            (
+                defaults.cache_ttl,
+                defaults.negative_cache_ttl,
+                defaults.timeout_ms
+            )
+        );
+
+        assert!(Config::try_from(proto::BlockList::default()).is_err());
+    }
+
+    #[tokio::test]
+    async fn blocks_keys_in_store() {
+        let banned = [
+            (Protocol::Redis, "ban:127.0.0.2"),
+            // The SHA-256 digest of `127.0.0.2`.
+            (
+                Protocol::Memcached,
+                "ban:1edd62868f2767a1fff68df0a4cb3c23448e45100715768db9310b5e719536a1",
+            ),
+        ];
+        for (protocol, key) in banned {
+            let address = store(protocol, vec![key.into()]).await;
+            let filter = BlockList::from_config(Some(config(protocol, address)));
+
+            // Allowed while the first lookup is in flight.
+            assert!(read(&filter, [127, 0, 0, 2]).is_some());
+            resolved(&filter, [127, 0, 0, 2]).await;
+            assert!(read(&filter, [127, 0, 0, 2]).is_none(), "{protocol:?}");
+
+            assert!(read(&filter, [127, 0, 0, 1]).is_some());
+            resolved(&filter, [127, 0, 0, 1]).await;
+            assert!(read(&filter, [127, 0, 0, 1]).is_some(), "{protocol:?}");
+
+            assert_write_no_change(&filter);
+        }
+    }
+
+    #[tokio::test]
+    async fn failure_policy() {
+        // Reserve a port, so that nothing is listening on it.
+        let address = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .to_string();
+
+        let mut config = config(Protocol::Redis, address);
+        let open = BlockList::from_config(Some(config.clone()));
+        config.failure_policy = FailurePolicy::Closed;
+        let closed = BlockList::from_config(Some(config.clone()));
+
+        for filter in [&open, &closed] {
+            read(filter, [127, 0, 0, 1]);
+            resolved(filter, [127, 0, 0, 1]).await;
+        }
+        assert!(read(&open, [127, 0, 0, 1]).is_some());
+        assert!(read(&closed, [127, 0, 0, 1]).is_none());
+
+        config.metadata_key = Some("BLOCK_LIST_TOKEN".into());
+        let filter = BlockList::from_config(Some(config));
+        assert!(read(&filter, [127, 0, 0, 1]).is_none());
+    }
+
+    #[tokio::test]
+    async fn invalid_config() {
+        let mut config = config(Protocol::Memcached, "".into());
+        assert!(BlockList::try_from_config(Some(config.clone())).is_err());
+
+        config.store.address = "memcached:11211".into();
+        config.key_prefix = "ban ".into();
+        assert!(BlockList::try_from_config(Some(config.clone())).is_err());
+
+        config.key_prefix = "b".repeat(187);
+        assert!(BlockList::try_from_config(Some(config)).is_err());
+    }
+
+    #[tokio::test]
+    async fn memcached_keys_are_hashed() {
+        let filter =
+            BlockList::from_config(Some(config(Protocol::Memcached, "memcached:11211".into())));
+
+        let key = filter.store_key("get x\r\nflush_all");
+        assert_eq!(4 + MEMCACHED_DIGEST_LENGTH, key.len());
+        assert!(key.starts_with("ban:"));
+        assert!(!key.contains(char::is_whitespace));
+    }
+}
@@ -0,0 +1,181 @@
+/*
+ * Copyright 2023 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::convert::TryFrom;
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::{filters::ConvertProtoConfigError, metadata};
+
+use super::proto;
+
+/// Configuration for the [`BlockList`][super::BlockList] filter.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize, JsonSchema)]
+pub struct Config {
+    /// The key-value store holding the block list.
+    pub store: Store,
+    /// The key of a token in the packet's dynamic metadata (such as one set
+    /// by the `Capture` filter) to look up, rather than the source IP
+    /// address. Packets without the token are dropped.
+    #[serde(
+        rename = "metadataKey",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub metadata_key: Option<metadata::Key>,
+    /// Prepended to every key looked up in the store, e.g. `ban:`. Memcached
+    /// keys are the SHA-256 digest of the address or token after the prefix.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub key_prefix: String,
+    /// How many seconds a blocked result is cached for.
+    #[serde(default = "default_cache_ttl")]
+    pub cache_ttl: u64,
+    /// How many seconds a result that isn't blocked is cached for, which is
+    /// the longest it takes for a new ban to apply to a connected client.
+    #[serde(default = "default_negative_cache_ttl")]
+    pub negative_cache_ttl: u64,
+    /// The maximum time in milliseconds a single lookup may take, including
+    /// connecting to the store.
+    #[serde(default = "default_timeout_ms")]
+    pub timeout_ms: u64,
+    /// Whether packets are allowed or dropped while their key's status is
+    /// unknown, because the store couldn't be reached or the first lookup
+    /// hasn't completed yet.
+    #[serde(default)]
+    pub failure_policy: FailurePolicy,
+}
+
+/// The address and protocol of a key-value store. A key is blocked when it's
+/// present in the store, regardless of its value.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize, JsonSchema)]
+pub struct Store {
+    pub protocol: Protocol,
+    /// The `host:port` of the store.
+    pub address: String,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize, JsonSchema)]
+pub enum Protocol {
+    /// Looks keys up with the Redis `EXISTS` command.
+    #[serde(rename = "REDIS")]
+    Redis,
+    /// Looks keys up with the memcached text protocol's `get` command.
+    #[serde(rename = "MEMCACHED")]
+    Memcached,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq, Serialize, JsonSchema)]
+pub enum FailurePolicy {
+    /// Packets are allowed.
+    #[default]
+    #[serde(rename = "OPEN")]
+    Open,
+    /// Packets are dropped.
+    #[serde(rename = "CLOSED")]
+    Closed,
+}
+
+fn default_cache_ttl() -> u64 {
+    60
+}
+
+fn default_negative_cache_ttl() -> u64 {
+    5
+}
+
+fn default_timeout_ms() -> u64 {
+    100
+}
+
+impl From<Protocol> for proto::block_list::Protocol {
+    fn from(protocol: Protocol) -> Self {
+        match protocol {
+            Protocol::Redis => Self::Redis,
+            Protocol::Memcached => Self::Memcached,
+        }
+    }
+}
+
+impl From<proto::block_list::Protocol> for Protocol {
+    fn from(protocol: proto::block_list::Protocol) -> Self {
+        match protocol {
+            proto::block_list::Protocol::Redis => Self::Redis,
+            proto::block_list::Protocol::Memcached => Self::Memcached,
+        }
+    }
+}
+
+impl From<FailurePolicy> for proto::block_list::FailurePolicy {
+    fn from(policy: FailurePolicy) -> Self {
+        match policy {
+            FailurePolicy::Open => Self::Open,
+            FailurePolicy::Closed => Self::Closed,
+        }
+    }
+}
+
+impl From<proto::block_list::FailurePolicy> for FailurePolicy {
+    fn from(policy: proto::block_list::FailurePolicy) -> Self {
+        match policy {
+            proto::block_list::FailurePolicy::Open => Self::Open,
+            proto::block_list::FailurePolicy::Closed => Self::Closed,
+        }
+    }
+}
+
+impl From<Config> for proto::BlockList {
+    fn from(config: Config) -> Self {
+        Self {
+            store: Some(proto::block_list::Store {
+                protocol: proto::block_list::Protocol::from(config.store.protocol) as i32,
+                address: config.store.address,
+            }),
+            metadata_key: config.metadata_key.map(|key| key.to_string()),
+            key_prefix: config.key_prefix,
+            cache_ttl: Some(config.cache_ttl),
+            negative_cache_ttl: Some(config.negative_cache_ttl),
+            timeout_ms: Some(config.timeout_ms),
+            failure_policy: proto::block_list::FailurePolicy::from(config.failure_policy) as i32,
+        }
+    }
+}
+
+impl TryFrom<proto::BlockList> for Config {
+    type Error = ConvertProtoConfigError;
+
+    fn try_from(p: proto::BlockList) -> Result<Self, Self::Error> {
+        let failure_policy = p.failure_policy().into();
+        let store = p
+            .store
+            .ok_or_else(|| ConvertProtoConfigError::missing_field("store"))?;
+
+        Ok(Self {
+            store: Store {
+                protocol: store.protocol().into(),
+                address: store.address,
+            },
+            metadata_key: p.metadata_key.map(metadata::Key::new),
+            key_prefix: p.key_prefix,
+            cache_ttl: p.cache_ttl.unwrap_or_else(default_cache_ttl),
+            negative_cache_ttl: p
+                .negative_cache_ttl
+                .unwrap_or_else(default_negative_cache_ttl),
+            timeout_ms: p.timeout_ms.unwrap_or_else(default_timeout_ms),
+            failure_policy,
+        })
+    }
+}
@@ -0,0 +1,65 @@
+/*
+ * Copyright 2023 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use prometheus::{IntCounter, IntCounterVec, Result as MetricsResult};
+
+use crate::metrics::{filter_opts, CollectorExt};
+
+const REASON_LABEL: &str = "reason";
+const RESULT_LABEL: &str = "result";
+
+/// Register and manage metrics for this filter
+pub(super) struct Metrics {
+    pub(super) packets_dropped_blocked: IntCounter,
+    pub(super) packets_dropped_unknown: IntCounter,
+    pub(super) packets_dropped_no_token: IntCounter,
+    pub(super) lookups_blocked: IntCounter,
+    pub(super) lookups_allowed: IntCounter,
+    pub(super) lookups_failed: IntCounter,
+}
+
+impl Metrics {
+    pub(super) fn new() -> MetricsResult<Self> {
+        let dropped = IntCounterVec::new(
+            filter_opts(
+                "packets_dropped_total",
+                "BlockList",
+                "Total number of packets dropped. Labels: reason.",
+            ),
+            &[REASON_LABEL],
+        )?
+        .register_if_not_exists()?;
+
+        let lookups = IntCounterVec::new(
+            filter_opts(
+                "lookups_total",
+                "BlockList",
+                "Total number of keys looked up in the store. Labels: result.",
+            ),
+            &[RESULT_LABEL],
+        )?
+        .register_if_not_exists()?;
+
+        Ok(Self {
+            packets_dropped_blocked: dropped.get_metric_with_label_values(&["blocked"])?,
+            packets_dropped_unknown: dropped.get_metric_with_label_values(&["unknown"])?,
+            packets_dropped_no_token: dropped.get_metric_with_label_values(&["no_token"])?,
+            lookups_blocked: lookups.get_metric_with_label_values(&["blocked"])?,
+            lookups_allowed: lookups.get_metric_with_label_values(&["allowed"])?,
+            lookups_failed: lookups.get_metric_with_label_values(&["error"])?,
+        })
+    }
+}
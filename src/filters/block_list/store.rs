@@ -0,0 +1,100 @@
+/*
+ * Copyright 2023 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Minimal clients for the commands the filter needs from each store.
+
+use std::io;
+
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::TcpStream,
+};
+
+use super::{Protocol, Store};
+
+/// A connection to a store, which looks up one key at a time.
+pub(super) struct Connection {
+    protocol: Protocol,
+    stream: BufReader<TcpStream>,
+}
+
+impl Connection {
+    pub(super) async fn connect(store: &Store) -> io::Result<Self> {
+        let stream = TcpStream::connect(&*store.address).await?;
+        stream.set_nodelay(true)?;
+
+        Ok(Self {
+            protocol: store.protocol,
+            stream: BufReader::new(stream),
+        })
+    }
+
+    /// Returns whether `key` is present in the store.
+    pub(super) async fn contains(&mut self, key: &str) -> io::Result<bool> {
+        match self.protocol {
+            Protocol::Redis => {
+                let command = format!("*2\r\n$6\r\nEXISTS\r\n${}\r\n{key}\r\n", key.len());
+                self.stream.get_mut().write_all(command.as_bytes()).await?;
+
+                let reply = self.read_line().await?;
+                match reply.strip_prefix(':').map(str::parse::<u64>) {
+                    Some(Ok(count)) => Ok(count > 0),
+                    _ => Err(invalid_reply(&reply)),
+                }
+            }
+            Protocol::Memcached => {
+                let command = format!("get {key}\r\n");
+                self.stream.get_mut().write_all(command.as_bytes()).await?;
+
+                let reply = self.read_line().await?;
+                if reply == "END" {
+                    return Ok(false);
+                }
+
+                // VALUE <key> <flags> <bytes> [<cas unique>]
+                let size = reply
+                    .strip_prefix("VALUE ")
+                    .and_then(|value| value.split(' ').nth(2))
+                    .and_then(|size| size.parse::<usize>().ok())
+                    .ok_or_else(|| invalid_reply(&reply))?;
+                let mut value = vec![0; size + 2];
+                self.stream.read_exact(&mut value).await?;
+
+                match &*self.read_line().await? {
+                    "END" => Ok(true),
+                    reply => Err(invalid_reply(reply)),
+                }
+            }
+        }
+    }
+
+    async fn read_line(&mut self) -> io::Result<String> {
+        let mut line = String::new();
+        if self.stream.read_line(&mut line).await? == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+
+        line.truncate(line.trim_end_matches(&['\r', '\n'][..]).len());
+        Ok(line)
+    }
+}
+
+fn invalid_reply(reply: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("unexpected reply from store: {reply:?}"),
+    )
+}
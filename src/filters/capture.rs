@@ -16,18 +16,26 @@
 
 mod affix;
 mod config;
+mod length_prefixed;
 mod metrics;
 mod regex;
 
 crate::include_proto!("quilkin.filters.capture.v1alpha1");
 
-use crate::{filters::prelude::*, metadata};
+use crate::{
+    filters::{
+        prelude::*,
+        suspicion::{self, Suspicion},
+    },
+    metadata,
+};
 
 use self::{metrics::Metrics, quilkin::filters::capture::v1alpha1 as proto};
 
 pub use self::{
     affix::{Prefix, Suffix},
     config::{Config, Strategy},
+    length_prefixed::{LengthPrefixed, LENGTH_SIZES},
     regex::Regex,
 };
 
@@ -47,13 +55,14 @@ pub struct Capture {
 }
 
 impl Capture {
-    fn new(config: Config, metrics: Metrics) -> Self {
-        Self {
+    fn new(config: Config, metrics: Metrics) -> Result<Self, Error> {
+        config.strategy.validate()?;
+        Ok(Self {
             capture: config.strategy.into_capture(),
             metrics,
             is_present_key: (config.metadata_key.to_string() + "/is_present").into(),
             metadata_key: config.metadata_key,
-        }
+        })
     }
 }
 
@@ -72,9 +81,18 @@ impl Filter for Capture {
             Some(())
         } else {
             tracing::trace!(key = %self.metadata_key, "No value captured");
+            suspicion::emit(|| {
+                Suspicion::new(Self::NAME, suspicion::MALFORMED_PACKET, &ctx.source).with_detail(
+                    format!("no value to capture in {} bytes", ctx.contents.len()),
+                )
+            });
             None
         }
     }
+
+    fn has_write(&self) -> bool {
+        false
+    }
 }
 
 impl StaticFilter for Capture {
@@ -83,10 +101,7 @@ impl StaticFilter for Capture {
     type BinaryConfiguration = proto::Capture;
 
     fn try_from_config(config: Option<Self::Configuration>) -> Result<Self, Error> {
-        Ok(Capture::new(
-            Self::ensure_config_exists(config)?,
-            Metrics::new()?,
-        ))
+        Capture::new(Self::ensure_config_exists(config)?, Metrics::new()?)
     }
 }
 
@@ -191,6 +206,7 @@ mod tests {
         let metrics = Metrics::new().unwrap();
         let end = Regex {
             pattern: ::regex::bytes::Regex::new(".{3}$").unwrap(),
+            group: None,
         };
         let mut contents = b"helloabc".to_vec();
         let result = end.capture(&mut contents, &metrics).unwrap();
@@ -198,6 +214,101 @@ mod tests {
         assert_eq!(b"helloabc".to_vec(), contents);
     }
 
+    #[test]
+    fn regex_group_capture() {
+        let metrics = Metrics::new().unwrap();
+        let token = Regex {
+            pattern: ::regex::bytes::Regex::new("token=(?P<token>[a-z]+);").unwrap(),
+            group: Some("token".into()),
+        };
+        let mut contents = b"v=1;token=abc;rest".to_vec();
+        let result = token.capture(&mut contents, &metrics).unwrap();
+        assert_eq!(Value::Bytes(b"abc".to_vec().into()), result);
+        assert_eq!(b"v=1;token=abc;rest".to_vec(), contents);
+
+        let mut contents = b"v=1;rest".to_vec();
+        assert!(token.capture(&mut contents, &metrics).is_none());
+    }
+
+    #[test]
+    fn length_prefixed_capture() {
+        let metrics = Metrics::new().unwrap();
+        let mut field = LengthPrefixed {
+            offset: 1,
+            length_size: 2,
+            remove: false,
+        };
+        let mut contents = b"\x07\x00\x03abchello".to_vec();
+        let result = field.capture(&mut contents, &metrics).unwrap();
+        assert_eq!(Value::Bytes(b"abc".to_vec().into()), result);
+        assert_eq!(b"\x07\x00\x03abchello".to_vec(), contents);
+
+        field.remove = true;
+        let result = field.capture(&mut contents, &metrics).unwrap();
+        assert_eq!(Value::Bytes(b"abc".to_vec().into()), result);
+        assert_eq!(b"\x07hello".to_vec(), contents);
+
+        // Fields longer than the rest of the packet aren't captured.
+        let mut contents = b"\x07\x00\x09abc".to_vec();
+        assert!(field.capture(&mut contents, &metrics).is_none());
+        assert_eq!(b"\x07\x00\x09abc".to_vec(), contents);
+        assert_eq!(1, metrics.packets_dropped_total.get());
+    }
+
+    #[test]
+    fn factory_length_prefixed() {
+        let config = serde_json::json!({
+            "metadataKey": TOKEN_KEY.to_string(),
+            "lengthPrefixed": {
+                "lengthSize": 1,
+            }
+        });
+        let config = serde_json::from_value::<Config>(config).unwrap();
+        assert_eq!(
+            Strategy::LengthPrefixed(LengthPrefixed {
+                offset: 0,
+                length_size: 1,
+                remove: false,
+            }),
+            config.strategy
+        );
+
+        let filter = Capture::from_config(Some(config));
+        let mut context = ReadContext::new(
+            vec![Endpoint::new("127.0.0.1:81".parse().unwrap())],
+            "127.0.0.1:80".parse().unwrap(),
+            b"\x03abchello".to_vec(),
+        );
+        filter.read(&mut context).unwrap();
+        assert_eq!(
+            b"abc",
+            &**context.metadata.get_bytes(TOKEN_KEY.into()).unwrap()
+        );
+    }
+
+    #[test]
+    fn invalid_strategies() {
+        let invalid = [
+            Strategy::Regex(Regex {
+                pattern: ::regex::bytes::Regex::new("(?P<token>.{3})$").unwrap(),
+                group: Some("missing".into()),
+            }),
+            Strategy::LengthPrefixed(LengthPrefixed {
+                offset: 0,
+                length_size: 3,
+                remove: false,
+            }),
+        ];
+
+        for strategy in invalid {
+            let config = Config {
+                metadata_key: TOKEN_KEY.into(),
+                strategy,
+            };
+            assert!(Capture::new(config, Metrics::new().unwrap()).is_err());
+        }
+    }
+
     #[test]
     fn end_capture() {
         let metrics = Metrics::new().unwrap();
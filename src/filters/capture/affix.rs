@@ -2,19 +2,19 @@ use crate::metadata::Value;
 
 use super::Metrics;
 
-fn is_valid_size(contents: &[u8], size: u32, metrics: &Metrics) -> bool {
+/// Converts `captured` into a value, or counts the packet as dropped if it
+/// was too short to capture `size` bytes from.
+fn captured(captured: Option<Vec<u8>>, size: u32, metrics: &Metrics) -> Option<Value> {
     // if the capture size is bigger than the packet size, then we drop the packet,
     // and occasionally warn
-    if contents.len() < size as usize {
+    if captured.is_none() {
         if metrics.packets_dropped_total.get() % 1000 == 0 {
             tracing::warn!(count = ?metrics.packets_dropped_total.get(), "Packets are being dropped due to their length being less than {} bytes", size);
         }
         metrics.packets_dropped_total.inc();
-
-        false
-    } else {
-        true
     }
+
+    captured.map(|bytes| Value::Bytes(bytes.into()))
 }
 
 /// Capture from the start of the packet.
@@ -29,13 +29,11 @@ pub struct Prefix {
 
 impl super::CaptureStrategy for Prefix {
     fn capture(&self, contents: &mut Vec<u8>, metrics: &Metrics) -> Option<Value> {
-        is_valid_size(contents, self.size, metrics).then(|| {
-            if self.remove {
-                Value::Bytes(contents.drain(..self.size as usize).collect())
-            } else {
-                Value::Bytes(contents.iter().take(self.size as usize).copied().collect())
-            }
-        })
+        captured(
+            crate::codec::capture_prefix(contents, self.size as usize, self.remove),
+            self.size,
+            metrics,
+        )
     }
 }
 
@@ -51,14 +49,10 @@ pub struct Suffix {
 
 impl super::CaptureStrategy for Suffix {
     fn capture(&self, contents: &mut Vec<u8>, metrics: &Metrics) -> Option<Value> {
-        is_valid_size(contents, self.size, metrics).then(|| {
-            let index = contents.len() - self.size as usize;
-
-            if self.remove {
-                Value::Bytes(contents.split_off(index).into())
-            } else {
-                Value::Bytes(contents.iter().skip(index).copied().collect())
-            }
-        })
+        captured(
+            crate::codec::capture_suffix(contents, self.size as usize, self.remove),
+            self.size,
+            metrics,
+        )
     }
 }
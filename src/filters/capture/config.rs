@@ -18,8 +18,8 @@ use std::convert::TryFrom;
 
 use serde::{Deserialize, Serialize};
 
-use super::{proto, Prefix, Regex, Suffix};
-use crate::filters::{metadata::CAPTURED_BYTES, ConvertProtoConfigError};
+use super::{proto, LengthPrefixed, Prefix, Regex, Suffix, LENGTH_SIZES};
+use crate::filters::{metadata::CAPTURED_BYTES, ConvertProtoConfigError, Error};
 
 /// Strategy to apply for acquiring a set of bytes in the UDP packet
 #[derive(Serialize, Deserialize, Debug, PartialEq, schemars::JsonSchema)]
@@ -34,6 +34,9 @@ pub enum Strategy {
     /// Look for the set of bytes at the end of the packet
     #[serde(rename = "REGEX")]
     Regex(Regex),
+    /// Look for a field prefixed with its length
+    #[serde(rename = "LENGTH_PREFIXED")]
+    LengthPrefixed(LengthPrefixed),
 }
 
 impl Strategy {
@@ -42,6 +45,36 @@ impl Strategy {
             Self::Prefix(value) => Box::from(value),
             Self::Suffix(value) => Box::from(value),
             Self::Regex(value) => Box::from(value),
+            Self::LengthPrefixed(value) => Box::from(value),
+        }
+    }
+
+    /// Checks the options of the strategy which can't be checked when
+    /// they're parsed.
+    pub(super) fn validate(&self) -> Result<(), Error> {
+        match self {
+            Self::Regex(Regex {
+                pattern,
+                group: Some(group),
+            }) if !pattern
+                .capture_names()
+                .flatten()
+                .any(|name| name == group.as_str()) =>
+            {
+                Err(Error::FieldInvalid {
+                    field: "regex.group".into(),
+                    reason: format!("the pattern has no group named `{group}`"),
+                })
+            }
+            Self::LengthPrefixed(length_prefixed)
+                if !LENGTH_SIZES.contains(&length_prefixed.length_size) =>
+            {
+                Err(Error::FieldInvalid {
+                    field: "lengthPrefixed.lengthSize".into(),
+                    reason: "value must be 1, 2 or 4".into(),
+                })
+            }
+            _ => Ok(()),
         }
     }
 }
@@ -58,12 +91,32 @@ impl From<Suffix> for Strategy {
     }
 }
 
+/// The strategy capturing and removing `token` from the packets of clients
+/// that add it with [`RoutingToken::encode`][crate::codec::client::RoutingToken::encode].
+impl From<&crate::codec::client::RoutingToken> for Strategy {
+    fn from(token: &crate::codec::client::RoutingToken) -> Self {
+        use crate::codec::client::Position;
+
+        let size = token.size() as u32;
+        match token.position() {
+            Position::Prefix => Prefix { size, remove: true }.into(),
+            Position::Suffix => Suffix { size, remove: true }.into(),
+        }
+    }
+}
+
 impl From<Regex> for Strategy {
     fn from(regex: Regex) -> Self {
         Self::Regex(regex)
     }
 }
 
+impl From<LengthPrefixed> for Strategy {
+    fn from(length_prefixed: LengthPrefixed) -> Self {
+        Self::LengthPrefixed(length_prefixed)
+    }
+}
+
 #[derive(Debug, PartialEq, schemars::JsonSchema)]
 pub struct Config {
     /// The key to use when storing the captured value in the filter context.
@@ -87,6 +140,7 @@ impl Serialize for Config {
             Strategy::Prefix(value) => s.serialize_field("prefix", value)?,
             Strategy::Suffix(value) => s.serialize_field("suffix", value)?,
             Strategy::Regex(value) => s.serialize_field("regex", value)?,
+            Strategy::LengthPrefixed(value) => s.serialize_field("lengthPrefixed", value)?,
         }
 
         s.end()
@@ -106,6 +160,8 @@ impl<'de> serde::Deserialize<'de> for Config {
             Prefix,
             Suffix,
             Regex,
+            #[serde(rename = "lengthPrefixed")]
+            LengthPrefixed,
         }
 
         struct ConfigVisitor;
@@ -162,6 +218,14 @@ impl<'de> serde::Deserialize<'de> for Config {
 
                             strategy = Some(Strategy::Regex(map.next_value()?));
                         }
+
+                        Field::LengthPrefixed => {
+                            if strategy.is_some() {
+                                return (strategy_exists_err)();
+                            }
+
+                            strategy = Some(Strategy::LengthPrefixed(map.next_value()?));
+                        }
                     }
                 }
 
@@ -169,7 +233,7 @@ impl<'de> serde::Deserialize<'de> for Config {
                     .unwrap_or_else(|| crate::metadata::Key::from_static(CAPTURED_BYTES));
                 let strategy = strategy.ok_or_else(|| {
                     serde::de::Error::custom(
-                        "Capture strategy of `regex`, `suffix`, or `prefix` is required",
+                        "Capture strategy of `regex`, `suffix`, `prefix`, or `lengthPrefixed` is required",
                     )
                 })?;
 
@@ -226,7 +290,15 @@ impl From<Strategy> for proto::capture::Strategy {
             }),
             Strategy::Regex(regex) => Self::Regex(proto::capture::Regex {
                 regex: Some(regex.pattern.as_str().into()),
+                group: regex.group,
             }),
+            Strategy::LengthPrefixed(length_prefixed) => {
+                Self::LengthPrefixed(proto::capture::LengthPrefixed {
+                    offset: length_prefixed.offset,
+                    length_size: Some(length_prefixed.length_size),
+                    remove: Some(length_prefixed.remove),
+                })
+            }
         }
     }
 }
@@ -246,14 +318,24 @@ impl TryFrom<proto::capture::Strategy> for Strategy {
                 size: suffix.size,
                 remove: suffix.remove.unwrap_or_default(),
             }),
-            capture::Strategy::Regex(regex) => {
-                let regex = regex.regex.ok_or_else(|| {
+            capture::Strategy::Regex(capture::Regex { regex, group }) => {
+                let regex = regex.ok_or_else(|| {
                     ConvertProtoConfigError::new("Missing", Some("Regex.regex".into()))
                 })?;
                 Self::Regex(Regex {
                     pattern: regex.parse().map_err(|error: regex::Error| {
                         ConvertProtoConfigError::new(error.to_string(), Some("Regex.regex".into()))
                     })?,
+                    group,
+                })
+            }
+            capture::Strategy::LengthPrefixed(length_prefixed) => {
+                Self::LengthPrefixed(LengthPrefixed {
+                    offset: length_prefixed.offset,
+                    length_size: length_prefixed
+                        .length_size
+                        .unwrap_or_else(super::length_prefixed::default_length_size),
+                    remove: length_prefixed.remove.unwrap_or_default(),
                 })
             }
         })
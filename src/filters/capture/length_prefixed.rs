@@ -0,0 +1,57 @@
+use crate::metadata::Value;
+
+use super::Metrics;
+
+/// The sizes a field's length can be, in bytes.
+pub const LENGTH_SIZES: [u32; 3] = [1, 2, 4];
+
+pub(super) fn default_length_size() -> u32 {
+    2
+}
+
+/// Capture a length prefixed field, for protocols whose routing token isn't
+/// at a fixed offset: a big endian length of `length_size` bytes at
+/// `offset`, followed by that many bytes, which are captured.
+#[derive(Debug, Eq, PartialEq, serde::Serialize, serde::Deserialize, schemars::JsonSchema)]
+pub struct LengthPrefixed {
+    /// Where the field's length is, in bytes from the start of the packet.
+    #[serde(default)]
+    pub offset: u32,
+    /// The size of the field's length, either 1, 2 or 4 bytes.
+    #[serde(rename = "lengthSize", default = "default_length_size")]
+    pub length_size: u32,
+    /// Whether the field, along with its length, is removed from the
+    /// original packet.
+    #[serde(default)]
+    pub remove: bool,
+}
+
+impl LengthPrefixed {
+    /// Returns the range of the field's bytes in `contents`, and where its
+    /// length starts, or `None` if `contents` is too short to hold it.
+    fn field(&self, contents: &[u8]) -> Option<(usize, std::ops::Range<usize>)> {
+        let offset = self.offset as usize;
+        let start = offset.checked_add(self.length_size as usize)?;
+        let length = contents
+            .get(offset..start)?
+            .iter()
+            .fold(0, |length, byte| length << 8 | usize::from(*byte));
+        let end = start.checked_add(length)?;
+        (end <= contents.len()).then_some((offset, start..end))
+    }
+}
+
+impl super::CaptureStrategy for LengthPrefixed {
+    fn capture(&self, contents: &mut Vec<u8>, metrics: &Metrics) -> Option<Value> {
+        let Some((offset, field)) = self.field(contents) else {
+            metrics.packets_dropped_total.inc();
+            return None;
+        };
+
+        let captured = bytes::Bytes::copy_from_slice(&contents[field.clone()]);
+        if self.remove {
+            contents.drain(offset..field.end);
+        }
+        Some(Value::Bytes(captured))
+    }
+}
@@ -9,15 +9,25 @@ pub struct Regex {
     #[serde(with = "serde_regex")]
     #[schemars(with = "String")]
     pub pattern: regex::bytes::Regex,
+    /// The name of the group whose bytes are captured from each match,
+    /// rather than the bytes of the whole match.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub group: Option<String>,
 }
 
 impl super::CaptureStrategy for Regex {
     fn capture(&self, contents: &mut Vec<u8>, _metrics: &Metrics) -> Option<Value> {
-        let matches = self
-            .pattern
-            .find_iter(contents)
-            .map(|mat| Value::Bytes(bytes::Bytes::copy_from_slice(mat.as_bytes())))
-            .collect::<Vec<_>>();
+        let value =
+            |mat: regex::bytes::Match| Value::Bytes(bytes::Bytes::copy_from_slice(mat.as_bytes()));
+        let matches = match &self.group {
+            Some(group) => self
+                .pattern
+                .captures_iter(contents)
+                .filter_map(|captures| captures.name(group))
+                .map(value)
+                .collect::<Vec<_>>(),
+            None => self.pattern.find_iter(contents).map(value).collect(),
+        };
 
         if matches.len() > 1 {
             Some(Value::List(matches))
@@ -29,6 +39,6 @@ impl super::CaptureStrategy for Regex {
 
 impl PartialEq for Regex {
     fn eq(&self, rhs: &Self) -> bool {
-        self.pattern.as_str() == rhs.pattern.as_str()
+        self.pattern.as_str() == rhs.pattern.as_str() && self.group == rhs.group
     }
 }
@@ -14,15 +14,27 @@
  * limitations under the License.
  */
 
-use prometheus::{exponential_buckets, Histogram};
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use prometheus::{exponential_buckets, Histogram, IntCounter, IntCounterVec, Registry};
 
 use crate::{
     config::Filter as FilterConfig,
-    filters::{prelude::*, FilterRegistry},
-    metrics::{histogram_opts, CollectorExt},
+    filters::{prelude::*, Dropped, FilterRegistry},
+    metrics::{histogram_opts, opts, CollectorExt, Direction},
+    proxy::decisions::{Step, Verdict},
 };
 
 const FILTER_LABEL: &str = "filter";
+const POSITION_LABEL: &str = "position";
+const REASON_LABEL: &str = "reason";
 
 /// Start the histogram bucket at an eighth of a millisecond, as we bucketed the full filter
 /// chain processing starting at a quarter of a millisecond, so we we will want finer granularity
@@ -36,57 +48,107 @@ const BUCKET_FACTOR: f64 = 2.5;
 /// the bucketing there as we don't care about granularity past this value.
 const BUCKET_COUNT: usize = 11;
 
+/// The reason recorded in `packets_dropped_total` for packets that exceeded
+/// the execution budget.
+const BUDGET_EXCEEDED_REASON: &str = "FilterBudgetExceeded";
+
+/// The most times a packet may be read again by a chain, see
+/// [`ReadContext::reprocess_from`], bounding packets encapsulated in
+/// themselves.
+pub const MAX_REPROCESSES: usize = 8;
+
+/// The reason recorded in `packets_dropped_total` for packets that were
+/// marked to be read again more than [`MAX_REPROCESSES`] times.
+const REPROCESS_LIMIT_REASON: &str = "ReprocessLimitExceeded";
+
+/// How long the filter chains of a config may spend processing a single
+/// packet in either direction, shared between the config's clones. Once the
+/// budget has elapsed, the packet is dropped rather than being passed to the
+/// rest of the chain, while the work of the filter that finished last is kept
+/// if it was the end of the chain.
+#[derive(Clone, Debug, Default)]
+pub struct ExecutionBudget(Arc<AtomicU64>);
+
+impl ExecutionBudget {
+    /// Sets the budget, `None` removing the limit.
+    pub fn set(&self, budget: Option<Duration>) {
+        let nanos = budget
+            .map(|budget| u64::try_from(budget.as_nanos()).unwrap_or(u64::MAX).max(1))
+            .unwrap_or_default();
+        self.0.store(nanos, Ordering::Relaxed);
+    }
+
+    /// Returns the budget, if there's one.
+    pub fn get(&self) -> Option<Duration> {
+        match self.0.load(Ordering::Relaxed) {
+            0 => None,
+            nanos => Some(Duration::from_nanos(nanos)),
+        }
+    }
+}
+
 /// A chain of [`Filter`]s to be executed in order.
 ///
 /// Executes each filter, passing the [`ReadContext`] and [`WriteContext`]
 /// between each filter's execution, returning the result of data that has gone
 /// through all of the filters in the chain. If any of the filters in the chain
 /// return `None`, then the chain is broken, and `None` is returned.
+///
+/// The metrics of the chain, and of filters created from configuration by the
+/// chain, are registered in a registry owned by the chain, so they are removed
+/// when the chain is replaced.
 #[derive(Clone, Default)]
 pub struct FilterChain {
     filters: Vec<(String, FilterInstance)>,
-    filter_read_duration_seconds: Vec<Histogram>,
-    filter_write_duration_seconds: Vec<Histogram>,
+    read_metrics: Vec<FilterMetrics>,
+    write_metrics: Vec<FilterMetrics>,
+    /// Whether any filter has a [`Filter::write`], computed once when the
+    /// chain is built so packets from upstream can skip the chain cheaply.
+    has_write: bool,
+    registry: Registry,
 }
 
 impl FilterChain {
     pub fn new(filters: Vec<(String, FilterInstance)>) -> Result<Self, Error> {
-        let subsystem = "filter";
+        Self::with_registry(filters, crate::metrics::new_registry())
+    }
 
-        Ok(Self {
-            filter_read_duration_seconds: filters
+    /// Creates a chain of `filters`, registering its metrics in `registry`.
+    fn with_registry(
+        filters: Vec<(String, FilterInstance)>,
+        registry: Registry,
+    ) -> Result<Self, Error> {
+        crate::metrics::with_registry(&registry.clone(), || Self::create(filters, registry))
+    }
+
+    fn create(filters: Vec<(String, FilterInstance)>, registry: Registry) -> Result<Self, Error> {
+        let metrics = |direction| {
+            // The same filter at different positions shares its durations.
+            let mut durations = HashMap::new();
+            filters
                 .iter()
-                .map(|(name, _)| {
-                    Histogram::with_opts(
-                        histogram_opts(
-                            "read_duration_seconds",
-                            subsystem,
-                            "Seconds taken to execute a given filter's `read`.",
-                            Some(
-                                exponential_buckets(BUCKET_START, BUCKET_FACTOR, BUCKET_COUNT)
-                                    .unwrap(),
-                            ),
-                        )
-                        .const_label(FILTER_LABEL, name),
-                    )
-                    .and_then(|histogram| histogram.register_if_not_exists())
+                .enumerate()
+                .map(|(position, (name, _))| {
+                    let duration_seconds = match durations.get(&**name) {
+                        Some(histogram) => Histogram::clone(histogram),
+                        None => {
+                            let histogram = FilterMetrics::duration_seconds(direction, name)?;
+                            durations.insert(&**name, histogram.clone());
+                            histogram
+                        }
+                    };
+                    FilterMetrics::new(direction, name, position, duration_seconds)
                 })
-                .collect::<Result<_, prometheus::Error>>()?,
-            filter_write_duration_seconds: filters
+                .collect::<Result<Vec<_>, prometheus::Error>>()
+        };
+
+        Ok(Self {
+            registry,
+            has_write: filters
                 .iter()
-                .map(|(name, _)| {
-                    Histogram::with_opts(
-                        histogram_opts(
-                            "write_duration_seconds",
-                            subsystem,
-                            "Seconds taken to execute a given filter's `write`.",
-                            Some(exponential_buckets(0.000125, 2.5, 11).unwrap()),
-                        )
-                        .const_label(FILTER_LABEL, name),
-                    )
-                    .and_then(|histogram| histogram.register_if_not_exists())
-                })
-                .collect::<Result<_, prometheus::Error>>()?,
+                .any(|(_, instance)| instance.filter.has_write()),
+            read_metrics: metrics(crate::metrics::READ)?,
+            write_metrics: metrics(crate::metrics::WRITE)?,
             filters,
         })
     }
@@ -97,6 +159,97 @@ impl FilterChain {
         Self::try_from(filter_configs)
     }
 
+    /// Like [`FilterChain::try_create`], also returning the index of the
+    /// filter that was rejected, if the error came from a single filter.
+    pub(crate) fn try_create_indexed(
+        filter_configs: &[FilterConfig],
+    ) -> Result<Self, (Option<usize>, Error)> {
+        let registry = crate::metrics::new_registry();
+        let filters = crate::metrics::with_registry(&registry, || {
+            filter_configs
+                .iter()
+                .enumerate()
+                .map(|(index, filter_config)| {
+                    FilterRegistry::get(
+                        &filter_config.name,
+                        CreateFilterArgs::fixed(filter_config.config.clone()),
+                    )
+                    .map(|filter| (filter_config.name.clone(), filter))
+                    .map_err(|error| (Some(index), error))
+                })
+                .collect::<Result<Vec<_>, _>>()
+        })?;
+
+        Self::with_registry(filters, registry).map_err(|error| (None, error))
+    }
+
+    /// Reads `ctx` with the filters from `index` on, and again from wherever
+    /// the filters mark it to be reprocessed from, with `reprocesses` being
+    /// the number of times it's already been reprocessed.
+    fn read_from(
+        &self,
+        ctx: &mut ReadContext,
+        mut index: usize,
+        mut reprocesses: usize,
+    ) -> Option<()> {
+        let budget = ctx.budget;
+        let start = Instant::now();
+
+        while let Some(((id, instance), metrics)) =
+            self.filters.get(index).zip(self.read_metrics.get(index))
+        {
+            tracing::trace!(%id, "read filtering packet");
+            let result = metrics
+                .duration_seconds
+                .observe_closure_duration(|| instance.filter.read(ctx));
+            metrics.packets_total.inc();
+
+            if result.is_none() {
+                tracing::trace!(%id, "read dropping packet");
+                trace(&mut ctx.trace, id, Verdict::Drop);
+                ctx.dropped = Some(Dropped::Filter(id.clone()));
+                crate::metrics::packets_dropped_total(crate::metrics::READ, id).inc();
+                metrics.packets_dropped_total.inc();
+                return None;
+            }
+
+            // The budget only stops the rest of the chain from running, so a
+            // packet the last filter passed late is still sent.
+            let remaining = ctx.reprocess.is_some() || index + 1 < self.filters.len();
+            if remaining && exceeded_budget(crate::metrics::READ, id, start, budget) {
+                trace(&mut ctx.trace, id, Verdict::BudgetExceeded);
+                metrics.budget_exceeded_total.inc();
+                ctx.dropped = Some(Dropped::BudgetExceeded);
+                return None;
+            }
+
+            tracing::trace!(%id, "read passing packet");
+            index = match ctx.reprocess.take() {
+                Some(from) => {
+                    trace(&mut ctx.trace, id, Verdict::Reprocess);
+                    reprocesses += 1;
+                    if !reprocess(id, from, reprocesses) {
+                        metrics.reprocess_limit_total.inc();
+                        ctx.dropped = Some(Dropped::ReprocessLimit);
+                        return None;
+                    }
+                    from
+                }
+                None => {
+                    trace(&mut ctx.trace, id, Verdict::Pass);
+                    index + 1
+                }
+            };
+        }
+
+        Some(())
+    }
+
+    /// The registry containing the metrics of this chain and its filters.
+    pub fn registry(&self) -> &Registry {
+        &self.registry
+    }
+
     pub fn len(&self) -> usize {
         self.filters.len()
     }
@@ -168,18 +321,7 @@ impl TryFrom<&[FilterConfig]> for FilterChain {
     type Error = Error;
 
     fn try_from(filter_configs: &[FilterConfig]) -> Result<Self, Error> {
-        let mut filters = Vec::new();
-
-        for filter_config in filter_configs {
-            let filter = FilterRegistry::get(
-                &filter_config.name,
-                CreateFilterArgs::fixed(filter_config.config.clone()),
-            )?;
-
-            filters.push((filter_config.name.clone(), filter));
-        }
-
-        Self::new(filters)
+        Self::try_create_indexed(filter_configs).map_err(|(_, error)| error)
     }
 }
 
@@ -249,48 +391,272 @@ impl schemars::JsonSchema for FilterChain {
     }
 }
 
+/// The metrics of a filter in one direction of a chain, labelled with its name
+/// and its position in the chain, as the same filter may appear several times.
+/// The duration histograms are only labelled with the name, keeping their
+/// existing series, and are shared by every position of the filter.
+#[derive(Clone)]
+struct FilterMetrics {
+    duration_seconds: Histogram,
+    packets_total: IntCounter,
+    packets_dropped_total: IntCounter,
+    budget_exceeded_total: IntCounter,
+    reprocess_limit_total: IntCounter,
+}
+
+impl FilterMetrics {
+    fn new(
+        direction: Direction,
+        name: &str,
+        position: usize,
+        duration_seconds: Histogram,
+    ) -> prometheus::Result<Self> {
+        let subsystem = "filter";
+        let position = position.to_string();
+        let labelled = |opts: prometheus::Opts| {
+            opts.const_label(Direction::LABEL, direction.label())
+                .const_label(FILTER_LABEL, name)
+                .const_label(POSITION_LABEL, &position)
+        };
+
+        let packets_total = IntCounter::with_opts(labelled(opts(
+            "packets_total",
+            subsystem,
+            "Total number of packets processed by a given filter.",
+        )))?
+        .register_if_not_exists()?;
+        let packets_dropped_total = IntCounter::with_opts(labelled(opts(
+            "packets_dropped_total",
+            subsystem,
+            "Total number of packets dropped by a given filter.",
+        )))?
+        .register_if_not_exists()?;
+        let errors = IntCounterVec::new(
+            labelled(opts(
+                "errors_total",
+                subsystem,
+                "Total number of packets dropped by the chain because of a given filter. Labels: reason.",
+            )),
+            &[REASON_LABEL],
+        )?
+        .register_if_not_exists()?;
+
+        Ok(Self {
+            duration_seconds,
+            packets_total,
+            packets_dropped_total,
+            budget_exceeded_total: errors.get_metric_with_label_values(&["budget_exceeded"])?,
+            reprocess_limit_total: errors.get_metric_with_label_values(&["reprocess_limit"])?,
+        })
+    }
+
+    /// Registers the histogram of how long the filter `name` takes in
+    /// `direction`.
+    fn duration_seconds(direction: Direction, name: &str) -> prometheus::Result<Histogram> {
+        let buckets = match direction {
+            Direction::Read => exponential_buckets(BUCKET_START, BUCKET_FACTOR, BUCKET_COUNT),
+            Direction::Write => exponential_buckets(0.000125, 2.5, 11),
+        };
+
+        Histogram::with_opts(
+            histogram_opts(
+                &format!("{}_duration_seconds", direction.label()),
+                "filter",
+                &format!(
+                    "Seconds taken to execute a given filter's `{}`.",
+                    direction.label()
+                ),
+                Some(buckets?),
+            )
+            .const_label(FILTER_LABEL, name),
+        )?
+        .register_if_not_exists()
+    }
+}
+
+/// Checks whether the packet that began processing at `start` has exceeded
+/// `budget`, with `id` being the last filter to run.
+fn exceeded_budget(
+    direction: crate::metrics::Direction,
+    id: &str,
+    start: Instant,
+    budget: Option<Duration>,
+) -> bool {
+    let Some(budget) = budget else {
+        return false;
+    };
+
+    let elapsed = start.elapsed();
+    if elapsed <= budget {
+        return false;
+    }
+
+    tracing::debug!(%id, ?elapsed, ?budget, event = direction.label(), "filter chain exceeded execution budget, dropping packet");
+    crate::metrics::filter_budget_exceeded_total(direction, id).inc();
+    crate::metrics::packets_dropped_total(direction, BUDGET_EXCEEDED_REASON).inc();
+    true
+}
+
+/// Checks whether a packet marked by the filter `id` to be read again from
+/// `from` may be, having been reprocessed `reprocesses` times with this one.
+fn reprocess(id: &str, from: usize, reprocesses: usize) -> bool {
+    if reprocesses > MAX_REPROCESSES {
+        tracing::debug!(%id, "packet exceeded the reprocess limit, dropping packet");
+        crate::metrics::packets_dropped_total(crate::metrics::READ, REPROCESS_LIMIT_REASON).inc();
+        return false;
+    }
+
+    tracing::trace!(%id, from, reprocesses, "reprocessing packet");
+    true
+}
+
+/// Records the `verdict` of the filter `id` on a packet, if its decision is
+/// being recorded.
+fn trace(trace: &mut Option<Vec<Step>>, id: &str, verdict: Verdict) {
+    if let Some(trace) = trace {
+        trace.push(Step {
+            filter: id.into(),
+            verdict,
+        });
+    }
+}
+
 impl Filter for FilterChain {
+    #[tracing::instrument(name = "filter_chain_read", level = "debug", skip_all, fields(source = %ctx.source))]
     fn read(&self, ctx: &mut ReadContext) -> Option<()> {
-        self.filters
-            .iter()
-            .zip(self.filter_read_duration_seconds.iter())
-            .try_fold((), |_, ((id, instance), histogram)| {
-                tracing::trace!(%id, "read filtering packet");
-                match histogram.observe_closure_duration(|| instance.filter.read(ctx)) {
-                    Some(()) => {
-                        tracing::trace!(%id, "read passing packet");
-                    }
-                    None => {
-                        tracing::trace!(%id, "read dropping packet");
-                        crate::metrics::packets_dropped_total(crate::metrics::READ, id).inc();
-                        return None;
+        self.read_from(ctx, 0, 0)
+    }
+
+    #[tracing::instrument(name = "filter_chain_read_batch", level = "debug", skip_all, fields(packets = ctxs.len()))]
+    fn read_batch(&self, ctxs: &mut [ReadContext]) -> Vec<Option<()>> {
+        // The budget is per packet, so a batch may take as long as its packets
+        // would have individually.
+        let budget = ctxs
+            .first()
+            .and_then(|ctx| ctx.budget)
+            .map(|budget| budget * ctxs.len() as u32);
+        let start = Instant::now();
+        let mut results = vec![Some(()); ctxs.len()];
+        // The contexts still passing are kept at the front of `ctxs`, so that
+        // each filter receives a single slice, with `positions` tracking where
+        // each context was originally.
+        let mut positions = (0..ctxs.len()).collect::<Vec<_>>();
+        let mut passing = ctxs.len();
+
+        for (filter, ((id, instance), metrics)) in
+            self.filters.iter().zip(&self.read_metrics).enumerate()
+        {
+            if passing == 0 {
+                break;
+            }
+
+            tracing::trace!(%id, packets = passing, "read filtering batch");
+            let batch = metrics
+                .duration_seconds
+                .observe_closure_duration(|| instance.filter.read_batch(&mut ctxs[..passing]));
+            metrics.packets_total.inc_by(passing as u64);
+
+            // Like single packets, the packets the filter passed are only
+            // dropped if there's more of the chain left to run for them.
+            let last = filter + 1 == self.filters.len();
+            let remaining = !last || ctxs[..passing].iter().any(|ctx| ctx.reprocess.is_some());
+            let exceeded = remaining && exceeded_budget(crate::metrics::READ, id, start, budget);
+            let mut kept = 0;
+            for index in 0..passing {
+                let passed = batch.get(index).copied().flatten().is_some();
+                if passed && exceeded && (!last || ctxs[index].reprocess.is_some()) {
+                    trace(&mut ctxs[index].trace, id, Verdict::BudgetExceeded);
+                    metrics.budget_exceeded_total.inc();
+                    ctxs[index].dropped = Some(Dropped::BudgetExceeded);
+                    results[positions[index]] = None;
+                } else if passed {
+                    // Packets to be read again leave the batch, and go
+                    // through the rest of the chain on their own.
+                    if let Some(from) = ctxs[index].reprocess.take() {
+                        trace(&mut ctxs[index].trace, id, Verdict::Reprocess);
+                        results[positions[index]] = if reprocess(id, from, 1) {
+                            self.read_from(&mut ctxs[index], from, 1)
+                        } else {
+                            metrics.reprocess_limit_total.inc();
+                            ctxs[index].dropped = Some(Dropped::ReprocessLimit);
+                            None
+                        };
+                        continue;
                     }
+                    trace(&mut ctxs[index].trace, id, Verdict::Pass);
+                    ctxs.swap(kept, index);
+                    positions.swap(kept, index);
+                    kept += 1;
+                } else {
+                    tracing::trace!(%id, "read dropping packet");
+                    trace(&mut ctxs[index].trace, id, Verdict::Drop);
+                    ctxs[index].dropped = Some(Dropped::Filter(id.clone()));
+                    crate::metrics::packets_dropped_total(crate::metrics::READ, id).inc();
+                    metrics.packets_dropped_total.inc();
+                    results[positions[index]] = None;
                 }
+            }
+            passing = kept;
+        }
 
-                Some(())
-            })
+        // Restore the original order of the contexts.
+        for index in 0..ctxs.len() {
+            while positions[index] != index {
+                let target = positions[index];
+                ctxs.swap(index, target);
+                positions.swap(index, target);
+            }
+        }
+
+        results
     }
 
+    #[tracing::instrument(name = "filter_chain_write", level = "debug", skip_all, fields(source = %ctx.source, dest = %ctx.dest))]
     fn write(&self, ctx: &mut WriteContext) -> Option<()> {
+        let budget = ctx.budget;
+        let start = Instant::now();
+
+        // Writes run the chain in reverse, so the first filter runs last.
         self.filters
             .iter()
+            .zip(&self.write_metrics)
+            .enumerate()
             .rev()
-            .zip(self.filter_write_duration_seconds.iter().rev())
-            .try_fold((), |_, ((id, instance), histogram)| {
+            .try_fold((), |_, (index, ((id, instance), metrics))| {
                 tracing::trace!(%id, "write filtering packet");
-                match histogram.observe_closure_duration(|| instance.filter.write(ctx)) {
+                let result = metrics
+                    .duration_seconds
+                    .observe_closure_duration(|| instance.filter.write(ctx));
+                metrics.packets_total.inc();
+
+                match result {
+                    Some(())
+                        if index > 0
+                            && exceeded_budget(crate::metrics::WRITE, id, start, budget) =>
+                    {
+                        trace(&mut ctx.trace, id, Verdict::BudgetExceeded);
+                        metrics.budget_exceeded_total.inc();
+                        None
+                    }
                     Some(()) => {
                         tracing::trace!(%id, "write passing packet");
+                        trace(&mut ctx.trace, id, Verdict::Pass);
                         Some(())
                     }
                     None => {
                         tracing::trace!(%id, "write dropping packet");
+                        trace(&mut ctx.trace, id, Verdict::Drop);
                         crate::metrics::packets_dropped_total(crate::metrics::WRITE, id).inc();
+                        metrics.packets_dropped_total.inc();
                         None
                     }
                 }
             })
     }
+
+    fn has_write(&self) -> bool {
+        self.has_write
+    }
 }
 
 #[cfg(test)]
@@ -300,15 +666,15 @@ mod tests {
     use crate::{
         config,
         endpoint::Endpoint,
-        filters::Debug,
         test_utils::{new_test_config, TestFilter},
     };
 
     use super::*;
 
     #[test]
+    #[cfg(feature = "filter-debug")]
     fn from_config() {
-        let provider = Debug::factory();
+        let provider = crate::filters::Debug::factory();
 
         // everything is fine
         let filter_configs = &[config::Filter {
@@ -428,6 +794,210 @@ mod tests {
         );
     }
 
+    #[test]
+    fn read_batch() {
+        struct DropB;
+        impl Filter for DropB {
+            fn read(&self, ctx: &mut ReadContext) -> Option<()> {
+                (ctx.contents != b"b").then_some(())
+            }
+        }
+
+        let chain = FilterChain::new(vec![
+            (
+                "DropB".into(),
+                FilterInstance {
+                    config: Arc::new(serde_json::json!(null)),
+                    filter: Arc::new(DropB),
+                },
+            ),
+            (
+                TestFilter::NAME.into(),
+                FilterInstance {
+                    config: Arc::new(serde_json::json!(null)),
+                    filter: Arc::new(TestFilter),
+                },
+            ),
+        ])
+        .unwrap();
+
+        let mut contexts = ["a", "b", "c"]
+            .into_iter()
+            .map(|contents| {
+                let mut ctx = ReadContext::new(
+                    endpoints(),
+                    "127.0.0.1:70".parse().unwrap(),
+                    contents.as_bytes().to_vec(),
+                );
+                ctx.trace = Some(Vec::new());
+                ctx
+            })
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            vec![Some(()), None, Some(())],
+            chain.read_batch(&mut contexts)
+        );
+        assert_eq!(
+            vec![&b"a:odr:127.0.0.1:70"[..], b"b", b"c:odr:127.0.0.1:70"],
+            contexts
+                .iter()
+                .map(|ctx| &*ctx.contents)
+                .collect::<Vec<_>>()
+        );
+
+        let verdicts = |ctx: &ReadContext| {
+            ctx.trace
+                .iter()
+                .flatten()
+                .map(|step| (step.filter.clone(), step.verdict))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            vec![
+                ("DropB".to_owned(), Verdict::Pass),
+                (TestFilter::NAME.to_owned(), Verdict::Pass)
+            ],
+            verdicts(&contexts[0])
+        );
+        assert_eq!(
+            vec![("DropB".to_owned(), Verdict::Drop)],
+            verdicts(&contexts[1])
+        );
+    }
+
+    #[test]
+    fn reprocess() {
+        struct Decapsulate;
+        impl Filter for Decapsulate {
+            fn read(&self, ctx: &mut ReadContext) -> Option<()> {
+                if let Some(inner) = ctx.contents.strip_prefix(b"t:") {
+                    ctx.contents = inner.to_vec();
+                    ctx.reprocess_from(0);
+                }
+                Some(())
+            }
+        }
+
+        let chain = FilterChain::new(vec![
+            (
+                "Decapsulate".into(),
+                FilterInstance {
+                    config: Arc::new(serde_json::json!(null)),
+                    filter: Arc::new(Decapsulate),
+                },
+            ),
+            (
+                TestFilter::NAME.into(),
+                FilterInstance {
+                    config: Arc::new(serde_json::json!(null)),
+                    filter: Arc::new(TestFilter),
+                },
+            ),
+        ])
+        .unwrap();
+
+        let context = |contents: &[u8]| {
+            ReadContext::new(
+                endpoints(),
+                "127.0.0.1:70".parse().unwrap(),
+                contents.to_vec(),
+            )
+        };
+
+        let mut nested = context(b"t:t:hello");
+        chain.read(&mut nested).unwrap();
+        assert_eq!(b"hello:odr:127.0.0.1:70", &*nested.contents);
+
+        let looping = b"t:".repeat(MAX_REPROCESSES + 1);
+        assert!(chain.read(&mut context(&looping)).is_none());
+        assert_eq!(1, chain.read_metrics[0].reprocess_limit_total.get());
+
+        let mut contexts = vec![context(b"a"), context(b"t:b"), context(&looping)];
+        assert_eq!(
+            vec![Some(()), Some(()), None],
+            chain.read_batch(&mut contexts)
+        );
+        assert_eq!(b"a:odr:127.0.0.1:70", &*contexts[0].contents);
+        assert_eq!(b"b:odr:127.0.0.1:70", &*contexts[1].contents);
+    }
+
+    #[test]
+    fn filter_metrics() {
+        struct DropB;
+        impl Filter for DropB {
+            fn read(&self, ctx: &mut ReadContext) -> Option<()> {
+                (ctx.contents != b"b").then_some(())
+            }
+        }
+
+        let instance = |filter: Arc<dyn Filter>| FilterInstance {
+            config: Arc::new(serde_json::json!(null)),
+            filter,
+        };
+        let chain = FilterChain::new(vec![
+            ("DropB".into(), instance(Arc::new(DropB))),
+            (TestFilter::NAME.into(), instance(Arc::new(TestFilter))),
+            (TestFilter::NAME.into(), instance(Arc::new(TestFilter))),
+        ])
+        .unwrap();
+
+        let context = |contents: &[u8]| {
+            ReadContext::new(
+                endpoints(),
+                "127.0.0.1:70".parse().unwrap(),
+                contents.to_vec(),
+            )
+        };
+        assert!(chain.read(&mut context(b"b")).is_none());
+        chain.read_batch(&mut [context(b"a"), context(b"b")]);
+
+        let endpoint = endpoints().remove(0);
+        let mut context = WriteContext::new(
+            endpoint.clone(),
+            endpoint.address,
+            "127.0.0.1:70".parse().unwrap(),
+            b"hello".to_vec(),
+        );
+        chain.write(&mut context).unwrap();
+
+        let counts = |metrics: &[FilterMetrics]| {
+            metrics
+                .iter()
+                .map(|metrics| {
+                    (
+                        metrics.packets_total.get(),
+                        metrics.packets_dropped_total.get(),
+                    )
+                })
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(vec![(3, 2), (1, 0), (1, 0)], counts(&chain.read_metrics));
+        assert_eq!(vec![(1, 0), (1, 0), (1, 0)], counts(&chain.write_metrics));
+
+        // The same filter at different positions is counted separately.
+        let packets = chain
+            .registry()
+            .gather()
+            .into_iter()
+            .find(|family| family.get_name() == "quilkin_filter_packets_total")
+            .unwrap();
+        assert_eq!(6, packets.get_metric().len());
+
+        // Durations are only labelled with the filter's name.
+        let durations = chain
+            .registry()
+            .gather()
+            .into_iter()
+            .find(|family| family.get_name() == "quilkin_filter_read_duration_seconds")
+            .unwrap();
+        assert_eq!(2, durations.get_metric().len());
+        assert_eq!(
+            2,
+            durations.get_metric()[1].get_histogram().get_sample_count()
+        );
+    }
+
     #[test]
     fn get_configs() {
         struct TestFilter2;
@@ -472,4 +1042,126 @@ mod tests {
             configs
         )
     }
+
+    #[test]
+    #[cfg(feature = "filter-debug")]
+    fn scoped_metrics() {
+        let filter_configs = &[config::Filter {
+            name: crate::filters::Debug::factory().name().into(),
+            config: Some(serde_json::Map::default().into()),
+        }];
+
+        let families = |registry: &Registry| {
+            registry
+                .gather()
+                .into_iter()
+                .map(|family| family.get_name().to_owned())
+                .collect::<Vec<_>>()
+        };
+
+        // Rebuilding a chain with the same filters must not fail from
+        // registering the same metrics twice.
+        let first = FilterChain::try_create(filter_configs).unwrap();
+        let second = FilterChain::try_create(filter_configs).unwrap();
+
+        for chain in [&first, &second] {
+            assert!(families(chain.registry())
+                .iter()
+                .any(|name| name == "quilkin_filter_read_duration_seconds"));
+        }
+        assert!(!families(crate::metrics::registry())
+            .iter()
+            .any(|name| name == "quilkin_filter_read_duration_seconds"));
+
+        let merged = crate::metrics::gather(&[first.registry(), second.registry()]);
+        let read_duration = merged
+            .iter()
+            .find(|family| family.get_name() == "quilkin_filter_read_duration_seconds")
+            .unwrap();
+        assert_eq!(2, read_duration.get_metric().len());
+    }
+
+    #[test]
+    fn execution_budget() {
+        let start = Instant::now();
+        assert!(!exceeded_budget(crate::metrics::READ, "test", start, None));
+        assert!(!exceeded_budget(
+            crate::metrics::READ,
+            "test",
+            start,
+            Some(Duration::from_secs(60))
+        ));
+
+        let start = Instant::now() - Duration::from_millis(10);
+        assert!(exceeded_budget(
+            crate::metrics::WRITE,
+            "test",
+            start,
+            Some(Duration::from_millis(1))
+        ));
+    }
+
+    #[test]
+    fn execution_budget_spares_finished_packets() {
+        struct Slow;
+        impl Filter for Slow {
+            fn read(&self, _: &mut ReadContext) -> Option<()> {
+                std::thread::sleep(Duration::from_millis(5));
+                Some(())
+            }
+        }
+
+        let instance = |filter: Arc<dyn Filter>| FilterInstance {
+            config: Arc::new(serde_json::json!(null)),
+            filter,
+        };
+        let context = || {
+            ReadContext::new(
+                endpoints(),
+                "127.0.0.1:70".parse().unwrap(),
+                b"hello".to_vec(),
+            )
+            .execution_budget(Some(Duration::from_millis(1)))
+        };
+
+        let chain = FilterChain::new(vec![
+            (TestFilter::NAME.into(), instance(Arc::new(TestFilter))),
+            ("Slow".into(), instance(Arc::new(Slow))),
+        ])
+        .unwrap();
+        assert!(chain.read(&mut context()).is_some());
+        assert_eq!(0, chain.read_metrics[1].budget_exceeded_total.get());
+
+        let chain = FilterChain::new(vec![
+            ("Slow".into(), instance(Arc::new(Slow))),
+            (TestFilter::NAME.into(), instance(Arc::new(TestFilter))),
+        ])
+        .unwrap();
+        assert!(chain.read(&mut context()).is_none());
+        assert_eq!(1, chain.read_metrics[0].budget_exceeded_total.get());
+    }
+
+    #[test]
+    #[cfg(feature = "filter-pass")]
+    fn has_write() {
+        let instance = |filter: Arc<dyn Filter>| FilterInstance {
+            config: Arc::new(serde_json::json!(null)),
+            filter,
+        };
+
+        let chain = FilterChain::new(vec![
+            ("pass".into(), instance(Arc::new(crate::filters::Pass))),
+            ("pass".into(), instance(Arc::new(crate::filters::Pass))),
+        ])
+        .unwrap();
+        assert!(!chain.has_write());
+        assert!(!FilterChain::default().has_write());
+
+        let chain = FilterChain::new(vec![
+            ("pass".into(), instance(Arc::new(crate::filters::Pass))),
+            (TestFilter::NAME.into(), instance(Arc::new(TestFilter))),
+        ])
+        .unwrap();
+        assert!(chain.has_write());
+    }
 }
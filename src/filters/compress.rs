@@ -104,6 +104,41 @@ impl Filter for Compress {
         }
     }
 
+    #[cfg_attr(feature = "instrument", tracing::instrument(skip(self, ctxs)))]
+    fn read_batch(&self, ctxs: &mut [ReadContext]) -> Vec<Option<()>> {
+        let (mut compressed, mut decompressed) = (0, 0);
+        let results = ctxs
+            .iter_mut()
+            .map(|ctx| {
+                let original_size = ctx.contents.len() as u64;
+                match self.on_read {
+                    Action::Compress => match self.compressor.encode(&mut ctx.contents) {
+                        Ok(()) => {
+                            decompressed += original_size;
+                            compressed += ctx.contents.len() as u64;
+                            Some(())
+                        }
+                        Err(err) => self.failed_compression(&err),
+                    },
+                    Action::Decompress => match self.compressor.decode(&mut ctx.contents) {
+                        Ok(()) => {
+                            compressed += original_size;
+                            decompressed += ctx.contents.len() as u64;
+                            Some(())
+                        }
+                        Err(err) => self.failed_decompression(&err),
+                    },
+                    Action::DoNothing => Some(()),
+                }
+            })
+            .collect();
+
+        // Updating the shared counters once per batch rather than per packet.
+        self.metrics.compressed_bytes_total.inc_by(compressed);
+        self.metrics.decompressed_bytes_total.inc_by(decompressed);
+        results
+    }
+
     #[cfg_attr(feature = "instrument", tracing::instrument(skip(self, ctx)))]
     fn write(&self, ctx: &mut WriteContext) -> Option<()> {
         let original_size = ctx.contents.len();
@@ -136,6 +171,10 @@ impl Filter for Compress {
             Action::DoNothing => Some(()),
         }
     }
+
+    fn has_write(&self) -> bool {
+        !matches!(self.on_write, Action::DoNothing)
+    }
 }
 
 impl StaticFilter for Compress {
@@ -361,6 +400,33 @@ mod tests {
         assert_eq!(b"hello".to_vec(), &*write_context.contents)
     }
 
+    #[test]
+    fn read_batch() {
+        let compress = Compress::new(
+            Config {
+                mode: Default::default(),
+                on_read: Action::Decompress,
+                on_write: Action::Compress,
+            },
+            Metrics::new().unwrap(),
+        );
+        let (expected, compressed) = assert_downstream(&compress);
+
+        let mut contexts = [compressed, b"invalid".to_vec()]
+            .into_iter()
+            .map(|contents| {
+                ReadContext::new(
+                    vec![Endpoint::new("127.0.0.1:80".parse().unwrap())],
+                    "127.0.0.1:8080".parse().unwrap(),
+                    contents,
+                )
+            })
+            .collect::<Vec<_>>();
+
+        assert_eq!(vec![Some(()), None], compress.read_batch(&mut contexts));
+        assert_eq!(expected, contexts[0].contents);
+    }
+
     #[test]
     fn snappy() {
         let expected = contents_fixture();
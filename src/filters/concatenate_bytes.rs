@@ -43,42 +43,63 @@ impl ConcatenateBytes {
     }
 }
 
-impl Filter for ConcatenateBytes {
-    fn read(&self, ctx: &mut ReadContext) -> Option<()> {
-        match self.on_read {
-            Strategy::Append => {
-                ctx.contents.extend(self.bytes.iter());
-            }
-            Strategy::Prepend => {
-                ctx.contents.splice(..0, self.bytes.iter().cloned());
-            }
+impl Strategy {
+    fn apply(&self, contents: &mut Vec<u8>, bytes: &[u8]) {
+        match self {
+            Strategy::Append => crate::codec::append(contents, bytes),
+            Strategy::Prepend => crate::codec::prepend(contents, bytes),
             Strategy::DoNothing => {}
         }
+    }
+}
 
+impl Filter for ConcatenateBytes {
+    fn read(&self, ctx: &mut ReadContext) -> Option<()> {
+        self.on_read.apply(&mut ctx.contents, &self.bytes);
         Some(())
     }
 
     fn write(&self, ctx: &mut WriteContext) -> Option<()> {
-        match self.on_write {
-            Strategy::Append => {
-                ctx.contents.extend(self.bytes.iter());
-            }
-            Strategy::Prepend => {
-                ctx.contents.splice(..0, self.bytes.iter().cloned());
-            }
-            Strategy::DoNothing => {}
-        }
-
+        self.on_write.apply(&mut ctx.contents, &self.bytes);
         Some(())
     }
+
+    fn has_write(&self) -> bool {
+        !matches!(self.on_write, Strategy::DoNothing)
+    }
 }
 
 impl StaticFilter for ConcatenateBytes {
     const NAME: &'static str = "quilkin.filters.concatenate_bytes.v1alpha1.ConcatenateBytes";
     type Configuration = Config;
     type BinaryConfiguration = proto::ConcatenateBytes;
+    /// Before it could also change packets sent to clients, the filter only
+    /// had a single `strategy`, which is now `on_read`.
+    const RENAMED_FIELDS: &'static [(&'static str, &'static str)] = &[("strategy", "on_read")];
 
     fn try_from_config(config: Option<Self::Configuration>) -> Result<Self, Error> {
         Ok(ConcatenateBytes::new(Self::ensure_config_exists(config)?))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filters::FilterFactory;
+
+    #[test]
+    fn upgrades_strategy() {
+        let config = serde_json::json!({ "strategy": "APPEND", "bytes": "YWJj" });
+        let instance = ConcatenateBytes::factory()
+            .create_filter(CreateFilterArgs::fixed(Some(config.clone())))
+            .unwrap();
+        assert_eq!(
+            serde_json::json!({ "on_read": "APPEND", "on_write": "DO_NOTHING", "bytes": "YWJj" }),
+            *instance.config
+        );
+
+        assert!(ConcatenateBytes::factory()
+            .encode_config_to_protobuf(config)
+            .is_ok());
+    }
+}
@@ -0,0 +1,602 @@
+/*
+ * Copyright 2023 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+mod config;
+mod metrics;
+
+crate::include_proto!("quilkin.filters.encrypt.v1alpha1");
+
+use std::time::Duration;
+
+use once_cell::sync::Lazy;
+use openssl::{
+    hash::MessageDigest,
+    pkey::PKey,
+    sign::Signer,
+    symm::{decrypt_aead, encrypt_aead, Cipher},
+};
+use parking_lot::Mutex;
+use rand::Rng;
+
+use crate::{
+    endpoint::EndpointAddress,
+    filters::prelude::*,
+    ttl_map::{Entry, TtlMap},
+};
+
+use self::{metrics::Metrics, quilkin::filters::encrypt::v1alpha1 as proto};
+use super::replay::{Counter, ReplayWindow};
+
+pub use self::config::{Config, Key, Mode, KEY_LEN};
+pub use super::replay::REPLAY_WINDOW;
+
+/// The length of the client ID each packet starts with.
+pub const CLIENT_ID_LEN: usize = 8;
+/// The length of the nonce following the client ID, made of the 4 byte ID
+/// of the sender and its 8 byte counter.
+pub const NONCE_LEN: usize = 12;
+/// The length of the authentication tag each packet ends with.
+pub const TAG_LEN: usize = 16;
+
+/// The bit set in the sender IDs of proxies, so that their nonces never
+/// collide with those of clients encrypting with the same key.
+const PROXY_SENDER: u32 = 1 << 31;
+/// How long the keys and replay windows of a client, and the client ID of
+/// each client address, are kept after the client's last packet.
+const CLIENT_TTL: Duration = Duration::from_secs(60 * 60);
+const CLIENT_EXPIRY_POLL_INTERVAL: Duration = Duration::from_secs(60);
+/// How many senders the replay windows of a client are kept for.
+const MAX_SENDERS: usize = 8;
+
+/// The replay window of each sender of a client's packets, by client ID.
+type Windows = TtlMap<u64, Mutex<Vec<(u32, ReplayWindow)>>>;
+
+/// Returns the replay windows shared by every instance of the filter, so
+/// that they outlive the filter chains rebuilt on configuration changes,
+/// which would otherwise accept every packet received before the rebuild
+/// once more.
+fn windows() -> Windows {
+    static WINDOWS: Lazy<Windows> =
+        Lazy::new(|| TtlMap::new(CLIENT_TTL, CLIENT_EXPIRY_POLL_INTERVAL));
+    WINDOWS.clone()
+}
+/// Separates the keys derived by this filter from other uses of the same
+/// master keys.
+const KEY_CONTEXT: &[u8] = b"quilkin.filters.encrypt.v1alpha1";
+
+/// Returns the key of the client `client_id`, derived from `master_key` as
+/// the HMAC-SHA256 of the ID. Give clients their own key rather than the
+/// master key, so that they can't decrypt each other's packets.
+pub fn derive_key(master_key: &[u8], client_id: u64) -> Vec<u8> {
+    let key = PKey::hmac(master_key).expect("HMAC keys can be of any length");
+    let mut signer =
+        Signer::new(MessageDigest::sha256(), &key).expect("SHA-256 is always available");
+    signer
+        .update(KEY_CONTEXT)
+        .and_then(|()| signer.update(&client_id.to_be_bytes()))
+        .and_then(|()| signer.sign_to_vec())
+        .expect("signing into a vector can't fail")
+}
+
+/// Filter that encrypts and decrypts packets with ChaCha20-Poly1305, for
+/// confidentiality between clients and the proxy without a DTLS stack in
+/// the game client. Each client has its own key, derived from the
+/// configured master keys and its client ID, so clients can't decrypt each
+/// other's packets. Encrypted packets are the client ID, followed by a
+/// nonce made of the sender's ID and counter, the ciphertext and its
+/// authentication tag. Packets that can't be decrypted with any of the
+/// keys, or whose nonce was already received, are dropped.
+pub struct Encrypt {
+    /// The master keys, or the client's own keys if `client_id` is set.
+    keys: Vec<Vec<u8>>,
+    client_id: Option<u64>,
+    on_read: Mode,
+    on_write: Mode,
+    /// The ID this filter encrypts packets as, with [`PROXY_SENDER`] set.
+    sender: u32,
+    /// The counter of the last packet this filter encrypted.
+    counter: Counter,
+    /// The keys of each client, by client ID, in the same order as the
+    /// filter's.
+    clients: TtlMap<u64, Vec<Vec<u8>>>,
+    /// The replay windows of each client, shared with the other instances of
+    /// the filter.
+    windows: Windows,
+    /// The client ID of each client address, learned from the packets the
+    /// clients send, to encrypt the packets sent back to them.
+    addresses: TtlMap<EndpointAddress, u64>,
+    metrics: Metrics,
+}
+
+/// Records that `counter` was received from `sender`, unless it was
+/// already received. Returns whether the packet is accepted.
+fn accept(windows: &mut Vec<(u32, ReplayWindow)>, sender: u32, counter: u64) -> bool {
+    if let Some((_, window)) = windows
+        .iter_mut()
+        .find(|(window_sender, _)| *window_sender == sender)
+    {
+        if !window.is_fresh(counter) {
+            return false;
+        }
+        window.receive(counter);
+        return true;
+    }
+
+    if windows.len() == MAX_SENDERS {
+        windows.remove(0);
+    }
+    let mut window = ReplayWindow::default();
+    window.receive(counter);
+    windows.push((sender, window));
+    true
+}
+
+/// Why a packet couldn't be decrypted.
+#[derive(Debug, PartialEq)]
+enum DecryptError {
+    /// The packet is malformed, or wasn't encrypted with any of the keys.
+    Invalid,
+    /// The packet's nonce was already received.
+    Replayed,
+}
+
+impl Encrypt {
+    fn new(config: Config, metrics: Metrics, windows: Windows) -> Result<Self, Error> {
+        if config.keys.is_empty() {
+            return Err(Error::FieldInvalid {
+                field: "keys".into(),
+                reason: "at least one key is required".into(),
+            });
+        }
+        if config.keys.iter().any(|key| key.0.len() != KEY_LEN) {
+            return Err(Error::FieldInvalid {
+                field: "keys".into(),
+                reason: format!("keys must be {KEY_LEN} bytes long"),
+            });
+        }
+        if config.client_id.is_none() && config.on_read == Mode::Encrypt {
+            return Err(Error::FieldInvalid {
+                field: "client_id".into(),
+                reason: "a client ID is required to encrypt the packets received from clients"
+                    .into(),
+            });
+        }
+
+        Ok(Self {
+            keys: config.keys.into_iter().map(|key| key.0).collect(),
+            client_id: config.client_id,
+            on_read: config.on_read,
+            on_write: config.on_write,
+            sender: PROXY_SENDER | rand::thread_rng().gen::<u32>(),
+            counter: Counter::default(),
+            clients: TtlMap::new(CLIENT_TTL, CLIENT_EXPIRY_POLL_INTERVAL),
+            windows,
+            addresses: TtlMap::new(CLIENT_TTL, CLIENT_EXPIRY_POLL_INTERVAL),
+            metrics,
+        })
+    }
+
+    /// Applies `mode` to `contents`. `client` is the address of the client
+    /// the packet was received from or is sent to.
+    fn apply(&self, mode: Mode, client: &EndpointAddress, contents: &mut Vec<u8>) -> Option<()> {
+        match mode {
+            Mode::Encrypt => {
+                let client_id = self
+                    .client_id
+                    .or_else(|| self.addresses.get(client).map(|id| id.value));
+                let Some(client_id) = client_id else {
+                    self.metrics.packets_to_unknown_clients_total.inc();
+                    return None;
+                };
+                *contents = self.encrypt(client_id, contents)?;
+            }
+            Mode::Decrypt => {
+                let (plaintext, client_id, key) = match self.decrypt(contents) {
+                    Ok(decrypted) => decrypted,
+                    Err(DecryptError::Invalid) => {
+                        self.metrics.packets_dropped_total.inc();
+                        return None;
+                    }
+                    Err(DecryptError::Replayed) => {
+                        self.metrics.packets_replayed_total.inc();
+                        return None;
+                    }
+                };
+                if key != 0 {
+                    self.metrics.packets_decrypted_with_old_key_total.inc();
+                }
+                if self.client_id.is_none() {
+                    self.addresses.insert(client.clone(), client_id);
+                }
+                *contents = plaintext;
+            }
+            Mode::DoNothing => {}
+        }
+        Some(())
+    }
+
+    /// Returns the keys of the client `client_id`.
+    fn client_keys(&self, client_id: u64) -> Vec<Vec<u8>> {
+        if self.client_id.is_some() {
+            self.keys.clone()
+        } else {
+            self.keys
+                .iter()
+                .map(|key| derive_key(key, client_id))
+                .collect()
+        }
+    }
+
+    /// Returns `plaintext` encrypted with the first key of the client
+    /// `client_id`.
+    fn encrypt(&self, id: u64, plaintext: &[u8]) -> Option<Vec<u8>> {
+        let client_id = id.to_be_bytes();
+        let mut nonce = [0; NONCE_LEN];
+        nonce[..4].copy_from_slice(&self.sender.to_be_bytes());
+        nonce[4..].copy_from_slice(&self.counter.next().to_be_bytes());
+        let mut tag = [0; TAG_LEN];
+
+        let mut encrypt = |key: &[u8]| {
+            encrypt_aead(
+                Cipher::chacha20_poly1305(),
+                key,
+                Some(&nonce),
+                &client_id,
+                plaintext,
+                &mut tag,
+            )
+        };
+        let ciphertext = match self.clients.get(&id) {
+            Some(keys) => encrypt(&keys[0]),
+            None => encrypt(&self.client_keys(id)[0]),
+        };
+
+        match ciphertext {
+            Ok(ciphertext) => {
+                Some([&client_id[..], &nonce[..], &ciphertext[..], &tag[..]].concat())
+            }
+            Err(error) => {
+                tracing::error!(%error, "failed to encrypt packet");
+                None
+            }
+        }
+    }
+
+    /// Returns `packet` decrypted, along with the ID of the client and the
+    /// index of the key it was encrypted with.
+    fn decrypt(&self, packet: &[u8]) -> Result<(Vec<u8>, u64, usize), DecryptError> {
+        if packet.len() < CLIENT_ID_LEN + NONCE_LEN + TAG_LEN {
+            return Err(DecryptError::Invalid);
+        }
+        let (client_id, rest) = packet.split_at(CLIENT_ID_LEN);
+        let (nonce, rest) = rest.split_at(NONCE_LEN);
+        let (ciphertext, tag) = rest.split_at(rest.len() - TAG_LEN);
+        let id = u64::from_be_bytes(client_id.try_into().unwrap());
+        let sender = u32::from_be_bytes(nonce[..4].try_into().unwrap());
+        let counter = u64::from_be_bytes(nonce[4..].try_into().unwrap());
+        if self.client_id.map_or(false, |own| own != id) {
+            return Err(DecryptError::Invalid);
+        }
+
+        let open = |keys: &[Vec<u8>]| {
+            keys.iter()
+                .enumerate()
+                .find_map(|(index, key)| {
+                    decrypt_aead(
+                        Cipher::chacha20_poly1305(),
+                        key,
+                        Some(nonce),
+                        client_id,
+                        ciphertext,
+                        tag,
+                    )
+                    .ok()
+                    .map(|plaintext| (plaintext, id, index))
+                })
+                .ok_or(DecryptError::Invalid)
+        };
+        let open_client = || match self.clients.get(&id) {
+            Some(keys) => open(&keys),
+            None => {
+                let keys = self.client_keys(id);
+                let decrypted = open(&keys)?;
+                self.clients.insert(id, keys);
+                Ok(decrypted)
+            }
+        };
+
+        // The window is only updated once the packet is authenticated, so
+        // that forged packets can't advance it.
+        if let Some(windows) = self.windows.get(&id) {
+            let mut windows = windows.lock();
+            let fresh = windows
+                .iter()
+                .find(|(window_sender, _)| *window_sender == sender)
+                .map_or(true, |(_, window)| window.is_fresh(counter));
+            if !fresh {
+                return Err(DecryptError::Replayed);
+            }
+            let decrypted = open_client()?;
+            accept(&mut windows, sender, counter);
+            return Ok(decrypted);
+        }
+
+        let decrypted = open_client()?;
+        match self.windows.entry(id) {
+            Entry::Occupied(entry) => {
+                if !accept(&mut entry.get().lock(), sender, counter) {
+                    return Err(DecryptError::Replayed);
+                }
+            }
+            Entry::Vacant(entry) => {
+                let mut windows = Vec::new();
+                accept(&mut windows, sender, counter);
+                entry.insert(Mutex::new(windows));
+            }
+        }
+        Ok(decrypted)
+    }
+}
+
+impl Filter for Encrypt {
+    #[cfg_attr(feature = "instrument", tracing::instrument(skip(self, ctx)))]
+    fn read(&self, ctx: &mut ReadContext) -> Option<()> {
+        self.apply(self.on_read, &ctx.source, &mut ctx.contents)
+    }
+
+    #[cfg_attr(feature = "instrument", tracing::instrument(skip(self, ctx)))]
+    fn write(&self, ctx: &mut WriteContext) -> Option<()> {
+        self.apply(self.on_write, &ctx.dest, &mut ctx.contents)
+    }
+
+    fn has_write(&self) -> bool {
+        self.on_write != Mode::DoNothing
+    }
+}
+
+impl StaticFilter for Encrypt {
+    const NAME: &'static str = "quilkin.filters.encrypt.v1alpha1.Encrypt";
+    const SECRET_FIELDS: &'static [&'static str] = &["keys"];
+    type Configuration = Config;
+    type BinaryConfiguration = proto::Encrypt;
+
+    fn try_from_config(config: Option<Self::Configuration>) -> Result<Self, Error> {
+        Self::new(
+            Self::ensure_config_exists(config)?,
+            Metrics::new()?,
+            windows(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+    use crate::endpoint::Endpoint;
+
+    const NEW: [u8; KEY_LEN] = [1; KEY_LEN];
+    const OLD: [u8; KEY_LEN] = [2; KEY_LEN];
+    const CLIENT_ID: u64 = 7;
+
+    fn encrypt(config: Config) -> Encrypt {
+        encrypt_with(config, new_windows())
+    }
+
+    /// Returns a filter keeping its replay windows in `windows`.
+    fn encrypt_with(config: Config, windows: Windows) -> Encrypt {
+        Encrypt::new(config, Metrics::new().unwrap(), windows).unwrap()
+    }
+
+    fn new_windows() -> Windows {
+        TtlMap::new(CLIENT_TTL, CLIENT_EXPIRY_POLL_INTERVAL)
+    }
+
+    /// Returns a filter encrypting packets like the client `client_id`, with
+    /// its key derived from `master_key`.
+    fn client_filter(master_key: [u8; KEY_LEN], client_id: u64) -> Encrypt {
+        encrypt(Config {
+            client_id: Some(client_id),
+            on_read: Mode::Encrypt,
+            on_write: Mode::Decrypt,
+            ..Config::new([derive_key(&master_key, client_id)])
+        })
+    }
+
+    fn read_from(filter: &Encrypt, port: u16, contents: Vec<u8>) -> Option<Vec<u8>> {
+        let mut ctx = ReadContext::new(
+            vec![Endpoint::new((Ipv4Addr::LOCALHOST, 8089).into())],
+            (Ipv4Addr::LOCALHOST, port).into(),
+            contents,
+        );
+        filter.read(&mut ctx).map(|()| ctx.contents)
+    }
+
+    fn write_to(filter: &Encrypt, port: u16, contents: Vec<u8>) -> Option<Vec<u8>> {
+        let mut ctx = WriteContext::new(
+            Endpoint::new((Ipv4Addr::LOCALHOST, 8089).into()),
+            (Ipv4Addr::LOCALHOST, 8089).into(),
+            (Ipv4Addr::LOCALHOST, port).into(),
+            contents,
+        );
+        filter.write(&mut ctx).map(|()| ctx.contents)
+    }
+
+    fn read(filter: &Encrypt, contents: Vec<u8>) -> Option<Vec<u8>> {
+        read_from(filter, 7000, contents)
+    }
+
+    fn write(filter: &Encrypt, contents: Vec<u8>) -> Option<Vec<u8>> {
+        write_to(filter, 7000, contents)
+    }
+
+    #[tokio::test]
+    async fn invalid_config() {
+        for config in [
+            Config::new(Vec::<Vec<u8>>::new()),
+            Config::new([&NEW[1..]]),
+            Config::new([&NEW[..], &[0; KEY_LEN + 1][..]]),
+            Config {
+                on_read: Mode::Encrypt,
+                ..Config::new([NEW])
+            },
+        ] {
+            assert!(Encrypt::new(config, Metrics::new().unwrap(), new_windows()).is_err());
+        }
+    }
+
+    #[tokio::test]
+    async fn round_trip() {
+        let filter = encrypt(Config::new([NEW]));
+        let client = client_filter(NEW, CLIENT_ID);
+        assert!(filter.has_write());
+
+        let packet = read(&client, b"hello".to_vec()).unwrap();
+        assert_eq!(
+            CLIENT_ID_LEN + NONCE_LEN + b"hello".len() + TAG_LEN,
+            packet.len()
+        );
+        assert_eq!(CLIENT_ID.to_be_bytes(), packet[..CLIENT_ID_LEN]);
+        assert!(!packet.windows(5).any(|window| window == b"hello"));
+        // Each packet has its own nonce.
+        assert_ne!(packet, read(&client, b"hello".to_vec()).unwrap());
+
+        assert_eq!(Some(b"hello".to_vec()), read(&filter, packet.clone()));
+        // Replies are encrypted with the key of the client they're sent to.
+        let reply = write(&filter, b"world".to_vec()).unwrap();
+        assert_eq!(Some(b"world".to_vec()), write(&client, reply));
+
+        let mut tampered = packet;
+        tampered[CLIENT_ID_LEN + NONCE_LEN] ^= 1;
+        assert_eq!(None, read(&filter, tampered));
+        assert_eq!(None, read(&filter, b"short".to_vec()));
+        assert_eq!(2, filter.metrics.packets_dropped_total.get());
+    }
+
+    #[tokio::test]
+    async fn rejects_replays() {
+        let filter = encrypt(Config::new([NEW]));
+        let client = client_filter(NEW, CLIENT_ID);
+
+        let first = read(&client, b"first".to_vec()).unwrap();
+        let second = read(&client, b"second".to_vec()).unwrap();
+        // Packets reordered on the way are still accepted, once.
+        assert_eq!(Some(b"second".to_vec()), read(&filter, second.clone()));
+        assert_eq!(Some(b"first".to_vec()), read(&filter, first.clone()));
+        assert_eq!(None, read(&filter, first));
+        assert_eq!(None, read(&filter, second));
+        assert_eq!(2, filter.metrics.packets_replayed_total.get());
+        assert_eq!(0, filter.metrics.packets_dropped_total.get());
+
+        let reply = write(&filter, b"reply".to_vec()).unwrap();
+        assert_eq!(Some(b"reply".to_vec()), write(&client, reply.clone()));
+        assert_eq!(None, write(&client, reply));
+        assert_eq!(1, client.metrics.packets_replayed_total.get());
+    }
+
+    #[tokio::test]
+    async fn rejects_replays_after_rebuild() {
+        let windows = new_windows();
+        let filter = encrypt_with(Config::new([NEW]), windows.clone());
+        let client = client_filter(NEW, CLIENT_ID);
+
+        let packet = read(&client, b"hello".to_vec()).unwrap();
+        assert_eq!(Some(b"hello".to_vec()), read(&filter, packet.clone()));
+
+        // Filters rebuilt from a new configuration keep the windows.
+        let rebuilt = encrypt_with(Config::new([OLD, NEW]), windows);
+        assert_eq!(None, read(&rebuilt, packet));
+        assert_eq!(1, rebuilt.metrics.packets_replayed_total.get());
+    }
+
+    #[tokio::test]
+    async fn isolates_clients() {
+        let filter = encrypt(Config::new([NEW]));
+        let client = client_filter(NEW, CLIENT_ID);
+        let other = client_filter(NEW, CLIENT_ID + 1);
+
+        // A client can't pass off its packets as another client's.
+        let mut packet = read(&other, b"hello".to_vec()).unwrap();
+        packet[..CLIENT_ID_LEN].copy_from_slice(&CLIENT_ID.to_be_bytes());
+        assert_eq!(None, read(&filter, packet));
+
+        let packet = read(&client, b"hello".to_vec()).unwrap();
+        assert_eq!(None, write(&other, packet.clone()));
+        assert_eq!(Some(b"hello".to_vec()), read(&filter, packet));
+        // Replies go to each client encrypted with its own key.
+        let packet = read(&other, b"hello".to_vec()).unwrap();
+        assert_eq!(Some(b"hello".to_vec()), read_from(&filter, 7001, packet));
+        let reply = write(&filter, b"reply".to_vec()).unwrap();
+        assert_eq!(None, write(&other, reply.clone()));
+        assert_eq!(Some(b"reply".to_vec()), write(&client, reply));
+        let reply = write_to(&filter, 7001, b"reply".to_vec()).unwrap();
+        assert_eq!(Some(b"reply".to_vec()), write(&other, reply));
+
+        // Packets to clients that haven't sent any are dropped.
+        assert_eq!(None, write_to(&filter, 7002, b"reply".to_vec()));
+        assert_eq!(1, filter.metrics.packets_to_unknown_clients_total.get());
+    }
+
+    #[tokio::test]
+    async fn rotates_keys() {
+        let old = client_filter(OLD, CLIENT_ID);
+        let filter = encrypt(Config::new([NEW, OLD]));
+        let unknown = encrypt(Config::new([[3; KEY_LEN]]));
+
+        let packet = read(&old, b"hello".to_vec()).unwrap();
+        assert_eq!(Some(b"hello".to_vec()), read(&filter, packet.clone()));
+        assert_eq!(1, filter.metrics.packets_decrypted_with_old_key_total.get());
+        assert_eq!(None, read(&unknown, packet));
+
+        // Packets are encrypted with the first key.
+        let packet = write(&filter, b"hello".to_vec()).unwrap();
+        assert_eq!(None, write(&old, packet.clone()));
+        assert_eq!(
+            Some(b"hello".to_vec()),
+            write(&client_filter(NEW, CLIENT_ID), packet)
+        );
+    }
+
+    #[tokio::test]
+    async fn modes() {
+        let filter = encrypt(Config {
+            on_write: Mode::DoNothing,
+            ..Config::new([NEW])
+        });
+        assert!(!filter.has_write());
+        assert_eq!(Some(b"hello".to_vec()), write(&filter, b"hello".to_vec()));
+    }
+
+    #[test]
+    fn convert_proto_config() {
+        let config = Config {
+            client_id: Some(CLIENT_ID),
+            on_read: Mode::DoNothing,
+            on_write: Mode::Decrypt,
+            ..Config::new([NEW, OLD])
+        };
+        assert_eq!(config, Config::from(proto::Encrypt::from(config.clone())));
+
+        let defaults = Config::from(proto::Encrypt {
+            keys: vec![NEW.to_vec()],
+            on_read: None,
+            on_write: None,
+            client_id: None,
+        });
+        assert_eq!(Config::new([NEW]), defaults);
+    }
+}
@@ -0,0 +1,153 @@
+/*
+ * Copyright 2023 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::config::Base64Standard;
+
+use super::proto;
+
+/// The length of ChaCha20-Poly1305 keys.
+pub const KEY_LEN: usize = 32;
+
+/// Configuration for the [`Encrypt`][super::Encrypt] filter.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize, JsonSchema)]
+pub struct Config {
+    /// The base64 encoded 32 byte master keys each client's keys are
+    /// derived from, or the client's own keys if `client_id` is set.
+    /// Packets are encrypted with the first key, and packets encrypted with
+    /// any of them are decrypted, so that keys can be rotated by adding the
+    /// new key in front and removing the old one once clients have stopped
+    /// using it.
+    pub keys: Vec<Key>,
+    /// The ID of the client packets are encrypted for, when the filter runs
+    /// on behalf of a single client, such as in a client side proxy.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client_id: Option<u64>,
+    /// Whether packets received from clients are decrypted, encrypted or
+    /// left as they are.
+    #[serde(default = "default_on_read")]
+    pub on_read: Mode,
+    /// Whether packets sent to clients are encrypted, decrypted or left as
+    /// they are.
+    #[serde(default = "default_on_write")]
+    pub on_write: Mode,
+}
+
+/// A key packets are encrypted with.
+#[derive(Clone, Deserialize, Eq, PartialEq, Serialize, JsonSchema)]
+#[serde(transparent)]
+pub struct Key(
+    #[serde(with = "Base64Standard")]
+    #[schemars(with = "String")]
+    pub Vec<u8>,
+);
+
+impl std::fmt::Debug for Key {
+    // Keys are left out of logs.
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str("Key(..)")
+    }
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize, JsonSchema)]
+pub enum Mode {
+    #[serde(rename = "ENCRYPT")]
+    Encrypt,
+    #[serde(rename = "DECRYPT")]
+    Decrypt,
+    #[serde(rename = "DO_NOTHING")]
+    DoNothing,
+}
+
+fn default_on_read() -> Mode {
+    Mode::Decrypt
+}
+
+fn default_on_write() -> Mode {
+    Mode::Encrypt
+}
+
+impl Config {
+    pub fn new(keys: impl IntoIterator<Item = impl AsRef<[u8]>>) -> Self {
+        Self {
+            keys: keys
+                .into_iter()
+                .map(|key| Key(key.as_ref().to_vec()))
+                .collect(),
+            client_id: None,
+            on_read: default_on_read(),
+            on_write: default_on_write(),
+        }
+    }
+}
+
+impl From<Mode> for proto::encrypt::Mode {
+    fn from(mode: Mode) -> Self {
+        match mode {
+            Mode::Encrypt => Self::Encrypt,
+            Mode::Decrypt => Self::Decrypt,
+            Mode::DoNothing => Self::DoNothing,
+        }
+    }
+}
+
+impl From<proto::encrypt::Mode> for Mode {
+    fn from(mode: proto::encrypt::Mode) -> Self {
+        match mode {
+            proto::encrypt::Mode::Encrypt => Self::Encrypt,
+            proto::encrypt::Mode::Decrypt => Self::Decrypt,
+            proto::encrypt::Mode::DoNothing => Self::DoNothing,
+        }
+    }
+}
+
+impl From<Mode> for proto::encrypt::ModeValue {
+    fn from(mode: Mode) -> Self {
+        Self {
+            value: proto::encrypt::Mode::from(mode) as i32,
+        }
+    }
+}
+
+impl From<Config> for proto::Encrypt {
+    fn from(config: Config) -> Self {
+        Self {
+            keys: config.keys.into_iter().map(|key| key.0).collect(),
+            client_id: config.client_id,
+            on_read: Some(config.on_read.into()),
+            on_write: Some(config.on_write.into()),
+        }
+    }
+}
+
+impl From<proto::Encrypt> for Config {
+    fn from(p: proto::Encrypt) -> Self {
+        Self {
+            keys: p.keys.into_iter().map(Key).collect(),
+            client_id: p.client_id,
+            on_read: p
+                .on_read
+                .map(|mode| mode.value().into())
+                .unwrap_or_else(default_on_read),
+            on_write: p
+                .on_write
+                .map(|mode| mode.value().into())
+                .unwrap_or_else(default_on_write),
+        }
+    }
+}
@@ -0,0 +1,58 @@
+/*
+ * Copyright 2023 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use prometheus::{IntCounter, Result as MetricsResult};
+
+use crate::metrics::{filter_opts, CollectorExt};
+
+/// Register and manage metrics for this filter
+pub(super) struct Metrics {
+    pub(super) packets_dropped_total: IntCounter,
+    pub(super) packets_decrypted_with_old_key_total: IntCounter,
+    pub(super) packets_replayed_total: IntCounter,
+    pub(super) packets_to_unknown_clients_total: IntCounter,
+}
+
+impl Metrics {
+    pub(super) fn new() -> MetricsResult<Self> {
+        Ok(Self {
+            packets_dropped_total: IntCounter::with_opts(filter_opts(
+                "packets_dropped_total",
+                "Encrypt",
+                "Total number of packets dropped because they couldn't be decrypted with any of the keys",
+            ))?
+            .register_if_not_exists()?,
+            packets_decrypted_with_old_key_total: IntCounter::with_opts(filter_opts(
+                "packets_decrypted_with_old_key_total",
+                "Encrypt",
+                "Total number of packets encrypted with a key other than the first one",
+            ))?
+            .register_if_not_exists()?,
+            packets_replayed_total: IntCounter::with_opts(filter_opts(
+                "packets_replayed_total",
+                "Encrypt",
+                "Total number of packets dropped because their nonce was already received",
+            ))?
+            .register_if_not_exists()?,
+            packets_to_unknown_clients_total: IntCounter::with_opts(filter_opts(
+                "packets_to_unknown_clients_total",
+                "Encrypt",
+                "Total number of packets dropped because the client they were sent to hadn't sent any",
+            ))?
+            .register_if_not_exists()?,
+        })
+    }
+}
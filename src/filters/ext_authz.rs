@@ -0,0 +1,510 @@
+/*
+ * Copyright 2023 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+mod config;
+mod metrics;
+
+crate::include_proto!("quilkin.filters.ext_authz.v1alpha1");
+
+#[allow(warnings)]
+pub mod service {
+    tonic::include_proto!("quilkin.authz.v1alpha1");
+}
+
+use std::{sync::Arc, time::Duration};
+
+use tokio::{
+    sync::{mpsc, Semaphore},
+    task::JoinHandle,
+};
+use tonic::transport::Channel;
+
+use crate::{endpoint::EndpointAddress, filters::prelude::*, metadata};
+
+use self::{
+    metrics::Metrics,
+    quilkin::filters::ext_authz::v1alpha1 as proto,
+    service::{CheckRequest, Revocation, WatchRevocationsRequest},
+};
+use super::verdict_cache::{VerdictCache, PURGE_INTERVAL, RETRY_INTERVAL};
+
+pub use self::{
+    config::{Config, FailurePolicy},
+    service::{
+        authorization_service_client::AuthorizationServiceClient,
+        authorization_service_server::{AuthorizationService, AuthorizationServiceServer},
+        CheckResponse,
+    },
+};
+
+/// The number of sessions that can be waiting to be checked, further sessions
+/// are checked once a later packet finds room in the queue.
+const CHECK_QUEUE_SIZE: usize = 1024;
+/// The maximum number of checks in flight at once.
+const MAX_CONCURRENT_CHECKS: usize = 64;
+/// How long to wait before watching revocations again after the stream
+/// failed.
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+/// Filter that admits sessions by checking them with an external
+/// authorization service, and drops packets from sessions that aren't
+/// authorized.
+///
+/// Each session is checked once, and its verdict is cached until its TTL
+/// expires or the service revokes it, so packets never wait on the service.
+pub struct ExtAuthz {
+    metadata_key: Option<metadata::Key>,
+    failure_policy: FailurePolicy,
+    /// Whether each session is allowed.
+    cache: Arc<VerdictCache>,
+    checks: mpsc::Sender<(String, EndpointAddress)>,
+    metrics: Arc<Metrics>,
+    revocations: JoinHandle<()>,
+}
+
+impl ExtAuthz {
+    fn new(config: Config, metrics: Metrics) -> Result<Self, Error> {
+        let endpoint =
+            tonic::transport::Endpoint::from_shared(config.service.clone()).map_err(|error| {
+                Error::FieldInvalid {
+                    field: "service".into(),
+                    reason: error.to_string(),
+                }
+            })?;
+        let client = AuthorizationServiceClient::new(endpoint.connect_lazy());
+
+        let cache = Arc::new(VerdictCache::default());
+        let metrics = Arc::new(metrics);
+        let deny_cache_ttl = Duration::from_secs(config.deny_cache_ttl);
+        let (checks, sessions) = mpsc::channel(CHECK_QUEUE_SIZE);
+        tokio::spawn(
+            Arc::new(Checker {
+                client: client.clone(),
+                timeout: Duration::from_millis(config.timeout_ms),
+                cache_ttl: Duration::from_secs(config.cache_ttl),
+                deny_cache_ttl,
+                cache: cache.clone(),
+                metrics: metrics.clone(),
+            })
+            .run(sessions),
+        );
+        let revocations = tokio::spawn(watch_revocations(
+            client,
+            cache.clone(),
+            deny_cache_ttl,
+            metrics.clone(),
+        ));
+
+        Ok(Self {
+            metadata_key: config.metadata_key,
+            failure_policy: config.failure_policy,
+            cache,
+            checks,
+            metrics,
+            revocations,
+        })
+    }
+
+    /// Returns the key of the packet's session, which is the token in the
+    /// metadata encoded as base64, or the source address.
+    fn key(&self, ctx: &ReadContext) -> Option<String> {
+        match self.metadata_key {
+            None => Some(ctx.source.to_string()),
+            Some(metadata_key) => match ctx.metadata.get(&metadata_key)? {
+                metadata::Value::Bytes(token) => Some(base64::encode(token)),
+                metadata::Value::String(token) => Some(token.clone()),
+                _ => None,
+            },
+        }
+    }
+
+    /// Returns whether the session with `key` is allowed, or `None` if it
+    /// isn't known yet. New and expired sessions are queued to be checked.
+    fn is_allowed(&self, key: String, source: &EndpointAddress) -> Option<bool> {
+        self.cache.get(key, |key| {
+            self.checks.try_send((key, source.clone())).is_ok()
+        })
+    }
+}
+
+impl Drop for ExtAuthz {
+    fn drop(&mut self) {
+        self.revocations.abort();
+    }
+}
+
+impl Filter for ExtAuthz {
+    #[cfg_attr(feature = "instrument", tracing::instrument(skip(self, ctx)))]
+    fn read(&self, ctx: &mut ReadContext) -> Option<()> {
+        let Some(key) = self.key(ctx) else {
+            tracing::trace!(source = %ctx.source, "Dropping packet, no token was found");
+            self.metrics.packets_dropped_no_token.inc();
+            return None;
+        };
+
+        match self.is_allowed(key, &ctx.source) {
+            Some(true) => Some(()),
+            Some(false) => {
+                tracing::trace!(source = %ctx.source, "Dropping packet from unauthorized session");
+                self.metrics.packets_dropped_denied.inc();
+                None
+            }
+            None => match self.failure_policy {
+                FailurePolicy::Open => Some(()),
+                FailurePolicy::Closed => {
+                    self.metrics.packets_dropped_unknown.inc();
+                    None
+                }
+            },
+        }
+    }
+
+    fn has_write(&self) -> bool {
+        false
+    }
+}
+
+impl StaticFilter for ExtAuthz {
+    const NAME: &'static str = "quilkin.filters.ext_authz.v1alpha1.ExtAuthz";
+    type Configuration = Config;
+    type BinaryConfiguration = proto::ExtAuthz;
+
+    fn try_from_config(config: Option<Self::Configuration>) -> Result<Self, Error> {
+        Self::new(Self::ensure_config_exists(config)?, Metrics::new()?)
+    }
+}
+
+/// The background task checking sessions queued by the filter, until the
+/// filter is dropped.
+struct Checker {
+    client: AuthorizationServiceClient<Channel>,
+    timeout: Duration,
+    cache_ttl: Duration,
+    deny_cache_ttl: Duration,
+    cache: Arc<VerdictCache>,
+    metrics: Arc<Metrics>,
+}
+
+impl Checker {
+    async fn run(self: Arc<Self>, mut sessions: mpsc::Receiver<(String, EndpointAddress)>) {
+        let permits = Arc::new(Semaphore::new(MAX_CONCURRENT_CHECKS));
+        let mut purge = tokio::time::interval(PURGE_INTERVAL);
+
+        loop {
+            let (key, source) = tokio::select! {
+                session = sessions.recv() => match session {
+                    Some(session) => session,
+                    None => return,
+                },
+                _ = purge.tick() => {
+                    self.cache.purge();
+                    continue;
+                }
+            };
+
+            let permit = permits.clone().acquire_owned().await.unwrap();
+            let checker = self.clone();
+            tokio::spawn(async move {
+                checker.check(key, source).await;
+                drop(permit);
+            });
+        }
+    }
+
+    async fn check(&self, key: String, source: EndpointAddress) {
+        let request = CheckRequest {
+            key: key.clone(),
+            source: source.to_string(),
+        };
+        let result = tokio::time::timeout(self.timeout, self.client.clone().check(request)).await;
+
+        let (allowed, ttl) = match result {
+            Ok(Ok(response)) => {
+                let response = response.into_inner();
+                let default_ttl = if response.allowed {
+                    self.metrics.checks_allowed.inc();
+                    self.cache_ttl
+                } else {
+                    tracing::debug!(%source, reason = %response.reason, "session denied");
+                    self.metrics.checks_denied.inc();
+                    self.deny_cache_ttl
+                };
+
+                let ttl = match response.ttl {
+                    0 => default_ttl,
+                    ttl => Duration::from_secs(ttl),
+                };
+                (Some(response.allowed), ttl)
+            }
+            Ok(Err(status)) => {
+                tracing::debug!(%status, %source, "authorization check failed");
+                self.metrics.checks_failed.inc();
+                (None, RETRY_INTERVAL)
+            }
+            Err(_) => {
+                tracing::debug!(%source, "authorization check timed out");
+                self.metrics.checks_failed.inc();
+                (None, RETRY_INTERVAL)
+            }
+        };
+
+        self.cache.accept(&key, allowed, ttl);
+    }
+}
+
+/// Denies sessions as the service revokes them, until the filter is dropped
+/// or the service doesn't implement revocations.
+async fn watch_revocations(
+    mut client: AuthorizationServiceClient<Channel>,
+    cache: Arc<VerdictCache>,
+    deny_cache_ttl: Duration,
+    metrics: Arc<Metrics>,
+) {
+    loop {
+        match client.watch_revocations(WatchRevocationsRequest {}).await {
+            Ok(response) => {
+                let mut stream = response.into_inner();
+                loop {
+                    match stream.message().await {
+                        Ok(Some(Revocation { keys })) => {
+                            metrics.revocations.inc_by(keys.len() as u64);
+                            let mut ignored = 0;
+                            for key in keys {
+                                // Sessions are denied until the TTL expires,
+                                // whether or not they've been checked yet.
+                                if !cache.set(key, false, deny_cache_ttl) {
+                                    ignored += 1;
+                                }
+                            }
+                            if ignored > 0 {
+                                tracing::debug!(
+                                    ignored,
+                                    "cache is full, ignoring revocations of unknown sessions"
+                                );
+                            }
+                        }
+                        Ok(None) => break,
+                        Err(status) => {
+                            tracing::warn!(%status, "revocation stream failed, reconnecting");
+                            break;
+                        }
+                    }
+                }
+            }
+            Err(status) if status.code() == tonic::Code::Unimplemented => {
+                tracing::debug!("authorization service doesn't support revocations");
+                return;
+            }
+            Err(status) => tracing::debug!(%status, "failed to watch revocations"),
+        }
+
+        tokio::time::sleep(RECONNECT_DELAY).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::Ipv4Addr,
+        sync::atomic::{AtomicUsize, Ordering},
+    };
+
+    use tokio::sync::broadcast;
+
+    use super::*;
+    use crate::{endpoint::Endpoint, test_utils::assert_write_no_change};
+
+    /// Admits clients on `127.0.0.1`, and revokes whatever is sent to
+    /// `revocations`.
+    #[derive(Clone)]
+    struct Service {
+        checks: Arc<AtomicUsize>,
+        revocations: broadcast::Sender<Revocation>,
+    }
+
+    #[tonic::async_trait]
+    impl AuthorizationService for Service {
+        async fn check(
+            &self,
+            request: tonic::Request<CheckRequest>,
+        ) -> Result<tonic::Response<CheckResponse>, tonic::Status> {
+            self.checks.fetch_add(1, Ordering::SeqCst);
+            let allowed = request.get_ref().source.starts_with("127.0.0.1:");
+            Ok(tonic::Response::new(CheckResponse {
+                allowed,
+                ttl: 0,
+                reason: String::new(),
+            }))
+        }
+
+        type WatchRevocationsStream = std::pin::Pin<
+            Box<dyn futures::Stream<Item = Result<Revocation, tonic::Status>> + Send>,
+        >;
+
+        async fn watch_revocations(
+            &self,
+            _: tonic::Request<WatchRevocationsRequest>,
+        ) -> Result<tonic::Response<Self::WatchRevocationsStream>, tonic::Status> {
+            let mut revocations = self.revocations.subscribe();
+            Ok(tonic::Response::new(Box::pin(async_stream::stream! {
+                while let Ok(revocation) = revocations.recv().await {
+                    yield Ok(revocation);
+                }
+            })))
+        }
+    }
+
+    /// Spawns an authorization service, and returns its URL.
+    async fn service(service: Service) -> String {
+        let address = crate::test_utils::available_addr().await;
+        tokio::spawn(
+            tonic::transport::Server::builder()
+                .add_service(AuthorizationServiceServer::new(service))
+                .serve(address),
+        );
+
+        format!("http://{address}")
+    }
+
+    fn config(service: String) -> Config {
+        serde_yaml::from_str::<Config>(&format!("service: '{service}'")).unwrap()
+    }
+
+    fn read(filter: &ExtAuthz, source: [u8; 4]) -> Option<()> {
+        let mut ctx = ReadContext::new(
+            vec![Endpoint::new((Ipv4Addr::LOCALHOST, 8080).into())],
+            (source, 9000).into(),
+            vec![],
+        );
+        filter.read(&mut ctx)
+    }
+
+    /// Waits until the check of `source` has completed.
+    async fn resolved(filter: &ExtAuthz, source: [u8; 4]) {
+        let key = format!("{}:9000", Ipv4Addr::from(source));
+        filter.cache.resolved(&key).await;
+    }
+
+    #[test]
+    fn convert_proto_config() {
+        let config = Config {
+            service: "http://authz:8080".into(),
+            metadata_key: Some("quilkin.dev/captured".into()),
+            cache_ttl: 10,
+            deny_cache_ttl: 1,
+            timeout_ms: 20,
+            failure_policy: FailurePolicy::Open,
+        };
+        assert_eq!(
+            config,
+            Config::try_from(proto::ExtAuthz::from(config.clone())).unwrap()
+        );
+
+        let defaults = Config::try_from(proto::ExtAuthz {
+            service: "http://authz:8080".into(),
+            ..<_>::default()
+        })
+        .unwrap();
+        assert_eq!(FailurePolicy::Closed, defaults.failure_policy);
+        assert_eq!(
+            (300, 30, 500),
+            (
+                defaults.cache_ttl,
+                defaults.deny_cache_ttl,
+                defaults.timeout_ms
+            )
+        );
+
+        assert!(Config::try_from(proto::ExtAuthz::default()).is_err());
+    }
+
+    #[tokio::test]
+    async fn checks_sessions_once() {
+        let checks = Arc::new(AtomicUsize::new(0));
+        let url = service(Service {
+            checks: checks.clone(),
+            revocations: broadcast::channel(1).0,
+        })
+        .await;
+        let filter = ExtAuthz::from_config(Some(config(url)));
+
+        // Dropped while the first check is in flight.
+        assert!(read(&filter, [127, 0, 0, 1]).is_none());
+        resolved(&filter, [127, 0, 0, 1]).await;
+        for _ in 0..10 {
+            assert!(read(&filter, [127, 0, 0, 1]).is_some());
+        }
+
+        read(&filter, [127, 0, 0, 2]);
+        resolved(&filter, [127, 0, 0, 2]).await;
+        assert!(read(&filter, [127, 0, 0, 2]).is_none());
+
+        assert_eq!(2, checks.load(Ordering::SeqCst));
+        assert_write_no_change(&filter);
+    }
+
+    #[tokio::test]
+    async fn revocations() {
+        let revocations = broadcast::channel(1).0;
+        let url = service(Service {
+            checks: <_>::default(),
+            revocations: revocations.clone(),
+        })
+        .await;
+        let filter = ExtAuthz::from_config(Some(config(url)));
+
+        read(&filter, [127, 0, 0, 1]);
+        resolved(&filter, [127, 0, 0, 1]).await;
+        assert!(read(&filter, [127, 0, 0, 1]).is_some());
+
+        // Sent until the filter has subscribed and applied it.
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while read(&filter, [127, 0, 0, 1]).is_some() {
+                revocations
+                    .send(Revocation {
+                        keys: vec!["127.0.0.1:9000".into()],
+                    })
+                    .ok();
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn failure_policy() {
+        // Nothing is listening for TCP connections on the address.
+        let address = crate::test_utils::available_addr().await;
+        let mut config = config(format!("http://{address}"));
+        let closed = ExtAuthz::from_config(Some(config.clone()));
+        config.failure_policy = FailurePolicy::Open;
+        let open = ExtAuthz::from_config(Some(config.clone()));
+
+        for filter in [&open, &closed] {
+            read(filter, [127, 0, 0, 1]);
+            resolved(filter, [127, 0, 0, 1]).await;
+        }
+        assert!(read(&open, [127, 0, 0, 1]).is_some());
+        assert!(read(&closed, [127, 0, 0, 1]).is_none());
+
+        config.metadata_key = Some("EXT_AUTHZ_TOKEN".into());
+        let filter = ExtAuthz::from_config(Some(config));
+        assert!(read(&filter, [127, 0, 0, 1]).is_none());
+
+        assert!(ExtAuthz::try_from_config(Some(self::config("not a url".into()))).is_err());
+    }
+}
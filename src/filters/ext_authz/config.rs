@@ -0,0 +1,131 @@
+/*
+ * Copyright 2023 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::convert::TryFrom;
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::{filters::ConvertProtoConfigError, metadata};
+
+use super::proto;
+
+/// Configuration for the [`ExtAuthz`][super::ExtAuthz] filter.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize, JsonSchema)]
+pub struct Config {
+    /// The URL of the `AuthorizationService` sessions are checked with, e.g.
+    /// `http://authz:8080`.
+    pub service: String,
+    /// The key of a token in the packet's dynamic metadata (such as one set
+    /// by the `Capture` filter) that identifies the session, rather than the
+    /// client's address. Packets without the token are dropped.
+    #[serde(
+        rename = "metadataKey",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub metadata_key: Option<metadata::Key>,
+    /// How many seconds a session is admitted for before it's checked again,
+    /// unless the service returns its own TTL.
+    #[serde(default = "default_cache_ttl")]
+    pub cache_ttl: u64,
+    /// How many seconds a denied or revoked session stays denied before it's
+    /// checked again, unless the service returns its own TTL.
+    #[serde(default = "default_deny_cache_ttl")]
+    pub deny_cache_ttl: u64,
+    /// The maximum time in milliseconds a single check may take.
+    #[serde(default = "default_timeout_ms")]
+    pub timeout_ms: u64,
+    /// Whether packets are allowed or dropped while their session's verdict
+    /// is unknown, because the service couldn't be reached or the first check
+    /// hasn't completed yet.
+    #[serde(default)]
+    pub failure_policy: FailurePolicy,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq, Serialize, JsonSchema)]
+pub enum FailurePolicy {
+    /// Packets are dropped.
+    #[default]
+    #[serde(rename = "CLOSED")]
+    Closed,
+    /// Packets are allowed.
+    #[serde(rename = "OPEN")]
+    Open,
+}
+
+fn default_cache_ttl() -> u64 {
+    300
+}
+
+fn default_deny_cache_ttl() -> u64 {
+    30
+}
+
+fn default_timeout_ms() -> u64 {
+    500
+}
+
+impl From<FailurePolicy> for proto::ext_authz::FailurePolicy {
+    fn from(policy: FailurePolicy) -> Self {
+        match policy {
+            FailurePolicy::Closed => Self::Closed,
+            FailurePolicy::Open => Self::Open,
+        }
+    }
+}
+
+impl From<proto::ext_authz::FailurePolicy> for FailurePolicy {
+    fn from(policy: proto::ext_authz::FailurePolicy) -> Self {
+        match policy {
+            proto::ext_authz::FailurePolicy::Closed => Self::Closed,
+            proto::ext_authz::FailurePolicy::Open => Self::Open,
+        }
+    }
+}
+
+impl From<Config> for proto::ExtAuthz {
+    fn from(config: Config) -> Self {
+        Self {
+            service: config.service,
+            metadata_key: config.metadata_key.map(|key| key.to_string()),
+            cache_ttl: Some(config.cache_ttl),
+            deny_cache_ttl: Some(config.deny_cache_ttl),
+            timeout_ms: Some(config.timeout_ms),
+            failure_policy: proto::ext_authz::FailurePolicy::from(config.failure_policy) as i32,
+        }
+    }
+}
+
+impl TryFrom<proto::ExtAuthz> for Config {
+    type Error = ConvertProtoConfigError;
+
+    fn try_from(p: proto::ExtAuthz) -> Result<Self, Self::Error> {
+        let failure_policy = p.failure_policy().into();
+        if p.service.is_empty() {
+            return Err(ConvertProtoConfigError::missing_field("service"));
+        }
+
+        Ok(Self {
+            service: p.service,
+            metadata_key: p.metadata_key.map(metadata::Key::new),
+            cache_ttl: p.cache_ttl.unwrap_or_else(default_cache_ttl),
+            deny_cache_ttl: p.deny_cache_ttl.unwrap_or_else(default_deny_cache_ttl),
+            timeout_ms: p.timeout_ms.unwrap_or_else(default_timeout_ms),
+            failure_policy,
+        })
+    }
+}
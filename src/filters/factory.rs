@@ -61,6 +61,12 @@ pub trait FilterFactory: Sync + Send {
     /// Returns the schema for the configuration of the [`Filter`].
     fn config_schema(&self) -> schemars::schema::RootSchema;
 
+    /// Returns the top-level fields of the filter's config holding secrets,
+    /// see [`StaticFilter::SECRET_FIELDS`].
+    fn secret_fields(&self) -> &'static [&'static str] {
+        &[]
+    }
+
     /// Returns a filter based on the provided arguments.
     fn create_filter(&self, args: CreateFilterArgs) -> Result<FilterInstance, Error>;
 
@@ -92,11 +98,16 @@ where
         schemars::schema_for!(F::Configuration)
     }
 
+    fn secret_fields(&self) -> &'static [&'static str] {
+        F::SECRET_FIELDS
+    }
+
     /// Returns a filter based on the provided arguments.
     fn create_filter(&self, args: CreateFilterArgs) -> Result<FilterInstance, Error> {
         let (config_json, config): (_, Option<F::Configuration>) = if let Some(config) = args.config
         {
             config
+                .upgrade(self.name(), F::RENAMED_FIELDS)?
                 .deserialize::<F::Configuration, F::BinaryConfiguration>(self.name())
                 .map(|(j, c)| (j, Some(c)))?
         } else {
@@ -111,8 +122,9 @@ where
 
     fn encode_config_to_protobuf(
         &self,
-        config: serde_json::Value,
+        mut config: serde_json::Value,
     ) -> Result<prost_types::Any, Error> {
+        ConfigType::upgrade_value(self.name(), F::RENAMED_FIELDS, &mut config)?;
         let config: F::Configuration = serde_json::from_value(config)?;
 
         Ok(prost_types::Any {
@@ -28,7 +28,8 @@ mod metrics;
 
 pub use config::{Action, Config, PortRange, PortRangeError, Rule};
 
-/// Filter for allowing/blocking traffic by IP and port.
+/// Filter for allowing/blocking traffic by IP and port. Packets received from
+/// the prefixes blocked by a [mitigation][crate::filters::mitigation] are denied before any rule.
 pub struct Firewall {
     metrics: Metrics,
     on_read: Vec<Rule>,
@@ -61,6 +62,12 @@ impl StaticFilter for Firewall {
 impl Filter for Firewall {
     #[cfg_attr(feature = "instrument", tracing::instrument(skip(self, ctx)))]
     fn read(&self, ctx: &mut ReadContext) -> Option<()> {
+        if ctx.mitigation_blocked() {
+            debug!(action = "Mitigation: Deny", event = "read", source = ?ctx.source);
+            self.metrics.packets_denied_read.inc();
+            return None;
+        }
+
         for rule in &self.on_read {
             if rule.contains(ctx.source.to_socket_addr().ok()?) {
                 return match rule.action {
@@ -128,7 +135,7 @@ mod tests {
     use std::net::Ipv4Addr;
 
     use crate::endpoint::Endpoint;
-    use crate::filters::firewall::config::PortRange;
+    use crate::filters::{firewall::config::PortRange, mitigation};
     use tracing_test::traced_test;
 
     use super::*;
@@ -172,6 +179,39 @@ mod tests {
         assert_eq!(0, firewall.metrics.packets_denied_write.get());
     }
 
+    #[tokio::test]
+    async fn read_mitigated() {
+        let firewall = Firewall {
+            metrics: Metrics::new().unwrap(),
+            on_read: vec![Rule {
+                action: Action::Allow,
+                source: "198.51.100.0/24".parse().unwrap(),
+                ports: vec![PortRange::new(10, 100).unwrap()],
+            }],
+            on_write: vec![],
+        };
+        let mitigations = mitigation::Mitigations::default();
+        mitigations
+            .apply(vec![mitigation::Mitigation {
+                prefix: "198.51.100.128/25".parse().unwrap(),
+                action: mitigation::Action::Block,
+                ttl_secs: 60,
+            }])
+            .unwrap();
+
+        // Blocked prefixes are denied even when a rule allows them.
+        for (ip, allowed) in [([198, 51, 100, 20], true), ([198, 51, 100, 200], false)] {
+            let mut ctx = ReadContext::new(
+                vec![Endpoint::new((Ipv4Addr::LOCALHOST, 8080).into())],
+                (ip, 80).into(),
+                vec![],
+            )
+            .mitigations(mitigations.clone());
+            assert_eq!(allowed, firewall.read(&mut ctx).is_some());
+        }
+        assert_eq!(1, firewall.metrics.packets_denied_read.get());
+    }
+
     #[test]
     fn write() {
         let firewall = Firewall {
@@ -70,6 +70,7 @@ pub struct Rule {
     /// ipv4 or ipv6 CIDR address.
     #[schemars(with = "String")]
     pub source: IpNetwork,
+    /// The port ranges matched. A rule without any matches no packets.
     pub ports: Vec<PortRange>,
 }
 
@@ -97,7 +98,14 @@ impl Rule {
     /// assert!(!rule.contains(([192, 168, 76, 10], 40).into()));
     /// ```
     pub fn contains(&self, address: SocketAddr) -> bool {
-        if !self.source.contains(address.ip()) {
+        // IPv4 addresses match IPv4 CIDRs, and IPv4-mapped IPv6 CIDRs such
+        // as `::ffff:10.0.0.0/104`, whichever representation they were
+        // received in.
+        let ip = address.ip();
+        let matches = self.source.contains(ip)
+            || crate::endpoint::other_ipv4_representation(ip)
+                .map_or(false, |ip| self.source.contains(ip));
+        if !matches {
             return false;
         }
 
@@ -322,6 +330,21 @@ on_write:
         assert_eq!(7001, rule2.ports[0].0.end);
     }
 
+    #[test]
+    fn rule_contains_ipv4_mapped() {
+        let ipv4 = std::net::Ipv4Addr::new(192, 168, 75, 10);
+        let mapped = SocketAddr::from((ipv4.to_ipv6_mapped(), 50));
+        for source in ["192.168.75.0/24", "::ffff:192.168.75.0/120"] {
+            let rule = Rule {
+                action: Action::Deny,
+                source: source.parse().unwrap(),
+                ports: vec![PortRange::new(10, 100).unwrap()],
+            };
+            assert!(rule.contains((ipv4, 50).into()), "{source}");
+            assert!(rule.contains(mapped), "{source}");
+        }
+    }
+
     #[test]
     fn portrange_contains() {
         let range = PortRange::new(10, 100).unwrap();
@@ -385,5 +408,25 @@ on_write:
         assert!(!rule.contains((ip, 5).into()));
         assert!(!rule.contains((ip, 1000).into()));
         assert!(!rule.contains(([192, 168, 76, 10], 40).into()));
+
+        // Rules without ports match no packets.
+        let rule = Rule {
+            ports: vec![],
+            ..rule
+        };
+        assert!(!rule.contains((ip, 50).into()));
+    }
+
+    #[test]
+    fn every_port() {
+        let rule: Rule =
+            serde_yaml::from_str("{action: DENY, source: 203.0.113.0/24, ports: [0-65535, 65535]}")
+                .unwrap();
+        assert!(rule.contains(([203, 0, 113, 7], 0).into()));
+        assert!(rule.contains(([203, 0, 113, 7], 7777).into()));
+        assert!(rule.contains(([203, 0, 113, 7], u16::MAX).into()));
+
+        // `ports` is required, so rules can't be left out of effect by accident.
+        assert!(serde_yaml::from_str::<Rule>("{action: DENY, source: 203.0.113.0/24}").is_err());
     }
 }
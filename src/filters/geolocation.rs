@@ -0,0 +1,219 @@
+/*
+ * Copyright 2023 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+mod config;
+mod metrics;
+
+crate::include_proto!("quilkin.filters.geolocation.v1alpha1");
+
+use crate::{filters::prelude::*, maxmind_db::Location, metadata};
+
+use self::{metrics::Metrics, quilkin::filters::geolocation::v1alpha1 as proto};
+
+pub use self::config::Config;
+
+/// Filter that allows or denies packets from clients by the country and
+/// autonomous system of their address, looked up in the Maxmind database
+/// downloaded with `--mmdb`, and can tag packets with them in their dynamic
+/// metadata for later filters. Only packets received from downstream are
+/// checked.
+pub struct Geolocation {
+    config: Config,
+    metrics: Metrics,
+}
+
+impl Geolocation {
+    fn new(mut config: Config, metrics: Metrics) -> Result<Self, Error> {
+        for (field, countries) in [
+            ("allow_countries", &mut config.allow_countries),
+            ("deny_countries", &mut config.deny_countries),
+        ] {
+            for country in countries.iter_mut() {
+                if country.len() != 2 || !country.bytes().all(|byte| byte.is_ascii_alphabetic()) {
+                    return Err(Error::FieldInvalid {
+                        field: field.into(),
+                        reason: format!("`{country}` isn't an ISO 3166-1 alpha-2 country code"),
+                    });
+                }
+                country.make_ascii_uppercase();
+            }
+        }
+
+        Ok(Self { config, metrics })
+    }
+
+    /// Whether packets from `location` are allowed.
+    fn allows(&self, location: &Location) -> bool {
+        let config = &self.config;
+        allows(
+            &config.allow_countries,
+            &config.deny_countries,
+            location.country.as_ref(),
+            config.allow_unknown,
+        ) && allows(
+            &config.allow_asns,
+            &config.deny_asns,
+            location.asn.as_ref(),
+            config.allow_unknown,
+        )
+    }
+}
+
+/// Whether `value` is allowed by the `allow` and `deny` lists, or
+/// `allow_unknown` if it's unknown and needs checking.
+fn allows<T: PartialEq>(allow: &[T], deny: &[T], value: Option<&T>, allow_unknown: bool) -> bool {
+    if allow.is_empty() && deny.is_empty() {
+        return true;
+    }
+
+    match value {
+        Some(value) => (allow.is_empty() || allow.contains(value)) && !deny.contains(value),
+        None => allow_unknown,
+    }
+}
+
+impl Filter for Geolocation {
+    #[cfg_attr(feature = "instrument", tracing::instrument(skip(self, ctx)))]
+    fn read(&self, ctx: &mut ReadContext) -> Option<()> {
+        let location = ctx
+            .source
+            .to_socket_addr()
+            .ok()
+            .and_then(|address| crate::MaxmindDb::lookup_location(address.ip()))
+            .unwrap_or_default();
+
+        if let (Some(key), Some(country)) = (self.config.country_key, &location.country) {
+            ctx.metadata
+                .insert(key, metadata::Value::String(country.clone()));
+        }
+        if let (Some(key), Some(asn)) = (self.config.asn_key, location.asn) {
+            ctx.metadata.insert(key, metadata::Value::Number(asn));
+        }
+
+        if self.allows(&location) {
+            return Some(());
+        }
+
+        tracing::trace!(source = %ctx.source, ?location, "Denying packet by its location");
+        self.metrics
+            .denied(location.country.as_deref().unwrap_or_default());
+        None
+    }
+
+    fn has_write(&self) -> bool {
+        false
+    }
+}
+
+impl StaticFilter for Geolocation {
+    const NAME: &'static str = "quilkin.filters.geolocation.v1alpha1.Geolocation";
+    type Configuration = Config;
+    type BinaryConfiguration = proto::Geolocation;
+
+    fn try_from_config(config: Option<Self::Configuration>) -> Result<Self, Error> {
+        Self::new(Self::ensure_config_exists(config)?, Metrics::new()?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::assert_write_no_change;
+
+    fn geolocation(config: Config) -> Geolocation {
+        Geolocation::new(config, Metrics::new().unwrap()).unwrap()
+    }
+
+    fn location(country: Option<&str>, asn: Option<u64>) -> Location {
+        Location {
+            country: country.map(String::from),
+            asn,
+        }
+    }
+
+    #[test]
+    fn invalid_config() {
+        for country in ["SWE", "1A", ""] {
+            let config = Config {
+                deny_countries: vec![country.into()],
+                ..<_>::default()
+            };
+            assert!(Geolocation::new(config, Metrics::new().unwrap()).is_err());
+        }
+    }
+
+    #[test]
+    fn allows() {
+        let filter = geolocation(Config {
+            allow_countries: vec!["se".into(), "NO".into()],
+            deny_asns: vec![64496],
+            ..<_>::default()
+        });
+        assert_write_no_change(&filter);
+
+        assert!(filter.allows(&location(Some("SE"), Some(1))));
+        assert!(filter.allows(&location(Some("NO"), None)));
+        assert!(!filter.allows(&location(Some("DK"), Some(1))));
+        assert!(!filter.allows(&location(Some("SE"), Some(64496))));
+        assert!(filter.allows(&location(None, None)));
+
+        let filter = geolocation(Config {
+            deny_countries: vec!["DK".into()],
+            allow_unknown: false,
+            ..<_>::default()
+        });
+        assert!(filter.allows(&location(Some("SE"), None)));
+        assert!(!filter.allows(&location(Some("DK"), None)));
+        assert!(!filter.allows(&location(None, Some(1))));
+    }
+
+    #[test]
+    fn denies_unknown() {
+        // Without a database, every location is unknown.
+        let filter = geolocation(Config {
+            allow_countries: vec!["SE".into()],
+            allow_unknown: false,
+            ..<_>::default()
+        });
+        let mut ctx = ReadContext::new(
+            vec![],
+            (std::net::Ipv4Addr::LOCALHOST, 7000).into(),
+            vec![9],
+        );
+
+        assert!(filter.read(&mut ctx).is_none());
+        assert_eq!(1, filter.metrics.denied_total(""));
+    }
+
+    #[test]
+    fn convert_proto_config() {
+        let config = Config {
+            allow_countries: vec!["SE".into()],
+            deny_asns: vec![64496],
+            allow_unknown: false,
+            country_key: Some("country".into()),
+            ..<_>::default()
+        };
+        assert_eq!(
+            config,
+            Config::from(proto::Geolocation::from(config.clone()))
+        );
+        assert_eq!(
+            Config::default(),
+            Config::from(proto::Geolocation::default())
+        );
+    }
+}
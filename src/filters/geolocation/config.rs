@@ -0,0 +1,100 @@
+/*
+ * Copyright 2023 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::metadata;
+
+use super::proto;
+
+/// Configuration for the [`Geolocation`][super::Geolocation] filter.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize, JsonSchema)]
+pub struct Config {
+    /// The ISO 3166-1 alpha-2 codes of the only countries packets are allowed
+    /// from, such as `SE`. Packets are allowed from every country when empty.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub allow_countries: Vec<String>,
+    /// The ISO 3166-1 alpha-2 codes of the countries packets are denied from.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub deny_countries: Vec<String>,
+    /// The numbers of the only autonomous systems packets are allowed from.
+    /// Packets are allowed from every autonomous system when empty.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub allow_asns: Vec<u64>,
+    /// The numbers of the autonomous systems packets are denied from.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub deny_asns: Vec<u64>,
+    /// Whether packets are allowed from addresses whose country or autonomous
+    /// system is unknown, when it's needed to check them, such as addresses
+    /// that aren't in the database or before it's downloaded.
+    #[serde(default = "default_allow_unknown")]
+    pub allow_unknown: bool,
+    /// The key of the dynamic metadata to set to the country code of the
+    /// packet's source, if any.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub country_key: Option<metadata::Key>,
+    /// The key of the dynamic metadata to set to the autonomous system number
+    /// of the packet's source, if any.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub asn_key: Option<metadata::Key>,
+}
+
+fn default_allow_unknown() -> bool {
+    true
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            allow_countries: Vec::new(),
+            deny_countries: Vec::new(),
+            allow_asns: Vec::new(),
+            deny_asns: Vec::new(),
+            allow_unknown: default_allow_unknown(),
+            country_key: None,
+            asn_key: None,
+        }
+    }
+}
+
+impl From<Config> for proto::Geolocation {
+    fn from(config: Config) -> Self {
+        Self {
+            allow_countries: config.allow_countries,
+            deny_countries: config.deny_countries,
+            allow_asns: config.allow_asns,
+            deny_asns: config.deny_asns,
+            allow_unknown: Some(config.allow_unknown),
+            country_key: config.country_key.map(|key| key.to_string()),
+            asn_key: config.asn_key.map(|key| key.to_string()),
+        }
+    }
+}
+
+impl From<proto::Geolocation> for Config {
+    fn from(p: proto::Geolocation) -> Self {
+        Self {
+            allow_countries: p.allow_countries,
+            deny_countries: p.deny_countries,
+            allow_asns: p.allow_asns,
+            deny_asns: p.deny_asns,
+            allow_unknown: p.allow_unknown.unwrap_or_else(default_allow_unknown),
+            country_key: p.country_key.map(metadata::Key::new),
+            asn_key: p.asn_key.map(metadata::Key::new),
+        }
+    }
+}
@@ -0,0 +1,56 @@
+/*
+ * Copyright 2023 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use prometheus::{IntCounterVec, Result as MetricsResult};
+
+use crate::metrics::{filter_opts, CollectorExt};
+
+const COUNTRY_LABEL: &str = "country";
+
+/// Register and manage metrics for this filter
+pub(super) struct Metrics {
+    packets_denied_total: IntCounterVec,
+}
+
+impl Metrics {
+    pub(super) fn new() -> MetricsResult<Self> {
+        Ok(Self {
+            packets_denied_total: IntCounterVec::new(
+                filter_opts(
+                    "packets_denied_total",
+                    "Geolocation",
+                    "Total number of packets denied. Labels: country.",
+                ),
+                &[COUNTRY_LABEL],
+            )?
+            .register_if_not_exists()?,
+        })
+    }
+
+    /// Counts a packet denied from `country`, which is empty if it's unknown.
+    pub(super) fn denied(&self, country: &str) {
+        self.packets_denied_total
+            .with_label_values(&[country])
+            .inc();
+    }
+
+    #[cfg(test)]
+    pub(super) fn denied_total(&self, country: &str) -> u64 {
+        self.packets_denied_total
+            .with_label_values(&[country])
+            .get()
+    }
+}
@@ -0,0 +1,465 @@
+/*
+ * Copyright 2023 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+mod config;
+mod metrics;
+
+crate::include_proto!("quilkin.filters.hmac.v1alpha1");
+
+use std::time::Duration;
+
+use once_cell::sync::Lazy;
+use openssl::{
+    hash::MessageDigest,
+    pkey::{PKey, Private},
+    sign::Signer,
+};
+use parking_lot::Mutex;
+use rand::Rng;
+
+use crate::{
+    filters::prelude::*,
+    ttl_map::{Entry, TtlMap},
+};
+
+use self::{metrics::Metrics, quilkin::filters::hmac::v1alpha1 as proto};
+use super::replay::{Counter, ReplayWindow};
+
+pub use self::config::{Config, Key, MAX_SIGNATURE_LEN, MIN_SIGNATURE_LEN};
+pub use super::replay::REPLAY_WINDOW;
+
+/// The length of the sender ID following the contents of signed packets.
+pub const SENDER_LEN: usize = 8;
+/// The length of the counter following the sender ID.
+pub const COUNTER_LEN: usize = 8;
+
+/// How long the replay window of a sender is kept after its last packet.
+const SENDER_TTL: Duration = Duration::from_secs(60 * 60);
+const SENDER_EXPIRY_POLL_INTERVAL: Duration = Duration::from_secs(60);
+
+/// The replay window of each sender, by sender ID.
+type Senders = TtlMap<u64, Mutex<ReplayWindow>>;
+
+/// Returns the replay windows shared by every instance of the filter, so
+/// that they outlive the filter chains rebuilt on configuration changes,
+/// which would otherwise accept every packet received before the rebuild
+/// once more.
+fn senders() -> Senders {
+    static SENDERS: Lazy<Senders> =
+        Lazy::new(|| TtlMap::new(SENDER_TTL, SENDER_EXPIRY_POLL_INTERVAL));
+    SENDERS.clone()
+}
+
+/// Filter that authenticates packets from clients with an HMAC-SHA256
+/// signature appended to them by the client, with a secret shared with the
+/// proxy. The signature covers the contents followed by the client's sender
+/// ID and counter, and each counter of a sender is only accepted once, so
+/// that captured packets can't be replayed. Packets that aren't signed with
+/// any of the keys, or that were already received, are dropped, and the
+/// rest are forwarded without the sender ID, counter and signature, so that
+/// game servers only receive packets from clients that know the secret
+/// without the cost of a DTLS session.
+///
+/// Packets sent to clients are signed with the first key when
+/// `sign_on_write` is set, so that clients can authenticate them too.
+pub struct Hmac {
+    keys: Vec<PKey<Private>>,
+    signature_len: usize,
+    sign_on_write: bool,
+    /// The ID this filter signs packets as.
+    sender: u64,
+    /// The counter of the last packet this filter signed.
+    counter: Counter,
+    /// The replay window of each sender packets were received from, shared
+    /// with the other instances of the filter.
+    senders: Senders,
+    metrics: Metrics,
+}
+
+/// Why a packet was dropped.
+#[derive(Debug, PartialEq)]
+enum Rejection {
+    /// The packet isn't signed with any of the keys.
+    Invalid,
+    /// The packet's counter was already received from its sender.
+    Replayed,
+}
+
+impl Hmac {
+    fn new(config: Config, metrics: Metrics, senders: Senders) -> Result<Self, Error> {
+        if config.keys.is_empty() {
+            return Err(Error::FieldInvalid {
+                field: "keys".into(),
+                reason: "at least one key is required".into(),
+            });
+        }
+        if !(MIN_SIGNATURE_LEN..=MAX_SIGNATURE_LEN).contains(&config.signature_len) {
+            return Err(Error::FieldInvalid {
+                field: "signature_len".into(),
+                reason: format!(
+                    "value must be between {MIN_SIGNATURE_LEN} and {MAX_SIGNATURE_LEN}"
+                ),
+            });
+        }
+
+        let keys = config
+            .keys
+            .iter()
+            .map(|key| {
+                if key.0.is_empty() {
+                    return Err(Error::FieldInvalid {
+                        field: "keys".into(),
+                        reason: "keys can't be empty".into(),
+                    });
+                }
+                PKey::hmac(&key.0).map_err(|error| Error::FieldInvalid {
+                    field: "keys".into(),
+                    reason: error.to_string(),
+                })
+            })
+            .collect::<Result<_, _>>()?;
+
+        Ok(Self {
+            keys,
+            signature_len: config.signature_len as usize,
+            sign_on_write: config.sign_on_write,
+            sender: rand::thread_rng().gen(),
+            counter: Counter::default(),
+            senders,
+            metrics,
+        })
+    }
+
+    /// Returns the signature of `contents` with `key`, truncated to
+    /// `signature_len`.
+    fn sign(&self, key: &PKey<Private>, contents: &[u8]) -> Option<Vec<u8>> {
+        let signature = Signer::new(MessageDigest::sha256(), key).and_then(|mut signer| {
+            signer.update(contents)?;
+            signer.sign_to_vec()
+        });
+
+        match signature {
+            Ok(mut signature) => {
+                signature.truncate(self.signature_len);
+                Some(signature)
+            }
+            Err(error) => {
+                tracing::error!(%error, "failed to sign packet");
+                None
+            }
+        }
+    }
+
+    /// Returns the index of the key `signed` was signed with, if any.
+    fn verify(&self, signed: &[u8], signature: &[u8]) -> Option<usize> {
+        self.keys.iter().position(|key| {
+            self.sign(key, signed)
+                .map_or(false, |expected| openssl::memcmp::eq(&expected, signature))
+        })
+    }
+
+    /// Returns the index of the key `signed` was signed with, unless it
+    /// wasn't signed with any of them or its counter was already received
+    /// from its sender. `signed` ends with the sender ID and counter.
+    fn authenticate(&self, signed: &[u8], signature: &[u8]) -> Result<usize, Rejection> {
+        let (sender, counter) =
+            signed[signed.len() - SENDER_LEN - COUNTER_LEN..].split_at(SENDER_LEN);
+        let sender = u64::from_be_bytes(sender.try_into().unwrap());
+        let counter = u64::from_be_bytes(counter.try_into().unwrap());
+
+        // Windows are only updated once the packet is authenticated, so that
+        // forged packets can't advance them.
+        if let Some(window) = self.senders.get(&sender) {
+            let mut window = window.lock();
+            if !window.is_fresh(counter) {
+                return Err(Rejection::Replayed);
+            }
+            let key = self.verify(signed, signature).ok_or(Rejection::Invalid)?;
+            window.receive(counter);
+            return Ok(key);
+        }
+
+        let key = self.verify(signed, signature).ok_or(Rejection::Invalid)?;
+        match self.senders.entry(sender) {
+            Entry::Occupied(entry) => {
+                let mut window = entry.get().lock();
+                if !window.is_fresh(counter) {
+                    return Err(Rejection::Replayed);
+                }
+                window.receive(counter);
+            }
+            Entry::Vacant(entry) => {
+                let mut window = ReplayWindow::default();
+                window.receive(counter);
+                entry.insert(Mutex::new(window));
+            }
+        }
+        Ok(key)
+    }
+}
+
+impl Filter for Hmac {
+    #[cfg_attr(feature = "instrument", tracing::instrument(skip(self, ctx)))]
+    fn read(&self, ctx: &mut ReadContext) -> Option<()> {
+        let verified = ctx
+            .contents
+            .len()
+            .checked_sub(SENDER_LEN + COUNTER_LEN + self.signature_len)
+            .ok_or(Rejection::Invalid)
+            .and_then(|len| {
+                let (signed, signature) = ctx.contents.split_at(len + SENDER_LEN + COUNTER_LEN);
+                self.authenticate(signed, signature).map(|key| (len, key))
+            });
+
+        let (len, key) = match verified {
+            Ok(verified) => verified,
+            Err(Rejection::Invalid) => {
+                tracing::trace!(source = %ctx.source, "Dropping packet without a valid signature");
+                self.metrics.packets_dropped_total.inc();
+                return None;
+            }
+            Err(Rejection::Replayed) => {
+                tracing::trace!(source = %ctx.source, "Dropping replayed packet");
+                self.metrics.packets_replayed_total.inc();
+                return None;
+            }
+        };
+
+        if key != 0 {
+            self.metrics.packets_verified_with_old_key_total.inc();
+        }
+        ctx.contents.truncate(len);
+        Some(())
+    }
+
+    #[cfg_attr(feature = "instrument", tracing::instrument(skip(self, ctx)))]
+    fn write(&self, ctx: &mut WriteContext) -> Option<()> {
+        if self.sign_on_write {
+            ctx.contents.extend_from_slice(&self.sender.to_be_bytes());
+            ctx.contents
+                .extend_from_slice(&self.counter.next().to_be_bytes());
+            let signature = self.sign(&self.keys[0], &ctx.contents)?;
+            ctx.contents.extend_from_slice(&signature);
+        }
+        Some(())
+    }
+
+    fn has_write(&self) -> bool {
+        self.sign_on_write
+    }
+}
+
+impl StaticFilter for Hmac {
+    const NAME: &'static str = "quilkin.filters.hmac.v1alpha1.Hmac";
+    const SECRET_FIELDS: &'static [&'static str] = &["keys"];
+    type Configuration = Config;
+    type BinaryConfiguration = proto::Hmac;
+
+    fn try_from_config(config: Option<Self::Configuration>) -> Result<Self, Error> {
+        Self::new(
+            Self::ensure_config_exists(config)?,
+            Metrics::new()?,
+            senders(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+    use crate::{endpoint::Endpoint, test_utils::assert_write_no_change};
+
+    const SENDER: u64 = 7;
+
+    fn hmac(config: Config) -> Hmac {
+        hmac_with(config, new_senders())
+    }
+
+    /// Returns a filter keeping its replay windows in `senders`.
+    fn hmac_with(config: Config, senders: Senders) -> Hmac {
+        Hmac::new(config, Metrics::new().unwrap(), senders).unwrap()
+    }
+
+    fn new_senders() -> Senders {
+        TtlMap::new(SENDER_TTL, SENDER_EXPIRY_POLL_INTERVAL)
+    }
+
+    /// Returns `contents` followed by the sender ID, `counter` and their
+    /// signature with `key`.
+    fn signed(key: &[u8], contents: &[u8], counter: u64) -> Vec<u8> {
+        let signed = [contents, &SENDER.to_be_bytes(), &counter.to_be_bytes()].concat();
+        let key = PKey::hmac(key).unwrap();
+        let mut signer = Signer::new(MessageDigest::sha256(), &key).unwrap();
+        signer.update(&signed).unwrap();
+        [&signed[..], &signer.sign_to_vec().unwrap()[..]].concat()
+    }
+
+    fn read(filter: &Hmac, contents: Vec<u8>) -> Option<Vec<u8>> {
+        let mut ctx = ReadContext::new(
+            vec![Endpoint::new((Ipv4Addr::LOCALHOST, 8089).into())],
+            (Ipv4Addr::LOCALHOST, 7000).into(),
+            contents,
+        );
+        filter.read(&mut ctx).map(|()| ctx.contents)
+    }
+
+    #[tokio::test]
+    async fn invalid_config() {
+        for config in [
+            Config::new(Vec::<Vec<u8>>::new()),
+            Config::new([Vec::<u8>::new()]),
+            Config {
+                signature_len: MIN_SIGNATURE_LEN - 1,
+                ..Config::new([b"key"])
+            },
+            Config {
+                signature_len: MAX_SIGNATURE_LEN + 1,
+                ..Config::new([b"key"])
+            },
+        ] {
+            assert!(Hmac::new(config, Metrics::new().unwrap(), new_senders()).is_err());
+        }
+    }
+
+    #[tokio::test]
+    async fn verifies() {
+        let filter = hmac(Config::new([b"key"]));
+        assert_write_no_change(&filter);
+
+        assert_eq!(
+            Some(b"hello".to_vec()),
+            read(&filter, signed(b"key", b"hello", 1))
+        );
+        assert_eq!(None, read(&filter, signed(b"other", b"hello", 2)));
+        let mut tampered = signed(b"key", b"hello", 3);
+        tampered[0] = b'j';
+        assert_eq!(None, read(&filter, tampered));
+        let mut tampered = signed(b"key", b"hello", 4);
+        tampered[b"hello".len() + SENDER_LEN] ^= 1;
+        assert_eq!(None, read(&filter, tampered));
+        assert_eq!(None, read(&filter, b"short".to_vec()));
+        assert_eq!(4, filter.metrics.packets_dropped_total.get());
+    }
+
+    #[tokio::test]
+    async fn rejects_replays() {
+        let filter = hmac(Config::new([b"key"]));
+
+        let first = signed(b"key", b"first", 1);
+        let second = signed(b"key", b"second", 2);
+        // Packets reordered on the way are still accepted, once.
+        assert_eq!(Some(b"second".to_vec()), read(&filter, second.clone()));
+        assert_eq!(Some(b"first".to_vec()), read(&filter, first.clone()));
+        assert_eq!(None, read(&filter, first));
+        assert_eq!(None, read(&filter, second));
+        assert_eq!(2, filter.metrics.packets_replayed_total.get());
+
+        // Forged packets don't advance the window.
+        let mut forged = signed(b"key", b"hello", 3 + REPLAY_WINDOW);
+        forged[0] ^= 1;
+        assert_eq!(None, read(&filter, forged));
+        assert_eq!(
+            Some(b"hello".to_vec()),
+            read(&filter, signed(b"key", b"hello", 3))
+        );
+    }
+
+    #[tokio::test]
+    async fn rejects_replays_after_rebuild() {
+        let senders = new_senders();
+        let filter = hmac_with(Config::new([b"key"]), senders.clone());
+        let packet = signed(b"key", b"hello", 1);
+        assert_eq!(Some(b"hello".to_vec()), read(&filter, packet.clone()));
+
+        // Filters rebuilt from a new configuration keep the windows.
+        let rebuilt = hmac_with(Config::new([b"new", b"key"]), senders);
+        assert_eq!(None, read(&rebuilt, packet));
+        assert_eq!(1, rebuilt.metrics.packets_replayed_total.get());
+    }
+
+    #[tokio::test]
+    async fn rotates_keys() {
+        let filter = hmac(Config::new([b"new", b"old"]));
+
+        assert!(read(&filter, signed(b"new", b"hello", 1)).is_some());
+        assert_eq!(0, filter.metrics.packets_verified_with_old_key_total.get());
+        assert!(read(&filter, signed(b"old", b"hello", 2)).is_some());
+        assert_eq!(1, filter.metrics.packets_verified_with_old_key_total.get());
+    }
+
+    #[tokio::test]
+    async fn truncated_signatures() {
+        let filter = hmac(Config {
+            signature_len: MIN_SIGNATURE_LEN,
+            ..Config::new([b"key"])
+        });
+
+        let len = b"hello".len() + SENDER_LEN + COUNTER_LEN;
+        let mut packet = signed(b"key", b"hello", 1);
+        packet.truncate(len + MIN_SIGNATURE_LEN as usize);
+        assert_eq!(Some(b"hello".to_vec()), read(&filter, packet));
+        assert_eq!(None, read(&filter, signed(b"key", b"hello", 2)));
+    }
+
+    #[tokio::test]
+    async fn signs_on_write() {
+        let filter = hmac(Config {
+            sign_on_write: true,
+            ..Config::new([b"new", b"old"])
+        });
+        let client = hmac(Config::new([b"new"]));
+        assert!(filter.has_write());
+
+        let write = || {
+            let mut ctx = WriteContext::new(
+                Endpoint::new((Ipv4Addr::LOCALHOST, 8089).into()),
+                (Ipv4Addr::LOCALHOST, 8089).into(),
+                (Ipv4Addr::LOCALHOST, 7000).into(),
+                b"hello".to_vec(),
+            );
+            filter.write(&mut ctx).unwrap();
+            ctx.contents
+        };
+        let packet = write();
+        assert_eq!(
+            b"hello".len() + SENDER_LEN + COUNTER_LEN + MAX_SIGNATURE_LEN as usize,
+            packet.len()
+        );
+        assert_eq!(Some(b"hello".to_vec()), read(&client, packet.clone()));
+        assert_eq!(None, read(&client, packet));
+        // Each packet has its own counter.
+        assert_eq!(Some(b"hello".to_vec()), read(&client, write()));
+    }
+
+    #[test]
+    fn convert_proto_config() {
+        let config = Config {
+            signature_len: 20,
+            sign_on_write: true,
+            ..Config::new([b"new", b"old"])
+        };
+        assert_eq!(config, Config::from(proto::Hmac::from(config.clone())));
+
+        let defaults = Config::from(proto::Hmac {
+            keys: vec![b"key".to_vec()],
+            signature_len: None,
+            sign_on_write: false,
+        });
+        assert_eq!(Config::new([b"key"]), defaults);
+    }
+}
@@ -0,0 +1,98 @@
+/*
+ * Copyright 2023 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::config::Base64Standard;
+
+use super::proto;
+
+/// The length of HMAC-SHA256 signatures, which is the default length of the
+/// signatures appended to packets.
+pub const MAX_SIGNATURE_LEN: u32 = 32;
+/// The shortest signatures can be truncated to.
+pub const MIN_SIGNATURE_LEN: u32 = 16;
+
+/// Configuration for the [`Hmac`][super::Hmac] filter.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize, JsonSchema)]
+pub struct Config {
+    /// The base64 encoded secrets that packets can be signed with. Packets
+    /// are signed with the first key, and packets signed with any of them
+    /// are accepted, so that keys can be rotated by adding the new key in
+    /// front and removing the old one once clients have stopped using it.
+    pub keys: Vec<Key>,
+    /// The length in bytes of the signatures appended to packets, which are
+    /// truncated HMAC-SHA256 signatures when shorter than 32 bytes.
+    #[serde(default = "default_signature_len")]
+    pub signature_len: u32,
+    /// Whether packets sent to clients are signed with the first key too.
+    #[serde(default)]
+    pub sign_on_write: bool,
+}
+
+/// A secret packets are signed with.
+#[derive(Clone, Deserialize, Eq, PartialEq, Serialize, JsonSchema)]
+#[serde(transparent)]
+pub struct Key(
+    #[serde(with = "Base64Standard")]
+    #[schemars(with = "String")]
+    pub Vec<u8>,
+);
+
+impl std::fmt::Debug for Key {
+    // Secrets are left out of logs.
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str("Key(..)")
+    }
+}
+
+fn default_signature_len() -> u32 {
+    MAX_SIGNATURE_LEN
+}
+
+impl Config {
+    pub fn new(keys: impl IntoIterator<Item = impl AsRef<[u8]>>) -> Self {
+        Self {
+            keys: keys
+                .into_iter()
+                .map(|key| Key(key.as_ref().to_vec()))
+                .collect(),
+            signature_len: MAX_SIGNATURE_LEN,
+            sign_on_write: false,
+        }
+    }
+}
+
+impl From<Config> for proto::Hmac {
+    fn from(config: Config) -> Self {
+        Self {
+            keys: config.keys.into_iter().map(|key| key.0).collect(),
+            signature_len: Some(config.signature_len),
+            sign_on_write: config.sign_on_write,
+        }
+    }
+}
+
+impl From<proto::Hmac> for Config {
+    fn from(p: proto::Hmac) -> Self {
+        Self {
+            keys: p.keys.into_iter().map(Key).collect(),
+            signature_len: p.signature_len.unwrap_or(MAX_SIGNATURE_LEN),
+            sign_on_write: p.sign_on_write,
+        }
+    }
+}
@@ -33,7 +33,7 @@ pub struct LoadBalancer {
 impl LoadBalancer {
     fn new(config: Config) -> Self {
         Self {
-            endpoint_chooser: config.policy.as_endpoint_chooser(),
+            endpoint_chooser: config.as_endpoint_chooser(),
         }
     }
 }
@@ -43,6 +43,10 @@ impl Filter for LoadBalancer {
         self.endpoint_chooser.choose_endpoints(ctx);
         Some(())
     }
+
+    fn has_write(&self) -> bool {
+        false
+    }
 }
 
 impl StaticFilter for LoadBalancer {
@@ -246,4 +250,191 @@ policy: RANDOM
             "the same sequence of addresses were chosen for hash load balancer"
         );
     }
+
+    #[test]
+    fn weighted_round_robin_load_balancer_policy() {
+        let endpoints = vec![
+            Endpoint::new(([127, 0, 0, 1], 8080).into()).with_weight(3),
+            Endpoint::new(([127, 0, 0, 2], 8080).into()).with_weight(0),
+            Endpoint::new(([127, 0, 0, 3], 8080).into()),
+        ];
+        let choose = |filter: &LoadBalancer, endpoints: &[Endpoint]| {
+            let mut context = ReadContext::new(
+                endpoints.to_vec(),
+                "127.0.0.1:8080".parse().unwrap(),
+                vec![],
+            );
+            filter.read(&mut context).unwrap();
+            context.endpoints[0].address.clone()
+        };
+
+        let filter = LoadBalancer::from_config(
+            serde_yaml::from_str("policy: WEIGHTED_ROUND_ROBIN").unwrap(),
+        );
+        let expected_sequence = [0, 0, 0, 2].map(|index| endpoints[index].address.clone());
+        for _ in 0..10 {
+            assert_eq!(
+                expected_sequence,
+                [(); 4].map(|_| choose(&filter, &endpoints))
+            );
+        }
+
+        // Drained endpoints are chosen in turns once every endpoint is.
+        let drained = [endpoints[0].clone().with_weight(0), endpoints[1].clone()];
+        assert_eq!(
+            vec![drained[0].address.clone(), drained[1].address.clone()],
+            (0..2)
+                .map(|_| choose(&filter, &drained))
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn weighted_random_load_balancer_policy() {
+        let drained: EndpointAddress = ([127, 0, 0, 2], 8080).into();
+        let endpoints = vec![
+            Endpoint::new(([127, 0, 0, 1], 8080).into()).with_weight(10),
+            Endpoint::new(drained.clone()).with_weight(0),
+            Endpoint::new(([127, 0, 0, 3], 8080).into()),
+        ];
+
+        let filter =
+            LoadBalancer::from_config(serde_yaml::from_str("policy: WEIGHTED_RANDOM").unwrap());
+        let mut chosen = Vec::new();
+        for _ in 0..1000 {
+            let mut context =
+                ReadContext::new(endpoints.clone(), "127.0.0.1:8080".parse().unwrap(), vec![]);
+            filter.read(&mut context).unwrap();
+            chosen.push(context.endpoints[0].address.clone());
+        }
+
+        assert!(!chosen.contains(&drained));
+        let heaviest = chosen
+            .iter()
+            .filter(|address| **address == endpoints[0].address)
+            .count();
+        assert!(heaviest > 800, "{heaviest} of 1000 packets");
+    }
+
+    #[test]
+    fn consistent_hash_load_balancer_policy() {
+        let addresses: Vec<EndpointAddress> = (1..=4)
+            .map(|last| ([127, 0, 0, last], 8080).into())
+            .collect();
+        let filter =
+            LoadBalancer::from_config(serde_yaml::from_str("policy: CONSISTENT_HASH").unwrap());
+        let choose = |addresses: &[EndpointAddress]| {
+            (0..200)
+                .map(|port| {
+                    get_response_addresses(&filter, addresses, ([127, 1, 1, 1], port).into())
+                        .remove(0)
+                })
+                .collect::<Vec<_>>()
+        };
+
+        let chosen = choose(&addresses);
+        for address in &addresses {
+            assert!(chosen.contains(address), "{address} was never chosen");
+        }
+        assert_eq!(chosen, choose(&addresses));
+
+        // Only the clients of a removed endpoint move.
+        let removed = &addresses[1];
+        let remaining = choose(&[
+            addresses[0].clone(),
+            addresses[2].clone(),
+            addresses[3].clone(),
+        ]);
+        for (before, after) in chosen.iter().zip(&remaining) {
+            assert_ne!(removed, after);
+            if before != removed {
+                assert_eq!(before, after);
+            }
+        }
+
+        // And clients only move to an added endpoint.
+        let added: EndpointAddress = ([127, 0, 0, 5], 8080).into();
+        let mut more = addresses.clone();
+        more.push(added.clone());
+        for (before, after) in chosen.iter().zip(&choose(&more)) {
+            assert!(before == after || *after == added);
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn slow_start() {
+        use crate::cluster::ClusterMap;
+
+        let existing: EndpointAddress = ([127, 0, 10, 1], 8080).into();
+        let added: EndpointAddress = ([127, 0, 10, 2], 8080).into();
+        let other: EndpointAddress = ([127, 0, 10, 3], 8080).into();
+        let addresses = [existing.clone(), added.clone(), other.clone()];
+        let config = crate::Config::default();
+        config
+            .clusters
+            .store(std::sync::Arc::new(ClusterMap::new_with_default_cluster(
+                vec![
+                    Endpoint::new(existing.clone()),
+                    Endpoint::new(other.clone()),
+                ],
+            )));
+        config
+            .clusters
+            .store(std::sync::Arc::new(ClusterMap::new_with_default_cluster(
+                Vec::from_iter(addresses.iter().cloned().map(Endpoint::new)),
+            )));
+
+        let choose = |filter: &dyn Filter, source: EndpointAddress| {
+            let mut context = ReadContext::new(
+                Vec::from_iter(addresses.iter().cloned().map(Endpoint::new)),
+                source,
+                vec![],
+            )
+            .added(config.clusters.added());
+            filter.read(&mut context).unwrap();
+            context.endpoints[0].address.clone()
+        };
+
+        let filter = |policy: &str| -> LoadBalancer {
+            LoadBalancer::from_config(
+                serde_yaml::from_str(&format!("{{ policy: {policy}, slow_start_ms: 10000 }}"))
+                    .unwrap(),
+            )
+        };
+        for policy in ["ROUND_ROBIN", "RANDOM", "HASH"] {
+            let filter = filter(policy);
+            for port in 0..20 {
+                assert_ne!(added, choose(&filter, ([127, 1, 1, 1], port).into()));
+            }
+        }
+
+        // Halfway through, the added endpoint has half the share of the
+        // others, which each keep an equal share of the rest.
+        tokio::time::advance(std::time::Duration::from_secs(5)).await;
+        for policy in ["ROUND_ROBIN", "RANDOM", "HASH"] {
+            let filter = filter(policy);
+            let mut counts = std::collections::HashMap::<_, usize>::new();
+            for port in 0..1000 {
+                let source = ([127, 1, (port / 256) as u8, (port % 256) as u8], 8080).into();
+                *counts.entry(choose(&filter, source)).or_default() += 1;
+            }
+            for (address, expected) in [(&existing, 400), (&added, 200), (&other, 400)] {
+                let count = counts.get(address).copied().unwrap_or_default();
+                assert!(
+                    (expected - 100..expected + 100).contains(&count),
+                    "{policy}: {address} chosen {count} times"
+                );
+            }
+        }
+
+        tokio::time::advance(std::time::Duration::from_secs(5)).await;
+        let filter = filter("ROUND_ROBIN");
+        let source: EndpointAddress = ([127, 1, 1, 1], 8080).into();
+        assert_eq!(
+            addresses.to_vec(),
+            (0..3)
+                .map(|_| choose(&filter, source.clone()))
+                .collect::<Vec<_>>()
+        );
+    }
 }
@@ -14,11 +14,15 @@
  * limitations under the License.
  */
 
+use std::time::Duration;
+
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 
 use super::endpoint_chooser::{
-    EndpointChooser, HashEndpointChooser, RandomEndpointChooser, RoundRobinEndpointChooser,
+    ConsistentHashEndpointChooser, EndpointChooser, HashEndpointChooser, RandomEndpointChooser,
+    RoundRobinEndpointChooser, SlowStart, WeightedRandomEndpointChooser,
+    WeightedRoundRobinEndpointChooser,
 };
 use super::proto;
 
@@ -28,12 +32,27 @@ use super::proto;
 pub struct Config {
     #[serde(default)]
     pub policy: Policy,
+    /// When set, endpoints added to the clusters after they were first
+    /// loaded start with no share of the packets, which grows to an equal
+    /// share over this many milliseconds.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub slow_start_ms: Option<u64>,
+}
+
+impl Config {
+    pub fn as_endpoint_chooser(&self) -> Box<dyn EndpointChooser> {
+        let slow_start = self
+            .slow_start_ms
+            .and_then(|ms| SlowStart::new(Duration::from_millis(ms)));
+        self.policy.as_endpoint_chooser(slow_start)
+    }
 }
 
 impl From<Config> for super::proto::LoadBalancer {
     fn from(config: Config) -> Self {
         Self {
             policy: Some(config.policy.into()),
+            slow_start_ms: config.slow_start_ms,
         }
     }
 }
@@ -46,6 +65,7 @@ impl From<proto::LoadBalancer> for Config {
                 .map(|p| p.value())
                 .map(Policy::from)
                 .unwrap_or_default(),
+            slow_start_ms: p.slow_start_ms,
         }
     }
 }
@@ -63,14 +83,32 @@ pub enum Policy {
     /// Send packets to endpoints based on hash of source IP and port.
     #[serde(rename = "HASH")]
     Hash,
+    /// Send packets to endpoints in turns, sending each endpoint as many
+    /// packets in a row as its weight.
+    #[serde(rename = "WEIGHTED_ROUND_ROBIN")]
+    WeightedRoundRobin,
+    /// Send packets to endpoints chosen at random, in proportion to their
+    /// weights.
+    #[serde(rename = "WEIGHTED_RANDOM")]
+    WeightedRandom,
+    /// Send packets to endpoints chosen by rendezvous hashing of the source
+    /// IP and port, in proportion to their weights, so that clients keep
+    /// their endpoint as other endpoints are added and removed.
+    #[serde(rename = "CONSISTENT_HASH")]
+    ConsistentHash,
 }
 
 impl Policy {
-    pub fn as_endpoint_chooser(&self) -> Box<dyn EndpointChooser> {
+    pub fn as_endpoint_chooser(&self, slow_start: Option<SlowStart>) -> Box<dyn EndpointChooser> {
         match self {
-            Policy::RoundRobin => Box::new(RoundRobinEndpointChooser::new()),
-            Policy::Random => Box::new(RandomEndpointChooser),
-            Policy::Hash => Box::new(HashEndpointChooser),
+            Policy::RoundRobin => Box::new(RoundRobinEndpointChooser::new(slow_start)),
+            Policy::Random => Box::new(RandomEndpointChooser::new(slow_start)),
+            Policy::Hash => Box::new(HashEndpointChooser::new(slow_start)),
+            Policy::WeightedRoundRobin => {
+                Box::new(WeightedRoundRobinEndpointChooser::new(slow_start))
+            }
+            Policy::WeightedRandom => Box::new(WeightedRandomEndpointChooser::new(slow_start)),
+            Policy::ConsistentHash => Box::new(ConsistentHashEndpointChooser::new(slow_start)),
         }
     }
 }
@@ -87,6 +125,9 @@ impl From<Policy> for proto::load_balancer::Policy {
             Policy::RoundRobin => Self::RoundRobin,
             Policy::Random => Self::Random,
             Policy::Hash => Self::Hash,
+            Policy::WeightedRoundRobin => Self::WeightedRoundRobin,
+            Policy::WeightedRandom => Self::WeightedRandom,
+            Policy::ConsistentHash => Self::ConsistentHash,
         }
     }
 }
@@ -97,6 +138,9 @@ impl From<proto::load_balancer::Policy> for Policy {
             proto::load_balancer::Policy::RoundRobin => Self::RoundRobin,
             proto::load_balancer::Policy::Random => Self::Random,
             proto::load_balancer::Policy::Hash => Self::Hash,
+            proto::load_balancer::Policy::WeightedRoundRobin => Self::WeightedRoundRobin,
+            proto::load_balancer::Policy::WeightedRandom => Self::WeightedRandom,
+            proto::load_balancer::Policy::ConsistentHash => Self::ConsistentHash,
         }
     }
 }
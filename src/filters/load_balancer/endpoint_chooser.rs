@@ -14,16 +14,14 @@
  * limitations under the License.
  */
 
-use std::sync::atomic::{AtomicUsize, Ordering};
-
-use rand::{thread_rng, Rng};
-
 use std::{
-    collections::hash_map::DefaultHasher,
-    hash::{Hash, Hasher},
+    sync::atomic::{AtomicUsize, Ordering},
+    time::Duration,
 };
 
-use crate::filters::ReadContext;
+use rand::{thread_rng, Rng};
+
+use crate::{endpoint::Endpoint, filters::ReadContext};
 
 /// EndpointChooser chooses from a set of endpoints that a proxy is connected to.
 pub trait EndpointChooser: Send + Sync {
@@ -31,15 +29,58 @@ pub trait EndpointChooser: Send + Sync {
     fn choose_endpoints(&self, endpoints: &mut ReadContext);
 }
 
+/// Ramps the share of packets sent to endpoints added to the clusters from
+/// zero to an equal share over `window`, so that newly started game servers
+/// aren't sent every new client at once.
+#[derive(Clone, Copy, Debug)]
+pub struct SlowStart {
+    window: Duration,
+}
+
+impl SlowStart {
+    /// Returns `None` when `window` is zero, as endpoints start at their full
+    /// share.
+    pub fn new(window: Duration) -> Option<Self> {
+        (!window.is_zero()).then_some(Self { window })
+    }
+
+    /// The share of packets `endpoint` receives relative to endpoints that
+    /// aren't ramping up, from 0 when it's added to 1 once `window` passes.
+    fn weight(&self, ctx: &ReadContext, endpoint: &Endpoint) -> f64 {
+        ctx.added_at(&endpoint.address).map_or(1.0, |added| {
+            (added.elapsed().as_secs_f64() / self.window.as_secs_f64()).min(1.0)
+        })
+    }
+
+    /// Returns the share of each of the packet's endpoints, and their sum,
+    /// when any of them is ramping up, and `None` when none of them are, or
+    /// every one of them has just been added, as they're then chosen alike.
+    fn shares(&self, ctx: &ReadContext) -> Option<(Vec<f64>, f64)> {
+        let shares: Vec<f64> = ctx
+            .endpoints
+            .iter()
+            .map(|endpoint| self.weight(ctx, endpoint))
+            .collect();
+        let total: f64 = shares.iter().sum();
+        (shares.iter().any(|share| *share < 1.0) && total > 0.0).then_some((shares, total))
+    }
+}
+
+/// The fractional part of the golden ratio, whose multiples spread evenly
+/// over every range between 0 and 1.
+const GOLDEN_RATIO_FRACTION: f64 = 0.618_033_988_749_895;
+
 /// RoundRobinEndpointChooser chooses endpoints in round-robin order.
 pub struct RoundRobinEndpointChooser {
     next_endpoint: AtomicUsize,
+    slow_start: Option<SlowStart>,
 }
 
 impl RoundRobinEndpointChooser {
-    pub fn new() -> Self {
+    pub fn new(slow_start: Option<SlowStart>) -> Self {
         RoundRobinEndpointChooser {
             next_endpoint: AtomicUsize::new(0),
+            slow_start,
         }
     }
 }
@@ -47,29 +88,215 @@ impl RoundRobinEndpointChooser {
 impl EndpointChooser for RoundRobinEndpointChooser {
     fn choose_endpoints(&self, ctx: &mut ReadContext) {
         let count = self.next_endpoint.fetch_add(1, Ordering::Relaxed);
+        let index = match self
+            .slow_start
+            .and_then(|slow_start| slow_start.shares(ctx))
+        {
+            // Each turn picks the endpoint whose range of shares contains the
+            // turn's multiple of the golden ratio, so that endpoints are
+            // chosen in proportion to their shares, and ramping endpoints
+            // don't hand the rest of their share to their neighbours.
+            Some((shares, total)) => weighted_index(
+                &shares,
+                (count as f64 * GOLDEN_RATIO_FRACTION).fract() * total,
+            ),
+            None => count % ctx.endpoints.len(),
+        };
         // Note: The index is guaranteed to be in range.
-        ctx.endpoints = vec![ctx.endpoints[count % ctx.endpoints.len()].clone()];
+        ctx.endpoints = vec![ctx.endpoints[index].clone()];
     }
 }
 
 /// RandomEndpointChooser chooses endpoints in random order.
-pub struct RandomEndpointChooser;
+pub struct RandomEndpointChooser {
+    slow_start: Option<SlowStart>,
+}
+
+impl RandomEndpointChooser {
+    pub fn new(slow_start: Option<SlowStart>) -> Self {
+        Self { slow_start }
+    }
+}
 
 impl EndpointChooser for RandomEndpointChooser {
     fn choose_endpoints(&self, ctx: &mut ReadContext) {
         // The index is guaranteed to be in range.
-        let index = thread_rng().gen_range(0..ctx.endpoints.len());
+        let index = match self
+            .slow_start
+            .and_then(|slow_start| slow_start.shares(ctx))
+        {
+            Some((shares, total)) => weighted_index(&shares, thread_rng().gen_range(0.0..total)),
+            None => thread_rng().gen_range(0..ctx.endpoints.len()),
+        };
+        ctx.endpoints = vec![ctx.endpoints[index].clone()];
+    }
+}
+
+/// Returns the weight of each of the packet's endpoints, scaled by
+/// `slow_start` when set, and their sum.
+fn weights(ctx: &ReadContext, slow_start: Option<&SlowStart>) -> (Vec<f64>, f64) {
+    let weights: Vec<f64> = ctx
+        .endpoints
+        .iter()
+        .map(|endpoint| {
+            let weight = endpoint.weight as f64;
+            slow_start.map_or(weight, |slow_start| {
+                weight * slow_start.weight(ctx, endpoint)
+            })
+        })
+        .collect();
+    let total = weights.iter().sum();
+    (weights, total)
+}
+
+/// Returns the index of the endpoint whose range of weights contains
+/// `position`, a number between 0 and the sum of `weights`.
+fn weighted_index(weights: &[f64], position: f64) -> usize {
+    let mut sum = 0.0;
+    weights
+        .iter()
+        .position(|weight| {
+            sum += weight;
+            position < sum
+        })
+        // Rounding can leave `position` just past the sum, which belongs to
+        // the last endpoint with any weight.
+        .unwrap_or_else(|| {
+            weights
+                .iter()
+                .rposition(|weight| *weight > 0.0)
+                .unwrap_or(0)
+        })
+}
+
+/// WeightedRoundRobinEndpointChooser chooses endpoints in turns, choosing
+/// each endpoint as many times in a row as its weight.
+pub struct WeightedRoundRobinEndpointChooser {
+    next_endpoint: AtomicUsize,
+    slow_start: Option<SlowStart>,
+}
+
+impl WeightedRoundRobinEndpointChooser {
+    pub fn new(slow_start: Option<SlowStart>) -> Self {
+        Self {
+            next_endpoint: AtomicUsize::new(0),
+            slow_start,
+        }
+    }
+}
+
+impl EndpointChooser for WeightedRoundRobinEndpointChooser {
+    fn choose_endpoints(&self, ctx: &mut ReadContext) {
+        let count = self.next_endpoint.fetch_add(1, Ordering::Relaxed) as u64;
+        let turns: u64 = ctx
+            .endpoints
+            .iter()
+            .map(|endpoint| endpoint.weight as u64)
+            .sum();
+        let (weights, total) = weights(ctx, self.slow_start.as_ref());
+        let index = if total > 0.0 {
+            // Turns are spread over the weights ramped by slow start, which
+            // are the endpoints' weights without it.
+            let turn = (count % turns) as f64 + 0.5;
+            weighted_index(&weights, turn / turns as f64 * total)
+        } else {
+            // Every endpoint is drained or ramping up, so they're all chosen
+            // in turns rather than none of them.
+            count as usize % ctx.endpoints.len()
+        };
+        ctx.endpoints = vec![ctx.endpoints[index].clone()];
+    }
+}
+
+/// WeightedRandomEndpointChooser chooses endpoints at random, in proportion
+/// to their weights.
+pub struct WeightedRandomEndpointChooser {
+    slow_start: Option<SlowStart>,
+}
+
+impl WeightedRandomEndpointChooser {
+    pub fn new(slow_start: Option<SlowStart>) -> Self {
+        Self { slow_start }
+    }
+}
+
+impl EndpointChooser for WeightedRandomEndpointChooser {
+    fn choose_endpoints(&self, ctx: &mut ReadContext) {
+        let (weights, total) = weights(ctx, self.slow_start.as_ref());
+        let index = if total > 0.0 {
+            weighted_index(&weights, thread_rng().gen_range(0.0..total))
+        } else {
+            thread_rng().gen_range(0..ctx.endpoints.len())
+        };
         ctx.endpoints = vec![ctx.endpoints[index].clone()];
     }
 }
 
 /// HashEndpointChooser chooses endpoints based on a hash of source IP and port.
-pub struct HashEndpointChooser;
+pub struct HashEndpointChooser {
+    slow_start: Option<SlowStart>,
+}
+
+impl HashEndpointChooser {
+    pub fn new(slow_start: Option<SlowStart>) -> Self {
+        Self { slow_start }
+    }
+}
 
 impl EndpointChooser for HashEndpointChooser {
     fn choose_endpoints(&self, ctx: &mut ReadContext) {
-        let mut hasher = DefaultHasher::new();
-        ctx.source.hash(&mut hasher);
-        ctx.endpoints = vec![ctx.endpoints[hasher.finish() as usize % ctx.endpoints.len()].clone()];
+        let hash = crate::utils::stable_hash(&ctx.source);
+        let index = match self
+            .slow_start
+            .and_then(|slow_start| slow_start.shares(ctx))
+        {
+            // Hashed rather than random, so that each client stays on the
+            // same endpoint while the shares are unchanged.
+            Some((shares, total)) => weighted_index(&shares, hash as f64 / u64::MAX as f64 * total),
+            None => hash as usize % ctx.endpoints.len(),
+        };
+        ctx.endpoints = vec![ctx.endpoints[index].clone()];
+    }
+}
+
+/// ConsistentHashEndpointChooser chooses endpoints by rendezvous hashing of
+/// the source IP and port with each endpoint's address, so that a client
+/// only moves to another endpoint when its endpoint is removed, or when an
+/// added endpoint outscores it, and the clients of other endpoints are left
+/// where they are.
+pub struct ConsistentHashEndpointChooser {
+    slow_start: Option<SlowStart>,
+}
+
+impl ConsistentHashEndpointChooser {
+    pub fn new(slow_start: Option<SlowStart>) -> Self {
+        Self { slow_start }
+    }
+}
+
+impl EndpointChooser for ConsistentHashEndpointChooser {
+    fn choose_endpoints(&self, ctx: &mut ReadContext) {
+        let (mut weights, total) = weights(ctx, self.slow_start.as_ref());
+        if total <= 0.0 {
+            weights.iter_mut().for_each(|weight| *weight = 1.0);
+        }
+
+        let source = &ctx.source;
+        let score = |(endpoint, weight): (&Endpoint, &f64)| {
+            let hash = crate::utils::stable_hash((source, &endpoint.address));
+            // The top 53 bits as a number between 0 and 1, exclusive, so
+            // that each endpoint wins in proportion to its weight.
+            let unit = ((hash >> 11) as f64 + 0.5) / (1u64 << 53) as f64;
+            weight / -unit.ln()
+        };
+        let index = ctx
+            .endpoints
+            .iter()
+            .zip(&weights)
+            .map(score)
+            .enumerate()
+            .max_by(|(_, lhs), (_, rhs)| lhs.total_cmp(rhs))
+            .map_or(0, |(index, _)| index);
+        ctx.endpoints = vec![ctx.endpoints[index].clone()];
     }
 }
@@ -25,8 +25,12 @@ use serde::{Deserialize, Serialize};
 
 use crate::{
     endpoint::EndpointAddress,
-    filters::prelude::*,
+    filters::{
+        prelude::*,
+        suspicion::{self, Suspicion},
+    },
     ttl_map::{Entry, TtlMap},
+    xds::rate_limit::SharedRateLimits,
 };
 
 use metrics::Metrics;
@@ -41,6 +45,11 @@ pub const SESSION_TIMEOUT_SECONDS: Duration = Duration::from_secs(60);
 /// SESSION_EXPIRY_POLL_INTERVAL is the default interval to check for expired sessions.
 const SESSION_EXPIRY_POLL_INTERVAL: Duration = Duration::from_secs(60);
 
+/// A source's usage of a shared limit is reported to the rest of the fleet
+/// each time it sends another `max_packets / SHARED_REPORT_DIVISOR` packets
+/// in a window, which keeps well-behaved sources out of the shared state.
+const SHARED_REPORT_DIVISOR: usize = 10;
+
 /// Bucket stores two atomics.
 /// - A counter that tracks how many packets we've processed within a time window.
 /// - A timestamp that stores the time we last reset the counter. It tracks
@@ -94,8 +103,13 @@ impl LocalRateLimit {
     /// acquire_token is called on behalf of every packet that is eligible
     /// for rate limiting. It returns whether there exists a token for the corresponding
     /// address in the current period - determining whether or not the packet
-    /// should be forwarded or dropped.
-    fn acquire_token(&self, address: &EndpointAddress) -> Option<()> {
+    /// should be forwarded or dropped. The usage of shared limits is counted
+    /// in `shared`, if set.
+    fn acquire_token(
+        &self,
+        address: &EndpointAddress,
+        shared: Option<&SharedRateLimits>,
+    ) -> Option<()> {
         if self.config.max_packets == 0 {
             return None;
         }
@@ -108,12 +122,27 @@ impl LocalRateLimit {
 
             let elapsed_secs = now_secs - window_start_secs;
             let start_new_window = elapsed_secs > self.config.period as u64;
+            let max_packets = self
+                .config
+                .max_packets
+                .saturating_sub(self.fleet_packets(shared, address));
 
             // Check if allowing this packet will put us over the maximum.
-            if prev_count >= self.config.max_packets {
+            if prev_count >= max_packets {
                 // If so, then we can only allow the packet if the current time
                 // window has ended.
                 if !start_new_window {
+                    // Only report the first packet over the limit in each
+                    // window, rather than every packet that follows it.
+                    if prev_count == max_packets {
+                        suspicion::emit(|| {
+                            Suspicion::new(Self::NAME, suspicion::EXCESSIVE_RATE, address)
+                                .with_detail(format!(
+                                    "more than {} packets in {} seconds",
+                                    self.config.max_packets, self.config.period
+                                ))
+                        });
+                    }
                     return None;
                 }
             }
@@ -126,6 +155,8 @@ impl LocalRateLimit {
                     .value
                     .window_start_time_secs
                     .store(now_secs, Ordering::Relaxed);
+            } else {
+                self.record_shared(shared, address, prev_count + 1);
             }
 
             return Some(());
@@ -151,14 +182,52 @@ impl LocalRateLimit {
 
         Some(())
     }
+
+    /// The packets the rest of the fleet counted in `shared` for `address`
+    /// against the shared limit, if any.
+    fn fleet_packets(&self, shared: Option<&SharedRateLimits>, address: &EndpointAddress) -> usize {
+        match (&self.config.shared, shared) {
+            (Some(limit), Some(shared)) => shared.fleet_packets(limit, address) as usize,
+            _ => 0,
+        }
+    }
+
+    /// Reports the `count` of packets that `address` sent in the current
+    /// window to the rest of the fleet through `shared`, see
+    /// [`SHARED_REPORT_DIVISOR`].
+    fn record_shared(
+        &self,
+        shared: Option<&SharedRateLimits>,
+        address: &EndpointAddress,
+        count: usize,
+    ) {
+        let (Some(limit), Some(shared)) = (&self.config.shared, shared) else {
+            return;
+        };
+
+        let threshold = (self.config.max_packets / SHARED_REPORT_DIVISOR).max(1);
+        if count % threshold == 0 {
+            shared.record(
+                limit,
+                address,
+                count as u64,
+                Duration::from_secs(self.config.period.into()),
+            );
+        }
+    }
 }
 
 impl Filter for LocalRateLimit {
     fn read(&self, ctx: &mut ReadContext) -> Option<()> {
-        self.acquire_token(&ctx.source).or_else(|| {
-            self.metrics.packets_dropped_total.inc();
-            None
-        })
+        self.acquire_token(&ctx.source, ctx.rate_limits.as_ref())
+            .or_else(|| {
+                self.metrics.packets_dropped_total.inc();
+                None
+            })
+    }
+
+    fn has_write(&self) -> bool {
+        false
     }
 }
 
@@ -181,6 +250,14 @@ pub struct Config {
     /// The duration in seconds during which max_packets applies. If none is provided, it
     /// defaults to one second.
     pub period: u32,
+    /// The name of a fleet-wide limit that this filter's usage counts
+    /// towards. Proxies started with `--share-rate-limits` share the usage
+    /// of sources close to the limit through the management server, so that
+    /// `max_packets` applies across every proxy with the same limit rather
+    /// than to each of them. The limit is only approximate, as usage is
+    /// shared every second.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub shared: Option<String>,
 }
 
 /// default value for [`Config::period`]
@@ -193,6 +270,7 @@ impl From<Config> for proto::LocalRateLimit {
         Self {
             max_packets: config.max_packets as u64,
             period: Some(config.period),
+            shared: config.shared,
         }
     }
 }
@@ -204,6 +282,7 @@ impl TryFrom<proto::LocalRateLimit> for Config {
         Ok(Self {
             max_packets: p.max_packets as usize,
             period: p.period.unwrap_or_else(default_period),
+            shared: p.shared,
         })
     }
 }
@@ -230,11 +309,23 @@ mod tests {
 
     /// Send a packet to the filter and assert whether or not it was processed.
     fn read(r: &LocalRateLimit, address: &EndpointAddress, should_succeed: bool) {
+        read_shared(r, address, &<_>::default(), should_succeed)
+    }
+
+    /// Send a packet counting towards the usage of `shared` to the filter, and
+    /// assert whether or not it was processed.
+    fn read_shared(
+        r: &LocalRateLimit,
+        address: &EndpointAddress,
+        shared: &SharedRateLimits,
+        should_succeed: bool,
+    ) {
         let endpoints = vec![crate::endpoint::Endpoint::new(
             (Ipv4Addr::LOCALHOST, 8089).into(),
         )];
 
-        let mut context = ReadContext::new(endpoints, address.clone(), vec![9]);
+        let mut context =
+            ReadContext::new(endpoints, address.clone(), vec![9]).rate_limits(shared.clone());
         let result = r.read(&mut context);
 
         if should_succeed {
@@ -269,10 +360,12 @@ period: 0
                 proto::LocalRateLimit {
                     max_packets: 10,
                     period: Some(2),
+                    shared: None,
                 },
                 Some(Config {
                     max_packets: 10,
                     period: 2,
+                    shared: None,
                 }),
             ),
             (
@@ -280,10 +373,12 @@ period: 0
                 proto::LocalRateLimit {
                     max_packets: 10,
                     period: None,
+                    shared: None,
                 },
                 Some(Config {
                     max_packets: 10,
                     period: 1,
+                    shared: None,
                 }),
             ),
         ];
@@ -307,6 +402,7 @@ period: 0
         let r = rate_limiter(Config {
             max_packets: 3,
             period: 1,
+            shared: None,
         });
 
         let (address, _) = address_pair();
@@ -322,6 +418,7 @@ period: 0
         let r = rate_limiter(Config {
             max_packets: 0,
             period: 1,
+            shared: None,
         });
 
         let (address, _) = address_pair();
@@ -340,6 +437,7 @@ period: 0
         let r = rate_limiter(Config {
             max_packets: 2,
             period: 1,
+            shared: None,
         });
 
         let (address1, address2) = address_pair();
@@ -384,6 +482,7 @@ period: 0
         let r = rate_limiter(Config {
             max_packets: 2,
             period: 1,
+            shared: None,
         });
 
         let (address, _) = address_pair();
@@ -402,4 +501,34 @@ period: 0
         // Check that other routes are not affected.
         assert_write_no_change(&r);
     }
+
+    #[tokio::test]
+    async fn shared_limit_counts_fleet_usage() {
+        let r = rate_limiter(Config {
+            max_packets: 10,
+            period: 60,
+            shared: Some("shared_limit_counts_fleet_usage".into()),
+        });
+
+        let (address, other) = address_pair();
+        let shared = SharedRateLimits::default();
+        shared.apply(crate::xds::rate_limit::FleetUsage {
+            usages: vec![crate::xds::rate_limit::Usage {
+                key: format!("shared_limit_counts_fleet_usage/{address}"),
+                packets: 8,
+                window_ms: 60_000,
+            }],
+        });
+
+        // The rest of the fleet already counted 8 of the 10 packets.
+        read_shared(&r, &address, &shared, true);
+        read_shared(&r, &address, &shared, true);
+        read_shared(&r, &address, &shared, false);
+
+        // Other sources are only limited locally.
+        for _ in 0..10 {
+            read_shared(&r, &other, &shared, true);
+        }
+        read_shared(&r, &other, &shared, false);
+    }
 }
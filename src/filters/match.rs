@@ -145,6 +145,10 @@ impl Filter for Match {
             |ctx, instance| instance.filter.write(ctx),
         )
     }
+
+    fn has_write(&self) -> bool {
+        self.on_write_filters.is_some()
+    }
 }
 
 impl StaticFilter for Match {
@@ -164,6 +168,7 @@ mod tests {
     use crate::{endpoint::Endpoint, filters::*};
 
     #[test]
+    #[cfg(feature = "filter-pass")]
     fn metrics() {
         let metrics = Metrics::new().unwrap();
         let key = crate::metadata::Key::from_static("myapp.com/token");
@@ -180,6 +180,7 @@ mod tests {
     use super::*;
 
     #[test]
+    #[cfg(feature = "filter-debug")]
     fn serde() {
         let matches_yaml = "
 on_read:
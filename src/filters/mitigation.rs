@@ -0,0 +1,485 @@
+/*
+ * Copyright 2023 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Mitigations pushed by external DDoS detection and scrubbing systems,
+//! blocking or rate limiting source prefixes for a while. Blocks are applied
+//! by every `Firewall` filter, and rate limits by every
+//! [`RateLimit`][super::RateLimit] filter, so that an attack can be
+//! mitigated across the fleet without pushing new filter chains. The
+//! mitigations are held by the [`Config`][crate::Config], and reach the
+//! filters with each packet's [`ReadContext`][super::ReadContext].
+//!
+//! Every change replaces the whole set of mitigations at once, so a packet is
+//! either checked against the mitigations before a change or after it.
+
+use std::{net::IpAddr, sync::Arc, time::Duration};
+
+use arc_swap::ArcSwap;
+use ipnetwork::IpNetwork;
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use prometheus::{IntCounterVec, IntGaugeVec};
+use serde::{Deserialize, Serialize};
+use tokio::time::Instant;
+
+use crate::endpoint::{other_ipv4_representation, EndpointAddress};
+
+const ACTION_LABEL: &str = "action";
+const BLOCK: &str = "block";
+const RATE_LIMIT: &str = "rate_limit";
+
+/// The longest a mitigation can last, so that a forgotten one doesn't block
+/// a prefix forever.
+pub const MAX_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// The filters applying each action.
+const FIREWALL_FILTER: &str = "quilkin.filters.firewall.v1alpha1.Firewall";
+const RATE_LIMIT_FILTER: &str = "quilkin.filters.rate_limit.v1alpha1.RateLimit";
+
+static ACTIVE: Lazy<IntGaugeVec> = Lazy::new(|| {
+    prometheus::register_int_gauge_vec_with_registry! {
+        prometheus::opts! {
+            "mitigations_active",
+            "Number of active mitigations pushed by external detection systems. Labels: action",
+        },
+        &[ACTION_LABEL],
+        crate::metrics::registry(),
+    }
+    .unwrap()
+});
+
+static PACKETS_TOTAL: Lazy<IntCounterVec> = Lazy::new(|| {
+    prometheus::register_int_counter_vec_with_registry! {
+        prometheus::opts! {
+            "mitigation_packets_total",
+            "Total number of packets blocked or over the rate limit of a mitigation. Labels: action",
+        },
+        &[ACTION_LABEL],
+        crate::metrics::registry(),
+    }
+    .unwrap()
+});
+
+/// A source prefix to block or rate limit, for `ttl_secs` seconds.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Mitigation {
+    /// The IPv4 or IPv6 CIDR of the sources, such as `203.0.113.0/24`.
+    pub prefix: IpNetwork,
+    #[serde(flatten)]
+    pub action: Action,
+    /// How long the mitigation lasts, in seconds. Listed mitigations hold the
+    /// seconds they have left.
+    pub ttl_secs: u64,
+}
+
+/// What is done with the packets from a mitigated prefix.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum Action {
+    /// Packets are denied by `Firewall` filters.
+    Block,
+    /// `RateLimit` filters apply their action to the packets over
+    /// `packets_per_second`, across every source in the prefix.
+    RateLimit { packets_per_second: u32 },
+}
+
+impl Action {
+    fn label(&self) -> &'static str {
+        match self {
+            Self::Block => BLOCK,
+            Self::RateLimit { .. } => RATE_LIMIT,
+        }
+    }
+
+    /// The name of the filter that applies the action, which does nothing
+    /// unless the filter chain has one.
+    pub fn filter(&self) -> &'static str {
+        match self {
+            Self::Block => FIREWALL_FILTER,
+            Self::RateLimit { .. } => RATE_LIMIT_FILTER,
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error, PartialEq, Eq)]
+pub enum MitigationError {
+    #[error("the mitigation of `{0}` must last between 1 and {max} seconds", max = MAX_TTL.as_secs())]
+    Ttl(IpNetwork),
+    #[error("the rate limit of `{0}` must be at least 1 packet per second")]
+    Rate(IpNetwork),
+}
+
+/// A mitigation as applied, with the bucket of tokens of rate limits.
+#[derive(Clone, Debug)]
+struct Active {
+    prefix: IpNetwork,
+    action: Action,
+    expires_at: Instant,
+    bucket: Option<Arc<Bucket>>,
+}
+
+impl Active {
+    fn contains(&self, ip: IpAddr, now: Instant) -> bool {
+        self.expires_at > now
+            && (self.prefix.contains(ip)
+                || other_ipv4_representation(ip).map_or(false, |ip| self.prefix.contains(ip)))
+    }
+}
+
+/// The tokens left for a rate limited prefix, as of the time they were last
+/// refilled.
+#[derive(Debug)]
+struct Bucket {
+    rate: f64,
+    tokens: Mutex<(f64, Instant)>,
+}
+
+impl Bucket {
+    fn new(packets_per_second: u32, now: Instant) -> Self {
+        let rate = packets_per_second as f64;
+        Self {
+            rate,
+            tokens: Mutex::new((rate, now)),
+        }
+    }
+
+    fn take(&self, now: Instant) -> bool {
+        let mut tokens = self.tokens.lock();
+        let (tokens, refilled_at) = &mut *tokens;
+        let elapsed = now.saturating_duration_since(*refilled_at).as_secs_f64();
+        *tokens = (*tokens + elapsed * self.rate).min(self.rate);
+        *refilled_at = now;
+
+        if *tokens >= 1.0 {
+            *tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// The set of active mitigations, shared by its clones.
+#[derive(Clone, Debug, Default)]
+pub struct Mitigations(Arc<Inner>);
+
+#[derive(Debug, Default)]
+struct Inner {
+    active: ArcSwap<Vec<Active>>,
+    /// Serialises changes, so that concurrent ones aren't lost.
+    changes: Mutex<()>,
+}
+
+impl Mitigations {
+    /// Adds `mitigations`, replacing the active mitigations of the same
+    /// prefixes, or none of them if one is invalid. Expired mitigations are
+    /// removed once they are over.
+    pub fn apply(&self, mitigations: Vec<Mitigation>) -> Result<(), MitigationError> {
+        for mitigation in &mitigations {
+            if mitigation.ttl_secs == 0 || mitigation.ttl_secs > MAX_TTL.as_secs() {
+                return Err(MitigationError::Ttl(mitigation.prefix));
+            }
+            if mitigation.action
+                == (Action::RateLimit {
+                    packets_per_second: 0,
+                })
+            {
+                return Err(MitigationError::Rate(mitigation.prefix));
+            }
+        }
+
+        let expiries: Vec<_> = mitigations
+            .iter()
+            .map(|mitigation| Duration::from_secs(mitigation.ttl_secs))
+            .collect();
+
+        self.change(|now, current| {
+            let mut active: Vec<_> = current
+                .iter()
+                .filter(|active| !mitigations.iter().any(|new| new.prefix == active.prefix))
+                .cloned()
+                .collect();
+
+            for mitigation in mitigations {
+                // Refreshing a rate limit keeps the tokens it has left.
+                let previous = current.iter().find(|active| {
+                    active.prefix == mitigation.prefix && active.action == mitigation.action
+                });
+                let bucket = match mitigation.action {
+                    Action::Block => None,
+                    Action::RateLimit { packets_per_second } => Some(
+                        previous
+                            .and_then(|previous| previous.bucket.clone())
+                            .unwrap_or_else(|| Arc::new(Bucket::new(packets_per_second, now))),
+                    ),
+                };
+                active.push(Active {
+                    prefix: mitigation.prefix,
+                    action: mitigation.action,
+                    expires_at: now + Duration::from_secs(mitigation.ttl_secs),
+                    bucket,
+                });
+            }
+
+            active
+        });
+
+        for ttl in expiries {
+            let this = self.clone();
+            tokio::spawn(async move {
+                tokio::time::sleep(ttl).await;
+                this.change(|_, current| current.to_vec());
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Lifts the active mitigations of `prefixes`.
+    pub fn remove(&self, prefixes: &[IpNetwork]) {
+        self.change(|_, current| {
+            current
+                .iter()
+                .filter(|active| !prefixes.contains(&active.prefix))
+                .cloned()
+                .collect()
+        });
+    }
+
+    /// Replaces the active mitigations with the ones returned by `change`,
+    /// leaving out those which have expired.
+    fn change(&self, change: impl FnOnce(Instant, &[Active]) -> Vec<Active>) {
+        let _guard = self.0.changes.lock();
+        let now = Instant::now();
+        let mut active = change(now, &self.0.active.load());
+        active.retain(|active| active.expires_at > now);
+
+        for label in [BLOCK, RATE_LIMIT] {
+            let count = active
+                .iter()
+                .filter(|active| active.action.label() == label)
+                .count();
+            ACTIVE.with_label_values(&[label]).set(count as i64);
+        }
+        self.0.active.store(Arc::new(active));
+    }
+
+    /// Returns the active mitigations.
+    pub fn list(&self) -> Vec<Mitigation> {
+        let now = Instant::now();
+        self.0
+            .active
+            .load()
+            .iter()
+            .filter(|active| active.expires_at > now)
+            .map(|active| Mitigation {
+                prefix: active.prefix,
+                action: active.action,
+                ttl_secs: (active.expires_at - now).as_secs_f64().ceil() as u64,
+            })
+            .collect()
+    }
+
+    /// Whether packets from `source` are blocked by a mitigation.
+    pub fn blocked(&self, source: &EndpointAddress) -> bool {
+        let active = self.0.active.load();
+        if active.is_empty() {
+            return false;
+        }
+
+        let Some(ip) = source_ip(source) else {
+            return false;
+        };
+        let now = Instant::now();
+        let blocked = active
+            .iter()
+            .any(|active| active.action == Action::Block && active.contains(ip, now));
+        if blocked {
+            PACKETS_TOTAL.with_label_values(&[BLOCK]).inc();
+        }
+        blocked
+    }
+
+    /// Whether a packet from `source` is within the rate limits of the
+    /// mitigations of its prefixes, taking a token from each of them.
+    pub fn admitted(&self, source: &EndpointAddress) -> bool {
+        let active = self.0.active.load();
+        if active.is_empty() {
+            return true;
+        }
+
+        let Some(ip) = source_ip(source) else {
+            return true;
+        };
+        let now = Instant::now();
+        let admitted = active
+            .iter()
+            .filter(|active| active.contains(ip, now))
+            .filter_map(|active| active.bucket.as_ref())
+            .all(|bucket| bucket.take(now));
+        if !admitted {
+            PACKETS_TOTAL.with_label_values(&[RATE_LIMIT]).inc();
+        }
+        admitted
+    }
+}
+
+fn source_ip(source: &EndpointAddress) -> Option<IpAddr> {
+    source.to_socket_addr().ok().map(|address| address.ip())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    fn mitigation(prefix: &str, action: Action, ttl_secs: u64) -> Mitigation {
+        Mitigation {
+            prefix: prefix.parse().unwrap(),
+            action,
+            ttl_secs,
+        }
+    }
+
+    fn source(ip: [u8; 4]) -> EndpointAddress {
+        (Ipv4Addr::from(ip), 7000).into()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn block() {
+        let mitigations = Mitigations::default();
+        mitigations
+            .apply(vec![mitigation("203.0.113.0/24", Action::Block, 10)])
+            .unwrap();
+
+        assert!(mitigations.blocked(&source([203, 0, 113, 7])));
+        assert!(mitigations.blocked(&(std::net::Ipv6Addr::from(0xffff_cb00_7107), 7000).into()));
+        assert!(!mitigations.blocked(&source([203, 0, 114, 7])));
+        assert!(mitigations.admitted(&source([203, 0, 113, 7])));
+
+        tokio::time::advance(Duration::from_secs(5)).await;
+        assert_eq!(
+            vec![mitigation("203.0.113.0/24", Action::Block, 5)],
+            mitigations.list()
+        );
+
+        // Mitigations are over after their TTL.
+        tokio::time::advance(Duration::from_secs(5)).await;
+        assert!(!mitigations.blocked(&source([203, 0, 113, 7])));
+        assert!(mitigations.list().is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn rate_limit() {
+        let mitigations = Mitigations::default();
+        let limit = Action::RateLimit {
+            packets_per_second: 2,
+        };
+        mitigations
+            .apply(vec![mitigation("203.0.113.0/24", limit, 60)])
+            .unwrap();
+
+        // The limit is shared by every source in the prefix.
+        assert!(mitigations.admitted(&source([203, 0, 113, 1])));
+        assert!(mitigations.admitted(&source([203, 0, 113, 2])));
+        assert!(!mitigations.admitted(&source([203, 0, 113, 3])));
+        assert!(mitigations.admitted(&source([198, 51, 100, 1])));
+        assert!(!mitigations.blocked(&source([203, 0, 113, 1])));
+
+        // Refreshing the mitigation keeps its tokens.
+        mitigations
+            .apply(vec![mitigation("203.0.113.0/24", limit, 60)])
+            .unwrap();
+        assert!(!mitigations.admitted(&source([203, 0, 113, 1])));
+
+        tokio::time::advance(Duration::from_millis(600)).await;
+        assert!(mitigations.admitted(&source([203, 0, 113, 1])));
+        assert!(!mitigations.admitted(&source([203, 0, 113, 1])));
+    }
+
+    #[tokio::test]
+    async fn apply_and_remove() {
+        let mitigations = Mitigations::default();
+        mitigations
+            .apply(vec![
+                mitigation("203.0.113.0/24", Action::Block, 60),
+                mitigation("198.51.100.0/24", Action::Block, 60),
+            ])
+            .unwrap();
+
+        // A prefix's mitigation replaces its previous one.
+        let limit = Action::RateLimit {
+            packets_per_second: 10,
+        };
+        mitigations
+            .apply(vec![mitigation("203.0.113.0/24", limit, 60)])
+            .unwrap();
+        assert!(!mitigations.blocked(&source([203, 0, 113, 1])));
+        assert!(mitigations.blocked(&source([198, 51, 100, 1])));
+
+        // Invalid mitigations are rejected together with the valid ones.
+        assert_eq!(
+            Err(MitigationError::Ttl("192.0.2.0/24".parse().unwrap())),
+            mitigations.apply(vec![
+                mitigation("198.51.100.0/24", limit, 60),
+                mitigation("192.0.2.0/24", Action::Block, MAX_TTL.as_secs() + 1),
+            ])
+        );
+        assert!(matches!(
+            mitigations.apply(vec![mitigation(
+                "192.0.2.0/24",
+                Action::RateLimit {
+                    packets_per_second: 0
+                },
+                60
+            )]),
+            Err(MitigationError::Rate(_))
+        ));
+        assert!(mitigations.blocked(&source([198, 51, 100, 1])));
+
+        mitigations.remove(&["198.51.100.0/24".parse().unwrap()]);
+        assert_eq!(
+            vec![mitigation("203.0.113.0/24", limit, 60)],
+            mitigations.list()
+        );
+    }
+
+    #[test]
+    fn parse() {
+        let mitigations: Vec<Mitigation> = serde_json::from_str(
+            r#"[
+                {"prefix": "203.0.113.0/24", "action": "block", "ttl_secs": 300},
+                {"prefix": "2001:db8::/32", "action": "rate_limit", "packets_per_second": 100, "ttl_secs": 60}
+            ]"#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            vec![
+                mitigation("203.0.113.0/24", Action::Block, 300),
+                mitigation(
+                    "2001:db8::/32",
+                    Action::RateLimit {
+                        packets_per_second: 100
+                    },
+                    60
+                ),
+            ],
+            mitigations
+        );
+    }
+}
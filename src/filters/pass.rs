@@ -42,6 +42,10 @@ impl Filter for Pass {
     fn write(&self, _: &mut WriteContext) -> Option<()> {
         Some(())
     }
+
+    fn has_write(&self) -> bool {
+        false
+    }
 }
 
 impl StaticFilter for Pass {
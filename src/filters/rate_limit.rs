@@ -0,0 +1,342 @@
+/*
+ * Copyright 2023 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+mod config;
+mod metrics;
+
+crate::include_proto!("quilkin.filters.rate_limit.v1alpha1");
+
+use std::time::Duration;
+
+use parking_lot::Mutex;
+use tokio::time::Instant;
+
+use crate::{
+    endpoint::EndpointAddress,
+    filters::prelude::*,
+    metadata,
+    ttl_map::{Entry, TtlMap},
+};
+
+use self::{metrics::Metrics, quilkin::filters::rate_limit::v1alpha1 as proto};
+
+pub use self::config::{Action, Config, DEFAULT_MARK_KEY};
+
+/// How often the buckets of clients that stopped sending are removed.
+const EXPIRY_POLL_INTERVAL: Duration = Duration::from_secs(10);
+/// The least time a client's bucket is kept after its latest packet.
+const MIN_BUCKET_TTL: Duration = Duration::from_secs(1);
+
+/// Filter that limits the rate of packets from each client with a bucket of
+/// tokens, so that a single abusive client can't starve the other clients
+/// behind the same proxy. Each client can send a burst of up to `capacity`
+/// packets, refilled at `refill_rate` packets per second.
+///
+/// Clients are keyed by their source address, or by a token in the packet's
+/// metadata. Only packets received from downstream are limited. The rate
+/// limits of [mitigations][crate::filters::mitigation] apply to their prefixes on top of the
+/// clients' own.
+pub struct RateLimit {
+    metadata_key: Option<metadata::Key>,
+    capacity: f64,
+    refill_rate: f64,
+    action: Action,
+    mark_key: metadata::Key,
+    buckets: TtlMap<Client, Bucket>,
+    metrics: Metrics,
+}
+
+/// What packets are limited by.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+enum Client {
+    Source(EndpointAddress),
+    Token(bytes::Bytes),
+}
+
+/// The tokens left in a client's bucket, as of the time it was last
+/// refilled.
+struct Bucket(Mutex<(f64, Instant)>);
+
+impl RateLimit {
+    fn new(config: Config, metrics: Metrics) -> Result<Self, Error> {
+        for (field, value) in [
+            ("capacity", config.capacity),
+            ("refill_rate", config.refill_rate),
+        ] {
+            if value == 0 {
+                return Err(Error::FieldInvalid {
+                    field: field.into(),
+                    reason: "value must be at least 1".into(),
+                });
+            }
+        }
+
+        let capacity = config.capacity as f64;
+        let refill_rate = config.refill_rate as f64;
+        // A bucket that has had time to fill up is the same as a new one.
+        let ttl = Duration::from_secs_f64(capacity / refill_rate).max(MIN_BUCKET_TTL);
+
+        Ok(Self {
+            metadata_key: config.metadata_key,
+            capacity,
+            refill_rate,
+            action: config.action,
+            mark_key: config.mark_key,
+            buckets: TtlMap::new(ttl, EXPIRY_POLL_INTERVAL),
+            metrics,
+        })
+    }
+
+    /// Returns the client the packet counts against, which is the token in
+    /// the metadata if there is one, and the source address otherwise.
+    fn client(&self, ctx: &ReadContext) -> Client {
+        let token = self
+            .metadata_key
+            .and_then(|key| ctx.metadata.get(&key))
+            .and_then(|value| match value {
+                metadata::Value::Bytes(token) => Some(token.clone()),
+                metadata::Value::String(token) => Some(token.clone().into()),
+                metadata::Value::Number(token) => {
+                    Some(bytes::Bytes::copy_from_slice(&token.to_be_bytes()))
+                }
+                _ => None,
+            });
+
+        match token {
+            Some(token) => Client::Token(token),
+            None => Client::Source(ctx.source.clone()),
+        }
+    }
+
+    /// Takes a token from `client`'s bucket, returning whether there was
+    /// one.
+    fn acquire(&self, client: Client) -> bool {
+        let now = Instant::now();
+        if let Some(bucket) = self.buckets.get(&client) {
+            return self.take(&bucket.value, now);
+        }
+
+        match self.buckets.entry(client) {
+            // Another packet may have added the bucket since it was checked.
+            Entry::Occupied(entry) => self.take(&entry.get().value, now),
+            Entry::Vacant(entry) => {
+                let bucket = entry.insert(Bucket(Mutex::new((self.capacity, now))));
+                self.take(&bucket.value, now)
+            }
+        }
+    }
+
+    fn take(&self, bucket: &Bucket, now: Instant) -> bool {
+        let mut bucket = bucket.0.lock();
+        let (tokens, refilled_at) = &mut *bucket;
+        let elapsed = now.saturating_duration_since(*refilled_at).as_secs_f64();
+        *tokens = (*tokens + elapsed * self.refill_rate).min(self.capacity);
+        *refilled_at = now;
+
+        if *tokens >= 1.0 {
+            *tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+impl Filter for RateLimit {
+    #[cfg_attr(feature = "instrument", tracing::instrument(skip(self, ctx)))]
+    fn read(&self, ctx: &mut ReadContext) -> Option<()> {
+        if ctx.mitigation_admitted() && self.acquire(self.client(ctx)) {
+            return Some(());
+        }
+
+        match self.action {
+            Action::Drop => {
+                tracing::trace!(source = %ctx.source, "Dropping packet, client is over the limit");
+                self.metrics.packets_dropped_total.inc();
+                None
+            }
+            Action::Mark => {
+                self.metrics.packets_marked_total.inc();
+                ctx.metadata
+                    .insert(self.mark_key, metadata::Value::Bool(true));
+                Some(())
+            }
+        }
+    }
+
+    fn has_write(&self) -> bool {
+        false
+    }
+}
+
+impl StaticFilter for RateLimit {
+    const NAME: &'static str = "quilkin.filters.rate_limit.v1alpha1.RateLimit";
+    type Configuration = Config;
+    type BinaryConfiguration = proto::RateLimit;
+
+    fn try_from_config(config: Option<Self::Configuration>) -> Result<Self, Error> {
+        Self::new(Self::ensure_config_exists(config)?, Metrics::new()?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+    use crate::{endpoint::Endpoint, filters::mitigation, test_utils::assert_write_no_change};
+
+    fn rate_limit(config: Config) -> RateLimit {
+        RateLimit::new(config, Metrics::new().unwrap()).unwrap()
+    }
+
+    fn config(capacity: u64, refill_rate: u64) -> Config {
+        Config {
+            metadata_key: None,
+            capacity,
+            refill_rate,
+            action: Action::Drop,
+            mark_key: DEFAULT_MARK_KEY.into(),
+        }
+    }
+
+    fn context(port: u16, token: Option<&'static str>) -> ReadContext {
+        let mut ctx = ReadContext::new(
+            vec![Endpoint::new((Ipv4Addr::LOCALHOST, 8089).into())],
+            (Ipv4Addr::LOCALHOST, port).into(),
+            vec![9],
+        );
+        if let Some(token) = token {
+            ctx.metadata.insert("token".into(), token.to_owned().into());
+        }
+        ctx
+    }
+
+    #[tokio::test]
+    async fn invalid_config() {
+        for config in [config(0, 1), config(1, 0)] {
+            assert!(RateLimit::new(config, Metrics::new().unwrap()).is_err());
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn limits_each_client() {
+        let filter = rate_limit(config(2, 1));
+        assert_write_no_change(&filter);
+
+        // Each client can send a burst of `capacity` packets.
+        for _ in 0..2 {
+            assert!(filter.read(&mut context(7000, None)).is_some());
+        }
+        assert!(filter.read(&mut context(7000, None)).is_none());
+        assert!(filter.read(&mut context(7001, None)).is_some());
+
+        // Then a packet per second.
+        tokio::time::advance(Duration::from_millis(1100)).await;
+        assert!(filter.read(&mut context(7000, None)).is_some());
+        assert!(filter.read(&mut context(7000, None)).is_none());
+    }
+
+    #[tokio::test]
+    async fn limits_tokens() {
+        let filter = rate_limit(Config {
+            metadata_key: Some("token".into()),
+            ..config(1, 1)
+        });
+
+        // Packets with the same token are limited together, whatever their
+        // source, and packets without one by their source.
+        assert!(filter.read(&mut context(7000, Some("abc"))).is_some());
+        assert!(filter.read(&mut context(7001, Some("abc"))).is_none());
+        assert!(filter.read(&mut context(7001, Some("xyz"))).is_some());
+        assert!(filter.read(&mut context(7001, None)).is_some());
+        assert!(filter.read(&mut context(7001, None)).is_none());
+    }
+
+    #[tokio::test]
+    async fn marks() {
+        let filter = rate_limit(Config {
+            action: Action::Mark,
+            ..config(1, 1)
+        });
+
+        let mut ctx = context(7000, None);
+        assert!(filter.read(&mut ctx).is_some());
+        assert!(ctx.metadata.get(&DEFAULT_MARK_KEY.into()).is_none());
+
+        let mut ctx = context(7000, None);
+        assert!(filter.read(&mut ctx).is_some());
+        assert_eq!(
+            Some(&metadata::Value::Bool(true)),
+            ctx.metadata.get(&DEFAULT_MARK_KEY.into())
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn limits_mitigated_prefixes() {
+        let filter = rate_limit(config(10, 10));
+        let mitigations = mitigation::Mitigations::default();
+        mitigations
+            .apply(vec![mitigation::Mitigation {
+                prefix: "203.0.113.0/24".parse().unwrap(),
+                action: mitigation::Action::RateLimit {
+                    packets_per_second: 1,
+                },
+                ttl_secs: 60,
+            }])
+            .unwrap();
+
+        let packet = |ip: [u8; 4]| {
+            ReadContext::new(
+                vec![Endpoint::new((Ipv4Addr::LOCALHOST, 8089).into())],
+                (ip, 7000).into(),
+                vec![9],
+            )
+            .mitigations(mitigations.clone())
+        };
+        // Every client in the prefix shares the mitigation's limit, whatever
+        // the filter's own limit.
+        assert!(filter.read(&mut packet([203, 0, 113, 1])).is_some());
+        assert!(filter.read(&mut packet([203, 0, 113, 2])).is_none());
+        assert!(filter.read(&mut packet([192, 0, 2, 1])).is_some());
+
+        mitigations.remove(&["203.0.113.0/24".parse().unwrap()]);
+        assert!(filter.read(&mut packet([203, 0, 113, 2])).is_some());
+    }
+
+    #[test]
+    fn convert_proto_config() {
+        let marking = Config {
+            metadata_key: Some("token".into()),
+            action: Action::Mark,
+            ..config(10, 5)
+        };
+        assert_eq!(
+            marking,
+            Config::try_from(proto::RateLimit::from(marking.clone())).unwrap()
+        );
+
+        let defaults = Config::try_from(proto::RateLimit {
+            metadata_key: None,
+            capacity: 10,
+            refill_rate: 5,
+            action: 0,
+            mark_key: None,
+        })
+        .unwrap();
+        assert_eq!(config(10, 5), defaults);
+    }
+}
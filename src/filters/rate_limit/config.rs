@@ -0,0 +1,119 @@
+/*
+ * Copyright 2023 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::convert::TryFrom;
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::{filters::ConvertProtoConfigError, metadata};
+
+use super::proto;
+
+/// The default key of the metadata set on packets over the limit with
+/// [`Action::Mark`].
+pub const DEFAULT_MARK_KEY: &str = "quilkin.dev/rate_limited";
+
+/// Configuration for the [`RateLimit`][super::RateLimit] filter.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize, JsonSchema)]
+pub struct Config {
+    /// The key of a token in the packet's dynamic metadata (such as one set
+    /// by the `Capture` filter) to limit clients by, rather than their source
+    /// address. Packets without the token are limited by their source
+    /// address.
+    #[serde(
+        rename = "metadataKey",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub metadata_key: Option<metadata::Key>,
+    /// The most packets a client can send in a burst, which is the size of
+    /// its bucket of tokens.
+    pub capacity: u64,
+    /// How many tokens are added to each client's bucket per second, which is
+    /// the rate of packets it can sustain.
+    pub refill_rate: u64,
+    /// What happens to packets sent once a client's bucket is empty.
+    #[serde(default)]
+    pub action: Action,
+    /// The key of the metadata set to `true` on packets over the limit with
+    /// the `MARK` action.
+    #[serde(default = "default_mark_key")]
+    pub mark_key: metadata::Key,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq, Serialize, JsonSchema)]
+pub enum Action {
+    /// Packets over the limit are dropped.
+    #[default]
+    #[serde(rename = "DROP")]
+    Drop,
+    /// Packets over the limit are forwarded with `mark_key` set, so that
+    /// later filters, such as `Match`, can decide what to do with them.
+    #[serde(rename = "MARK")]
+    Mark,
+}
+
+fn default_mark_key() -> metadata::Key {
+    metadata::Key::from_static(DEFAULT_MARK_KEY)
+}
+
+impl From<Action> for proto::rate_limit::Action {
+    fn from(action: Action) -> Self {
+        match action {
+            Action::Drop => Self::Drop,
+            Action::Mark => Self::Mark,
+        }
+    }
+}
+
+impl From<proto::rate_limit::Action> for Action {
+    fn from(action: proto::rate_limit::Action) -> Self {
+        match action {
+            proto::rate_limit::Action::Drop => Self::Drop,
+            proto::rate_limit::Action::Mark => Self::Mark,
+        }
+    }
+}
+
+impl From<Config> for proto::RateLimit {
+    fn from(config: Config) -> Self {
+        Self {
+            metadata_key: config.metadata_key.map(|key| key.to_string()),
+            capacity: config.capacity,
+            refill_rate: config.refill_rate,
+            action: proto::rate_limit::Action::from(config.action) as i32,
+            mark_key: Some(config.mark_key.to_string()),
+        }
+    }
+}
+
+impl TryFrom<proto::RateLimit> for Config {
+    type Error = ConvertProtoConfigError;
+
+    fn try_from(p: proto::RateLimit) -> Result<Self, Self::Error> {
+        Ok(Self {
+            action: p.action().into(),
+            metadata_key: p.metadata_key.map(metadata::Key::new),
+            capacity: p.capacity,
+            refill_rate: p.refill_rate,
+            mark_key: p
+                .mark_key
+                .map(metadata::Key::new)
+                .unwrap_or_else(default_mark_key),
+        })
+    }
+}
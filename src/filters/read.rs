@@ -16,12 +16,19 @@
 
 #[cfg(doc)]
 use crate::filters::Filter;
+use std::{collections::HashMap, sync::Arc, time::Duration};
+
 use crate::{
     endpoint::{Endpoint, EndpointAddress},
+    filters::mitigation::Mitigations,
     metadata::DynamicMetadata,
+    proxy::decisions::{self, Step},
+    xds::rate_limit::SharedRateLimits,
 };
 
-/// The input arguments to [`Filter::read`].
+/// The input arguments to [`Filter::read`]. Outside of this crate it's
+/// created with [`ReadContext::new`] and its builder methods, as it's
+/// `#[non_exhaustive]` so that fields can be added to it.
 #[non_exhaustive]
 pub struct ReadContext {
     /// The upstream endpoints that the packet will be forwarded to.
@@ -30,8 +37,39 @@ pub struct ReadContext {
     pub source: EndpointAddress,
     /// Contents of the received packet.
     pub contents: Vec<u8>,
-    /// Arbitrary values that can be passed from one filter to another.
+    /// Arbitrary values that can be passed from one filter to another, read
+    /// by type with [`MetadataExt`][crate::metadata::MetadataExt].
     pub metadata: DynamicMetadata,
+    /// The index of the filter to read the packet again from, once the
+    /// current filter passes it.
+    pub(crate) reprocess: Option<usize>,
+    /// The filters the packet went through, when decisions are recorded.
+    pub(crate) trace: Option<Vec<Step>>,
+    /// The mitigations the packet is checked against, if any.
+    pub(crate) mitigations: Option<Mitigations>,
+    /// The usage of the shared rate limits the packet counts towards, if any.
+    pub(crate) rate_limits: Option<SharedRateLimits>,
+    /// The packets sent after this one, see [`ReadContext::send_additional`].
+    pub(crate) additional: Vec<Vec<u8>>,
+    /// Why the filter chain dropped the packet, once it has.
+    pub(crate) dropped: Option<Dropped>,
+    /// When the endpoints added to the clusters since they were first loaded
+    /// were added, see [`Clusters`][crate::cluster::Clusters].
+    pub(crate) added: Option<Arc<HashMap<EndpointAddress, tokio::time::Instant>>>,
+    /// How long the filter chain may spend on the packet, see
+    /// [`ExecutionBudget`][crate::filters::ExecutionBudget].
+    pub(crate) budget: Option<Duration>,
+}
+
+/// Why the filter chain dropped a packet.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) enum Dropped {
+    /// The filter with this name dropped it.
+    Filter(String),
+    /// The chain ran beyond its execution budget.
+    BudgetExceeded,
+    /// The packet was reprocessed too many times.
+    ReprocessLimit,
 }
 
 impl ReadContext {
@@ -42,6 +80,14 @@ impl ReadContext {
             source,
             contents,
             metadata: DynamicMetadata::new(),
+            reprocess: None,
+            trace: decisions::trace(),
+            mitigations: None,
+            rate_limits: None,
+            additional: Vec::new(),
+            dropped: None,
+            added: None,
+            budget: None,
         }
     }
 
@@ -49,4 +95,71 @@ impl ReadContext {
         self.metadata = metadata;
         self
     }
+
+    /// Sets the mitigations the packet is checked against by the filters
+    /// that apply them.
+    pub fn mitigations(mut self, mitigations: Mitigations) -> Self {
+        self.mitigations = Some(mitigations);
+        self
+    }
+
+    /// Sets when the endpoints added to the clusters were added, which the
+    /// load balancer's slow start ramps up.
+    pub(crate) fn added(
+        mut self,
+        added: Arc<HashMap<EndpointAddress, tokio::time::Instant>>,
+    ) -> Self {
+        self.added = Some(added);
+        self
+    }
+
+    /// When the endpoint at `address` was added, or `None` if it was there
+    /// when the clusters were first loaded.
+    pub(crate) fn added_at(&self, address: &EndpointAddress) -> Option<tokio::time::Instant> {
+        self.added.as_ref()?.get(address).copied()
+    }
+
+    /// Sets how long the filter chain may spend on the packet.
+    pub fn execution_budget(mut self, budget: Option<Duration>) -> Self {
+        self.budget = budget;
+        self
+    }
+
+    /// Sets the usage of the shared rate limits the packet counts towards.
+    pub fn rate_limits(mut self, rate_limits: SharedRateLimits) -> Self {
+        self.rate_limits = Some(rate_limits);
+        self
+    }
+
+    /// Whether the packet's source is blocked by a mitigation.
+    pub(crate) fn mitigation_blocked(&self) -> bool {
+        self.mitigations
+            .as_ref()
+            .map_or(false, |mitigations| mitigations.blocked(&self.source))
+    }
+
+    /// Whether the packet is within the rate limits of the mitigations of its
+    /// source, taking a token from each of them.
+    pub(crate) fn mitigation_admitted(&self) -> bool {
+        self.mitigations
+            .as_ref()
+            .map_or(true, |mitigations| mitigations.admitted(&self.source))
+    }
+
+    /// Marks the packet to be read again by the filter chain, from the filter
+    /// at `index` of the chain, once the current filter passes it. This lets a
+    /// filter that de-encapsulated the packet have the inner payload go
+    /// through the chain again, up to [`MAX_REPROCESSES`] times a packet.
+    ///
+    /// [`MAX_REPROCESSES`]: crate::filters::MAX_REPROCESSES
+    pub fn reprocess_from(&mut self, index: usize) {
+        self.reprocess = Some(index);
+    }
+
+    /// Sends `contents` as another packet to the same endpoints, right after
+    /// this one, if the filter chain passes it. The additional packet doesn't
+    /// go through the rest of the chain.
+    pub fn send_additional(&mut self, contents: Vec<u8>) {
+        self.additional.push(contents);
+    }
 }
@@ -0,0 +1,601 @@
+/*
+ * Copyright 2023 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+mod config;
+mod metrics;
+
+crate::include_proto!("quilkin.filters.reassemble.v1alpha1");
+
+use std::{
+    collections::{hash_map::Entry, HashMap, VecDeque},
+    time::{Duration, Instant},
+};
+
+use parking_lot::Mutex;
+
+use crate::{endpoint::EndpointAddress, filters::prelude::*};
+
+use self::{metrics::Metrics, quilkin::filters::reassemble::v1alpha1 as proto};
+
+pub use self::config::{Config, MAX_MESSAGE_BYTES};
+
+/// The length of the header starting every fragment: the message's sequence
+/// number as two big-endian bytes, the index of the fragment, and the number
+/// of fragments of the message.
+pub const HEADER_LEN: usize = 4;
+
+/// An incomplete message, by the client that sent it and its sequence number.
+type Key = (EndpointAddress, u16);
+
+/// The memory taken by an incomplete message of `count` fragments besides
+/// its payload, so that messages of empty fragments count towards
+/// `max_buffered_bytes` too.
+fn overhead(count: u8) -> usize {
+    usize::from(count) * std::mem::size_of::<Option<Vec<u8>>>()
+        + std::mem::size_of::<(Key, Partial)>()
+        + std::mem::size_of::<(Instant, u64, Key)>()
+}
+
+/// Filter that reassembles the messages clients split across several
+/// packets, each starting with a header numbering its fragment, into a single
+/// packet, so that the filters after it, such as `Compress`, see the whole
+/// message.
+///
+/// The fragments of a message are buffered until the last of them arrives,
+/// in any order. Messages that aren't complete within `timeout_ms` are
+/// dropped, as are the oldest incomplete messages once `max_buffered_bytes`
+/// are buffered. Only packets received from downstream are reassembled,
+/// and packets without a valid header are dropped.
+pub struct Reassemble {
+    timeout: Duration,
+    max_message_bytes: usize,
+    max_buffered_bytes: usize,
+    buffers: Mutex<Buffers>,
+    metrics: Metrics,
+}
+
+/// A packet carrying one fragment of a message.
+#[derive(Debug, PartialEq, Eq)]
+struct Fragment<'a> {
+    sequence: u16,
+    index: u8,
+    count: u8,
+    payload: &'a [u8],
+}
+
+impl<'a> Fragment<'a> {
+    /// Reads the fragment in `packet`, if it starts with a valid header.
+    fn parse(packet: &'a [u8]) -> Option<Self> {
+        let header = packet.get(..HEADER_LEN)?;
+        let fragment = Self {
+            sequence: u16::from_be_bytes([header[0], header[1]]),
+            index: header[2],
+            count: header[3],
+            payload: &packet[HEADER_LEN..],
+        };
+
+        (fragment.index < fragment.count).then_some(fragment)
+    }
+}
+
+/// What became of a fragment.
+#[derive(Debug, PartialEq, Eq)]
+enum Reassembled {
+    /// The fragment completed its message.
+    Complete(Vec<u8>),
+    /// The fragment is buffered until the rest of its message arrives.
+    Buffered,
+    /// The fragment was received before.
+    Duplicate,
+    /// The fragment has a different number of fragments than the fragments
+    /// of its message received before it.
+    Mismatched,
+    /// The fragment's message grew beyond `max_message_bytes`, and was
+    /// dropped.
+    TooLarge,
+}
+
+/// The fragments of every incomplete message.
+#[derive(Default)]
+struct Buffers {
+    messages: HashMap<Key, Partial>,
+    /// The messages by when their first fragment arrived, oldest first, so
+    /// that they expire and are evicted in order. Messages that completed
+    /// are only removed from here as their entry reaches the front.
+    order: VecDeque<(Instant, u64, Key)>,
+    /// The bytes of every fragment buffered, and the overhead of their
+    /// messages.
+    bytes: usize,
+    /// The identifier of the next message buffered, telling it apart in
+    /// `order` from earlier messages with the same key.
+    next_id: u64,
+}
+
+struct Partial {
+    id: u64,
+    fragments: Vec<Option<Vec<u8>>>,
+    received: usize,
+    /// The bytes of the fragments received.
+    bytes: usize,
+    /// The bytes the message takes besides its fragments.
+    overhead: usize,
+}
+
+impl Buffers {
+    /// Adds `fragment` from `source`, received at `now`, to its message,
+    /// returning the message once it's complete.
+    fn add(
+        &mut self,
+        source: &EndpointAddress,
+        fragment: &Fragment,
+        now: Instant,
+        max_message_bytes: usize,
+    ) -> Reassembled {
+        let key = (source.clone(), fragment.sequence);
+        let partial = match self.messages.entry(key.clone()) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => {
+                let id = self.next_id;
+                self.next_id += 1;
+                self.order.push_back((now, id, key.clone()));
+                let overhead = overhead(fragment.count);
+                self.bytes += overhead;
+                entry.insert(Partial {
+                    id,
+                    fragments: vec![None; fragment.count as usize],
+                    received: 0,
+                    bytes: 0,
+                    overhead,
+                })
+            }
+        };
+
+        if partial.fragments.len() != fragment.count as usize {
+            return Reassembled::Mismatched;
+        }
+        let slot = &mut partial.fragments[fragment.index as usize];
+        if slot.is_some() {
+            return Reassembled::Duplicate;
+        }
+
+        if partial.bytes + fragment.payload.len() > max_message_bytes {
+            let id = partial.id;
+            self.remove(&key, id);
+            return Reassembled::TooLarge;
+        }
+
+        *slot = Some(fragment.payload.to_vec());
+        partial.received += 1;
+        partial.bytes += fragment.payload.len();
+        self.bytes += fragment.payload.len();
+        if partial.received < partial.fragments.len() {
+            return Reassembled::Buffered;
+        }
+
+        let partial = self.messages.remove(&key).unwrap();
+        self.bytes -= partial.bytes + partial.overhead;
+        let mut message = Vec::with_capacity(partial.bytes);
+        for fragment in partial.fragments.into_iter().flatten() {
+            message.extend(fragment);
+        }
+        Reassembled::Complete(message)
+    }
+
+    /// Removes the messages whose first fragment arrived before `before`,
+    /// returning how many were removed.
+    fn expire(&mut self, before: Instant) -> usize {
+        let mut expired = 0;
+        while self
+            .order
+            .front()
+            .map_or(false, |(started, ..)| *started < before)
+        {
+            let (_, id, key) = self.order.pop_front().unwrap();
+            expired += usize::from(self.remove(&key, id));
+        }
+        expired
+    }
+
+    /// Removes the oldest message, returning whether there was one.
+    fn evict_oldest(&mut self) -> bool {
+        while let Some((_, id, key)) = self.order.pop_front() {
+            if self.remove(&key, id) {
+                return true;
+            }
+        }
+        false
+    }
+
+    fn remove(&mut self, key: &Key, id: u64) -> bool {
+        if self
+            .messages
+            .get(key)
+            .map_or(true, |partial| partial.id != id)
+        {
+            return false;
+        }
+
+        let partial = self.messages.remove(key).unwrap();
+        self.bytes -= partial.bytes + partial.overhead;
+        true
+    }
+}
+
+impl Reassemble {
+    fn new(config: Config, metrics: Metrics) -> Result<Self, Error> {
+        if config.timeout_ms == 0 {
+            return Err(Error::FieldInvalid {
+                field: "timeout_ms".into(),
+                reason: "value must be at least 1".into(),
+            });
+        }
+        if !(1..=MAX_MESSAGE_BYTES).contains(&config.max_message_bytes) {
+            return Err(Error::FieldInvalid {
+                field: "max_message_bytes".into(),
+                reason: format!("value must be between 1 and {MAX_MESSAGE_BYTES}"),
+            });
+        }
+        if config.max_buffered_bytes < u64::from(config.max_message_bytes) {
+            return Err(Error::FieldInvalid {
+                field: "max_buffered_bytes".into(),
+                reason: "value must be at least `max_message_bytes`".into(),
+            });
+        }
+
+        Ok(Self {
+            timeout: config.timeout(),
+            max_message_bytes: config.max_message_bytes as usize,
+            max_buffered_bytes: usize::try_from(config.max_buffered_bytes).unwrap_or(usize::MAX),
+            buffers: <_>::default(),
+            metrics,
+        })
+    }
+
+    /// Adds `fragment` from `source`, received at `now`, to its message.
+    fn reassemble(
+        &self,
+        source: &EndpointAddress,
+        fragment: &Fragment,
+        now: Instant,
+    ) -> Reassembled {
+        let mut buffers = self.buffers.lock();
+        let buffered = buffers.bytes;
+
+        if let Some(before) = now.checked_sub(self.timeout) {
+            let expired = buffers.expire(before);
+            self.metrics.messages_dropped_timeout.inc_by(expired as u64);
+        }
+
+        // A fragment starting a message also brings the message's overhead.
+        // Evicting every older message makes room for all but the overhead
+        // of a message at most, as the payload of every message fits within
+        // `max_buffered_bytes` on its own.
+        let mut len = fragment.payload.len();
+        if !buffers
+            .messages
+            .contains_key(&(source.clone(), fragment.sequence))
+        {
+            len += overhead(fragment.count);
+        }
+        while buffers.bytes + len > self.max_buffered_bytes && buffers.evict_oldest() {
+            self.metrics.messages_dropped_evicted.inc();
+        }
+
+        let reassembled = buffers.add(source, fragment, now, self.max_message_bytes);
+        match &reassembled {
+            Reassembled::Complete(_) => self.metrics.messages_reassembled_total.inc(),
+            Reassembled::TooLarge => self.metrics.messages_dropped_too_large.inc(),
+            Reassembled::Duplicate => self.metrics.packets_dropped_duplicate.inc(),
+            Reassembled::Mismatched => self.metrics.packets_dropped_invalid.inc(),
+            Reassembled::Buffered => {}
+        }
+
+        self.metrics
+            .buffered_bytes
+            .add(buffers.bytes as i64 - buffered as i64);
+        reassembled
+    }
+}
+
+impl Filter for Reassemble {
+    #[cfg_attr(feature = "instrument", tracing::instrument(skip(self, ctx)))]
+    fn read(&self, ctx: &mut ReadContext) -> Option<()> {
+        let Some(fragment) = Fragment::parse(&ctx.contents) else {
+            tracing::trace!(source = %ctx.source, "Dropping packet without a valid fragment header");
+            self.metrics.packets_dropped_invalid.inc();
+            return None;
+        };
+
+        // Messages that fit in one packet don't need buffering.
+        if fragment.count == 1 {
+            self.metrics.messages_reassembled_total.inc();
+            ctx.contents.drain(..HEADER_LEN);
+            return Some(());
+        }
+
+        match self.reassemble(&ctx.source, &fragment, Instant::now()) {
+            Reassembled::Complete(message) => {
+                ctx.contents = message;
+                Some(())
+            }
+            reassembled => {
+                tracing::trace!(source = %ctx.source, sequence = fragment.sequence, ?reassembled, "Holding back fragment");
+                None
+            }
+        }
+    }
+
+    fn has_write(&self) -> bool {
+        false
+    }
+}
+
+impl Drop for Reassemble {
+    fn drop(&mut self) {
+        self.metrics
+            .buffered_bytes
+            .sub(self.buffers.get_mut().bytes as i64);
+    }
+}
+
+impl StaticFilter for Reassemble {
+    const NAME: &'static str = "quilkin.filters.reassemble.v1alpha1.Reassemble";
+    type Configuration = Config;
+    type BinaryConfiguration = proto::Reassemble;
+
+    fn try_from_config(config: Option<Self::Configuration>) -> Result<Self, Error> {
+        Self::new(config.unwrap_or_default(), Metrics::new()?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+    use crate::{endpoint::Endpoint, test_utils::assert_write_no_change};
+
+    fn reassemble(config: Config) -> Reassemble {
+        Reassemble::new(config, Metrics::new().unwrap()).unwrap()
+    }
+
+    fn packet(sequence: u16, index: u8, count: u8, payload: &[u8]) -> Vec<u8> {
+        let mut packet = sequence.to_be_bytes().to_vec();
+        packet.extend([index, count]);
+        packet.extend(payload);
+        packet
+    }
+
+    fn fragment(sequence: u16, index: u8, count: u8, payload: &[u8]) -> Fragment {
+        Fragment {
+            sequence,
+            index,
+            count,
+            payload,
+        }
+    }
+
+    fn context(contents: Vec<u8>) -> ReadContext {
+        ReadContext::new(
+            vec![Endpoint::new((Ipv4Addr::LOCALHOST, 8089).into())],
+            (Ipv4Addr::LOCALHOST, 7000).into(),
+            contents,
+        )
+    }
+
+    #[test]
+    fn invalid_config() {
+        for config in [
+            Config {
+                timeout_ms: 0,
+                ..<_>::default()
+            },
+            Config {
+                max_message_bytes: 0,
+                ..<_>::default()
+            },
+            Config {
+                max_message_bytes: MAX_MESSAGE_BYTES + 1,
+                ..<_>::default()
+            },
+            Config {
+                max_message_bytes: 1000,
+                max_buffered_bytes: 999,
+                ..<_>::default()
+            },
+        ] {
+            assert!(Reassemble::new(config, Metrics::new().unwrap()).is_err());
+        }
+    }
+
+    #[test]
+    fn parse() {
+        assert_eq!(
+            Some(fragment(258, 1, 2, b"ab")),
+            Fragment::parse(&packet(258, 1, 2, b"ab"))
+        );
+        assert_eq!(
+            Some(fragment(1, 0, 1, b"")),
+            Fragment::parse(&packet(1, 0, 1, b""))
+        );
+        assert_eq!(None, Fragment::parse(&[0, 1, 0]));
+        assert_eq!(None, Fragment::parse(&packet(1, 2, 2, b"ab")));
+        assert_eq!(None, Fragment::parse(&packet(1, 0, 0, b"ab")));
+    }
+
+    #[test]
+    fn read() {
+        let filter = reassemble(Config::default());
+        assert_write_no_change(&filter);
+
+        // Fragments can arrive in any order.
+        assert!(filter.read(&mut context(packet(7, 2, 3, b"!"))).is_none());
+        assert!(filter
+            .read(&mut context(packet(7, 1, 3, b"world")))
+            .is_none());
+        let mut ctx = context(packet(7, 0, 3, b"hello "));
+        assert!(filter.read(&mut ctx).is_some());
+        assert_eq!(b"hello world!", &*ctx.contents);
+        assert_eq!(0, filter.buffers.lock().bytes);
+
+        let mut ctx = context(packet(8, 0, 1, b"whole"));
+        assert!(filter.read(&mut ctx).is_some());
+        assert_eq!(b"whole", &*ctx.contents);
+        assert_eq!(2, filter.metrics.messages_reassembled_total.get());
+
+        assert!(filter.read(&mut context(vec![0, 8])).is_none());
+        assert_eq!(1, filter.metrics.packets_dropped_invalid.get());
+    }
+
+    #[test]
+    fn duplicates() {
+        let filter = reassemble(Config::default());
+        let now = Instant::now();
+        let source = (Ipv4Addr::LOCALHOST, 7000).into();
+        let other = (Ipv4Addr::LOCALHOST, 7001).into();
+
+        assert_eq!(
+            Reassembled::Buffered,
+            filter.reassemble(&source, &fragment(1, 0, 3, b"a"), now)
+        );
+        assert_eq!(
+            Reassembled::Duplicate,
+            filter.reassemble(&source, &fragment(1, 0, 3, b"a"), now)
+        );
+        assert_eq!(
+            Reassembled::Mismatched,
+            filter.reassemble(&source, &fragment(1, 1, 2, b"b"), now)
+        );
+
+        // The messages of each client are kept apart.
+        assert_eq!(
+            Reassembled::Buffered,
+            filter.reassemble(&other, &fragment(1, 1, 2, b"b"), now)
+        );
+        assert_eq!(
+            Reassembled::Complete(b"ab".to_vec()),
+            filter.reassemble(&other, &fragment(1, 0, 2, b"a"), now)
+        );
+        assert_eq!(1, filter.metrics.packets_dropped_duplicate.get());
+        assert_eq!(1, filter.metrics.packets_dropped_invalid.get());
+    }
+
+    #[test]
+    fn timeout() {
+        let filter = reassemble(Config {
+            timeout_ms: 100,
+            ..<_>::default()
+        });
+        let now = Instant::now();
+        let source = (Ipv4Addr::LOCALHOST, 7000).into();
+
+        assert_eq!(
+            Reassembled::Buffered,
+            filter.reassemble(&source, &fragment(1, 0, 2, b"a"), now)
+        );
+        assert_eq!(
+            Reassembled::Buffered,
+            filter.reassemble(
+                &source,
+                &fragment(1, 1, 2, b"b"),
+                now + Duration::from_millis(150)
+            )
+        );
+        assert_eq!(1, filter.metrics.messages_dropped_timeout.get());
+        assert_eq!(1 + overhead(2), filter.buffers.lock().bytes);
+    }
+
+    #[test]
+    fn limits() {
+        let filter = reassemble(Config {
+            max_message_bytes: 4,
+            max_buffered_bytes: 6,
+            ..<_>::default()
+        });
+        let now = Instant::now();
+        let source = (Ipv4Addr::LOCALHOST, 7000).into();
+
+        assert_eq!(
+            Reassembled::Buffered,
+            filter.reassemble(&source, &fragment(1, 0, 3, b"abc"), now)
+        );
+        assert_eq!(
+            Reassembled::TooLarge,
+            filter.reassemble(&source, &fragment(1, 1, 3, b"de"), now)
+        );
+        assert_eq!(0, filter.buffers.lock().bytes);
+
+        // The oldest messages make room for newer ones.
+        assert_eq!(
+            Reassembled::Buffered,
+            filter.reassemble(&source, &fragment(2, 0, 2, b"abcd"), now)
+        );
+        assert_eq!(
+            Reassembled::Buffered,
+            filter.reassemble(&source, &fragment(3, 0, 2, b"abc"), now)
+        );
+        assert_eq!(3 + overhead(2), filter.buffers.lock().bytes);
+        assert_eq!(1, filter.metrics.messages_dropped_too_large.get());
+        assert_eq!(1, filter.metrics.messages_dropped_evicted.get());
+    }
+
+    #[test]
+    fn empty_fragments() {
+        // Empty fragments carry no payload, but their messages still count
+        // towards the limit.
+        let max_buffered_bytes = 64 * 1024;
+        let filter = reassemble(Config {
+            max_message_bytes: 1024,
+            max_buffered_bytes: max_buffered_bytes as u64,
+            ..<_>::default()
+        });
+        let now = Instant::now();
+        let source = (Ipv4Addr::LOCALHOST, 7000).into();
+
+        for sequence in 0..=u16::MAX {
+            assert_eq!(
+                Reassembled::Buffered,
+                filter.reassemble(&source, &fragment(sequence, 0, 255, b""), now)
+            );
+            assert!(filter.buffers.lock().bytes <= max_buffered_bytes);
+        }
+
+        let buffers = filter.buffers.lock();
+        let held = max_buffered_bytes / overhead(255);
+        assert_eq!(held, buffers.messages.len());
+        assert_eq!(
+            (usize::from(u16::MAX) + 1 - held) as u64,
+            filter.metrics.messages_dropped_evicted.get()
+        );
+    }
+
+    #[test]
+    fn convert_proto_config() {
+        let config = Config {
+            timeout_ms: 500,
+            max_message_bytes: 1200,
+            max_buffered_bytes: 1 << 20,
+        };
+        assert_eq!(
+            config,
+            Config::from(proto::Reassemble::from(config.clone()))
+        );
+        assert_eq!(
+            Config::default(),
+            Config::from(proto::Reassemble::default())
+        );
+    }
+}
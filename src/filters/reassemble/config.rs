@@ -0,0 +1,97 @@
+/*
+ * Copyright 2023 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::time::Duration;
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use super::proto;
+
+/// The largest payload of a UDP packet over IPv4, which reassembled messages
+/// have to fit in to be sent on.
+pub const MAX_MESSAGE_BYTES: u32 = 65507;
+
+const DEFAULT_TIMEOUT_MS: u64 = 1000;
+const DEFAULT_MAX_BUFFERED_BYTES: u64 = 16 * 1024 * 1024;
+
+/// Config represents a `Reassemble` filter configuration.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// How long the fragments of an incomplete message are kept, in
+    /// milliseconds from its first fragment, before the message is dropped.
+    #[serde(default = "default_timeout_ms")]
+    pub timeout_ms: u64,
+    /// The largest message reassembled in bytes, messages growing beyond it
+    /// are dropped.
+    #[serde(default = "default_max_message_bytes")]
+    pub max_message_bytes: u32,
+    /// The most bytes of incomplete messages buffered at once across every
+    /// client, beyond which the oldest incomplete messages are dropped. Each
+    /// incomplete message also counts the memory it takes besides its
+    /// fragments, up to about 6 KiB for a message of 255 fragments.
+    #[serde(default = "default_max_buffered_bytes")]
+    pub max_buffered_bytes: u64,
+}
+
+fn default_timeout_ms() -> u64 {
+    DEFAULT_TIMEOUT_MS
+}
+
+fn default_max_message_bytes() -> u32 {
+    MAX_MESSAGE_BYTES
+}
+
+fn default_max_buffered_bytes() -> u64 {
+    DEFAULT_MAX_BUFFERED_BYTES
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            timeout_ms: DEFAULT_TIMEOUT_MS,
+            max_message_bytes: MAX_MESSAGE_BYTES,
+            max_buffered_bytes: DEFAULT_MAX_BUFFERED_BYTES,
+        }
+    }
+}
+
+impl Config {
+    pub(super) fn timeout(&self) -> Duration {
+        Duration::from_millis(self.timeout_ms)
+    }
+}
+
+impl From<Config> for proto::Reassemble {
+    fn from(config: Config) -> Self {
+        Self {
+            timeout_ms: Some(config.timeout_ms),
+            max_message_bytes: Some(config.max_message_bytes),
+            max_buffered_bytes: Some(config.max_buffered_bytes),
+        }
+    }
+}
+
+impl From<proto::Reassemble> for Config {
+    fn from(p: proto::Reassemble) -> Self {
+        Self {
+            timeout_ms: p.timeout_ms.unwrap_or(DEFAULT_TIMEOUT_MS),
+            max_message_bytes: p.max_message_bytes.unwrap_or(MAX_MESSAGE_BYTES),
+            max_buffered_bytes: p.max_buffered_bytes.unwrap_or(DEFAULT_MAX_BUFFERED_BYTES),
+        }
+    }
+}
@@ -0,0 +1,79 @@
+/*
+ * Copyright 2023 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use prometheus::{IntCounter, IntCounterVec, IntGauge, Result as MetricsResult};
+
+use crate::metrics::{filter_opts, CollectorExt};
+
+const REASON_LABEL: &str = "reason";
+
+/// Register and manage metrics for this filter
+pub(super) struct Metrics {
+    pub(super) messages_reassembled_total: IntCounter,
+    pub(super) messages_dropped_timeout: IntCounter,
+    pub(super) messages_dropped_too_large: IntCounter,
+    pub(super) messages_dropped_evicted: IntCounter,
+    pub(super) packets_dropped_invalid: IntCounter,
+    pub(super) packets_dropped_duplicate: IntCounter,
+    pub(super) buffered_bytes: IntGauge,
+}
+
+impl Metrics {
+    pub(super) fn new() -> MetricsResult<Self> {
+        let messages_dropped = IntCounterVec::new(
+            filter_opts(
+                "messages_dropped_total",
+                "Reassemble",
+                "Total number of incomplete messages dropped. Labels: reason.",
+            ),
+            &[REASON_LABEL],
+        )?
+        .register_if_not_exists()?;
+        let packets_dropped = IntCounterVec::new(
+            filter_opts(
+                "packets_dropped_total",
+                "Reassemble",
+                "Total number of packets dropped without being buffered. Labels: reason.",
+            ),
+            &[REASON_LABEL],
+        )?
+        .register_if_not_exists()?;
+
+        Ok(Self {
+            messages_reassembled_total: IntCounter::with_opts(filter_opts(
+                "messages_reassembled_total",
+                "Reassemble",
+                "Total number of messages reassembled from their fragments.",
+            ))?
+            .register_if_not_exists()?,
+            messages_dropped_timeout: messages_dropped
+                .get_metric_with_label_values(&["timeout"])?,
+            messages_dropped_too_large: messages_dropped
+                .get_metric_with_label_values(&["too_large"])?,
+            messages_dropped_evicted: messages_dropped
+                .get_metric_with_label_values(&["evicted"])?,
+            packets_dropped_invalid: packets_dropped.get_metric_with_label_values(&["invalid"])?,
+            packets_dropped_duplicate: packets_dropped
+                .get_metric_with_label_values(&["duplicate"])?,
+            buffered_bytes: IntGauge::with_opts(filter_opts(
+                "buffered_bytes",
+                "Reassemble",
+                "Bytes of incomplete messages currently buffered.",
+            ))?
+            .register_if_not_exists()?,
+        })
+    }
+}
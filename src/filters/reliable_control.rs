@@ -0,0 +1,613 @@
+/*
+ * Copyright 2023 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+mod config;
+
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use prometheus::{IntCounter, IntCounterVec};
+
+use crate::{
+    endpoint::EndpointAddress,
+    filters::prelude::*,
+    ttl_map::{Entry, TtlMap},
+};
+
+crate::include_proto!("quilkin.filters.reliable_control.v1alpha1");
+use self::quilkin::filters::reliable_control::v1alpha1 as proto;
+
+pub use config::{Config, Peer};
+
+/// How long a client's channel lasts without traffic.
+const CHANNEL_TIMEOUT: Duration = Duration::from_secs(60);
+/// How often expired channels are removed.
+const CHANNEL_EXPIRY_POLL_INTERVAL: Duration = Duration::from_secs(60);
+/// The most bytes of each client's messages waiting for room in the window.
+const MAX_QUEUED_BYTES: usize = 256 * 1024;
+
+/// The frame has the sequence number of the next message its sender expects
+/// from its peer, acknowledging every message before it.
+const ACK: u8 = 1;
+/// The frame has a message rather than the rest of the traffic.
+const MESSAGE: u8 = 1 << 1;
+
+const SENT: &str = "sent";
+const RETRANSMITTED: &str = "retransmitted";
+const ACKNOWLEDGED: &str = "acknowledged";
+const DELIVERED: &str = "delivered";
+const DUPLICATE: &str = "duplicate";
+const OUT_OF_ORDER: &str = "out_of_order";
+const QUEUED: &str = "queued";
+const QUEUE_FULL: &str = "queue_full";
+
+fn messages_total(event: &str) -> IntCounter {
+    static MESSAGES_TOTAL: Lazy<IntCounterVec> = Lazy::new(|| {
+        prometheus::register_int_counter_vec_with_registry! {
+            crate::metrics::filter_opts(
+                "messages_total",
+                "ReliableControl",
+                "Total number of control messages by what happened to them. Labels: event.",
+            ),
+            &["event"],
+            crate::metrics::registry(),
+        }
+        .unwrap()
+    });
+
+    MESSAGES_TOTAL.with_label_values(&[event])
+}
+
+fn invalid_packets_total() -> &'static IntCounter {
+    static INVALID_PACKETS_TOTAL: Lazy<IntCounter> = Lazy::new(|| {
+        crate::metrics::register(
+            IntCounter::with_opts(crate::metrics::filter_opts(
+                "invalid_packets_total",
+                "ReliableControl",
+                "Total number of packets from the peer dropped as they weren't valid frames.",
+            ))
+            .unwrap(),
+        )
+    });
+
+    &INVALID_PACKETS_TOTAL
+}
+
+/// Sends the packets starting with a prefix reliably and in order to the
+/// other proxy of a pair, using sequence numbers, acknowledgements and
+/// retransmissions, while the rest of the traffic is sent as it is.
+///
+/// Both proxies of the pair wrap every packet they send each other in a
+/// frame, which acknowledges the messages received from the other proxy,
+/// and which carries either a message or a packet of the rest of the
+/// traffic. Acknowledgements ride on the next packet to the peer, while
+/// messages being retransmitted, or that waited for room in the window, are
+/// sent in frames of their own after it, so they're only sent while there's
+/// traffic.
+pub struct ReliableControl {
+    config: Config,
+    retransmit_timeout: Duration,
+    /// The channel with each client, keyed by the client's address.
+    channels: TtlMap<EndpointAddress, Mutex<Channel>>,
+}
+
+/// The state of the messages sent to and received from a client's peer.
+#[derive(Debug, Default)]
+struct Channel {
+    /// The sequence number of the next message sent.
+    next_seq: u32,
+    /// The messages sent that haven't been acknowledged, oldest first.
+    unacked: VecDeque<Pending>,
+    /// The messages waiting for room in the window, oldest first.
+    queued: VecDeque<Vec<u8>>,
+    /// The total length of the queued messages.
+    queued_bytes: usize,
+    /// The sequence number of the next message delivered from the peer.
+    expected: u32,
+    /// Whether a message was received since the last acknowledgement.
+    ack_pending: bool,
+}
+
+#[derive(Debug)]
+struct Pending {
+    seq: u32,
+    contents: Vec<u8>,
+    sent_at: Instant,
+}
+
+/// A packet between the proxies of a pair.
+#[derive(Debug, PartialEq, Eq)]
+struct Frame<'a> {
+    ack: Option<u32>,
+    body: Body<'a>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Body<'a> {
+    /// A message and its sequence number.
+    Message(u32, &'a [u8]),
+    /// A packet of the rest of the traffic.
+    Data(&'a [u8]),
+}
+
+impl<'a> Frame<'a> {
+    fn encode(&self) -> Vec<u8> {
+        let (flags, header_len, payload) = match self.body {
+            Body::Message(_, message) => (MESSAGE, 4, message),
+            Body::Data(data) => (0, 0, data),
+        };
+        let mut bytes = Vec::with_capacity(5 + header_len + payload.len());
+        bytes.push(flags | self.ack.map_or(0, |_| ACK));
+        if let Some(ack) = self.ack {
+            bytes.extend_from_slice(&ack.to_be_bytes());
+        }
+        if let Body::Message(seq, _) = self.body {
+            bytes.extend_from_slice(&seq.to_be_bytes());
+        }
+        bytes.extend_from_slice(payload);
+        bytes
+    }
+
+    /// Decodes `bytes`, returning `None` if it isn't a valid frame.
+    fn decode(bytes: &'a [u8]) -> Option<Self> {
+        fn take_u32(bytes: &mut &[u8]) -> Option<u32> {
+            let taken = bytes.get(..4)?;
+            *bytes = &bytes[4..];
+            Some(u32::from_be_bytes(taken.try_into().ok()?))
+        }
+
+        let (&flags, mut bytes) = bytes.split_first()?;
+        if flags & !(ACK | MESSAGE) != 0 {
+            return None;
+        }
+
+        let ack = if flags & ACK != 0 {
+            Some(take_u32(&mut bytes)?)
+        } else {
+            None
+        };
+        let body = if flags & MESSAGE != 0 {
+            Body::Message(take_u32(&mut bytes)?, bytes)
+        } else {
+            Body::Data(bytes)
+        };
+
+        Some(Self { ack, body })
+    }
+}
+
+/// Whether the sequence number `lhs` comes before `rhs`, allowing for them
+/// wrapping around.
+fn precedes(lhs: u32, rhs: u32) -> bool {
+    (rhs.wrapping_sub(lhs) as i32) > 0
+}
+
+impl ReliableControl {
+    fn new(config: Config) -> Result<Self, Error> {
+        if config.prefix.is_empty() {
+            return Err(Error::FieldInvalid {
+                field: "prefix".into(),
+                reason: "value must not be empty".into(),
+            });
+        }
+        if config.window == 0 {
+            return Err(Error::FieldInvalid {
+                field: "window".into(),
+                reason: "value must be at least 1".into(),
+            });
+        }
+
+        Ok(Self {
+            retransmit_timeout: config.retransmit_timeout(),
+            config,
+            channels: TtlMap::new(CHANNEL_TIMEOUT, CHANNEL_EXPIRY_POLL_INTERVAL),
+        })
+    }
+
+    fn with_channel<T>(&self, client: &EndpointAddress, f: impl FnOnce(&mut Channel) -> T) -> T {
+        if let Some(channel) = self.channels.get(client) {
+            return f(&mut channel.value.lock());
+        }
+
+        match self.channels.entry(client.clone()) {
+            Entry::Occupied(entry) => f(&mut entry.get().value.lock()),
+            Entry::Vacant(entry) => f(&mut entry.insert(<_>::default()).value.lock()),
+        }
+    }
+
+    /// Wraps `contents`, which is being sent to `client`'s peer, in a frame,
+    /// adding the frames of the messages due to be sent along with it to
+    /// `additional`. Messages wait in a queue while the window is full, and
+    /// a message packet is dropped if there isn't any message to send in its
+    /// place yet.
+    fn send(
+        &self,
+        client: &EndpointAddress,
+        contents: &mut Vec<u8>,
+        additional: &mut Vec<Vec<u8>>,
+    ) -> Option<()> {
+        let now = Instant::now();
+        let is_message = contents.starts_with(&self.config.prefix);
+        self.with_channel(client, |channel| {
+            if is_message {
+                if channel.queued_bytes + contents.len() > MAX_QUEUED_BYTES {
+                    messages_total(QUEUE_FULL).inc();
+                    return None;
+                }
+                channel.queued_bytes += contents.len();
+                channel.queued.push_back(std::mem::take(contents));
+            }
+
+            let mut frames = Vec::new();
+            // Only the oldest message is retransmitted, as the peer drops
+            // the messages after a missing one.
+            if let Some(pending) = channel
+                .unacked
+                .front_mut()
+                .filter(|pending| now.duration_since(pending.sent_at) >= self.retransmit_timeout)
+            {
+                pending.sent_at = now;
+                messages_total(RETRANSMITTED).inc();
+                frames.push((pending.seq, pending.contents.clone()));
+            }
+
+            while channel.unacked.len() < self.config.window as usize {
+                let Some(message) = channel.queued.pop_front() else {
+                    break;
+                };
+                channel.queued_bytes -= message.len();
+                let seq = channel.next_seq;
+                channel.next_seq = seq.wrapping_add(1);
+                frames.push((seq, message.clone()));
+                channel.unacked.push_back(Pending {
+                    seq,
+                    contents: message,
+                    sent_at: now,
+                });
+                messages_total(SENT).inc();
+            }
+            if !channel.queued.is_empty() && is_message {
+                messages_total(QUEUED).inc();
+            }
+
+            let mut frames = frames.into_iter();
+            let ack = std::mem::take(&mut channel.ack_pending).then_some(channel.expected);
+            if is_message {
+                let Some((seq, message)) = frames.next() else {
+                    // The acknowledgement waits for the next packet.
+                    channel.ack_pending = ack.is_some();
+                    return None;
+                };
+                *contents = Frame {
+                    ack,
+                    body: Body::Message(seq, &message),
+                }
+                .encode();
+            } else {
+                let frame = Frame {
+                    ack,
+                    body: Body::Data(contents),
+                }
+                .encode();
+                *contents = frame;
+            }
+
+            additional.extend(frames.map(|(seq, message)| {
+                Frame {
+                    ack: None,
+                    body: Body::Message(seq, &message),
+                }
+                .encode()
+            }));
+            Some(())
+        })
+    }
+
+    /// Unwraps the frame in `contents`, which was received from `client`'s
+    /// peer, into the message it carries if it's the next one, or the packet
+    /// of the rest of the traffic.
+    fn receive(&self, client: &EndpointAddress, contents: &mut Vec<u8>) -> Option<()> {
+        let Some(frame) = Frame::decode(contents) else {
+            invalid_packets_total().inc();
+            return None;
+        };
+
+        let delivered = self.with_channel(client, |channel| {
+            if let Some(ack) = frame.ack {
+                while let Some(pending) = channel.unacked.front() {
+                    if !precedes(pending.seq, ack) {
+                        break;
+                    }
+                    channel.unacked.pop_front();
+                    messages_total(ACKNOWLEDGED).inc();
+                }
+            }
+
+            let (seq, message) = match frame.body {
+                Body::Message(seq, message) => (seq, message),
+                Body::Data(data) => return Some(data.to_vec()),
+            };
+            // Every message is acknowledged, even duplicates, as they mean
+            // that the last acknowledgement was lost.
+            channel.ack_pending = true;
+            if seq != channel.expected {
+                let event = if precedes(seq, channel.expected) {
+                    DUPLICATE
+                } else {
+                    OUT_OF_ORDER
+                };
+                messages_total(event).inc();
+                return None;
+            }
+
+            channel.expected = seq.wrapping_add(1);
+            messages_total(DELIVERED).inc();
+            Some(message.to_vec())
+        })?;
+
+        *contents = delivered;
+        Some(())
+    }
+}
+
+impl Filter for ReliableControl {
+    #[cfg_attr(feature = "instrument", tracing::instrument(skip(self, ctx)))]
+    fn read(&self, ctx: &mut ReadContext) -> Option<()> {
+        match self.config.peer {
+            Peer::Upstream => self.send(&ctx.source, &mut ctx.contents, &mut ctx.additional),
+            Peer::Downstream => self.receive(&ctx.source, &mut ctx.contents),
+        }
+    }
+
+    #[cfg_attr(feature = "instrument", tracing::instrument(skip(self, ctx)))]
+    fn write(&self, ctx: &mut WriteContext) -> Option<()> {
+        match self.config.peer {
+            Peer::Upstream => self.receive(&ctx.dest, &mut ctx.contents),
+            Peer::Downstream => self.send(&ctx.dest, &mut ctx.contents, &mut ctx.additional),
+        }
+    }
+}
+
+impl StaticFilter for ReliableControl {
+    const NAME: &'static str = "quilkin.filters.reliable_control.v1alpha1.ReliableControl";
+    type Configuration = Config;
+    type BinaryConfiguration = proto::ReliableControl;
+
+    fn try_from_config(config: Option<Self::Configuration>) -> Result<Self, Error> {
+        Self::new(Self::ensure_config_exists(config)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+    use crate::endpoint::Endpoint;
+
+    const PREFIX: &[u8] = b"CTRL";
+
+    fn filter(peer: Peer) -> ReliableControl {
+        ReliableControl::from_config(
+            Config {
+                retransmit_timeout_ms: 0,
+                ..Config::new(PREFIX, peer)
+            }
+            .into(),
+        )
+    }
+
+    fn client() -> EndpointAddress {
+        (Ipv4Addr::LOCALHOST, 9000).into()
+    }
+
+    /// Sends `contents` from the client through the client's proxy, returning
+    /// every packet sent.
+    fn read(filter: &ReliableControl, contents: &[u8]) -> Vec<Vec<u8>> {
+        let mut ctx = ReadContext::new(vec![], client(), contents.to_vec());
+        match filter.read(&mut ctx) {
+            Some(()) => std::iter::once(ctx.contents)
+                .chain(ctx.additional)
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+
+    /// Sends `contents` from the server back to the client, returning every
+    /// packet sent.
+    fn write(filter: &ReliableControl, contents: &[u8]) -> Vec<Vec<u8>> {
+        let server: EndpointAddress = (Ipv4Addr::LOCALHOST, 7001).into();
+        let mut ctx = WriteContext::new(
+            Endpoint::new(server.clone()),
+            server,
+            client(),
+            contents.to_vec(),
+        );
+        match filter.write(&mut ctx) {
+            Some(()) => std::iter::once(ctx.contents)
+                .chain(ctx.additional)
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+
+    /// Passes the `packets` from one proxy through the other, returning what
+    /// was delivered.
+    fn deliver(packets: Vec<Vec<u8>>, mut f: impl FnMut(&[u8]) -> Vec<Vec<u8>>) -> Vec<Vec<u8>> {
+        packets.iter().flat_map(|packet| f(packet)).collect()
+    }
+
+    #[test]
+    fn frame() {
+        for frame in [
+            Frame {
+                ack: Some(7),
+                body: Body::Message(3, b"CTRL result"),
+            },
+            Frame {
+                ack: None,
+                body: Body::Data(b"data"),
+            },
+        ] {
+            assert_eq!(Some(&frame), Frame::decode(&frame.encode()).as_ref());
+        }
+        assert_eq!(
+            Some(Frame {
+                ack: None,
+                body: Body::Data(b"data"),
+            }),
+            Frame::decode(b"\0data")
+        );
+
+        assert_eq!(None, Frame::decode(b"\x03\0\0\0\x07\0\0"));
+        assert_eq!(None, Frame::decode(b""));
+        assert_eq!(None, Frame::decode(b"\x04data"));
+        assert!(precedes(u32::MAX, 0));
+        assert!(!precedes(1, 1));
+    }
+
+    #[test]
+    fn new() {
+        assert!(ReliableControl::new(Config::new(Vec::new(), Peer::Upstream)).is_err());
+        assert!(ReliableControl::new(Config {
+            window: 0,
+            ..Config::new(PREFIX, Peer::Upstream)
+        })
+        .is_err());
+    }
+
+    #[test]
+    fn retransmits_until_acknowledged() {
+        let client_proxy = filter(Peer::Upstream);
+        let server_proxy = filter(Peer::Downstream);
+
+        // The first message is lost, and retransmitted after the next packet,
+        // which is delivered as well.
+        assert_eq!(1, read(&client_proxy, b"CTRL one").len());
+        let packets = read(&client_proxy, b"tick 1");
+        assert_eq!(
+            vec![b"tick 1".to_vec(), b"CTRL one".to_vec()],
+            deliver(packets, |packet| read(&server_proxy, packet))
+        );
+
+        // Its acknowledgement hasn't reached the client's proxy yet, so it's
+        // retransmitted again, and the duplicate is dropped.
+        let packets = read(&client_proxy, b"tick 2");
+        assert_eq!(2, packets.len());
+        assert_eq!(
+            vec![b"tick 2".to_vec()],
+            deliver(packets, |packet| read(&server_proxy, packet))
+        );
+
+        // The acknowledgement rides on the server's next packet.
+        let packets = write(&server_proxy, b"reply 1");
+        assert_eq!(
+            vec![b"reply 1".to_vec()],
+            deliver(packets, |packet| write(&client_proxy, packet))
+        );
+        assert_eq!(vec![b"\0tick 3".to_vec()], read(&client_proxy, b"tick 3"));
+    }
+
+    #[test]
+    fn delivers_in_order() {
+        let client_proxy = filter(Peer::Upstream);
+        let server_proxy = filter(Peer::Downstream);
+
+        // The second message is lost, so the third one is dropped.
+        let first = read(&client_proxy, b"CTRL one");
+        read(&client_proxy, b"CTRL two");
+        let third = read(&client_proxy, b"CTRL three");
+        // The first message was retransmitted ahead of the third one.
+        assert_eq!(2, third.len());
+        assert_eq!(
+            vec![b"CTRL one".to_vec()],
+            deliver(first, |packet| read(&server_proxy, packet))
+        );
+        assert_eq!(
+            Vec::<Vec<u8>>::new(),
+            deliver(vec![third[1].clone()], |packet| read(&server_proxy, packet))
+        );
+
+        // Once the first message is acknowledged, the second one is
+        // retransmitted, followed by the third one once the second one is.
+        let packets = write(&server_proxy, b"reply");
+        deliver(packets, |packet| write(&client_proxy, packet));
+        let packets = read(&client_proxy, b"tick");
+        assert_eq!(
+            vec![b"tick".to_vec(), b"CTRL two".to_vec()],
+            deliver(packets, |packet| read(&server_proxy, packet))
+        );
+        let packets = write(&server_proxy, b"reply");
+        deliver(packets, |packet| write(&client_proxy, packet));
+        let packets = read(&client_proxy, b"tick");
+        assert_eq!(
+            vec![b"tick".to_vec(), b"CTRL three".to_vec()],
+            deliver(packets, |packet| read(&server_proxy, packet))
+        );
+    }
+
+    #[test]
+    fn window() {
+        let client_proxy = ReliableControl::from_config(
+            Config {
+                window: 1,
+                retransmit_timeout_ms: 60_000,
+                ..Config::new(PREFIX, Peer::Upstream)
+            }
+            .into(),
+        );
+        let server_proxy = ReliableControl::from_config(
+            Config {
+                window: 1,
+                ..Config::new(PREFIX, Peer::Downstream)
+            }
+            .into(),
+        );
+
+        // The second message waits for the first one to be acknowledged.
+        let first = read(&client_proxy, b"CTRL one");
+        assert_eq!(1, first.len());
+        assert!(read(&client_proxy, b"CTRL two").is_empty());
+        assert_eq!(1, read(&client_proxy, b"tick").len());
+        deliver(first, |packet| read(&server_proxy, packet));
+
+        let packets = write(&server_proxy, b"reply");
+        deliver(packets, |packet| write(&client_proxy, packet));
+        let packets = read(&client_proxy, b"tick");
+        assert_eq!(
+            vec![b"tick".to_vec(), b"CTRL two".to_vec()],
+            deliver(packets, |packet| read(&server_proxy, packet))
+        );
+
+        // Messages are only dropped once the queue is full.
+        let message = [PREFIX, &[0; 1024][..]].concat();
+        let queued = (0..MAX_QUEUED_BYTES / message.len())
+            .filter(|_| read(&client_proxy, &message).is_empty())
+            .count();
+        assert_eq!(MAX_QUEUED_BYTES / message.len(), queued);
+        assert!(read(&client_proxy, &message).is_empty());
+        assert_eq!(
+            queued,
+            client_proxy.with_channel(&client(), |channel| channel.queued.len())
+        );
+
+        assert!(write(&client_proxy, b"\x04invalid").is_empty());
+    }
+}
@@ -0,0 +1,140 @@
+/*
+ * Copyright 2023 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::time::Duration;
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use super::proto;
+use crate::config::Base64Standard;
+
+const DEFAULT_RETRANSMIT_TIMEOUT_MS: u64 = 200;
+const DEFAULT_WINDOW: u32 = 32;
+
+/// Which side of the proxy the other proxy of the pair is on.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq, Serialize, JsonSchema)]
+pub enum Peer {
+    /// The other proxy is an upstream endpoint, as with a proxy next to the
+    /// game clients, so messages from clients are sent reliably.
+    #[default]
+    #[serde(rename = "UPSTREAM")]
+    Upstream,
+    /// The other proxy is a downstream client, as with a proxy next to the
+    /// game servers, so messages from servers are sent reliably.
+    #[serde(rename = "DOWNSTREAM")]
+    Downstream,
+}
+
+impl From<Peer> for proto::reliable_control::Peer {
+    fn from(peer: Peer) -> Self {
+        match peer {
+            Peer::Upstream => Self::Upstream,
+            Peer::Downstream => Self::Downstream,
+        }
+    }
+}
+
+impl From<proto::reliable_control::Peer> for Peer {
+    fn from(peer: proto::reliable_control::Peer) -> Self {
+        match peer {
+            proto::reliable_control::Peer::Upstream => Self::Upstream,
+            proto::reliable_control::Peer::Downstream => Self::Downstream,
+        }
+    }
+}
+
+impl From<Peer> for proto::reliable_control::PeerValue {
+    fn from(peer: Peer) -> Self {
+        Self {
+            value: proto::reliable_control::Peer::from(peer) as i32,
+        }
+    }
+}
+
+/// Config represents a `ReliableControl` filter configuration.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+#[non_exhaustive]
+pub struct Config {
+    /// The base64 encoded prefix of the control messages sent reliably, the
+    /// rest of the traffic is sent as it is.
+    #[serde(with = "Base64Standard")]
+    #[schemars(with = "String")]
+    pub prefix: Vec<u8>,
+    /// Which side of the proxy the other proxy of the pair is on.
+    #[serde(default)]
+    pub peer: Peer,
+    /// How long a message waits for its acknowledgement before it's sent
+    /// again, after the next packet to the peer.
+    #[serde(default = "default_retransmit_timeout_ms")]
+    pub retransmit_timeout_ms: u64,
+    /// The most messages of each client waiting for their acknowledgement,
+    /// further messages are queued until older ones are acknowledged.
+    #[serde(default = "default_window")]
+    pub window: u32,
+}
+
+fn default_retransmit_timeout_ms() -> u64 {
+    DEFAULT_RETRANSMIT_TIMEOUT_MS
+}
+
+fn default_window() -> u32 {
+    DEFAULT_WINDOW
+}
+
+impl Config {
+    pub fn new(prefix: impl Into<Vec<u8>>, peer: Peer) -> Self {
+        Self {
+            prefix: prefix.into(),
+            peer,
+            retransmit_timeout_ms: DEFAULT_RETRANSMIT_TIMEOUT_MS,
+            window: DEFAULT_WINDOW,
+        }
+    }
+
+    pub(super) fn retransmit_timeout(&self) -> Duration {
+        Duration::from_millis(self.retransmit_timeout_ms)
+    }
+}
+
+impl From<Config> for proto::ReliableControl {
+    fn from(config: Config) -> Self {
+        Self {
+            prefix: config.prefix,
+            peer: Some(config.peer.into()),
+            retransmit_timeout_ms: Some(config.retransmit_timeout_ms),
+            window: Some(config.window),
+        }
+    }
+}
+
+impl From<proto::ReliableControl> for Config {
+    fn from(p: proto::ReliableControl) -> Self {
+        Self {
+            prefix: p.prefix,
+            peer: p
+                .peer
+                .map(|peer| peer.value())
+                .map(Peer::from)
+                .unwrap_or_default(),
+            retransmit_timeout_ms: p
+                .retransmit_timeout_ms
+                .unwrap_or(DEFAULT_RETRANSMIT_TIMEOUT_MS),
+            window: p.window.unwrap_or(DEFAULT_WINDOW),
+        }
+    }
+}
@@ -0,0 +1,126 @@
+/*
+ * Copyright 2023 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Replay protection for filters authenticating packets with a counter.
+
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// How many of the counters below the highest one received from a sender
+/// are still accepted, for packets reordered on the way.
+pub const REPLAY_WINDOW: u64 = 64;
+
+/// The counter of the packets a filter authenticates, which is the
+/// wall-clock time in nanoseconds unless packets are sent faster than that,
+/// so that counters aren't reused after a restart.
+#[derive(Debug, Default)]
+pub struct Counter(AtomicU64);
+
+impl Counter {
+    /// Returns the counter of the next packet.
+    pub fn next(&self) -> u64 {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_nanos() as u64);
+        let previous = self
+            .0
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |counter| {
+                Some(now.max(counter + 1))
+            })
+            .unwrap();
+        now.max(previous + 1)
+    }
+}
+
+/// Tracks the counters received from a sender, so that each counter is
+/// only accepted once.
+#[derive(Debug, Default, PartialEq)]
+pub struct ReplayWindow {
+    /// The highest counter received.
+    highest: u64,
+    /// Which of the [`REPLAY_WINDOW`] counters up to `highest` were
+    /// received, with `highest` as the lowest bit.
+    received: u64,
+}
+
+impl ReplayWindow {
+    /// Whether `counter` wasn't received yet and isn't too far behind the
+    /// highest counter to tell.
+    pub fn is_fresh(&self, counter: u64) -> bool {
+        if self.received == 0 || counter > self.highest {
+            return true;
+        }
+        let behind = self.highest - counter;
+        behind < REPLAY_WINDOW && self.received & (1 << behind) == 0
+    }
+
+    /// Records that `counter` was received, after it was authenticated.
+    pub fn receive(&mut self, counter: u64) {
+        if self.received == 0 {
+            self.highest = counter;
+            self.received = 1;
+        } else if counter > self.highest {
+            let ahead = counter - self.highest;
+            self.received = if ahead < REPLAY_WINDOW {
+                self.received << ahead
+            } else {
+                0
+            } | 1;
+            self.highest = counter;
+        } else {
+            self.received |= 1 << (self.highest - counter);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counter() {
+        let counter = Counter::default();
+        let first = counter.next();
+        assert!(first > 0);
+        assert!(counter.next() > first);
+    }
+
+    #[test]
+    fn replay_window() {
+        let mut window = ReplayWindow::default();
+        assert!(window.is_fresh(100));
+        window.receive(100);
+        assert!(!window.is_fresh(100));
+        assert!(window.is_fresh(99));
+        assert!(window.is_fresh(101));
+
+        window.receive(100 + REPLAY_WINDOW - 1);
+        assert!(!window.is_fresh(100));
+        assert!(window.is_fresh(101));
+        window.receive(100 + REPLAY_WINDOW);
+        // Counters too far behind can't be told apart from replays.
+        assert!(!window.is_fresh(100));
+        assert!(!window.is_fresh(100 - 1));
+        assert!(window.is_fresh(101));
+
+        window.receive(100 + REPLAY_WINDOW * 3);
+        assert!(!window.is_fresh(100 + REPLAY_WINDOW * 3));
+        assert!(!window.is_fresh(101));
+        assert!(window.is_fresh(100 + REPLAY_WINDOW * 2 + 1));
+    }
+}
@@ -0,0 +1,208 @@
+/*
+ * Copyright 2023 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+mod config;
+mod metrics;
+
+crate::include_proto!("quilkin.filters.response_validation.v1alpha1");
+
+use crate::filters::prelude::*;
+
+use self::{metrics::Metrics, quilkin::filters::response_validation::v1alpha1 as proto};
+
+pub use self::config::{Config, MessageType};
+
+/// Filter that drops packets sent back to clients by endpoints unless they're
+/// structurally valid, by their size, the magic bytes they start with and the
+/// type of their message, so that clients are shielded from a compromised or
+/// buggy game server. Only packets sent to clients are validated.
+pub struct ResponseValidation {
+    config: Config,
+    metrics: Metrics,
+}
+
+/// Why a packet is malformed.
+#[derive(Debug, PartialEq, Eq)]
+enum Invalid {
+    TooSmall,
+    TooLarge,
+    Magic,
+    MessageType,
+}
+
+impl ResponseValidation {
+    fn new(config: Config, metrics: Metrics) -> Result<Self, Error> {
+        if config.max_size.map_or(false, |max| max < config.min_size) {
+            return Err(Error::FieldInvalid {
+                field: "max_size".into(),
+                reason: "value must be at least `min_size`".into(),
+            });
+        }
+        if config
+            .message_type
+            .as_ref()
+            .map_or(false, |message_type| message_type.allowed.is_empty())
+        {
+            return Err(Error::FieldInvalid {
+                field: "message_type.allowed".into(),
+                reason: "at least one message type is required".into(),
+            });
+        }
+
+        Ok(Self { config, metrics })
+    }
+
+    fn validate(&self, packet: &[u8]) -> Result<(), Invalid> {
+        if packet.len() < self.config.min_size as usize {
+            return Err(Invalid::TooSmall);
+        }
+        if self
+            .config
+            .max_size
+            .map_or(false, |max| packet.len() > max as usize)
+        {
+            return Err(Invalid::TooLarge);
+        }
+        if !packet.starts_with(&self.config.magic) {
+            return Err(Invalid::Magic);
+        }
+        if let Some(message_type) = &self.config.message_type {
+            match packet.get(message_type.offset as usize) {
+                Some(found) if message_type.allowed.contains(found) => {}
+                _ => return Err(Invalid::MessageType),
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Filter for ResponseValidation {
+    #[cfg_attr(feature = "instrument", tracing::instrument(skip(self, ctx)))]
+    fn write(&self, ctx: &mut WriteContext) -> Option<()> {
+        let Err(invalid) = self.validate(&ctx.contents) else {
+            return Some(());
+        };
+
+        tracing::trace!(source = %ctx.source, dest = %ctx.dest, reason = ?invalid, "Dropping malformed packet from upstream");
+        match invalid {
+            Invalid::TooSmall => self.metrics.packets_dropped_too_small.inc(),
+            Invalid::TooLarge => self.metrics.packets_dropped_too_large.inc(),
+            Invalid::Magic => self.metrics.packets_dropped_magic.inc(),
+            Invalid::MessageType => self.metrics.packets_dropped_message_type.inc(),
+        }
+        None
+    }
+}
+
+impl StaticFilter for ResponseValidation {
+    const NAME: &'static str = "quilkin.filters.response_validation.v1alpha1.ResponseValidation";
+    type Configuration = Config;
+    type BinaryConfiguration = proto::ResponseValidation;
+
+    fn try_from_config(config: Option<Self::Configuration>) -> Result<Self, Error> {
+        Self::new(Self::ensure_config_exists(config)?, Metrics::new()?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::assert_filter_read_no_change;
+
+    fn config() -> Config {
+        Config {
+            min_size: 4,
+            max_size: Some(8),
+            magic: b"QK".to_vec(),
+            message_type: Some(MessageType {
+                offset: 2,
+                allowed: vec![1, 2],
+            }),
+        }
+    }
+
+    #[test]
+    fn invalid_config() {
+        for config in [
+            Config {
+                max_size: Some(2),
+                ..config()
+            },
+            Config {
+                message_type: Some(MessageType {
+                    offset: 2,
+                    allowed: vec![],
+                }),
+                ..config()
+            },
+        ] {
+            assert!(ResponseValidation::new(config, Metrics::new().unwrap()).is_err());
+        }
+    }
+
+    #[test]
+    fn validate() {
+        let filter = ResponseValidation::new(config(), Metrics::new().unwrap()).unwrap();
+        assert_filter_read_no_change(&filter);
+
+        assert_eq!(Ok(()), filter.validate(b"QK\x01\x00"));
+        assert_eq!(Ok(()), filter.validate(b"QK\x02\x00\x00\x00\x00\x00"));
+        assert_eq!(Err(Invalid::TooSmall), filter.validate(b"QK\x01"));
+        assert_eq!(
+            Err(Invalid::TooLarge),
+            filter.validate(b"QK\x01\x00\x00\x00\x00\x00\x00")
+        );
+        assert_eq!(Err(Invalid::Magic), filter.validate(b"QX\x01\x00"));
+        assert_eq!(Err(Invalid::MessageType), filter.validate(b"QK\x03\x00"));
+
+        // Every check is optional.
+        let filter = ResponseValidation::new(Config::default(), Metrics::new().unwrap()).unwrap();
+        assert_eq!(Ok(()), filter.validate(b""));
+    }
+
+    #[test]
+    fn write() {
+        let filter = ResponseValidation::new(config(), Metrics::new().unwrap()).unwrap();
+        let context = |contents: &[u8]| {
+            WriteContext::new(
+                "127.0.0.1:80".parse().unwrap(),
+                "127.0.0.1:80".parse().unwrap(),
+                "127.0.0.1:70".parse().unwrap(),
+                contents.to_vec(),
+            )
+        };
+
+        assert!(filter.write(&mut context(b"QK\x01\x00")).is_some());
+        assert!(filter.write(&mut context(b"QK\x03\x00")).is_none());
+        assert_eq!(1, filter.metrics.packets_dropped_message_type.get());
+    }
+
+    #[test]
+    fn convert_proto_config() {
+        assert_eq!(
+            config(),
+            Config::try_from(proto::ResponseValidation::from(config())).unwrap()
+        );
+        assert!(Config::try_from(proto::ResponseValidation {
+            message_type: Some(proto::response_validation::MessageType {
+                offset: 0,
+                allowed: vec![256],
+            }),
+            ..<_>::default()
+        })
+        .is_err());
+    }
+}
@@ -0,0 +1,106 @@
+/*
+ * Copyright 2023 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::convert::TryFrom;
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::{config::Base64Standard, filters::ConvertProtoConfigError};
+
+use super::proto;
+
+/// Configuration for the [`ResponseValidation`][super::ResponseValidation]
+/// filter. Packets sent back to clients are dropped unless they pass every
+/// check that's set.
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize, JsonSchema)]
+pub struct Config {
+    /// The smallest packets in bytes.
+    #[serde(default)]
+    pub min_size: u32,
+    /// The largest packets in bytes.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_size: Option<u32>,
+    /// The base64 encoded bytes packets must start with.
+    #[serde(
+        default,
+        with = "Base64Standard",
+        skip_serializing_if = "Vec::is_empty"
+    )]
+    #[schemars(with = "String")]
+    pub magic: Vec<u8>,
+    /// The byte identifying the type of the message in packets, and the types
+    /// allowed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub message_type: Option<MessageType>,
+}
+
+/// Where the type of packets' messages is, and which types are allowed.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize, JsonSchema)]
+pub struct MessageType {
+    /// The offset in bytes of the message type from the start of the packet,
+    /// including any `magic`.
+    pub offset: u32,
+    /// The message types allowed.
+    pub allowed: Vec<u8>,
+}
+
+impl From<Config> for proto::ResponseValidation {
+    fn from(config: Config) -> Self {
+        Self {
+            min_size: config.min_size,
+            max_size: config.max_size,
+            magic: config.magic,
+            message_type: config.message_type.map(|message_type| {
+                proto::response_validation::MessageType {
+                    offset: message_type.offset,
+                    allowed: message_type.allowed.into_iter().map(u32::from).collect(),
+                }
+            }),
+        }
+    }
+}
+
+impl TryFrom<proto::ResponseValidation> for Config {
+    type Error = ConvertProtoConfigError;
+
+    fn try_from(p: proto::ResponseValidation) -> Result<Self, Self::Error> {
+        let message_type = p
+            .message_type
+            .map(|message_type| {
+                let allowed = message_type
+                    .allowed
+                    .into_iter()
+                    .map(u8::try_from)
+                    .collect::<Result<_, _>>()
+                    .map_err(|error| {
+                        ConvertProtoConfigError::new(error, Some("message_type.allowed".into()))
+                    })?;
+                Ok(MessageType {
+                    offset: message_type.offset,
+                    allowed,
+                })
+            })
+            .transpose()?;
+
+        Ok(Self {
+            min_size: p.min_size,
+            max_size: p.max_size,
+            magic: p.magic,
+            message_type,
+        })
+    }
+}
@@ -0,0 +1,51 @@
+/*
+ * Copyright 2023 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use prometheus::{IntCounter, IntCounterVec, Result as MetricsResult};
+
+use crate::metrics::{filter_opts, CollectorExt};
+
+const REASON_LABEL: &str = "reason";
+
+/// Register and manage metrics for this filter
+pub(super) struct Metrics {
+    pub(super) packets_dropped_too_small: IntCounter,
+    pub(super) packets_dropped_too_large: IntCounter,
+    pub(super) packets_dropped_magic: IntCounter,
+    pub(super) packets_dropped_message_type: IntCounter,
+}
+
+impl Metrics {
+    pub(super) fn new() -> MetricsResult<Self> {
+        let dropped = IntCounterVec::new(
+            filter_opts(
+                "packets_dropped_total",
+                "ResponseValidation",
+                "Total number of packets from upstream dropped as malformed. Labels: reason.",
+            ),
+            &[REASON_LABEL],
+        )?
+        .register_if_not_exists()?;
+
+        Ok(Self {
+            packets_dropped_too_small: dropped.get_metric_with_label_values(&["too_small"])?,
+            packets_dropped_too_large: dropped.get_metric_with_label_values(&["too_large"])?,
+            packets_dropped_magic: dropped.get_metric_with_label_values(&["magic"])?,
+            packets_dropped_message_type: dropped
+                .get_metric_with_label_values(&["message_type"])?,
+        })
+    }
+}
@@ -16,6 +16,8 @@
 
 use std::{iter::FromIterator, sync::Arc};
 
+// `filters` and `StaticFilter` are unused when built without any filters.
+#[allow(unused_imports)]
 use crate::filters::{self, DynFilterFactory, StaticFilter};
 
 #[cfg(doc)]
@@ -40,6 +42,9 @@ impl FilterSet {
     /// - [`capture`][filters::capture]
     /// - [`token_router`][filters::token_router]
     /// - [`compress`][filters::compress]
+    ///
+    /// Only the filters enabled by their `filter-*` cargo features, all of
+    /// which are enabled by default, are included.
     pub fn default() -> Self {
         Self::default_with(Option::into_iter(None))
     }
@@ -52,18 +57,52 @@ impl FilterSet {
     pub fn default_with(filters: impl IntoIterator<Item = DynFilterFactory>) -> Self {
         Self::with(
             [
+                #[cfg(feature = "filter-block-list")]
+                filters::BlockList::factory(),
+                #[cfg(feature = "filter-capture")]
                 filters::Capture::factory(),
+                #[cfg(feature = "filter-compress")]
                 filters::Compress::factory(),
+                #[cfg(feature = "filter-concatenate-bytes")]
                 filters::ConcatenateBytes::factory(),
+                #[cfg(feature = "filter-debug")]
                 filters::Debug::factory(),
+                #[cfg(feature = "filter-drop")]
                 filters::Drop::factory(),
+                #[cfg(feature = "filter-encrypt")]
+                filters::Encrypt::factory(),
+                #[cfg(feature = "filter-ext-authz")]
+                filters::ExtAuthz::factory(),
+                #[cfg(feature = "filter-firewall")]
                 filters::Firewall::factory(),
+                #[cfg(feature = "filter-geolocation")]
+                filters::Geolocation::factory(),
+                #[cfg(feature = "filter-hmac")]
+                filters::Hmac::factory(),
+                #[cfg(feature = "filter-load-balancer")]
                 filters::LoadBalancer::factory(),
+                #[cfg(feature = "filter-local-rate-limit")]
                 filters::LocalRateLimit::factory(),
+                #[cfg(feature = "filter-match")]
                 filters::Match::factory(),
+                #[cfg(feature = "filter-pass")]
                 filters::Pass::factory(),
+                #[cfg(feature = "filter-rate-limit")]
+                filters::RateLimit::factory(),
+                #[cfg(feature = "filter-reassemble")]
+                filters::Reassemble::factory(),
+                #[cfg(feature = "filter-reliable-control")]
+                filters::ReliableControl::factory(),
+                #[cfg(feature = "filter-response-validation")]
+                filters::ResponseValidation::factory(),
+                #[cfg(feature = "filter-timestamp")]
                 filters::Timestamp::factory(),
+                #[cfg(feature = "filter-token-router")]
                 filters::TokenRouter::factory(),
+                #[cfg(feature = "filter-ttl")]
+                filters::Ttl::factory(),
+                #[cfg(feature = "filter-wasm")]
+                filters::Wasm::factory(),
             ]
             .into_iter()
             .chain(filters),
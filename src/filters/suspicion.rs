@@ -0,0 +1,346 @@
+/*
+ * Copyright 2023 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Suspicion events, which filters emit when a client behaves in a way that
+//! may indicate cheating or an attack, so that anti-cheat systems get
+//! proxy-level signals without custom builds of Quilkin.
+//!
+//! Events are only built when a sink is installed, and are sent to the sink in
+//! the background. Events are discarded rather than slowing packet processing
+//! down when the sink can't keep up.
+
+use std::{
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use arc_swap::ArcSwapOption;
+use once_cell::sync::Lazy;
+use prometheus::{IntCounter, IntCounterVec};
+use serde::{Deserialize, Serialize};
+use tokio::sync::{mpsc, Mutex};
+
+use crate::endpoint::EndpointAddress;
+
+#[allow(warnings)]
+pub mod proto {
+    tonic::include_proto!("quilkin.suspicion.v1alpha1");
+}
+
+pub use self::proto::{
+    suspicion_service_client::SuspicionServiceClient,
+    suspicion_service_server::{SuspicionService, SuspicionServiceServer},
+    SuspicionResponse,
+};
+
+/// The client sent a packet the filter couldn't parse.
+pub const MALFORMED_PACKET: &str = "malformed_packet";
+/// The client sent packets faster than allowed.
+pub const EXCESSIVE_RATE: &str = "excessive_rate";
+/// The client sent a packet out of the order its protocol requires, such as
+/// a replayed or skipped sequence number.
+pub const INVALID_SEQUENCE: &str = "invalid_sequence";
+
+/// The number of events that can be waiting to be sent to the sink.
+const QUEUE_SIZE: usize = 4096;
+/// The maximum number of events sent to a webhook in a single request.
+const MAX_WEBHOOK_BATCH: usize = 100;
+/// How long to wait before reconnecting to a gRPC sink.
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+static SINK: Lazy<ArcSwapOption<Sink>> = Lazy::new(<_>::default);
+
+static EVENTS_TOTAL: Lazy<IntCounterVec> = Lazy::new(|| {
+    prometheus::register_int_counter_vec_with_registry! {
+        prometheus::opts! {
+            "suspicion_events_total",
+            "Total number of suspicion events emitted by filters. Labels: filter, kind",
+        },
+        &["filter", "kind"],
+        crate::metrics::registry(),
+    }
+    .unwrap()
+});
+
+static EVENTS_DROPPED_TOTAL: Lazy<IntCounter> = Lazy::new(|| {
+    prometheus::register_int_counter_with_registry! {
+        prometheus::opts! {
+            "suspicion_events_dropped_total",
+            "Total number of suspicion events discarded because the sink couldn't keep up or was unreachable",
+        },
+        crate::metrics::registry(),
+    }
+    .unwrap()
+});
+
+/// A sign that a client may be cheating or attacking the proxy.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Suspicion {
+    /// When the event was emitted, in milliseconds since the UNIX epoch.
+    pub timestamp_ms: u64,
+    /// The name of the filter which emitted the event.
+    pub filter: String,
+    /// What the client did, such as [`MALFORMED_PACKET`].
+    pub kind: String,
+    /// The address of the client.
+    pub source: String,
+    /// Human readable details of the event, if any.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub detail: String,
+}
+
+impl Suspicion {
+    /// Creates an event of `kind` emitted by `filter` about the client at
+    /// `source`.
+    pub fn new(filter: &str, kind: &str, source: &EndpointAddress) -> Self {
+        Self {
+            timestamp_ms: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis() as u64,
+            filter: filter.into(),
+            kind: kind.into(),
+            source: source.to_string(),
+            detail: String::new(),
+        }
+    }
+
+    pub fn with_detail(mut self, detail: impl Into<String>) -> Self {
+        self.detail = detail.into();
+        self
+    }
+
+    fn into_proto(self, proxy_id: &str) -> proto::Suspicion {
+        proto::Suspicion {
+            timestamp_ms: self.timestamp_ms,
+            proxy_id: proxy_id.into(),
+            filter: self.filter,
+            kind: self.kind,
+            source: self.source,
+            detail: self.detail,
+        }
+    }
+}
+
+/// Emits the event returned by `suspicion` to the installed sink. `suspicion`
+/// is only called when a sink is installed, so filters can emit events from
+/// the packet path without any cost for proxies that don't collect them.
+pub fn emit(suspicion: impl FnOnce() -> Suspicion) {
+    if let Some(sink) = &*SINK.load() {
+        sink.send(suspicion());
+    }
+}
+
+/// Installs the sink every event is sent to, replacing any existing sink.
+pub(crate) fn install(sink: Option<Sink>) {
+    SINK.store(sink.map(Arc::new));
+}
+
+/// Where events are sent to.
+#[derive(Clone, Debug)]
+pub enum Destination {
+    /// `POST`s batches of events as JSON to a URL.
+    Webhook(url::Url),
+    /// Streams events to a `SuspicionService`.
+    Grpc(tonic::transport::Endpoint),
+}
+
+/// Sends events to a [`Destination`] in the background, until dropped.
+pub(crate) struct Sink {
+    events: mpsc::Sender<Suspicion>,
+    task: tokio::task::JoinHandle<()>,
+}
+
+impl Sink {
+    pub(crate) fn spawn(proxy_id: String, destination: Destination) -> Self {
+        let (events, receiver) = mpsc::channel(QUEUE_SIZE);
+        let task = match destination {
+            Destination::Webhook(url) => tokio::spawn(webhook(proxy_id, url, receiver)),
+            Destination::Grpc(endpoint) => tokio::spawn(grpc(proxy_id, endpoint, receiver)),
+        };
+
+        Self { events, task }
+    }
+
+    fn send(&self, suspicion: Suspicion) {
+        EVENTS_TOTAL
+            .with_label_values(&[&suspicion.filter, &suspicion.kind])
+            .inc();
+
+        if self.events.try_send(suspicion).is_err() {
+            EVENTS_DROPPED_TOTAL.inc();
+        }
+    }
+}
+
+impl Drop for Sink {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+#[derive(Serialize)]
+struct WebhookBody<'a> {
+    proxy_id: &'a str,
+    suspicions: &'a [Suspicion],
+}
+
+async fn webhook(proxy_id: String, url: url::Url, mut events: mpsc::Receiver<Suspicion>) {
+    let client = hyper::Client::builder().build::<_, hyper::Body>(
+        hyper_rustls::HttpsConnectorBuilder::new()
+            .with_webpki_roots()
+            .https_or_http()
+            .enable_http1()
+            .enable_http2()
+            .build(),
+    );
+
+    let mut batch = Vec::with_capacity(MAX_WEBHOOK_BATCH);
+    while let Some(suspicion) = events.recv().await {
+        batch.push(suspicion);
+        while batch.len() < MAX_WEBHOOK_BATCH {
+            match events.try_recv() {
+                Ok(suspicion) => batch.push(suspicion),
+                Err(_) => break,
+            }
+        }
+
+        let body = serde_json::to_vec(&WebhookBody {
+            proxy_id: &proxy_id,
+            suspicions: &batch,
+        })
+        .unwrap();
+        let request = hyper::Request::post(url.as_str())
+            .header(hyper::header::CONTENT_TYPE, "application/json")
+            .body(body.into())
+            .unwrap();
+
+        match client.request(request).await {
+            Ok(response) if response.status().is_success() => {}
+            Ok(response) => {
+                tracing::warn!(%url, status = %response.status(), "suspicion webhook rejected events");
+                EVENTS_DROPPED_TOTAL.inc_by(batch.len() as u64);
+            }
+            Err(error) => {
+                tracing::warn!(%url, %error, "failed to send events to suspicion webhook");
+                EVENTS_DROPPED_TOTAL.inc_by(batch.len() as u64);
+            }
+        }
+
+        batch.clear();
+    }
+}
+
+async fn grpc(
+    proxy_id: String,
+    endpoint: tonic::transport::Endpoint,
+    events: mpsc::Receiver<Suspicion>,
+) {
+    // Shared between streams, so that events queued while reconnecting are
+    // sent on the next stream.
+    let events = Arc::new(Mutex::new(events));
+
+    loop {
+        match SuspicionServiceClient::connect(endpoint.clone()).await {
+            Ok(mut client) => {
+                let stream = {
+                    let events = events.clone();
+                    let proxy_id = proxy_id.clone();
+                    async_stream::stream! {
+                        let mut events = events.lock().await;
+                        while let Some(suspicion) = events.recv().await {
+                            yield suspicion.into_proto(&proxy_id);
+                        }
+                    }
+                };
+
+                match client.stream_suspicions(stream).await {
+                    Ok(_) => tracing::debug!("suspicion stream closed by sink"),
+                    Err(error) => tracing::warn!(%error, "suspicion stream failed, reconnecting"),
+                }
+            }
+            Err(error) => {
+                tracing::warn!(%error, uri = %endpoint.uri(), "failed to connect to suspicion sink")
+            }
+        }
+
+        tokio::time::sleep(RECONNECT_DELAY).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    #[derive(Clone, Default)]
+    struct Collector(Arc<parking_lot::Mutex<Vec<proto::Suspicion>>>);
+
+    #[tonic::async_trait]
+    impl SuspicionService for Collector {
+        async fn stream_suspicions(
+            &self,
+            request: tonic::Request<tonic::Streaming<proto::Suspicion>>,
+        ) -> Result<tonic::Response<SuspicionResponse>, tonic::Status> {
+            let mut stream = request.into_inner();
+            while let Some(suspicion) = stream.message().await? {
+                self.0.lock().push(suspicion);
+            }
+
+            Ok(tonic::Response::new(SuspicionResponse {}))
+        }
+    }
+
+    #[tokio::test]
+    async fn grpc_sink() {
+        let collector = Collector::default();
+        let address = crate::test_utils::available_addr().await;
+        tokio::spawn(
+            tonic::transport::Server::builder()
+                .add_service(SuspicionServiceServer::new(collector.clone()))
+                .serve(address),
+        );
+
+        let sink = Sink::spawn(
+            "proxy".into(),
+            Destination::Grpc(format!("http://{address}").parse().unwrap()),
+        );
+        let source = (Ipv4Addr::LOCALHOST, 9000).into();
+        sink.send(Suspicion::new("test", MALFORMED_PACKET, &source).with_detail("too short"));
+
+        let suspicions = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                if let Some(suspicion) = collector.0.lock().first() {
+                    break suspicion.clone();
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+
+        assert_eq!("proxy", suspicions.proxy_id);
+        assert_eq!(MALFORMED_PACKET, suspicions.kind);
+        assert_eq!("127.0.0.1:9000", suspicions.source);
+        assert_eq!("too short", suspicions.detail);
+    }
+
+    #[test]
+    fn emit_without_sink() {
+        emit(|| unreachable!("events are only built with a sink installed"));
+    }
+}
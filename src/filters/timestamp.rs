@@ -179,8 +179,6 @@ impl TryFrom<proto::Timestamp> for Config {
 mod tests {
     use super::*;
 
-    use crate::filters::capture::{self, Capture};
-
     #[test]
     fn basic() {
         const TIMESTAMP_KEY: &str = "BASIC";
@@ -201,7 +199,10 @@ mod tests {
     }
 
     #[test]
+    #[cfg(feature = "filter-capture")]
     fn with_capture() {
+        use crate::filters::capture::{self, Capture};
+
         const TIMESTAMP_KEY: &str = "WITH_CAPTURE";
         let capture = Capture::from_config(
             capture::Config {
@@ -18,32 +18,80 @@ mod metrics;
 
 crate::include_proto!("quilkin.filters.token_router.v1alpha1");
 
-use std::convert::TryFrom;
+use std::{convert::TryFrom, time::Duration};
 
 use serde::{Deserialize, Serialize};
 
 use crate::{
+    endpoint::{Endpoint, EndpointAddress},
     filters::{metadata::CAPTURED_BYTES, prelude::*},
     metadata,
+    ttl_map::TtlMap,
 };
 
 use metrics::Metrics;
 
 use self::quilkin::filters::token_router::v1alpha1 as proto;
 
+/// How often expired entries are removed from the route cache.
+const CACHE_EXPIRY_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The endpoints a token was routed to, along with the configuration
+/// generation they were resolved against.
+struct CachedRoute {
+    generation: u64,
+    endpoints: Vec<Endpoint>,
+}
+
 /// Filter that only allows packets to be passed to Endpoints that have a matching
 /// connection_id to the token stored in the Filter's dynamic metadata.
 pub struct TokenRouter {
     config: Config,
     metrics: Metrics,
+    /// Caches the endpoints matched for a source address and token hash.
+    cache: Option<TtlMap<(EndpointAddress, u64), CachedRoute>>,
 }
 
 impl TokenRouter {
     fn new(config: Config, metrics: Metrics) -> Self {
-        Self { config, metrics }
+        let cache = config
+            .cache_ttl
+            .map(|ttl| TtlMap::new(Duration::from_secs(ttl), CACHE_EXPIRY_POLL_INTERVAL));
+
+        Self {
+            config,
+            metrics,
+            cache,
+        }
+    }
+
+    /// Returns the cached endpoints for `key`, if they were resolved against
+    /// the current `generation`.
+    fn cached_endpoints(
+        &self,
+        key: &(EndpointAddress, u64),
+        generation: u64,
+    ) -> Option<Vec<Endpoint>> {
+        let cache = self.cache.as_ref()?;
+        let endpoints = cache
+            .get(key)
+            .filter(|route| route.generation == generation)
+            .map(|route| route.endpoints.clone());
+
+        if endpoints.is_some() {
+            self.metrics.cache_hits_total.inc();
+        } else {
+            self.metrics.cache_misses_total.inc();
+        }
+
+        endpoints
     }
 }
 
+fn hash_token(token: &[u8]) -> u64 {
+    crate::utils::stable_hash(token)
+}
+
 impl StaticFilter for TokenRouter {
     const NAME: &'static str = "quilkin.filters.token_router.v1alpha1.TokenRouter";
     type Configuration = Config;
@@ -70,8 +118,15 @@ impl Filter for TokenRouter {
             }
             Some(value) => match value {
                 metadata::Value::Bytes(token) => {
+                    let generation = crate::config::generation();
+                    let key = (ctx.source.clone(), hash_token(token));
+                    if let Some(endpoints) = self.cached_endpoints(&key, generation) {
+                        ctx.endpoints = endpoints;
+                        return Some(());
+                    }
+
                     ctx.endpoints.retain(|endpoint| {
-                        if endpoint.metadata.known.tokens.contains(&**token) {
+                        if crate::codec::routes_to(&endpoint.metadata.known.tokens, token) {
                             tracing::trace!(%endpoint.address, token = &*base64::encode(token), "Endpoint matched");
                             true
                         } else {
@@ -87,6 +142,16 @@ impl Filter for TokenRouter {
                         self.metrics.packets_dropped_total_no_endpoint_match.inc();
                         None
                     } else {
+                        if let Some(cache) = &self.cache {
+                            cache.insert(
+                                key,
+                                CachedRoute {
+                                    generation,
+                                    endpoints: ctx.endpoints.clone(),
+                                },
+                            );
+                        }
+
                         Some(())
                     }
                 }
@@ -102,6 +167,10 @@ impl Filter for TokenRouter {
             },
         }
     }
+
+    fn has_write(&self) -> bool {
+        false
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Eq, PartialEq, schemars::JsonSchema)]
@@ -110,6 +179,15 @@ pub struct Config {
     /// the key to use when retrieving the token from the Filter's dynamic metadata
     #[serde(rename = "metadataKey", default = "default_metadata_key")]
     pub metadata_key: metadata::Key,
+    /// When set, caches the endpoints matched for each source address and
+    /// token for this many seconds since the entry was last used, so
+    /// subsequent packets skip the endpoint lookup. Cached routes are
+    /// discarded whenever the configuration, the health of its endpoints,
+    /// or their preflight state changes. Cached endpoints replace
+    /// the packet's endpoints, so this should only be used when no filter
+    /// earlier in the chain changes the endpoints.
+    #[serde(rename = "cacheTtl", default, skip_serializing_if = "Option::is_none")]
+    pub cache_ttl: Option<u64>,
 }
 
 /// Default value for [`Config::metadata_key`]
@@ -121,6 +199,7 @@ impl Default for Config {
     fn default() -> Self {
         Self {
             metadata_key: default_metadata_key(),
+            cache_ttl: None,
         }
     }
 }
@@ -129,6 +208,7 @@ impl From<Config> for proto::TokenRouter {
     fn from(config: Config) -> Self {
         Self {
             metadata_key: Some(config.metadata_key.to_string()),
+            cache_ttl: config.cache_ttl,
         }
     }
 }
@@ -142,6 +222,7 @@ impl TryFrom<proto::TokenRouter> for Config {
                 .metadata_key
                 .map(metadata::Key::new)
                 .unwrap_or_else(default_metadata_key),
+            cache_ttl: p.cache_ttl,
         })
     }
 }
@@ -165,16 +246,22 @@ mod tests {
                 "should succeed when all valid values are provided",
                 proto::TokenRouter {
                     metadata_key: Some("foobar".into()),
+                    cache_ttl: Some(5),
                 },
                 Some(Config {
                     metadata_key: "foobar".into(),
+                    cache_ttl: Some(5),
                 }),
             ),
             (
                 "should use correct default values",
-                proto::TokenRouter { metadata_key: None },
+                proto::TokenRouter {
+                    metadata_key: None,
+                    cache_ttl: None,
+                },
                 Some(Config {
                     metadata_key: default_metadata_key(),
+                    cache_ttl: None,
                 }),
             ),
         ];
@@ -197,6 +284,7 @@ mod tests {
         let filter = TokenRouter::from_config(
             Config {
                 metadata_key: TOKEN_KEY.into(),
+                ..<_>::default()
             }
             .into(),
         );
@@ -220,6 +308,7 @@ mod tests {
         // valid key
         let config = Config {
             metadata_key: CAPTURED_BYTES.into(),
+            ..<_>::default()
         };
         let filter = TokenRouter::from_config(config.into());
 
@@ -252,10 +341,60 @@ mod tests {
         assert_eq!(1, filter.metrics.packets_dropped_total_invalid_token.get());
     }
 
+    #[tokio::test]
+    async fn cached_routes() {
+        let filter = TokenRouter::from_config(
+            Config {
+                cache_ttl: Some(60),
+                ..<_>::default()
+            }
+            .into(),
+        );
+        let new_ctx = || {
+            let mut ctx = new_ctx();
+            ctx.metadata
+                .insert(CAPTURED_BYTES.into(), Value::Bytes(b"123".to_vec().into()));
+            ctx
+        };
+
+        let mut ctx = new_ctx();
+        filter.read(&mut ctx).unwrap();
+        assert_eq!(1, ctx.endpoints.len());
+        assert_eq!(1, filter.metrics.cache_misses_total.get());
+
+        let key = (ctx.source.clone(), hash_token(b"123"));
+        let generation = filter.cache.as_ref().unwrap().get(&key).unwrap().generation;
+        assert_eq!(
+            ctx.endpoints,
+            filter.cached_endpoints(&key, generation).unwrap()
+        );
+        assert_eq!(1, filter.metrics.cache_hits_total.get());
+
+        // Routes resolved against an older configuration are ignored.
+        assert!(filter.cached_endpoints(&key, generation + 1).is_none());
+        assert_eq!(2, filter.metrics.cache_misses_total.get());
+
+        // As are routes resolved before an endpoint became unhealthy.
+        let mut ctx = new_ctx();
+        filter.read(&mut ctx).unwrap();
+        let generation = filter.cache.as_ref().unwrap().get(&key).unwrap().generation;
+        crate::cluster::Clusters::default().store_unhealthy(
+            ctx.endpoints
+                .iter()
+                .map(|endpoint| endpoint.address.clone())
+                .collect(),
+        );
+        assert!(crate::config::generation() > generation);
+        assert!(filter
+            .cached_endpoints(&key, crate::config::generation())
+            .is_none());
+    }
+
     #[test]
     fn write() {
         let config = Config {
             metadata_key: CAPTURED_BYTES.into(),
+            ..<_>::default()
         };
         let filter = TokenRouter::from_config(config.into());
         assert_write_no_change(&filter);
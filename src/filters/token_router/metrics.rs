@@ -25,6 +25,8 @@ pub(super) struct Metrics {
     pub(super) packets_dropped_total_no_token_found: GenericCounter<AtomicU64>,
     pub(super) packets_dropped_total_invalid_token: GenericCounter<AtomicU64>,
     pub(super) packets_dropped_total_no_endpoint_match: GenericCounter<AtomicU64>,
+    pub(super) cache_hits_total: GenericCounter<AtomicU64>,
+    pub(super) cache_misses_total: GenericCounter<AtomicU64>,
 }
 
 impl Metrics {
@@ -40,7 +42,19 @@ impl Metrics {
         )?
         .register_if_not_exists()?;
 
+        let cache_lookups = IntCounterVec::new(
+            filter_opts(
+                "cache_lookups_total",
+                "TokenRouter",
+                "Total number of route cache lookups. labels: result.",
+            ),
+            &["result"],
+        )?
+        .register_if_not_exists()?;
+
         Ok(Metrics {
+            cache_hits_total: cache_lookups.get_metric_with_label_values(&["hit"])?,
+            cache_misses_total: cache_lookups.get_metric_with_label_values(&["miss"])?,
             packets_dropped_total_no_token_found: metric
                 .get_metric_with_label_values(vec!["NoTokenFound"].as_slice())?,
             packets_dropped_total_invalid_token: metric
@@ -0,0 +1,207 @@
+/*
+ * Copyright 2023 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+mod config;
+mod metrics;
+
+crate::include_proto!("quilkin.filters.ttl.v1alpha1");
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::{filters::prelude::*, metadata};
+
+use self::{metrics::Metrics, quilkin::filters::ttl::v1alpha1 as proto};
+
+pub use self::config::Config;
+
+/// Filter that drops packets that were sent more than `max_age_ms` ago,
+/// according to a timestamp the client embedded in them, as in fast-paced
+/// games a stale input, such as one held in a buffer along the way, is worse
+/// than a lost one.
+///
+/// Packets without a valid timestamp are passed through. Only packets received
+/// from downstream are checked.
+pub struct Ttl {
+    metadata_key: metadata::Key,
+    max_age: Duration,
+    metrics: Metrics,
+}
+
+impl Ttl {
+    fn new(config: Config, metrics: Metrics) -> Result<Self, Error> {
+        if config.max_age_ms == 0 {
+            return Err(Error::FieldInvalid {
+                field: "max_age_ms".into(),
+                reason: "value must be at least 1".into(),
+            });
+        }
+
+        Ok(Self {
+            metadata_key: config.metadata_key,
+            max_age: Duration::from_millis(config.max_age_ms),
+            metrics,
+        })
+    }
+
+    /// Returns the time the packet was sent, if it has a timestamp.
+    fn sent_at(&self, ctx: &ReadContext) -> Option<SystemTime> {
+        let millis = match ctx.metadata.get(&self.metadata_key)? {
+            metadata::Value::Number(millis) => *millis,
+            metadata::Value::Bytes(bytes) => u64::from_be_bytes((**bytes).try_into().ok()?),
+            _ => return None,
+        };
+
+        UNIX_EPOCH.checked_add(Duration::from_millis(millis))
+    }
+}
+
+impl Filter for Ttl {
+    #[cfg_attr(feature = "instrument", tracing::instrument(skip(self, ctx)))]
+    fn read(&self, ctx: &mut ReadContext) -> Option<()> {
+        let Some(sent_at) = self.sent_at(ctx) else {
+            return Some(());
+        };
+
+        // Timestamps ahead of the proxy's clock, from clients whose clocks
+        // have drifted, count as fresh.
+        let age = SystemTime::now()
+            .duration_since(sent_at)
+            .unwrap_or_default();
+        self.metrics.staleness_seconds.observe(age.as_secs_f64());
+
+        if age > self.max_age {
+            tracing::trace!(source = %ctx.source, ?age, "Dropping stale packet");
+            self.metrics.packets_dropped_stale_total.inc();
+            return None;
+        }
+
+        Some(())
+    }
+
+    fn has_write(&self) -> bool {
+        false
+    }
+}
+
+impl StaticFilter for Ttl {
+    const NAME: &'static str = "quilkin.filters.ttl.v1alpha1.Ttl";
+    type Configuration = Config;
+    type BinaryConfiguration = proto::Ttl;
+
+    fn try_from_config(config: Option<Self::Configuration>) -> Result<Self, Error> {
+        Self::new(Self::ensure_config_exists(config)?, Metrics::new()?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+    use crate::{endpoint::Endpoint, test_utils::assert_write_no_change};
+
+    const KEY: &str = "sent_at";
+
+    fn ttl(max_age_ms: u64) -> Ttl {
+        Ttl::new(Config::new(KEY, max_age_ms), Metrics::new().unwrap()).unwrap()
+    }
+
+    fn context(sent_at: Option<metadata::Value>) -> ReadContext {
+        let mut ctx = ReadContext::new(
+            vec![Endpoint::new((Ipv4Addr::LOCALHOST, 8089).into())],
+            (Ipv4Addr::LOCALHOST, 7000).into(),
+            vec![9],
+        );
+        if let Some(sent_at) = sent_at {
+            ctx.metadata.insert(KEY.into(), sent_at);
+        }
+        ctx
+    }
+
+    /// Returns the timestamp of `age` ago.
+    fn millis_ago(age: Duration) -> u64 {
+        (SystemTime::now() - age)
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as u64
+    }
+
+    #[test]
+    fn invalid_config() {
+        assert!(Ttl::new(Config::new(KEY, 0), Metrics::new().unwrap()).is_err());
+    }
+
+    #[test]
+    fn drops_stale_packets() {
+        let filter = ttl(500);
+        assert_write_no_change(&filter);
+        let dropped = filter.metrics.packets_dropped_stale_total.get();
+
+        let fresh = millis_ago(Duration::from_millis(10));
+        assert!(filter
+            .read(&mut context(Some(metadata::Value::Number(fresh))))
+            .is_some());
+
+        let stale = millis_ago(Duration::from_secs(2));
+        assert!(filter
+            .read(&mut context(Some(metadata::Value::Number(stale))))
+            .is_none());
+        let stale = bytes::Bytes::copy_from_slice(&stale.to_be_bytes());
+        assert!(filter
+            .read(&mut context(Some(metadata::Value::Bytes(stale))))
+            .is_none());
+        assert_eq!(
+            dropped + 2,
+            filter.metrics.packets_dropped_stale_total.get()
+        );
+
+        // Timestamps from the future are fresh.
+        let future = millis_ago(Duration::ZERO) + 60_000;
+        assert!(filter
+            .read(&mut context(Some(metadata::Value::Number(future))))
+            .is_some());
+    }
+
+    #[test]
+    fn passes_packets_without_timestamps() {
+        let filter = ttl(500);
+
+        assert!(filter.read(&mut context(None)).is_some());
+        assert!(filter
+            .read(&mut context(Some(metadata::Value::Bool(true))))
+            .is_some());
+        // Timestamps must be 8 bytes.
+        assert!(filter
+            .read(&mut context(Some(metadata::Value::Bytes(
+                vec![1, 2].into()
+            ))))
+            .is_some());
+    }
+
+    #[test]
+    fn convert_proto_config() {
+        let config = Config::new(KEY, 250);
+        assert_eq!(
+            config,
+            Config::try_from(proto::Ttl::from(config.clone())).unwrap()
+        );
+        assert!(Config::try_from(proto::Ttl {
+            metadata_key: None,
+            max_age_ms: 250,
+        })
+        .is_err());
+    }
+}
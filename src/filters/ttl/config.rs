@@ -0,0 +1,65 @@
+/*
+ * Copyright 2023 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::convert::TryFrom;
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::{filters::ConvertProtoConfigError, metadata};
+
+use super::proto;
+
+/// Configuration for the [`Ttl`][super::Ttl] filter.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize, JsonSchema)]
+pub struct Config {
+    /// The key of the time the packet was sent by the client, in
+    /// milliseconds since the Unix epoch, in the packet's dynamic metadata,
+    /// such as one set by the `Capture` filter.
+    #[serde(rename = "metadataKey")]
+    pub metadata_key: metadata::Key,
+    /// The age in milliseconds beyond which packets are dropped.
+    pub max_age_ms: u64,
+}
+
+impl Config {
+    pub fn new(metadata_key: impl AsRef<str>, max_age_ms: u64) -> Self {
+        Self {
+            metadata_key: metadata_key.as_ref().into(),
+            max_age_ms,
+        }
+    }
+}
+
+impl From<Config> for proto::Ttl {
+    fn from(config: Config) -> Self {
+        Self {
+            metadata_key: Some(config.metadata_key.to_string()),
+            max_age_ms: config.max_age_ms,
+        }
+    }
+}
+
+impl TryFrom<proto::Ttl> for Config {
+    type Error = ConvertProtoConfigError;
+
+    fn try_from(p: proto::Ttl) -> Result<Self, Self::Error> {
+        let metadata_key = p
+            .metadata_key
+            .ok_or_else(|| ConvertProtoConfigError::missing_field("metadata_key"))?;
+        Ok(Self::new(metadata_key, p.max_age_ms))
+    }
+}
@@ -0,0 +1,46 @@
+/*
+ * Copyright 2023 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use prometheus::{exponential_buckets, Histogram, IntCounter, Result as MetricsResult};
+
+use crate::metrics::{filter_opts, histogram_opts, CollectorExt};
+
+/// Register and manage metrics for this filter
+pub(super) struct Metrics {
+    pub(super) packets_dropped_stale_total: IntCounter,
+    pub(super) staleness_seconds: Histogram,
+}
+
+impl Metrics {
+    pub(super) fn new() -> MetricsResult<Self> {
+        Ok(Self {
+            packets_dropped_stale_total: IntCounter::with_opts(filter_opts(
+                "packets_dropped_stale_total",
+                "Ttl",
+                "Total number of packets dropped because they were older than `max_age_ms`",
+            ))?
+            .register_if_not_exists()?,
+            staleness_seconds: Histogram::with_opts(histogram_opts(
+                "staleness_seconds",
+                "filter_Ttl",
+                "Seconds between the time packets were sent and received by the filter",
+                // From 1ms to a little over 8 seconds.
+                exponential_buckets(0.001, 2.0, 14)?,
+            ))?
+            .register_if_not_exists()?,
+        })
+    }
+}
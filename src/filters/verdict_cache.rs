@@ -0,0 +1,198 @@
+/*
+ * Copyright 2023 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! The verdicts of filters that look clients up in an external service in
+//! the background, so that packets never wait on the service.
+
+use std::time::Duration;
+
+use dashmap::DashMap;
+use tokio::time::Instant;
+
+/// How long a key's verdict stays unknown after a failed lookup, before the
+/// next packet looks it up again.
+pub const RETRY_INTERVAL: Duration = Duration::from_secs(1);
+/// How often entries past their retention are removed, see
+/// [`VerdictCache::purge`].
+pub const PURGE_INTERVAL: Duration = Duration::from_secs(10);
+/// How long an expired verdict is still used while it's being looked up
+/// again, so that active clients don't fall back to the failure policy on
+/// every refresh.
+const STALE_RETENTION: Duration = Duration::from_secs(60);
+/// The number of keys cached by default, beyond which new keys aren't
+/// looked up until older ones are purged.
+const MAX_ENTRIES: usize = 1 << 20;
+
+/// The last known verdict of each key.
+pub struct VerdictCache {
+    entries: DashMap<String, Entry>,
+    max_entries: usize,
+}
+
+struct Entry {
+    /// The key's verdict, `None` if it's unknown.
+    verdict: Option<bool>,
+    expires_at: Instant,
+    /// Whether the key is queued or being looked up.
+    refreshing: bool,
+}
+
+impl Default for VerdictCache {
+    fn default() -> Self {
+        Self::new(MAX_ENTRIES)
+    }
+}
+
+impl VerdictCache {
+    /// Creates a cache holding up to `max_entries` keys.
+    pub fn new(max_entries: usize) -> Self {
+        Self {
+            entries: <_>::default(),
+            max_entries,
+        }
+    }
+
+    /// Returns the verdict of `key`, or `None` if it isn't known yet.
+    /// Missing and expired keys are passed to `refresh`, which returns
+    /// whether the key was queued to be looked up. New keys stay unknown
+    /// without being queued while the cache is full.
+    pub fn get(&self, key: String, refresh: impl FnOnce(String) -> bool) -> Option<bool> {
+        let now = Instant::now();
+        if let Some(entry) = self.entries.get(&key) {
+            if entry.refreshing || entry.expires_at > now {
+                return entry.verdict;
+            }
+        } else if self.entries.len() >= self.max_entries {
+            return None;
+        }
+
+        let mut entry = self.entries.entry(key.clone()).or_insert(Entry {
+            verdict: None,
+            expires_at: now,
+            refreshing: false,
+        });
+
+        if !entry.refreshing && entry.expires_at <= now {
+            entry.refreshing = refresh(key);
+        }
+
+        entry.verdict
+    }
+
+    /// Caches the `verdict` of the lookup of `key` for `ttl`, if the key is
+    /// still waiting on it. The entry stays locked between checking and
+    /// updating it, so a verdict set with [`VerdictCache::set`] while the
+    /// lookup was in flight is never overwritten by it.
+    pub fn accept(&self, key: &str, verdict: Option<bool>, ttl: Duration) {
+        if let Some(mut entry) = self.entries.get_mut(key) {
+            if entry.refreshing {
+                *entry = Entry {
+                    verdict,
+                    expires_at: Instant::now() + ttl,
+                    refreshing: false,
+                };
+            }
+        }
+    }
+
+    /// Sets the `verdict` of `key` for `ttl`, whether or not it's been looked
+    /// up yet, unless the key is unknown and the cache is full. Returns
+    /// whether the verdict was set.
+    pub fn set(&self, key: String, verdict: bool, ttl: Duration) -> bool {
+        let entry = Entry {
+            verdict: Some(verdict),
+            expires_at: Instant::now() + ttl,
+            refreshing: false,
+        };
+
+        match self.entries.get_mut(&key) {
+            Some(mut existing) => *existing = entry,
+            None if self.entries.len() < self.max_entries => {
+                self.entries.insert(key, entry);
+            }
+            None => return false,
+        }
+
+        true
+    }
+
+    /// Removes the entries that expired longer than the stale retention ago.
+    pub fn purge(&self) {
+        let now = Instant::now();
+        self.entries
+            .retain(|_, entry| entry.refreshing || entry.expires_at + STALE_RETENTION > now);
+    }
+
+    /// Waits until the lookup of `key` has completed.
+    #[cfg(test)]
+    pub async fn resolved(&self, key: &str) {
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while self.entries.get(key).map_or(true, |entry| entry.refreshing) {
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+        })
+        .await
+        .unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lookups() {
+        let cache = VerdictCache::default();
+        let key = String::from("a");
+
+        assert_eq!(None, cache.get(key.clone(), |_| true));
+        // Keys are only queued once.
+        assert_eq!(None, cache.get(key.clone(), |_| unreachable!()));
+        cache.accept(&key, Some(true), Duration::from_secs(60));
+        assert_eq!(Some(true), cache.get(key.clone(), |_| unreachable!()));
+
+        // Lookups of keys that aren't waiting on them are dropped.
+        cache.accept("b", Some(true), Duration::from_secs(60));
+        assert_eq!(None, cache.get("b".into(), |_| false));
+    }
+
+    #[test]
+    fn set_wins_over_lookups_in_flight() {
+        let cache = VerdictCache::default();
+        let key = String::from("a");
+
+        cache.get(key.clone(), |_| true);
+        assert!(cache.set(key.clone(), false, Duration::from_secs(30)));
+        cache.accept(&key, Some(true), Duration::from_secs(300));
+        assert_eq!(Some(false), cache.get(key, |_| unreachable!()));
+    }
+
+    #[test]
+    fn bounded() {
+        let cache = VerdictCache::new(2);
+
+        cache.get("a".into(), |_| true);
+        assert!(cache.set("b".into(), true, Duration::from_secs(60)));
+        // Once full, new keys are neither queued nor set.
+        assert_eq!(None, cache.get("c".into(), |_| unreachable!()));
+        assert!(!cache.set("c".into(), true, Duration::from_secs(60)));
+        assert_eq!(2, cache.entries.len());
+
+        // Known keys are still updated.
+        assert!(cache.set("a".into(), false, Duration::from_secs(60)));
+        assert_eq!(Some(false), cache.get("a".into(), |_| unreachable!()));
+    }
+}
@@ -0,0 +1,477 @@
+/*
+ * Copyright 2023 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+mod config;
+mod metrics;
+
+crate::include_proto!("quilkin.filters.wasm.v1alpha1");
+
+use std::{
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use wasmtime::{
+    Engine, Instance, Memory, Module, Store, StoreLimits, StoreLimitsBuilder, TypedFunc,
+};
+
+use crate::filters::prelude::*;
+
+use self::{metrics::Metrics, quilkin::filters::wasm::v1alpha1 as proto};
+
+pub use self::config::{Config, Source};
+
+/// The length of the buffer packets are passed to the module in, enough for
+/// any UDP packet.
+pub const BUFFER_LEN: usize = 65535;
+
+/// The environment variable with the directory modules can be read from. As
+/// the management server can set the path of a module, modules can only be
+/// inline unless it's set.
+pub const MODULE_DIR_ENV: &str = "QUILKIN_WASM_MODULE_DIR";
+
+/// The export returning the address of the module's packet buffer.
+const BUFFER: &str = "quilkin_buffer";
+/// The export processing packets received from downstream.
+const READ: &str = "quilkin_read";
+/// The export processing packets received from upstream.
+const WRITE: &str = "quilkin_write";
+
+/// Compiles and runs every module, counting the fuel they use.
+static ENGINE: Lazy<Engine> = Lazy::new(|| {
+    let mut config = wasmtime::Config::new();
+    config.consume_fuel(true);
+    Engine::new(&config).unwrap()
+});
+
+/// Filter that runs a WebAssembly module for every packet, so that custom
+/// packet processing can be deployed without building Quilkin.
+///
+/// The module exports its `memory`, `quilkin_buffer`, which returns the
+/// address of a [`BUFFER_LEN`] byte buffer in its memory, and either or both
+/// of `quilkin_read` and `quilkin_write`, which process the packets received
+/// from downstream and upstream respectively. These are called with the
+/// length of the packet, whose contents are in the buffer, and return the
+/// length of the contents they left in the buffer, or a negative number to
+/// drop the packet.
+///
+/// As a module's instance runs one packet at a time, the filter keeps a pool
+/// of instances, growing with the number of packets processed at once up to
+/// `max_instances`, beyond which packets are dropped. Instances that trap,
+/// such as by running out of fuel, are dropped along with their packet.
+pub struct Wasm {
+    module: Module,
+    fuel: u64,
+    max_memory_bytes: usize,
+    max_instances: usize,
+    has_write: bool,
+    guests: Mutex<Vec<Guest>>,
+    /// The number of instances, whether they're in the pool or processing a
+    /// packet.
+    instances: AtomicUsize,
+    metrics: Metrics,
+}
+
+/// An instance of the module.
+struct Guest {
+    store: Store<StoreLimits>,
+    memory: Memory,
+    /// The address of the packet buffer in `memory`.
+    buffer: usize,
+    read: Option<TypedFunc<i32, i32>>,
+    write: Option<TypedFunc<i32, i32>>,
+}
+
+impl Guest {
+    fn new(module: &Module, fuel: u64, max_memory_bytes: usize) -> wasmtime::Result<Self> {
+        let mut store = Store::new(
+            &ENGINE,
+            StoreLimitsBuilder::new()
+                .memory_size(max_memory_bytes)
+                .instances(1)
+                .build(),
+        );
+        store.limiter(|limits| limits);
+        store.add_fuel(fuel)?;
+
+        let instance = Instance::new(&mut store, module, &[])?;
+        let memory = instance
+            .get_memory(&mut store, "memory")
+            .ok_or_else(|| wasmtime::Error::msg("the module doesn't export its `memory`"))?;
+        let buffer: TypedFunc<(), i32> = instance.get_typed_func(&mut store, BUFFER)?;
+        let buffer = buffer.call(&mut store, ())? as u32 as usize;
+        if buffer + BUFFER_LEN > memory.data_size(&store) {
+            return Err(wasmtime::Error::msg(format!(
+                "the buffer returned by `{BUFFER}` must have {BUFFER_LEN} bytes of memory"
+            )));
+        }
+
+        Ok(Self {
+            read: instance.get_typed_func(&mut store, READ).ok(),
+            write: instance.get_typed_func(&mut store, WRITE).ok(),
+            store,
+            memory,
+            buffer,
+        })
+    }
+
+    /// Runs `function` on `contents`, with `fuel`, returning whether the
+    /// packet is kept.
+    fn call(
+        &mut self,
+        function: TypedFunc<i32, i32>,
+        contents: &mut Vec<u8>,
+        fuel: u64,
+    ) -> wasmtime::Result<bool> {
+        if contents.len() > BUFFER_LEN {
+            return Err(wasmtime::Error::msg("the packet doesn't fit in the buffer"));
+        }
+
+        let remaining = self.store.consume_fuel(0)?;
+        self.store.add_fuel(fuel.saturating_sub(remaining))?;
+        self.memory.write(&mut self.store, self.buffer, contents)?;
+
+        let len = function.call(&mut self.store, contents.len() as i32)?;
+        let Ok(len) = usize::try_from(len) else {
+            return Ok(false);
+        };
+        if len > BUFFER_LEN {
+            return Err(wasmtime::Error::msg(format!(
+                "the module returned a length of {len}, beyond the buffer"
+            )));
+        }
+
+        contents.resize(len, 0);
+        self.memory.read(&self.store, self.buffer, contents)?;
+        Ok(true)
+    }
+}
+
+/// Resolves the `path` of a module within the [`MODULE_DIR_ENV`] directory.
+fn module_path(path: &Path) -> Result<PathBuf, String> {
+    let dir = std::env::var_os(MODULE_DIR_ENV).ok_or_else(|| {
+        format!("modules can only be read from the directory set by `{MODULE_DIR_ENV}`")
+    })?;
+    let dir = std::fs::canonicalize(&dir)
+        .map_err(|error| format!("{MODULE_DIR_ENV} `{}`: {error}", Path::new(&dir).display()))?;
+    let path = std::fs::canonicalize(dir.join(path))
+        .map_err(|error| format!("`{}`: {error}", path.display()))?;
+    if !path.starts_with(&dir) {
+        return Err(format!(
+            "`{}` isn't within {MODULE_DIR_ENV} `{}`",
+            path.display(),
+            dir.display()
+        ));
+    }
+
+    Ok(path)
+}
+
+/// Runs `f`, which blocks, such as by reading and compiling a module. As
+/// filters are created synchronously, the other tasks of a runtime's worker
+/// thread are moved to other threads meanwhile, rather than being stalled.
+fn blocking<T>(f: impl FnOnce() -> T) -> T {
+    match tokio::runtime::Handle::try_current() {
+        Ok(handle) if handle.runtime_flavor() == tokio::runtime::RuntimeFlavor::MultiThread => {
+            tokio::task::block_in_place(f)
+        }
+        _ => f(),
+    }
+}
+
+impl Wasm {
+    fn new(config: Config, metrics: Metrics) -> Result<Self, Error> {
+        let invalid = |field: &str, error: &dyn std::fmt::Display| Error::FieldInvalid {
+            field: field.into(),
+            reason: format!("{error:#}"),
+        };
+
+        if config.max_instances == 0 {
+            return Err(invalid("max_instances", &"value must be at least 1"));
+        }
+        let max_memory_bytes = usize::try_from(config.max_memory_bytes).unwrap_or(usize::MAX);
+
+        let (module, guest) = blocking(|| {
+            let module = match &config.module {
+                Source::Path(path) => {
+                    let path = module_path(path).map_err(|error| invalid("module", &error))?;
+                    Module::from_file(&ENGINE, path)
+                }
+                Source::Inline(module) => Module::new(&ENGINE, module),
+            }
+            .map_err(|error| invalid("module", &error))?;
+
+            // Instantiating the module once checks that it implements the
+            // interface within the limits, before any packet reaches it.
+            let guest = Guest::new(&module, config.fuel, max_memory_bytes)
+                .map_err(|error| invalid("module", &error))?;
+            Ok::<_, Error>((module, guest))
+        })?;
+
+        Ok(Self {
+            has_write: guest.write.is_some(),
+            module,
+            fuel: config.fuel,
+            max_memory_bytes,
+            max_instances: config.max_instances as usize,
+            guests: Mutex::new(vec![guest]),
+            instances: AtomicUsize::new(1),
+            metrics,
+        })
+    }
+
+    /// Takes an instance from the pool, or creates one if there's room for
+    /// it, returning `None` when every instance is busy.
+    fn guest(&self) -> Option<wasmtime::Result<Guest>> {
+        if let Some(guest) = self.guests.lock().pop() {
+            return Some(Ok(guest));
+        }
+
+        self.instances
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |instances| {
+                (instances < self.max_instances).then_some(instances + 1)
+            })
+            .ok()?;
+        let guest = Guest::new(&self.module, self.fuel, self.max_memory_bytes);
+        if guest.is_err() {
+            self.instances.fetch_sub(1, Ordering::AcqRel);
+        }
+        Some(guest)
+    }
+
+    /// Runs the function `select` picks from an instance of the module on
+    /// `contents`, passing the packet when the module doesn't export it.
+    fn process(
+        &self,
+        select: fn(&Guest) -> Option<TypedFunc<i32, i32>>,
+        contents: &mut Vec<u8>,
+    ) -> Option<()> {
+        let Some(guest) = self.guest() else {
+            self.metrics.packets_dropped_busy.inc();
+            return None;
+        };
+        let result = guest.and_then(|mut guest| {
+            let kept = match select(&guest) {
+                Some(function) => guest.call(function, contents, self.fuel),
+                None => Ok(true),
+            };
+
+            // Instances that trapped may have been left in any state, so only
+            // those that succeeded are reused.
+            match kept {
+                Ok(_) => self.guests.lock().push(guest),
+                Err(_) => {
+                    self.instances.fetch_sub(1, Ordering::AcqRel);
+                }
+            }
+            kept
+        });
+
+        match result {
+            Ok(true) => Some(()),
+            Ok(false) => {
+                self.metrics.packets_dropped_module.inc();
+                None
+            }
+            Err(error) => {
+                tracing::debug!(error = %format!("{error:#}"), "Wasm module failed to process packet");
+                self.metrics.packets_dropped_trap.inc();
+                None
+            }
+        }
+    }
+}
+
+impl Filter for Wasm {
+    #[cfg_attr(feature = "instrument", tracing::instrument(skip(self, ctx)))]
+    fn read(&self, ctx: &mut ReadContext) -> Option<()> {
+        self.process(|guest| guest.read, &mut ctx.contents)
+    }
+
+    #[cfg_attr(feature = "instrument", tracing::instrument(skip(self, ctx)))]
+    fn write(&self, ctx: &mut WriteContext) -> Option<()> {
+        self.process(|guest| guest.write, &mut ctx.contents)
+    }
+
+    fn has_write(&self) -> bool {
+        self.has_write
+    }
+}
+
+impl StaticFilter for Wasm {
+    const NAME: &'static str = "quilkin.filters.wasm.v1alpha1.Wasm";
+    type Configuration = Config;
+    type BinaryConfiguration = proto::Wasm;
+
+    fn try_from_config(config: Option<Self::Configuration>) -> Result<Self, Error> {
+        Self::new(Self::ensure_config_exists(config)?, Metrics::new()?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+    use crate::endpoint::Endpoint;
+
+    /// Appends `!` to packets from downstream, dropping those starting with
+    /// `0`, and removes the last byte of packets from upstream.
+    const GUEST: &str = r#"
+        (module
+            (memory (export "memory") 2)
+            (func (export "quilkin_buffer") (result i32)
+                i32.const 0)
+            (func (export "quilkin_read") (param $len i32) (result i32)
+                (if (i32.eqz (i32.load8_u (i32.const 0)))
+                    (then (return (i32.const -1))))
+                (i32.store8 (local.get $len) (i32.const 33))
+                (i32.add (local.get $len) (i32.const 1)))
+            (func (export "quilkin_write") (param $len i32) (result i32)
+                (i32.sub (local.get $len) (i32.const 1))))
+    "#;
+
+    /// Loops forever on packets from downstream.
+    const LOOP: &str = r#"
+        (module
+            (memory (export "memory") 2)
+            (func (export "quilkin_buffer") (result i32)
+                i32.const 0)
+            (func (export "quilkin_read") (param i32) (result i32)
+                (loop $forever (br $forever))
+                i32.const 0))
+    "#;
+
+    fn wasm(config: Config) -> Result<Wasm, Error> {
+        Wasm::new(config, Metrics::new().unwrap())
+    }
+
+    fn inline(wat: &str) -> Config {
+        Config::new(Source::Inline(wat.as_bytes().to_vec()))
+    }
+
+    fn read(filter: &Wasm, contents: &[u8]) -> Option<Vec<u8>> {
+        let mut ctx = ReadContext::new(
+            vec![Endpoint::new((Ipv4Addr::LOCALHOST, 8089).into())],
+            (Ipv4Addr::LOCALHOST, 7000).into(),
+            contents.to_vec(),
+        );
+        filter.read(&mut ctx).map(|()| ctx.contents)
+    }
+
+    #[test]
+    fn read_and_write() {
+        let filter = wasm(inline(GUEST)).unwrap();
+        assert!(filter.has_write());
+
+        assert_eq!(Some(b"hello!".to_vec()), read(&filter, b"hello"));
+        assert_eq!(Some(b"again!".to_vec()), read(&filter, b"again"));
+        assert_eq!(None, read(&filter, b"\0dropped"));
+        assert_eq!(1, filter.metrics.packets_dropped_module.get());
+
+        let mut ctx = WriteContext::new(
+            Endpoint::new((Ipv4Addr::LOCALHOST, 8089).into()),
+            (Ipv4Addr::LOCALHOST, 8089).into(),
+            (Ipv4Addr::LOCALHOST, 7000).into(),
+            b"hello!".to_vec(),
+        );
+        assert!(filter.write(&mut ctx).is_some());
+        assert_eq!(b"hello", &*ctx.contents);
+    }
+
+    #[test]
+    fn out_of_fuel() {
+        let filter = wasm(Config {
+            fuel: 10_000,
+            ..inline(LOOP)
+        })
+        .unwrap();
+        assert!(!filter.has_write());
+
+        assert_eq!(None, read(&filter, b"hello"));
+        assert_eq!(1, filter.metrics.packets_dropped_trap.get());
+        // The instance that trapped was replaced.
+        assert!(filter.guests.lock().is_empty());
+        assert_eq!(0, filter.instances.load(Ordering::Relaxed));
+        assert_eq!(None, read(&filter, b"hello"));
+    }
+
+    #[test]
+    fn max_instances() {
+        assert!(wasm(Config {
+            max_instances: 0,
+            ..inline(GUEST)
+        })
+        .is_err());
+
+        let filter = wasm(Config {
+            max_instances: 1,
+            ..inline(GUEST)
+        })
+        .unwrap();
+        let guest = filter.guests.lock().pop().unwrap();
+        assert_eq!(None, read(&filter, b"hello"));
+        assert_eq!(1, filter.metrics.packets_dropped_busy.get());
+
+        filter.guests.lock().push(guest);
+        assert_eq!(Some(b"hello!".to_vec()), read(&filter, b"hello"));
+    }
+
+    #[test]
+    fn module_path() {
+        let dir = tempdir::TempDir::new("wasm").unwrap();
+        std::fs::write(dir.path().join("guest.wat"), GUEST).unwrap();
+        let path = |path: &str| Config::new(Source::Path(path.into()));
+
+        std::env::remove_var(MODULE_DIR_ENV);
+        assert!(wasm(path("guest.wat")).is_err());
+
+        std::env::set_var(MODULE_DIR_ENV, dir.path());
+        assert!(wasm(path("guest.wat")).is_ok());
+        assert!(wasm(path(&dir.path().join("guest.wat").to_string_lossy())).is_ok());
+        assert!(wasm(path("../guest.wat")).is_err());
+        assert!(wasm(path("/etc/hostname")).is_err());
+        std::env::remove_var(MODULE_DIR_ENV);
+    }
+
+    #[test]
+    fn invalid_module() {
+        assert!(wasm(Config::new(Source::Inline(b"not wasm".to_vec()))).is_err());
+        // The module's memory is larger than the limit.
+        assert!(wasm(Config {
+            max_memory_bytes: 65536,
+            ..inline(GUEST)
+        })
+        .is_err());
+        // The module doesn't export a buffer.
+        assert!(wasm(inline(r#"(module (memory (export "memory") 2))"#)).is_err());
+    }
+
+    #[test]
+    fn convert_proto_config() {
+        let config = Config {
+            fuel: 10_000,
+            ..inline(GUEST)
+        };
+        assert_eq!(
+            config,
+            Config::try_from(proto::Wasm::from(config.clone())).unwrap()
+        );
+        assert!(Config::try_from(proto::Wasm::default()).is_err());
+    }
+}
@@ -0,0 +1,125 @@
+/*
+ * Copyright 2023 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::{convert::TryFrom, path::PathBuf};
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::{config::Base64Standard, filters::ConvertProtoConfigError};
+
+use super::proto;
+
+const DEFAULT_FUEL: u64 = 1_000_000;
+const DEFAULT_MAX_MEMORY_BYTES: u64 = 16 * 1024 * 1024;
+const DEFAULT_MAX_INSTANCES: u32 = 16;
+
+/// Config represents a `Wasm` filter configuration.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// The WebAssembly module processing the packets.
+    pub module: Source,
+    /// The fuel the module can use for each packet, roughly the number of
+    /// instructions it runs, beyond which the packet is dropped.
+    #[serde(default = "default_fuel")]
+    pub fuel: u64,
+    /// The most memory in bytes each instance of the module can have, as
+    /// it's instantiated and as it grows.
+    #[serde(default = "default_max_memory_bytes")]
+    pub max_memory_bytes: u64,
+    /// The most instances of the module processing packets at once, packets
+    /// beyond them are dropped. Together with `max_memory_bytes`, this bounds
+    /// the memory the filter uses.
+    #[serde(default = "default_max_instances")]
+    pub max_instances: u32,
+}
+
+fn default_fuel() -> u64 {
+    DEFAULT_FUEL
+}
+
+fn default_max_memory_bytes() -> u64 {
+    DEFAULT_MAX_MEMORY_BYTES
+}
+
+fn default_max_instances() -> u32 {
+    DEFAULT_MAX_INSTANCES
+}
+
+impl Config {
+    pub fn new(module: Source) -> Self {
+        Self {
+            module,
+            fuel: DEFAULT_FUEL,
+            max_memory_bytes: DEFAULT_MAX_MEMORY_BYTES,
+            max_instances: DEFAULT_MAX_INSTANCES,
+        }
+    }
+}
+
+/// Where the module is loaded from.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Source {
+    /// The path of a `.wasm` file, read when the filter is created. The file
+    /// must be within the directory set by the
+    /// [`QUILKIN_WASM_MODULE_DIR`][super::MODULE_DIR_ENV] environment
+    /// variable, as the path may come from the management server, and
+    /// relative paths are relative to it.
+    Path(PathBuf),
+    /// The base64 encoded module.
+    Inline(
+        #[serde(with = "Base64Standard")]
+        #[schemars(with = "String")]
+        Vec<u8>,
+    ),
+}
+
+impl From<Config> for proto::Wasm {
+    fn from(config: Config) -> Self {
+        Self {
+            module: Some(match config.module {
+                Source::Path(path) => proto::wasm::Module::Path(path.to_string_lossy().into()),
+                Source::Inline(module) => proto::wasm::Module::Inline(module),
+            }),
+            fuel: Some(config.fuel),
+            max_memory_bytes: Some(config.max_memory_bytes),
+            max_instances: Some(config.max_instances),
+        }
+    }
+}
+
+impl TryFrom<proto::Wasm> for Config {
+    type Error = ConvertProtoConfigError;
+
+    fn try_from(p: proto::Wasm) -> Result<Self, Self::Error> {
+        let module = match p
+            .module
+            .ok_or_else(|| ConvertProtoConfigError::missing_field("module"))?
+        {
+            proto::wasm::Module::Path(path) => Source::Path(path.into()),
+            proto::wasm::Module::Inline(module) => Source::Inline(module),
+        };
+
+        Ok(Self {
+            module,
+            fuel: p.fuel.unwrap_or(DEFAULT_FUEL),
+            max_memory_bytes: p.max_memory_bytes.unwrap_or(DEFAULT_MAX_MEMORY_BYTES),
+            max_instances: p.max_instances.unwrap_or(DEFAULT_MAX_INSTANCES),
+        })
+    }
+}
@@ -0,0 +1,48 @@
+/*
+ * Copyright 2023 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use prometheus::{IntCounter, IntCounterVec, Result as MetricsResult};
+
+use crate::metrics::{filter_opts, CollectorExt};
+
+const REASON_LABEL: &str = "reason";
+
+/// Register and manage metrics for this filter
+pub(super) struct Metrics {
+    pub(super) packets_dropped_module: IntCounter,
+    pub(super) packets_dropped_trap: IntCounter,
+    pub(super) packets_dropped_busy: IntCounter,
+}
+
+impl Metrics {
+    pub(super) fn new() -> MetricsResult<Self> {
+        let dropped = IntCounterVec::new(
+            filter_opts(
+                "packets_dropped_total",
+                "Wasm",
+                "Total number of packets dropped by the module. Labels: reason.",
+            ),
+            &[REASON_LABEL],
+        )?
+        .register_if_not_exists()?;
+
+        Ok(Self {
+            packets_dropped_module: dropped.get_metric_with_label_values(&["module"])?,
+            packets_dropped_trap: dropped.get_metric_with_label_values(&["trap"])?,
+            packets_dropped_busy: dropped.get_metric_with_label_values(&["busy"])?,
+        })
+    }
+}
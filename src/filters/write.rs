@@ -19,12 +19,15 @@ use std::collections::HashMap;
 use crate::{
     endpoint::{Endpoint, EndpointAddress},
     metadata::DynamicMetadata,
+    proxy::decisions::{self, Step},
 };
 
 #[cfg(doc)]
 use crate::filters::Filter;
 
-/// The input arguments to [`Filter::write`].
+/// The input arguments to [`Filter::write`]. Outside of this crate it's
+/// created with [`WriteContext::new`] and its builder methods, as it's
+/// `#[non_exhaustive]` so that fields can be added to it.
 #[non_exhaustive]
 pub struct WriteContext {
     /// The upstream endpoint that we're expecting packets from.
@@ -35,8 +38,16 @@ pub struct WriteContext {
     pub dest: EndpointAddress,
     /// Contents of the received packet.
     pub contents: Vec<u8>,
-    /// Arbitrary values that can be passed from one filter to another
+    /// Arbitrary values that can be passed from one filter to another, read
+    /// by type with [`MetadataExt`][crate::metadata::MetadataExt].
     pub metadata: DynamicMetadata,
+    /// The filters the packet went through, when decisions are recorded.
+    pub(crate) trace: Option<Vec<Step>>,
+    /// The packets sent after this one, see [`WriteContext::send_additional`].
+    pub(crate) additional: Vec<Vec<u8>>,
+    /// How long the filter chain may spend on the packet, see
+    /// [`ExecutionBudget`][crate::filters::ExecutionBudget].
+    pub(crate) budget: Option<std::time::Duration>,
 }
 
 impl WriteContext {
@@ -53,6 +64,22 @@ impl WriteContext {
             dest,
             contents,
             metadata: HashMap::new(),
+            trace: decisions::trace(),
+            additional: Vec::new(),
+            budget: None,
         }
     }
+
+    /// Sets how long the filter chain may spend on the packet.
+    pub fn execution_budget(mut self, budget: Option<std::time::Duration>) -> Self {
+        self.budget = budget;
+        self
+    }
+
+    /// Sends `contents` as another packet to the same destination, right
+    /// after this one, if the filter chain passes it. The additional packet
+    /// doesn't go through the rest of the chain.
+    pub fn send_additional(&mut self, contents: Vec<u8>) {
+        self.additional.push(contents);
+    }
 }
@@ -16,20 +16,28 @@
 
 #![deny(unused_must_use)]
 
+extern crate alloc;
+
 mod admin;
 mod cluster;
+#[cfg(feature = "filter-geolocation")]
 mod maxmind_db;
 pub(crate) mod metrics;
 pub(crate) mod prost;
 mod proxy;
+mod quota;
 pub(crate) mod ttl_map;
 pub(crate) mod utils;
 
 pub mod cli;
+pub mod codec;
 pub mod config;
 pub mod endpoint;
 pub mod filters;
+pub mod masque;
 pub mod metadata;
+#[cfg(feature = "sim")]
+pub mod sim;
 pub mod xds;
 
 #[doc(hidden)]
@@ -40,26 +48,42 @@ pub type Result<T, E = eyre::Error> = std::result::Result<T, E>;
 #[doc(inline)]
 pub use self::{
     cli::{Cli, Proxy},
+    cluster::{ClusterChange, ClusterMap},
     config::Config,
 };
 
 pub use quilkin_macros::include_proto;
 
+pub use self::{cluster::UpstreamBind, utils::net::SocketConfig};
+
+#[cfg(feature = "filter-geolocation")]
 pub(crate) use self::maxmind_db::MaxmindDb;
 
 #[cfg(doctest)]
 mod external_doc_tests {
-    #![doc = include_str!("../docs/src/services/proxy/filters.md")]
-    #![doc = include_str!("../docs/src/services/proxy/filters/capture.md")]
-    #![doc = include_str!("../docs/src/services/proxy/filters/compress.md")]
-    #![doc = include_str!("../docs/src/services/proxy/filters/concatenate_bytes.md")]
-    #![doc = include_str!("../docs/src/services/proxy/filters/debug.md")]
-    #![doc = include_str!("../docs/src/services/proxy/filters/firewall.md")]
-    #![doc = include_str!("../docs/src/services/proxy/filters/load_balancer.md")]
-    #![doc = include_str!("../docs/src/services/proxy/filters/local_rate_limit.md")]
-    #![doc = include_str!("../docs/src/services/proxy/filters/match.md")]
-    #![doc = include_str!("../docs/src/services/proxy/filters/timestamp.md")]
-    #![doc = include_str!("../docs/src/services/proxy/filters/token_router.md")]
+    // The filter docs use every built-in filter in their examples.
+    #![cfg_attr(feature = "all-filters", doc = include_str!("../docs/src/services/proxy/filters.md"))]
+    #![cfg_attr(feature = "all-filters", doc = include_str!("../docs/src/services/proxy/filters/block_list.md"))]
+    #![cfg_attr(feature = "all-filters", doc = include_str!("../docs/src/services/proxy/filters/capture.md"))]
+    #![cfg_attr(feature = "all-filters", doc = include_str!("../docs/src/services/proxy/filters/compress.md"))]
+    #![cfg_attr(feature = "all-filters", doc = include_str!("../docs/src/services/proxy/filters/concatenate_bytes.md"))]
+    #![cfg_attr(feature = "all-filters", doc = include_str!("../docs/src/services/proxy/filters/debug.md"))]
+    #![cfg_attr(feature = "all-filters", doc = include_str!("../docs/src/services/proxy/filters/encrypt.md"))]
+    #![cfg_attr(feature = "all-filters", doc = include_str!("../docs/src/services/proxy/filters/ext_authz.md"))]
+    #![cfg_attr(feature = "all-filters", doc = include_str!("../docs/src/services/proxy/filters/firewall.md"))]
+    #![cfg_attr(feature = "all-filters", doc = include_str!("../docs/src/services/proxy/filters/geolocation.md"))]
+    #![cfg_attr(feature = "all-filters", doc = include_str!("../docs/src/services/proxy/filters/hmac.md"))]
+    #![cfg_attr(feature = "all-filters", doc = include_str!("../docs/src/services/proxy/filters/load_balancer.md"))]
+    #![cfg_attr(feature = "all-filters", doc = include_str!("../docs/src/services/proxy/filters/local_rate_limit.md"))]
+    #![cfg_attr(feature = "all-filters", doc = include_str!("../docs/src/services/proxy/filters/match.md"))]
+    #![cfg_attr(feature = "all-filters", doc = include_str!("../docs/src/services/proxy/filters/rate_limit.md"))]
+    #![cfg_attr(feature = "all-filters", doc = include_str!("../docs/src/services/proxy/filters/reassemble.md"))]
+    #![cfg_attr(feature = "all-filters", doc = include_str!("../docs/src/services/proxy/filters/reliable_control.md"))]
+    #![cfg_attr(feature = "all-filters", doc = include_str!("../docs/src/services/proxy/filters/response_validation.md"))]
+    #![cfg_attr(feature = "all-filters", doc = include_str!("../docs/src/services/proxy/filters/timestamp.md"))]
+    #![cfg_attr(feature = "all-filters", doc = include_str!("../docs/src/services/proxy/filters/token_router.md"))]
+    #![cfg_attr(feature = "all-filters", doc = include_str!("../docs/src/services/proxy/filters/ttl.md"))]
+    #![cfg_attr(feature = "filter-wasm", doc = include_str!("../docs/src/services/proxy/filters/wasm.md"))]
     #![doc = include_str!("../docs/src/services/proxy/filters/writing_custom_filters.md")]
-    #![doc = include_str!("../docs/src/services/xds/providers/filesystem.md")]
+    #![cfg_attr(feature = "all-filters", doc = include_str!("../docs/src/services/xds/providers/filesystem.md"))]
 }
@@ -0,0 +1,265 @@
+/*
+ * Copyright 2023 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! The wire format of MASQUE CONNECT-UDP ([RFC 9298]), which tunnels UDP
+//! payloads through HTTP.
+//!
+//! The proxy accepts CONNECT-UDP requests over HTTP/2 ([RFC 8441]) with
+//! [`serve`], the UDP payloads being carried in DATAGRAM capsules
+//! ([RFC 9297]). HTTP/3 isn't supported, but the framing here is the same one
+//! an HTTP/3 front would need to unwrap the UDP payloads of a request before
+//! forwarding them to the proxy.
+//!
+//! [RFC 9298]: https://www.rfc-editor.org/rfc/rfc9298
+//! [RFC 9297]: https://www.rfc-editor.org/rfc/rfc9297
+//! [RFC 8441]: https://www.rfc-editor.org/rfc/rfc8441
+
+mod server;
+
+pub use self::server::serve;
+
+/// The value of the `:protocol` pseudo-header of a CONNECT-UDP request.
+pub const PROTOCOL: &str = "connect-udp";
+/// The context ID of HTTP Datagrams that contain a UDP payload.
+pub const UDP_PAYLOAD_CONTEXT_ID: u64 = 0;
+/// The capsule type carrying an HTTP Datagram, used when the HTTP connection
+/// doesn't support datagrams (e.g. HTTP/2).
+pub const DATAGRAM_CAPSULE_TYPE: u64 = 0;
+
+const PATH_PREFIX: &str = "/.well-known/masque/udp/";
+const MAX_VARINT: u64 = (1 << 62) - 1;
+
+#[derive(Debug, PartialEq, Eq, thiserror::Error)]
+pub enum Error {
+    #[error("truncated variable-length integer")]
+    TruncatedVarint,
+    #[error("`{0}` doesn't match the CONNECT-UDP path template")]
+    InvalidPath(String),
+    #[error("invalid target port `{0}`")]
+    InvalidPort(String),
+}
+
+/// The UDP target of a CONNECT-UDP request.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Target {
+    pub host: String,
+    pub port: u16,
+}
+
+impl Target {
+    /// Parses the target from a request path using the default
+    /// `/.well-known/masque/udp/{target_host}/{target_port}/` template.
+    pub fn from_path(path: &str) -> Result<Self, Error> {
+        let invalid = || Error::InvalidPath(path.into());
+        let (host, port) = path
+            .strip_prefix(PATH_PREFIX)
+            .and_then(|rest| rest.strip_suffix('/'))
+            .and_then(|rest| rest.split_once('/'))
+            .filter(|(host, port)| !host.is_empty() && !port.contains('/'))
+            .ok_or_else(invalid)?;
+
+        Ok(Self {
+            host: percent_decode(host).ok_or_else(invalid)?,
+            port: port.parse().map_err(|_| Error::InvalidPort(port.into()))?,
+        })
+    }
+
+    /// Returns the request path for this target.
+    pub fn to_path(&self) -> String {
+        let host = self
+            .host
+            .chars()
+            .map(|c| match c {
+                ':' => "%3A".into(),
+                '%' => "%25".into(),
+                '/' => "%2F".into(),
+                c => c.to_string(),
+            })
+            .collect::<String>();
+
+        format!("{PATH_PREFIX}{host}/{}/", self.port)
+    }
+}
+
+/// Returns the UDP payload of an HTTP Datagram, or `None` if the datagram
+/// uses a context this implementation doesn't know, in which case it should
+/// be dropped.
+pub fn decode_datagram(datagram: &[u8]) -> Result<Option<&[u8]>, Error> {
+    let (context_id, length) = read_varint(datagram).ok_or(Error::TruncatedVarint)?;
+
+    Ok((context_id == UDP_PAYLOAD_CONTEXT_ID).then(|| &datagram[length..]))
+}
+
+/// Wraps a UDP payload in an HTTP Datagram.
+pub fn encode_datagram(payload: &[u8]) -> Vec<u8> {
+    let mut datagram = Vec::with_capacity(payload.len() + 1);
+    write_varint(UDP_PAYLOAD_CONTEXT_ID, &mut datagram);
+    datagram.extend_from_slice(payload);
+    datagram
+}
+
+/// A capsule ([RFC 9297] section 3.2) read from a request or response body.
+///
+/// [RFC 9297]: https://www.rfc-editor.org/rfc/rfc9297
+#[derive(Debug, PartialEq, Eq)]
+pub struct Capsule<'buf> {
+    pub r#type: u64,
+    pub value: &'buf [u8],
+}
+
+impl<'buf> Capsule<'buf> {
+    /// Reads the first capsule in `buf`, returning it alongside the number of
+    /// bytes it occupied, or `None` if `buf` doesn't contain a full capsule.
+    pub fn decode(buf: &'buf [u8]) -> Option<(Self, usize)> {
+        let (r#type, type_length) = read_varint(buf)?;
+        let (length, length_length) = read_varint(&buf[type_length..])?;
+        let start = type_length + length_length;
+        let end = start.checked_add(usize::try_from(length).ok()?)?;
+
+        buf.get(start..end)
+            .map(|value| (Self { r#type, value }, end))
+    }
+
+    /// Writes the capsule to `out`.
+    pub fn encode(&self, out: &mut Vec<u8>) {
+        write_varint(self.r#type, out);
+        write_varint(self.value.len() as u64, out);
+        out.extend_from_slice(self.value);
+    }
+}
+
+/// Reads a QUIC variable-length integer ([RFC 9000] section 16), returning
+/// it with the number of bytes it occupied.
+///
+/// [RFC 9000]: https://www.rfc-editor.org/rfc/rfc9000
+fn read_varint(buf: &[u8]) -> Option<(u64, usize)> {
+    let first = *buf.first()?;
+    let length = 1 << (first >> 6);
+    let bytes = buf.get(..length)?;
+
+    let value = bytes[1..]
+        .iter()
+        .fold(u64::from(first & 0x3f), |value, byte| {
+            (value << 8) | u64::from(*byte)
+        });
+
+    Some((value, length))
+}
+
+fn write_varint(value: u64, out: &mut Vec<u8>) {
+    debug_assert!(value <= MAX_VARINT);
+    match value {
+        0..=0x3f => out.push(value as u8),
+        0x40..=0x3fff => out.extend_from_slice(&(value as u16 | 0x4000).to_be_bytes()),
+        0x4000..=0x3fff_ffff => out.extend_from_slice(&(value as u32 | 0x8000_0000).to_be_bytes()),
+        _ => out.extend_from_slice(&(value | 0xc000_0000_0000_0000).to_be_bytes()),
+    }
+}
+
+fn percent_decode(input: &str) -> Option<String> {
+    let mut bytes = Vec::with_capacity(input.len());
+    let mut iter = input.bytes();
+
+    while let Some(byte) = iter.next() {
+        if byte == b'%' {
+            let hex = [iter.next()?, iter.next()?];
+            bytes.push(u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?);
+        } else {
+            bytes.push(byte);
+        }
+    }
+
+    String::from_utf8(bytes).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn target() {
+        let target = Target::from_path("/.well-known/masque/udp/192.0.2.6/443/").unwrap();
+        assert_eq!(
+            Target {
+                host: "192.0.2.6".into(),
+                port: 443
+            },
+            target
+        );
+        assert_eq!("/.well-known/masque/udp/192.0.2.6/443/", target.to_path());
+
+        let target = Target::from_path("/.well-known/masque/udp/2001%3Adb8%3A%3A42/7777/").unwrap();
+        assert_eq!("2001:db8::42", target.host);
+        assert_eq!(Target::from_path(&target.to_path()).as_ref(), Ok(&target));
+
+        assert!(matches!(
+            Target::from_path("/.well-known/masque/udp/host/port/"),
+            Err(Error::InvalidPort(_))
+        ));
+        assert!(matches!(
+            Target::from_path("/.well-known/masque/udp/host/443"),
+            Err(Error::InvalidPath(_))
+        ));
+        assert!(matches!(
+            Target::from_path("/.well-known/masque/ip/host/443/"),
+            Err(Error::InvalidPath(_))
+        ));
+    }
+
+    #[test]
+    fn varint() {
+        for value in [
+            0,
+            37,
+            15293,
+            494_878_333,
+            151_288_809_941_952_652,
+            MAX_VARINT,
+        ] {
+            let mut buf = Vec::new();
+            write_varint(value, &mut buf);
+            assert_eq!(Some((value, buf.len())), read_varint(&buf));
+        }
+
+        // The examples from RFC 9000 appendix A.1.
+        assert_eq!(Some((37, 1)), read_varint(&[0x25]));
+        assert_eq!(Some((15293, 2)), read_varint(&[0x7b, 0xbd]));
+        assert_eq!(None, read_varint(&[0x9d, 0x7f, 0x3e]));
+    }
+
+    #[test]
+    fn datagrams() {
+        let datagram = encode_datagram(b"hello");
+        assert_eq!(Ok(Some(&b"hello"[..])), decode_datagram(&datagram));
+        assert_eq!(Ok(None), decode_datagram(&[0x01, 0xff]));
+        assert_eq!(Err(Error::TruncatedVarint), decode_datagram(&[]));
+
+        let mut buf = Vec::new();
+        Capsule {
+            r#type: DATAGRAM_CAPSULE_TYPE,
+            value: &datagram,
+        }
+        .encode(&mut buf);
+        let length = buf.len();
+        buf.extend_from_slice(&[0x00, 0x10]);
+
+        let (capsule, read) = Capsule::decode(&buf).unwrap();
+        assert_eq!(length, read);
+        assert_eq!(DATAGRAM_CAPSULE_TYPE, capsule.r#type);
+        assert_eq!(Ok(Some(&b"hello"[..])), decode_datagram(capsule.value));
+        assert_eq!(None, Capsule::decode(&buf[read..]));
+    }
+}
@@ -0,0 +1,232 @@
+/*
+ * Copyright 2023 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::{convert::Infallible, net::SocketAddr, sync::Arc};
+
+use hyper::{server::conn::Http, service::service_fn, Body, Method, Request, Response, StatusCode};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::{TcpListener, UdpSocket},
+    sync::Semaphore,
+};
+
+use super::{Capsule, Target, DATAGRAM_CAPSULE_TYPE, PROTOCOL};
+
+/// The largest capsule that's buffered, as the largest UDP payload and its
+/// headers, so that a client can't exhaust the memory of the process.
+const MAX_CAPSULE_SIZE: usize = u16::MAX as usize + 16;
+/// The most CONNECT-UDP requests relayed at once for a connection, as each
+/// holds a socket of its own.
+const MAX_TUNNELS_PER_CONNECTION: usize = 16;
+
+/// Accepts CONNECT-UDP requests from `listener`, sending the UDP payloads of
+/// each request to `proxy` from a socket of its own, so that they go through
+/// the filter chain and sessions as the packets of any other client. The
+/// packets `proxy` sends back to that socket are returned to the request's
+/// client. As the packets come from that socket, filters acting on the
+/// source of packets see the proxy's own address rather than the client's.
+pub async fn serve(listener: TcpListener, proxy: SocketAddr) {
+    loop {
+        let (stream, remote) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(error) => {
+                tracing::warn!(%error, "failed to accept MASQUE connection");
+                continue;
+            }
+        };
+
+        tokio::spawn(async move {
+            let tunnels = Arc::new(Semaphore::new(MAX_TUNNELS_PER_CONNECTION));
+            let service = service_fn(move |request| handle(request, proxy, tunnels.clone()));
+            if let Err(error) = Http::new()
+                .http2_only(true)
+                .http2_enable_connect_protocol()
+                .serve_connection(stream, service)
+                .await
+            {
+                tracing::debug!(%error, %remote, "MASQUE connection failed");
+            }
+        });
+    }
+}
+
+async fn handle(
+    mut request: Request<Body>,
+    proxy: SocketAddr,
+    tunnels: Arc<Semaphore>,
+) -> Result<Response<Body>, Infallible> {
+    let protocol = request
+        .extensions()
+        .get::<hyper::ext::Protocol>()
+        .map(|protocol| protocol.as_str());
+    if request.method() != Method::CONNECT || protocol != Some(PROTOCOL) {
+        return Ok(status(StatusCode::METHOD_NOT_ALLOWED));
+    }
+
+    // The filter chain picks the endpoint as for any other packet, so the
+    // target is only checked to be valid.
+    if let Err(error) = Target::from_path(request.uri().path()) {
+        tracing::debug!(%error, "invalid CONNECT-UDP request");
+        return Ok(status(StatusCode::BAD_REQUEST));
+    }
+
+    let Ok(tunnel) = tunnels.try_acquire_owned() else {
+        tracing::debug!("too many CONNECT-UDP requests on the connection");
+        return Ok(status(StatusCode::TOO_MANY_REQUESTS));
+    };
+
+    let socket = match connect(proxy).await {
+        Ok(socket) => socket,
+        Err(error) => {
+            tracing::warn!(%error, "failed to open socket for CONNECT-UDP request");
+            return Ok(status(StatusCode::SERVICE_UNAVAILABLE));
+        }
+    };
+
+    let upgrade = hyper::upgrade::on(&mut request);
+    tokio::spawn(async move {
+        let result = match upgrade.await {
+            Ok(stream) => relay(stream, socket).await,
+            Err(error) => Err(std::io::Error::new(std::io::ErrorKind::Other, error)),
+        };
+        if let Err(error) = result {
+            tracing::debug!(%error, "CONNECT-UDP request failed");
+        }
+        drop(tunnel);
+    });
+
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .header("capsule-protocol", "?1")
+        .body(Body::empty())
+        .unwrap())
+}
+
+async fn connect(proxy: SocketAddr) -> std::io::Result<UdpSocket> {
+    let local: SocketAddr = if proxy.is_ipv4() {
+        (std::net::Ipv4Addr::UNSPECIFIED, 0).into()
+    } else {
+        (std::net::Ipv6Addr::UNSPECIFIED, 0).into()
+    };
+    let socket = UdpSocket::bind(local).await?;
+    socket.connect(proxy).await?;
+    Ok(socket)
+}
+
+/// Forwards the UDP payloads in the DATAGRAM capsules read from `stream` to
+/// `socket`, and writes the packets `socket` receives back to `stream` as
+/// DATAGRAM capsules, until either side is closed.
+async fn relay(
+    stream: impl AsyncRead + AsyncWrite + Unpin,
+    socket: UdpSocket,
+) -> std::io::Result<()> {
+    let (mut reader, mut writer) = tokio::io::split(stream);
+
+    let upstream = async {
+        let mut buf = Vec::new();
+        loop {
+            while let Some((capsule, length)) = Capsule::decode(&buf) {
+                // Capsules of other types, and datagrams of other contexts,
+                // are skipped.
+                if capsule.r#type == DATAGRAM_CAPSULE_TYPE {
+                    if let Ok(Some(payload)) = super::decode_datagram(capsule.value) {
+                        socket.send(payload).await?;
+                    }
+                }
+                buf.drain(..length);
+            }
+
+            if buf.len() > MAX_CAPSULE_SIZE {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    "CONNECT-UDP capsule too large",
+                ));
+            }
+            if reader.read_buf(&mut buf).await? == 0 {
+                return Ok(());
+            }
+        }
+    };
+
+    let downstream = async {
+        let mut packet = vec![0; u16::MAX as usize];
+        let mut out = Vec::new();
+        loop {
+            let length = socket.recv(&mut packet).await?;
+            out.clear();
+            Capsule {
+                r#type: DATAGRAM_CAPSULE_TYPE,
+                value: &super::encode_datagram(&packet[..length]),
+            }
+            .encode(&mut out);
+            writer.write_all(&out).await?;
+        }
+    };
+
+    tokio::select! {
+        result = upstream => result,
+        result = downstream => result,
+    }
+}
+
+fn status(status: StatusCode) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .body(Body::empty())
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn relay() {
+        let proxy = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let socket = connect(proxy.local_addr().unwrap()).await.unwrap();
+        let (mut client, stream) = tokio::io::duplex(1024);
+        tokio::spawn(super::relay(stream, socket));
+
+        // A capsule of an unknown type is skipped.
+        let mut request = Vec::new();
+        Capsule {
+            r#type: 0x2a,
+            value: b"skipped",
+        }
+        .encode(&mut request);
+        Capsule {
+            r#type: DATAGRAM_CAPSULE_TYPE,
+            value: &crate::masque::encode_datagram(b"hello"),
+        }
+        .encode(&mut request);
+        client.write_all(&request).await.unwrap();
+
+        let mut packet = [0; 64];
+        let (length, source) = proxy.recv_from(&mut packet).await.unwrap();
+        assert_eq!(b"hello", &packet[..length]);
+
+        proxy.send_to(b"world", source).await.unwrap();
+        let mut expected = Vec::new();
+        Capsule {
+            r#type: DATAGRAM_CAPSULE_TYPE,
+            value: &crate::masque::encode_datagram(b"world"),
+        }
+        .encode(&mut expected);
+        let mut response = vec![0; expected.len()];
+        client.read_exact(&mut response).await.unwrap();
+        assert_eq!(expected, response);
+    }
+}
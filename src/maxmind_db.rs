@@ -4,6 +4,8 @@ use bytes::Bytes;
 use maxminddb::Reader;
 use once_cell::sync::Lazy;
 
+use crate::endpoint::Coordinates;
+
 type Result<T, E = Error> = std::result::Result<T, E>;
 
 static HTTP: Lazy<
@@ -88,6 +90,37 @@ impl MaxmindDb {
         }
     }
 
+    /// Looks up the coordinates of `ip`, which requires a database with
+    /// location data, such as GeoLite2 City.
+    pub fn lookup_coordinates(ip: std::net::IpAddr) -> Option<Coordinates> {
+        let mmdb = crate::MaxmindDb::instance().clone()?;
+
+        match mmdb.lookup::<CityEntry>(ip) {
+            Ok(city) => city.location.map(|location| Coordinates {
+                latitude: location.latitude,
+                longitude: location.longitude,
+            }),
+            Err(error) => {
+                tracing::debug!(%ip, %error, "ip location not found in maxmind database");
+                None
+            }
+        }
+    }
+
+    /// Looks up the country and autonomous system of `ip`, from either a
+    /// GeoIP2 style database or an IPinfo style one.
+    pub fn lookup_location(ip: std::net::IpAddr) -> Option<Location> {
+        let mmdb = crate::MaxmindDb::instance().clone()?;
+
+        match mmdb.lookup::<LocationEntry>(ip) {
+            Ok(entry) => Some(entry.into()),
+            Err(error) => {
+                tracing::trace!(%ip, %error, "ip location not found in maxmind database");
+                None
+            }
+        }
+    }
+
     #[tracing::instrument(skip_all)]
     pub async fn update(source: Source) -> Result<()> {
         let db = Self::from_source(source).await?;
@@ -189,6 +222,57 @@ pub struct IpNetEntry {
     pub rpki_status: String,
 }
 
+#[derive(Debug, serde::Deserialize)]
+struct CityEntry {
+    location: Option<CityLocation>,
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct CityLocation {
+    latitude: f64,
+    longitude: f64,
+}
+
+/// The country and autonomous system of an IP address.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Location {
+    /// The ISO 3166-1 alpha-2 code of the country, in upper case.
+    pub country: Option<String>,
+    pub asn: Option<u64>,
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct LocationEntry {
+    // GeoIP2 and GeoLite2 databases.
+    country: Option<CountryEntry>,
+    autonomous_system_number: Option<u64>,
+    // IPinfo style databases, as used for the session metrics.
+    #[serde(default)]
+    as_cc: String,
+    #[serde(default)]
+    r#as: u64,
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct CountryEntry {
+    iso_code: Option<String>,
+}
+
+impl From<LocationEntry> for Location {
+    fn from(entry: LocationEntry) -> Self {
+        let country = entry
+            .country
+            .and_then(|country| country.iso_code)
+            .or_else(|| (!entry.as_cc.is_empty()).then_some(entry.as_cc))
+            .map(|country| country.to_ascii_uppercase());
+        let asn = entry
+            .autonomous_system_number
+            .or_else(|| (entry.r#as != 0).then_some(entry.r#as));
+
+        Self { country, asn }
+    }
+}
+
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
     #[error(transparent)]
@@ -14,6 +14,7 @@
  * limitations under the License.
  */
 
+mod schema;
 pub(crate) mod symbol;
 
 #[doc(hidden)]
@@ -25,6 +26,7 @@ use std::{collections::HashMap, convert::TryFrom};
 
 use crate::xds::config::core::v3::Metadata as ProtoMetadata;
 
+pub use schema::{KeySchema, MetadataSchema, NamespaceSchema, SchemaError, ValueType};
 pub use symbol::{Key, Reference, Symbol};
 
 /// Shared state between [`Filter`][crate::filters::Filter]s during processing for a single packet.
@@ -45,8 +47,8 @@ pub enum Value {
 }
 
 impl Value {
-    /// Returns the inner `String` value of `self` if it
-    /// matches [`Value::String`].
+    /// Returns the inner `Bytes` value of `self` if it
+    /// matches [`Value::Bytes`].
     pub fn as_bytes(&self) -> Option<&bytes::Bytes> {
         match self {
             Self::Bytes(value) => Some(value),
@@ -71,6 +73,80 @@ impl Value {
             _ => None,
         }
     }
+
+    /// Returns the inner `u64` value of `self` if it
+    /// matches [`Value::Number`].
+    pub fn as_number(&self) -> Option<u64> {
+        match self {
+            Self::Number(value) => Some(*value),
+            _ => None,
+        }
+    }
+
+    /// Returns the inner `bool` value of `self` if it
+    /// matches [`Value::Bool`].
+    pub fn as_bool(&self) -> Option<bool> {
+        match self {
+            Self::Bool(value) => Some(*value),
+            _ => None,
+        }
+    }
+
+    /// Returns the inner values of `self` if it
+    /// matches [`Value::List`].
+    pub fn as_list(&self) -> Option<&[Value]> {
+        match self {
+            Self::List(values) => Some(values),
+            _ => None,
+        }
+    }
+}
+
+/// Typed accessors of the values in [`DynamicMetadata`], so that a filter can
+/// consume what an earlier filter in the chain stored, such as a token
+/// captured from the packet, without matching on [`Value`] itself. Each
+/// accessor returns `None` when the key isn't set, or is set to a value of
+/// another type.
+///
+/// ```
+/// use quilkin::filters::prelude::*;
+///
+/// let mut metadata = DynamicMetadata::new();
+/// metadata.insert("token".into(), b"abc".into());
+/// metadata.insert("score".into(), 7u64.into());
+///
+/// assert_eq!(Some(&b"abc"[..]), metadata.get_bytes("token".into()).map(|token| &**token));
+/// assert_eq!(Some(7), metadata.get_number("score".into()));
+/// assert_eq!(None, metadata.get_number("token".into()));
+/// ```
+pub trait MetadataExt {
+    fn get_bytes(&self, key: Key) -> Option<&bytes::Bytes>;
+    fn get_string(&self, key: Key) -> Option<&str>;
+    fn get_number(&self, key: Key) -> Option<u64>;
+    fn get_bool(&self, key: Key) -> Option<bool>;
+    fn get_list(&self, key: Key) -> Option<&[Value]>;
+}
+
+impl MetadataExt for DynamicMetadata {
+    fn get_bytes(&self, key: Key) -> Option<&bytes::Bytes> {
+        self.get(&key)?.as_bytes()
+    }
+
+    fn get_string(&self, key: Key) -> Option<&str> {
+        self.get(&key)?.as_string()
+    }
+
+    fn get_number(&self, key: Key) -> Option<u64> {
+        self.get(&key)?.as_number()
+    }
+
+    fn get_bool(&self, key: Key) -> Option<bool> {
+        self.get(&key)?.as_bool()
+    }
+
+    fn get_list(&self, key: Key) -> Option<&[Value]> {
+        self.get(&key)?.as_list()
+    }
 }
 
 impl std::fmt::Display for Value {
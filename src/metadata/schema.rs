@@ -0,0 +1,269 @@
+/*
+ * Copyright 2023 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::collections::BTreeMap;
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::cluster::Cluster;
+
+/// The metadata namespaces that endpoints are expected to have, keyed by the
+/// namespace. Namespaces that aren't in the schema aren't validated.
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq, Eq, JsonSchema)]
+pub struct MetadataSchema(BTreeMap<String, NamespaceSchema>);
+
+/// The keys expected in a metadata namespace.
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq, Eq, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct NamespaceSchema {
+    /// The type of each key, keyed by the key.
+    #[serde(default)]
+    pub keys: BTreeMap<String, KeySchema>,
+    /// Whether keys missing from `keys` are allowed in the namespace.
+    #[serde(default = "default_additional_keys")]
+    pub additional_keys: bool,
+}
+
+fn default_additional_keys() -> bool {
+    true
+}
+
+/// The expected type of a metadata key.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct KeySchema {
+    #[serde(rename = "type")]
+    pub kind: ValueType,
+    /// Whether every endpoint must have the key.
+    #[serde(default)]
+    pub required: bool,
+}
+
+/// The type of a metadata value.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ValueType {
+    Bool,
+    Number,
+    String,
+    List,
+    Struct,
+}
+
+impl ValueType {
+    fn of(value: &serde_json::Value) -> &'static str {
+        match value {
+            serde_json::Value::Null => "null",
+            serde_json::Value::Bool(_) => "bool",
+            serde_json::Value::Number(_) => "number",
+            serde_json::Value::String(_) => "string",
+            serde_json::Value::Array(_) => "list",
+            serde_json::Value::Object(_) => "struct",
+        }
+    }
+
+    fn matches(self, value: &serde_json::Value) -> bool {
+        matches!(
+            (self, value),
+            (Self::Bool, serde_json::Value::Bool(_))
+                | (Self::Number, serde_json::Value::Number(_))
+                | (Self::String, serde_json::Value::String(_))
+                | (Self::List, serde_json::Value::Array(_))
+                | (Self::Struct, serde_json::Value::Object(_))
+        )
+    }
+}
+
+impl std::fmt::Display for ValueType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Bool => "bool",
+            Self::Number => "number",
+            Self::String => "string",
+            Self::List => "list",
+            Self::Struct => "struct",
+        })
+    }
+}
+
+/// Why an endpoint's metadata doesn't match the [`MetadataSchema`].
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+#[error("endpoint `{endpoint}` in cluster `{cluster}` has invalid metadata: {reason}")]
+pub struct SchemaError {
+    pub cluster: String,
+    pub endpoint: String,
+    pub reason: String,
+}
+
+impl MetadataSchema {
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Returns the schema of `namespace`, if present.
+    pub fn get(&self, namespace: &str) -> Option<&NamespaceSchema> {
+        self.0.get(namespace)
+    }
+
+    /// Validates the metadata of every endpoint in `cluster`, returning the
+    /// first mismatch.
+    pub fn validate(&self, cluster: &Cluster) -> Result<(), SchemaError> {
+        if self.is_empty() {
+            return Ok(());
+        }
+
+        for endpoint in cluster.endpoints() {
+            self.validate_metadata(&endpoint.metadata.unknown)
+                .map_err(|reason| SchemaError {
+                    cluster: cluster.name.clone(),
+                    endpoint: endpoint.address.to_string(),
+                    reason,
+                })?;
+        }
+
+        Ok(())
+    }
+
+    fn validate_metadata(
+        &self,
+        metadata: &serde_json::Map<String, serde_json::Value>,
+    ) -> Result<(), String> {
+        for (namespace, schema) in &self.0 {
+            let values = match metadata.get(namespace) {
+                Some(serde_json::Value::Object(values)) => values,
+                Some(value) => {
+                    return Err(format!(
+                        "namespace `{namespace}` must be a struct, found {}",
+                        ValueType::of(value)
+                    ))
+                }
+                None if schema.keys.values().any(|key| key.required) => {
+                    return Err(format!("missing required namespace `{namespace}`"))
+                }
+                None => continue,
+            };
+
+            for (key, key_schema) in &schema.keys {
+                match values.get(key) {
+                    Some(value) if !key_schema.kind.matches(value) => {
+                        return Err(format!(
+                            "`{namespace}.{key}` must be a {}, found {}",
+                            key_schema.kind,
+                            ValueType::of(value)
+                        ))
+                    }
+                    None if key_schema.required => {
+                        return Err(format!("missing required key `{namespace}.{key}`"))
+                    }
+                    _ => {}
+                }
+            }
+
+            if !schema.additional_keys {
+                if let Some(key) = values.keys().find(|key| !schema.keys.contains_key(*key)) {
+                    return Err(format!("unexpected key `{namespace}.{key}`"));
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl<const N: usize> From<[(String, NamespaceSchema); N]> for MetadataSchema {
+    fn from(value: [(String, NamespaceSchema); N]) -> Self {
+        Self(value.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::endpoint::{Endpoint, LocalityEndpoints};
+
+    fn schema() -> MetadataSchema {
+        serde_yaml::from_str(
+            "
+studio.example:
+  keys:
+    game_mode: { type: string, required: true }
+    max_players: { type: number }
+  additional_keys: false
+",
+        )
+        .unwrap()
+    }
+
+    fn cluster(metadata: serde_json::Value) -> Cluster {
+        let endpoint = Endpoint::with_metadata(
+            ([127, 0, 0, 1], 7777).into(),
+            crate::metadata::MetadataView::with_unknown(
+                crate::endpoint::Metadata::default(),
+                serde_json::from_value(metadata).unwrap(),
+            ),
+        );
+
+        Cluster::new("default".into(), vec![LocalityEndpoints::from(endpoint)])
+    }
+
+    #[test]
+    fn validate() {
+        let schema = schema();
+
+        schema
+            .validate(&cluster(serde_json::json!({
+                "studio.example": { "game_mode": "ranked", "max_players": 10 },
+                "other": { "anything": true },
+            })))
+            .unwrap();
+
+        let assert_invalid = |metadata, reason: &str| {
+            assert_eq!(
+                SchemaError {
+                    cluster: "default".into(),
+                    endpoint: "127.0.0.1:7777".into(),
+                    reason: reason.into(),
+                },
+                schema.validate(&cluster(metadata)).unwrap_err()
+            );
+        };
+
+        assert_invalid(
+            serde_json::json!({}),
+            "missing required namespace `studio.example`",
+        );
+        assert_invalid(
+            serde_json::json!({ "studio.example": { "max_players": 10 } }),
+            "missing required key `studio.example.game_mode`",
+        );
+        assert_invalid(
+            serde_json::json!({ "studio.example": { "game_mode": 1 } }),
+            "`studio.example.game_mode` must be a string, found number",
+        );
+        assert_invalid(
+            serde_json::json!({ "studio.example": { "game_mode": "ranked", "map": "dust" } }),
+            "unexpected key `studio.example.map`",
+        );
+    }
+
+    #[test]
+    fn empty_schema_accepts_anything() {
+        MetadataSchema::default()
+            .validate(&cluster(serde_json::json!({ "studio.example": 1 })))
+            .unwrap();
+    }
+}
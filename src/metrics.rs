@@ -14,10 +14,15 @@
  * limitations under the License.
  */
 
+use std::{
+    cell::RefCell,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
 use once_cell::sync::Lazy;
 use prometheus::{
-    core::Collector, Histogram, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, Opts,
-    Registry, DEFAULT_BUCKETS,
+    core::Collector, proto::MetricFamily, Histogram, HistogramOpts, HistogramVec, IntCounter,
+    IntCounterVec, Opts, Registry, DEFAULT_BUCKETS,
 };
 
 pub use prometheus::Result;
@@ -38,15 +43,132 @@ pub const READ_DIRECTION_LABEL: &str = "read";
 /// Label value for [DIRECTION_LABEL] for `write` events
 pub const WRITE_DIRECTION_LABEL: &str = "write";
 
+const PREFIX: &str = "quilkin";
+
+/// The multiple counts are rounded down to in aggregation only mode, or zero
+/// when it's disabled.
+static AGGREGATION_BUCKET: AtomicU64 = AtomicU64::new(0);
+
+thread_local! {
+    /// The registry that [CollectorExt::register_if_not_exists] registers
+    /// collectors in while inside [with_registry].
+    static SCOPED_REGISTRY: RefCell<Option<Registry>> = RefCell::new(None);
+}
+
 /// Returns the [prometheus::Registry] containing all the metrics
 /// registered in Quilkin.
 pub fn registry() -> &'static Registry {
-    static REGISTRY: Lazy<Registry> =
-        Lazy::new(|| Registry::new_custom(Some("quilkin".into()), None).unwrap());
+    static REGISTRY: Lazy<Registry> = Lazy::new(new_registry);
 
     &REGISTRY
 }
 
+/// Creates an empty registry for metrics that only live as long as some
+/// other object (such as a filter chain), rather than the whole process.
+pub fn new_registry() -> Registry {
+    Registry::new_custom(Some(PREFIX.into()), None).unwrap()
+}
+
+/// Runs `scope`, registering any collectors registered through
+/// [CollectorExt::register_if_not_exists] inside of it in `registry` rather
+/// than the global [registry].
+pub fn with_registry<T>(registry: &Registry, scope: impl FnOnce() -> T) -> T {
+    struct Restore(Option<Registry>);
+
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            let previous = self.0.take();
+            SCOPED_REGISTRY.with(|scoped| *scoped.borrow_mut() = previous);
+        }
+    }
+
+    let _restore = Restore(SCOPED_REGISTRY.with(|scoped| scoped.replace(Some(registry.clone()))));
+    scope()
+}
+
+fn current_registry() -> Registry {
+    SCOPED_REGISTRY
+        .with(|scoped| scoped.borrow().clone())
+        .unwrap_or_else(|| registry().clone())
+}
+
+/// Enables the aggregation only mode when `bucket` is set, for jurisdictions
+/// with strict telemetry rules. Labels identifying clients, such as their
+/// address prefix, are never emitted, and the values of counters and the
+/// counts of histograms are rounded down to multiples of `bucket` when
+/// gathered. Gauges are levels rather than counts of events, so they're
+/// emitted as is. Set before any metric is recorded.
+pub fn set_aggregation_only(bucket: Option<u64>) {
+    AGGREGATION_BUCKET.store(bucket.map_or(0, |bucket| bucket.max(1)), Ordering::Relaxed);
+}
+
+pub fn is_aggregation_only() -> bool {
+    AGGREGATION_BUCKET.load(Ordering::Relaxed) > 0
+}
+
+/// Returns the value of a label identifying clients, which is empty in
+/// aggregation only mode so that every client is counted in the same series.
+pub(crate) fn client_label(value: &str) -> &str {
+    if is_aggregation_only() {
+        ""
+    } else {
+        value
+    }
+}
+
+/// Rounds the counts of `families` down to multiples of `bucket`.
+fn round_counts(families: &mut [MetricFamily], bucket: u64) {
+    let round = |value: u64| value - value % bucket;
+    let round_f64 = |value: f64| (value / bucket as f64).floor() * bucket as f64;
+
+    for metric in families
+        .iter_mut()
+        .flat_map(|family| family.mut_metric().iter_mut())
+    {
+        if metric.has_counter() {
+            let counter = metric.mut_counter();
+            counter.set_value(round_f64(counter.get_value()));
+        }
+        if metric.has_histogram() {
+            let histogram = metric.mut_histogram();
+            histogram.set_sample_count(round(histogram.get_sample_count()));
+            for bucket in histogram.mut_bucket().iter_mut() {
+                bucket.set_cumulative_count(round(bucket.get_cumulative_count()));
+            }
+        }
+    }
+}
+
+/// Gathers the metrics from the global [registry] along with `scoped`,
+/// merging families that are present in more than one registry, and
+/// rounding their counts in aggregation only mode.
+pub fn gather(scoped: &[&Registry]) -> Vec<MetricFamily> {
+    let mut families = std::collections::BTreeMap::<String, MetricFamily>::new();
+
+    for mut family in std::iter::once(registry())
+        .chain(scoped.iter().copied())
+        .flat_map(Registry::gather)
+    {
+        match families.entry(family.get_name().to_owned()) {
+            std::collections::btree_map::Entry::Vacant(entry) => {
+                entry.insert(family);
+            }
+            std::collections::btree_map::Entry::Occupied(mut entry) => {
+                for metric in family.take_metric() {
+                    entry.get_mut().mut_metric().push(metric);
+                }
+            }
+        }
+    }
+
+    let mut families = families.into_values().collect::<Vec<_>>();
+    match AGGREGATION_BUCKET.load(Ordering::Relaxed) {
+        0 => {}
+        bucket => round_counts(&mut families, bucket),
+    }
+    families
+}
+
 /// Start the histogram bucket at a quarter of a millisecond, as number below a millisecond are
 /// what we are aiming for, but some granularity below a millisecond is useful for performance
 /// profiling.
@@ -157,6 +279,38 @@ pub(crate) fn packets_dropped_total(direction: Direction, reason: &str) -> IntCo
     PACKETS_DROPPED.with_label_values(&[direction.label(), reason])
 }
 
+pub(crate) fn filter_budget_exceeded_total(direction: Direction, filter: &str) -> IntCounter {
+    static BUDGET_EXCEEDED: Lazy<IntCounterVec> = Lazy::new(|| {
+        prometheus::register_int_counter_vec_with_registry! {
+            prometheus::opts! {
+                "filter_budget_exceeded_total",
+                "Total number of packets dropped for exceeding the filter chain execution budget",
+            },
+            &[Direction::LABEL, "filter"],
+            registry(),
+        }
+        .unwrap()
+    });
+
+    BUDGET_EXCEEDED.with_label_values(&[direction.label(), filter])
+}
+
+pub(crate) fn filter_config_upgrades_total(filter: &str, field: &str) -> IntCounter {
+    static CONFIG_UPGRADES: Lazy<IntCounterVec> = Lazy::new(|| {
+        prometheus::register_int_counter_vec_with_registry! {
+            prometheus::opts! {
+                "filter_config_upgrades_total",
+                "Total number of filter configs upgraded from a deprecated field name",
+            },
+            &["filter", "field"],
+            registry(),
+        }
+        .unwrap()
+    });
+
+    CONFIG_UPGRADES.with_label_values(&[filter, field])
+}
+
 /// Create a generic metrics options.
 /// Use [filter_opts] instead if the intended target is a filter.
 pub fn opts(name: &str, subsystem: &str, description: &str) -> Opts {
@@ -196,10 +350,11 @@ pub fn register<T: Collector + Sized + Clone + 'static>(collector: T) -> T {
 }
 
 pub trait CollectorExt: Collector + Clone + Sized + 'static {
-    /// Registers the current metric collector with the provided registry
-    /// if not already registered.
+    /// Registers the current metric collector with the current registry
+    /// if not already registered. This is the global [registry], unless
+    /// called inside [with_registry].
     fn register_if_not_exists(self) -> Result<Self> {
-        match registry().register(Box::from(self.clone())) {
+        match current_registry().register(Box::from(self.clone())) {
             Ok(_) | Err(prometheus::Error::AlreadyReg) => Ok(self),
             Err(err) => Err(err),
         }
@@ -207,3 +362,48 @@ pub trait CollectorExt: Collector + Clone + Sized + 'static {
 }
 
 impl<C: Collector + Clone + 'static> CollectorExt for C {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_counts() {
+        let registry = new_registry();
+        let counter = IntCounter::new("rounded_total", "test counter").unwrap();
+        registry.register(Box::new(counter.clone())).unwrap();
+        let histogram = Histogram::with_opts(
+            HistogramOpts::new("rounded_seconds", "test histogram").buckets(vec![1.0]),
+        )
+        .unwrap();
+        registry.register(Box::new(histogram.clone())).unwrap();
+
+        counter.inc_by(27);
+        for _ in 0..13 {
+            histogram.observe(0.5);
+        }
+        histogram.observe(2.0);
+
+        let mut families = registry.gather();
+        super::round_counts(&mut families, 10);
+        let metric = |name: &str| {
+            families
+                .iter()
+                .find(|family| family.get_name() == name)
+                .unwrap()
+                .get_metric()[0]
+                .clone()
+        };
+
+        assert_eq!(
+            20.0,
+            metric("quilkin_rounded_total").get_counter().get_value()
+        );
+        let histogram = metric("quilkin_rounded_seconds");
+        assert_eq!(10, histogram.get_histogram().get_sample_count());
+        assert_eq!(
+            10,
+            histogram.get_histogram().get_bucket()[0].get_cumulative_count()
+        );
+    }
+}
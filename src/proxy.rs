@@ -14,42 +14,85 @@
  * limitations under the License.
  */
 
+mod address_discovery;
+pub(crate) mod checksum;
+pub(crate) mod cpu_budget;
+pub(crate) mod decisions;
+pub(crate) mod dispatch;
+pub(crate) mod drain;
+pub(crate) mod sampling;
 mod sessions;
+mod tasks;
+mod unrouted;
+pub(crate) mod workers;
 
 use std::sync::Arc;
 
 use prometheus::HistogramTimer;
-use tokio::{net::UdpSocket, sync::watch};
+use tokio::{
+    net::UdpSocket,
+    sync::{mpsc, watch},
+};
 
 use crate::{
+    codec::rejection::Reason,
     endpoint::{Endpoint, EndpointAddress},
     filters::{Filter, ReadContext},
     ttl_map::TryResult,
     utils::debug,
-    Config,
+    Config, SocketConfig,
 };
 
-pub use sessions::{Session, SessionArgs, SessionKey, SessionMap};
+pub use address_discovery::AddressDiscovery;
+pub use dispatch::Dispatch;
+pub(crate) use sessions::{failure_domain, journal, metrics::set_endpoint_metrics, prewarm};
+pub use sessions::{
+    Eviction, Expiry, Session, SessionArgs, SessionKey, SessionMap, SessionMemory, SessionPolicy,
+    SessionShard,
+};
+pub use tasks::Tasks;
+pub(crate) use unrouted::RejectionLimit;
+pub use unrouted::UnroutedPolicy;
 
 /// Packet received from local port
 #[derive(Debug)]
-struct DownstreamPacket {
+pub(crate) struct DownstreamPacket {
     source: EndpointAddress,
     contents: Vec<u8>,
     timer: HistogramTimer,
 }
 
+impl DownstreamPacket {
+    /// Copies a packet just received from `source`, timing its processing
+    /// from now.
+    fn received(buf: &[u8], source: std::net::SocketAddr) -> Self {
+        Self {
+            source: crate::endpoint::normalize_socket_addr(source).into(),
+            contents: buf.to_vec(),
+            timer: crate::metrics::processing_time(crate::metrics::READ).start_timer(),
+        }
+    }
+}
+
 /// Represents the required arguments to run a worker task that
 /// processes packets received downstream.
 pub(crate) struct DownstreamReceiveWorkerConfig {
     /// ID of the worker.
     pub worker_id: usize,
-    /// Socket with reused port from which the worker receives packets.
+    /// Socket with reused port from which the worker receives packets, or
+    /// which it only sends replies from with keyed dispatch.
     pub socket: Arc<UdpSocket>,
     pub config: Arc<Config>,
+    /// The sessions of every worker. New sessions are added to this worker's
+    /// shard, while existing sessions are found in any shard, as clients can
+    /// move between workers when their number changes.
     pub sessions: SessionMap,
     /// The worker task exits when a value is received from this shutdown channel.
     pub shutdown_rx: watch::Receiver<()>,
+    /// The configuration of the sockets created for new sessions.
+    pub socket_config: Arc<SocketConfig>,
+    /// The most packets received with a single `recvmmsg` call on Linux.
+    pub recv_batch_size: usize,
 }
 
 impl DownstreamReceiveWorkerConfig {
@@ -60,12 +103,12 @@ impl DownstreamReceiveWorkerConfig {
             config,
             sessions,
             mut shutdown_rx,
+            socket_config,
+            recv_batch_size,
         } = self;
-
-        tokio::spawn(async move {
-            // Initialize a buffer for the UDP packet. We use the maximum size of a UDP
-            // packet, which is the maximum value of 16 a bit integer.
-            let mut buf = vec![0; 1 << 16];
+        let tasks = sessions.tasks().clone();
+        tasks.spawn("downstream worker", async move {
+            let mut buffers = crate::utils::mmsg::RecvBuffers::new(recv_batch_size);
             loop {
                 tracing::debug!(
                     id = worker_id,
@@ -73,9 +116,13 @@ impl DownstreamReceiveWorkerConfig {
                     "Awaiting packet"
                 );
                 tokio::select! {
-                    result = socket.recv_from(&mut buf) => {
+                    result = buffers.recv(&socket) => {
                         match result {
-                            Ok((size, source)) => Self::spawn_process_task(&buf, size, source, worker_id, &socket, &config, &sessions),
+                            Ok(_) => {
+                                for (contents, source) in buffers.packets() {
+                                    Self::spawn_process_task(contents, source, worker_id, &socket, &config, &sessions, &socket_config);
+                                }
+                            }
                             Err(error) => {
                                 tracing::error!(%error, "error receiving packet");
                                 return;
@@ -91,94 +138,376 @@ impl DownstreamReceiveWorkerConfig {
         });
     }
 
+    /// Spawns the worker processing the packets queued to it by the keyed
+    /// dispatcher, one at a time and in the order they were received, rather
+    /// than receiving from `socket`, which it only sends replies from.
+    pub fn spawn_keyed(self, mut queue: mpsc::Receiver<dispatch::Queued>) {
+        let Self {
+            worker_id,
+            socket,
+            config,
+            sessions,
+            mut shutdown_rx,
+            socket_config,
+            ..
+        } = self;
+        let tasks = sessions.tasks().clone();
+        tasks.spawn("downstream worker", async move {
+            loop {
+                tokio::select! {
+                    queued = queue.recv() => {
+                        let Some((packet, in_flight)) = queued else {
+                            return;
+                        };
+                        tracing::trace!(
+                            id = worker_id,
+                            size = packet.contents.len(),
+                            source = %packet.source,
+                            contents=&*debug::bytes_to_string(&packet.contents),
+                            "received packet from downstream"
+                        );
+                        Self::process(
+                            packet,
+                            worker_id,
+                            config.clone(),
+                            socket.clone(),
+                            sessions.clone(),
+                            socket_config.clone(),
+                        )
+                        .await;
+                        drop(in_flight);
+                    }
+                    _ = shutdown_rx.changed() => {
+                        tracing::debug!(id = worker_id, "Received shutdown signal");
+                        return;
+                    }
+                }
+            }
+        });
+    }
+
     #[inline]
     fn spawn_process_task(
         buf: &[u8],
-        size: usize,
         source: std::net::SocketAddr,
         worker_id: usize,
         socket: &Arc<UdpSocket>,
         config: &Arc<Config>,
         sessions: &SessionMap,
+        socket_config: &Arc<SocketConfig>,
     ) {
-        let timer = crate::metrics::processing_time(crate::metrics::READ).start_timer();
-        let contents = buf[..size].to_vec();
+        let in_flight = workers::InFlight::start();
+        let packet = DownstreamPacket::received(buf, source);
 
         tracing::trace!(
             id = worker_id,
-            size = size,
+            size = buf.len(),
             source = %source,
-            contents=&*debug::bytes_to_string(&contents),
+            contents=&*debug::bytes_to_string(&packet.contents),
             "received packet from downstream"
         );
 
-        let packet = DownstreamPacket {
-            source: source.into(),
-            contents,
-            timer,
-        };
         let config = config.clone();
         let sessions = sessions.clone();
         let socket = socket.clone();
+        let socket_config = socket_config.clone();
 
         tokio::spawn(async move {
-            match Self::process_downstream_received_packet(packet, config, socket, sessions).await {
-                Ok(size) => {
-                    crate::metrics::packets_total(crate::metrics::READ).inc();
-                    crate::metrics::bytes_total(crate::metrics::READ).inc_by(size as u64);
-                }
-                Err(error) => {
-                    crate::metrics::packets_dropped_total(
-                        crate::metrics::READ,
-                        "proxy::Session::send",
-                    )
+            let _in_flight = in_flight;
+            Self::process(packet, worker_id, config, socket, sessions, socket_config).await;
+        });
+    }
+
+    /// Processes a packet, recording its outcome in the metrics.
+    async fn process(
+        packet: DownstreamPacket,
+        worker_id: usize,
+        config: Arc<Config>,
+        socket: Arc<UdpSocket>,
+        sessions: SessionMap,
+        socket_config: Arc<SocketConfig>,
+    ) {
+        match Self::process_downstream_received_packet(
+            packet,
+            worker_id,
+            config,
+            socket,
+            sessions,
+            socket_config,
+        )
+        .await
+        {
+            Ok(size) => {
+                crate::metrics::packets_total(crate::metrics::READ).inc();
+                crate::metrics::bytes_total(crate::metrics::READ).inc_by(size as u64);
+            }
+            Err(error) => {
+                crate::metrics::packets_dropped_total(crate::metrics::READ, "proxy::Session::send")
                     .inc();
-                    crate::metrics::errors_total(crate::metrics::READ).inc();
-                    tracing::error!(kind=%error.kind(), "{}", error);
-                }
+                crate::metrics::errors_total(crate::metrics::READ).inc();
+                tracing::error!(kind=%error.kind(), "{}", error);
             }
-        });
+        }
     }
 
     /// Processes a packet by running it through the filter chain.
     async fn process_downstream_received_packet(
         packet: DownstreamPacket,
+        worker_id: usize,
         config: Arc<Config>,
         downstream_socket: Arc<UdpSocket>,
         sessions: SessionMap,
+        socket_config: Arc<SocketConfig>,
     ) -> std::io::Result<usize> {
-        let clusters = config.clusters.load();
-        let endpoints: Vec<_> = clusters.endpoints().collect();
-        if endpoints.is_empty() {
-            return Err(std::io::Error::new(
-                std::io::ErrorKind::Other,
-                "dropping packet, no upstream endpoints available",
-            ));
+        let discovery = config.address_discovery.load();
+        if discovery.is_request(&packet.contents) {
+            let source = packet.source.to_socket_addr()?;
+            if let Some(response) = discovery.respond(&packet.contents, source) {
+                downstream_socket.send_to(&response, source).await?;
+                address_discovery::requests_total().inc();
+                tracing::trace!(%source, "answered address discovery request");
+            }
+            packet.timer.stop_and_record();
+            return Ok(0);
         }
 
-        let filters = config.filters.load();
-        let mut context = ReadContext::new(endpoints, packet.source, packet.contents);
-        let result = filters.read(&mut context);
+        if let Some(budget) = cpu_budget::budget() {
+            let source = packet.source.to_socket_addr()?;
+            if !budget.allows(source.ip()) {
+                crate::metrics::packets_dropped_total(
+                    crate::metrics::READ,
+                    cpu_budget::BUDGET_EXCEEDED_REASON,
+                )
+                .inc();
+                tracing::trace!(%source, "dropping packet, source is over its CPU budget");
+                packet.timer.stop_and_record();
+                return Ok(0);
+            }
+        }
+
+        let policy = config.unrouted.load();
+        // Buffered packets are routed again, so they need a copy of the
+        // original contents.
+        let original =
+            matches!(*policy, UnroutedPolicy::Buffer { .. }).then(|| packet.contents.clone());
+
+        let size = packet.contents.len();
+        let mut context = match Self::route(&config, packet.source.clone(), packet.contents) {
+            Ok(context) => context,
+            Err(reason) => {
+                Self::reject(&config, &downstream_socket, &packet.source, size, reason).await?;
+                packet.timer.stop_and_record();
+                return Ok(0);
+            }
+        };
+
+        if context.endpoints.is_empty() {
+            unrouted::packets_unrouted_total(policy.action()).inc();
+            match &*policy {
+                UnroutedPolicy::Drop => {}
+                UnroutedPolicy::Respond {
+                    payload,
+                    max_per_second,
+                } => {
+                    // The same guards as rejections, as the source may be
+                    // spoofed.
+                    if payload.len() > size {
+                        unrouted::responses_suppressed_total("too_large").inc();
+                    } else if !config.rejections.admit(*max_per_second) {
+                        unrouted::responses_suppressed_total("rate_limited").inc();
+                    } else {
+                        let size = downstream_socket
+                            .send_to(payload, context.source.to_socket_addr()?)
+                            .await?;
+                        tracing::trace!(source = %context.source, size, "responded to unrouted packet");
+                    }
+                }
+                UnroutedPolicy::Fallback { cluster } => {
+                    context.endpoints = config
+                        .clusters
+                        .load()
+                        .get(cluster)
+                        .map(|cluster| {
+                            config
+                                .clusters
+                                .healthy(cluster.endpoints().cloned().collect())
+                        })
+                        .unwrap_or_default();
+                }
+                UnroutedPolicy::Buffer {
+                    timeout_ms,
+                    max_packets,
+                } => {
+                    let contents = original.unwrap_or_default();
+                    match unrouted::Buffered::try_new(*timeout_ms, *max_packets) {
+                        Some(buffered) => {
+                            if let Some(routed) =
+                                Self::buffer(&config, &context.source, contents, buffered).await
+                            {
+                                context = routed;
+                            }
+                        }
+                        None => unrouted::packets_buffered_total("overflow").inc(),
+                    }
+                }
+                UnroutedPolicy::Reject { .. } => {
+                    Self::reject(
+                        &config,
+                        &downstream_socket,
+                        &context.source,
+                        size,
+                        Reason::NoEndpoint,
+                    )
+                    .await?;
+                }
+            }
+
+            if context.endpoints.is_empty() {
+                crate::metrics::packets_dropped_total(
+                    crate::metrics::READ,
+                    "NoConfiguredEndpoints",
+                )
+                .inc();
+                tracing::debug!(source = %context.source, "dropping packet, no upstream endpoints available");
+                packet.timer.stop_and_record();
+                return Ok(0);
+            }
+        }
 
+        let token = sessions::prewarm::token(&context.metadata);
         let mut bytes_written = 0;
-        if let Some(()) = result {
-            for endpoint in context.endpoints.iter() {
+        for endpoint in context.endpoints.iter() {
+            for contents in std::iter::once(&context.contents).chain(&context.additional) {
                 bytes_written += Self::session_send_packet(
-                    &context.contents,
+                    contents,
                     &context.source,
                     endpoint,
                     &downstream_socket,
                     &config,
                     &sessions,
+                    worker_id,
+                    &socket_config,
+                    token.as_deref(),
                 )
                 .await?;
             }
         }
 
-        packet.timer.stop_and_record();
+        let seconds = packet.timer.stop_and_record();
+        for endpoint in context.endpoints.iter() {
+            sessions::metrics::endpoint_processing_time(
+                crate::metrics::READ,
+                &endpoint.address,
+                seconds,
+            );
+        }
         Ok(bytes_written)
     }
 
+    /// Runs a packet through the filter chain, returning the reason to
+    /// reject it with if a filter dropped it. The chain isn't run when there
+    /// are no endpoints at all.
+    fn route(
+        config: &Config,
+        source: EndpointAddress,
+        contents: Vec<u8>,
+    ) -> Result<ReadContext, Reason> {
+        let endpoints = config.clusters.healthy_endpoints();
+        let mut context = ReadContext::new(endpoints, source, contents)
+            .mitigations(config.mitigations.clone())
+            .rate_limits(config.rate_limits.clone())
+            .execution_budget(config.filter_budget.get())
+            .added(config.clusters.added());
+        if context.endpoints.is_empty() {
+            return Ok(context);
+        }
+
+        let filters = config.filters.load();
+        let budget = cpu_budget::budget()
+            .and_then(|budget| Some((budget, context.source.to_socket_addr().ok()?.ip())));
+        let result = match budget {
+            Some((budget, ip)) => {
+                let start = std::time::Instant::now();
+                let result = filters.read(&mut context);
+                budget.charge(ip, start.elapsed());
+                result
+            }
+            None => filters.read(&mut context),
+        };
+
+        decisions::read(&mut context, result.is_some());
+        match result {
+            Some(()) => Ok(context),
+            None => Err(unrouted::dropped_reason(context.dropped.as_ref())),
+        }
+    }
+
+    /// Sends `source` a rejection for `reason` when the unrouted policy is
+    /// [`UnroutedPolicy::Reject`], if its packet of `size` bytes was at least
+    /// as large as the rejection, and the policy's rate allows it.
+    async fn reject(
+        config: &Config,
+        downstream_socket: &UdpSocket,
+        source: &EndpointAddress,
+        size: usize,
+        reason: Reason,
+    ) -> std::io::Result<()> {
+        let policy = config.unrouted.load();
+        let UnroutedPolicy::Reject { key, max_per_second } = &*policy else {
+            return Ok(());
+        };
+        if size < crate::codec::rejection::LEN {
+            return Ok(());
+        }
+        if !config.rejections.admit(*max_per_second) {
+            unrouted::rejections_limited_total().inc();
+            return Ok(());
+        }
+
+        let rejection = unrouted::sign_rejection(key, reason)
+            .map_err(|error| std::io::Error::new(std::io::ErrorKind::Other, error))?;
+        downstream_socket
+            .send_to(&rejection, source.to_socket_addr()?)
+            .await?;
+        unrouted::rejections_total(reason).inc();
+        tracing::trace!(%source, ?reason, "rejected unrouted packet");
+        Ok(())
+    }
+
+    /// Holds an unrouted packet until it can be routed after the clusters
+    /// change, or until its buffer timeout passes.
+    async fn buffer(
+        config: &Config,
+        source: &EndpointAddress,
+        contents: Vec<u8>,
+        buffered: unrouted::Buffered,
+    ) -> Option<ReadContext> {
+        let mut changes = config.clusters.changes();
+        let deadline = tokio::time::Instant::now() + buffered.timeout;
+
+        loop {
+            match tokio::time::timeout_at(deadline, changes.recv()).await {
+                Ok(Ok(_) | Err(tokio::sync::broadcast::error::RecvError::Lagged(_))) => {}
+                Ok(Err(tokio::sync::broadcast::error::RecvError::Closed)) | Err(_) => {
+                    unrouted::packets_buffered_total("expired").inc();
+                    return None;
+                }
+            }
+
+            match Self::route(config, source.clone(), contents.clone()) {
+                Ok(context) if context.endpoints.is_empty() => continue,
+                Ok(context) => {
+                    unrouted::packets_buffered_total("routed").inc();
+                    return Some(context);
+                }
+                Err(_) => {
+                    unrouted::packets_buffered_total("dropped").inc();
+                    return None;
+                }
+            }
+        }
+    }
+
     /// Send a packet received from `recv_addr` to an endpoint.
     #[tracing::instrument(level="trace", skip_all, fields(source = %recv_addr, dest = %endpoint.address))]
     async fn session_send_packet(
@@ -188,25 +517,83 @@ impl DownstreamReceiveWorkerConfig {
         downstream_socket: &Arc<UdpSocket>,
         config: &Arc<Config>,
         sessions: &SessionMap,
+        worker_id: usize,
+        socket_config: &Arc<SocketConfig>,
+        token: Option<&[u8]>,
     ) -> std::io::Result<usize> {
-        let session_key = SessionKey {
+        let mut session_key = SessionKey {
             source: recv_addr.clone(),
             dest: endpoint.address.clone(),
         };
 
-        let send_future = match sessions.try_get(&session_key) {
+        let mut shard = sessions.shard_for(&session_key, worker_id);
+        let failover = if shard.contains_key(&session_key) {
+            None
+        } else {
+            self::sessions::failover_endpoint(config, recv_addr, endpoint, |dest| {
+                sessions.contains_key(&SessionKey {
+                    source: recv_addr.clone(),
+                    dest: dest.clone(),
+                })
+            })
+        };
+        let endpoint = match &failover {
+            Some(failover) => {
+                session_key.dest = failover.address.clone();
+                shard = sessions.shard_for(&session_key, worker_id);
+                failover
+            }
+            None => endpoint,
+        };
+
+        let entry = match shard.try_get(&session_key) {
+            // A session whose connection failed, such as its DTLS handshake,
+            // is replaced by a new one.
+            TryResult::Present(entry) if entry.is_closed() => {
+                drop(entry);
+                shard.remove(&session_key);
+                TryResult::Absent
+            }
+            entry => entry,
+        };
+        let send_future = match entry {
             TryResult::Present(entry) => entry.send(packet),
+            TryResult::Absent if drain::is_draining() => {
+                crate::metrics::packets_dropped_total(crate::metrics::READ, drain::DRAINING_REASON)
+                    .inc();
+                tracing::trace!(source = %recv_addr, "dropping packet, the proxy is draining");
+                Self::reject(
+                    config,
+                    downstream_socket,
+                    recv_addr,
+                    packet.len(),
+                    Reason::Draining,
+                )
+                .await?;
+                return Ok(0);
+            }
             TryResult::Absent => {
+                let policy = sessions.policy(config);
+                let _reservation = sessions.reserve_session(&policy)?;
+                sessions.reserve_memory()?;
                 let session_args = SessionArgs {
                     config: config.clone(),
                     source: session_key.source.clone(),
                     downstream_socket: downstream_socket.clone(),
                     dest: endpoint.clone(),
+                    socket_config: socket_config.clone(),
+                    tasks: sessions.tasks().clone(),
+                    memory: sessions.memory().clone(),
+                    token: token.map(<[u8]>::to_vec),
                 };
 
                 let session = session_args.into_session().await?;
                 let future = session.send(packet);
-                sessions.insert(session_key, session);
+                let ttl = session.idle_timeout().unwrap_or_else(|| policy.ttl());
+                match policy.expiry() {
+                    Expiry::Idle => shard.insert_with_ttl(session_key, session, ttl),
+                    Expiry::Absolute => shard.insert_absolute(session_key, session, ttl),
+                };
                 future
             }
             TryResult::Locked => {
@@ -0,0 +1,134 @@
+/*
+ * Copyright 2023 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! A STUN-like responder telling clients the address the proxy sees their
+//! packets coming from, so that game clients behind NAT can learn their
+//! reflexive address without a separate STUN deployment.
+
+use std::net::{IpAddr, SocketAddr};
+
+use once_cell::sync::Lazy;
+use prometheus::IntCounter;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+const IPV4_FAMILY: u8 = 4;
+const IPV6_FAMILY: u8 = 6;
+
+pub(crate) fn requests_total() -> &'static IntCounter {
+    static REQUESTS: Lazy<IntCounter> = Lazy::new(|| {
+        prometheus::register_int_counter_with_registry! {
+            prometheus::opts! {
+                "address_discovery_requests_total",
+                "Total number of address discovery requests answered",
+            },
+            crate::metrics::registry(),
+        }
+        .unwrap()
+    });
+
+    &REQUESTS
+}
+
+/// Answers packets starting with `prefix` with the address they were sent
+/// from, instead of routing them. Disabled while `prefix` is empty.
+///
+/// The response is `prefix`, followed by the address family (`4` or `6`),
+/// the port as a big endian `u16`, and the 4 or 16 bytes of the IP address.
+/// Requests shorter than their response are dropped, so that the responder
+/// can't be used to amplify reflection attacks.
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq, Eq, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct AddressDiscovery {
+    /// The base64 encoded bytes that identify a request.
+    #[serde(default, with = "crate::config::Base64Standard")]
+    #[schemars(with = "String")]
+    pub prefix: Vec<u8>,
+}
+
+impl AddressDiscovery {
+    /// Whether `packet` is an address discovery request, rather than a packet
+    /// to route.
+    pub(crate) fn is_request(&self, packet: &[u8]) -> bool {
+        !self.prefix.is_empty() && packet.starts_with(&self.prefix)
+    }
+
+    /// Returns the response to `packet` received from `source`, or `None` if
+    /// `packet` isn't a request or is too short to be answered.
+    pub(crate) fn respond(&self, packet: &[u8], source: SocketAddr) -> Option<Vec<u8>> {
+        if !self.is_request(packet) {
+            return None;
+        }
+
+        let mut response = self.prefix.clone();
+        match crate::endpoint::canonical_ip(source.ip()) {
+            IpAddr::V4(ip) => {
+                response.push(IPV4_FAMILY);
+                response.extend_from_slice(&source.port().to_be_bytes());
+                response.extend_from_slice(&ip.octets());
+            }
+            IpAddr::V6(ip) => {
+                response.push(IPV6_FAMILY);
+                response.extend_from_slice(&source.port().to_be_bytes());
+                response.extend_from_slice(&ip.octets());
+            }
+        }
+
+        (packet.len() >= response.len()).then_some(response)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    use super::*;
+
+    #[test]
+    fn respond() {
+        let discovery = AddressDiscovery {
+            prefix: b"WHOAMI".to_vec(),
+        };
+        let request = [b"WHOAMI".as_slice(), &[0; 19]].concat();
+
+        let source = (Ipv4Addr::new(203, 0, 113, 7), 0x1e61).into();
+        assert_eq!(
+            Some([b"WHOAMI".as_slice(), &[4, 0x1e, 0x61, 203, 0, 113, 7]].concat()),
+            discovery.respond(&request, source)
+        );
+
+        let mapped = (Ipv4Addr::new(203, 0, 113, 7).to_ipv6_mapped(), 0x1e61).into();
+        assert_eq!(
+            discovery.respond(&request, source),
+            discovery.respond(&request, mapped)
+        );
+
+        let source = (Ipv6Addr::LOCALHOST, 7777).into();
+        let response = discovery.respond(&request, source).unwrap();
+        assert_eq!(6 + 1 + 2 + 16, response.len());
+        assert_eq!(IPV6_FAMILY, response[6]);
+
+        // Not requests.
+        assert!(discovery
+            .respond(b"hello world, hello world", source)
+            .is_none());
+        assert!(AddressDiscovery::default()
+            .respond(&request, source)
+            .is_none());
+        // Too short to be answered without amplification.
+        assert!(discovery.respond(b"WHOAMI", source).is_none());
+    }
+}
@@ -0,0 +1,145 @@
+/*
+ * Copyright 2023 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! The UDP checksum errors counted by the kernel, which drops packets with
+//! bad checksums before the proxy can receive them, so that broken checksum
+//! offload can be told apart from packet loss elsewhere.
+
+use once_cell::sync::Lazy;
+use prometheus::{
+    core::{Collector, Desc},
+    proto::MetricFamily,
+    IntCounterVec,
+};
+
+const SUBSYSTEM: &str = "udp";
+
+/// Mirrors the kernel's counters on every scrape.
+#[derive(Clone)]
+struct KernelChecksumErrors(IntCounterVec);
+
+impl Collector for KernelChecksumErrors {
+    fn desc(&self) -> Vec<&Desc> {
+        self.0.desc()
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        for (family, errors) in kernel_checksum_errors() {
+            let counter = self.0.with_label_values(&[family]);
+            counter.inc_by(errors.saturating_sub(counter.get()));
+        }
+
+        self.0.collect()
+    }
+}
+
+/// Registers `udp_checksum_errors_total`, once.
+pub(crate) fn register_metrics() {
+    static METRICS: Lazy<KernelChecksumErrors> = Lazy::new(|| {
+        crate::metrics::register(KernelChecksumErrors(
+            IntCounterVec::new(
+                crate::metrics::opts(
+                    "checksum_errors_total",
+                    SUBSYSTEM,
+                    "Total number of UDP packets the kernel dropped because of bad checksums, in the proxy's network namespace. Labels: family",
+                ),
+                &["family"],
+            )
+            .unwrap(),
+        ))
+    });
+
+    Lazy::force(&METRICS);
+}
+
+/// The number of UDP packets received with bad checksums, for each address
+/// family the kernel reports it for.
+#[cfg(target_os = "linux")]
+fn kernel_checksum_errors() -> Vec<(&'static str, u64)> {
+    let read = |path: &str| std::fs::read_to_string(path).ok();
+
+    [
+        (
+            "ipv4",
+            read("/proc/net/snmp").and_then(|snmp| parse_snmp(&snmp)),
+        ),
+        (
+            "ipv6",
+            read("/proc/net/snmp6").and_then(|snmp6| parse_snmp6(&snmp6)),
+        ),
+    ]
+    .into_iter()
+    .filter_map(|(family, errors)| Some((family, errors?)))
+    .collect()
+}
+
+#[cfg(not(target_os = "linux"))]
+fn kernel_checksum_errors() -> Vec<(&'static str, u64)> {
+    Vec::new()
+}
+
+/// Reads `InCsumErrors` from the `Udp:` lines of `/proc/net/snmp`, the first
+/// of which names the fields of the second.
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+fn parse_snmp(snmp: &str) -> Option<u64> {
+    let mut lines = snmp.lines().filter(|line| line.starts_with("Udp:"));
+    let names = lines.next()?.split_whitespace();
+    let values = lines.next()?.split_whitespace();
+
+    names
+        .zip(values)
+        .find(|(name, _)| *name == "InCsumErrors")?
+        .1
+        .parse()
+        .ok()
+}
+
+/// Reads `Udp6InCsumErrors` from `/proc/net/snmp6`, which has a field per
+/// line.
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+fn parse_snmp6(snmp6: &str) -> Option<u64> {
+    snmp6.lines().find_map(|line| {
+        let mut fields = line.split_whitespace();
+        (fields.next()? == "Udp6InCsumErrors").then(|| fields.next()?.parse().ok())?
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        let snmp = "\
+Ip: Forwarding DefaultTTL
+Ip: 1 64
+Udp: InDatagrams NoPorts InErrors OutDatagrams RcvbufErrors SndbufErrors InCsumErrors IgnoredMulti
+Udp: 1000 2 7 900 0 0 5 0
+UdpLite: InDatagrams NoPorts InErrors OutDatagrams RcvbufErrors SndbufErrors InCsumErrors IgnoredMulti
+UdpLite: 0 0 0 0 0 0 9 0
+";
+        assert_eq!(Some(5), parse_snmp(snmp));
+        assert_eq!(None, parse_snmp("Ip: Forwarding\nIp: 1\n"));
+
+        let snmp6 = "\
+Udp6InDatagrams                 	1000
+Udp6InCsumErrors                	3
+UdpLite6InCsumErrors            	9
+";
+        assert_eq!(Some(3), parse_snmp6(snmp6));
+        assert_eq!(None, parse_snmp6(""));
+    }
+}
@@ -0,0 +1,149 @@
+/*
+ * Copyright 2023 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Per-source CPU budgets, which throttle the clients whose packets take the
+//! filter chain longest to process, such as packets crafted to be as
+//! expensive as possible to decompress or decrypt.
+//!
+//! Each client IP address has a token bucket of filter chain time, refilled
+//! at the budget's rate up to its burst, and charged the time the chain
+//! spent on each of its packets. The bucket can go into debt, so that a
+//! single expensive packet is paid for in full, and packets from a client
+//! whose bucket is empty are dropped before they reach the filter chain.
+
+use std::{
+    net::IpAddr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use arc_swap::ArcSwapOption;
+use dashmap::DashMap;
+use once_cell::sync::Lazy;
+
+/// The reason recorded in `packets_dropped_total` for packets from sources
+/// over their budget.
+pub(crate) const BUDGET_EXCEEDED_REASON: &str = "SourceCpuBudgetExceeded";
+/// How often the buckets of sources back within their budget are forgotten.
+pub(crate) const PRUNE_INTERVAL: Duration = Duration::from_secs(10);
+
+static BUDGET: Lazy<ArcSwapOption<CpuBudget>> = Lazy::new(<_>::default);
+
+pub(crate) fn install(budget: Option<CpuBudget>) {
+    BUDGET.store(budget.map(Arc::new));
+}
+
+/// The installed budget, if any.
+pub(crate) fn budget() -> Option<Arc<CpuBudget>> {
+    BUDGET.load_full()
+}
+
+/// The filter chain time each source may use per second, and how much of it
+/// may be used at once.
+pub(crate) struct CpuBudget {
+    /// Nanoseconds added to each bucket per second.
+    rate: f64,
+    /// The most nanoseconds a bucket holds.
+    burst: f64,
+    buckets: DashMap<IpAddr, Bucket>,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Bucket {
+    /// The nanoseconds left, negative while the source is in debt.
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl CpuBudget {
+    /// Allows each source `rate` of filter chain time per second, up to
+    /// `burst` at once, which is at least `rate`.
+    pub(crate) fn new(rate: Duration, burst: Option<Duration>) -> Self {
+        let rate = rate.as_nanos() as f64;
+        Self {
+            rate,
+            burst: burst
+                .map_or(rate, |burst| burst.as_nanos() as f64)
+                .max(rate),
+            buckets: <_>::default(),
+        }
+    }
+
+    fn refill(&self, bucket: &mut Bucket, now: Instant) {
+        let elapsed = now.saturating_duration_since(bucket.refilled);
+        bucket.tokens = (bucket.tokens + elapsed.as_secs_f64() * self.rate).min(self.burst);
+        bucket.refilled = now;
+    }
+
+    /// Whether `source` has any of its budget left.
+    pub(crate) fn allows(&self, source: IpAddr) -> bool {
+        let Some(mut bucket) = self.buckets.get_mut(&source) else {
+            return true;
+        };
+
+        self.refill(&mut bucket, Instant::now());
+        bucket.tokens > 0.0
+    }
+
+    /// Charges `source` for `elapsed` of filter chain time.
+    pub(crate) fn charge(&self, source: IpAddr, elapsed: Duration) {
+        let now = Instant::now();
+        let mut bucket = self.buckets.entry(source).or_insert(Bucket {
+            tokens: self.burst,
+            refilled: now,
+        });
+        self.refill(&mut bucket, now);
+        bucket.tokens -= elapsed.as_nanos() as f64;
+    }
+
+    /// Forgets the buckets that have refilled, which are the same as those
+    /// of sources without one.
+    pub(crate) fn prune(&self) {
+        let now = Instant::now();
+        self.buckets.retain(|_, bucket| {
+            self.refill(bucket, now);
+            bucket.tokens < self.burst
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn throttles_expensive_sources() {
+        let budget = CpuBudget::new(Duration::from_millis(1), None);
+        let expensive: IpAddr = [192, 0, 2, 1].into();
+        let cheap: IpAddr = [192, 0, 2, 2].into();
+
+        assert!(budget.allows(expensive));
+        budget.charge(expensive, Duration::from_millis(5));
+        budget.charge(cheap, Duration::from_micros(1));
+        assert!(!budget.allows(expensive));
+        assert!(budget.allows(cheap));
+
+        budget.prune();
+        assert!(budget.buckets.contains_key(&expensive));
+
+        // A bucket that refilled is forgotten.
+        let refilled = Instant::now().checked_sub(Duration::from_secs(10)).unwrap();
+        budget.buckets.get_mut(&expensive).unwrap().refilled = refilled;
+        budget.prune();
+        assert!(!budget.buckets.contains_key(&expensive));
+        assert!(budget.allows(expensive));
+    }
+}
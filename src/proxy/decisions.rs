@@ -0,0 +1,244 @@
+/*
+ * Copyright 2023 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! A small in-memory log of how recent packets went through the filter
+//! chain, served by the admin server, so that why a client's packets are
+//! dropped or where they're sent can be seen live, without turning on debug
+//! logging.
+
+use std::{
+    collections::VecDeque,
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use arc_swap::ArcSwapOption;
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use serde::Serialize;
+
+use crate::{
+    endpoint::EndpointAddress,
+    filters::{ReadContext, WriteContext},
+};
+
+/// The path the log is served at.
+pub(crate) const PATH: &str = "/debug/decisions";
+
+static LOG: Lazy<ArcSwapOption<Log>> = Lazy::new(<_>::default);
+
+/// Sets the log that decisions are recorded to, or stops recording them if
+/// `None`.
+pub(crate) fn install(log: Option<Log>) {
+    LOG.store(log.map(Arc::new));
+}
+
+/// Whether decisions are being recorded.
+pub(crate) fn is_enabled() -> bool {
+    LOG.load().is_some()
+}
+
+/// Returns the trace a new packet's filters are recorded in, if decisions
+/// are being recorded.
+pub(crate) fn trace() -> Option<Vec<Step>> {
+    is_enabled().then(Vec::new)
+}
+
+/// Records the decision of the filter chain on a packet received from
+/// downstream, which `passed` it or not.
+pub(crate) fn read(ctx: &mut ReadContext, passed: bool) {
+    let Some(filters) = ctx.trace.take() else {
+        return;
+    };
+
+    record(Decision {
+        timestamp_ms: now_ms(),
+        direction: crate::metrics::READ.label(),
+        client: ctx.source.clone(),
+        filters,
+        verdict: Verdict::from(passed),
+        endpoints: if passed {
+            ctx.endpoints
+                .iter()
+                .map(|endpoint| endpoint.address.clone())
+                .collect()
+        } else {
+            Vec::new()
+        },
+    });
+}
+
+/// Records the decision of the filter chain on a packet received from
+/// upstream, which `passed` it or not.
+pub(crate) fn write(ctx: &mut WriteContext, passed: bool) {
+    let Some(filters) = ctx.trace.take() else {
+        return;
+    };
+
+    record(Decision {
+        timestamp_ms: now_ms(),
+        direction: crate::metrics::WRITE.label(),
+        client: ctx.dest.clone(),
+        filters,
+        verdict: Verdict::from(passed),
+        endpoints: vec![ctx.endpoint.address.clone()],
+    });
+}
+
+fn record(decision: Decision) {
+    if let Some(log) = &*LOG.load() {
+        log.record(decision);
+    }
+}
+
+/// Returns the recorded decisions, oldest first, only including those on
+/// the packets of `client` if set, which is either an `ip:port` or an IP.
+pub(crate) fn get(client: Option<&str>) -> Vec<Decision> {
+    LOG.load()
+        .as_ref()
+        .map(|log| log.get(client))
+        .unwrap_or_default()
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |now| now.as_millis() as u64)
+}
+
+/// What a filter, or the whole chain, did with a packet.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Verdict {
+    Pass,
+    Drop,
+    /// The filter passed the packet, marking it to be read again from an
+    /// earlier filter.
+    Reprocess,
+    /// The chain ran out of its execution budget after the filter, dropping
+    /// the packet.
+    BudgetExceeded,
+}
+
+impl From<bool> for Verdict {
+    fn from(passed: bool) -> Self {
+        if passed {
+            Self::Pass
+        } else {
+            Self::Drop
+        }
+    }
+}
+
+/// A filter that a packet went through.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub(crate) struct Step {
+    pub filter: String,
+    pub verdict: Verdict,
+}
+
+/// How the filter chain handled a packet.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub(crate) struct Decision {
+    pub timestamp_ms: u64,
+    /// `read` for packets from clients, `write` for packets to them.
+    pub direction: &'static str,
+    /// The address of the client.
+    pub client: EndpointAddress,
+    /// The filters the packet went through, in order.
+    pub filters: Vec<Step>,
+    pub verdict: Verdict,
+    /// The endpoints the packet was sent to for reads, or came from for
+    /// writes.
+    pub endpoints: Vec<EndpointAddress>,
+}
+
+impl Decision {
+    fn is_of(&self, client: &str) -> bool {
+        self.client.to_string() == client || self.client.host.to_string() == client
+    }
+}
+
+/// The latest decisions, up to a capacity.
+pub(crate) struct Log {
+    capacity: usize,
+    decisions: Mutex<VecDeque<Decision>>,
+}
+
+impl Log {
+    pub(crate) fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            decisions: Mutex::new(VecDeque::with_capacity(capacity)),
+        }
+    }
+
+    fn record(&self, decision: Decision) {
+        let mut decisions = self.decisions.lock();
+        if decisions.len() >= self.capacity {
+            decisions.pop_front();
+        }
+        decisions.push_back(decision);
+    }
+
+    fn get(&self, client: Option<&str>) -> Vec<Decision> {
+        self.decisions
+            .lock()
+            .iter()
+            .filter(|decision| client.map_or(true, |client| decision.is_of(client)))
+            .cloned()
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decision(client: &str) -> Decision {
+        Decision {
+            timestamp_ms: 0,
+            direction: crate::metrics::READ.label(),
+            client: client.parse().unwrap(),
+            filters: vec![Step {
+                filter: "test".into(),
+                verdict: Verdict::Drop,
+            }],
+            verdict: Verdict::Drop,
+            endpoints: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn log() {
+        let log = Log::new(2);
+        log.record(decision("127.0.0.1:7000"));
+        log.record(decision("127.0.0.1:7001"));
+        log.record(decision("127.0.0.2:7000"));
+
+        // Only the latest decisions are kept.
+        assert_eq!(
+            vec![decision("127.0.0.1:7001"), decision("127.0.0.2:7000")],
+            log.get(None)
+        );
+        assert_eq!(vec![decision("127.0.0.1:7001")], log.get(Some("127.0.0.1")));
+        assert_eq!(
+            vec![decision("127.0.0.2:7000")],
+            log.get(Some("127.0.0.2:7000"))
+        );
+        assert!(log.get(Some("127.0.0.1:7000")).is_empty());
+    }
+}
@@ -0,0 +1,140 @@
+/*
+ * Copyright 2023 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! How packets received from downstream are dispatched to the workers.
+//!
+//! By default every worker has its own `SO_REUSEPORT` socket, and the kernel
+//! picks the worker of each packet, which keeps a client on the same worker
+//! only as long as the number of sockets doesn't change, and not at all on
+//! platforms balancing reused ports differently. Keyed dispatch instead
+//! receives every packet on a single socket and hashes its source address to
+//! pick the worker, handing the packet over through the worker's queue, so
+//! that every packet of a client is processed by the same worker, in order.
+
+use std::{net::SocketAddr, sync::Arc};
+
+use tokio::{
+    net::UdpSocket,
+    sync::{mpsc, watch},
+};
+
+use super::{workers::InFlight, DownstreamPacket};
+
+/// The reason recorded in `packets_dropped_total` for packets dropped as
+/// their worker's queue is full.
+pub(crate) const QUEUE_FULL_REASON: &str = "WorkerQueueFull";
+
+/// How packets received from downstream are dispatched to the workers.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum Dispatch {
+    /// Each worker receives from its own `SO_REUSEPORT` socket, leaving the
+    /// choice of worker to the kernel.
+    #[default]
+    ReusePort,
+    /// A single socket receives every packet, which is queued to the worker
+    /// picked by hashing its source address.
+    Keyed,
+}
+
+/// A packet queued to a worker, counted as in flight until it's processed.
+pub(crate) type Queued = (DownstreamPacket, InFlight);
+
+/// Returns the index of the worker, out of `workers`, processing the
+/// packets from `source`.
+pub(crate) fn worker_for(source: &SocketAddr, workers: usize) -> usize {
+    (crate::utils::stable_hash(source) % workers as u64) as usize
+}
+
+/// Creates the queues of `workers` workers, each holding up to `capacity`
+/// packets.
+pub(crate) fn queues(
+    workers: usize,
+    capacity: usize,
+) -> (Vec<mpsc::Sender<Queued>>, Vec<mpsc::Receiver<Queued>>) {
+    (0..workers).map(|_| mpsc::channel(capacity.max(1))).unzip()
+}
+
+/// Spawns the task receiving packets from `socket` and queueing each to the
+/// worker of its source, until a value is received from `shutdown_rx`.
+pub(crate) fn spawn(
+    socket: Arc<UdpSocket>,
+    queues: Vec<mpsc::Sender<Queued>>,
+    recv_batch_size: usize,
+    tasks: &super::Tasks,
+    mut shutdown_rx: watch::Receiver<()>,
+) {
+    tasks.spawn("downstream dispatcher", async move {
+        let mut buffers = crate::utils::mmsg::RecvBuffers::new(recv_batch_size);
+        loop {
+            tokio::select! {
+                result = buffers.recv(&socket) => {
+                    if let Err(error) = result {
+                        tracing::error!(%error, "error receiving packet");
+                        return;
+                    }
+
+                    for (contents, source) in buffers.packets() {
+                        // The same client always reaches the same worker,
+                        // whichever representation its address arrived in.
+                        let source = crate::endpoint::normalize_socket_addr(source);
+                        let queue = &queues[worker_for(&source, queues.len())];
+                        let packet = DownstreamPacket::received(contents, source);
+                        match queue.try_send((packet, InFlight::start())) {
+                            Ok(()) => {}
+                            Err(mpsc::error::TrySendError::Full(_)) => {
+                                crate::metrics::packets_dropped_total(
+                                    crate::metrics::READ,
+                                    QUEUE_FULL_REASON,
+                                )
+                                .inc();
+                                tracing::trace!(%source, "dropping packet, its worker's queue is full");
+                            }
+                            Err(mpsc::error::TrySendError::Closed(_)) => return,
+                        }
+                    }
+                }
+                _ = shutdown_rx.changed() => {
+                    tracing::debug!("Dispatcher received shutdown signal");
+                    return;
+                }
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn worker_for() {
+        let sources: Vec<SocketAddr> = (0..64)
+            .map(|port| SocketAddr::from(([192, 0, 2, 1], 7000 + port)))
+            .collect();
+
+        for source in &sources {
+            let worker = super::worker_for(source, 4);
+            assert!(worker < 4);
+            assert_eq!(worker, super::worker_for(source, 4));
+        }
+
+        let workers: std::collections::HashSet<_> = sources
+            .iter()
+            .map(|source| super::worker_for(source, 4))
+            .collect();
+        assert_eq!(4, workers.len());
+    }
+}
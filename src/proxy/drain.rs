@@ -0,0 +1,184 @@
+/*
+ * Copyright 2023 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Draining, which stops the proxy accepting new sessions while it keeps
+//! forwarding the packets of its existing sessions, until they expire or
+//! the drain timeout passes, so that rolling updates don't drop the games
+//! in progress.
+//!
+//! The proxy drains on shutdown, or when requested through the admin
+//! server, and stops once it's drained. Its workers keep receiving packets
+//! until then. Drains requested through the admin server can be cancelled,
+//! returning the proxy to accepting new sessions.
+
+use std::{
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
+
+use once_cell::sync::Lazy;
+use tokio::{sync::Notify, time::Instant};
+
+use super::SessionMap;
+
+/// The reason recorded in `packets_dropped_total` for packets that would
+/// have started a session while the proxy is draining.
+pub(crate) const DRAINING_REASON: &str = "Draining";
+/// How often the remaining sessions are checked while draining.
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Whether draining was enabled with a drain timeout.
+static ENABLED: AtomicBool = AtomicBool::new(false);
+/// Whether the proxy is draining.
+static DRAINING: AtomicBool = AtomicBool::new(false);
+/// Whether the current drain was requested, rather than started by shutdown,
+/// and so can be cancelled.
+static CANCELLABLE: AtomicBool = AtomicBool::new(false);
+/// Wakes the proxy when draining is requested.
+static REQUESTED: Lazy<Notify> = Lazy::new(Notify::new);
+/// Wakes the draining proxy when the drain is cancelled.
+static CANCELLED: Lazy<Notify> = Lazy::new(Notify::new);
+
+#[derive(Debug, thiserror::Error)]
+pub(crate) enum DrainError {
+    #[error("draining isn't enabled, see `--drain-timeout-secs`")]
+    Disabled,
+    #[error("the proxy isn't draining")]
+    NotDraining,
+    #[error("the proxy is shutting down")]
+    ShuttingDown,
+}
+
+/// Enables draining, so that it can be requested.
+pub(crate) fn enable(enabled: bool) {
+    ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+/// Whether the proxy is draining, and so not accepting new sessions.
+pub(crate) fn is_draining() -> bool {
+    DRAINING.load(Ordering::Relaxed)
+}
+
+/// Requests the proxy to drain and then stop, returning whether it wasn't
+/// draining already.
+pub(crate) fn request() -> Result<bool, DrainError> {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return Err(DrainError::Disabled);
+    }
+
+    let started = !DRAINING.swap(true, Ordering::Relaxed);
+    if started {
+        CANCELLABLE.store(true, Ordering::Relaxed);
+        REQUESTED.notify_one();
+    }
+    Ok(started)
+}
+
+/// Cancels a requested drain, so that the proxy accepts new sessions again.
+/// Drains started by shutdown can't be cancelled.
+pub(crate) fn cancel() -> Result<(), DrainError> {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return Err(DrainError::Disabled);
+    }
+
+    if !DRAINING.load(Ordering::Relaxed) {
+        return Err(DrainError::NotDraining);
+    }
+
+    if !CANCELLABLE.swap(false, Ordering::Relaxed) {
+        return Err(DrainError::ShuttingDown);
+    }
+
+    DRAINING.store(false, Ordering::Relaxed);
+    CANCELLED.notify_one();
+    Ok(())
+}
+
+/// Waits until draining is requested.
+pub(crate) async fn requested() {
+    REQUESTED.notified().await
+}
+
+/// Drains `sessions`, returning once they've all expired or `timeout` has
+/// passed, or `false` if the drain was cancelled first. Only requested
+/// drains are `cancellable`.
+pub(crate) async fn drain(sessions: &SessionMap, timeout: Duration, cancellable: bool) -> bool {
+    DRAINING.store(true, Ordering::Relaxed);
+    CANCELLABLE.store(cancellable, Ordering::Relaxed);
+    tracing::info!(
+        sessions = sessions.len(),
+        ?timeout,
+        "Draining, no longer accepting new sessions"
+    );
+
+    let remaining = tokio::select! {
+        remaining = wait(|| sessions.len(), timeout) => remaining,
+        _ = CANCELLED.notified(), if cancellable => {
+            tracing::info!("Drain cancelled, accepting new sessions again");
+            return false;
+        }
+    };
+
+    if remaining > 0 {
+        tracing::warn!(
+            sessions = remaining,
+            "Drain timed out, dropping the remaining sessions"
+        );
+    } else {
+        tracing::info!("Drained every session");
+    }
+
+    true
+}
+
+/// Waits until `remaining` returns `0` or `timeout` passes, returning the
+/// last number remaining.
+async fn wait(mut remaining: impl FnMut() -> usize, timeout: Duration) -> usize {
+    let deadline = Instant::now() + timeout;
+    loop {
+        let count = remaining();
+        if count == 0 || Instant::now() >= deadline {
+            return count;
+        }
+        tokio::time::sleep_until(deadline.min(Instant::now() + POLL_INTERVAL)).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn wait() {
+        let mut sessions = 3;
+        let start = Instant::now();
+        let drained = super::wait(
+            || {
+                sessions -= 1;
+                sessions
+            },
+            Duration::from_secs(60),
+        )
+        .await;
+        assert_eq!(0, drained);
+        assert_eq!(POLL_INTERVAL * 2, start.elapsed());
+
+        let start = Instant::now();
+        let remaining = super::wait(|| 1, Duration::from_secs(2)).await;
+        assert_eq!(1, remaining);
+        assert_eq!(Duration::from_secs(2), start.elapsed());
+    }
+}
@@ -0,0 +1,311 @@
+/*
+ * Copyright 2023 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Packet sampling, which copies a share of the packets of clusters with
+//! [`Sampling`] set to a `SamplingService` for offline analysis of traffic
+//! patterns.
+//!
+//! Like suspicion events, samples are only built when a sink is installed,
+//! and are discarded rather than slowing packet processing down when the
+//! sink can't keep up.
+
+use std::{
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use arc_swap::ArcSwapOption;
+use once_cell::sync::Lazy;
+use prometheus::{IntCounter, IntCounterVec};
+use rand::Rng;
+use tokio::sync::{mpsc, Mutex};
+
+use crate::{cluster::Sampling, endpoint::EndpointAddress};
+
+#[allow(warnings)]
+pub mod proto {
+    tonic::include_proto!("quilkin.sampling.v1alpha1");
+}
+
+pub use self::proto::{
+    sampling_service_client::SamplingServiceClient,
+    sampling_service_server::{SamplingService, SamplingServiceServer},
+    Sample, SamplingResponse,
+};
+
+const SUBSYSTEM: &str = "sampling";
+/// The number of samples that can be waiting to be sent to the sink.
+const QUEUE_SIZE: usize = 4096;
+/// How long to wait before reconnecting to the sink.
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+static SINK: Lazy<ArcSwapOption<Sink>> = Lazy::new(<_>::default);
+
+fn samples_total(cluster: &str, direction: &str) -> IntCounter {
+    static SAMPLES_TOTAL: Lazy<IntCounterVec> = Lazy::new(|| {
+        crate::metrics::register(
+            IntCounterVec::new(
+                crate::metrics::opts(
+                    "samples_total",
+                    SUBSYSTEM,
+                    "Total number of packets sampled. Labels: cluster, direction",
+                ),
+                &["cluster", "direction"],
+            )
+            .unwrap(),
+        )
+    });
+
+    SAMPLES_TOTAL.with_label_values(&[cluster, direction])
+}
+
+fn samples_dropped_total() -> &'static IntCounter {
+    static SAMPLES_DROPPED_TOTAL: Lazy<IntCounter> = Lazy::new(|| {
+        crate::metrics::register(
+            IntCounter::with_opts(crate::metrics::opts(
+                "samples_dropped_total",
+                SUBSYSTEM,
+                "Total number of samples discarded because the sink couldn't keep up or was unreachable",
+            ))
+            .unwrap(),
+        )
+    });
+
+    &SAMPLES_DROPPED_TOTAL
+}
+
+/// Samples `packet`, sent from `source` to `destination` in `direction` in a
+/// session of `cluster`, if a sink is installed and the cluster is sampled.
+pub(crate) fn sample(
+    cluster: &str,
+    sampling: Option<&Sampling>,
+    direction: &'static str,
+    source: &EndpointAddress,
+    destination: &EndpointAddress,
+    packet: &[u8],
+) {
+    let Some(sampling) = sampling.filter(|sampling| sampling.rate > 0) else {
+        return;
+    };
+    let Some(sink) = &*SINK.load() else {
+        return;
+    };
+    if !rand::thread_rng().gen_ratio(1, sampling.rate) {
+        return;
+    }
+
+    let address = |address: &EndpointAddress| match sampling.hash_addresses {
+        true => sink.hash(address),
+        false => address.to_string(),
+    };
+    let payload = &packet[..packet.len().min(sampling.max_payload_bytes as usize)];
+
+    samples_total(cluster, direction).inc();
+    sink.send(Sample {
+        timestamp_ms: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64,
+        proxy_id: sink.proxy_id.clone(),
+        cluster: cluster.into(),
+        direction: direction.into(),
+        source: address(source),
+        destination: address(destination),
+        addresses_hashed: sampling.hash_addresses,
+        size: packet.len() as u32,
+        payload: payload.to_vec(),
+    });
+}
+
+/// Installs the sink every sample is sent to, replacing any existing sink.
+pub(crate) fn install(sink: Option<Sink>) {
+    SINK.store(sink.map(Arc::new));
+}
+
+/// Streams samples to a `SamplingService` in the background, until dropped.
+pub(crate) struct Sink {
+    proxy_id: String,
+    /// Prepended to addresses before hashing them, so that hashes can't be
+    /// reversed by hashing every address.
+    salt: Vec<u8>,
+    samples: mpsc::Sender<Sample>,
+    task: tokio::task::JoinHandle<()>,
+}
+
+impl Sink {
+    /// Spawns a sink streaming to `endpoint`. Addresses are hashed with
+    /// `salt`, or with a random salt when unset, in which case hashes only
+    /// match between samples from the same process.
+    pub(crate) fn spawn(
+        proxy_id: String,
+        endpoint: tonic::transport::Endpoint,
+        salt: Option<String>,
+    ) -> Self {
+        let (samples, receiver) = mpsc::channel(QUEUE_SIZE);
+        let salt = salt.map_or_else(
+            || rand::thread_rng().gen::<[u8; 16]>().to_vec(),
+            String::into_bytes,
+        );
+
+        Self {
+            proxy_id,
+            salt,
+            samples,
+            task: tokio::spawn(grpc(endpoint, receiver)),
+        }
+    }
+
+    fn hash(&self, address: &EndpointAddress) -> String {
+        let mut hasher = openssl::sha::Sha256::new();
+        hasher.update(&self.salt);
+        hasher.update(address.to_string().as_bytes());
+        hasher.finish()[..16]
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect()
+    }
+
+    fn send(&self, sample: Sample) {
+        if self.samples.try_send(sample).is_err() {
+            samples_dropped_total().inc();
+        }
+    }
+}
+
+impl Drop for Sink {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+async fn grpc(endpoint: tonic::transport::Endpoint, samples: mpsc::Receiver<Sample>) {
+    // Shared between streams, so that samples queued while reconnecting are
+    // sent on the next stream.
+    let samples = Arc::new(Mutex::new(samples));
+
+    loop {
+        match SamplingServiceClient::connect(endpoint.clone()).await {
+            Ok(mut client) => {
+                let stream = {
+                    let samples = samples.clone();
+                    async_stream::stream! {
+                        let mut samples = samples.lock().await;
+                        while let Some(sample) = samples.recv().await {
+                            yield sample;
+                        }
+                    }
+                };
+
+                match client.stream_samples(stream).await {
+                    Ok(_) => tracing::debug!("sampling stream closed by sink"),
+                    Err(error) => tracing::warn!(%error, "sampling stream failed, reconnecting"),
+                }
+            }
+            Err(error) => {
+                tracing::warn!(%error, uri = %endpoint.uri(), "failed to connect to sampling sink")
+            }
+        }
+
+        tokio::time::sleep(RECONNECT_DELAY).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    #[derive(Clone, Default)]
+    struct Collector(Arc<parking_lot::Mutex<Vec<Sample>>>);
+
+    #[tonic::async_trait]
+    impl SamplingService for Collector {
+        async fn stream_samples(
+            &self,
+            request: tonic::Request<tonic::Streaming<Sample>>,
+        ) -> Result<tonic::Response<SamplingResponse>, tonic::Status> {
+            let mut stream = request.into_inner();
+            while let Some(sample) = stream.message().await? {
+                self.0.lock().push(sample);
+            }
+
+            Ok(tonic::Response::new(SamplingResponse {}))
+        }
+    }
+
+    #[tokio::test]
+    async fn grpc_sink() {
+        let collector = Collector::default();
+        let address = crate::test_utils::available_addr().await;
+        tokio::spawn(
+            tonic::transport::Server::builder()
+                .add_service(SamplingServiceServer::new(collector.clone()))
+                .serve(address),
+        );
+
+        install(Some(Sink::spawn(
+            "proxy".into(),
+            format!("http://{address}").parse().unwrap(),
+            Some("salt".into()),
+        )));
+        let source = (Ipv4Addr::LOCALHOST, 9000).into();
+        let destination = (Ipv4Addr::LOCALHOST, 7000).into();
+        let sampling = Sampling {
+            rate: 1,
+            max_payload_bytes: 4,
+            hash_addresses: true,
+        };
+        sample(
+            "sampled",
+            Some(&sampling),
+            crate::metrics::READ_DIRECTION_LABEL,
+            &source,
+            &destination,
+            b"hello world",
+        );
+        sample(
+            "unsampled",
+            None,
+            crate::metrics::READ_DIRECTION_LABEL,
+            &source,
+            &destination,
+            b"hello world",
+        );
+
+        let sample = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                if let Some(sample) = collector.0.lock().first() {
+                    break sample.clone();
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        install(None);
+
+        assert_eq!("proxy", sample.proxy_id);
+        assert_eq!("sampled", sample.cluster);
+        assert_eq!(11, sample.size);
+        assert_eq!(b"hell", &*sample.payload);
+        assert!(sample.addresses_hashed);
+        assert_eq!(32, sample.source.len());
+        assert_ne!(sample.source, sample.destination);
+        assert!(!sample.source.contains("127.0.0.1"));
+        assert_eq!(1, collector.0.lock().len());
+    }
+}
@@ -14,12 +14,38 @@
  * limitations under the License.
  */
 
+mod batch;
+mod dtls;
+pub(crate) mod failure_domain;
+pub(crate) mod journal;
+mod map;
+mod memory;
 pub(crate) mod metrics;
+mod pacing;
+mod permit;
+mod policy;
+pub(crate) mod prewarm;
 
-use std::sync::Arc;
+use std::{
+    borrow::Cow,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
 
+use arc_swap::{ArcSwap, ArcSwapOption};
+use futures::future::BoxFuture;
 use prometheus::HistogramTimer;
-use tokio::{net::UdpSocket, select, sync::watch, time::Instant};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt, ReadHalf, WriteHalf},
+    net::UdpSocket,
+    select,
+    sync::{watch, Mutex},
+    time::{Instant, MissedTickBehavior},
+};
 
 use crate::{
     endpoint::{Endpoint, EndpointAddress},
@@ -27,23 +53,74 @@ use crate::{
     utils::{debug, Loggable},
 };
 
-pub type SessionMap = crate::ttl_map::TtlMap<SessionKey, Session>;
+pub use self::{
+    map::{Reservation, SessionMap, SessionShard},
+    memory::SessionMemory,
+    policy::{Eviction, Expiry, SessionPolicy},
+};
+
+/// The size of the buffer each session receives upstream packets into.
+const RECV_BUFFER_LEN: usize = 65535;
+/// How long a recycled upstream socket keeps receiving the replies still on
+/// their way to it, once its replacement sends the session's packets.
+const RECYCLE_DRAIN: Duration = Duration::from_secs(2);
+/// The most packets a session holds while its DTLS handshake is pending.
+const MAX_PENDING_PACKETS: usize = 16;
+/// The reason recorded in `packets_dropped_total` for packets sent while a
+/// session's DTLS handshake was pending, once it already held
+/// [`MAX_PENDING_PACKETS`].
+const HANDSHAKE_PENDING_REASON: &str = "DtlsHandshakePending";
 
 /// Session encapsulates a UDP stream session
 pub struct Session {
     config: Arc<crate::Config>,
     /// created_at is time at which the session was created
     created_at: Instant,
-    /// socket that sends and receives from and to the endpoint address
-    upstream_socket: Arc<UdpSocket>,
+    /// Where packets to the endpoint address are sent, replaced whenever the
+    /// session's upstream socket is recycled. `None` until the session's DTLS
+    /// handshake completes.
+    upstream: Arc<ArcSwapOption<Upstream>>,
+    /// The packets sent while the session's DTLS handshake is pending, which
+    /// are sent once it completes.
+    pending: Arc<parking_lot::Mutex<Vec<Vec<u8>>>>,
+    /// Whether the session's connection failed, such as its DTLS handshake,
+    /// in which case the session is replaced by the next packet from its
+    /// client.
+    closed: Arc<AtomicBool>,
     /// dest is where to send data to
     dest: Endpoint,
     /// address of original sender
     source: EndpointAddress,
+    /// The socket packets are sent back to `source` from, replaced when the
+    /// session is handed off to another worker.
+    downstream_socket: Arc<ArcSwap<UdpSocket>>,
     /// a channel to broadcast on if we are shutting down this Session
     shutdown_tx: watch::Sender<()>,
     /// The ASN information.
+    #[cfg(feature = "filter-geolocation")]
     asn_info: Option<crate::maxmind_db::IpNetEntry>,
+    /// The name of the cluster `dest` belongs to, empty if it wasn't found.
+    cluster: Arc<str>,
+    /// The cluster and locality of `dest`, counted towards the sessions in
+    /// them.
+    failure_domain: failure_domain::Tag,
+    /// The namespace of the cluster `dest` belongs to, empty if it has none.
+    namespace: Arc<str>,
+    /// The traffic of this session, for its journal record.
+    stats: Arc<journal::Stats>,
+    /// The pacing of packets sent back to `source`, if its cluster has any.
+    pacing: Option<crate::cluster::Pacing>,
+    /// The settings of the sessions to `dest`'s cluster.
+    settings: crate::cluster::SessionSettings,
+    /// How the session's packets are sampled, if its cluster is sampled.
+    sampling: Option<crate::cluster::Sampling>,
+    /// Counts the session towards its cluster's `max_sessions`.
+    _permit: permit::Permit,
+    /// Counts the session towards its cluster's failover capacity, if `dest`
+    /// is one of the cluster's local endpoints.
+    _local_permit: Option<permit::Permit>,
+    /// Counts the session's memory towards the memory of its map's sessions.
+    memory: memory::Usage,
 }
 
 // A (source, destination) address pair that uniquely identifies a session.
@@ -67,6 +144,13 @@ struct ReceivedPacketContext<'a> {
     source: EndpointAddress,
     dest: EndpointAddress,
     timer: HistogramTimer,
+    stats: &'a journal::Stats,
+    pacer: Option<&'a mut pacing::Pacer>,
+    cluster: &'a str,
+    sampling: Option<&'a crate::cluster::Sampling>,
+    /// Whether the downstream socket is an IPv6 socket, which IPv4 addresses
+    /// are sent to as IPv4-mapped IPv6 addresses.
+    downstream_ipv6: bool,
 }
 
 pub struct SessionArgs {
@@ -74,6 +158,15 @@ pub struct SessionArgs {
     pub source: EndpointAddress,
     pub downstream_socket: Arc<UdpSocket>,
     pub dest: Endpoint,
+    pub socket_config: Arc<crate::SocketConfig>,
+    /// The tasks the session's receive loop is spawned in.
+    pub tasks: super::Tasks,
+    /// The memory of the sessions of the map the session is inserted into,
+    /// which the session's memory is counted towards.
+    pub memory: SessionMemory,
+    /// The token of the session's first packet, which takes the connection
+    /// pre-established for it to `dest`, if any.
+    pub token: Option<Vec<u8>>,
 }
 
 impl SessionArgs {
@@ -84,75 +177,485 @@ impl SessionArgs {
     }
 }
 
+/// Returns the endpoint a new session from `source` to `dest` fails over to,
+/// when `dest` is one of its cluster's local endpoints and they're at the
+/// cluster's failover capacity, or when the client has already failed over
+/// to it, as reported by `has_session`.
+pub(crate) fn failover_endpoint(
+    config: &crate::Config,
+    source: &EndpointAddress,
+    dest: &Endpoint,
+    has_session: impl Fn(&EndpointAddress) -> bool,
+) -> Option<Endpoint> {
+    let clusters = config.clusters.load();
+    let cluster = clusters.cluster_of_endpoint(&dest.address)?;
+    let failover = cluster.failover.as_ref()?;
+    if !cluster.is_local_endpoint(&dest.address) {
+        return None;
+    }
+
+    let endpoint = failover.choose(
+        source,
+        cluster.locality_of_endpoint(&dest.address),
+        cluster.localities.iter(),
+    )?;
+    if has_session(&endpoint.address) {
+        return Some(endpoint.clone());
+    }
+
+    if permit::Permit::local_sessions(&cluster.name) < failover.capacity {
+        return None;
+    }
+
+    tracing::debug!(%source, dest = %endpoint.address, cluster = %cluster.name, "failing session over");
+    metrics::failed_over_total(&cluster.name).inc();
+    Some(endpoint.clone())
+}
+
+/// The upstream socket of a session, and its DTLS stream if its cluster uses
+/// DTLS.
+pub(crate) struct Connection {
+    upstream_socket: Arc<UdpSocket>,
+    dtls_reader: Option<ReadHalf<dtls::Stream>>,
+    dtls: Option<Arc<Mutex<WriteHalf<dtls::Stream>>>>,
+}
+
+impl Connection {
+    /// Connects to `dest` with the settings of its cluster in `config`,
+    /// within the cluster's `connect_timeout`.
+    pub(crate) async fn establish(
+        config: &crate::Config,
+        socket_config: &crate::SocketConfig,
+        dest: &EndpointAddress,
+    ) -> std::io::Result<Self> {
+        let (dtls, fwmark, normalize, upstream_bind, settings) = {
+            let clusters = config.clusters.load();
+            let cluster = clusters.cluster_of_endpoint(dest);
+            (
+                cluster.and_then(|cluster| cluster.dtls.clone()),
+                cluster.and_then(|cluster| cluster.fwmark),
+                cluster.and_then(|cluster| cluster.normalize),
+                cluster.and_then(|cluster| cluster.upstream_bind.clone()),
+                cluster
+                    .and_then(|cluster| cluster.sessions)
+                    .unwrap_or_default(),
+            )
+        };
+
+        let connect = async {
+            let dest = config.clusters.to_socket_addr(dest)?;
+            let upstream_socket = Arc::new(socket_config.bind_upstream(
+                dest,
+                upstream_bind.as_ref(),
+                fwmark,
+                normalize.as_ref(),
+            )?);
+            upstream_socket.connect(dest).await?;
+
+            let (dtls_reader, dtls) = match dtls {
+                Some(dtls) => {
+                    let (reader, writer) = tokio::io::split(
+                        self::dtls::connect(&dtls, upstream_socket.clone()).await?,
+                    );
+                    (Some(reader), Some(Arc::new(Mutex::new(writer))))
+                }
+                None => (None, None),
+            };
+
+            std::io::Result::Ok(Self {
+                upstream_socket,
+                dtls_reader,
+                dtls,
+            })
+        };
+
+        match settings.connect_timeout() {
+            Some(timeout) => tokio::time::timeout(timeout, connect)
+                .await
+                .unwrap_or_else(|_| {
+                    Err(std::io::Error::new(
+                        std::io::ErrorKind::TimedOut,
+                        "timed out establishing session",
+                    ))
+                }),
+            None => connect.await,
+        }
+    }
+
+    /// Splits the connection into the halves sending and receiving its
+    /// packets, batching the packets sent when `socket_config` enables it.
+    fn split(self, socket_config: &crate::SocketConfig) -> (Upstream, Receiver) {
+        let batch = socket_config
+            .upstream_batch_window()
+            .filter(|window| self.dtls.is_none() && !window.is_zero())
+            .map(|window| batch::Batcher::new(self.upstream_socket.clone(), window));
+
+        (
+            Upstream {
+                socket: self.upstream_socket.clone(),
+                dtls: self.dtls,
+                batch,
+            },
+            Receiver {
+                socket: self.upstream_socket,
+                dtls: self.dtls_reader,
+            },
+        )
+    }
+}
+
+/// The half of a session's connection its packets are sent upstream with.
+struct Upstream {
+    socket: Arc<UdpSocket>,
+    /// The encrypted stream to the endpoint, if its cluster uses DTLS.
+    dtls: Option<Arc<Mutex<WriteHalf<dtls::Stream>>>>,
+    /// Batches the packets sent upstream, when enabled and not using DTLS.
+    batch: Option<batch::Batcher>,
+}
+
+/// The half of a session's connection its packets are received from
+/// upstream with.
+struct Receiver {
+    socket: Arc<UdpSocket>,
+    dtls: Option<ReadHalf<dtls::Stream>>,
+}
+
+impl Receiver {
+    /// Receives a packet from upstream, decrypting it first if the session
+    /// uses DTLS.
+    async fn recv(&mut self, buf: &mut [u8]) -> std::io::Result<(usize, SocketAddr)> {
+        let Some(stream) = &mut self.dtls else {
+            return self.socket.recv_from(buf).await;
+        };
+
+        match stream.read(buf).await? {
+            0 => Err(std::io::Error::new(
+                std::io::ErrorKind::ConnectionAborted,
+                "DTLS session closed by upstream",
+            )),
+            size => Ok((size, self.socket.peer_addr()?)),
+        }
+    }
+}
+
+/// A recycled upstream socket, which receives the replies still on their
+/// way to it until `until`.
+struct Draining {
+    receiver: Receiver,
+    buf: Vec<u8>,
+    until: Instant,
+}
+
+/// What a session's receive loop needs to send the packets it receives from
+/// upstream back to the session's client.
+struct Receiving {
+    downstream_socket: Arc<ArcSwap<UdpSocket>>,
+    /// Whether the downstream socket is an IPv6 socket.
+    downstream_ipv6: bool,
+    config: Arc<crate::Config>,
+    endpoint: Endpoint,
+    source: EndpointAddress,
+    namespace: Arc<str>,
+    stats: Arc<journal::Stats>,
+    pacer: Option<pacing::Pacer>,
+    cluster: Arc<str>,
+    sampling: Option<crate::cluster::Sampling>,
+}
+
+impl Receiving {
+    /// Sends the packet `received` into `buf` back to the client, returning
+    /// whether receiving it succeeded.
+    async fn received(
+        &mut self,
+        received: std::io::Result<(usize, SocketAddr)>,
+        buf: &[u8],
+    ) -> bool {
+        let (size, recv_addr) = match received {
+            Ok(received) => received,
+            Err(error) => {
+                crate::metrics::errors_total(crate::metrics::WRITE).inc();
+                tracing::error!(%error, source = %self.source, dest = ?self.endpoint, "Error receiving packet");
+                return false;
+            }
+        };
+
+        crate::metrics::bytes_total(crate::metrics::WRITE).inc_by(size as u64);
+        crate::metrics::packets_total(crate::metrics::WRITE).inc();
+        metrics::namespace_bytes_total(crate::metrics::WRITE, &self.namespace).inc_by(size as u64);
+        metrics::namespace_packets_total(crate::metrics::WRITE, &self.namespace).inc();
+        metrics::endpoint_packet(crate::metrics::WRITE, &self.endpoint.address, size);
+        Session::process_recv_packet(
+            &self.downstream_socket.load_full(),
+            ReceivedPacketContext {
+                config: self.config.clone(),
+                packet: &buf[..size],
+                endpoint: &self.endpoint,
+                source: crate::endpoint::normalize_socket_addr(recv_addr).into(),
+                dest: self.source.clone(),
+                timer: crate::metrics::processing_time(crate::metrics::WRITE).start_timer(),
+                stats: &self.stats,
+                pacer: self.pacer.as_mut(),
+                cluster: &self.cluster,
+                sampling: self.sampling.as_ref(),
+                downstream_ipv6: self.downstream_ipv6,
+            },
+        )
+        .await;
+        true
+    }
+}
+
 impl Session {
     /// internal constructor for a Session from SessionArgs
-    #[tracing::instrument(skip_all)]
+    #[tracing::instrument(skip_all, fields(source = %args.source, dest = %args.dest.address))]
     async fn new(args: SessionArgs) -> std::io::Result<Self> {
-        let addr = (std::net::Ipv4Addr::UNSPECIFIED, 0);
-        let upstream_socket = Arc::new(UdpSocket::bind(addr).await?);
-        upstream_socket
-            .connect(args.dest.address.to_socket_addr()?)
-            .await?;
+        let (cluster, locality, namespace, pacing, settings, sampling, local, dtls) = {
+            let clusters = args.config.clusters.load();
+            let cluster = clusters.cluster_of_endpoint(&args.dest.address);
+            (
+                Arc::<str>::from(cluster.map(|cluster| &*cluster.name).unwrap_or_default()),
+                cluster
+                    .and_then(|cluster| cluster.locality_of_endpoint(&args.dest.address))
+                    .cloned(),
+                cluster
+                    .and_then(crate::cluster::Cluster::namespace)
+                    .unwrap_or_default()
+                    .into(),
+                cluster.and_then(|cluster| cluster.pacing),
+                cluster
+                    .and_then(|cluster| cluster.sessions)
+                    .unwrap_or_default(),
+                cluster.and_then(|cluster| cluster.sampling),
+                cluster.map_or(false, |cluster| {
+                    cluster.is_local_endpoint(&args.dest.address)
+                }),
+                cluster.map_or(false, |cluster| cluster.dtls.is_some()),
+            )
+        };
+
+        let permit =
+            permit::Permit::try_acquire(&cluster, settings.max_sessions).ok_or_else(|| {
+                metrics::max_sessions_rejected_total(&cluster).inc();
+                std::io::Error::new(
+                    std::io::ErrorKind::Other,
+                    format!("cluster `{cluster}` has reached its maximum number of sessions"),
+                )
+            })?;
+        let local_permit = local.then(|| permit::Permit::acquire_local(&cluster));
+
+        let prewarmed = args
+            .token
+            .as_deref()
+            .and_then(|token| prewarm::take(token, &args.dest.address));
+        let connection = match prewarmed {
+            Some(connection) => Some(connection),
+            // A DTLS handshake takes at least a round trip, so the session's
+            // task completes it, and the session can be added to the map
+            // straight away, rather than every packet from the client until
+            // then starting a handshake of its own.
+            None if dtls => None,
+            None => Some(
+                Connection::establish(&args.config, &args.socket_config, &args.dest.address)
+                    .await?,
+            ),
+        };
         let (shutdown_tx, shutdown_rx) = watch::channel::<()>(());
+        let memory = args.memory.count(memory::estimate(dtls));
+        let (upstream, receiver) = match connection {
+            Some(connection) => {
+                let (upstream, receiver) = connection.split(&args.socket_config);
+                (Some(Arc::new(upstream)), Some(receiver))
+            }
+            None => (None, None),
+        };
 
-        let ip = args.source.to_socket_addr().unwrap().ip();
-        let asn_info = crate::MaxmindDb::lookup(ip);
+        #[cfg(feature = "filter-geolocation")]
+        let asn_info = crate::MaxmindDb::lookup(args.source.to_socket_addr().unwrap().ip());
+        let failure_domain = failure_domain::Tag::new(failure_domain::FailureDomain {
+            cluster: cluster.clone(),
+            locality,
+        });
+        let downstream_socket = Arc::new(ArcSwap::new(args.downstream_socket));
         let s = Session {
             config: args.config.clone(),
-            upstream_socket,
+            upstream: Arc::new(ArcSwapOption::new(upstream)),
+            pending: <_>::default(),
+            closed: <_>::default(),
             source: args.source.clone(),
             dest: args.dest,
+            downstream_socket: downstream_socket.clone(),
             created_at: Instant::now(),
             shutdown_tx,
+            #[cfg(feature = "filter-geolocation")]
             asn_info,
+            cluster,
+            failure_domain,
+            namespace,
+            stats: <_>::default(),
+            pacing,
+            settings,
+            sampling,
+            _permit: permit,
+            _local_permit: local_permit,
+            memory,
         };
 
-        tracing::debug!(source = %s.source, dest = ?s.dest, "Session created");
+        journal::record(|| s.journal_record(journal::Event::Start));
+        tracing::debug!(source = %s.source, dest = ?s.dest, namespace = %s.namespace, "Session created");
 
         self::metrics::total_sessions().inc();
         s.active_session_metric().inc();
-        s.run(args.downstream_socket, shutdown_rx);
+        metrics::endpoint_session_started(&s.dest.address);
+        s.run(
+            &args.tasks,
+            downstream_socket,
+            args.socket_config,
+            shutdown_rx,
+            receiver,
+        );
         Ok(s)
     }
 
     /// run starts processing receiving upstream udp packets
-    /// and sending them back downstream
-    fn run(&self, downstream_socket: Arc<UdpSocket>, mut shutdown_rx: watch::Receiver<()>) {
-        let source = self.source.clone();
-        let config = self.config.clone();
-        let endpoint = self.dest.clone();
-        let upstream_socket = self.upstream_socket.clone();
-
-        tokio::spawn(async move {
-            let mut buf: Vec<u8> = vec![0; 65535];
+    /// and sending them back downstream, recycling the upstream socket once
+    /// it reaches its cluster's `max_socket_age_ms`. Without a `receiver`,
+    /// the session's connection is established first.
+    fn run(
+        &self,
+        tasks: &super::Tasks,
+        downstream_socket: Arc<ArcSwap<UdpSocket>>,
+        socket_config: Arc<crate::SocketConfig>,
+        mut shutdown_rx: watch::Receiver<()>,
+        receiver: Option<Receiver>,
+    ) {
+        let upstream = self.upstream.clone();
+        let pending = self.pending.clone();
+        let closed = self.closed.clone();
+        let max_socket_age = self.settings.max_socket_age();
+        let mut receiving = Receiving {
+            downstream_ipv6: downstream_socket
+                .load()
+                .local_addr()
+                .map_or(false, |address| address.is_ipv6()),
+            downstream_socket,
+            config: self.config.clone(),
+            endpoint: self.dest.clone(),
+            source: self.source.clone(),
+            namespace: self.namespace.clone(),
+            stats: self.stats.clone(),
+            pacer: self.pacing.as_ref().map(pacing::Pacer::new),
+            cluster: self.cluster.clone(),
+            sampling: self.sampling,
+        };
+
+        tasks.spawn("session", async move {
+            let mut receiver = match receiver {
+                Some(receiver) => receiver,
+                None => {
+                    let connection = select! {
+                        connection = Connection::establish(&receiving.config, &socket_config, &receiving.endpoint.address) => connection,
+                        _ = shutdown_rx.changed() => return,
+                    };
+                    let (sender, receiver) = match connection {
+                        Ok(connection) => connection.split(&socket_config),
+                        Err(error) => {
+                            tracing::warn!(%error, source = %receiving.source, dest = ?receiving.endpoint, "Failed to establish session");
+                            closed.store(true, Ordering::Relaxed);
+                            return;
+                        }
+                    };
+
+                    let sender = Arc::new(sender);
+                    // Holding the lock while the upstream is stored means no
+                    // packet can be added after the pending ones are taken.
+                    let queued = {
+                        let mut pending = pending.lock();
+                        upstream.store(Some(sender.clone()));
+                        std::mem::take(&mut *pending)
+                    };
+                    if let Some(stream) = &sender.dtls {
+                        let mut stream = stream.lock().await;
+                        for packet in queued {
+                            if let Err(error) = stream.write(&packet).await {
+                                tracing::warn!(%error, source = %receiving.source, dest = ?receiving.endpoint, "Failed to send pending packet");
+                            }
+                        }
+                    }
+                    receiver
+                }
+            };
+            let mut buf: Vec<u8> = vec![0; RECV_BUFFER_LEN];
+            let mut recycle = max_socket_age.map(|age| {
+                let mut interval = tokio::time::interval_at(Instant::now() + age, age);
+                interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+                interval
+            });
+            let mut replacement: Option<BoxFuture<'static, std::io::Result<Connection>>> = None;
+            let mut draining: Option<Draining> = None;
+
             loop {
-                tracing::debug!(source = %source, dest = ?endpoint, "Awaiting incoming packet");
+                tracing::debug!(source = %receiving.source, dest = ?receiving.endpoint, "Awaiting incoming packet");
 
                 select! {
-                    received = upstream_socket.recv_from(&mut buf) => {
-                        match received {
-                            Err(error) => {
-                                crate::metrics::errors_total(crate::metrics::WRITE).inc();
-                                tracing::error!(%error, %source, dest = ?endpoint, "Error receiving packet");
-                            },
-                            Ok((size, recv_addr)) => {
-                                crate::metrics::bytes_total(crate::metrics::WRITE).inc_by(size as u64);
-                                crate::metrics::packets_total(crate::metrics::WRITE).inc();
-                                Session::process_recv_packet(
-                                    &downstream_socket,
-                                    ReceivedPacketContext {
-                                        config: config.clone(),
-                                        packet: &buf[..size],
-                                        endpoint: &endpoint,
-                                        source: recv_addr.into(),
-                                        dest: source.clone(),
-                                        timer: crate::metrics::processing_time(crate::metrics::WRITE).start_timer(),
-                                    }).await
+                    received = receiver.recv(&mut buf) => {
+                        // A DTLS session can't recover once its stream fails.
+                        if !receiving.received(received, &buf).await && receiver.dtls.is_some() {
+                            closed.store(true, Ordering::Relaxed);
+                            return;
+                        }
+                    }
+                    drained = async {
+                        let draining = draining.as_mut().unwrap();
+                        tokio::time::timeout_at(draining.until, draining.receiver.recv(&mut draining.buf)).await
+                    }, if draining.is_some() => {
+                        let done = match drained {
+                            Ok(received) => {
+                                let draining = draining.as_ref().unwrap();
+                                !receiving.received(received, &draining.buf).await
+                                    && draining.receiver.dtls.is_some()
                             }
+                            Err(_) => true,
                         };
+                        if done {
+                            tracing::debug!(source = %receiving.source, dest = ?receiving.endpoint, "Closing recycled upstream socket");
+                            draining = None;
+                        }
+                    }
+                    _ = async { recycle.as_mut().unwrap().tick().await }, if recycle.is_some() && replacement.is_none() => {
+                        let config = receiving.config.clone();
+                        let socket_config = socket_config.clone();
+                        let address = receiving.endpoint.address.clone();
+                        replacement = Some(Box::pin(async move {
+                            Connection::establish(&config, &socket_config, &address).await
+                        }));
+                    }
+                    connection = async { replacement.as_mut().unwrap().await }, if replacement.is_some() => {
+                        replacement = None;
+                        match connection {
+                            Ok(connection) => {
+                                // The new socket sends every packet from now
+                                // on, while the old one still receives the
+                                // replies to the packets it sent.
+                                let (sender, new_receiver) = connection.split(&socket_config);
+                                upstream.store(Some(Arc::new(sender)));
+                                draining = Some(Draining {
+                                    receiver: std::mem::replace(&mut receiver, new_receiver),
+                                    buf: draining
+                                        .take()
+                                        .map_or_else(|| vec![0; RECV_BUFFER_LEN], |draining| draining.buf),
+                                    until: Instant::now() + RECYCLE_DRAIN,
+                                });
+                                metrics::socket_recycled_total(&receiving.cluster).inc();
+                                tracing::debug!(source = %receiving.source, dest = ?receiving.endpoint, "Recycled upstream socket");
+                            }
+                            Err(error) => {
+                                metrics::socket_recycle_failed_total(&receiving.cluster).inc();
+                                tracing::warn!(%error, source = %receiving.source, dest = ?receiving.endpoint, "Failed to recycle upstream socket, keeping it");
+                            }
+                        }
                     }
                     _ = shutdown_rx.changed() => {
-                        tracing::debug!(%source, dest = ?endpoint, "Closing Session");
+                        tracing::debug!(source = %receiving.source, dest = ?receiving.endpoint, "Closing Session");
                         return;
                     }
                 };
@@ -160,14 +663,30 @@ impl Session {
         });
     }
 
+    fn journal_record(&self, event: journal::Event) -> journal::Record {
+        journal::Record {
+            locality: self.failure_domain.domain().locality.clone(),
+            ..journal::Record::new(
+                event,
+                self.source.to_string(),
+                self.dest.address.to_string(),
+                self.cluster.to_string(),
+            )
+        }
+    }
+
     fn active_session_metric(&self) -> prometheus::IntGauge {
+        #[cfg(feature = "filter-geolocation")]
         let (asn_number, ip_prefix) = self
             .asn_info
             .as_ref()
             .map(|asn| (asn.r#as, &*asn.prefix))
             .unwrap_or_else(|| (<_>::default(), <_>::default()));
+        // Without the Maxmind database every session is counted as unknown.
+        #[cfg(not(feature = "filter-geolocation"))]
+        let (asn_number, ip_prefix) = (0u64, "");
 
-        metrics::active_sessions(asn_number as u16, ip_prefix)
+        metrics::active_sessions(asn_number as u16, ip_prefix, &self.namespace)
     }
 
     /// process_recv_packet processes a packet that is received by this session.
@@ -182,31 +701,61 @@ impl Session {
             source: from,
             dest,
             timer,
+            stats,
+            pacer,
+            cluster,
+            sampling,
+            downstream_ipv6,
         } = packet_ctx;
 
         tracing::trace!(%from, dest = %endpoint.address, contents = %debug::bytes_to_string(packet), "received packet from upstream");
 
-        let mut context = WriteContext::new(
-            endpoint.clone(),
-            from.clone(),
-            dest.clone(),
-            packet.to_vec(),
-        );
+        // Chains without any filter handling writes send packets on
+        // untouched, so there's no need to copy them into a context.
+        let filters = config.filters.load();
+        let mut additional = Vec::new();
+        let contents = if filters.has_write() {
+            let mut context = WriteContext::new(
+                endpoint.clone(),
+                from.clone(),
+                dest.clone(),
+                packet.to_vec(),
+            )
+            .execution_budget(config.filter_budget.get());
 
-        let result = config
-            .filters
-            .load()
-            .write(&mut context)
-            .ok_or(Error::FilterDroppedPacket)
-            .map(|_| context)
-            .and_then(|context| {
-                dest.to_socket_addr()
-                    .map(|addr| (addr, context))
-                    .map_err(Error::ToSocketAddr)
-            });
+            let result = filters.write(&mut context);
+            crate::proxy::decisions::write(&mut context, result.is_some());
+            additional = std::mem::take(&mut context.additional);
+            result
+                .ok_or(Error::FilterDroppedPacket)
+                .map(|_| Cow::Owned(context.contents))
+        } else {
+            Ok(Cow::Borrowed(packet))
+        };
+
+        let result = contents.and_then(|contents| {
+            dest.to_socket_addr()
+                .map(|addr| (crate::endpoint::for_socket(addr, downstream_ipv6), contents))
+                .map_err(Error::ToSocketAddr)
+        });
 
         let handle_error = |error: Error| {
             error.log();
+            if let Error::PacingExceeded = error {
+                stats.dropped(journal::DropCause::Pacing);
+                metrics::pacing_dropped_total().inc();
+                crate::metrics::packets_dropped_total(
+                    crate::metrics::WRITE,
+                    pacing::PACING_EXCEEDED_REASON,
+                )
+                .inc();
+                return;
+            }
+
+            stats.dropped(match error {
+                Error::FilterDroppedPacket => journal::DropCause::Filter,
+                _ => journal::DropCause::Error,
+            });
             crate::metrics::packets_dropped_total(
                 crate::metrics::WRITE,
                 "proxy::Session::process_recv_packet",
@@ -215,23 +764,74 @@ impl Session {
             crate::metrics::errors_total(crate::metrics::WRITE).inc();
         };
 
+        let result = result.and_then(|(addr, contents)| {
+            let delay = match pacer {
+                Some(pacer) => pacer.reserve(contents.len()).ok_or(Error::PacingExceeded)?,
+                None => std::time::Duration::ZERO,
+            };
+
+            Ok((addr, contents, delay))
+        });
+
         match result {
-            Ok((addr, context)) => {
-                let packet = context.contents.as_ref();
+            Ok((addr, contents, delay)) => {
+                if !delay.is_zero() {
+                    metrics::pacing_delayed_total().inc();
+                    metrics::pacing_delay_seconds().observe(delay.as_secs_f64());
+                    tokio::time::sleep(delay).await;
+                }
+
+                let packet = contents.as_ref();
+                crate::proxy::sampling::sample(
+                    cluster,
+                    sampling,
+                    crate::metrics::WRITE_DIRECTION_LABEL,
+                    &from,
+                    &dest,
+                    packet,
+                );
                 tracing::trace!(%from, dest = %addr, contents = %debug::bytes_to_string(packet), "sending packet downstream");
-                let _ = downstream_socket
-                    .send_to(packet, addr)
-                    .await
-                    .map_err(Error::SendTo)
-                    .map_err(handle_error);
+                for packet in std::iter::once(packet).chain(additional.iter().map(Vec::as_slice)) {
+                    let _ = downstream_socket
+                        .send_to(packet, addr)
+                        .await
+                        .map(|size| stats.written(size))
+                        .map_err(Error::SendTo)
+                        .map_err(&handle_error);
+                }
             }
             Err(error) => (handle_error)(error),
         };
 
-        timer.stop_and_record();
+        let seconds = timer.stop_and_record();
+        metrics::endpoint_processing_time(crate::metrics::WRITE, &endpoint.address, seconds);
+    }
+
+    /// Sends the packets from now on back to the session's client from
+    /// `socket`, as the session is handed off to the worker receiving on it.
+    pub(crate) fn hand_off(&self, socket: Arc<UdpSocket>) {
+        self.downstream_socket.store(socket);
+    }
+
+    /// Whether the session's connection failed, so that it should be
+    /// replaced rather than used.
+    pub(crate) fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::Relaxed)
+    }
+
+    /// The approximate memory held by the session, in bytes.
+    pub fn memory_bytes(&self) -> usize {
+        self.memory.bytes()
     }
 
-    /// Sends a packet to the Session's dest.
+    /// How long the session lasts without traffic from its client, if its
+    /// cluster overrides the proxy's default.
+    pub fn idle_timeout(&self) -> Option<std::time::Duration> {
+        self.settings.idle_timeout()
+    }
+
+    /// Sends a packet to the Session's dest, unless doing so would exceed the
+    /// quota of its cluster, in which case the packet is dropped.
     pub fn send<'buf>(
         &self,
         buf: &'buf [u8],
@@ -241,8 +841,82 @@ impl Session {
         contents = %debug::bytes_to_string(buf),
         "sending packet upstream");
 
-        let socket = self.upstream_socket.clone();
-        async move { socket.send(buf).await }
+        let quota = self
+            .config
+            .quotas
+            .load()
+            .try_acquire(&self.cluster, buf.len());
+        match &quota {
+            Ok(()) => {
+                self.stats.read(buf.len());
+                crate::proxy::sampling::sample(
+                    &self.cluster,
+                    self.sampling.as_ref(),
+                    crate::metrics::READ_DIRECTION_LABEL,
+                    &self.source,
+                    &self.dest.address,
+                    buf,
+                );
+                metrics::namespace_bytes_total(crate::metrics::READ, &self.namespace)
+                    .inc_by(buf.len() as u64);
+                metrics::namespace_packets_total(crate::metrics::READ, &self.namespace).inc();
+                metrics::endpoint_packet(crate::metrics::READ, &self.dest.address, buf.len());
+            }
+            Err(error) => {
+                tracing::trace!(%error, dest_address = %self.dest.address, "dropping packet");
+                self.stats.dropped(journal::DropCause::Quota);
+                crate::metrics::packets_dropped_total(
+                    crate::metrics::READ,
+                    crate::quota::QUOTA_EXCEEDED_REASON,
+                )
+                .inc();
+            }
+        }
+
+        let upstream = match self.upstream.load_full() {
+            Some(upstream) => Some(upstream),
+            None if quota.is_ok() => self.hold(buf),
+            None => None,
+        };
+        let batched = match (&quota, upstream.as_ref().and_then(|u| u.batch.as_ref())) {
+            (Ok(()), Some(batch)) => {
+                batch.send(buf);
+                true
+            }
+            _ => false,
+        };
+
+        async move {
+            let Some(upstream) = upstream else {
+                return Ok(0);
+            };
+
+            match (quota, &upstream.dtls) {
+                (Err(_), _) => Ok(0),
+                (Ok(()), Some(stream)) => stream.lock().await.write(buf).await,
+                (Ok(()), None) if batched => Ok(buf.len()),
+                (Ok(()), None) => upstream.socket.send(buf).await,
+            }
+        }
+    }
+
+    /// Holds `buf` until the session's DTLS handshake completes, unless it
+    /// completed in the meantime, in which case its upstream is returned.
+    fn hold(&self, buf: &[u8]) -> Option<Arc<Upstream>> {
+        let mut pending = self.pending.lock();
+        if let Some(upstream) = self.upstream.load_full() {
+            return Some(upstream);
+        }
+
+        if pending.len() < MAX_PENDING_PACKETS {
+            pending.push(buf.to_vec());
+        } else {
+            tracing::trace!(dest_address = %self.dest.address, "dropping packet, the DTLS handshake is pending");
+            self.stats.dropped(journal::DropCause::Error);
+            crate::metrics::packets_dropped_total(crate::metrics::READ, HANDSHAKE_PENDING_REASON)
+                .inc();
+        }
+        None
     }
 }
 
@@ -250,6 +924,16 @@ impl Drop for Session {
     fn drop(&mut self) {
         self.active_session_metric().dec();
         metrics::duration_secs().observe(self.created_at.elapsed().as_secs() as f64);
+        metrics::endpoint_session_ended(
+            &self.dest.address,
+            self.created_at.elapsed().as_secs_f64(),
+        );
+        journal::record(|| {
+            let mut record = self.journal_record(journal::Event::End);
+            record.duration_ms = Some(self.created_at.elapsed().as_millis() as u64);
+            self.stats.apply(&mut record);
+            record
+        });
 
         if let Err(error) = self.shutdown_tx.send(()) {
             tracing::warn!(%error, "Error sending session shutdown signal");
@@ -267,6 +951,8 @@ pub enum Error {
     SendTo(std::io::Error),
     #[error("filter dropped packet from upstream")]
     FilterDroppedPacket,
+    #[error("pacing would delay packet from upstream for too long")]
+    PacingExceeded,
 }
 
 impl Loggable for Error {
@@ -275,7 +961,7 @@ impl Loggable for Error {
             Self::ToSocketAddr(error) | Self::SendTo(error) => {
                 tracing::error!(kind=%error.kind(), "{}", self)
             }
-            Self::FilterDroppedPacket => {
+            Self::FilterDroppedPacket | Self::PacingExceeded => {
                 tracing::trace!("{}", self)
             }
         }
@@ -310,6 +996,10 @@ mod tests {
             source: addr.clone(),
             downstream_socket: socket.clone(),
             dest: endpoint,
+            socket_config: <_>::default(),
+            tasks: <_>::default(),
+            memory: <_>::default(),
+            token: None,
         })
         .await
         .unwrap();
@@ -326,6 +1016,146 @@ mod tests {
         assert_eq!(addr.port(), recv_addr.port());
     }
 
+    #[tokio::test]
+    async fn recycle_upstream_socket() {
+        let mut t = TestHelper::default();
+        let addr = t.run_echo_server().await;
+        let socket = Arc::new(create_socket().await);
+        let config = Arc::new(crate::Config::default());
+        let mut cluster = crate::cluster::Cluster::new(
+            "sessions/recycle".into(),
+            vec![crate::endpoint::LocalityEndpoints::from(Endpoint::new(
+                addr.clone(),
+            ))],
+        );
+        cluster.sessions = Some(crate::cluster::SessionSettings {
+            max_socket_age_ms: Some(50),
+            ..<_>::default()
+        });
+        config.clusters.modify(|map| {
+            map.insert(cluster.clone());
+        });
+
+        let sess = Session::new(SessionArgs {
+            config,
+            source: addr.clone(),
+            downstream_socket: socket.clone(),
+            dest: Endpoint::new(addr.clone()),
+            socket_config: <_>::default(),
+            tasks: <_>::default(),
+            memory: <_>::default(),
+            token: None,
+        })
+        .await
+        .unwrap();
+        let first = sess
+            .upstream
+            .load()
+            .as_ref()
+            .unwrap()
+            .socket
+            .local_addr()
+            .unwrap();
+
+        let recycled = metrics::socket_recycled_total(&cluster.name);
+        timeout(Duration::from_secs(5), async {
+            while recycled.get() == 0 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        assert_ne!(
+            first,
+            sess.upstream
+                .load()
+                .as_ref()
+                .unwrap()
+                .socket
+                .local_addr()
+                .unwrap()
+        );
+
+        // Packets keep flowing through the new socket.
+        sess.send(b"hello").await.unwrap();
+        let mut buf = vec![0; 1024];
+        let (size, _) = timeout(Duration::from_secs(5), socket.recv_from(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!("hello", from_utf8(&buf[..size]).unwrap());
+    }
+
+    #[tokio::test]
+    async fn dtls_handshake_pending() {
+        // An endpoint that never answers the handshake.
+        let endpoint = create_socket().await;
+        let addr: EndpointAddress = endpoint.local_addr().unwrap().into();
+        let socket = Arc::new(create_socket().await);
+        let config = Arc::new(crate::Config::default());
+        let mut cluster = crate::cluster::Cluster::new(
+            "sessions/dtls".into(),
+            vec![crate::endpoint::LocalityEndpoints::from(Endpoint::new(
+                addr.clone(),
+            ))],
+        );
+        cluster.dtls = Some(crate::cluster::Dtls {
+            insecure_skip_verify: true,
+            ..<_>::default()
+        });
+        cluster.sessions = Some(crate::cluster::SessionSettings {
+            connect_timeout_ms: Some(1500),
+            ..<_>::default()
+        });
+        config.clusters.modify(|map| {
+            map.insert(cluster);
+        });
+
+        // The session is created without waiting for the handshake, holding
+        // its packets until it completes.
+        let sess = timeout(
+            Duration::from_millis(500),
+            Session::new(SessionArgs {
+                config,
+                source: "127.0.0.1:7000".parse().unwrap(),
+                downstream_socket: socket,
+                dest: Endpoint::new(addr),
+                socket_config: <_>::default(),
+                tasks: <_>::default(),
+                memory: <_>::default(),
+                token: None,
+            }),
+        )
+        .await
+        .unwrap()
+        .unwrap();
+        sess.send(b"hello").await.unwrap();
+        assert_eq!(1, sess.pending.lock().len());
+
+        // The unanswered ClientHello is sent again.
+        let mut buf = vec![0; 1500];
+        let (first, _) = timeout(Duration::from_secs(1), endpoint.recv_from(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        let hello = buf[..first].to_vec();
+        let (second, _) = timeout(Duration::from_secs(2), endpoint.recv_from(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(hello, buf[..second]);
+
+        // Once the handshake times out, the session is replaced by the next
+        // packet from its client.
+        timeout(Duration::from_secs(5), async {
+            while !sess.is_closed() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+    }
+
     #[tokio::test]
     async fn process_recv_packet() {
         crate::test_utils::load_test_filters();
@@ -346,6 +1176,11 @@ mod tests {
                 source: endpoint.address.clone(),
                 dest: dest.clone(),
                 timer: histogram.start_timer(),
+                stats: &<_>::default(),
+                pacer: None,
+                cluster: "",
+                sampling: None,
+                downstream_ipv6: false,
             },
         )
         .await;
@@ -369,6 +1204,11 @@ mod tests {
                 source: endpoint.address.clone(),
                 dest: dest.clone(),
                 timer: histogram.start_timer(),
+                stats: &<_>::default(),
+                pacer: None,
+                cluster: "",
+                sampling: None,
+                downstream_ipv6: false,
             },
         )
         .await;
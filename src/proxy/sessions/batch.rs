@@ -0,0 +1,124 @@
+/*
+ * Copyright 2023 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Batching of the packets sessions send upstream, which holds the packets
+//! sent within a short window and sends them together with one `sendmmsg`
+//! call on Linux, see [`crate::utils::mmsg`], trading a little latency for
+//! fewer system calls.
+//!
+//! Every session has its own upstream socket, as that's how the replies of
+//! its endpoint reach it, and `sendmmsg` sends on one socket, so packets are
+//! batched per session rather than across the sessions to an endpoint.
+
+use std::{io, sync::Arc, time::Duration};
+
+use parking_lot::Mutex;
+use tokio::net::UdpSocket;
+
+/// The longest packets are held for, longer windows are shortened to it.
+pub const MAX_WINDOW: Duration = Duration::from_millis(1);
+/// The most packets sent at once, after which a batch is sent early.
+const MAX_PACKETS: usize = 64;
+
+/// Holds the packets a session sends upstream until its window passes.
+pub(crate) struct Batcher {
+    socket: Arc<UdpSocket>,
+    window: Duration,
+    queue: Arc<Mutex<Vec<Vec<u8>>>>,
+}
+
+impl Batcher {
+    /// Batches the packets sent on `socket` over `window`, at most
+    /// [`MAX_WINDOW`].
+    pub(crate) fn new(socket: Arc<UdpSocket>, window: Duration) -> Self {
+        Self {
+            socket,
+            window: window.min(MAX_WINDOW),
+            queue: <_>::default(),
+        }
+    }
+
+    /// Queues `packet`, which is sent along with the other packets queued
+    /// once the window from the first of them passes, or as soon as the
+    /// batch is full.
+    pub(crate) fn send(&self, packet: &[u8]) {
+        let mut queue = self.queue.lock();
+        queue.push(packet.to_vec());
+
+        let window = match queue.len() {
+            1 => self.window,
+            MAX_PACKETS => Duration::ZERO,
+            _ => return,
+        };
+        let socket = self.socket.clone();
+        let queue = self.queue.clone();
+        tokio::spawn(async move {
+            if !window.is_zero() {
+                tokio::time::sleep(window).await;
+            }
+
+            let packets = std::mem::take(&mut *queue.lock());
+            if packets.is_empty() {
+                return;
+            }
+
+            super::metrics::upstream_batch_size().observe(packets.len() as f64);
+            if let Err(error) = send_all(&socket, &packets).await {
+                tracing::warn!(%error, packets = packets.len(), "failed to send batch upstream");
+            }
+        });
+    }
+}
+
+/// Sends every packet of `packets` on the connected `socket`.
+async fn send_all(socket: &UdpSocket, mut packets: &[Vec<u8>]) -> io::Result<()> {
+    while !packets.is_empty() {
+        let sent = crate::utils::mmsg::send(socket, packets).await?;
+        packets = &packets[sent..];
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn send() {
+        let receiver = crate::test_utils::create_socket().await;
+        let socket = Arc::new(crate::test_utils::create_socket().await);
+        socket
+            .connect(receiver.local_addr().unwrap())
+            .await
+            .unwrap();
+
+        let batcher = Batcher::new(socket, Duration::from_secs(1));
+        assert_eq!(MAX_WINDOW, batcher.window);
+        for packet in [b"one", b"two"] {
+            batcher.send(packet);
+        }
+
+        let mut buf = [0; 16];
+        for expected in [b"one", b"two"] {
+            let len = tokio::time::timeout(Duration::from_secs(1), receiver.recv(&mut buf))
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(expected, &buf[..len]);
+        }
+    }
+}
@@ -0,0 +1,263 @@
+/*
+ * Copyright 2023 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! DTLS origination for sessions towards clusters configured with
+//! [`Dtls`].
+
+use std::{
+    future::Future,
+    io,
+    net::SocketAddr,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use dashmap::DashMap;
+use once_cell::sync::Lazy;
+use openssl::{
+    error::ErrorStack,
+    ex_data::Index,
+    ssl::{
+        Ssl, SslConnector, SslMethod, SslOptions, SslSession, SslSessionCacheMode, SslVerifyMode,
+    },
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::UdpSocket,
+    time::{Instant, Sleep},
+};
+
+use crate::cluster::Dtls;
+
+/// How long to wait for a handshake to complete, including retransmissions.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long to wait for a reply to a flight of handshake messages before
+/// sending it again, doubled for every retransmission.
+const RETRANSMIT_TIMEOUT: Duration = Duration::from_secs(1);
+/// The largest datagram sent upstream, leaving room for the IP and UDP
+/// headers within a 1500 byte MTU.
+const MTU: u32 = 1400;
+
+/// Connectors are expensive to create, so they're shared between every
+/// session using the same configuration, which also allows them to share
+/// their session caches.
+static CONNECTORS: Lazy<DashMap<Dtls, Arc<Connector>>> = Lazy::new(<_>::default);
+/// The index of the upstream's address in the `ex_data` of each connection, so
+/// that new sessions can be cached under it.
+static PEER_INDEX: Lazy<Index<Ssl, SocketAddr>> = Lazy::new(|| Ssl::new_ex_index().unwrap());
+
+pub type Stream = tokio_openssl::SslStream<Datagrams>;
+
+/// Adapts a connected UDP socket into a stream where every read and write is
+/// exactly one datagram, which is how DTLS expects its transport to behave.
+///
+/// OpenSSL only retransmits lost handshake messages through its own socket
+/// BIO, so while handshaking the adapter does it instead: it keeps the last
+/// flight of datagrams sent, and sends it again whenever no reply arrives in
+/// time, as described in RFC 6347, section 4.2.4.
+pub struct Datagrams {
+    socket: Arc<UdpSocket>,
+    /// The flight of handshake messages last sent, while handshaking.
+    flight: Option<Flight>,
+}
+
+struct Flight {
+    datagrams: Vec<Vec<u8>>,
+    /// Whether a datagram was received since the flight was sent, in which
+    /// case the next datagram sent starts the next flight.
+    answered: bool,
+    timeout: Duration,
+    timer: Pin<Box<Sleep>>,
+}
+
+impl Flight {
+    fn new() -> Self {
+        Self {
+            datagrams: Vec::new(),
+            answered: false,
+            timeout: RETRANSMIT_TIMEOUT,
+            timer: Box::pin(tokio::time::sleep(RETRANSMIT_TIMEOUT)),
+        }
+    }
+
+    fn sent(&mut self, datagram: &[u8]) {
+        if self.answered {
+            self.datagrams.clear();
+            self.answered = false;
+            self.timeout = RETRANSMIT_TIMEOUT;
+        }
+
+        self.datagrams.push(datagram.to_vec());
+        self.timer.as_mut().reset(Instant::now() + self.timeout);
+    }
+
+    /// Sends the flight again each time its timer expires, registering `cx`
+    /// to be woken for the next expiry.
+    fn poll_retransmit(&mut self, socket: &UdpSocket, cx: &mut Context<'_>) {
+        while !self.datagrams.is_empty() && self.timer.as_mut().poll(cx).is_ready() {
+            tracing::debug!(datagrams = self.datagrams.len(), timeout = ?self.timeout, "retransmitting DTLS handshake flight");
+            for datagram in &self.datagrams {
+                // A datagram that can't be sent now is as good as lost, and is
+                // sent again with the next retransmission.
+                let _ = socket.try_send(datagram);
+            }
+            self.timeout *= 2;
+            self.timer.as_mut().reset(Instant::now() + self.timeout);
+        }
+    }
+}
+
+impl AsyncRead for Datagrams {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        match this.socket.poll_recv(cx, buf) {
+            Poll::Ready(result) => {
+                if let Some(flight) = &mut this.flight {
+                    flight.answered = true;
+                }
+                Poll::Ready(result)
+            }
+            Poll::Pending => {
+                if let Some(flight) = &mut this.flight {
+                    flight.poll_retransmit(&this.socket, cx);
+                }
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl AsyncWrite for Datagrams {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        let result = this.socket.poll_send(cx, buf);
+        if let (Poll::Ready(Ok(_)), Some(flight)) = (&result, &mut this.flight) {
+            flight.sent(buf);
+        }
+        result
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+struct Connector {
+    ssl: SslConnector,
+    /// The most recent session with each upstream, used to resume sessions
+    /// without a full handshake.
+    sessions: Arc<DashMap<SocketAddr, SslSession>>,
+}
+
+impl Connector {
+    fn new(config: &Dtls) -> Result<Self, ErrorStack> {
+        let sessions = Arc::new(DashMap::<SocketAddr, SslSession>::new());
+        let mut builder = SslConnector::builder(SslMethod::dtls())?;
+
+        if let Some(ca_file) = &config.ca_file {
+            builder.set_ca_file(ca_file)?;
+        }
+
+        if config.insecure_skip_verify {
+            builder.set_verify(SslVerifyMode::NONE);
+        }
+
+        // The datagram adapter isn't a socket, so the MTU has to be set
+        // explicitly rather than queried.
+        builder.set_options(SslOptions::NO_QUERY_MTU);
+        builder.set_session_cache_mode(SslSessionCacheMode::CLIENT);
+
+        let cache = sessions.clone();
+        builder.set_new_session_callback(move |ssl, session| {
+            if let Some(peer) = ssl.ex_data(*PEER_INDEX) {
+                cache.insert(*peer, session);
+            }
+        });
+
+        Ok(Self {
+            ssl: builder.build(),
+            sessions,
+        })
+    }
+
+    fn get(config: &Dtls) -> Result<Arc<Self>, ErrorStack> {
+        if let Some(connector) = CONNECTORS.get(config) {
+            return Ok(connector.clone());
+        }
+
+        let connector = Arc::new(Self::new(config)?);
+        CONNECTORS.insert(config.clone(), connector.clone());
+        Ok(connector)
+    }
+}
+
+/// Performs a DTLS handshake with the peer `socket` is connected to,
+/// resuming the previous session with that peer if there is one.
+pub async fn connect(config: &Dtls, socket: Arc<UdpSocket>) -> io::Result<Stream> {
+    config
+        .validate()
+        .map_err(|error| io::Error::new(io::ErrorKind::InvalidInput, error))?;
+    let peer = socket.peer_addr()?;
+    let connector = Connector::get(config).map_err(other)?;
+
+    let mut configuration = connector.ssl.configure().map_err(other)?;
+    configuration.set_use_server_name_indication(config.server_name.is_some());
+    configuration.set_verify_hostname(config.server_name.is_some());
+    configuration.set_mtu(MTU).map_err(other)?;
+    configuration.set_ex_data(*PEER_INDEX, peer);
+    if let Some(session) = connector.sessions.get(&peer) {
+        // SAFETY: The session was created by a connection from the same
+        // context.
+        unsafe { configuration.set_session(&session) }.map_err(other)?;
+    }
+
+    let ssl = configuration
+        .into_ssl(config.server_name.as_deref().unwrap_or_default())
+        .map_err(other)?;
+    let datagrams = Datagrams {
+        socket,
+        flight: Some(Flight::new()),
+    };
+    let mut stream = Stream::new(ssl, datagrams).map_err(other)?;
+
+    tokio::time::timeout(HANDSHAKE_TIMEOUT, Pin::new(&mut stream).connect())
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "DTLS handshake timed out"))?
+        .map_err(other)?;
+    // Application data is never retransmitted.
+    stream.get_mut().flight = None;
+
+    tracing::debug!(%peer, resumed = stream.ssl().session_reused(), "DTLS session established");
+    Ok(stream)
+}
+
+fn other<E: std::error::Error + Send + Sync + 'static>(error: E) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, error)
+}
@@ -0,0 +1,152 @@
+/*
+ * Copyright 2023 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! The failure domain of each session, the cluster and locality of its
+//! endpoint, so that during an outage of a zone operators can see how many
+//! live sessions it affects.
+
+use std::sync::Arc;
+
+use dashmap::DashMap;
+use once_cell::sync::Lazy;
+use serde::Serialize;
+
+use crate::endpoint::Locality;
+
+/// The number of live sessions in each failure domain.
+static SESSIONS: Lazy<DashMap<FailureDomain, u64>> = Lazy::new(<_>::default);
+
+/// Where a session's endpoint is, and so which outages affect it.
+#[derive(Clone, Debug, Default, Hash, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+pub(crate) struct FailureDomain {
+    /// The cluster of the endpoint, empty if it wasn't found.
+    pub cluster: Arc<str>,
+    /// The locality of the endpoint, if it has one.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub locality: Option<Locality>,
+}
+
+impl FailureDomain {
+    fn labels(&self) -> [&str; 4] {
+        let locality = self.locality.as_ref();
+        [
+            &self.cluster,
+            locality.map_or("", |locality| &*locality.region),
+            locality.map_or("", |locality| &*locality.zone),
+            locality.map_or("", |locality| &*locality.sub_zone),
+        ]
+    }
+}
+
+/// The number of live sessions in a failure domain.
+#[derive(Debug, PartialEq, Eq, Serialize)]
+pub(crate) struct DomainSessions {
+    #[serde(flatten)]
+    pub domain: FailureDomain,
+    pub sessions: u64,
+}
+
+/// Returns the number of live sessions in each failure domain with any.
+pub(crate) fn sessions() -> Vec<DomainSessions> {
+    let mut sessions: Vec<_> = SESSIONS
+        .iter()
+        .map(|entry| DomainSessions {
+            domain: entry.key().clone(),
+            sessions: *entry.value(),
+        })
+        .collect();
+    sessions.sort_by(|lhs, rhs| lhs.domain.cmp(&rhs.domain));
+    sessions
+}
+
+/// A session's place in the count of its failure domain, released when
+/// dropped.
+#[derive(Debug)]
+pub(crate) struct Tag {
+    domain: FailureDomain,
+}
+
+impl Tag {
+    /// Counts a new session in `domain`.
+    pub(crate) fn new(domain: FailureDomain) -> Self {
+        // The gauges are updated while the count is locked, so that they
+        // agree with it.
+        let mut sessions = SESSIONS.entry(domain.clone()).or_default();
+        *sessions += 1;
+        super::metrics::active_by_failure_domain()
+            .with_label_values(&domain.labels())
+            .inc();
+        drop(sessions);
+        Self { domain }
+    }
+
+    pub(crate) fn domain(&self) -> &FailureDomain {
+        &self.domain
+    }
+}
+
+impl Drop for Tag {
+    fn drop(&mut self) {
+        let labels = self.domain.labels();
+        SESSIONS.remove_if_mut(&self.domain, |_, sessions| {
+            let gauges = super::metrics::active_by_failure_domain();
+            *sessions -= 1;
+            // Domains without sessions are forgotten, so that the gauges of
+            // localities that were removed don't linger.
+            if *sessions == 0 {
+                let _ = gauges.remove_label_values(&labels);
+            } else {
+                gauges.with_label_values(&labels).dec();
+            }
+            *sessions == 0
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sessions() {
+        let zone = |zone: &str| FailureDomain {
+            cluster: "failure-domain-test".into(),
+            locality: Some(Locality {
+                region: "us-east1".into(),
+                zone: zone.into(),
+                sub_zone: String::new(),
+            }),
+        };
+        let count = |domain: &FailureDomain| {
+            super::sessions()
+                .into_iter()
+                .find(|sessions| sessions.domain == *domain)
+                .map_or(0, |sessions| sessions.sessions)
+        };
+
+        let first = Tag::new(zone("b"));
+        let second = Tag::new(zone("b"));
+        let other = Tag::new(zone("c"));
+        assert_eq!(2, count(&zone("b")));
+        assert_eq!(1, count(&zone("c")));
+
+        drop(first);
+        assert_eq!(1, count(&zone("b")));
+        drop((second, other));
+        assert_eq!(0, count(&zone("b")));
+        assert!(!SESSIONS.contains_key(&zone("c")));
+    }
+}
@@ -0,0 +1,443 @@
+/*
+ * Copyright 2023 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! A local, size bounded journal of session lifecycle records, for
+//! investigating sessions after the fact when central logging missed them.
+//!
+//! Records are written as JSON lines to the journal file, which is moved to
+//! `<path>.1` once it reaches half of the maximum size, replacing the
+//! previous rotation, so the journal never takes more than the maximum size.
+
+use std::{
+    collections::{BTreeMap, VecDeque},
+    fs::{File, OpenOptions},
+    io::{self, BufRead, BufReader, BufWriter, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc, Arc,
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use arc_swap::ArcSwapOption;
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+
+/// The number of records that can be waiting to be written before new
+/// records are dropped, so that a slow disk never slows down the proxy.
+const QUEUE_SIZE: usize = 4096;
+
+static JOURNAL: Lazy<ArcSwapOption<Journal>> = Lazy::new(<_>::default);
+
+/// Sets the journal that sessions are recorded to, or stops journaling if
+/// `None`.
+pub(crate) fn install(journal: Option<Journal>) {
+    JOURNAL.store(journal.map(Arc::new));
+}
+
+/// Records the record returned by `record` if a journal is installed.
+pub(crate) fn record(record: impl FnOnce() -> Record) {
+    if let Some(journal) = &*JOURNAL.load() {
+        journal.record(record());
+    }
+}
+
+/// Whether a session started or ended.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize, clap::ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum Event {
+    Start,
+    End,
+}
+
+/// A session lifecycle event.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Record {
+    /// When the event happened, in milliseconds since the UNIX epoch.
+    pub timestamp_ms: u64,
+    pub event: Event,
+    /// The address of the client.
+    pub source: String,
+    /// The address of the endpoint.
+    pub dest: String,
+    /// The cluster of the endpoint, if it was found.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub cluster: String,
+    /// The locality of the endpoint, if it has one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub locality: Option<crate::endpoint::Locality>,
+    /// How long the session lasted, only set for `end` events.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub duration_ms: Option<u64>,
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub packets_read: u64,
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub bytes_read: u64,
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub packets_written: u64,
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub bytes_written: u64,
+    /// The number of packets dropped during the session, keyed by cause.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub dropped: BTreeMap<String, u64>,
+}
+
+impl Record {
+    /// Creates a record of `event` happening now.
+    pub fn new(event: Event, source: String, dest: String, cluster: String) -> Self {
+        Self {
+            timestamp_ms: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |now| now.as_millis() as u64),
+            event,
+            source,
+            dest,
+            cluster,
+            locality: None,
+            duration_ms: None,
+            packets_read: 0,
+            bytes_read: 0,
+            packets_written: 0,
+            bytes_written: 0,
+            dropped: <_>::default(),
+        }
+    }
+}
+
+fn is_zero(value: &u64) -> bool {
+    *value == 0
+}
+
+/// The traffic of a single session, included in its `end` record.
+#[derive(Debug, Default)]
+pub(crate) struct Stats {
+    packets_read: AtomicU64,
+    bytes_read: AtomicU64,
+    packets_written: AtomicU64,
+    bytes_written: AtomicU64,
+    dropped_quota: AtomicU64,
+    dropped_filter: AtomicU64,
+    dropped_error: AtomicU64,
+    dropped_pacing: AtomicU64,
+}
+
+/// The causes of dropped packets in [`Record::dropped`].
+pub(crate) enum DropCause {
+    Quota,
+    Filter,
+    Error,
+    Pacing,
+}
+
+impl Stats {
+    pub(crate) fn read(&self, size: usize) {
+        self.packets_read.fetch_add(1, Ordering::Relaxed);
+        self.bytes_read.fetch_add(size as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn written(&self, size: usize) {
+        self.packets_written.fetch_add(1, Ordering::Relaxed);
+        self.bytes_written.fetch_add(size as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn dropped(&self, cause: DropCause) {
+        match cause {
+            DropCause::Quota => &self.dropped_quota,
+            DropCause::Filter => &self.dropped_filter,
+            DropCause::Error => &self.dropped_error,
+            DropCause::Pacing => &self.dropped_pacing,
+        }
+        .fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Adds the counters to `record`.
+    pub(crate) fn apply(&self, record: &mut Record) {
+        record.packets_read = self.packets_read.load(Ordering::Relaxed);
+        record.bytes_read = self.bytes_read.load(Ordering::Relaxed);
+        record.packets_written = self.packets_written.load(Ordering::Relaxed);
+        record.bytes_written = self.bytes_written.load(Ordering::Relaxed);
+        record.dropped = [
+            (crate::quota::QUOTA_EXCEEDED_REASON, &self.dropped_quota),
+            ("FilterDroppedPacket", &self.dropped_filter),
+            ("Error", &self.dropped_error),
+            (super::pacing::PACING_EXCEEDED_REASON, &self.dropped_pacing),
+        ]
+        .into_iter()
+        .map(|(cause, count)| (cause.to_owned(), count.load(Ordering::Relaxed)))
+        .filter(|(_, count)| *count > 0)
+        .collect();
+    }
+}
+
+/// Writes records to a rotating file from a background thread.
+pub struct Journal {
+    records: mpsc::SyncSender<Record>,
+}
+
+impl Journal {
+    /// Opens the journal at `path`, which together with its rotation will
+    /// take at most `max_bytes`.
+    pub fn open(path: impl Into<PathBuf>, max_bytes: u64) -> io::Result<Self> {
+        let mut writer = Writer::open(path.into(), max_bytes / 2)?;
+        let (records, rx) = mpsc::sync_channel::<Record>(QUEUE_SIZE);
+
+        std::thread::Builder::new()
+            .name("session-journal".into())
+            .spawn(move || {
+                while let Ok(record) = rx.recv() {
+                    for record in std::iter::once(record).chain(rx.try_iter()) {
+                        if let Err(error) = writer.write(&record) {
+                            tracing::warn!(%error, "failed to write session journal");
+                        }
+                    }
+
+                    if let Err(error) = writer.file.flush() {
+                        tracing::warn!(%error, "failed to flush session journal");
+                    }
+                }
+            })?;
+
+        Ok(Self { records })
+    }
+
+    /// Queues `record` to be written, dropping it if the queue is full.
+    pub fn record(&self, record: Record) {
+        if self.records.try_send(record).is_err() {
+            tracing::debug!("session journal queue full, dropping record");
+        }
+    }
+}
+
+struct Writer {
+    path: PathBuf,
+    file: BufWriter<File>,
+    size: u64,
+    limit: u64,
+}
+
+impl Writer {
+    fn open(path: PathBuf, limit: u64) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let size = file.metadata()?.len();
+
+        Ok(Self {
+            path,
+            file: BufWriter::new(file),
+            size,
+            limit,
+        })
+    }
+
+    fn write(&mut self, record: &Record) -> io::Result<()> {
+        let mut line = serde_json::to_vec(record)?;
+        line.push(b'\n');
+
+        if self.size > 0 && self.size + line.len() as u64 > self.limit {
+            self.file.flush()?;
+            std::fs::rename(&self.path, rotated(&self.path))?;
+            *self = Self::open(self.path.clone(), self.limit)?;
+        }
+
+        self.file.write_all(&line)?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+}
+
+fn rotated(path: &Path) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(".1");
+    path.into()
+}
+
+/// The criteria for records returned by [`query`], every criteria that is set
+/// must match.
+#[derive(Clone, Debug, Default)]
+pub struct Query {
+    /// The client, either as an IP address or a socket address.
+    pub source: Option<String>,
+    /// The endpoint, either as an IP address or a socket address.
+    pub dest: Option<String>,
+    pub cluster: Option<String>,
+    pub event: Option<Event>,
+    /// Only records at or after this time, in milliseconds since the UNIX
+    /// epoch.
+    pub since_ms: Option<u64>,
+    /// Only the most recent `limit` matching records.
+    pub limit: Option<usize>,
+}
+
+impl Query {
+    fn matches(&self, record: &Record) -> bool {
+        address_matches(self.source.as_deref(), &record.source)
+            && address_matches(self.dest.as_deref(), &record.dest)
+            && self
+                .cluster
+                .as_ref()
+                .map_or(true, |cluster| *cluster == record.cluster)
+            && self.event.map_or(true, |event| event == record.event)
+            && self
+                .since_ms
+                .map_or(true, |since| record.timestamp_ms >= since)
+    }
+}
+
+fn address_matches(expected: Option<&str>, address: &str) -> bool {
+    let Some(expected) = expected else {
+        return true;
+    };
+
+    let ip = address
+        .rsplit_once(':')
+        .map_or(address, |(ip, _)| ip)
+        .trim_start_matches('[')
+        .trim_end_matches(']');
+    expected == address || expected == ip
+}
+
+/// Reads the records of the journal at `path` matching `query`, from the
+/// oldest to the most recent. Lines which can't be parsed, such as a line
+/// that was being written during a crash, are skipped.
+pub fn query(path: &Path, query: &Query) -> io::Result<Vec<Record>> {
+    let mut records = VecDeque::new();
+
+    for path in [rotated(path), path.to_owned()] {
+        let file = match File::open(&path) {
+            Ok(file) => file,
+            Err(error) if error.kind() == io::ErrorKind::NotFound => continue,
+            Err(error) => return Err(error),
+        };
+
+        for line in BufReader::new(file).lines() {
+            let Ok(record) = serde_json::from_str::<Record>(&line?) else {
+                continue;
+            };
+
+            if query.matches(&record) {
+                records.push_back(record);
+                if query.limit.map_or(false, |limit| records.len() > limit) {
+                    records.pop_front();
+                }
+            }
+        }
+    }
+
+    Ok(records.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(event: Event, source: &str) -> Record {
+        Record::new(
+            event,
+            source.into(),
+            "10.0.0.1:7777".into(),
+            "default".into(),
+        )
+    }
+
+    #[test]
+    fn rotates_and_queries() {
+        let path = std::env::temp_dir().join(format!("quilkin-journal-{}", uuid::Uuid::new_v4()));
+        let line = serde_json::to_vec(&record(Event::Start, "127.0.0.1:1")).unwrap();
+        // Room for three records per file.
+        let mut writer = Writer::open(path.clone(), line.len() as u64 * 3 + 3).unwrap();
+
+        for port in 0..8 {
+            let event = if port % 2 == 0 {
+                Event::Start
+            } else {
+                Event::End
+            };
+            writer
+                .write(&record(event, &format!("127.0.0.{port}:1")))
+                .unwrap();
+        }
+        writer.file.flush().unwrap();
+
+        let sources = |query: &Query| {
+            super::query(&path, query)
+                .unwrap()
+                .into_iter()
+                .map(|record| record.source)
+                .collect::<Vec<_>>()
+        };
+
+        // The oldest rotation was discarded.
+        assert_eq!(
+            vec![
+                "127.0.0.3:1",
+                "127.0.0.4:1",
+                "127.0.0.5:1",
+                "127.0.0.6:1",
+                "127.0.0.7:1"
+            ],
+            sources(&Query::default())
+        );
+        assert_eq!(
+            vec!["127.0.0.5:1", "127.0.0.7:1"],
+            sources(&Query {
+                event: Some(Event::End),
+                limit: Some(2),
+                ..<_>::default()
+            })
+        );
+        assert_eq!(
+            vec!["127.0.0.4:1"],
+            sources(&Query {
+                source: Some("127.0.0.4".into()),
+                ..<_>::default()
+            })
+        );
+        assert!(sources(&Query {
+            cluster: Some("other".into()),
+            ..<_>::default()
+        })
+        .is_empty());
+
+        std::fs::remove_file(rotated(&path)).unwrap();
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn stats() {
+        let stats = Stats::default();
+        stats.read(10);
+        stats.written(20);
+        stats.written(20);
+        stats.dropped(DropCause::Quota);
+
+        let mut record = record(Event::End, "127.0.0.1:1");
+        stats.apply(&mut record);
+        assert_eq!(
+            (1, 10, 2, 40),
+            (
+                record.packets_read,
+                record.bytes_read,
+                record.packets_written,
+                record.bytes_written
+            )
+        );
+        assert_eq!(
+            Some(&1),
+            record.dropped.get(crate::quota::QUOTA_EXCEEDED_REASON)
+        );
+        assert_eq!(1, record.dropped.len());
+    }
+}
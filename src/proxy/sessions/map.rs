@@ -0,0 +1,611 @@
+/*
+ * Copyright 2023 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use tokio::{net::UdpSocket, sync::broadcast::error::RecvError};
+
+use super::{
+    memory, metrics,
+    policy::{Eviction, SessionPolicy},
+    Session, SessionKey, SessionMemory, Tasks,
+};
+use crate::{cluster::Clusters, endpoint::EndpointAddress, ttl_map::TtlMap};
+
+/// The fraction of the memory limit, or of the maximum number of sessions,
+/// that sessions are evicted down to once it's reached, so that a flood of
+/// new clients doesn't evict sessions for every one of them.
+const EVICTION_TARGET: f64 = 0.9;
+
+/// The sessions created by a single worker.
+pub type SessionShard = TtlMap<SessionKey, Session>;
+
+/// The sessions of every worker, split into a shard per worker.
+///
+/// The kernel pins each client to one of the `SO_REUSEPORT` sockets, so a
+/// worker only ever needs its own shard, and looking up a session never
+/// contends with the other workers. Operations over the whole map visit every
+/// shard, and are meant for aggregation rather than the packet path.
+#[derive(Clone)]
+pub struct SessionMap {
+    shards: Arc<[SessionShard]>,
+    tasks: Tasks,
+    /// The most memory sessions may hold, in bytes.
+    memory_limit: Option<usize>,
+    /// The session policy overriding the one in the config.
+    policy: SessionPolicy,
+    /// The number of sessions being created, see [`Reservation`].
+    reserved: Arc<AtomicUsize>,
+    /// The memory held by the sessions.
+    memory: SessionMemory,
+}
+
+/// A place for a new session under the maximum number of sessions, held
+/// from [`SessionMap::reserve_session`] until the session is inserted, so
+/// that concurrent workers can't create more sessions than the maximum
+/// between them.
+#[must_use]
+#[derive(Debug)]
+pub struct Reservation(Arc<AtomicUsize>);
+
+impl Reservation {
+    fn new(reserved: &Arc<AtomicUsize>) -> Self {
+        reserved.fetch_add(1, Ordering::SeqCst);
+        Self(reserved.clone())
+    }
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl SessionMap {
+    /// Creates a map with `shards` shards (at least one), whose sessions
+    /// expire after `ttl` without traffic.
+    pub fn new(shards: usize, ttl: Duration, poll_interval: Duration) -> Self {
+        Self {
+            shards: (0..shards.max(1))
+                .map(|_| SessionShard::new(ttl, poll_interval))
+                .collect(),
+            tasks: <_>::default(),
+            memory_limit: None,
+            policy: <_>::default(),
+            reserved: <_>::default(),
+            memory: <_>::default(),
+        }
+    }
+
+    /// Spawns the tasks of the map and its sessions in `tasks`, so that
+    /// they're stopped along with the rest of the proxy.
+    pub fn with_tasks(mut self, tasks: Tasks) -> Self {
+        self.tasks = tasks;
+        self
+    }
+
+    /// Limits the approximate memory held by sessions to `limit` bytes, see
+    /// [`SessionMap::reserve_memory`].
+    pub fn with_memory_limit(mut self, limit: Option<usize>) -> Self {
+        self.memory_limit = limit;
+        self
+    }
+
+    /// Overrides the fields of the config's session policy that are set in
+    /// `policy`, see [`SessionMap::policy`].
+    pub fn with_policy(mut self, policy: SessionPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// The policy of new sessions, which is the map's policy falling back to
+    /// the one in `config`.
+    pub fn policy(&self, config: &crate::Config) -> SessionPolicy {
+        self.policy.or(&config.sessions.load())
+    }
+
+    /// The tasks the map's sessions are spawned in.
+    pub fn tasks(&self) -> &Tasks {
+        &self.tasks
+    }
+
+    /// The number of shards, which is the number of workers the map was
+    /// created for.
+    pub fn shard_count(&self) -> usize {
+        self.shards.len()
+    }
+
+    /// Returns the shard of the worker with `worker_id`.
+    pub fn shard(&self, worker_id: usize) -> &SessionShard {
+        &self.shards[worker_id % self.shards.len()]
+    }
+
+    /// Returns the shard holding the session for `key`, which is the shard
+    /// of the worker with `worker_id` unless the session was created by
+    /// another worker. That only happens after the number of workers
+    /// changes, when the kernel moves clients between the `SO_REUSEPORT`
+    /// sockets, so the other shards are only searched when the worker's own
+    /// shard doesn't have the session.
+    pub fn shard_for(&self, key: &SessionKey, worker_id: usize) -> &SessionShard {
+        let own = self.shard(worker_id);
+        if own.contains_key(key) {
+            return own;
+        }
+
+        self.shards
+            .iter()
+            .find(|shard| shard.contains_key(key))
+            .unwrap_or(own)
+    }
+
+    /// Moves the sessions of the worker with `worker_id`, which is being
+    /// removed, to the shards of the remaining workers, whose `sockets` the
+    /// sessions send their packets back to clients from from then on. Returns
+    /// the number of sessions moved.
+    pub fn hand_off(&self, worker_id: usize, sockets: &[Arc<UdpSocket>]) -> usize {
+        if sockets.is_empty() {
+            return 0;
+        }
+
+        let from = self.shard(worker_id);
+        let mut moved = 0;
+        for (_, key) in from.expirations(|key, _| key.clone()) {
+            let Some(session) = from.remove(&key) else {
+                continue;
+            };
+            let worker_id = moved % sockets.len();
+            session.hand_off(sockets[worker_id].clone());
+            self.shard(worker_id).insert(key, session);
+            moved += 1;
+        }
+
+        moved
+    }
+
+    /// Returns the number of sessions across every shard.
+    pub fn len(&self) -> usize {
+        self.shards.iter().map(SessionShard::len).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The approximate memory held by every active session, in bytes.
+    pub fn memory_bytes(&self) -> usize {
+        self.memory.bytes()
+    }
+
+    /// The memory of the sessions, which new sessions are counted towards.
+    pub fn memory(&self) -> &SessionMemory {
+        &self.memory
+    }
+
+    /// Makes room for a new session under the memory limit, if any. Once the
+    /// limit is reached, the sessions closest to expiring, which are the
+    /// least recently active, are closed until the sessions are back under
+    /// [`EVICTION_TARGET`] of the limit. Returns an error if there still
+    /// isn't room.
+    pub fn reserve_memory(&self) -> std::io::Result<()> {
+        let Some(limit) = self.memory_limit else {
+            return Ok(());
+        };
+
+        let bytes = memory::estimate(false);
+        let total = self.memory.bytes();
+        if total + bytes <= limit {
+            return Ok(());
+        }
+
+        let target = (limit as f64 * EVICTION_TARGET) as usize;
+        let evicted = self.evict((total + bytes).saturating_sub(target));
+        metrics::memory_evicted_total().inc_by(evicted as u64);
+        tracing::debug!(
+            evicted,
+            limit,
+            "closed sessions to stay under the memory limit"
+        );
+        if self.memory.bytes() + bytes > limit {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Other,
+                "sessions have reached their memory limit",
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Makes room for a new session under the maximum number of sessions of
+    /// `policy`, if any, counting the sessions other workers are creating.
+    /// Once it's reached, the new session is rejected with an error, or the
+    /// sessions closest to expiring are closed until the sessions are back
+    /// under [`EVICTION_TARGET`] of the maximum, depending on the policy's
+    /// [`Eviction`]. The returned [`Reservation`] must be held until the new
+    /// session is inserted.
+    pub fn reserve_session(&self, policy: &SessionPolicy) -> std::io::Result<Reservation> {
+        let reservation = Reservation::new(&self.reserved);
+        let Some(max) = policy.max_sessions else {
+            return Ok(reservation);
+        };
+
+        let sessions = || self.len() + self.reserved.load(Ordering::SeqCst);
+        if sessions() <= max {
+            return Ok(reservation);
+        }
+
+        if policy.eviction() == Eviction::LeastRecentlyActive {
+            let target = (max as f64 * EVICTION_TARGET) as usize;
+            let excess = sessions().saturating_sub(target);
+            let evicted = self.evict_until(|_, evicted| evicted >= excess);
+            metrics::capacity_evicted_total().inc_by(evicted as u64);
+            tracing::debug!(evicted, max, "closed sessions to stay under the maximum");
+            if sessions() <= max {
+                return Ok(reservation);
+            }
+        }
+
+        metrics::capacity_rejected_total().inc();
+        Err(std::io::Error::new(
+            std::io::ErrorKind::Other,
+            "the proxy has reached its maximum number of sessions",
+        ))
+    }
+
+    /// Closes the least recently active sessions until at least `bytes` have
+    /// been freed, returning the number of sessions closed.
+    fn evict(&self, bytes: usize) -> usize {
+        self.evict_until(|freed, _| freed >= bytes)
+    }
+
+    /// Closes the sessions closest to expiring, which are the least recently
+    /// active ones, until `done` returns `true` for the bytes freed and the
+    /// number of sessions closed so far, returning the number closed.
+    fn evict_until(&self, mut done: impl FnMut(usize, usize) -> bool) -> usize {
+        let mut sessions: Vec<_> = self
+            .shards
+            .iter()
+            .enumerate()
+            .flat_map(|(index, shard)| {
+                shard.expirations(|key, session| (index, key.clone(), session.memory_bytes()))
+            })
+            .collect();
+        sessions.sort_unstable_by_key(|(expires_at, _)| *expires_at);
+
+        let mut freed = 0;
+        let mut evicted = 0;
+        for (_, (index, key, size)) in sessions {
+            if done(freed, evicted) {
+                break;
+            }
+            if self.shards[index].remove(&key).is_some() {
+                freed += size;
+                evicted += 1;
+            }
+        }
+
+        evicted
+    }
+
+    /// Whether any worker has a session for `key`.
+    pub fn contains_key(&self, key: &SessionKey) -> bool {
+        self.shards.iter().any(|shard| shard.contains_key(key))
+    }
+
+    /// Closes every session to the endpoint at `address`, returning the
+    /// number of sessions closed.
+    pub fn remove_endpoint(&self, address: &EndpointAddress) -> usize {
+        self.shards
+            .iter()
+            .map(|shard| shard.retain(|key, _| key.dest != *address))
+            .sum()
+    }
+
+    /// Spawns a task closing the sessions to endpoints as they are removed
+    /// from `clusters`, rather than leaving them to expire. The task stops
+    /// once every clone of the map has been dropped.
+    pub fn close_removed_endpoints(&self, clusters: &Clusters) {
+        let shards = Arc::downgrade(&self.shards);
+        let mut changes = clusters.changes();
+
+        self.tasks.spawn("close removed endpoints", async move {
+            loop {
+                let change = match changes.recv().await {
+                    Ok(change) => change,
+                    Err(RecvError::Lagged(skipped)) => {
+                        tracing::warn!(
+                            skipped,
+                            "missed cluster changes, sessions to removed endpoints will expire instead"
+                        );
+                        continue;
+                    }
+                    Err(RecvError::Closed) => return,
+                };
+
+                let Some(shards) = shards.upgrade() else {
+                    return;
+                };
+
+                // The task doesn't keep a whole map, which would keep the
+                // tasks it's spawned in alive.
+                for address in change.removed_endpoints() {
+                    super::metrics::remove_endpoint(address);
+                    let closed: usize = shards
+                        .iter()
+                        .map(|shard| shard.retain(|key, _| key.dest != *address))
+                        .sum();
+                    if closed > 0 {
+                        tracing::debug!(%address, closed, "closed sessions to removed endpoint");
+                    }
+                }
+            }
+        });
+    }
+}
+
+impl Default for SessionMap {
+    fn default() -> Self {
+        Self {
+            shards: Arc::new([SessionShard::default()]),
+            tasks: <_>::default(),
+            memory_limit: None,
+            policy: <_>::default(),
+            reserved: <_>::default(),
+            memory: <_>::default(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        cluster::Cluster,
+        endpoint::{Endpoint, LocalityEndpoints},
+    };
+
+    #[tokio::test]
+    async fn shards() {
+        let map = SessionMap::new(0, Duration::from_secs(1), Duration::from_secs(1));
+        assert_eq!(1, map.shard_count());
+
+        let map = SessionMap::new(4, Duration::from_secs(1), Duration::from_secs(1));
+        assert_eq!(4, map.shard_count());
+        assert!(std::ptr::eq(map.shard(1), map.shard(5)));
+        assert!(!std::ptr::eq(map.shard(1), map.shard(2)));
+        assert!(map.is_empty());
+    }
+
+    #[tokio::test]
+    async fn evicts_least_recently_active() {
+        let socket = Arc::new(crate::test_utils::create_socket().await);
+        let config = Arc::new(crate::Config::default());
+        let dest: EndpointAddress = socket.local_addr().unwrap().into();
+        let map = SessionMap::new(2, Duration::from_secs(60), Duration::from_secs(60));
+
+        let mut keys = Vec::new();
+        for (port, ttl) in [(9100, 30), (9101, 10), (9102, 20)] {
+            let key = SessionKey {
+                source: (std::net::Ipv4Addr::LOCALHOST, port).into(),
+                dest: dest.clone(),
+            };
+            let session = crate::proxy::SessionArgs {
+                config: config.clone(),
+                source: key.source.clone(),
+                downstream_socket: socket.clone(),
+                dest: Endpoint::new(dest.clone()),
+                socket_config: <_>::default(),
+                tasks: <_>::default(),
+                memory: map.memory().clone(),
+                token: None,
+            }
+            .into_session()
+            .await
+            .unwrap();
+            map.shard(port as usize).insert_with_ttl(
+                key.clone(),
+                session,
+                Duration::from_secs(ttl),
+            );
+            keys.push(key);
+        }
+
+        assert_eq!(1, map.evict(1));
+        assert!(!map.contains_key(&keys[1]));
+        assert_eq!(2, map.evict(memory::estimate(false) + 1));
+        assert!(map.is_empty());
+
+        // Without a limit there's always room.
+        assert!(map.reserve_memory().is_ok());
+        assert!(map.with_memory_limit(Some(0)).reserve_memory().is_err());
+    }
+
+    #[tokio::test]
+    async fn hand_off() {
+        let socket = Arc::new(crate::test_utils::create_socket().await);
+        let sockets = [
+            Arc::new(crate::test_utils::create_socket().await),
+            Arc::new(crate::test_utils::create_socket().await),
+        ];
+        let config = Arc::new(crate::Config::default());
+        let dest: EndpointAddress = socket.local_addr().unwrap().into();
+        let map = SessionMap::new(3, Duration::from_secs(60), Duration::from_secs(60));
+
+        for port in 9300..9304 {
+            let key = SessionKey {
+                source: (std::net::Ipv4Addr::LOCALHOST, port).into(),
+                dest: dest.clone(),
+            };
+            let session = crate::proxy::SessionArgs {
+                config: config.clone(),
+                source: key.source.clone(),
+                downstream_socket: socket.clone(),
+                dest: Endpoint::new(dest.clone()),
+                socket_config: <_>::default(),
+                tasks: <_>::default(),
+                memory: map.memory().clone(),
+                token: None,
+            }
+            .into_session()
+            .await
+            .unwrap();
+            map.shard(2).insert(key, session);
+        }
+
+        // The sessions are spread over the remaining workers, and reply from
+        // their sockets.
+        assert_eq!(0, map.hand_off(2, &[]));
+        assert_eq!(4, map.hand_off(2, &sockets));
+        assert_eq!(4, map.len());
+        assert_eq!(0, map.shard(2).len());
+        for (worker_id, socket) in sockets.iter().enumerate() {
+            let shard = map.shard(worker_id);
+            assert_eq!(2, shard.len());
+            for (_, key) in shard.expirations(|key, _| key.clone()) {
+                let session = shard.get(&key).unwrap();
+                assert!(Arc::ptr_eq(socket, &session.downstream_socket.load()));
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn reserve_session() {
+        let socket = Arc::new(crate::test_utils::create_socket().await);
+        let config = Arc::new(crate::Config::default());
+        let dest: EndpointAddress = socket.local_addr().unwrap().into();
+        let map = SessionMap::new(1, Duration::from_secs(60), Duration::from_secs(60));
+
+        let mut keys = Vec::new();
+        for (port, ttl) in [(9200, 30), (9201, 10), (9202, 20)] {
+            let key = SessionKey {
+                source: (std::net::Ipv4Addr::LOCALHOST, port).into(),
+                dest: dest.clone(),
+            };
+            let session = crate::proxy::SessionArgs {
+                config: config.clone(),
+                source: key.source.clone(),
+                downstream_socket: socket.clone(),
+                dest: Endpoint::new(dest.clone()),
+                socket_config: <_>::default(),
+                tasks: <_>::default(),
+                memory: map.memory().clone(),
+                token: None,
+            }
+            .into_session()
+            .await
+            .unwrap();
+            map.shard(0)
+                .insert_with_ttl(key.clone(), session, Duration::from_secs(ttl));
+            keys.push(key);
+        }
+
+        let mut policy = SessionPolicy {
+            max_sessions: Some(4),
+            ..<_>::default()
+        };
+        assert!(map.reserve_session(&SessionPolicy::default()).is_ok());
+        assert!(map.reserve_session(&policy).is_ok());
+
+        policy.max_sessions = Some(3);
+        assert!(map.reserve_session(&policy).is_err());
+        assert_eq!(3, map.len());
+
+        // Sessions are evicted down to a fraction of the maximum, rather
+        // than one at a time.
+        policy.eviction = Some(Eviction::LeastRecentlyActive);
+        assert!(map.reserve_session(&policy).is_ok());
+        assert!(!map.contains_key(&keys[1]));
+        assert!(!map.contains_key(&keys[2]));
+        assert!(map.contains_key(&keys[0]));
+
+        // Sessions being created count towards the maximum.
+        policy.eviction = None;
+        policy.max_sessions = Some(2);
+        let reservation = map.reserve_session(&policy).unwrap();
+        assert!(map.reserve_session(&policy).is_err());
+        drop(reservation);
+        assert!(map.reserve_session(&policy).is_ok());
+
+        policy.max_sessions = Some(0);
+        assert!(map.reserve_session(&policy).is_err());
+        assert!(map.is_empty());
+    }
+
+    #[tokio::test]
+    async fn close_removed_endpoints() {
+        let socket = Arc::new(crate::test_utils::create_socket().await);
+        let config = Arc::new(crate::Config::default());
+        let dest: EndpointAddress = socket.local_addr().unwrap().into();
+        let cluster = Cluster::new(
+            "sessions/removed".into(),
+            vec![LocalityEndpoints::from(Endpoint::new(dest.clone()))],
+        );
+        config.clusters.modify(|map| {
+            map.insert(cluster.clone());
+        });
+
+        let map = SessionMap::new(2, Duration::from_secs(60), Duration::from_secs(60));
+        map.close_removed_endpoints(&config.clusters);
+
+        let key = SessionKey {
+            source: (std::net::Ipv4Addr::LOCALHOST, 9000).into(),
+            dest: dest.clone(),
+        };
+        let session = crate::proxy::SessionArgs {
+            config: config.clone(),
+            source: key.source.clone(),
+            downstream_socket: socket,
+            dest: Endpoint::new(dest),
+            socket_config: <_>::default(),
+            tasks: <_>::default(),
+            memory: map.memory().clone(),
+            token: None,
+        }
+        .into_session()
+        .await
+        .unwrap();
+        map.shard(1).insert(key.clone(), session);
+        assert!(map.contains_key(&key));
+        assert!(std::ptr::eq(map.shard(1), map.shard_for(&key, 0)));
+        assert!(std::ptr::eq(
+            map.shard(0),
+            map.shard_for(
+                &SessionKey {
+                    source: (std::net::Ipv4Addr::LOCALHOST, 9001).into(),
+                    dest: key.dest.clone(),
+                },
+                0
+            )
+        ));
+
+        config.clusters.modify(|map| {
+            map.remove(&cluster.name);
+        });
+
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while map.contains_key(&key) {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+    }
+}
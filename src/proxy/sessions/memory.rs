@@ -0,0 +1,106 @@
+/*
+ * Copyright 2023 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Approximate accounting of the memory held by sessions, so that a proxy
+//! with a limit closes its least recently active sessions when a flood of
+//! clients creates sessions faster than they expire, rather than being
+//! killed by the kernel.
+//!
+//! A session's memory is estimated when it's created, from the session
+//! itself, its receive buffer and its DTLS stream, if any. Memory held by
+//! filters on behalf of a client, and by the kernel for the session's socket,
+//! isn't included.
+
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+};
+
+use super::{metrics, Session, SessionKey, RECV_BUFFER_LEN};
+
+/// The approximate memory of a DTLS stream's buffers and state.
+const DTLS_BYTES: usize = 64 * 1024;
+
+/// The approximate memory held by a new session.
+pub(crate) fn estimate(dtls: bool) -> usize {
+    std::mem::size_of::<Session>()
+        + std::mem::size_of::<SessionKey>()
+        + RECV_BUFFER_LEN
+        + if dtls { DTLS_BYTES } else { 0 }
+}
+
+/// The memory held by the active sessions of a [`SessionMap`], which is
+/// shared between its clones.
+///
+/// [`SessionMap`]: super::SessionMap
+#[derive(Clone, Debug, Default)]
+pub struct SessionMemory(Arc<AtomicUsize>);
+
+impl SessionMemory {
+    /// The approximate memory held by the sessions, in bytes.
+    pub fn bytes(&self) -> usize {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    /// Counts `bytes` of a session's memory towards the total until the
+    /// returned usage is dropped.
+    pub(crate) fn count(&self, bytes: usize) -> Usage {
+        let total = self.0.fetch_add(bytes, Ordering::Relaxed) + bytes;
+        metrics::memory_bytes().set(total as i64);
+        Usage {
+            bytes,
+            total: self.clone(),
+        }
+    }
+}
+
+/// Counts the memory of a session towards its total until dropped.
+#[derive(Debug)]
+pub(crate) struct Usage {
+    bytes: usize,
+    total: SessionMemory,
+}
+
+impl Usage {
+    pub(crate) fn bytes(&self) -> usize {
+        self.bytes
+    }
+}
+
+impl Drop for Usage {
+    fn drop(&mut self) {
+        let total = self.total.0.fetch_sub(self.bytes, Ordering::Relaxed) - self.bytes;
+        metrics::memory_bytes().set(total as i64);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn usage() {
+        let memory = SessionMemory::default();
+        let usage = memory.count(100);
+        assert_eq!(100, usage.bytes());
+        assert_eq!(100, memory.clone().bytes());
+        assert_eq!(0, SessionMemory::default().bytes());
+        drop(usage);
+        assert_eq!(0, memory.bytes());
+        assert!(estimate(true) > estimate(false));
+        assert!(estimate(false) > RECV_BUFFER_LEN);
+    }
+}
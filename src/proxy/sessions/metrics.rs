@@ -14,26 +14,94 @@
  * limitations under the License.
  */
 
+use std::sync::atomic::{AtomicBool, Ordering};
+
 use once_cell::sync::Lazy;
-use prometheus::{Histogram, IntCounter, IntGauge, IntGaugeVec, Opts};
+use prometheus::{Histogram, HistogramVec, IntCounter, IntCounterVec, IntGauge, IntGaugeVec, Opts};
 
-use crate::metrics::{histogram_opts, register};
+use crate::{
+    endpoint::EndpointAddress,
+    metrics::{histogram_opts, register, Direction},
+};
 
-const SUBSYSTEM: &str = "session";
+pub(super) const SUBSYSTEM: &str = "session";
 const ASN_NUMBER_LABEL: &str = "asn";
 const IP_PREFIX_LABEL: &str = "ip_prefix";
+const NAMESPACE_LABEL: &str = "namespace";
+const ENDPOINT_LABEL: &str = "endpoint";
+
+/// Whether metrics are recorded per upstream endpoint, which is off by
+/// default as it adds series for every game server.
+static ENDPOINT_METRICS: AtomicBool = AtomicBool::new(false);
+
+/// Enables the metrics recorded per upstream endpoint.
+pub(crate) fn set_endpoint_metrics(enabled: bool) {
+    ENDPOINT_METRICS.store(enabled, Ordering::Relaxed);
+}
+
+/// Returns the label of `endpoint` if metrics are recorded per endpoint.
+fn endpoint_label(endpoint: &EndpointAddress) -> Option<String> {
+    ENDPOINT_METRICS
+        .load(Ordering::Relaxed)
+        .then(|| endpoint.to_string())
+}
 
-pub(crate) fn active_sessions(asn_number: u16, ip_prefix: &str) -> IntGauge {
+pub(crate) fn active_sessions(asn_number: u16, ip_prefix: &str, namespace: &str) -> IntGauge {
     static ACTIVE_SESSIONS: Lazy<IntGaugeVec> = Lazy::new(|| {
         prometheus::register_int_gauge_vec_with_registry! {
             Opts::new("active", "number of sessions currently active").subsystem(SUBSYSTEM),
-            &[ASN_NUMBER_LABEL, IP_PREFIX_LABEL],
+            &[ASN_NUMBER_LABEL, IP_PREFIX_LABEL, NAMESPACE_LABEL],
+            crate::metrics::registry(),
+        }
+        .unwrap()
+    });
+
+    // The address prefix and ASN of clients are never emitted in aggregation
+    // only mode.
+    ACTIVE_SESSIONS.with_label_values(&[
+        crate::metrics::client_label(&asn_number.to_string()),
+        crate::metrics::client_label(ip_prefix),
+        namespace,
+    ])
+}
+
+pub(crate) fn active_by_failure_domain() -> &'static IntGaugeVec {
+    static ACTIVE_BY_FAILURE_DOMAIN: Lazy<IntGaugeVec> = Lazy::new(|| {
+        prometheus::register_int_gauge_vec_with_registry! {
+            Opts::new("active_by_failure_domain", "number of sessions currently active, per cluster and locality of their endpoint").subsystem(SUBSYSTEM),
+            &["cluster", "region", "zone", "sub_zone"],
+            crate::metrics::registry(),
+        }
+        .unwrap()
+    });
+
+    &ACTIVE_BY_FAILURE_DOMAIN
+}
+
+pub(crate) fn namespace_packets_total(direction: Direction, namespace: &str) -> IntCounter {
+    static PACKETS_TOTAL: Lazy<IntCounterVec> = Lazy::new(|| {
+        prometheus::register_int_counter_vec_with_registry! {
+            Opts::new("namespace_packets_total", "total number of packets sent through sessions, per namespace").subsystem(SUBSYSTEM),
+            &[Direction::LABEL, NAMESPACE_LABEL],
+            crate::metrics::registry(),
+        }
+        .unwrap()
+    });
+
+    PACKETS_TOTAL.with_label_values(&[direction.label(), namespace])
+}
+
+pub(crate) fn namespace_bytes_total(direction: Direction, namespace: &str) -> IntCounter {
+    static BYTES_TOTAL: Lazy<IntCounterVec> = Lazy::new(|| {
+        prometheus::register_int_counter_vec_with_registry! {
+            Opts::new("namespace_bytes_total", "total number of bytes sent through sessions, per namespace").subsystem(SUBSYSTEM),
+            &[Direction::LABEL, NAMESPACE_LABEL],
             crate::metrics::registry(),
         }
         .unwrap()
     });
 
-    ACTIVE_SESSIONS.with_label_values(&[&asn_number.to_string(), ip_prefix])
+    BYTES_TOTAL.with_label_values(&[direction.label(), namespace])
 }
 
 pub(crate) fn total_sessions() -> &'static IntCounter {
@@ -66,3 +134,360 @@ pub(crate) fn duration_secs() -> &'static Histogram {
 
     &DURATION_SECS
 }
+
+pub(crate) fn pacing_delayed_total() -> &'static IntCounter {
+    static PACING_DELAYED_TOTAL: Lazy<IntCounter> = Lazy::new(|| {
+        register(
+            IntCounter::with_opts(
+                Opts::new(
+                    "pacing_delayed_total",
+                    "total number of packets delayed by pacing before being sent downstream",
+                )
+                .subsystem(SUBSYSTEM),
+            )
+            .unwrap(),
+        )
+    });
+
+    &PACING_DELAYED_TOTAL
+}
+
+pub(crate) fn pacing_dropped_total() -> &'static IntCounter {
+    static PACING_DROPPED_TOTAL: Lazy<IntCounter> = Lazy::new(|| {
+        register(
+            IntCounter::with_opts(
+                Opts::new(
+                    "pacing_dropped_total",
+                    "total number of packets dropped because pacing would have delayed them for too long",
+                )
+                .subsystem(SUBSYSTEM),
+            )
+            .unwrap(),
+        )
+    });
+
+    &PACING_DROPPED_TOTAL
+}
+
+pub(crate) fn pacing_delay_seconds() -> &'static Histogram {
+    static PACING_DELAY_SECONDS: Lazy<Histogram> = Lazy::new(|| {
+        register(
+            Histogram::with_opts(histogram_opts(
+                "pacing_delay_seconds",
+                SUBSYSTEM,
+                "how long packets were delayed by pacing before being sent downstream",
+                vec![0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0],
+            ))
+            .unwrap(),
+        )
+    });
+
+    &PACING_DELAY_SECONDS
+}
+
+pub(crate) fn upstream_batch_size() -> &'static Histogram {
+    static UPSTREAM_BATCH_SIZE: Lazy<Histogram> = Lazy::new(|| {
+        register(
+            Histogram::with_opts(histogram_opts(
+                "upstream_batch_size",
+                SUBSYSTEM,
+                "number of packets sent upstream together in each batch",
+                vec![1.0, 2.0, 4.0, 8.0, 16.0, 32.0, 64.0],
+            ))
+            .unwrap(),
+        )
+    });
+
+    &UPSTREAM_BATCH_SIZE
+}
+
+pub(crate) fn max_sessions_rejected_total(cluster: &str) -> IntCounter {
+    static MAX_SESSIONS_REJECTED_TOTAL: Lazy<IntCounterVec> = Lazy::new(|| {
+        prometheus::register_int_counter_vec_with_registry! {
+            Opts::new("max_sessions_rejected_total", "total number of sessions not created because their cluster had reached its maximum number of sessions").subsystem(SUBSYSTEM),
+            &["cluster"],
+            crate::metrics::registry(),
+        }
+        .unwrap()
+    });
+
+    MAX_SESSIONS_REJECTED_TOTAL.with_label_values(&[cluster])
+}
+
+pub(crate) fn failed_over_total(cluster: &str) -> IntCounter {
+    static FAILED_OVER_TOTAL: Lazy<IntCounterVec> = Lazy::new(|| {
+        prometheus::register_int_counter_vec_with_registry! {
+            Opts::new("failed_over_total", "total number of sessions sent to another locality because their cluster's local endpoints were at capacity").subsystem(SUBSYSTEM),
+            &["cluster"],
+            crate::metrics::registry(),
+        }
+        .unwrap()
+    });
+
+    FAILED_OVER_TOTAL.with_label_values(&[cluster])
+}
+
+pub(crate) fn socket_recycled_total(cluster: &str) -> IntCounter {
+    static SOCKET_RECYCLED_TOTAL: Lazy<IntCounterVec> = Lazy::new(|| {
+        prometheus::register_int_counter_vec_with_registry! {
+            Opts::new("socket_recycled_total", "total number of upstream sockets replaced because they reached their cluster's maximum socket age").subsystem(SUBSYSTEM),
+            &["cluster"],
+            crate::metrics::registry(),
+        }
+        .unwrap()
+    });
+
+    SOCKET_RECYCLED_TOTAL.with_label_values(&[cluster])
+}
+
+pub(crate) fn socket_recycle_failed_total(cluster: &str) -> IntCounter {
+    static SOCKET_RECYCLE_FAILED_TOTAL: Lazy<IntCounterVec> = Lazy::new(|| {
+        prometheus::register_int_counter_vec_with_registry! {
+            Opts::new("socket_recycle_failed_total", "total number of upstream sockets kept past their cluster's maximum socket age because their replacement couldn't be established").subsystem(SUBSYSTEM),
+            &["cluster"],
+            crate::metrics::registry(),
+        }
+        .unwrap()
+    });
+
+    SOCKET_RECYCLE_FAILED_TOTAL.with_label_values(&[cluster])
+}
+
+pub(crate) fn memory_bytes() -> &'static IntGauge {
+    static MEMORY_BYTES: Lazy<IntGauge> = Lazy::new(|| {
+        register(
+            IntGauge::with_opts(
+                Opts::new(
+                    "memory_bytes",
+                    "approximate memory held by the active sessions, in bytes",
+                )
+                .subsystem(SUBSYSTEM),
+            )
+            .unwrap(),
+        )
+    });
+
+    &MEMORY_BYTES
+}
+
+pub(crate) fn memory_evicted_total() -> &'static IntCounter {
+    static MEMORY_EVICTED_TOTAL: Lazy<IntCounter> = Lazy::new(|| {
+        register(
+            IntCounter::with_opts(
+                Opts::new(
+                    "memory_evicted_total",
+                    "total number of sessions closed to keep the memory held by sessions under its limit",
+                )
+                .subsystem(SUBSYSTEM),
+            )
+            .unwrap(),
+        )
+    });
+
+    &MEMORY_EVICTED_TOTAL
+}
+
+pub(crate) fn capacity_rejected_total() -> &'static IntCounter {
+    static CAPACITY_REJECTED_TOTAL: Lazy<IntCounter> = Lazy::new(|| {
+        register(
+            IntCounter::with_opts(
+                Opts::new(
+                    "capacity_rejected_total",
+                    "total number of sessions not created because the proxy had reached its maximum number of sessions",
+                )
+                .subsystem(SUBSYSTEM),
+            )
+            .unwrap(),
+        )
+    });
+
+    &CAPACITY_REJECTED_TOTAL
+}
+
+pub(crate) fn capacity_evicted_total() -> &'static IntCounter {
+    static CAPACITY_EVICTED_TOTAL: Lazy<IntCounter> = Lazy::new(|| {
+        register(
+            IntCounter::with_opts(
+                Opts::new(
+                    "capacity_evicted_total",
+                    "total number of sessions closed to make room for new sessions once the proxy had reached its maximum number of sessions",
+                )
+                .subsystem(SUBSYSTEM),
+            )
+            .unwrap(),
+        )
+    });
+
+    &CAPACITY_EVICTED_TOTAL
+}
+
+static ENDPOINT_PACKETS_TOTAL: Lazy<IntCounterVec> = Lazy::new(|| {
+    prometheus::register_int_counter_vec_with_registry! {
+        Opts::new("endpoint_packets_total", "total number of packets sent through sessions, per upstream endpoint").subsystem(SUBSYSTEM),
+        &[Direction::LABEL, ENDPOINT_LABEL],
+        crate::metrics::registry(),
+    }
+    .unwrap()
+});
+
+static ENDPOINT_BYTES_TOTAL: Lazy<IntCounterVec> = Lazy::new(|| {
+    prometheus::register_int_counter_vec_with_registry! {
+        Opts::new("endpoint_bytes_total", "total number of bytes sent through sessions, per upstream endpoint").subsystem(SUBSYSTEM),
+        &[Direction::LABEL, ENDPOINT_LABEL],
+        crate::metrics::registry(),
+    }
+    .unwrap()
+});
+
+static ENDPOINT_ACTIVE: Lazy<IntGaugeVec> = Lazy::new(|| {
+    prometheus::register_int_gauge_vec_with_registry! {
+        Opts::new("endpoint_active", "number of sessions currently active, per upstream endpoint").subsystem(SUBSYSTEM),
+        &[ENDPOINT_LABEL],
+        crate::metrics::registry(),
+    }
+    .unwrap()
+});
+
+static ENDPOINT_DURATION_SECS: Lazy<HistogramVec> = Lazy::new(|| {
+    register(
+        HistogramVec::new(
+            histogram_opts(
+                "endpoint_duration_secs",
+                SUBSYSTEM,
+                "duration of sessions, per upstream endpoint",
+                vec![
+                    1f64, 5f64, 10f64, 25f64, 60f64, 300f64, 900f64, 1800f64, 3600f64,
+                ],
+            ),
+            &[ENDPOINT_LABEL],
+        )
+        .unwrap(),
+    )
+});
+
+static ENDPOINT_PROCESSING_TIME: Lazy<HistogramVec> = Lazy::new(|| {
+    register(
+        HistogramVec::new(
+            histogram_opts(
+                "endpoint_processing_duration_seconds",
+                SUBSYSTEM,
+                "total processing time for a packet, per upstream endpoint",
+                prometheus::exponential_buckets(
+                    crate::metrics::BUCKET_START,
+                    crate::metrics::BUCKET_FACTOR,
+                    crate::metrics::BUCKET_COUNT,
+                )
+                .unwrap(),
+            ),
+            &[Direction::LABEL, ENDPOINT_LABEL],
+        )
+        .unwrap(),
+    )
+});
+
+/// Counts a packet of `size` bytes sent through a session to or from
+/// `endpoint`.
+pub(crate) fn endpoint_packet(direction: Direction, endpoint: &EndpointAddress, size: usize) {
+    let Some(endpoint) = endpoint_label(endpoint) else {
+        return;
+    };
+
+    let labels = [direction.label(), &*endpoint];
+    ENDPOINT_PACKETS_TOTAL.with_label_values(&labels).inc();
+    ENDPOINT_BYTES_TOTAL
+        .with_label_values(&labels)
+        .inc_by(size as u64);
+}
+
+/// Records how long a packet to or from `endpoint` took to process, in
+/// seconds.
+pub(crate) fn endpoint_processing_time(
+    direction: Direction,
+    endpoint: &EndpointAddress,
+    seconds: f64,
+) {
+    if let Some(endpoint) = endpoint_label(endpoint) {
+        ENDPOINT_PROCESSING_TIME
+            .with_label_values(&[direction.label(), &*endpoint])
+            .observe(seconds);
+    }
+}
+
+/// Counts a new session to `endpoint`.
+pub(crate) fn endpoint_session_started(endpoint: &EndpointAddress) {
+    if let Some(endpoint) = endpoint_label(endpoint) {
+        ENDPOINT_ACTIVE.with_label_values(&[&*endpoint]).inc();
+    }
+}
+
+/// Counts a session to `endpoint` that lasted `seconds` as closed.
+pub(crate) fn endpoint_session_ended(endpoint: &EndpointAddress, seconds: f64) {
+    if let Some(endpoint) = endpoint_label(endpoint) {
+        ENDPOINT_ACTIVE.with_label_values(&[&*endpoint]).dec();
+        ENDPOINT_DURATION_SECS
+            .with_label_values(&[&*endpoint])
+            .observe(seconds);
+    }
+}
+
+/// Removes the series of an endpoint that was removed from its cluster, so
+/// that the metrics of replaced game servers don't linger.
+pub(crate) fn remove_endpoint(endpoint: &EndpointAddress) {
+    let Some(endpoint) = endpoint_label(endpoint) else {
+        return;
+    };
+
+    for direction in [crate::metrics::READ, crate::metrics::WRITE] {
+        let labels = [direction.label(), &*endpoint];
+        let _ = ENDPOINT_PACKETS_TOTAL.remove_label_values(&labels);
+        let _ = ENDPOINT_BYTES_TOTAL.remove_label_values(&labels);
+        let _ = ENDPOINT_PROCESSING_TIME.remove_label_values(&labels);
+    }
+    let _ = ENDPOINT_ACTIVE.remove_label_values(&[&*endpoint]);
+    let _ = ENDPOINT_DURATION_SECS.remove_label_values(&[&*endpoint]);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn endpoint_metrics() {
+        let endpoint: EndpointAddress = (std::net::Ipv4Addr::LOCALHOST, 9917).into();
+        let label = endpoint.to_string();
+        let packets = || {
+            ENDPOINT_PACKETS_TOTAL
+                .with_label_values(&[crate::metrics::READ.label(), &*label])
+                .get()
+        };
+
+        endpoint_packet(crate::metrics::READ, &endpoint, 10);
+        assert_eq!(0, packets());
+
+        set_endpoint_metrics(true);
+        endpoint_packet(crate::metrics::READ, &endpoint, 10);
+        endpoint_packet(crate::metrics::READ, &endpoint, 5);
+        endpoint_session_started(&endpoint);
+        assert_eq!(2, packets());
+        assert_eq!(
+            15,
+            ENDPOINT_BYTES_TOTAL
+                .with_label_values(&[crate::metrics::READ.label(), &*label])
+                .get()
+        );
+        assert_eq!(1, ENDPOINT_ACTIVE.with_label_values(&[&*label]).get());
+
+        endpoint_session_ended(&endpoint, 1.0);
+        assert_eq!(0, ENDPOINT_ACTIVE.with_label_values(&[&*label]).get());
+        assert_eq!(
+            1,
+            ENDPOINT_DURATION_SECS
+                .with_label_values(&[&*label])
+                .get_sample_count()
+        );
+
+        remove_endpoint(&endpoint);
+        assert_eq!(0, packets());
+        set_endpoint_metrics(false);
+    }
+}
@@ -0,0 +1,112 @@
+/*
+ * Copyright 2023 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Pacing of the packets sessions send back to their clients, for clusters
+//! configured with [`Pacing`].
+
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+use crate::cluster::Pacing;
+
+/// The reason pacing drops a packet, in `packets_dropped_total`.
+pub(crate) const PACING_EXCEEDED_REASON: &str = "PacingExceeded";
+
+/// A token bucket of bytes, owned by the task sending a session's packets
+/// downstream.
+#[derive(Debug)]
+pub(crate) struct Pacer {
+    rate: f64,
+    burst: f64,
+    max_delay: Duration,
+    /// Negative while packets that were delayed are waiting to be sent.
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl Pacer {
+    pub(crate) fn new(pacing: &Pacing) -> Self {
+        let burst = pacing.burst_bytes.unwrap_or(pacing.bytes_per_second) as f64;
+        Self {
+            rate: pacing.bytes_per_second as f64,
+            burst,
+            max_delay: Duration::from_millis(pacing.max_delay_ms),
+            tokens: burst,
+            last_refill: Instant::now(),
+        }
+    }
+
+    /// Returns how long to wait before sending a packet of `size` bytes, or
+    /// `None` if it would have to wait longer than the maximum delay and
+    /// should be dropped.
+    pub(crate) fn reserve(&mut self, size: usize) -> Option<Duration> {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.burst);
+        self.last_refill = now;
+
+        let size = size as f64;
+        let delay = if self.tokens >= size {
+            Duration::ZERO
+        } else if self.rate > 0.0 {
+            Duration::from_secs_f64((size - self.tokens) / self.rate)
+        } else {
+            return None;
+        };
+
+        if delay > self.max_delay {
+            return None;
+        }
+
+        self.tokens -= size;
+        Some(delay)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The delay of a reservation, rounded to milliseconds.
+    fn millis(delay: Option<Duration>) -> Option<u64> {
+        delay.map(|delay| (delay.as_secs_f64() * 1000.0).round() as u64)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn reserve() {
+        let mut pacer = Pacer::new(&Pacing {
+            bytes_per_second: 1000,
+            burst_bytes: Some(500),
+            max_delay_ms: 200,
+        });
+
+        assert_eq!(Some(0), millis(pacer.reserve(500)));
+        assert_eq!(Some(100), millis(pacer.reserve(100)));
+        assert_eq!(Some(200), millis(pacer.reserve(100)));
+        // Waiting 300ms would exceed the maximum delay.
+        assert_eq!(None, millis(pacer.reserve(100)));
+
+        tokio::time::advance(Duration::from_millis(200)).await;
+        assert_eq!(Some(0), millis(pacer.reserve(0)));
+        assert_eq!(Some(100), millis(pacer.reserve(100)));
+
+        // The bucket never holds more than the burst.
+        tokio::time::advance(Duration::from_secs(10)).await;
+        assert_eq!(Some(0), millis(pacer.reserve(500)));
+        assert_eq!(Some(1), millis(pacer.reserve(1)));
+    }
+}
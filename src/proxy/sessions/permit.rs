@@ -0,0 +1,110 @@
+/*
+ * Copyright 2023 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! The number of sessions to each cluster, for clusters limiting them with
+//! [`SessionSettings::max_sessions`][crate::cluster::SessionSettings], and to
+//! the local endpoints of clusters with
+//! [`Failover`][crate::cluster::Failover].
+
+use std::sync::Arc;
+
+use dashmap::DashMap;
+use once_cell::sync::Lazy;
+
+/// The number of sessions to each cluster, keyed by the cluster's name.
+static SESSIONS: Lazy<DashMap<Arc<str>, u32>> = Lazy::new(<_>::default);
+/// The number of sessions to the local endpoints of each cluster with
+/// failover, keyed by the cluster's name.
+static LOCAL_SESSIONS: Lazy<DashMap<Arc<str>, u32>> = Lazy::new(<_>::default);
+
+/// A session's place in its cluster's count, released when dropped.
+#[derive(Debug)]
+pub(crate) struct Permit {
+    sessions: &'static DashMap<Arc<str>, u32>,
+    cluster: Arc<str>,
+}
+
+impl Permit {
+    /// Counts a new session to `cluster`, unless it already has `max`
+    /// sessions.
+    pub(crate) fn try_acquire(cluster: &Arc<str>, max: Option<u32>) -> Option<Self> {
+        Self::try_acquire_in(&SESSIONS, cluster, max)
+    }
+
+    /// Counts a new session to one of `cluster`'s local endpoints, towards
+    /// its failover capacity.
+    pub(crate) fn acquire_local(cluster: &Arc<str>) -> Self {
+        Self::try_acquire_in(&LOCAL_SESSIONS, cluster, None).unwrap()
+    }
+
+    /// The number of sessions to `cluster`'s local endpoints.
+    pub(crate) fn local_sessions(cluster: &str) -> u32 {
+        LOCAL_SESSIONS.get(cluster).map_or(0, |sessions| *sessions)
+    }
+
+    fn try_acquire_in(
+        sessions: &'static DashMap<Arc<str>, u32>,
+        cluster: &Arc<str>,
+        max: Option<u32>,
+    ) -> Option<Self> {
+        let mut count = sessions.entry(cluster.clone()).or_default();
+        if max.map_or(false, |max| *count >= max) {
+            return None;
+        }
+
+        *count += 1;
+        Some(Self {
+            sessions,
+            cluster: cluster.clone(),
+        })
+    }
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        self.sessions.remove_if_mut(&self.cluster, |_, sessions| {
+            *sessions -= 1;
+            *sessions == 0
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn try_acquire() {
+        let cluster = Arc::<str>::from("permit-test");
+
+        let first = Permit::try_acquire(&cluster, Some(2)).unwrap();
+        let second = Permit::try_acquire(&cluster, Some(2)).unwrap();
+        assert!(Permit::try_acquire(&cluster, Some(2)).is_none());
+        assert!(Permit::try_acquire(&cluster, None).is_some());
+
+        drop(first);
+        let third = Permit::try_acquire(&cluster, Some(2)).unwrap();
+
+        drop((second, third));
+        assert!(!SESSIONS.contains_key(&cluster));
+
+        let local = Permit::acquire_local(&cluster);
+        assert_eq!(1, Permit::local_sessions(&cluster));
+        assert!(!SESSIONS.contains_key(&cluster));
+        drop(local);
+        assert_eq!(0, Permit::local_sessions(&cluster));
+    }
+}
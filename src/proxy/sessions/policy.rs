@@ -0,0 +1,153 @@
+/*
+ * Copyright 2023 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! How long the proxy's sessions last, and how many of them it holds at
+//! once, so that a flood of spoofed sources can't fill the session table.
+
+use std::time::Duration;
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+/// How long sessions last by default.
+pub const DEFAULT_TTL: Duration = Duration::from_secs(60);
+/// How often expired sessions are closed by default.
+pub const DEFAULT_EXPIRY_POLL_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How long sessions last, and how many of them the proxy holds at once.
+/// Every field is optional, falling back to its default, and is overridden
+/// by the matching `quilkin proxy` flag.
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq, Eq, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct SessionPolicy {
+    /// How long a session lasts, 60 seconds by default. Overridden by the
+    /// `idle_timeout_ms` of the session's cluster, if it has one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ttl_secs: Option<u64>,
+    /// How often expired sessions are closed, 60 seconds by default, so a
+    /// session can last up to this much longer than its TTL. Changes are
+    /// only applied on restart.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expiry_poll_interval_secs: Option<u64>,
+    /// Whether a session's TTL counts from its latest packet or from its
+    /// creation, `idle` by default.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expiry: Option<Expiry>,
+    /// The most sessions the proxy holds at once, across every cluster,
+    /// unlimited if unset.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_sessions: Option<usize>,
+    /// What happens to a new session once there are `max_sessions`,
+    /// `reject` by default.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub eviction: Option<Eviction>,
+}
+
+/// When a session's TTL starts counting.
+#[derive(
+    Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq, Eq, JsonSchema, clap::ValueEnum,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum Expiry {
+    /// The session expires its TTL after the latest packet from its client.
+    #[default]
+    Idle,
+    /// The session expires its TTL after it was created, however active it
+    /// is, so that clients have to reconnect periodically.
+    Absolute,
+}
+
+/// What to do with a new session when the proxy already holds its maximum
+/// number of sessions.
+#[derive(
+    Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq, Eq, JsonSchema, clap::ValueEnum,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum Eviction {
+    /// Drops the new session's packet, keeping the existing sessions.
+    #[default]
+    Reject,
+    /// Closes the session closest to expiring, which is the least recently
+    /// active one unless expiry is `absolute`, to make room.
+    LeastRecentlyActive,
+}
+
+impl SessionPolicy {
+    /// Returns the fields set in `self`, falling back to those of `other`.
+    pub fn or(&self, other: &Self) -> Self {
+        Self {
+            ttl_secs: self.ttl_secs.or(other.ttl_secs),
+            expiry_poll_interval_secs: self
+                .expiry_poll_interval_secs
+                .or(other.expiry_poll_interval_secs),
+            expiry: self.expiry.or(other.expiry),
+            max_sessions: self.max_sessions.or(other.max_sessions),
+            eviction: self.eviction.or(other.eviction),
+        }
+    }
+
+    pub fn ttl(&self) -> Duration {
+        self.ttl_secs.map_or(DEFAULT_TTL, Duration::from_secs)
+    }
+
+    pub fn expiry_poll_interval(&self) -> Duration {
+        self.expiry_poll_interval_secs
+            .map_or(DEFAULT_EXPIRY_POLL_INTERVAL, Duration::from_secs)
+            .max(Duration::from_secs(1))
+    }
+
+    pub fn expiry(&self) -> Expiry {
+        self.expiry.unwrap_or_default()
+    }
+
+    pub fn eviction(&self) -> Eviction {
+        self.eviction.unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn or() {
+        let flags = SessionPolicy {
+            ttl_secs: Some(30),
+            eviction: Some(Eviction::LeastRecentlyActive),
+            ..<_>::default()
+        };
+        let config: SessionPolicy = serde_yaml::from_str(
+            "
+ttl_secs: 120
+expiry: absolute
+max_sessions: 10000
+",
+        )
+        .unwrap();
+
+        let policy = flags.or(&config);
+        assert_eq!(Duration::from_secs(30), policy.ttl());
+        assert_eq!(Expiry::Absolute, policy.expiry());
+        assert_eq!(Some(10000), policy.max_sessions);
+        assert_eq!(Eviction::LeastRecentlyActive, policy.eviction());
+        assert_eq!(DEFAULT_EXPIRY_POLL_INTERVAL, policy.expiry_poll_interval());
+
+        let default = SessionPolicy::default();
+        assert_eq!(DEFAULT_TTL, default.ttl());
+        assert_eq!(Expiry::Idle, default.expiry());
+        assert_eq!(Eviction::Reject, default.eviction());
+    }
+}
@@ -0,0 +1,263 @@
+/*
+ * Copyright 2023 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Session pre-establishment, where a matchmaker tells the proxy which
+//! endpoint a player's token is about to be routed to, so that the upstream
+//! socket, and the DTLS handshake for clusters using DTLS, are ready before
+//! the player's first packet arrives.
+//!
+//! The player's source address isn't known until that packet, so the
+//! connection is kept under its token and endpoint, and taken by the first
+//! session created for a packet carrying the token to the endpoint.
+
+use std::{collections::HashMap, sync::Arc, time::Duration};
+
+use arc_swap::ArcSwapOption;
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use prometheus::IntCounterVec;
+use tokio::time::Instant;
+
+use super::Connection;
+use crate::{
+    endpoint::EndpointAddress,
+    metadata::{DynamicMetadata, Key},
+    Config, SocketConfig,
+};
+
+static PREWARMER: Lazy<ArcSwapOption<Prewarmer>> = Lazy::new(<_>::default);
+
+fn prewarmed_total(outcome: &str) -> prometheus::IntCounter {
+    static PREWARMED_TOTAL: Lazy<IntCounterVec> = Lazy::new(|| {
+        crate::metrics::register(
+            IntCounterVec::new(
+                crate::metrics::opts(
+                    "prewarmed_total",
+                    super::metrics::SUBSYSTEM,
+                    "Total number of pre-established sessions. Labels: outcome",
+                ),
+                &["outcome"],
+            )
+            .unwrap(),
+        )
+    });
+
+    PREWARMED_TOTAL.with_label_values(&[outcome])
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum PrewarmError {
+    #[error("session pre-establishment isn't enabled")]
+    Disabled,
+    #[error("`{0}` isn't the address of any endpoint")]
+    UnknownEndpoint(EndpointAddress),
+    #[error("the token isn't routed to `{0}`")]
+    NotRouted(EndpointAddress),
+    #[error("there are already {0} pre-established sessions")]
+    Full(usize),
+    #[error("failed to connect to the endpoint: {0}")]
+    Connect(#[from] std::io::Error),
+}
+
+/// Installs the sessions pre-established for the proxy, replacing any
+/// existing ones.
+pub(crate) fn install(prewarmer: Option<Prewarmer>) {
+    PREWARMER.store(prewarmer.map(Arc::new));
+}
+
+/// Pre-establishes a session to `endpoint` for the player with `token`.
+pub(crate) async fn prewarm(token: Vec<u8>, endpoint: EndpointAddress) -> Result<(), PrewarmError> {
+    let prewarmer = PREWARMER.load_full().ok_or(PrewarmError::Disabled)?;
+    let result = prewarmer.prewarm(token, endpoint).await;
+    prewarmed_total(match &result {
+        Ok(()) => "created",
+        Err(_) => "rejected",
+    })
+    .inc();
+    result
+}
+
+/// Returns the token of a packet with `metadata`, if any session is waiting
+/// to be taken, so that packets aren't copying tokens when there's nothing to
+/// take.
+pub(crate) fn token(metadata: &DynamicMetadata) -> Option<Vec<u8>> {
+    let prewarmer = PREWARMER.load();
+    let prewarmer = prewarmer.as_ref()?;
+    if prewarmer.pending.lock().is_empty() {
+        return None;
+    }
+
+    metadata
+        .get(&prewarmer.metadata_key)?
+        .as_bytes()
+        .map(|token| token.to_vec())
+}
+
+/// Takes the connection pre-established for `token` to `endpoint`, if it
+/// hasn't expired.
+pub(crate) fn take(token: &[u8], endpoint: &EndpointAddress) -> Option<Connection> {
+    let prewarmer = PREWARMER.load_full()?;
+    let pending = prewarmer
+        .pending
+        .lock()
+        .remove(&(token.to_vec(), endpoint.clone()))?;
+    if pending.expires_at <= Instant::now() {
+        prewarmed_total("expired").inc();
+        return None;
+    }
+
+    prewarmed_total("taken").inc();
+    Some(pending.connection)
+}
+
+struct Pending {
+    connection: Connection,
+    expires_at: Instant,
+}
+
+/// The connections pre-established for expected players, keyed by their
+/// token and endpoint.
+pub(crate) struct Prewarmer {
+    config: Arc<Config>,
+    socket_config: Arc<SocketConfig>,
+    /// The metadata key holding a packet's token, as captured by a filter.
+    metadata_key: Key,
+    /// How long a connection waits for its player.
+    ttl: Duration,
+    max_pending: usize,
+    pending: Mutex<HashMap<(Vec<u8>, EndpointAddress), Pending>>,
+}
+
+impl Prewarmer {
+    pub(crate) fn new(
+        config: Arc<Config>,
+        socket_config: Arc<SocketConfig>,
+        metadata_key: Key,
+        ttl: Duration,
+        max_pending: usize,
+    ) -> Self {
+        Self {
+            config,
+            socket_config,
+            metadata_key,
+            ttl,
+            max_pending,
+            pending: <_>::default(),
+        }
+    }
+
+    async fn prewarm(&self, token: Vec<u8>, endpoint: EndpointAddress) -> Result<(), PrewarmError> {
+        {
+            let clusters = self.config.clusters.load();
+            let found = clusters
+                .endpoints()
+                .find(|found| found.address == endpoint)
+                .ok_or_else(|| PrewarmError::UnknownEndpoint(endpoint.clone()))?;
+            let tokens = &found.metadata.known.tokens;
+            if !tokens.is_empty() && !crate::codec::routes_to(tokens, &token) {
+                return Err(PrewarmError::NotRouted(endpoint));
+            }
+        }
+
+        self.remove_expired();
+        if self.pending.lock().len() >= self.max_pending {
+            return Err(PrewarmError::Full(self.max_pending));
+        }
+
+        let connection =
+            Connection::establish(&self.config, &self.socket_config, &endpoint).await?;
+        tracing::debug!(%endpoint, "pre-established session");
+        self.pending.lock().insert(
+            (token, endpoint),
+            Pending {
+                connection,
+                expires_at: Instant::now() + self.ttl,
+            },
+        );
+        Ok(())
+    }
+
+    fn remove_expired(&self) {
+        let now = Instant::now();
+        let mut pending = self.pending.lock();
+        let before = pending.len();
+        pending.retain(|_, pending| pending.expires_at > now);
+        let expired = before - pending.len();
+        if expired > 0 {
+            prewarmed_total("expired").inc_by(expired as u64);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{cluster::ClusterMap, endpoint::Endpoint, metadata::Value};
+
+    #[tokio::test]
+    async fn prewarm_and_take() {
+        let mut t = crate::test_utils::TestHelper::default();
+        let address = t.run_echo_server().await;
+        let mut endpoint = Endpoint::new(address.clone());
+        endpoint.metadata.known.tokens.insert(b"abc".to_vec());
+
+        let config = Arc::new(Config::default());
+        config
+            .clusters
+            .store(Arc::new(ClusterMap::new_with_default_cluster(vec![
+                endpoint,
+            ])));
+
+        assert!(matches!(
+            prewarm(b"abc".to_vec(), address.clone()).await,
+            Err(PrewarmError::Disabled)
+        ));
+
+        install(Some(Prewarmer::new(
+            config,
+            <_>::default(),
+            Key::from_static(crate::filters::metadata::CAPTURED_BYTES),
+            Duration::from_secs(60),
+            1,
+        )));
+        let unknown = EndpointAddress::from((std::net::Ipv4Addr::LOCALHOST, 1));
+        assert!(matches!(
+            prewarm(b"abc".to_vec(), unknown).await,
+            Err(PrewarmError::UnknownEndpoint(_))
+        ));
+        assert!(matches!(
+            prewarm(b"xyz".to_vec(), address.clone()).await,
+            Err(PrewarmError::NotRouted(_))
+        ));
+
+        let mut metadata = DynamicMetadata::new();
+        metadata.insert(
+            Key::from_static(crate::filters::metadata::CAPTURED_BYTES),
+            Value::Bytes(b"abc".to_vec().into()),
+        );
+        assert_eq!(None, token(&metadata));
+
+        prewarm(b"abc".to_vec(), address.clone()).await.unwrap();
+        assert!(matches!(
+            prewarm(b"abc".to_vec(), address.clone()).await,
+            Err(PrewarmError::Full(1))
+        ));
+        assert_eq!(Some(b"abc".to_vec()), token(&metadata));
+        assert!(take(b"abc", &address).is_some());
+        assert!(take(b"abc", &address).is_none());
+        install(None);
+    }
+}
@@ -0,0 +1,170 @@
+/*
+ * Copyright 2023 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! The background tasks of a proxy, such as its workers, session receive
+//! loops and xDS streams, which are cancelled and waited for when the proxy
+//! shuts down, so that none of them outlive [`Proxy::run`].
+//!
+//! [`Proxy::run`]: crate::cli::Proxy::run
+
+use std::{collections::BTreeMap, future::Future, sync::Arc, time::Duration};
+
+use futures::FutureExt;
+use parking_lot::Mutex;
+use tokio::{sync::watch, task::JoinSet};
+
+/// The tasks spawned by a proxy, cloned into every part of the proxy that
+/// spawns long lived tasks.
+#[derive(Clone, Default)]
+pub struct Tasks(Arc<Inner>);
+
+struct Inner {
+    set: Mutex<Option<JoinSet<()>>>,
+    /// The names of the tasks that haven't stopped yet, by their id.
+    running: Arc<Mutex<BTreeMap<u64, &'static str>>>,
+    next_id: std::sync::atomic::AtomicU64,
+    cancel: watch::Sender<()>,
+}
+
+impl Default for Inner {
+    fn default() -> Self {
+        Self {
+            set: Mutex::new(Some(JoinSet::new())),
+            running: <_>::default(),
+            next_id: <_>::default(),
+            cancel: watch::channel(()).0,
+        }
+    }
+}
+
+/// Removes a task from the running tasks once its future is dropped, whether
+/// it finished, panicked or was cancelled.
+struct Running {
+    id: u64,
+    running: Arc<Mutex<BTreeMap<u64, &'static str>>>,
+}
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        let name = self.running.lock().remove(&self.id);
+        if std::thread::panicking() {
+            tracing::error!(task = name, "task panicked");
+        }
+    }
+}
+
+impl Tasks {
+    /// Spawns `future` as a task called `name`, which is cancelled at its
+    /// next `.await` once the proxy shuts down. Tasks spawned after shutdown
+    /// has started are never run.
+    pub fn spawn(&self, name: &'static str, future: impl Future<Output = ()> + Send + 'static) {
+        let mut set = self.0.set.lock();
+        let Some(set) = set.as_mut() else {
+            tracing::debug!(task = name, "not spawning task, shutting down");
+            return;
+        };
+
+        // Finished tasks are kept in the set until they're joined, so they're
+        // removed here rather than only on shutdown.
+        while let Some(Some(_)) = set.join_next().now_or_never() {}
+
+        let id = self
+            .0
+            .next_id
+            .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        self.0.running.lock().insert(id, name);
+        let running = Running {
+            id,
+            running: self.0.running.clone(),
+        };
+        let mut cancel = self.0.cancel.subscribe();
+        set.spawn(async move {
+            let _running = running;
+            tokio::select! {
+                _ = future => {}
+                _ = cancel.changed() => {}
+            }
+        });
+    }
+
+    /// The number of tasks that haven't stopped yet.
+    pub fn len(&self) -> usize {
+        self.0.running.lock().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Cancels every task, and waits up to `deadline` for them to stop before
+    /// aborting the rest. Returns the number of tasks that failed to stop in
+    /// time by their name, which are logged.
+    pub async fn shutdown(&self, deadline: Duration) -> BTreeMap<&'static str, usize> {
+        let Some(mut set) = self.0.set.lock().take() else {
+            return <_>::default();
+        };
+        self.0.cancel.send(()).ok();
+
+        let stopped =
+            tokio::time::timeout(deadline, async { while set.join_next().await.is_some() {} })
+                .await
+                .is_ok();
+
+        let mut stuck = BTreeMap::new();
+        if !stopped {
+            for name in self.0.running.lock().values() {
+                *stuck.entry(*name).or_default() += 1;
+            }
+            for (task, count) in &stuck {
+                tracing::warn!(task, count, ?deadline, "task failed to stop, aborting");
+            }
+        }
+
+        // Tasks stuck outside of an `.await` can't be aborted, they're
+        // detached instead by dropping the set.
+        set.abort_all();
+        stuck
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn shutdown() {
+        let tasks = Tasks::default();
+        tasks.spawn("finishes", async {});
+        tasks.spawn("cancelled", std::future::pending());
+        let (started_tx, started_rx) = tokio::sync::oneshot::channel();
+        let (stopped_tx, stopped_rx) = tokio::sync::oneshot::channel::<()>();
+        tasks.spawn("stuck", async move {
+            let _stopped = stopped_tx;
+            started_tx.send(()).ok();
+            // Blocks the worker thread, so it can't be cancelled.
+            std::thread::sleep(Duration::from_millis(300));
+        });
+        started_rx.await.unwrap();
+
+        let stuck = tasks.shutdown(Duration::from_millis(50)).await;
+        assert_eq!(BTreeMap::from([("stuck", 1)]), stuck);
+        stopped_rx.await.ok();
+
+        tasks.spawn("late", std::future::pending());
+        assert!(!tasks.0.running.lock().values().any(|name| *name == "late"));
+        assert!(tasks.shutdown(Duration::ZERO).await.is_empty());
+    }
+}
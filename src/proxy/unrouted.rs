@@ -0,0 +1,402 @@
+/*
+ * Copyright 2023 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! What the proxy does with packets that no endpoint matches, either because
+//! there are no endpoints or because the filter chain left none.
+
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use prometheus::{IntCounter, IntCounterVec};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    codec::rejection::{self, Reason, Rejection},
+    filters::Dropped,
+};
+
+const DEFAULT_MAX_BUFFERED_PACKETS: usize = 1024;
+const DEFAULT_MAX_REJECTIONS_PER_SECOND: u32 = 100;
+
+/// The number of packets currently buffered by [`UnroutedPolicy::Buffer`].
+static BUFFERED: AtomicUsize = AtomicUsize::new(0);
+
+pub(crate) fn packets_unrouted_total(action: &str) -> IntCounter {
+    static PACKETS_UNROUTED: Lazy<IntCounterVec> = Lazy::new(|| {
+        prometheus::register_int_counter_vec_with_registry! {
+            prometheus::opts! {
+                "packets_unrouted_total",
+                "Total number of packets that no endpoint matched. Labels: action",
+            },
+            &["action"],
+            crate::metrics::registry(),
+        }
+        .unwrap()
+    });
+
+    PACKETS_UNROUTED.with_label_values(&[action])
+}
+
+pub(crate) fn packets_buffered_total(result: &str) -> IntCounter {
+    static PACKETS_BUFFERED: Lazy<IntCounterVec> = Lazy::new(|| {
+        prometheus::register_int_counter_vec_with_registry! {
+            prometheus::opts! {
+                "unrouted_packets_buffered_total",
+                "Total number of unrouted packets held waiting for a configuration update. Labels: result",
+            },
+            &["result"],
+            crate::metrics::registry(),
+        }
+        .unwrap()
+    });
+
+    PACKETS_BUFFERED.with_label_values(&[result])
+}
+
+pub(crate) fn rejections_total(reason: Reason) -> IntCounter {
+    static REJECTIONS: Lazy<IntCounterVec> = Lazy::new(|| {
+        prometheus::register_int_counter_vec_with_registry! {
+            prometheus::opts! {
+                "unrouted_rejections_total",
+                "Total number of signed rejections sent to clients whose packets weren't routed. Labels: reason",
+            },
+            &["reason"],
+            crate::metrics::registry(),
+        }
+        .unwrap()
+    });
+
+    REJECTIONS.with_label_values(&[match reason {
+        Reason::NoEndpoint => "no_endpoint",
+        Reason::InvalidToken => "invalid_token",
+        Reason::Draining => "draining",
+        Reason::Blocked => "blocked",
+        Reason::RateLimited => "rate_limited",
+        Reason::Overloaded => "overloaded",
+        Reason::Filtered => "filtered",
+    }])
+}
+
+pub(crate) fn rejections_limited_total() -> &'static IntCounter {
+    static REJECTIONS_LIMITED: Lazy<IntCounter> = Lazy::new(|| {
+        crate::metrics::register(
+            IntCounter::with_opts(prometheus::opts! {
+                "unrouted_rejections_limited_total",
+                "Total number of rejections that weren't sent, as `max_per_second` rejections were already sent that second",
+            })
+            .unwrap(),
+        )
+    });
+
+    &REJECTIONS_LIMITED
+}
+
+pub(crate) fn responses_suppressed_total(reason: &str) -> IntCounter {
+    static RESPONSES_SUPPRESSED: Lazy<IntCounterVec> = Lazy::new(|| {
+        prometheus::register_int_counter_vec_with_registry! {
+            prometheus::opts! {
+                "unrouted_responses_suppressed_total",
+                "Total number of responses the `respond` action didn't send. Labels: reason",
+            },
+            &["reason"],
+            crate::metrics::registry(),
+        }
+        .unwrap()
+    });
+
+    RESPONSES_SUPPRESSED.with_label_values(&[reason])
+}
+
+/// What to do with packets that no endpoint matches.
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq, Eq, JsonSchema)]
+#[serde(tag = "action", rename_all = "snake_case", deny_unknown_fields)]
+pub enum UnroutedPolicy {
+    /// Drops the packet.
+    #[default]
+    Drop,
+    /// Sends `payload` back to the client, so that it can tell that it
+    /// wasn't routed rather than waiting for a response. As with
+    /// [`UnroutedPolicy::Reject`], packets shorter than `payload` aren't
+    /// answered, and at most `max_per_second` responses are sent each second,
+    /// so the proxy can't be used to amplify or reflect traffic at spoofed
+    /// sources.
+    Respond {
+        #[serde(with = "crate::config::Base64Standard")]
+        #[schemars(with = "String")]
+        payload: Vec<u8>,
+        #[serde(default = "default_max_rejections_per_second")]
+        max_per_second: u32,
+    },
+    /// Sends the packet to every endpoint of `cluster` instead.
+    Fallback { cluster: String },
+    /// Holds the packet for up to `timeout_ms`, routing it again whenever the
+    /// clusters change, e.g. while waiting for a management server to send
+    /// the endpoints of a new game server. At most `max_packets` packets are
+    /// held at once, further packets are dropped.
+    Buffer {
+        timeout_ms: u64,
+        #[serde(default = "default_max_buffered_packets")]
+        max_packets: usize,
+    },
+    /// Sends the client a [rejection] signed with `key`, the 32 byte
+    /// Ed25519 private key, saying why its packet wasn't routed. Unlike the
+    /// other actions, this also applies to packets dropped by the filter
+    /// chain. Packets shorter than a rejection aren't answered, so the proxy
+    /// can't be used to amplify traffic, and at most `max_per_second`
+    /// rejections are sent each second, so it can't be used to reflect
+    /// traffic at spoofed sources either.
+    Reject {
+        #[serde(with = "crate::config::Base64Standard")]
+        #[schemars(with = "String")]
+        key: Vec<u8>,
+        #[serde(default = "default_max_rejections_per_second")]
+        max_per_second: u32,
+    },
+}
+
+fn default_max_buffered_packets() -> usize {
+    DEFAULT_MAX_BUFFERED_PACKETS
+}
+
+fn default_max_rejections_per_second() -> u32 {
+    DEFAULT_MAX_REJECTIONS_PER_SECOND
+}
+
+impl UnroutedPolicy {
+    /// The `action` label of the policy in metrics.
+    pub(crate) fn action(&self) -> &'static str {
+        match self {
+            Self::Drop => "drop",
+            Self::Respond { .. } => "respond",
+            Self::Fallback { .. } => "fallback",
+            Self::Buffer { .. } => "buffer",
+            Self::Reject { .. } => "reject",
+        }
+    }
+}
+
+/// The reason a rejection gives for a packet the filter chain dropped.
+pub(crate) fn dropped_reason(dropped: Option<&Dropped>) -> Reason {
+    match dropped {
+        Some(Dropped::Filter(name)) => match &**name {
+            "quilkin.filters.token_router.v1alpha1.TokenRouter" => Reason::InvalidToken,
+            "quilkin.filters.firewall.v1alpha1.Firewall"
+            | "quilkin.filters.block_list.v1alpha1.BlockList" => Reason::Blocked,
+            "quilkin.filters.local_rate_limit.v1alpha1.LocalRateLimit"
+            | "quilkin.filters.rate_limit.v1alpha1.RateLimit" => Reason::RateLimited,
+            _ => Reason::Filtered,
+        },
+        Some(Dropped::BudgetExceeded) => Reason::Overloaded,
+        Some(Dropped::ReprocessLimit) | None => Reason::Filtered,
+    }
+}
+
+/// Returns a rejection for `reason`, sent now and signed with the Ed25519
+/// private `key`.
+pub(crate) fn sign_rejection(
+    key: &[u8],
+    reason: Reason,
+) -> Result<Vec<u8>, openssl::error::ErrorStack> {
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let rejection = Rejection::new(reason, timestamp);
+
+    let key = openssl::pkey::PKey::private_key_from_raw_bytes(key, openssl::pkey::Id::ED25519)?;
+    let mut signer = openssl::sign::Signer::new_without_digest(&key)?;
+    let mut signature = [0; rejection::SIGNATURE_LEN];
+    signature.copy_from_slice(&signer.sign_oneshot_to_vec(&rejection.signed_bytes())?);
+
+    Ok(rejection.encode(&signature))
+}
+
+/// Limits the rejections sent each second, shared by its clones.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct RejectionLimit(Arc<Mutex<Window>>);
+
+#[derive(Debug, Default)]
+struct Window {
+    start: Option<tokio::time::Instant>,
+    sent: u32,
+}
+
+impl RejectionLimit {
+    /// Takes one of the `max_per_second` rejections of the current second,
+    /// returning whether there was one left.
+    pub(crate) fn admit(&self, max_per_second: u32) -> bool {
+        let now = tokio::time::Instant::now();
+        let mut window = self.0.lock();
+        if window.start.map_or(true, |start| {
+            now.duration_since(start) >= Duration::from_secs(1)
+        }) {
+            window.start = Some(now);
+            window.sent = 0;
+        }
+        if window.sent >= max_per_second {
+            return false;
+        }
+
+        window.sent += 1;
+        true
+    }
+}
+
+/// A packet held by [`UnroutedPolicy::Buffer`], releasing its place in the
+/// buffer when dropped.
+pub(crate) struct Buffered {
+    pub timeout: Duration,
+}
+
+impl Buffered {
+    /// Reserves a place in the buffer, unless `max_packets` are already
+    /// buffered.
+    pub(crate) fn try_new(timeout_ms: u64, max_packets: usize) -> Option<Self> {
+        BUFFERED
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |buffered| {
+                (buffered < max_packets).then_some(buffered + 1)
+            })
+            .ok()
+            .map(|_| Self {
+                timeout: Duration::from_millis(timeout_ms),
+            })
+    }
+}
+
+impl Drop for Buffered {
+    fn drop(&mut self) {
+        BUFFERED.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        let policy: UnroutedPolicy = serde_yaml::from_str(
+            "
+action: respond
+payload: bm8gcm91dGU=
+",
+        )
+        .unwrap();
+        assert_eq!(
+            UnroutedPolicy::Respond {
+                payload: b"no route".to_vec(),
+                max_per_second: DEFAULT_MAX_REJECTIONS_PER_SECOND,
+            },
+            policy
+        );
+
+        let policy: UnroutedPolicy =
+            serde_yaml::from_str("{ action: buffer, timeout_ms: 500 }").unwrap();
+        assert_eq!(
+            UnroutedPolicy::Buffer {
+                timeout_ms: 500,
+                max_packets: DEFAULT_MAX_BUFFERED_PACKETS,
+            },
+            policy
+        );
+        assert!(serde_yaml::from_str::<UnroutedPolicy>("action: retry").is_err());
+
+        let policy: UnroutedPolicy = serde_yaml::from_str("{ action: reject, key: a2V5 }").unwrap();
+        assert_eq!(
+            UnroutedPolicy::Reject {
+                key: b"key".to_vec(),
+                max_per_second: DEFAULT_MAX_REJECTIONS_PER_SECOND,
+            },
+            policy
+        );
+    }
+
+    #[test]
+    fn signed_rejection() {
+        let key = openssl::pkey::PKey::generate_ed25519().unwrap();
+        let private_key = key.raw_private_key().unwrap();
+        let packet = sign_rejection(&private_key, Reason::InvalidToken).unwrap();
+        let (rejection, signature) = Rejection::decode(&packet).unwrap();
+        assert_eq!(Reason::InvalidToken, rejection.reason);
+
+        // Clients only need the public key to verify the rejection.
+        let public_key = openssl::pkey::PKey::public_key_from_raw_bytes(
+            &key.raw_public_key().unwrap(),
+            openssl::pkey::Id::ED25519,
+        )
+        .unwrap();
+        let mut verifier = openssl::sign::Verifier::new_without_digest(&public_key).unwrap();
+        assert!(verifier
+            .verify_oneshot(&signature, &rejection.signed_bytes())
+            .unwrap());
+
+        assert!(sign_rejection(b"not an ed25519 key", Reason::InvalidToken).is_err());
+    }
+
+    #[test]
+    fn dropped_reasons() {
+        let filter = |name: &str| Some(Dropped::Filter(name.into()));
+        assert_eq!(
+            Reason::InvalidToken,
+            dropped_reason(filter("quilkin.filters.token_router.v1alpha1.TokenRouter").as_ref())
+        );
+        assert_eq!(
+            Reason::Blocked,
+            dropped_reason(filter("quilkin.filters.firewall.v1alpha1.Firewall").as_ref())
+        );
+        assert_eq!(
+            Reason::RateLimited,
+            dropped_reason(
+                filter("quilkin.filters.local_rate_limit.v1alpha1.LocalRateLimit").as_ref()
+            )
+        );
+        assert_eq!(
+            Reason::Filtered,
+            dropped_reason(filter("quilkin.filters.drop.v1alpha1.Drop").as_ref())
+        );
+        assert_eq!(
+            Reason::Overloaded,
+            dropped_reason(Some(&Dropped::BudgetExceeded))
+        );
+    }
+
+    #[tokio::test]
+    async fn rejection_limit() {
+        tokio::time::pause();
+        let limit = RejectionLimit::default();
+        assert!(limit.admit(2));
+        assert!(limit.clone().admit(2));
+        assert!(!limit.admit(2));
+
+        tokio::time::advance(Duration::from_secs(1)).await;
+        assert!(limit.admit(2));
+    }
+
+    #[test]
+    fn buffer_limit() {
+        let first = Buffered::try_new(10, BUFFERED.load(Ordering::Acquire) + 1).unwrap();
+        assert!(Buffered::try_new(10, BUFFERED.load(Ordering::Acquire)).is_none());
+        drop(first);
+    }
+}
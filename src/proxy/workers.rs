@@ -0,0 +1,323 @@
+/*
+ * Copyright 2023 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Scales the number of downstream workers with the proxy's load, so that
+//! small deployments don't keep a socket and a task per CPU on idle hosts.
+//!
+//! Workers are added while they're busy, measured by the CPU used per worker
+//! and by the number of packets being processed at once, and removed once
+//! they've been mostly idle for a while. Adding or removing a socket makes
+//! the kernel move clients between the `SO_REUSEPORT` sockets, so workers
+//! look up sessions in the other workers' shards before creating new ones,
+//! while the sessions of a removed worker are handed off to the remaining
+//! workers, replying from their sockets once the removed one is closed.
+
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use once_cell::sync::Lazy;
+use tokio::{net::UdpSocket, sync::watch};
+
+use super::SessionMap;
+use crate::utils::idle::IdleBackoff;
+
+/// How often the workers' load is measured.
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(5);
+/// The number of consecutive idle samples before a worker is removed.
+const SCALE_DOWN_SAMPLES: u32 = 12;
+
+/// The packets currently being processed, across every worker.
+static IN_FLIGHT: AtomicUsize = AtomicUsize::new(0);
+/// The most packets processed at once since the last sample.
+static PEAK_IN_FLIGHT: AtomicUsize = AtomicUsize::new(0);
+
+static WORKERS: Lazy<prometheus::IntGauge> = Lazy::new(|| {
+    crate::metrics::register(
+        prometheus::IntGauge::with_opts(crate::metrics::opts(
+            "workers",
+            "downstream",
+            "The number of workers receiving packets from downstream",
+        ))
+        .unwrap(),
+    )
+});
+
+/// Counts a packet as being processed until dropped.
+pub(crate) struct InFlight(());
+
+impl InFlight {
+    pub(crate) fn start() -> Self {
+        let in_flight = IN_FLIGHT.fetch_add(1, Ordering::Relaxed) + 1;
+        PEAK_IN_FLIGHT.fetch_max(in_flight, Ordering::Relaxed);
+        crate::utils::idle::activity();
+        Self(())
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        IN_FLIGHT.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// The bounds and targets the number of workers is scaled within.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct Scaling {
+    pub min: usize,
+    pub max: usize,
+    /// The fraction of a CPU each worker should use, above which workers are
+    /// added.
+    pub target_cpu: f64,
+    /// The number of packets each worker should be processing at once, above
+    /// which workers are added.
+    pub target_queue_depth: usize,
+    /// The longest time between samples while the workers are idle and at
+    /// `min`. A packet ends the wait early.
+    pub max_idle_interval: Duration,
+}
+
+/// The load of the workers over a sample interval.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+struct Sample {
+    /// The fraction of a CPU used by the proxy, `None` where it isn't
+    /// available.
+    cpu: Option<f64>,
+    /// The most packets processed at once.
+    queue_depth: usize,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Decision {
+    Add,
+    Remove,
+    Keep,
+}
+
+/// Decides whether to add or remove workers from successive samples.
+struct Scaler {
+    scaling: Scaling,
+    idle_samples: u32,
+}
+
+impl Scaler {
+    fn new(scaling: Scaling) -> Self {
+        Self {
+            scaling,
+            idle_samples: 0,
+        }
+    }
+
+    /// Decides how to scale `workers` workers under the load of `sample`. A
+    /// worker is only removed when the remaining workers would still be at
+    /// less than half of their targets, and have been for
+    /// [`SCALE_DOWN_SAMPLES`] samples.
+    fn decide(&mut self, workers: usize, sample: Sample) -> Decision {
+        let Scaling {
+            min,
+            max,
+            target_cpu,
+            target_queue_depth,
+            ..
+        } = self.scaling;
+        let per_worker = |workers: usize| {
+            let workers = workers.max(1) as f64;
+            (
+                sample.cpu.map_or(0.0, |cpu| cpu / workers),
+                sample.queue_depth as f64 / workers,
+            )
+        };
+
+        let (cpu, queue_depth) = per_worker(workers);
+        if cpu > target_cpu || queue_depth > target_queue_depth as f64 {
+            self.idle_samples = 0;
+            return if workers < max {
+                Decision::Add
+            } else {
+                Decision::Keep
+            };
+        }
+
+        let (cpu, queue_depth) = per_worker(workers.saturating_sub(1));
+        if workers <= min || cpu > target_cpu / 2.0 || queue_depth > target_queue_depth as f64 / 2.0
+        {
+            self.idle_samples = 0;
+            return Decision::Keep;
+        }
+
+        self.idle_samples += 1;
+        if self.idle_samples < SCALE_DOWN_SAMPLES {
+            return Decision::Keep;
+        }
+
+        self.idle_samples = 0;
+        Decision::Remove
+    }
+}
+
+/// Starts `scaling.min` workers with `spawn_worker`, which is passed the id
+/// of the worker and the channel stopping it, and returns the worker's
+/// socket, and a task adding and removing workers with their load until
+/// `shutdown_rx` changes.
+pub(crate) fn spawn(
+    scaling: Scaling,
+    sessions: SessionMap,
+    mut shutdown_rx: watch::Receiver<()>,
+    spawn_worker: impl Fn(usize, watch::Receiver<()>) -> crate::Result<Arc<UdpSocket>> + Send + 'static,
+) -> crate::Result<()> {
+    let start = move |workers: &mut Vec<(watch::Sender<()>, Arc<UdpSocket>)>| -> crate::Result<()> {
+        let (stop_tx, stop_rx) = watch::channel(());
+        let socket = spawn_worker(workers.len(), stop_rx)?;
+        workers.push((stop_tx, socket));
+        WORKERS.set(workers.len() as i64);
+        Ok(())
+    };
+
+    let mut workers = Vec::with_capacity(scaling.max);
+    for _ in 0..scaling.min.max(1) {
+        start(&mut workers)?;
+    }
+
+    let tasks = sessions.tasks().clone();
+    tasks.spawn("worker scaler", async move {
+        let mut scaler = Scaler::new(scaling);
+        let mut backoff = IdleBackoff::new(SAMPLE_INTERVAL, scaling.max_idle_interval);
+
+        let mut at = Instant::now();
+        let mut cpu_seconds = crate::admin::autoscale::cpu_seconds();
+        PEAK_IN_FLIGHT.store(0, Ordering::Relaxed);
+        loop {
+            tokio::select! {
+                _ = backoff.sleep() => {}
+                // Dropping the workers' channels stops them.
+                _ = shutdown_rx.changed() => return,
+            }
+
+            let now = Instant::now();
+            let current_cpu_seconds = crate::admin::autoscale::cpu_seconds();
+            let elapsed = (now - at).as_secs_f64();
+            let sample = Sample {
+                cpu: current_cpu_seconds
+                    .zip(cpu_seconds)
+                    .filter(|_| elapsed > 0.0)
+                    .map(|(current, previous)| (current - previous) / elapsed),
+                queue_depth: PEAK_IN_FLIGHT
+                    .swap(IN_FLIGHT.load(Ordering::Relaxed), Ordering::Relaxed),
+            };
+            at = now;
+            cpu_seconds = current_cpu_seconds;
+
+            match scaler.decide(workers.len(), sample) {
+                Decision::Add => {
+                    if let Err(error) = start(&mut workers) {
+                        tracing::warn!(%error, "failed to add a worker");
+                        continue;
+                    }
+                    tracing::info!(workers = workers.len(), ?sample, "added a worker");
+                }
+                Decision::Remove => {
+                    workers.pop();
+                    let worker_id = workers.len();
+                    let sockets = workers
+                        .iter()
+                        .map(|(_, socket)| socket.clone())
+                        .collect::<Vec<_>>();
+                    let handed_off = sessions.hand_off(worker_id, &sockets);
+                    WORKERS.set(workers.len() as i64);
+                    tracing::info!(
+                        workers = workers.len(),
+                        handed_off,
+                        ?sample,
+                        "removed a worker"
+                    );
+                }
+                Decision::Keep => {}
+            }
+
+            // Idle workers at their minimum have nothing to scale, so they're
+            // sampled less often until traffic returns.
+            backoff.next(sample.queue_depth == 0 && workers.len() <= scaling.min);
+        }
+    });
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SCALING: Scaling = Scaling {
+        min: 1,
+        max: 4,
+        target_cpu: 0.5,
+        target_queue_depth: 10,
+        max_idle_interval: SAMPLE_INTERVAL,
+    };
+
+    fn sample(cpu: f64, queue_depth: usize) -> Sample {
+        Sample {
+            cpu: Some(cpu),
+            queue_depth,
+        }
+    }
+
+    #[test]
+    fn adds_busy_workers() {
+        let mut scaler = Scaler::new(SCALING);
+        assert_eq!(Decision::Add, scaler.decide(1, sample(0.6, 0)));
+        assert_eq!(Decision::Add, scaler.decide(2, sample(0.1, 21)));
+        assert_eq!(Decision::Keep, scaler.decide(2, sample(0.9, 0)));
+        assert_eq!(Decision::Keep, scaler.decide(4, sample(4.0, 100)));
+    }
+
+    #[test]
+    fn removes_idle_workers() {
+        let mut scaler = Scaler::new(SCALING);
+        for _ in 1..SCALE_DOWN_SAMPLES {
+            assert_eq!(Decision::Keep, scaler.decide(3, sample(0.2, 4)));
+        }
+        assert_eq!(Decision::Remove, scaler.decide(3, sample(0.2, 4)));
+
+        // The remaining workers would be above half their target.
+        for _ in 0..SCALE_DOWN_SAMPLES {
+            assert_eq!(Decision::Keep, scaler.decide(2, sample(0.3, 0)));
+        }
+
+        // A busy sample restarts the count.
+        for _ in 1..SCALE_DOWN_SAMPLES {
+            scaler.decide(2, sample(0.1, 0));
+        }
+        scaler.decide(2, sample(0.1, 30));
+        assert_eq!(Decision::Keep, scaler.decide(2, sample(0.1, 0)));
+
+        for _ in 0..SCALE_DOWN_SAMPLES {
+            assert_eq!(Decision::Keep, scaler.decide(1, sample(0.0, 0)));
+        }
+    }
+
+    #[test]
+    fn in_flight() {
+        let first = InFlight::start();
+        let second = InFlight::start();
+        assert!(PEAK_IN_FLIGHT.load(Ordering::Relaxed) >= 2);
+        drop((first, second));
+    }
+}
@@ -0,0 +1,363 @@
+/*
+ * Copyright 2023 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Packet rate and bandwidth quotas for traffic sent to clusters.
+
+use std::{collections::HashMap, sync::Arc, time::Instant};
+
+use once_cell::sync::Lazy;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+const QUOTA_LABEL: &str = "quota";
+const KIND_LABEL: &str = "kind";
+
+/// The reason recorded in `packets_dropped_total` for packets exceeding a quota.
+pub(crate) const QUOTA_EXCEEDED_REASON: &str = "QuotaExceeded";
+
+fn quota_exceeded_total(quota: &str, kind: Kind) -> prometheus::IntCounter {
+    static QUOTA_EXCEEDED: Lazy<prometheus::IntCounterVec> = Lazy::new(|| {
+        prometheus::register_int_counter_vec_with_registry! {
+            prometheus::opts! {
+                "quota_exceeded_total",
+                "Total number of packets dropped for exceeding a cluster or namespace quota",
+            },
+            &[QUOTA_LABEL, KIND_LABEL],
+            crate::metrics::registry(),
+        }
+        .unwrap()
+    });
+
+    QUOTA_EXCEEDED.with_label_values(&[quota, kind.label()])
+}
+
+/// The limits on traffic sent towards a cluster or every cluster in a
+/// namespace. Each limit allows bursts of up to one second's worth of traffic.
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq, Eq, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct Quota {
+    /// The maximum number of packets per second.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub packets_per_second: Option<u64>,
+    /// The maximum number of bytes per second.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bytes_per_second: Option<u64>,
+}
+
+/// The type of limit that a packet exceeded.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Kind {
+    Packets,
+    Bytes,
+}
+
+impl Kind {
+    fn label(self) -> &'static str {
+        match self {
+            Self::Packets => "packets",
+            Self::Bytes => "bytes",
+        }
+    }
+}
+
+/// The quota that a packet exceeded.
+#[derive(Debug, PartialEq, Eq, thiserror::Error)]
+#[error("{kind:?} quota for `{quota}` exceeded")]
+pub struct QuotaExceeded {
+    pub quota: String,
+    pub kind: Kind,
+}
+
+/// The set of quotas, keyed by a cluster name or a namespace. Updating the
+/// quotas resets their accounting.
+#[derive(Clone, Debug, Default)]
+pub struct Quotas(HashMap<String, Limiter>);
+
+impl Quotas {
+    /// Accounts for a packet of `size` bytes sent towards `cluster`, checking
+    /// both the quota of the cluster and of the namespace it belongs to. Tokens
+    /// are only taken once every quota admits the packet, so a packet dropped
+    /// by one quota isn't counted against the others.
+    pub fn try_acquire(&self, cluster: &str, size: usize) -> Result<(), QuotaExceeded> {
+        if self.0.is_empty() {
+            return Ok(());
+        }
+
+        // The cluster's buckets are always locked before its namespace's, so
+        // concurrent packets can't lock them in opposite orders.
+        let namespace = crate::cluster::namespace_of(cluster);
+        let mut buckets = Vec::with_capacity(4);
+        for key in std::iter::once(cluster).chain(namespace) {
+            let Some(limiter) = self.0.get(key) else {
+                continue;
+            };
+
+            for (kind, bucket, amount) in limiter.buckets(size) {
+                let state = bucket.refilled();
+                if state.tokens < amount {
+                    quota_exceeded_total(key, kind).inc();
+                    return Err(QuotaExceeded {
+                        quota: key.to_owned(),
+                        kind,
+                    });
+                }
+
+                buckets.push((state, amount));
+            }
+        }
+
+        for (mut state, amount) in buckets {
+            state.tokens -= amount;
+        }
+
+        Ok(())
+    }
+
+    /// Returns the quota for `key`, if present.
+    pub fn get(&self, key: &str) -> Option<&Quota> {
+        self.0.get(key).map(|limiter| &limiter.quota)
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl FromIterator<(String, Quota)> for Quotas {
+    fn from_iter<I: IntoIterator<Item = (String, Quota)>>(iter: I) -> Self {
+        Self(
+            iter.into_iter()
+                .map(|(key, quota)| (key, Limiter::new(quota)))
+                .collect(),
+        )
+    }
+}
+
+impl PartialEq for Quotas {
+    fn eq(&self, rhs: &Self) -> bool {
+        self.0.len() == rhs.0.len()
+            && self
+                .0
+                .iter()
+                .all(|(key, limiter)| rhs.get(key) == Some(&limiter.quota))
+    }
+}
+
+impl Serialize for Quotas {
+    fn serialize<S: serde::Serializer>(&self, ser: S) -> Result<S::Ok, S::Error> {
+        self.0
+            .iter()
+            .map(|(key, limiter)| (key, &limiter.quota))
+            .collect::<std::collections::BTreeMap<_, _>>()
+            .serialize(ser)
+    }
+}
+
+impl<'de> Deserialize<'de> for Quotas {
+    fn deserialize<D: serde::Deserializer<'de>>(de: D) -> Result<Self, D::Error> {
+        Ok(HashMap::<String, Quota>::deserialize(de)?
+            .into_iter()
+            .collect())
+    }
+}
+
+impl JsonSchema for Quotas {
+    fn schema_name() -> String {
+        <HashMap<String, Quota>>::schema_name()
+    }
+
+    fn json_schema(gen: &mut schemars::gen::SchemaGenerator) -> schemars::schema::Schema {
+        <HashMap<String, Quota>>::json_schema(gen)
+    }
+
+    fn is_referenceable() -> bool {
+        <HashMap<String, Quota>>::is_referenceable()
+    }
+}
+
+/// The runtime accounting for a single [`Quota`].
+#[derive(Clone, Debug)]
+struct Limiter {
+    quota: Quota,
+    packets: Option<Arc<TokenBucket>>,
+    bytes: Option<Arc<TokenBucket>>,
+}
+
+impl Limiter {
+    fn new(quota: Quota) -> Self {
+        Self {
+            packets: quota.packets_per_second.map(TokenBucket::new).map(Arc::new),
+            bytes: quota.bytes_per_second.map(TokenBucket::new).map(Arc::new),
+            quota,
+        }
+    }
+
+    /// Returns the buckets a packet of `size` bytes takes tokens from, along
+    /// with the number of tokens it takes from each.
+    fn buckets(&self, size: usize) -> impl Iterator<Item = (Kind, &TokenBucket, f64)> {
+        let packets = self
+            .packets
+            .as_deref()
+            .map(|bucket| (Kind::Packets, bucket, 1.0));
+        let bytes = self
+            .bytes
+            .as_deref()
+            .map(|bucket| (Kind::Bytes, bucket, size as f64));
+        packets.into_iter().chain(bytes)
+    }
+}
+
+/// A token bucket refilled at `rate` tokens per second, holding at most one
+/// second's worth of tokens.
+#[derive(Debug)]
+struct TokenBucket {
+    rate: f64,
+    state: parking_lot::Mutex<BucketState>,
+}
+
+#[derive(Debug)]
+struct BucketState {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: u64) -> Self {
+        let rate = rate as f64;
+        Self {
+            rate,
+            state: parking_lot::Mutex::new(BucketState {
+                tokens: rate,
+                last_refill: Instant::now(),
+            }),
+        }
+    }
+
+    /// Locks the bucket, adding the tokens accrued since it was last used.
+    fn refilled(&self) -> parking_lot::MutexGuard<'_, BucketState> {
+        let mut state = self.state.lock();
+        let now = Instant::now();
+        let elapsed = now.duration_since(state.last_refill).as_secs_f64();
+        state.tokens = (state.tokens + elapsed * self.rate).min(self.rate);
+        state.last_refill = now;
+        state
+    }
+
+    #[cfg(test)]
+    fn try_take(&self, amount: f64) -> bool {
+        let mut state = self.refilled();
+        if state.tokens >= amount {
+            state.tokens -= amount;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn packets_per_second() {
+        let quotas: Quotas = serde_yaml::from_str(
+            "
+studio/us-east-1:
+  packets_per_second: 2
+",
+        )
+        .unwrap();
+
+        assert!(quotas.try_acquire("studio/us-east-1", 10).is_ok());
+        assert!(quotas.try_acquire("studio/us-east-1", 10).is_ok());
+        assert_eq!(
+            Err(QuotaExceeded {
+                quota: "studio/us-east-1".into(),
+                kind: Kind::Packets,
+            }),
+            quotas.try_acquire("studio/us-east-1", 10)
+        );
+        assert!(quotas.try_acquire("studio/eu-west-1", 10).is_ok());
+    }
+
+    #[test]
+    fn namespace_bytes_per_second() {
+        let quotas: Quotas = serde_yaml::from_str(
+            "
+studio:
+  bytes_per_second: 100
+",
+        )
+        .unwrap();
+
+        assert!(quotas.try_acquire("studio/us-east-1", 60).is_ok());
+        assert_eq!(
+            Err(QuotaExceeded {
+                quota: "studio".into(),
+                kind: Kind::Bytes,
+            }),
+            quotas.try_acquire("studio/eu-west-1", 60)
+        );
+        assert!(quotas.try_acquire("other/eu-west-1", 60).is_ok());
+        assert!(quotas.try_acquire("default", 60).is_ok());
+    }
+
+    #[test]
+    fn rejected_packets_take_no_tokens() {
+        let quotas: Quotas = serde_yaml::from_str(
+            "
+studio/us-east-1:
+  packets_per_second: 1
+studio:
+  bytes_per_second: 100
+",
+        )
+        .unwrap();
+
+        // The namespace rejects the packet, so the cluster keeps its token.
+        assert_eq!(
+            Err(QuotaExceeded {
+                quota: "studio".into(),
+                kind: Kind::Bytes,
+            }),
+            quotas.try_acquire("studio/us-east-1", 200)
+        );
+        assert!(quotas.try_acquire("studio/us-east-1", 60).is_ok());
+        assert_eq!(
+            Err(QuotaExceeded {
+                quota: "studio/us-east-1".into(),
+                kind: Kind::Packets,
+            }),
+            quotas.try_acquire("studio/us-east-1", 10)
+        );
+        // Nor does the namespace lose bytes to the packet the cluster rejected.
+        assert!(quotas.try_acquire("studio/eu-west-1", 40).is_ok());
+    }
+
+    #[test]
+    fn refills() {
+        let bucket = TokenBucket::new(1000);
+        assert!(bucket.try_take(1000.0));
+        assert!(!bucket.try_take(1.0));
+        std::thread::sleep(std::time::Duration::from_millis(20));
+        assert!(bucket.try_take(1.0));
+    }
+}
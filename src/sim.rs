@@ -0,0 +1,284 @@
+/*
+ * Copyright 2023 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! A deterministic runtime for running the full proxy pipeline — workers,
+//! sessions and filters — in simulated time, for reproducible tests of
+//! timeout, expiry and retry logic.
+//!
+//! A [`Scenario`] scripts the packets that simulated clients send at given
+//! points in simulated time, runs the proxy in front of a simulated
+//! upstream, and returns a [`Trace`] of every packet the clients and the
+//! upstream received. Scenarios run on a single threaded runtime whose clock
+//! is paused, so time only advances when every task is waiting on a timer,
+//! and a scenario covering hours of session expiry finishes instantly.
+//!
+//! Simulated clients and the upstream use sockets bound to the loopback
+//! interface, which deliver a packet before the send returns, so packets
+//! arrive at the instant they were sent in simulated time.
+//!
+//! ```no_run
+//! use std::time::Duration;
+//! use quilkin::sim::{Scenario, Simulation};
+//!
+//! let trace = Simulation::new().unwrap().run(
+//!     Scenario::new(quilkin::Config::default())
+//!         .send(Duration::ZERO, 0, "hello")
+//!         .run_for(Duration::from_secs(120)),
+//! );
+//! assert_eq!(1, trace.upstream().count());
+//! ```
+
+use std::{
+    future::Future,
+    net::{Ipv4Addr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+
+use parking_lot::Mutex;
+use tokio::{
+    net::UdpSocket,
+    sync::watch,
+    time::{sleep_until, Instant},
+};
+
+use crate::{endpoint::Endpoint, Config, Proxy};
+
+/// A single threaded runtime with a paused clock, which scenarios run on.
+pub struct Simulation {
+    runtime: tokio::runtime::Runtime,
+}
+
+impl Simulation {
+    pub fn new() -> std::io::Result<Self> {
+        Ok(Self {
+            runtime: tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .start_paused(true)
+                .build()?,
+        })
+    }
+
+    /// Runs `future`, such as [`Scenario::run_for`], to completion in
+    /// simulated time.
+    pub fn run<F: Future>(&self, future: F) -> F::Output {
+        self.runtime.block_on(future)
+    }
+}
+
+/// How the simulated upstream responds to the packets it receives.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Upstream {
+    /// Sends every packet back to the proxy.
+    #[default]
+    Echo,
+    /// Never responds.
+    Silent,
+}
+
+/// A packet sent by a simulated client.
+#[derive(Clone, Debug)]
+struct Scripted {
+    at: Duration,
+    client: usize,
+    payload: Vec<u8>,
+}
+
+/// A script of the packets simulated clients send through the proxy.
+pub struct Scenario {
+    config: Arc<Config>,
+    proxy: Proxy,
+    upstream: Upstream,
+    sends: Vec<Scripted>,
+}
+
+impl Scenario {
+    /// Creates a scenario proxying to a simulated upstream, which is added to
+    /// the default cluster of `config`.
+    pub fn new(config: Config) -> Self {
+        Self {
+            config: Arc::new(config),
+            proxy: Proxy::default(),
+            upstream: Upstream::default(),
+            sends: Vec::new(),
+        }
+    }
+
+    /// Runs the proxy with the flags of `proxy`, other than its port, which
+    /// is always a free port on the loopback interface.
+    pub fn proxy(mut self, proxy: Proxy) -> Self {
+        self.proxy = proxy;
+        self
+    }
+
+    pub fn upstream(mut self, upstream: Upstream) -> Self {
+        self.upstream = upstream;
+        self
+    }
+
+    /// Sends `payload` from the simulated client numbered `client` at `at`
+    /// after the start of the scenario. Each client has its own socket, so
+    /// its own sessions.
+    pub fn send(mut self, at: Duration, client: usize, payload: impl Into<Vec<u8>>) -> Self {
+        self.sends.push(Scripted {
+            at,
+            client,
+            payload: payload.into(),
+        });
+        self
+    }
+
+    /// Runs the scenario for `duration` of simulated time, returning every
+    /// packet received during it.
+    pub async fn run_for(mut self, duration: Duration) -> Trace {
+        let start = Instant::now();
+        let trace = Arc::new(Mutex::new(Vec::new()));
+        let mut tasks = Vec::new();
+
+        let upstream = Arc::new(bind().await);
+        let upstream_address = upstream.local_addr().unwrap();
+        tasks.push(tokio::spawn(receive(
+            upstream,
+            Receiver::Upstream,
+            self.upstream == Upstream::Echo,
+            start,
+            trace.clone(),
+        )));
+        self.config.clusters.modify(|clusters| {
+            clusters
+                .default_cluster_mut()
+                .insert(Endpoint::new(upstream_address.into()))
+        });
+
+        let proxy_address = free_address().await;
+        let (shutdown_tx, shutdown_rx) = watch::channel(());
+        let proxy = Proxy {
+            port: proxy_address.port(),
+            to: Vec::new(),
+            ..self.proxy
+        };
+        let config = self.config.clone();
+        tokio::spawn(async move {
+            if let Err(error) = proxy.run(config, shutdown_rx).await {
+                tracing::error!(%error, "simulated proxy failed");
+            }
+        });
+        // Lets the proxy bind its sockets before any packet is sent.
+        tokio::task::yield_now().await;
+
+        let mut clients = Vec::<Arc<UdpSocket>>::new();
+        self.sends.sort_by_key(|send| send.at);
+        for send in self.sends {
+            while clients.len() <= send.client {
+                let socket = Arc::new(bind().await);
+                tasks.push(tokio::spawn(receive(
+                    socket.clone(),
+                    Receiver::Client(clients.len()),
+                    false,
+                    start,
+                    trace.clone(),
+                )));
+                clients.push(socket);
+            }
+
+            sleep_until(start + send.at).await;
+            if let Err(error) = clients[send.client]
+                .send_to(&send.payload, proxy_address)
+                .await
+            {
+                tracing::warn!(%error, client = send.client, "simulated client failed to send");
+            }
+        }
+
+        sleep_until(start + duration).await;
+        let _ = shutdown_tx.send(());
+        for task in tasks {
+            task.abort();
+        }
+
+        let events = std::mem::take(&mut *trace.lock());
+        Trace { events }
+    }
+}
+
+/// Who received a packet in a [`Trace`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Receiver {
+    /// The simulated client with this number.
+    Client(usize),
+    Upstream,
+}
+
+/// A packet received during a scenario.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Received {
+    /// The simulated time since the start of the scenario.
+    pub at: Duration,
+    pub receiver: Receiver,
+    pub payload: Vec<u8>,
+}
+
+/// Every packet received during a scenario, in the order they were received.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Trace {
+    pub events: Vec<Received>,
+}
+
+impl Trace {
+    /// The packets received by the upstream.
+    pub fn upstream(&self) -> impl Iterator<Item = &Received> {
+        self.events
+            .iter()
+            .filter(|event| event.receiver == Receiver::Upstream)
+    }
+
+    /// The packets received by the simulated client numbered `client`.
+    pub fn client(&self, client: usize) -> impl Iterator<Item = &Received> {
+        self.events
+            .iter()
+            .filter(move |event| event.receiver == Receiver::Client(client))
+    }
+}
+
+async fn bind() -> UdpSocket {
+    UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap()
+}
+
+/// Returns a loopback address that was free when this was called.
+async fn free_address() -> SocketAddr {
+    bind().await.local_addr().unwrap()
+}
+
+async fn receive(
+    socket: Arc<UdpSocket>,
+    receiver: Receiver,
+    echo: bool,
+    start: Instant,
+    trace: Arc<Mutex<Vec<Received>>>,
+) {
+    let mut buf = vec![0; 1 << 16];
+    while let Ok((size, source)) = socket.recv_from(&mut buf).await {
+        trace.lock().push(Received {
+            at: start.elapsed(),
+            receiver,
+            payload: buf[..size].to_vec(),
+        });
+
+        if echo {
+            let _ = socket.send_to(&buf[..size], source).await;
+        }
+    }
+}
@@ -230,11 +230,18 @@ impl TestHelper {
         self.server_shutdown_tx.push(Some(shutdown_tx));
 
         if let Some(address) = with_admin {
-            tokio::spawn(crate::admin::server(
-                crate::admin::Mode::Proxy,
-                config.clone(),
-                address,
-            ));
+            let config = config.clone();
+            tokio::spawn(async move {
+                crate::admin::server(
+                    crate::admin::Mode::Proxy,
+                    config,
+                    address,
+                    false,
+                    <_>::default(),
+                    None,
+                )
+                .await
+            });
         }
 
         tokio::spawn(async move {
@@ -303,6 +310,16 @@ pub fn config_with_dummy_endpoint() -> Config {
     config.clusters.modify(|map| {
         let _ = map.get_default_mut().insert(&mut Cluster {
             name: "default".into(),
+            dtls: None,
+            fwmark: None,
+            normalize: None,
+            upstream_bind: None,
+            pacing: None,
+            pinned: false,
+            sessions: None,
+            duplicate_endpoints: None,
+            failover: None,
+            sampling: None,
             localities: vec![LocalityEndpoints {
                 locality: None,
                 endpoints: [Endpoint {
@@ -310,6 +327,7 @@ pub fn config_with_dummy_endpoint() -> Config {
                     ..<_>::default()
                 }]
                 .into(),
+                weight: None,
             }]
             .into_iter()
             .collect(),
@@ -17,20 +17,18 @@
 use dashmap::mapref::entry::Entry as DashMapEntry;
 use dashmap::mapref::one::{Ref, RefMut};
 use dashmap::DashMap;
-use tracing::warn;
 
 use std::hash::Hash;
 use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
 use std::time::Duration;
-use tokio::sync::oneshot::{channel, Receiver, Sender};
+use tokio::sync::{
+    oneshot::{channel, Receiver, Sender},
+    Notify,
+};
 
 pub use dashmap::try_result::TryResult;
 
-// Clippy isn't recognizing that these imports are used conditionally.
-#[allow(unused_imports)]
-use std::time::{SystemTime, UNIX_EPOCH};
-#[allow(unused_imports)]
 use tokio::time::Instant;
 
 /// A wrapper around the value of an entry in the map.
@@ -38,39 +36,54 @@ use tokio::time::Instant;
 pub struct Value<V> {
     pub value: V,
     expires_at: Arc<AtomicU64>,
+    /// The TTL of this value, if it differs from the map's.
+    ttl: Option<Duration>,
+    /// Whether the value expires its TTL after it was inserted, rather than
+    /// after it was last read.
+    absolute: bool,
     clock: Clock,
 }
 
 impl<V> Value<V> {
     fn new(value: V, ttl: Duration, clock: Clock) -> Value<V> {
+        Self::with_ttl(value, ttl, None, clock)
+    }
+
+    fn with_ttl(value: V, default_ttl: Duration, ttl: Option<Duration>, clock: Clock) -> Value<V> {
         let value = Value {
             value,
             expires_at: Arc::new(AtomicU64::new(0)),
+            ttl,
+            absolute: false,
             clock,
         };
-        value.update_expiration(ttl);
+        value.update_expiration(default_ttl);
         value
     }
 
     /// Get the expiration time for this value. The returned value is the
-    /// number of seconds relative to some reference point (e.g UNIX_EPOCH), based
-    /// on the clock being used.
+    /// number of seconds relative to when the map's clock was created.
     fn expiration_secs(&self) -> u64 {
         self.expires_at.load(Ordering::Relaxed)
     }
 
-    /// Update the value's expiration time to (now + TTL).
-    fn update_expiration(&self, ttl: Duration) {
-        match self.clock.compute_expiration_secs(ttl) {
-            Ok(new_expiration_time) => {
-                self.expires_at
-                    .store(new_expiration_time, Ordering::Relaxed);
-            }
-            Err(err) => {
-                warn!("failed to increment key expiration: {}", err);
-            }
+    /// Updates the value's expiration time like [`Value::update_expiration`]
+    /// when it's read, unless it expires at an absolute time.
+    fn touch(&self, default_ttl: Duration) {
+        if !self.absolute {
+            self.update_expiration(default_ttl);
         }
     }
+
+    /// Update the value's expiration time to (now + TTL), where `default_ttl`
+    /// is used unless the value has its own TTL.
+    fn update_expiration(&self, default_ttl: Duration) {
+        let new_expiration_time = self
+            .clock
+            .compute_expiration_secs(self.ttl.unwrap_or(default_ttl));
+        self.expires_at
+            .store(new_expiration_time, Ordering::Relaxed);
+    }
 }
 
 impl<V> std::ops::Deref for Value<V> {
@@ -87,6 +100,8 @@ struct Map<K, V> {
     ttl: Duration,
     clock: Clock,
     shutdown_tx: Option<Sender<()>>,
+    /// Wakes the cleanup task, which sleeps while the map is empty.
+    inserted: Notify,
 }
 
 impl<K, V> Drop for Map<K, V> {
@@ -101,8 +116,9 @@ impl<K, V> Drop for Map<K, V> {
 /// When the TTL for an entry elapses, the entry is removed from the map.
 /// The TTL is reset each time the entry is (re)inserted or read via [`TtlMap::get`],
 /// [`TtlMap::get_mut`] functions, or via the [`TtlMap::entry`] interface.
-/// During tests, the internal clock implementation is driven by [`tokio::time`] so
-/// functions like [`tokio::time::pause`] and [`tokio::time::advance`] can be used.
+/// The internal clock is driven by [`tokio::time`], so functions like
+/// [`tokio::time::pause`] and [`tokio::time::advance`] can be used, and the map
+/// follows the paused clock of a simulation.
 pub struct TtlMap<K, V>(Arc<Map<K, V>>);
 
 impl<K, V> TtlMap<K, V>
@@ -126,6 +142,7 @@ where
             shutdown_tx: Some(shutdown_tx),
             ttl,
             clock: Clock::new(),
+            inserted: Notify::new(),
         }));
         spawn_cleanup_task(
             map.0.clone(),
@@ -136,11 +153,10 @@ where
         map
     }
 
-    /// Returns the current time as the number of seconds relative to some initial
-    /// reference point (e.g UNIX_EPOCH), based on the clock implementation being used.
-    /// In tests, this will be driven by [`tokio::time`]
+    /// Returns the current time as the number of seconds since the map was
+    /// created, driven by [`tokio::time`].
     pub(crate) fn now_relative_secs(&self) -> u64 {
-        self.0.clock.now_relative_secs().unwrap_or_default()
+        self.0.clock.now_relative_secs()
     }
 }
 
@@ -154,7 +170,7 @@ where
     pub fn get(&self, key: &K) -> Option<Ref<K, Value<V>>> {
         let value = self.0.inner.get(key);
         if let Some(ref value) = value {
-            value.update_expiration(self.0.ttl)
+            value.touch(self.0.ttl)
         }
 
         value
@@ -164,7 +180,7 @@ where
     pub fn try_get(&self, key: &K) -> TryResult<Ref<K, Value<V>>> {
         let value = self.0.inner.try_get(key);
         if let TryResult::Present(ref value) = value {
-            value.update_expiration(self.0.ttl)
+            value.touch(self.0.ttl)
         }
 
         value
@@ -175,7 +191,7 @@ where
     pub fn get_mut(&self, key: &K) -> Option<RefMut<K, Value<V>>> {
         let value = self.0.inner.get_mut(key);
         if let Some(ref value) = value {
-            value.update_expiration(self.0.ttl);
+            value.touch(self.0.ttl);
         }
 
         value
@@ -196,10 +212,67 @@ where
     /// The value will be set to expire at the configured TTL after the time of insertion.
     /// If a previous value existed for this key, that value is returned.
     pub fn insert(&self, key: K, value: V) -> Option<V> {
-        self.0
+        let previous = self
+            .0
             .inner
             .insert(key, Value::new(value, self.0.ttl, self.0.clock.clone()))
-            .map(|value| value.value)
+            .map(|value| value.value);
+        self.0.inserted.notify_one();
+        previous
+    }
+
+    /// Inserts a key-value pair into the map, like [`TtlMap::insert`], except
+    /// that the value expires after `ttl` rather than the map's TTL.
+    pub fn insert_with_ttl(&self, key: K, value: V, ttl: Duration) -> Option<V> {
+        let previous = self
+            .0
+            .inner
+            .insert(
+                key,
+                Value::with_ttl(value, self.0.ttl, Some(ttl), self.0.clock.clone()),
+            )
+            .map(|value| value.value);
+        self.0.inserted.notify_one();
+        previous
+    }
+
+    /// Inserts a key-value pair into the map, like
+    /// [`TtlMap::insert_with_ttl`], except that reading the value doesn't
+    /// reset its TTL, so it expires `ttl` after it was inserted.
+    pub fn insert_absolute(&self, key: K, value: V, ttl: Duration) -> Option<V> {
+        let mut value = Value::with_ttl(value, self.0.ttl, Some(ttl), self.0.clock.clone());
+        value.absolute = true;
+        let previous = self.0.inner.insert(key, value).map(|value| value.value);
+        self.0.inserted.notify_one();
+        previous
+    }
+
+    /// Removes the entry for `key`, returning its value.
+    pub fn remove(&self, key: &K) -> Option<V> {
+        self.0.inner.remove(key).map(|(_, value)| value.value)
+    }
+
+    /// Returns `select` of every entry, along with when the entry expires, in
+    /// the seconds of [`TtlMap::now_relative_secs`].
+    pub(crate) fn expirations<T>(&self, mut select: impl FnMut(&K, &V) -> T) -> Vec<(u64, T)> {
+        self.0
+            .inner
+            .iter()
+            .map(|entry| {
+                (
+                    entry.value().expiration_secs(),
+                    select(entry.key(), &entry.value().value),
+                )
+            })
+            .collect()
+    }
+
+    /// Removes every entry for which `keep` returns `false`, returning the
+    /// number of entries removed.
+    pub fn retain(&self, mut keep: impl FnMut(&K, &V) -> bool) -> usize {
+        let len = self.len();
+        self.0.inner.retain(|key, value| keep(key, &value.value));
+        len.saturating_sub(self.len())
     }
 
     /// Returns an entry for in-place updates of the specified key-value pair.
@@ -217,6 +290,7 @@ where
                 inner,
                 ttl,
                 clock: self.0.clock.clone(),
+                inserted: &self.0.inserted,
             }),
         }
     }
@@ -252,6 +326,7 @@ pub struct VacantEntry<'a, K, V> {
     inner: DashMapEntry<'a, K, V>,
     ttl: Duration,
     clock: Clock,
+    inserted: &'a Notify,
 }
 
 /// A view into an entry in the map.
@@ -271,7 +346,7 @@ where
         match &self.inner {
             DashMapEntry::Occupied(entry) => {
                 let value = entry.get();
-                value.update_expiration(self.ttl);
+                value.touch(self.ttl);
                 value
             }
             _ => unreachable!("BUG: entry type should be occupied"),
@@ -285,7 +360,7 @@ where
         match &mut self.inner {
             DashMapEntry::Occupied(entry) => {
                 let value = entry.get_mut();
-                value.update_expiration(self.ttl);
+                value.touch(self.ttl);
                 value
             }
             _ => unreachable!("BUG: entry type should be occupied"),
@@ -312,12 +387,14 @@ where
     /// Set an entry's value.
     /// The value will be set to expire at the configured TTL after the time of insertion.
     pub fn insert(self, value: V) -> RefMut<'a, K, Value<V>> {
-        match self.inner {
+        let entry = match self.inner {
             DashMapEntry::Vacant(entry) => {
                 entry.insert(Value::new(value, self.ttl, self.clock.clone()))
             }
             _ => unreachable!("BUG: entry type should be vacant"),
-        }
+        };
+        self.inserted.notify_one();
+        entry
     }
 }
 
@@ -334,6 +411,16 @@ fn spawn_cleanup_task<K, V>(
 
     tokio::spawn(async move {
         loop {
+            // An empty map has nothing to expire, so rather than waking every
+            // `poll_interval` the task sleeps until the next insert.
+            if map.inner.is_empty() {
+                tokio::select! {
+                    _ = map.inserted.notified() => interval.reset(),
+                    _ = &mut shutdown_rx => return,
+                }
+                continue;
+            }
+
             tokio::select! {
                 _ = interval.tick() => {
                     prune_entries( &map, &clock).await;
@@ -351,12 +438,7 @@ where
     K: Hash + Eq + Send + Sync + 'static,
     V: Send + Sync + 'static,
 {
-    let now_secs = if let Ok(now_secs) = clock.now_relative_secs() {
-        now_secs
-    } else {
-        warn!("Failed to get current time when pruning sessions");
-        return;
-    };
+    let now_secs = clock.now_relative_secs();
 
     // Take a read lock first and check if there is at least 1 item to remove.
     let has_expired_keys = map
@@ -376,53 +458,30 @@ where
     }
 }
 
-/// A wrapper over functions to generate relative timestamps and ttl.
-/// During test it is driven via [`tokio::time`], otherwise it uses system time.
+/// A wrapper over functions to generate relative timestamps and ttl, driven
+/// by [`tokio::time`] in every configuration, so that a paused clock, as in
+/// tests and simulations, is followed.
 #[derive(Clone)]
 struct Clock {
-    #[cfg(test)]
     base: Instant,
 }
 
 impl Clock {
     fn new() -> Clock {
-        #[cfg(not(test))]
-        return Clock {};
-
-        #[cfg(test)]
-        return Clock {
+        Clock {
             base: Instant::now(),
-        };
+        }
     }
 
-    /// Returns the current time in seconds, relative to some base time instant.
-    /// For non test cases, relative to UNIX_EPOCH, while during test, a random
-    /// point in the past is used.
-    fn now_relative_secs(&self) -> Result<u64, String> {
-        #[cfg(not(test))]
-        return SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .map_err(|_| {
-                String::from("duration_since was called with time later than the current time")
-            })
-            .map(|t| t.as_secs());
-
-        #[cfg(test)]
-        return Ok((Instant::now()).duration_since(self.base).as_secs());
+    /// Returns the current time in seconds, relative to when the clock was
+    /// created.
+    fn now_relative_secs(&self) -> u64 {
+        Instant::now().duration_since(self.base).as_secs()
     }
 
     /// Returns the expiration time from now in seconds for the given ttl.
-    fn compute_expiration_secs(&self, ttl: Duration) -> Result<u64, String> {
-        #[cfg(not(test))]
-        return (SystemTime::now() + ttl)
-            .duration_since(UNIX_EPOCH)
-            .map_err(|_| {
-                String::from("duration_since was called with time later than the current time")
-            })
-            .map(|t| t.as_secs());
-
-        #[cfg(test)]
-        return Ok((Instant::now() + ttl).duration_since(self.base).as_secs());
+    fn compute_expiration_secs(&self, ttl: Duration) -> u64 {
+        (Instant::now() + ttl).duration_since(self.base).as_secs()
     }
 }
 
@@ -497,6 +556,56 @@ mod tests {
         assert_eq!(3, exp3 - exp2);
     }
 
+    #[tokio::test]
+    async fn insert_with_ttl() {
+        time::pause();
+
+        let (one, two) = address_pair();
+
+        let map = TtlMap::<EndpointAddress, usize>::new(
+            Duration::from_secs(10),
+            Duration::from_millis(10),
+        );
+        map.insert(one.clone(), 1);
+        map.insert_with_ttl(two.clone(), 2, Duration::from_secs(30));
+
+        let now = map.now_relative_secs();
+        assert_eq!(10, map.get(&one).unwrap().expiration_secs() - now);
+        assert_eq!(30, map.get(&two).unwrap().expiration_secs() - now);
+
+        // The value keeps its own TTL when it's read.
+        time::advance(Duration::from_secs(5)).await;
+        let now = map.now_relative_secs();
+        assert_eq!(30, map.get(&two).unwrap().expiration_secs() - now);
+    }
+
+    #[tokio::test]
+    async fn insert_absolute() {
+        time::pause();
+
+        let (one, two) = address_pair();
+
+        let map = TtlMap::<EndpointAddress, usize>::new(
+            Duration::from_secs(10),
+            Duration::from_millis(10),
+        );
+        map.insert(one.clone(), 1);
+        map.insert_absolute(two.clone(), 2, Duration::from_secs(30));
+
+        // Reading the value doesn't push back its expiration.
+        let expires_at = map.get(&two).unwrap().expiration_secs();
+        time::advance(Duration::from_secs(5)).await;
+        assert_eq!(expires_at, map.get(&two).unwrap().expiration_secs());
+        assert!(matches!(
+            map.try_get(&two),
+            TryResult::Present(value) if value.expiration_secs() == expires_at
+        ));
+        assert_eq!(expires_at, map.get_mut(&two).unwrap().expiration_secs());
+
+        let now = map.now_relative_secs();
+        assert_eq!(10, map.get(&one).unwrap().expiration_secs() - now);
+    }
+
     #[tokio::test]
     async fn contains_key() {
         let (one, two) = address_pair();
@@ -736,4 +845,30 @@ mod tests {
         assert!(!map.contains_key(&two));
         assert_eq!(map.len(), 0);
     }
+
+    #[tokio::test]
+    async fn cleanup_after_empty() {
+        // Test that entries inserted while the cleanup task sleeps on an
+        // empty map still expire.
+        time::pause();
+
+        let (one, _) = address_pair();
+
+        let map =
+            TtlMap::<EndpointAddress, usize>::new(Duration::from_secs(5), Duration::from_secs(1));
+        time::advance(Duration::from_secs(10)).await;
+
+        match map.entry(one.clone()) {
+            Entry::Vacant(entry) => {
+                entry.insert(1);
+            }
+            _ => unreachable!("expected vacant entry"),
+        }
+        assert!(map.contains_key(&one));
+
+        for _ in 0..7 {
+            time::advance(Duration::from_secs(1)).await;
+        }
+        assert!(!map.contains_key(&one));
+    }
 }
@@ -15,6 +15,8 @@
  */
 
 pub(crate) mod debug;
+pub(crate) mod idle;
+pub(crate) mod mmsg;
 pub(crate) mod net;
 
 /// A type which can be logged, usually error types.
@@ -22,3 +24,37 @@ pub(crate) trait Loggable {
     /// Output a log.
     fn log(&self);
 }
+
+/// Hashes `value` with SipHash-1-3 and fixed keys. Unlike `DefaultHasher`,
+/// whose algorithm may change between Rust releases, the hash of a value
+/// stays the same after an upgrade, so that clients keep their endpoint and
+/// worker.
+pub(crate) fn stable_hash(value: impl std::hash::Hash) -> u64 {
+    use std::hash::Hasher;
+
+    let mut hasher = siphasher::sip::SipHasher13::new_with_keys(0, 0);
+    value.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Compares `a` and `b` in time only dependent on their lengths, so that
+/// secrets such as tokens can't be guessed a byte at a time.
+pub(crate) fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn constant_time_eq() {
+        assert!(super::constant_time_eq(b"abc", b"abc"));
+        assert!(!super::constant_time_eq(b"abc", b"abd"));
+        assert!(!super::constant_time_eq(b"abc", b"abcd"));
+    }
+
+    #[test]
+    fn stable_hash() {
+        // Changing this value moves clients to other endpoints.
+        assert_eq!(5378393838603134665, super::stable_hash(&b"abc"[..]));
+    }
+}
@@ -0,0 +1,124 @@
+/*
+ * Copyright 2023 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Lengthens the sleeps of periodic background tasks while the proxy is
+//! idle, so that an idle proxy barely uses any CPU, and wakes them again as
+//! soon as it receives a packet.
+
+use std::{
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
+
+use once_cell::sync::Lazy;
+use tokio::sync::Notify;
+
+/// Whether any task is sleeping for longer than its interval.
+static PARKED: AtomicBool = AtomicBool::new(false);
+static ACTIVITY: Lazy<Notify> = Lazy::new(Notify::new);
+
+/// Wakes every task sleeping for longer than its interval. Called for every
+/// packet, so it's a single load while no task is parked.
+pub(crate) fn activity() {
+    if PARKED.load(Ordering::Relaxed) && PARKED.swap(false, Ordering::Relaxed) {
+        ACTIVITY.notify_waiters();
+    }
+}
+
+/// The sleeps between runs of a periodic task, which double after every idle
+/// run from the task's interval up to `max`, and go back to the interval
+/// after a busy one.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct IdleBackoff {
+    interval: Duration,
+    max: Duration,
+    current: Duration,
+}
+
+impl IdleBackoff {
+    /// A `max` shorter than `interval` disables the backoff.
+    pub(crate) fn new(interval: Duration, max: Duration) -> Self {
+        Self {
+            interval,
+            max: max.max(interval),
+            current: interval,
+        }
+    }
+
+    /// Returns the sleep before the next run, after a run that was `idle`.
+    pub(crate) fn next(&mut self, idle: bool) -> Duration {
+        self.current = if idle {
+            (self.current * 2).min(self.max)
+        } else {
+            self.interval
+        };
+
+        self.current
+    }
+
+    /// Sleeps until the next run, or until the proxy receives a packet while
+    /// sleeping for longer than the interval.
+    pub(crate) async fn sleep(&self) {
+        if self.current <= self.interval {
+            return tokio::time::sleep(self.current).await;
+        }
+
+        // Created before parking, so that a packet arriving in between
+        // still wakes the task.
+        let activity = ACTIVITY.notified();
+        PARKED.store(true, Ordering::Relaxed);
+        tokio::select! {
+            _ = tokio::time::sleep(self.current) => {}
+            _ = activity => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff() {
+        let second = Duration::from_secs(1);
+        let mut backoff = IdleBackoff::new(second, 5 * second);
+        assert_eq!(2 * second, backoff.next(true));
+        assert_eq!(4 * second, backoff.next(true));
+        assert_eq!(5 * second, backoff.next(true));
+        assert_eq!(5 * second, backoff.next(true));
+        assert_eq!(second, backoff.next(false));
+
+        let mut disabled = IdleBackoff::new(second, Duration::ZERO);
+        assert_eq!(second, disabled.next(true));
+    }
+
+    #[tokio::test]
+    async fn wakes_on_activity() {
+        let mut backoff = IdleBackoff::new(Duration::from_millis(1), Duration::from_secs(60));
+        backoff.next(true);
+        backoff.next(true);
+
+        let sleep = tokio::spawn(async move { backoff.sleep().await });
+        while !PARKED.load(Ordering::Relaxed) {
+            tokio::task::yield_now().await;
+        }
+        activity();
+        tokio::time::timeout(Duration::from_secs(5), sleep)
+            .await
+            .unwrap()
+            .unwrap();
+    }
+}
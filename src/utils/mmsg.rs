@@ -0,0 +1,276 @@
+/*
+ * Copyright 2023 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Batched packet I/O, which sends and receives many packets with a single
+//! `sendmmsg` or `recvmmsg` call on Linux, and falls back to a call per
+//! packet elsewhere, or on kernels without those calls.
+
+use std::{io, net::SocketAddr};
+
+use tokio::net::UdpSocket;
+
+/// The size of each receive buffer, the largest a UDP packet can be.
+const BUFFER_SIZE: usize = 1 << 16;
+
+/// Buffers receiving up to their number of packets at once.
+pub(crate) struct RecvBuffers {
+    buffers: Vec<Vec<u8>>,
+    /// The length and source of each packet last received, in `buffers`.
+    received: Vec<(usize, SocketAddr)>,
+}
+
+impl RecvBuffers {
+    /// Creates buffers receiving up to `packets` packets at once, at least
+    /// one.
+    pub(crate) fn new(packets: usize) -> Self {
+        let packets = packets.max(1);
+        Self {
+            buffers: vec![vec![0; BUFFER_SIZE]; packets],
+            received: Vec::with_capacity(packets),
+        }
+    }
+
+    /// Waits for packets on `socket`, receiving as many of them as there are
+    /// buffers, and returns the number received.
+    pub(crate) async fn recv(&mut self, socket: &UdpSocket) -> io::Result<usize> {
+        self.received.clear();
+
+        #[cfg(target_os = "linux")]
+        if self.buffers.len() > 1 && linux::supported() {
+            loop {
+                socket.readable().await?;
+                let buffers = &mut self.buffers;
+                let received = &mut self.received;
+                match socket.try_io(tokio::io::Interest::READABLE, || {
+                    linux::recv_mmsg(socket, buffers, received)
+                }) {
+                    Err(error) if error.kind() == io::ErrorKind::WouldBlock => continue,
+                    Err(error) if linux::unsupported(&error) => break,
+                    result => return result,
+                }
+            }
+        }
+
+        let (size, source) = socket.recv_from(&mut self.buffers[0]).await?;
+        self.received.push((size, source));
+        Ok(1)
+    }
+
+    /// The contents and source of each packet last received.
+    pub(crate) fn packets(&self) -> impl Iterator<Item = (&[u8], SocketAddr)> {
+        self.buffers
+            .iter()
+            .zip(&self.received)
+            .map(|(buffer, (size, source))| (&buffer[..*size], *source))
+    }
+}
+
+/// Sends as many of `packets` as the connected `socket` accepts at once,
+/// returning how many were sent.
+pub(crate) async fn send(socket: &UdpSocket, packets: &[Vec<u8>]) -> io::Result<usize> {
+    #[cfg(target_os = "linux")]
+    if packets.len() > 1 && linux::supported() {
+        loop {
+            socket.writable().await?;
+            match socket.try_io(tokio::io::Interest::WRITABLE, || {
+                linux::send_mmsg(socket, packets)
+            }) {
+                Err(error) if error.kind() == io::ErrorKind::WouldBlock => continue,
+                Err(error) if linux::unsupported(&error) => break,
+                result => return result,
+            }
+        }
+    }
+
+    socket.send(&packets[0]).await?;
+    Ok(1)
+}
+
+#[cfg(target_os = "linux")]
+mod linux {
+    use std::{
+        io,
+        net::SocketAddr,
+        os::unix::io::AsRawFd,
+        sync::atomic::{AtomicBool, Ordering},
+    };
+
+    use socket2::SockAddr;
+    use tokio::net::UdpSocket;
+
+    /// Cleared once the kernel turns out not to have the calls.
+    static SUPPORTED: AtomicBool = AtomicBool::new(true);
+
+    pub(super) fn supported() -> bool {
+        SUPPORTED.load(Ordering::Relaxed)
+    }
+
+    /// Whether `error` means the kernel doesn't have the calls, in which
+    /// case they aren't made again.
+    pub(super) fn unsupported(error: &io::Error) -> bool {
+        if error.raw_os_error() != Some(libc::ENOSYS) {
+            return false;
+        }
+
+        if SUPPORTED.swap(false, Ordering::Relaxed) {
+            tracing::warn!("recvmmsg and sendmmsg aren't supported, using a call per packet");
+        }
+        true
+    }
+
+    fn message(
+        iovec: &libc::iovec,
+        name: *mut libc::c_void,
+        name_len: libc::socklen_t,
+    ) -> libc::mmsghdr {
+        // SAFETY: Every field of `mmsghdr` is an integer or a pointer, for
+        // which zero is valid, including the padding some targets have.
+        let mut message: libc::mmsghdr = unsafe { std::mem::zeroed() };
+        message.msg_hdr.msg_name = name;
+        message.msg_hdr.msg_namelen = name_len;
+        message.msg_hdr.msg_iov = iovec as *const libc::iovec as *mut libc::iovec;
+        message.msg_hdr.msg_iovlen = 1;
+        message
+    }
+
+    fn result(count: libc::c_int) -> io::Result<usize> {
+        if count < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(count as usize)
+        }
+    }
+
+    pub(super) fn send_mmsg(socket: &UdpSocket, packets: &[Vec<u8>]) -> io::Result<usize> {
+        let iovecs: Vec<_> = packets
+            .iter()
+            .map(|packet| libc::iovec {
+                iov_base: packet.as_ptr() as *mut libc::c_void,
+                iov_len: packet.len(),
+            })
+            .collect();
+        // The socket is connected, so the messages have no address.
+        let mut messages: Vec<_> = iovecs
+            .iter()
+            .map(|iovec| message(iovec, std::ptr::null_mut(), 0))
+            .collect();
+
+        // SAFETY: `messages` and the packets and `iovecs` it points to outlive
+        // the call, and `len` is its length. The packets are only read from.
+        result(unsafe {
+            libc::sendmmsg(
+                socket.as_raw_fd(),
+                messages.as_mut_ptr(),
+                messages.len() as libc::c_uint,
+                0,
+            )
+        })
+    }
+
+    pub(super) fn recv_mmsg(
+        socket: &UdpSocket,
+        buffers: &mut [Vec<u8>],
+        received: &mut Vec<(usize, SocketAddr)>,
+    ) -> io::Result<usize> {
+        let iovecs: Vec<_> = buffers
+            .iter_mut()
+            .map(|buffer| libc::iovec {
+                iov_base: buffer.as_mut_ptr().cast(),
+                iov_len: buffer.len(),
+            })
+            .collect();
+        // SAFETY: `sockaddr_storage` is plain data, for which zero is valid.
+        let mut names =
+            vec![unsafe { std::mem::zeroed::<libc::sockaddr_storage>() }; buffers.len()];
+        let mut messages: Vec<_> = iovecs
+            .iter()
+            .zip(&mut names)
+            .map(|(iovec, name)| {
+                message(
+                    iovec,
+                    (name as *mut libc::sockaddr_storage).cast(),
+                    std::mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t,
+                )
+            })
+            .collect();
+
+        // SAFETY: `messages` and the buffers and `names` it points to outlive
+        // the call, and `len` is its length. The socket is non-blocking, so
+        // the call returns the packets already queued without a timeout.
+        let count = result(unsafe {
+            libc::recvmmsg(
+                socket.as_raw_fd(),
+                messages.as_mut_ptr(),
+                messages.len() as libc::c_uint,
+                0,
+                std::ptr::null_mut(),
+            )
+        })?;
+
+        for (message, name) in messages.iter().zip(&names).take(count) {
+            // SAFETY: The kernel wrote an address of `msg_namelen` bytes to
+            // `name`.
+            let address = unsafe { SockAddr::new(*name, message.msg_hdr.msg_namelen) };
+            let address = address.as_socket().ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("unexpected address family {}", address.family()),
+                )
+            })?;
+            received.push((message.msg_len as usize, address));
+        }
+
+        Ok(count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn send_and_recv() {
+        let receiver = crate::test_utils::create_socket().await;
+        let sender = crate::test_utils::create_socket().await;
+        sender
+            .connect(receiver.local_addr().unwrap())
+            .await
+            .unwrap();
+        let source = sender.local_addr().unwrap();
+
+        let packets = vec![b"one".to_vec(), b"two".to_vec(), b"three".to_vec()];
+        let mut sent = 0;
+        while sent < packets.len() {
+            sent += send(&sender, &packets[sent..]).await.unwrap();
+        }
+
+        let mut buffers = RecvBuffers::new(packets.len());
+        let mut received = Vec::new();
+        while received.len() < packets.len() {
+            let count =
+                tokio::time::timeout(std::time::Duration::from_secs(1), buffers.recv(&receiver))
+                    .await
+                    .unwrap()
+                    .unwrap();
+            assert_eq!(count, buffers.packets().count());
+            received.extend(buffers.packets().map(|(contents, from)| {
+                assert_eq!(source.port(), from.port());
+                contents.to_vec()
+            }));
+        }
+        assert_eq!(packets, received);
+    }
+}
@@ -14,26 +14,251 @@
  * limitations under the License.
  */
 
-use crate::Result;
 use socket2::{Protocol, Socket, Type};
-use std::{io, net::SocketAddr};
+use std::{io, net::SocketAddr, sync::Arc};
 use tokio::net::UdpSocket;
 
-/// returns a UdpSocket with address and port reuse.
-pub fn socket_with_reuse(addr: SocketAddr) -> Result<UdpSocket> {
-    let sock = Socket::new(
+use crate::cluster::{Checksum, Normalize, UpstreamBind};
+
+type Setup = dyn Fn(&Socket) -> io::Result<()> + Send + Sync;
+
+/// Configuration applied to every socket the proxy creates, both those
+/// receiving packets from downstream and each session's upstream socket.
+#[derive(Clone, Default)]
+pub struct SocketConfig {
+    setup: Vec<Arc<Setup>>,
+    upstream_bind: UpstreamBind,
+    upstream_batch_window: Option<std::time::Duration>,
+}
+
+impl SocketConfig {
+    /// Adds a callback that is run on each socket after it has been created
+    /// and before it is bound, to set options that Quilkin doesn't otherwise
+    /// expose (e.g. `SO_MARK` or `SO_BINDTODEVICE`). An error returned from
+    /// `setup` fails the creation of the socket.
+    pub fn with_setup(
+        mut self,
+        setup: impl Fn(&Socket) -> io::Result<()> + Send + Sync + 'static,
+    ) -> Self {
+        self.setup.push(Arc::new(setup));
+        self
+    }
+
+    /// Sets where each session's upstream socket is bound, for clusters
+    /// that don't set their own [`UpstreamBind`].
+    pub fn with_upstream_bind(mut self, upstream_bind: UpstreamBind) -> Self {
+        self.upstream_bind = upstream_bind;
+        self
+    }
+
+    pub fn upstream_bind(&self) -> &UpstreamBind {
+        &self.upstream_bind
+    }
+
+    /// Batches the packets each session sends upstream within `window`, at
+    /// most a millisecond, into one system call where supported.
+    pub fn with_upstream_batch_window(
+        mut self,
+        window: impl Into<Option<std::time::Duration>>,
+    ) -> Self {
+        self.upstream_batch_window = window.into();
+        self
+    }
+
+    pub fn upstream_batch_window(&self) -> Option<std::time::Duration> {
+        self.upstream_batch_window
+    }
+
+    /// Returns a non-blocking UdpSocket bound to `addr`, with address and
+    /// port reuse if `reuse` is set, its packets marked with `fwmark` if
+    /// set, and their headers normalised by `normalize` if set.
+    pub(crate) fn bind(
+        &self,
+        addr: SocketAddr,
+        reuse: bool,
+        fwmark: Option<u32>,
+        normalize: Option<&Normalize>,
+    ) -> io::Result<UdpSocket> {
+        self.bind_to_interface(addr, reuse, fwmark, normalize, None)
+    }
+
+    /// Returns a session's socket sending to `dest`, bound where the
+    /// cluster's `upstream_bind` (falling back to the proxy's) says.
+    pub(crate) fn bind_upstream(
+        &self,
+        dest: SocketAddr,
+        upstream_bind: Option<&UpstreamBind>,
+        fwmark: Option<u32>,
+        normalize: Option<&Normalize>,
+    ) -> io::Result<UdpSocket> {
+        let upstream_bind = upstream_bind.map_or_else(
+            || self.upstream_bind.clone(),
+            |bind| bind.or(&self.upstream_bind),
+        );
+
+        self.bind_to_interface(
+            upstream_bind.address_for(dest),
+            false,
+            fwmark,
+            normalize,
+            upstream_bind.interface.as_deref(),
+        )
+    }
+
+    fn bind_to_interface(
+        &self,
+        addr: SocketAddr,
+        reuse: bool,
+        fwmark: Option<u32>,
+        normalize: Option<&Normalize>,
+        interface: Option<&str>,
+    ) -> io::Result<UdpSocket> {
+        let sock = Socket::new(
+            match addr {
+                SocketAddr::V4(_) => socket2::Domain::IPV4,
+                SocketAddr::V6(_) => socket2::Domain::IPV6,
+            },
+            Type::DGRAM,
+            Some(Protocol::UDP),
+        )?;
+        if reuse {
+            enable_reuse(&sock)?;
+        }
+        sock.set_nonblocking(true)?;
+        if let Some(fwmark) = fwmark {
+            set_mark(&sock, fwmark)?;
+        }
+        if let Some(normalize) = normalize {
+            set_normalize(&sock, addr, normalize)?;
+        }
+        if let Some(interface) = interface {
+            bind_device(&sock, interface)?;
+        }
+        // Sockets bound to `[::]` also receive IPv4 packets, whatever the
+        // system's default.
+        if let SocketAddr::V6(addr) = addr {
+            if addr.ip().is_unspecified() {
+                sock.set_only_v6(false)?;
+            }
+        }
+        for setup in &self.setup {
+            setup(&sock)?;
+        }
+        sock.bind(&addr.into())?;
+
+        UdpSocket::from_std(sock.into())
+    }
+}
+
+impl std::fmt::Debug for SocketConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("SocketConfig")
+            .field("setup", &self.setup.len())
+            .field("upstream_bind", &self.upstream_bind)
+            .finish()
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn set_mark(sock: &Socket, fwmark: u32) -> io::Result<()> {
+    sock.set_mark(fwmark)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_mark(_: &Socket, fwmark: u32) -> io::Result<()> {
+    tracing::warn!(fwmark, "fwmark is only supported on Linux, ignoring");
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+fn bind_device(sock: &Socket, interface: &str) -> io::Result<()> {
+    sock.bind_device(Some(interface.as_bytes()))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn bind_device(_: &Socket, interface: &str) -> io::Result<()> {
+    tracing::warn!(
+        interface,
+        "binding to an interface is only supported on Linux, ignoring"
+    );
+    Ok(())
+}
+
+fn set_normalize(sock: &Socket, addr: SocketAddr, normalize: &Normalize) -> io::Result<()> {
+    if let Some(hop_limit) = normalize.hop_limit {
         match addr {
-            SocketAddr::V4(_) => socket2::Domain::IPV4,
-            SocketAddr::V6(_) => socket2::Domain::IPV6,
-        },
-        Type::DGRAM,
-        Some(Protocol::UDP),
-    )?;
-    enable_reuse(&sock)?;
-    sock.set_nonblocking(true)?;
-    sock.bind(&addr.into())?;
+            SocketAddr::V4(_) => sock.set_ttl(hop_limit.into())?,
+            SocketAddr::V6(_) => sock.set_unicast_hops_v6(hop_limit.into())?,
+        }
+    }
 
-    UdpSocket::from_std(sock.into()).map_err(|error| eyre::eyre!(error))
+    if normalize.clear_flow_label && addr.is_ipv6() {
+        disable_auto_flow_label(sock)?;
+    }
+
+    if normalize.checksum == Checksum::Omit {
+        omit_checksums(sock, addr)?;
+    }
+
+    Ok(())
+}
+
+/// Disables `IPV6_AUTOFLOWLABEL`, which isn't exposed by `socket2`, so that
+/// packets are sent with a flow label of zero.
+#[cfg(target_os = "linux")]
+fn disable_auto_flow_label(sock: &Socket) -> io::Result<()> {
+    set_int_option(sock, libc::IPPROTO_IPV6, libc::IPV6_AUTOFLOWLABEL, 0)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn disable_auto_flow_label(_: &Socket) -> io::Result<()> {
+    tracing::warn!("clearing flow labels is only supported on Linux, ignoring");
+    Ok(())
+}
+
+/// Sets `SO_NO_CHECK` on IPv4 sockets, so that packets are sent with a
+/// checksum of zero, and `UDP_NO_CHECK6_RX` on IPv6 sockets, so that packets
+/// received with a checksum of zero aren't dropped. Neither is exposed by
+/// `socket2`.
+#[cfg(target_os = "linux")]
+fn omit_checksums(sock: &Socket, addr: SocketAddr) -> io::Result<()> {
+    match addr {
+        SocketAddr::V4(_) => set_int_option(sock, libc::SOL_SOCKET, libc::SO_NO_CHECK, 1),
+        SocketAddr::V6(_) => set_int_option(sock, libc::IPPROTO_UDP, libc::UDP_NO_CHECK6_RX, 1),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn omit_checksums(_: &Socket, _: SocketAddr) -> io::Result<()> {
+    tracing::warn!("omitting UDP checksums is only supported on Linux, ignoring");
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+fn set_int_option(
+    sock: &Socket,
+    level: libc::c_int,
+    name: libc::c_int,
+    value: libc::c_int,
+) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    // SAFETY: `value` outlives the call, and `length` is its size.
+    let result = unsafe {
+        libc::setsockopt(
+            sock.as_raw_fd(),
+            level,
+            name,
+            (&value as *const libc::c_int).cast(),
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+
+    if result == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
 }
 
 #[cfg(not(target_family = "windows"))]
@@ -50,19 +275,116 @@ fn enable_reuse(sock: &Socket) -> io::Result<()> {
 
 #[cfg(test)]
 mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
     use crate::test_utils::available_addr;
 
     #[tokio::test]
     async fn socket_with_reuse() {
         let expected = available_addr().await;
-        let socket = super::socket_with_reuse(expected).unwrap();
+        let socket = SocketConfig::default()
+            .bind(expected, true, None, None)
+            .unwrap();
         let addr = socket.local_addr().unwrap();
 
         assert_eq!(expected, socket.local_addr().unwrap());
 
         // should be able to do it a second time, since we are reusing the address.
-        let socket = super::socket_with_reuse(expected).unwrap();
+        let socket = SocketConfig::default()
+            .bind(expected, true, None, None)
+            .unwrap();
         let addr2 = socket.local_addr().unwrap();
         assert_eq!(addr, addr2);
     }
+
+    #[tokio::test]
+    async fn with_setup() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let config = SocketConfig::default().with_setup({
+            let calls = calls.clone();
+            move |socket| {
+                calls.fetch_add(1, Ordering::SeqCst);
+                socket.set_broadcast(true)
+            }
+        });
+
+        let socket = config
+            .bind((std::net::Ipv4Addr::LOCALHOST, 0).into(), false, None, None)
+            .unwrap();
+        assert!(socket.broadcast().unwrap());
+        assert_eq!(1, calls.load(Ordering::SeqCst));
+
+        let config = config.with_setup(|_| Err(io::Error::new(io::ErrorKind::Other, "nope")));
+        assert!(config
+            .bind((std::net::Ipv4Addr::LOCALHOST, 0).into(), false, None, None)
+            .is_err());
+        assert_eq!(2, calls.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn normalize() {
+        let normalize = Normalize {
+            hop_limit: Some(42),
+            clear_flow_label: true,
+            checksum: Checksum::Omit,
+        };
+
+        let socket = SocketConfig::default()
+            .bind(
+                (std::net::Ipv4Addr::LOCALHOST, 0).into(),
+                false,
+                None,
+                Some(&normalize),
+            )
+            .unwrap();
+        assert_eq!(42, socket.ttl().unwrap());
+
+        let socket = SocketConfig::default()
+            .bind(
+                (std::net::Ipv6Addr::LOCALHOST, 0).into(),
+                false,
+                None,
+                Some(&normalize),
+            )
+            .unwrap();
+        assert_eq!(
+            42,
+            socket2::SockRef::from(&socket).unicast_hops_v6().unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn bind_upstream() {
+        let config = SocketConfig::default().with_upstream_bind(UpstreamBind {
+            ipv4: Some(std::net::Ipv4Addr::LOCALHOST),
+            ..<_>::default()
+        });
+
+        let v4 = (std::net::Ipv4Addr::new(10, 0, 0, 1), 7777).into();
+        let v6 = (std::net::Ipv6Addr::LOCALHOST, 7777).into();
+        let socket = config.bind_upstream(v4, None, None, None).unwrap();
+        assert_eq!(
+            std::net::IpAddr::from(std::net::Ipv4Addr::LOCALHOST),
+            socket.local_addr().unwrap().ip()
+        );
+
+        let socket = config.bind_upstream(v6, None, None, None).unwrap();
+        assert_eq!(
+            std::net::IpAddr::from(std::net::Ipv6Addr::UNSPECIFIED),
+            socket.local_addr().unwrap().ip()
+        );
+
+        let cluster = UpstreamBind {
+            ipv6: Some(std::net::Ipv6Addr::LOCALHOST),
+            ..<_>::default()
+        };
+        let socket = config
+            .bind_upstream(v6, Some(&cluster), None, None)
+            .unwrap();
+        assert_eq!(
+            std::net::IpAddr::from(std::net::Ipv6Addr::LOCALHOST),
+            socket.local_addr().unwrap().ip()
+        );
+    }
 }
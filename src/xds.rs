@@ -118,6 +118,27 @@ mod xds {
     }
 }
 
+#[allow(warnings)]
+mod quilkin {
+    pub mod rate_limit {
+        pub mod v1alpha1 {
+            tonic::include_proto!("quilkin.rate_limit.v1alpha1");
+        }
+    }
+
+    pub mod registration {
+        pub mod v1alpha1 {
+            tonic::include_proto!("quilkin.registration.v1alpha1");
+        }
+    }
+
+    pub mod telemetry {
+        pub mod v1alpha1 {
+            tonic::include_proto!("quilkin.telemetry.v1alpha1");
+        }
+    }
+}
+
 #[allow(warnings)]
 mod google {
     pub mod rpc {
@@ -126,16 +147,27 @@ mod google {
 }
 
 pub(crate) mod client;
+pub mod faults;
 mod metrics;
+pub mod rate_limit;
+pub mod rbac;
+pub mod registration;
 mod resource;
 pub(crate) mod server;
+pub(crate) mod status;
+pub mod telemetry;
 
-pub use client::Client;
+pub use client::{Client, ClientAuth};
+pub use faults::Faults;
 pub use resource::{Resource, ResourceType};
 pub use server::ControlPlane;
 pub use service::discovery::v3::aggregated_discovery_service_client::AggregatedDiscoveryServiceClient;
 pub use xds::*;
 
+/// The key of the invalid endpoints a proxy skipped when applying a response,
+/// in the metadata of the node acknowledging it.
+pub(crate) const SKIPPED_ENDPOINTS_METADATA_KEY: &str = "quilkin.dev/skipped_endpoints";
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -145,6 +177,11 @@ mod tests {
     use crate::{config::Config, endpoint::Endpoint, filters::*};
 
     #[tokio::test]
+    #[cfg(all(
+        feature = "filter-capture",
+        feature = "filter-match",
+        feature = "filter-token-router"
+    ))]
     async fn token_routing() {
         let mut helper = crate::test_utils::TestHelper::default();
         let token = uuid::Uuid::new_v4().into_bytes();
@@ -239,6 +276,7 @@ mod tests {
                             value: 1.into(),
                             filter: TokenRouter::as_filter_config(token_router::Config {
                                 metadata_key: TOKEN_KEY.into(),
+                                ..<_>::default()
                             })
                             .unwrap(),
                         }],
@@ -280,6 +318,7 @@ mod tests {
     }
 
     #[tokio::test]
+    #[cfg(feature = "filter-concatenate-bytes")]
     async fn basic() {
         let config: Arc<Config> = serde_json::from_value(serde_json::json!({
             "version": "v1alpha1",
@@ -359,4 +398,178 @@ mod tests {
             assert_eq!(iter.next().unwrap(), filters[1].clone().into());
         }
     }
+
+    /// Polls `condition` until it's true, failing after a few seconds.
+    async fn eventually(mut condition: impl FnMut() -> bool) {
+        tokio::time::timeout(std::time::Duration::from_secs(5), async {
+            while !condition() {
+                tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn keeps_config_through_faults() {
+        let server_config: Arc<Config> = serde_json::from_value(serde_json::json!({
+            "version": "v1alpha1",
+            "id": "test-server",
+        }))
+        .map(Arc::new)
+        .unwrap();
+        let client_config: Arc<Config> = serde_json::from_value(serde_json::json!({
+            "version": "v1alpha1",
+            "id": "test-proxy",
+        }))
+        .map(Arc::new)
+        .unwrap();
+
+        let address = |port: u16| {
+            crate::endpoint::EndpointAddress::from((std::net::Ipv4Addr::LOCALHOST, port))
+        };
+        let set_endpoint = |port: u16| {
+            server_config.clusters.modify(|clusters| {
+                let cluster = clusters.default_cluster_mut();
+                cluster.localities.clear();
+                cluster.insert(Endpoint::new(address(port)));
+            });
+        };
+        let endpoint = || {
+            client_config
+                .clusters
+                .load()
+                .get_default()
+                .and_then(|cluster| {
+                    cluster
+                        .endpoints()
+                        .next()
+                        .map(|endpoint| endpoint.address.clone())
+                })
+        };
+
+        set_endpoint(1001);
+        let faults = Faults::default();
+        let xds_port = crate::test_utils::available_addr().await.port();
+        tokio::spawn(server::serve(
+            xds_port,
+            ControlPlane::from_arc(server_config.clone()).with_faults(faults.clone()),
+        ));
+
+        let client = Client::connect(
+            "test-proxy".into(),
+            vec![format!("http://127.0.0.1:{xds_port}").parse().unwrap()],
+        )
+        .await
+        .unwrap();
+        let mut stream = client.stream_config(client_config.clone()).await.unwrap();
+        stream.send(ResourceType::Endpoint, &[]).await.unwrap();
+        eventually(|| endpoint() == Some(address(1001))).await;
+
+        // The previous config is used until a delayed response arrives.
+        faults.delay_responses(std::time::Duration::from_millis(500));
+        set_endpoint(1002);
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        assert_eq!(Some(address(1001)), endpoint());
+        eventually(|| endpoint() == Some(address(1002))).await;
+        faults.delay_responses(std::time::Duration::ZERO);
+
+        // The config is kept while reconnecting, and updates resume once
+        // reconnected.
+        faults.reset_streams();
+        assert_eq!(Some(address(1002)), endpoint());
+        tokio::time::sleep(std::time::Duration::from_millis(500)).await;
+        set_endpoint(1003);
+        eventually(|| endpoint() == Some(address(1003))).await;
+        set_endpoint(1004);
+        eventually(|| endpoint() == Some(address(1004))).await;
+
+        // Responses older than the applied config are rejected.
+        faults.roll_back();
+        tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+        assert_eq!(Some(address(1004)), endpoint());
+    }
+
+    #[tokio::test]
+    async fn delta() {
+        let server_config: Arc<Config> = serde_json::from_value(serde_json::json!({
+            "version": "v1alpha1",
+            "id": "test-server",
+        }))
+        .map(Arc::new)
+        .unwrap();
+        let client_config: Arc<Config> = serde_json::from_value(serde_json::json!({
+            "version": "v1alpha1",
+            "id": "test-proxy",
+        }))
+        .map(Arc::new)
+        .unwrap();
+
+        let address = |port: u16| {
+            crate::endpoint::EndpointAddress::from((std::net::Ipv4Addr::LOCALHOST, port))
+        };
+        let set_endpoint = |cluster: &str, port: u16| {
+            server_config.clusters.modify(|clusters| {
+                clusters.insert(crate::cluster::Cluster::new(
+                    cluster.into(),
+                    vec![crate::endpoint::LocalityEndpoints::from(Endpoint::new(
+                        address(port),
+                    ))],
+                ));
+            });
+        };
+        let endpoint = |cluster: &str| {
+            client_config
+                .clusters
+                .load()
+                .get(cluster)
+                .and_then(|cluster| {
+                    cluster
+                        .endpoints()
+                        .next()
+                        .map(|endpoint| endpoint.address.clone())
+                })
+        };
+
+        set_endpoint("first", 1001);
+        set_endpoint("second", 2001);
+        let faults = Faults::default();
+        let xds_port = crate::test_utils::available_addr().await.port();
+        tokio::spawn(server::serve(
+            xds_port,
+            ControlPlane::from_arc(server_config.clone()).with_faults(faults.clone()),
+        ));
+
+        let client = Client::connect(
+            "test-proxy".into(),
+            vec![format!("http://127.0.0.1:{xds_port}").parse().unwrap()],
+        )
+        .await
+        .unwrap();
+        let mut stream = client
+            .delta_stream_config(client_config.clone())
+            .await
+            .unwrap();
+        stream.send(ResourceType::Endpoint, &[]).await.unwrap();
+        eventually(|| {
+            endpoint("first") == Some(address(1001)) && endpoint("second") == Some(address(2001))
+        })
+        .await;
+
+        set_endpoint("second", 2002);
+        eventually(|| endpoint("second") == Some(address(2002))).await;
+        assert_eq!(Some(address(1001)), endpoint("first"));
+
+        // Removed clusters are removed from the client.
+        server_config.clusters.modify(|clusters| {
+            clusters.remove("first");
+        });
+        eventually(|| client_config.clusters.load().get("first").is_none()).await;
+
+        // Updates resume once reconnected.
+        faults.reset_streams();
+        tokio::time::sleep(std::time::Duration::from_millis(500)).await;
+        set_endpoint("second", 2003);
+        eventually(|| endpoint("second") == Some(address(2003))).await;
+    }
 }
@@ -14,12 +14,21 @@
  * limitations under the License.
  */
 
-use std::{collections::HashSet, sync::Arc, time::Duration};
+mod auth;
+
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    sync::Arc,
+    time::Duration,
+};
 
 use futures::StreamExt;
 use rand::Rng;
 use tokio::sync::{broadcast, Mutex};
-use tonic::transport::{channel::Channel as TonicChannel, Endpoint, Error as TonicError};
+use tonic::{
+    codegen::InterceptedService,
+    transport::{channel::Channel as TonicChannel, Endpoint, Error as TonicError},
+};
 use tracing::Instrument;
 use tryhard::{
     backoff_strategies::{BackoffStrategy, ExponentialBackoff},
@@ -27,39 +36,72 @@ use tryhard::{
 };
 
 use crate::{
+    config::{Config, ConfigHash},
+    endpoint::EndpointAddress,
     xds::{
         config::core::v3::Node,
         metrics,
+        rate_limit::Sharer,
+        registration::{Registrar, Registration},
+        resource::ResourceMap,
         service::discovery::v3::{
-            aggregated_discovery_service_client::AggregatedDiscoveryServiceClient, DiscoveryRequest,
+            aggregated_discovery_service_client::AggregatedDiscoveryServiceClient,
+            DeltaDiscoveryRequest, DiscoveryRequest,
         },
-        Resource, ResourceType,
+        telemetry::Reporter,
+        Resource, ResourceType, SKIPPED_ENDPOINTS_METADATA_KEY,
     },
     Result,
 };
 
-type AdsClient = AggregatedDiscoveryServiceClient<TonicChannel>;
+pub(crate) use auth::BearerToken;
+pub use auth::ClientAuth;
+
+/// A connection to a management server, sending the bearer token, if any,
+/// with every request.
+pub(crate) type Channel = InterceptedService<TonicChannel, BearerToken>;
+type AdsClient = AggregatedDiscoveryServiceClient<Channel>;
 
 /// Client that can talk to an XDS server using the aDS protocol.
 #[derive(Clone)]
 pub struct Client {
     identifier: String,
     management_servers: Vec<Endpoint>,
+    token: BearerToken,
+    channel: Channel,
     client: AdsClient,
 }
 
 impl Client {
-    #[tracing::instrument(skip_all, level = "trace", fields(servers = ?management_servers))]
     pub async fn connect(identifier: String, management_servers: Vec<Endpoint>) -> Result<Self> {
-        let client = Self::new_ads_client(&management_servers).await?;
+        Self::connect_with_auth(identifier, management_servers, &ClientAuth::default()).await
+    }
+
+    /// Connects to the first available management server like
+    /// [`Client::connect`], over TLS and with the bearer token configured in
+    /// `auth`.
+    #[tracing::instrument(skip_all, level = "trace", fields(servers = ?management_servers))]
+    pub async fn connect_with_auth(
+        identifier: String,
+        management_servers: Vec<Endpoint>,
+        auth: &ClientAuth,
+    ) -> Result<Self> {
+        let management_servers = auth.apply_tls(management_servers)?;
+        let token = auth.bearer_token()?;
+        let channel = Self::connect_channel(&management_servers, &token).await?;
         Ok(Self {
-            client,
+            client: AdsClient::new(channel.clone()),
+            channel,
             identifier,
             management_servers,
+            token,
         })
     }
 
-    async fn new_ads_client(management_servers: &[Endpoint]) -> Result<AdsClient> {
+    async fn connect_channel(
+        management_servers: &[Endpoint],
+        token: &BearerToken,
+    ) -> Result<Channel> {
         use crate::config::{
             BACKOFF_INITIAL_DELAY_MILLISECONDS, BACKOFF_MAX_DELAY_SECONDS,
             BACKOFF_MAX_JITTER_MILLISECONDS, CONNECTION_TIMEOUT,
@@ -128,23 +170,24 @@ impl Client {
                             ));
                         }
 
-                        AggregatedDiscoveryServiceClient::connect(endpoint)
-                            .instrument(tracing::debug_span!(
-                                "AggregatedDiscoveryServiceClient::connect"
-                            ))
+                        let channel = endpoint
+                            .connect()
+                            .instrument(tracing::debug_span!("Endpoint::connect"))
                             .await
-                            .map_err(RpcSessionError::InitialConnect)
+                            .map_err(RpcSessionError::InitialConnect)?;
+                        super::status::connected(endpoint.uri().to_string());
+                        Ok(channel)
                     }
                 }
             }
         })
         .with_config(retry_config);
 
-        let client = connect_to_server
+        let channel = connect_to_server
             .instrument(tracing::trace_span!("xds_client_connect"))
             .await?;
         tracing::info!("Connected to xDS server");
-        Ok(client)
+        Ok(InterceptedService::new(channel, token.clone()))
     }
 
     /// Starts a new stream to the xDS management server.
@@ -152,16 +195,106 @@ impl Client {
         &self,
         on_new_resource: impl Fn(&Resource) -> crate::Result<()> + Send + Sync + 'static,
     ) -> Result<Stream> {
-        Stream::connect(self, on_new_resource).await
+        Stream::connect(
+            self,
+            move |resources, _| {
+                resources.iter().try_for_each(&on_new_resource)?;
+                Ok(Vec::new())
+            },
+            || None,
+        )
+        .await
+    }
+
+    /// Starts a new stream to the xDS management server, applying every
+    /// response to `config`, or none of it if any resource is invalid, and
+    /// reporting the resulting [`ConfigHash`] when
+    /// acknowledging each response, allowing the server to detect drift.
+    /// Invalid endpoints skipped under
+    /// [`InvalidEndpointPolicy::Skip`][crate::cluster::InvalidEndpointPolicy::Skip]
+    /// are reported in the node metadata of the acknowledgement.
+    pub async fn stream_config(&self, config: Arc<Config>) -> Result<Stream> {
+        let hashed = config.clone();
+        Stream::connect(
+            self,
+            move |resources, version| config.apply_all(resources, &[], Some(version)),
+            move || {
+                let hash = hashed.hash();
+                hash.record();
+                Some(hash)
+            },
+        )
+        .await
+    }
+
+    /// Starts a new stream to the xDS management server like
+    /// [`Client::stream_config`], using the delta protocol, where the server
+    /// only sends the resources that changed and the names of those removed,
+    /// rather than every resource on every change.
+    pub async fn delta_stream_config(&self, config: Arc<Config>) -> Result<Stream> {
+        let hashed = config.clone();
+        Stream::connect_delta(
+            self,
+            move |resources, removed, version| config.apply_all(resources, removed, Some(version)),
+            move || {
+                let hash = hashed.hash();
+                hash.record();
+                Some(hash)
+            },
+        )
+        .await
+    }
+
+    /// Starts pushing coarse traffic counters to the management server every
+    /// `interval`, over the same connection as the xDS stream.
+    pub fn report_telemetry(&self, interval: Duration) -> Reporter {
+        Reporter::spawn(self.identifier.clone(), self.channel.clone(), interval)
+    }
+
+    /// Starts sharing the usage of the shared rate limits of `config` with
+    /// the rest of the fleet through the management server, over the same
+    /// connection as the xDS stream.
+    pub fn share_rate_limits(&self, config: &Config) -> Sharer {
+        Sharer::spawn(
+            self.identifier.clone(),
+            self.channel.clone(),
+            config.rate_limits.clone(),
+        )
+    }
+
+    /// Registers `endpoints` in `cluster` (the default cluster if `None`) of
+    /// the management server's config for as long as this proxy is
+    /// connected, over the same connection as the xDS stream.
+    pub fn register(&self, cluster: Option<String>, endpoints: &[EndpointAddress]) -> Registrar {
+        Registrar::spawn(
+            self.channel.clone(),
+            Registration {
+                id: self.identifier.clone(),
+                cluster: cluster.unwrap_or_default(),
+                endpoints: endpoints.iter().map(ToString::to_string).collect(),
+            },
+        )
     }
 }
 
 type SubscribedResources = Arc<Mutex<HashSet<(ResourceType, Vec<String>)>>>;
+/// The version of every resource applied over a delta stream, by type and
+/// name.
+type ResourceVersions = Arc<parking_lot::Mutex<ResourceMap<BTreeMap<String, String>>>>;
+
+/// The requests sent over a stream, in the protocol it uses.
+enum Requests {
+    StateOfTheWorld(broadcast::Sender<DiscoveryRequest>),
+    /// Delta streams send the versions of the resources they've applied when
+    /// subscribing, so that unchanged resources aren't sent again after
+    /// reconnecting.
+    Delta(broadcast::Sender<DeltaDiscoveryRequest>, ResourceVersions),
+}
 
 /// An active xDS gRPC management stream.
 pub struct Stream {
     identifier: Arc<str>,
-    requests: broadcast::Sender<DiscoveryRequest>,
+    requests: Requests,
     handle_discovery_response: tokio::task::JoinHandle<Result<()>>,
     subscribed_resources: SubscribedResources,
 }
@@ -173,18 +306,24 @@ impl Stream {
             client,
             identifier,
             management_servers,
+            token,
+            ..
         }: &Client,
-        on_new_resource: impl Fn(&Resource) -> crate::Result<()> + Send + Sync + 'static,
+        on_update: impl Fn(&[Resource], &str) -> crate::Result<Vec<String>> + Send + Sync + 'static,
+        config_hash: impl Fn() -> Option<ConfigHash> + Send + Sync + 'static,
     ) -> Result<Self> {
-        let (requests, mut rx) = broadcast::channel(12);
+        let (sender, mut rx) = broadcast::channel(12);
         let subscribed_resources: SubscribedResources = <_>::default();
         let identifier: Arc<str> = Arc::from(&**identifier);
 
         let handle_discovery_response = tokio::spawn({
             let mut client = client.clone();
             let identifier = identifier.clone();
-            let mut requests = requests.clone();
+            let node_id = identifier.clone();
+            let sender = sender.clone();
+            let requests = Requests::StateOfTheWorld(sender.clone());
             let management_servers = management_servers.clone();
+            let token = token.clone();
             let subscribed_resources = subscribed_resources.clone();
             async move {
                 loop {
@@ -200,16 +339,22 @@ impl Stream {
                         .await?
                         .into_inner();
 
+                    // The latest version applied for each type over this
+                    // stream, as a new stream may be to a different server.
+                    let mut versions = HashMap::<String, u64>::new();
                     loop {
                         let timeout = tokio::time::sleep(std::time::Duration::from_millis(500));
                         let new_message = responses.message();
 
                         tokio::select! {
                             _ = timeout => {
-                                Self::refresh_resources(&identifier, &subscribed_resources, &mut requests).await?;
+                                Self::refresh_resources(&identifier, &subscribed_resources, &requests).await?;
                             }
                             response = new_message => {
-                                let Some(response) = response.map_err(|error| tracing::warn!(%error, "Error from xDS server")).ok().flatten() else {
+                                let Some(response) = response.map_err(|error| {
+                                    tracing::warn!(%error, "Error from xDS server");
+                                    super::status::failed(&error);
+                                }).ok().flatten() else {
                                     break;
                                 };
 
@@ -228,27 +373,76 @@ impl Stream {
                                     "Received response"
                                 );
 
-                                let result = response
-                                    .resources
-                                    .iter()
-                                    .cloned()
-                                    .map(Resource::try_from)
-                                    .try_for_each(|resource| {
-                                        let resource = resource?;
-                                        metrics::DISCOVERY_RESPONSES
-                                            .with_label_values(&[&*identifier, resource.type_url()])
-                                            .inc();
-                                        (on_new_resource)(&resource)
-                                    });
+                                // A response older than the applied config
+                                // means the server misbehaved, so it's
+                                // rejected rather than rolling the config
+                                // back.
+                                let rolled_back = match (
+                                    response.version_info.parse::<u64>(),
+                                    versions.get(&response.type_url),
+                                ) {
+                                    (Ok(version), Some(&applied)) if version < applied => {
+                                        Some(applied)
+                                    }
+                                    _ => None,
+                                };
+
+                                let (errors, skipped) = if let Some(applied) = rolled_back {
+                                    tracing::warn!(
+                                        version = &*response.version_info,
+                                        applied,
+                                        r#type = &*response.type_url,
+                                        "rejecting response older than the applied config"
+                                    );
+                                    let error = format!(
+                                        "version {} is older than the applied version {applied}",
+                                        response.version_info
+                                    );
+                                    (vec![error], Vec::new())
+                                } else {
+                                    // The update is applied as a whole or not
+                                    // at all, so that a NACKed update doesn't
+                                    // leave part of it applied, and only
+                                    // counts as applied if it was.
+                                    let result = Self::decode(&identifier, response.resources.iter().cloned())
+                                        .and_then(|resources| {
+                                            (on_update)(&resources, &response.version_info)
+                                                .map_err(|error| vec![error.to_string()])
+                                        });
+                                    if let (Ok(_), Ok(version)) = (&result, response.version_info.parse()) {
+                                        versions.insert(response.type_url.clone(), version);
+                                    }
+                                    match result {
+                                        Ok(skipped) => (Vec::new(), skipped),
+                                        Err(errors) => (errors, Vec::new()),
+                                    }
+                                };
 
+                                super::status::received(
+                                    &identifier,
+                                    &response.type_url,
+                                    &response.version_info,
+                                    &response.nonce,
+                                    response.resources.len(),
+                                    &errors,
+                                );
                                 let mut request = DiscoveryRequest::try_from(response)?;
-                                if let Err(error) = result {
+                                if let Some(applied) = rolled_back {
+                                    request.version_info = applied.to_string();
+                                }
+                                if let Some(metadata) = ack_metadata((config_hash)(), &skipped) {
+                                    request.node = Some(Node {
+                                        metadata: Some(metadata),
+                                        ..Self::node(&node_id)
+                                    });
+                                }
+                                if !errors.is_empty() {
                                     metrics::NACKS
                                         .with_label_values(&[&*identifier, &*request.type_url])
                                         .inc();
                                     request.error_detail = Some(crate::xds::google::rpc::Status {
                                         code: 3,
-                                        message: error.to_string(),
+                                        message: errors.join("; "),
                                         ..<_>::default()
                                     });
                                 } else {
@@ -257,7 +451,7 @@ impl Stream {
                                         .inc();
                                 }
 
-                                requests.send(request)?;
+                                sender.send(request)?;
                             }
                             else => {
                                 break;
@@ -266,11 +460,13 @@ impl Stream {
                     }
 
                     tracing::info!("Lost connection to xDS, retrying");
+                    super::status::disconnected();
                     // If we've reached here, something has gone wrong with the
                     // connection, so we just create a new client and restart.
-                    client = Client::new_ads_client(&management_servers).await?;
-                    rx = requests.subscribe();
-                    Self::refresh_resources(&identifier, &subscribed_resources, &mut requests).await?;
+                    client =
+                        AdsClient::new(Client::connect_channel(&management_servers, &token).await?);
+                    rx = sender.subscribe();
+                    Self::refresh_resources(&identifier, &subscribed_resources, &requests).await?;
                 }
             }
             .instrument(tracing::trace_span!("handle_discovery_response"))
@@ -278,25 +474,214 @@ impl Stream {
 
         Ok(Self {
             identifier,
-            requests,
+            requests: Requests::StateOfTheWorld(sender),
+            handle_discovery_response,
+            subscribed_resources,
+        })
+    }
+
+    #[tracing::instrument(level = "trace", skip_all)]
+    async fn connect_delta(
+        Client {
+            client,
+            identifier,
+            management_servers,
+            token,
+            ..
+        }: &Client,
+        on_update: impl Fn(&[Resource], &[(ResourceType, String)], &str) -> crate::Result<Vec<String>>
+            + Send
+            + Sync
+            + 'static,
+        config_hash: impl Fn() -> Option<ConfigHash> + Send + Sync + 'static,
+    ) -> Result<Self> {
+        let (sender, mut rx) = broadcast::channel(12);
+        let subscribed_resources: SubscribedResources = <_>::default();
+        let versions: ResourceVersions = <_>::default();
+        let identifier: Arc<str> = Arc::from(&**identifier);
+
+        let handle_discovery_response = tokio::spawn({
+            let mut client = client.clone();
+            let identifier = identifier.clone();
+            let sender = sender.clone();
+            let versions = versions.clone();
+            let requests = Requests::Delta(sender.clone(), versions.clone());
+            let management_servers = management_servers.clone();
+            let token = token.clone();
+            let subscribed_resources = subscribed_resources.clone();
+            async move {
+                loop {
+                    let mut responses = client
+                        .delta_aggregated_resources(
+                            tokio_stream::wrappers::BroadcastStream::from(rx)
+                                .filter_map(|result| futures::future::ready(result.ok())),
+                        )
+                        .in_current_span()
+                        .await?
+                        .into_inner();
+
+                    while let Some(response) = responses
+                        .message()
+                        .await
+                        .map_err(|error| {
+                            tracing::warn!(%error, "Error from xDS server");
+                            super::status::failed(&error);
+                        })
+                        .ok()
+                        .flatten()
+                    {
+                        let control_plane = response
+                            .control_plane
+                            .as_ref()
+                            .map(|cp| cp.identifier.clone())
+                            .unwrap_or_default();
+                        let _stream_metrics =
+                            super::metrics::StreamConnectionMetrics::new(&control_plane);
+                        tracing::info!(
+                            id = &*response.system_version_info,
+                            r#type = &*response.type_url,
+                            nonce = &*response.nonce,
+                            control_plane = &*control_plane,
+                            resources = response.resources.len(),
+                            removed = response.removed_resources.len(),
+                            "Received delta response"
+                        );
+
+                        // Like state of the world responses, the update is
+                        // applied as a whole or not at all.
+                        let result = response
+                            .type_url
+                            .parse::<ResourceType>()
+                            .map_err(|error| vec![error.to_string()])
+                            .and_then(|resource_type| {
+                                let resources = Self::decode(
+                                    &control_plane,
+                                    response
+                                        .resources
+                                        .iter()
+                                        .filter_map(|resource| resource.resource.clone()),
+                                )?;
+                                let removed = response
+                                    .removed_resources
+                                    .iter()
+                                    .map(|name| (resource_type, name.clone()))
+                                    .collect::<Vec<_>>();
+                                let skipped = (on_update)(
+                                    &resources,
+                                    &removed,
+                                    &response.system_version_info,
+                                )
+                                .map_err(|error| vec![error.to_string()])?;
+
+                                let mut versions = versions.lock();
+                                let versions = &mut versions[resource_type];
+                                for resource in &response.resources {
+                                    versions
+                                        .insert(resource.name.clone(), resource.version.clone());
+                                }
+                                for name in &response.removed_resources {
+                                    versions.remove(name);
+                                }
+                                Ok(skipped)
+                            });
+                        let (errors, skipped) = match result {
+                            Ok(skipped) => (Vec::new(), skipped),
+                            Err(errors) => (errors, Vec::new()),
+                        };
+
+                        super::status::received(
+                            &control_plane,
+                            &response.type_url,
+                            &response.system_version_info,
+                            &response.nonce,
+                            response.resources.len(),
+                            &errors,
+                        );
+                        let mut node = Self::node(&identifier);
+                        node.metadata = ack_metadata((config_hash)(), &skipped);
+                        let mut request = DeltaDiscoveryRequest {
+                            node: Some(node),
+                            type_url: response.type_url,
+                            response_nonce: response.nonce,
+                            ..<_>::default()
+                        };
+                        if !errors.is_empty() {
+                            metrics::NACKS
+                                .with_label_values(&[&*control_plane, &*request.type_url])
+                                .inc();
+                            request.error_detail = Some(crate::xds::google::rpc::Status {
+                                code: 3,
+                                message: errors.join("; "),
+                                ..<_>::default()
+                            });
+                        } else {
+                            metrics::ACKS
+                                .with_label_values(&[&*control_plane, &*request.type_url])
+                                .inc();
+                        }
+
+                        sender.send(request)?;
+                    }
+
+                    tracing::info!("Lost connection to xDS, retrying");
+                    super::status::disconnected();
+                    client =
+                        AdsClient::new(Client::connect_channel(&management_servers, &token).await?);
+                    rx = sender.subscribe();
+                    Self::refresh_resources(&identifier, &subscribed_resources, &requests).await?;
+                }
+            }
+            .instrument(tracing::trace_span!("handle_delta_discovery_response"))
+        });
+
+        Ok(Self {
+            identifier,
+            requests: Requests::Delta(sender, versions),
             handle_discovery_response,
             subscribed_resources,
         })
     }
 
+    /// Decodes every resource of a response, returning why each resource
+    /// that couldn't be decoded is invalid if there are any.
+    fn decode(
+        control_plane: &str,
+        resources: impl Iterator<Item = prost_types::Any>,
+    ) -> Result<Vec<Resource>, Vec<String>> {
+        let mut decoded = Vec::new();
+        let mut errors = Vec::new();
+        for resource in resources.map(Resource::try_from) {
+            match resource {
+                Ok(resource) => {
+                    metrics::DISCOVERY_RESPONSES
+                        .with_label_values(&[control_plane, resource.type_url()])
+                        .inc();
+                    decoded.push(resource);
+                }
+                Err(error) => errors.push(error.to_string()),
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(decoded)
+        } else {
+            Err(errors)
+        }
+    }
+
     #[tracing::instrument(level = "trace", skip(self))]
     pub async fn send(&mut self, resource_type: ResourceType, names: &[String]) -> Result<()> {
         self.subscribed_resources
             .lock()
             .await
             .insert((resource_type, names.to_vec()));
-        Self::send_without_cache(&self.identifier, &mut self.requests, resource_type, names)
+        Self::send_without_cache(&self.identifier, &self.requests, resource_type, names)
     }
 
     async fn refresh_resources(
         identifier: &str,
         subscribed_resources: &SubscribedResources,
-        requests: &mut broadcast::Sender<DiscoveryRequest>,
+        requests: &Requests,
     ) -> Result<()> {
         for (resource, names) in subscribed_resources.lock().await.iter() {
             Self::send_without_cache(identifier, requests, *resource, names)?;
@@ -307,24 +692,62 @@ impl Stream {
 
     fn send_without_cache(
         identifier: &str,
-        requests: &mut broadcast::Sender<DiscoveryRequest>,
+        requests: &Requests,
         resource_type: ResourceType,
         names: &[String],
     ) -> Result<()> {
-        let request = DiscoveryRequest {
-            node: Some(Node {
-                id: identifier.into(),
-                user_agent_name: "quilkin".into(),
-                ..Node::default()
-            }),
-            resource_names: names.to_vec(),
-            type_url: resource_type.type_url().into(),
-            ..DiscoveryRequest::default()
-        };
-
         tracing::trace!(r#type=%resource_type, ?names, "sending discovery request");
-        requests.send(request).map_err(From::from).map(drop)
+        match requests {
+            Requests::StateOfTheWorld(requests) => {
+                let request = DiscoveryRequest {
+                    node: Some(Self::node(identifier)),
+                    resource_names: names.to_vec(),
+                    type_url: resource_type.type_url().into(),
+                    ..DiscoveryRequest::default()
+                };
+                requests.send(request).map_err(From::from).map(drop)
+            }
+            Requests::Delta(requests, versions) => {
+                let request = DeltaDiscoveryRequest {
+                    node: Some(Self::node(identifier)),
+                    resource_names_subscribe: names.to_vec(),
+                    type_url: resource_type.type_url().into(),
+                    initial_resource_versions: versions.lock()[resource_type]
+                        .iter()
+                        .map(|(name, version)| (name.clone(), version.clone()))
+                        .collect(),
+                    ..DeltaDiscoveryRequest::default()
+                };
+                requests.send(request).map_err(From::from).map(drop)
+            }
+        }
+    }
+
+    fn node(identifier: &str) -> Node {
+        Node {
+            id: identifier.into(),
+            user_agent_name: "quilkin".into(),
+            ..Node::default()
+        }
+    }
+}
+
+/// Returns the node metadata acknowledging a response, with the hash of the
+/// config it resulted in and the invalid endpoints that were skipped when
+/// applying it. Skipped endpoints are reported here rather than in
+/// `error_detail`, as that would reject the response, which management
+/// servers answer by sending it again.
+fn ack_metadata(hash: Option<ConfigHash>, skipped: &[String]) -> Option<prost_types::Struct> {
+    let mut metadata = hash.map(ConfigHash::to_node_metadata);
+    if !skipped.is_empty() {
+        metadata.get_or_insert_with(Default::default).fields.insert(
+            SKIPPED_ENDPOINTS_METADATA_KEY.into(),
+            prost_types::Value {
+                kind: Some(prost_types::value::Kind::StringValue(skipped.join("; "))),
+            },
+        );
     }
+    metadata
 }
 
 impl Drop for Stream {
@@ -0,0 +1,216 @@
+/*
+ * Copyright 2023 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! How proxies authenticate their management servers over TLS, and
+//! authenticate themselves to them with client certificates or the bearer
+//! tokens of `--rbac` roles, for control planes across untrusted networks.
+
+use std::path::PathBuf;
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use tonic::{
+    metadata::AsciiMetadataValue,
+    transport::{Certificate, ClientTlsConfig, Endpoint, Identity},
+};
+
+const AUTHORIZATION_HEADER: &str = "authorization";
+
+/// How a proxy connects to its management servers. Every field is optional,
+/// and connections are only made over TLS when one of the TLS fields is set,
+/// or a management server's URL is `https`.
+#[derive(Clone, Debug, Default, Deserialize, Serialize, JsonSchema, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct ClientAuth {
+    /// The path of a PEM bundle of the certificate authorities management
+    /// servers' certificates are verified with, in addition to the system's.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ca_certificate: Option<PathBuf>,
+    /// The path of a PEM certificate presented to management servers, for
+    /// mutual TLS. Requires `client_key`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client_certificate: Option<PathBuf>,
+    /// The path of the PEM private key of `client_certificate`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client_key: Option<PathBuf>,
+    /// The name management servers' certificates are verified against, and
+    /// sent with SNI, rather than the host of their URL.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub server_name: Option<String>,
+    /// The bearer token sent to management servers with every request. It's
+    /// never serialized, so that it isn't served by the admin server.
+    #[serde(default, skip_serializing)]
+    pub token: Option<String>,
+}
+
+impl ClientAuth {
+    pub fn is_default(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// Returns the fields set in `self`, falling back to those of `other`.
+    pub fn or(self, other: &Self) -> Self {
+        Self {
+            ca_certificate: self.ca_certificate.or_else(|| other.ca_certificate.clone()),
+            client_certificate: self
+                .client_certificate
+                .or_else(|| other.client_certificate.clone()),
+            client_key: self.client_key.or_else(|| other.client_key.clone()),
+            server_name: self.server_name.or_else(|| other.server_name.clone()),
+            token: self.token.or_else(|| other.token.clone()),
+        }
+    }
+
+    fn uses_tls(&self) -> bool {
+        self.ca_certificate.is_some()
+            || self.client_certificate.is_some()
+            || self.server_name.is_some()
+    }
+
+    /// Reads the certificates and key, returning the TLS config of the
+    /// connections to `endpoint`, if they use TLS.
+    fn tls_config(&self, endpoint: &Endpoint) -> crate::Result<Option<ClientTlsConfig>> {
+        let https = endpoint.uri().scheme_str() == Some("https");
+        if !https && !self.uses_tls() {
+            return Ok(None);
+        }
+
+        let mut tls = ClientTlsConfig::new();
+        if let Some(path) = &self.ca_certificate {
+            tls = tls.ca_certificate(Certificate::from_pem(std::fs::read(path)?));
+        }
+        match (&self.client_certificate, &self.client_key) {
+            (Some(certificate), Some(key)) => {
+                tls = tls.identity(Identity::from_pem(
+                    std::fs::read(certificate)?,
+                    std::fs::read(key)?,
+                ));
+            }
+            (None, None) => {}
+            _ => {
+                return Err(eyre::eyre!(
+                    "`client_certificate` and `client_key` must be set together"
+                ))
+            }
+        }
+        if let Some(name) = &self.server_name {
+            tls = tls.domain_name(name);
+        }
+
+        Ok(Some(tls))
+    }
+
+    /// Applies the TLS config to every endpoint in `endpoints`.
+    pub(crate) fn apply_tls(&self, endpoints: Vec<Endpoint>) -> crate::Result<Vec<Endpoint>> {
+        endpoints
+            .into_iter()
+            .map(|endpoint| match self.tls_config(&endpoint)? {
+                Some(tls) => Ok(endpoint.tls_config(tls)?),
+                None => Ok(endpoint),
+            })
+            .collect()
+    }
+
+    pub(crate) fn bearer_token(&self) -> crate::Result<BearerToken> {
+        self.token
+            .as_ref()
+            .map(|token| format!("Bearer {token}").parse::<AsciiMetadataValue>())
+            .transpose()
+            .map(BearerToken)
+            .map_err(|_| eyre::eyre!("the xDS bearer token must be ASCII"))
+    }
+}
+
+/// Adds the bearer token, if any, to the metadata of every request.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct BearerToken(Option<AsciiMetadataValue>);
+
+impl tonic::service::Interceptor for BearerToken {
+    fn call(
+        &mut self,
+        mut request: tonic::Request<()>,
+    ) -> Result<tonic::Request<()>, tonic::Status> {
+        if let Some(token) = &self.0 {
+            request
+                .metadata_mut()
+                .insert(AUTHORIZATION_HEADER, token.clone());
+        }
+
+        Ok(request)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tls_config() {
+        let endpoint = |url: &'static str| Endpoint::from_static(url);
+
+        let auth = ClientAuth::default();
+        assert!(auth
+            .tls_config(&endpoint("http://localhost:7800"))
+            .unwrap()
+            .is_none());
+        assert!(auth
+            .tls_config(&endpoint("https://localhost:7800"))
+            .unwrap()
+            .is_some());
+
+        let auth = ClientAuth {
+            server_name: Some("quilkin-manage".into()),
+            ..<_>::default()
+        };
+        assert!(auth
+            .tls_config(&endpoint("http://localhost:7800"))
+            .unwrap()
+            .is_some());
+
+        let auth = ClientAuth {
+            client_certificate: Some("cert.pem".into()),
+            ..<_>::default()
+        };
+        assert!(auth.tls_config(&endpoint("http://localhost:7800")).is_err());
+    }
+
+    #[test]
+    fn or() {
+        let flags = ClientAuth {
+            token: Some("flag".into()),
+            ..<_>::default()
+        };
+        let config = ClientAuth {
+            server_name: Some("quilkin-manage".into()),
+            token: Some("config".into()),
+            ..<_>::default()
+        };
+
+        let auth = flags.or(&config);
+        assert_eq!(Some("flag"), auth.token.as_deref());
+        assert_eq!(Some("quilkin-manage"), auth.server_name.as_deref());
+    }
+
+    #[test]
+    fn token_is_never_serialized() {
+        let auth = ClientAuth {
+            token: Some("secret".into()),
+            ..<_>::default()
+        };
+        assert_eq!("{}\n", serde_yaml::to_string(&auth).unwrap());
+        assert!(auth.bearer_token().unwrap().0.is_some());
+    }
+}
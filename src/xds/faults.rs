@@ -0,0 +1,150 @@
+/*
+ * Copyright 2023 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Failure injection for the management server, to test how proxies behave
+//! while their control plane misbehaves. Faults can only be installed in a
+//! [`ControlPlane`][crate::xds::ControlPlane] when the crate is built with the
+//! `failure-injection` feature.
+
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use tokio::sync::broadcast;
+
+/// A fault injected into every open stream of a management server.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Fault {
+    /// Ends the stream with an error.
+    Reset,
+    /// Sends the first response of the stream again, with its old version
+    /// and resources.
+    Rollback,
+}
+
+/// Controls for the faults injected into a management server.
+#[derive(Clone)]
+pub struct Faults(Arc<Inner>);
+
+struct Inner {
+    faults: broadcast::Sender<Fault>,
+    delay_ms: AtomicU64,
+}
+
+impl Default for Faults {
+    fn default() -> Self {
+        Self(Arc::new(Inner {
+            faults: broadcast::channel(16).0,
+            delay_ms: AtomicU64::new(0),
+        }))
+    }
+}
+
+impl Faults {
+    /// Ends every open stream with an `UNAVAILABLE` error, as if the server
+    /// had restarted.
+    pub fn reset_streams(&self) {
+        self.inject(Fault::Reset);
+    }
+
+    /// Delays every response by `delay`, until set back to zero.
+    pub fn delay_responses(&self, delay: Duration) {
+        self.0
+            .delay_ms
+            .store(delay.as_millis() as u64, Ordering::Relaxed);
+    }
+
+    /// Sends every open stream its first response again, rolling the proxies
+    /// back to an older version of the config.
+    pub fn roll_back(&self) {
+        self.inject(Fault::Rollback);
+    }
+
+    fn inject(&self, fault: Fault) {
+        tracing::info!(?fault, "injecting fault into xDS streams");
+        // No streams being open isn't an error.
+        self.0.faults.send(fault).ok();
+    }
+
+    pub(crate) fn subscribe(&self) -> Subscription {
+        Subscription {
+            faults: Some(self.0.faults.subscribe()),
+            inner: Some(self.0.clone()),
+        }
+    }
+}
+
+/// The faults injected into a single stream, which never has any faults
+/// when the server doesn't have [`Faults`] installed.
+#[derive(Default)]
+pub(crate) struct Subscription {
+    faults: Option<broadcast::Receiver<Fault>>,
+    inner: Option<Arc<Inner>>,
+}
+
+impl Subscription {
+    /// Waits for the next fault injected into the stream.
+    pub(crate) async fn next(&mut self) -> Fault {
+        loop {
+            let Some(faults) = &mut self.faults else {
+                return std::future::pending().await;
+            };
+
+            match faults.recv().await {
+                Ok(fault) => return fault,
+                Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                Err(broadcast::error::RecvError::Closed) => self.faults = None,
+            }
+        }
+    }
+
+    /// Waits for the configured response delay, if any.
+    pub(crate) async fn delay(&self) {
+        let delay = self
+            .inner
+            .as_ref()
+            .map_or(0, |inner| inner.delay_ms.load(Ordering::Relaxed));
+
+        if delay > 0 {
+            tokio::time::sleep(Duration::from_millis(delay)).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn subscription() {
+        let faults = Faults::default();
+        let mut subscription = faults.subscribe();
+
+        faults.reset_streams();
+        faults.roll_back();
+        assert_eq!(Fault::Reset, subscription.next().await);
+        assert_eq!(Fault::Rollback, subscription.next().await);
+
+        let mut none = Subscription::default();
+        assert!(tokio::time::timeout(Duration::from_millis(10), none.next())
+            .await
+            .is_err());
+    }
+}
@@ -15,11 +15,13 @@
  */
 
 use once_cell::sync::Lazy;
-use prometheus::{IntCounterVec, IntGaugeVec};
+use prometheus::{GaugeVec, IntCounterVec, IntGaugeVec};
 
 pub(crate) const CONTROL_PLANE_LABEL: &str = "control_plane";
 pub(crate) const NODE_LABEL: &str = "node";
 pub(crate) const TYPE_LABEL: &str = "type";
+pub(crate) const ROLE_LABEL: &str = "role";
+pub(crate) const REASON_LABEL: &str = "reason";
 
 pub(crate) static ACTIVE_XDS_CLIENTS: Lazy<IntGaugeVec> = Lazy::new(|| {
     prometheus::register_int_gauge_vec_with_registry! {
@@ -81,6 +83,66 @@ pub(crate) static NACKS: Lazy<IntCounterVec> = Lazy::new(|| {
     .unwrap()
 });
 
+pub(crate) static RBAC_REJECTIONS: Lazy<IntCounterVec> = Lazy::new(|| {
+    prometheus::register_int_counter_vec_with_registry! {
+        prometheus::opts! {
+            "xds_rbac_rejections",
+            "Total number of xDS requests or resources rejected by access control",
+        },
+        &[ROLE_LABEL, REASON_LABEL],
+        crate::metrics::registry(),
+    }
+    .unwrap()
+});
+
+pub(crate) static PROXY_ACTIVE_SESSIONS: Lazy<IntGaugeVec> = Lazy::new(|| {
+    prometheus::register_int_gauge_vec_with_registry! {
+        prometheus::opts! {
+            "xds_proxy_active_sessions",
+            "Number of active sessions reported by each proxy",
+        },
+        &[NODE_LABEL],
+        crate::metrics::registry(),
+    }
+    .unwrap()
+});
+
+pub(crate) static PROXY_PACKETS_PER_SECOND: Lazy<GaugeVec> = Lazy::new(|| {
+    prometheus::register_gauge_vec_with_registry! {
+        prometheus::opts! {
+            "xds_proxy_packets_per_second",
+            "Rate of packets received from downstream reported by each proxy",
+        },
+        &[NODE_LABEL],
+        crate::metrics::registry(),
+    }
+    .unwrap()
+});
+
+pub(crate) static PROXY_PACKETS_DROPPED: Lazy<IntCounterVec> = Lazy::new(|| {
+    prometheus::register_int_counter_vec_with_registry! {
+        prometheus::opts! {
+            "xds_proxy_packets_dropped",
+            "Total number of dropped packets reported by each proxy",
+        },
+        &[NODE_LABEL],
+        crate::metrics::registry(),
+    }
+    .unwrap()
+});
+
+pub(crate) static PROXY_CONFIG_DRIFT: Lazy<IntGaugeVec> = Lazy::new(|| {
+    prometheus::register_int_gauge_vec_with_registry! {
+        prometheus::opts! {
+            "xds_proxy_config_drift",
+            "Set to 1 when the config a proxy acknowledged doesn't match the config it was sent",
+        },
+        &[NODE_LABEL, TYPE_LABEL],
+        crate::metrics::registry(),
+    }
+    .unwrap()
+});
+
 pub struct StreamConnectionMetrics {
     node: String,
 }
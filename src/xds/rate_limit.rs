@@ -0,0 +1,469 @@
+/*
+ * Copyright 2023 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Fleet-wide rate limits, which share the usage of sources close to their
+//! limit through the management server, so that a source spreading its
+//! traffic across proxies is still limited by the fleet as a whole.
+//!
+//! Sharing is approximate and eventually consistent: usage is exchanged
+//! every [`SYNC_INTERVAL`], so a source may exceed its limit by the packets
+//! it sends before the other proxies learn about them.
+
+use std::{
+    collections::HashMap,
+    pin::Pin,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use dashmap::DashMap;
+
+use crate::{endpoint::EndpointAddress, xds::client::Channel};
+
+pub use super::quilkin::rate_limit::v1alpha1::{
+    rate_limit_service_client::RateLimitServiceClient,
+    rate_limit_service_server::{RateLimitService, RateLimitServiceServer},
+    FleetUsage, Usage, UsageReport,
+};
+
+/// How often proxies and the management server exchange usage.
+pub const SYNC_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The usage of the shared rate limits of a [`Config`], as counted by this
+/// proxy and by the rest of the fleet, which is shared between clones.
+///
+/// [`Config`]: crate::Config
+#[derive(Clone, Debug, Default)]
+pub struct SharedRateLimits(Arc<Shared>);
+
+#[derive(Debug, Default)]
+struct Shared {
+    /// The usage counted by this proxy since its last report, keyed by
+    /// [`key`], as the packets and the window of the limit in milliseconds.
+    pending: DashMap<String, (u64, u64)>,
+    /// The usage counted by the rest of the fleet, keyed by [`key`].
+    fleet: DashMap<String, FleetPackets>,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct FleetPackets {
+    packets: u64,
+    expires_at: Instant,
+}
+
+fn key(limit: &str, source: &EndpointAddress) -> String {
+    format!("{limit}/{source}")
+}
+
+impl SharedRateLimits {
+    /// Records that `source` sent `packets` packets in the current `window`
+    /// of the shared `limit`, to be reported to the management server.
+    pub(crate) fn record(
+        &self,
+        limit: &str,
+        source: &EndpointAddress,
+        packets: u64,
+        window: Duration,
+    ) {
+        let window_ms = window.as_millis() as u64;
+        self.0
+            .pending
+            .entry(key(limit, source))
+            .and_modify(|usage| *usage = (usage.0.max(packets), window_ms))
+            .or_insert((packets, window_ms));
+    }
+
+    /// The packets the rest of the fleet counted for `source` against the
+    /// shared `limit` in its current window.
+    pub(crate) fn fleet_packets(&self, limit: &str, source: &EndpointAddress) -> u64 {
+        // Avoids building the key for every packet while no source is close
+        // to its limit anywhere in the fleet.
+        if self.0.fleet.is_empty() {
+            return 0;
+        }
+
+        self.0
+            .fleet
+            .get(&key(limit, source))
+            .filter(|usage| usage.expires_at > Instant::now())
+            .map_or(0, |usage| usage.packets)
+    }
+
+    /// Takes the usage recorded since the last report.
+    fn take_report(&self, id: &str) -> UsageReport {
+        let keys = self
+            .0
+            .pending
+            .iter()
+            .map(|entry| entry.key().clone())
+            .collect::<Vec<_>>();
+
+        UsageReport {
+            id: id.into(),
+            usages: keys
+                .into_iter()
+                .filter_map(|key| self.0.pending.remove(&key))
+                .map(|(key, (packets, window_ms))| Usage {
+                    key,
+                    packets,
+                    window_ms,
+                })
+                .collect(),
+        }
+    }
+
+    /// Updates the usage of the rest of the fleet with `fleet`, keeping
+    /// earlier usage until its window ends.
+    pub(crate) fn apply(&self, fleet: FleetUsage) {
+        let now = Instant::now();
+        self.0.fleet.retain(|_, usage| usage.expires_at > now);
+        for usage in fleet.usages {
+            self.0.fleet.insert(
+                usage.key,
+                FleetPackets {
+                    packets: usage.packets,
+                    expires_at: now + Duration::from_millis(usage.window_ms),
+                },
+            );
+        }
+    }
+}
+
+/// Shares the usage of this proxy's shared rate limits with the management
+/// server, and receives the usage of the rest of the fleet, until dropped.
+pub struct Sharer(tokio::task::JoinHandle<()>);
+
+impl Sharer {
+    pub(crate) fn spawn(id: String, channel: Channel, limits: SharedRateLimits) -> Self {
+        Self(tokio::spawn(async move {
+            let mut client = RateLimitServiceClient::new(channel);
+            loop {
+                match client
+                    .share_usage(reports(id.clone(), limits.clone()))
+                    .await
+                {
+                    Ok(response) => {
+                        let mut fleet = response.into_inner();
+                        loop {
+                            match fleet.message().await {
+                                Ok(Some(usage)) => limits.apply(usage),
+                                Ok(None) => {
+                                    tracing::debug!(
+                                        "rate limit stream closed by management server"
+                                    );
+                                    break;
+                                }
+                                Err(error) => {
+                                    tracing::warn!(%error, "rate limit stream failed, retrying");
+                                    break;
+                                }
+                            }
+                        }
+                    }
+                    Err(error) => tracing::warn!(%error, "rate limit stream failed, retrying"),
+                }
+
+                // The fleet's usage can't be kept up to date while
+                // disconnected, so limits fall back to being local.
+                limits.0.fleet.clear();
+                tokio::time::sleep(SYNC_INTERVAL).await;
+            }
+        }))
+    }
+}
+
+impl Drop for Sharer {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+fn reports(id: String, limits: SharedRateLimits) -> impl futures::Stream<Item = UsageReport> {
+    async_stream::stream! {
+        let mut ticker = tokio::time::interval(SYNC_INTERVAL);
+        ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            ticker.tick().await;
+            yield limits.take_report(&id);
+        }
+    }
+}
+
+/// The usage a proxy reported for a key, until its window ends.
+#[derive(Clone, Copy, Debug)]
+struct Contribution {
+    packets: u64,
+    expires_at: Instant,
+}
+
+/// The usage reported by every proxy connected to the management server,
+/// keyed by [`Usage::key`] and then by the ID of the proxy.
+#[derive(Clone, Debug, Default)]
+pub struct FleetRateLimits(Arc<DashMap<String, HashMap<String, Contribution>>>);
+
+impl FleetRateLimits {
+    /// Records the usage in `report` as that of the proxy with `id`, which is
+    /// the authenticated peer rather than [`UsageReport::id`], so that a
+    /// proxy can't report usage on behalf of another.
+    pub(crate) fn record(&self, id: &str, report: UsageReport) {
+        let now = Instant::now();
+        for usage in report.usages {
+            self.0.entry(usage.key).or_default().insert(
+                id.to_owned(),
+                Contribution {
+                    packets: usage.packets,
+                    expires_at: now + Duration::from_millis(usage.window_ms),
+                },
+            );
+        }
+    }
+
+    /// Returns the usage reported by every proxy other than `id`, summed by
+    /// key, with the time left until the last of them expires as the window.
+    pub(crate) fn fleet(&self, id: &str) -> FleetUsage {
+        let now = Instant::now();
+        self.0.retain(|_, contributions| {
+            contributions.retain(|_, contribution| contribution.expires_at > now);
+            !contributions.is_empty()
+        });
+
+        FleetUsage {
+            usages: self
+                .0
+                .iter()
+                .filter_map(|entry| {
+                    let others = entry
+                        .value()
+                        .iter()
+                        .filter(|(proxy, _)| *proxy != id)
+                        .map(|(_, contribution)| contribution);
+                    let (packets, expires_at) = others.fold(
+                        (0, None::<Instant>),
+                        |(packets, expires_at), contribution| {
+                            (
+                                packets + contribution.packets,
+                                Some(expires_at.map_or(contribution.expires_at, |expires_at| {
+                                    expires_at.max(contribution.expires_at)
+                                })),
+                            )
+                        },
+                    );
+
+                    expires_at.map(|expires_at| Usage {
+                        key: entry.key().clone(),
+                        packets,
+                        window_ms: (expires_at - now).as_millis() as u64,
+                    })
+                })
+                .collect(),
+        }
+    }
+
+    /// Removes the usage reported by the proxy with `id`.
+    pub(crate) fn remove(&self, id: &str) {
+        self.0.retain(|_, contributions| {
+            contributions.remove(id);
+            !contributions.is_empty()
+        });
+    }
+}
+
+pub(crate) type FleetUsageStream =
+    Pin<Box<dyn futures::Stream<Item = Result<FleetUsage, tonic::Status>> + Send>>;
+
+/// Returns the ID the usage of a proxy is recorded under, from the `role` it
+/// authenticated as, if any, and the `address` it's connected from, so that
+/// every stream has its own ID that its peer can't choose.
+pub(crate) fn contributor_id(role: Option<&str>, address: Option<std::net::SocketAddr>) -> String {
+    let address = address.map_or_else(|| "unknown".into(), |address| address.to_string());
+    match role {
+        Some(role) => format!("{role}@{address}"),
+        None => address,
+    }
+}
+
+/// Receives the reports of a single proxy, recording them under `id`, see
+/// [`contributor_id`], and streams back the usage of the rest of the fleet
+/// every [`SYNC_INTERVAL`], until the proxy disconnects.
+pub(crate) fn share_usage(
+    limits: FleetRateLimits,
+    id: String,
+    mut reports: tonic::Streaming<UsageReport>,
+) -> FleetUsageStream {
+    Box::pin(async_stream::try_stream! {
+        let mut contributor = Contributor { limits, id: None };
+        let mut ticker = tokio::time::interval(SYNC_INTERVAL);
+        ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            let report = tokio::select! {
+                report = reports.message() => Some(report),
+                _ = ticker.tick() => None,
+            };
+
+            match report {
+                Some(report) => match report? {
+                    Some(report) => {
+                        tracing::trace!(%id, reported_id = %report.id, usages = report.usages.len(), "received rate limit usage");
+                        contributor.id = Some(id.clone());
+                        contributor.limits.record(&id, report);
+                    }
+                    None => break,
+                },
+                None => {
+                    if let Some(id) = &contributor.id {
+                        yield contributor.limits.fleet(id);
+                    }
+                }
+            }
+        }
+    })
+}
+
+/// Removes the usage of a proxy once its stream ends, as it's only kept up
+/// to date while the proxy is connected.
+struct Contributor {
+    limits: FleetRateLimits,
+    id: Option<String>,
+}
+
+impl Drop for Contributor {
+    fn drop(&mut self) {
+        if let Some(id) = &self.id {
+            self.limits.remove(id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    fn usage(key: &str, packets: u64) -> Usage {
+        Usage {
+            key: key.into(),
+            packets,
+            window_ms: 60_000,
+        }
+    }
+
+    #[test]
+    fn fleet_excludes_own_usage() {
+        let limits = FleetRateLimits::default();
+        limits.record(
+            "a",
+            UsageReport {
+                id: "c".into(),
+                usages: vec![usage("game/1.1.1.1:1", 10), usage("game/2.2.2.2:2", 5)],
+            },
+        );
+        limits.record(
+            "b",
+            UsageReport {
+                id: "c".into(),
+                usages: vec![usage("game/1.1.1.1:1", 20)],
+            },
+        );
+
+        let packets = |id: &str| {
+            let mut usages = limits
+                .fleet(id)
+                .usages
+                .into_iter()
+                .map(|usage| (usage.key, usage.packets))
+                .collect::<Vec<_>>();
+            usages.sort();
+            usages
+        };
+
+        assert_eq!(vec![("game/1.1.1.1:1".to_owned(), 20)], packets("a"));
+        assert_eq!(
+            vec![
+                ("game/1.1.1.1:1".to_owned(), 10),
+                ("game/2.2.2.2:2".to_owned(), 5)
+            ],
+            packets("b")
+        );
+        assert_eq!(
+            vec![
+                ("game/1.1.1.1:1".to_owned(), 30),
+                ("game/2.2.2.2:2".to_owned(), 5)
+            ],
+            packets("c")
+        );
+
+        limits.remove("a");
+        assert_eq!(vec![("game/1.1.1.1:1".to_owned(), 20)], packets("c"));
+    }
+
+    #[test]
+    fn contributor_ids() {
+        let address = Some((Ipv4Addr::new(10, 0, 0, 1), 7000).into());
+        assert_eq!(
+            "us-east@10.0.0.1:7000",
+            contributor_id(Some("us-east"), address)
+        );
+        assert_eq!("10.0.0.1:7000", contributor_id(None, address));
+        assert_eq!("unknown", contributor_id(None, None));
+    }
+
+    #[test]
+    fn usage_expires() {
+        let limits = FleetRateLimits::default();
+        limits.record(
+            "a",
+            UsageReport {
+                id: "c".into(),
+                usages: vec![Usage {
+                    window_ms: 0,
+                    ..usage("game/1.1.1.1:1", 10)
+                }],
+            },
+        );
+
+        assert!(limits.fleet("b").usages.is_empty());
+        assert!(limits.0.is_empty());
+    }
+
+    #[test]
+    fn record_and_apply() {
+        let limits = SharedRateLimits::default();
+        let source = (Ipv4Addr::new(10, 0, 0, 1), 7777).into();
+        limits.record("record_and_apply", &source, 10, Duration::from_secs(1));
+        limits.record("record_and_apply", &source, 5, Duration::from_secs(1));
+
+        let report = limits.take_report("proxy");
+        let usage = report
+            .usages
+            .iter()
+            .find(|usage| usage.key == "record_and_apply/10.0.0.1:7777")
+            .unwrap();
+        assert_eq!(10, usage.packets);
+        assert_eq!(1000, usage.window_ms);
+
+        assert_eq!(0, limits.fleet_packets("record_and_apply", &source));
+        limits.apply(FleetUsage {
+            usages: vec![usage.clone()],
+        });
+        assert_eq!(10, limits.fleet_packets("record_and_apply", &source));
+        assert_eq!(
+            0,
+            SharedRateLimits::default().fleet_packets("record_and_apply", &source)
+        );
+        assert!(limits.take_report("proxy").usages.is_empty());
+    }
+}
@@ -0,0 +1,332 @@
+/*
+ * Copyright 2023 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Role based access control for the management server.
+
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+
+use crate::xds::{metrics, ResourceType};
+
+/// The wildcard cluster name, granting access to every cluster.
+pub const ANY_CLUSTER: &str = "*";
+
+const AUTHORIZATION_HEADER: &str = "authorization";
+const BEARER_PREFIX: &str = "Bearer ";
+
+/// A set of roles that xDS clients can authenticate as.
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct Rbac {
+    pub roles: Vec<Arc<Role>>,
+}
+
+/// A role that clients holding one of its `tokens` are granted, restricting
+/// which resources they are able to access.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct Role {
+    /// The name of the role, used for audit logging.
+    pub name: String,
+    /// The bearer tokens that identify a client as having this role.
+    #[serde(default)]
+    pub tokens: Vec<String>,
+    /// The names of the clusters this role is allowed to read, `*` allows
+    /// access to all clusters, and `<namespace>/*` to every cluster in a
+    /// namespace.
+    #[serde(default)]
+    pub clusters: Vec<String>,
+    /// Whether this role is allowed to read the filter chain.
+    #[serde(default)]
+    pub filters: bool,
+    /// The names of the clusters this role is allowed to register endpoints
+    /// in, matched the same way as `clusters`.
+    #[serde(default)]
+    pub register: Vec<String>,
+    /// Whether this role is allowed to peer with the management server,
+    /// exchanging the endpoints registered with each server by their proxies.
+    /// The endpoints a peer shares are limited to the clusters in `register`.
+    #[serde(default)]
+    pub peer: bool,
+}
+
+impl Rbac {
+    /// Reads the RBAC configuration from a YAML or JSON source.
+    pub fn from_reader<R: std::io::Read>(input: R) -> Result<Self, eyre::Error> {
+        Ok(serde_yaml::from_reader(input)?)
+    }
+
+    /// Finds the role matching the bearer token in the `authorization`
+    /// header of `metadata`. Clients are only authenticated by their token,
+    /// not by a client certificate.
+    pub fn authenticate(
+        &self,
+        metadata: &tonic::metadata::MetadataMap,
+    ) -> Result<Arc<Role>, tonic::Status> {
+        let token = metadata
+            .get(AUTHORIZATION_HEADER)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix(BEARER_PREFIX));
+
+        let Some(token) = token else {
+            reject(None, None, "missing_token");
+            return Err(tonic::Status::unauthenticated("bearer token required"));
+        };
+
+        self.role(token).ok_or_else(|| {
+            reject(None, None, "unknown_token");
+            tonic::Status::unauthenticated("unknown bearer token")
+        })
+    }
+
+    /// The role identified by `token`, if any.
+    pub fn role(&self, token: &str) -> Option<Arc<Role>> {
+        self.roles
+            .iter()
+            .find(|role| {
+                role.tokens.iter().any(|candidate| {
+                    crate::utils::constant_time_eq(candidate.as_bytes(), token.as_bytes())
+                })
+            })
+            .cloned()
+    }
+}
+
+impl Role {
+    /// Whether this role is allowed to read the cluster named `cluster`.
+    pub fn can_read_cluster(&self, cluster: &str) -> bool {
+        matches_cluster(&self.clusters, cluster)
+    }
+
+    /// Whether this role is allowed to register endpoints in the cluster
+    /// named `cluster`.
+    pub fn can_register_cluster(&self, cluster: &str) -> bool {
+        matches_cluster(&self.register, cluster)
+    }
+
+    /// Checks that this role is allowed to register endpoints in the cluster
+    /// named `cluster`, logging an audit event with the registration's
+    /// reported `id` if it isn't.
+    pub fn authorize_registration(&self, id: &str, cluster: &str) -> Result<(), tonic::Status> {
+        if self.can_register_cluster(cluster) {
+            return Ok(());
+        }
+
+        tracing::warn!(
+            role = %self.name,
+            registration = %id,
+            %cluster,
+            "rejected registration in cluster"
+        );
+        metrics::RBAC_REJECTIONS
+            .with_label_values(&[&*self.name, "register_forbidden"])
+            .inc();
+        Err(tonic::Status::permission_denied(format!(
+            "role `{}` can't register endpoints in cluster `{cluster}`",
+            self.name
+        )))
+    }
+
+    /// The ID that the registration a client of this role reports as `id` is
+    /// stored under, so that clients can only replace or remove the
+    /// registrations of their own role.
+    pub fn registration_id(&self, id: &str) -> String {
+        format!("{}/{id}", self.name)
+    }
+
+    /// Checks that this role is allowed to peer with the management server,
+    /// logging an audit event if it isn't.
+    pub fn authorize_peer(&self) -> Result<(), tonic::Status> {
+        if self.peer {
+            Ok(())
+        } else {
+            reject(Some(self), None, "peer_forbidden");
+            Err(tonic::Status::permission_denied(format!(
+                "role `{}` is not allowed to peer",
+                self.name
+            )))
+        }
+    }
+
+    /// Checks that this role is allowed to request resources of
+    /// `resource_type`, logging an audit event if it isn't.
+    pub fn authorize(&self, node: &str, resource_type: ResourceType) -> Result<(), tonic::Status> {
+        let allowed = match resource_type {
+            ResourceType::Listener => self.filters,
+            ResourceType::Cluster | ResourceType::Endpoint => !self.clusters.is_empty(),
+            _ => false,
+        };
+
+        if allowed {
+            Ok(())
+        } else {
+            reject(
+                Some(self),
+                Some((node, resource_type)),
+                "resource_forbidden",
+            );
+            Err(tonic::Status::permission_denied(format!(
+                "role `{}` is not allowed to read {}",
+                self.name,
+                resource_type.type_url()
+            )))
+        }
+    }
+
+    /// Records that `cluster` was withheld from `node`.
+    pub(crate) fn reject_cluster(&self, node: &str, resource_type: ResourceType, cluster: &str) {
+        tracing::warn!(
+            role = %self.name,
+            %node,
+            r#type = resource_type.type_url(),
+            %cluster,
+            "rejected access to cluster"
+        );
+        metrics::RBAC_REJECTIONS
+            .with_label_values(&[&*self.name, "cluster_forbidden"])
+            .inc();
+    }
+}
+
+fn matches_cluster(names: &[String], cluster: &str) -> bool {
+    names.iter().any(|name| {
+        name == ANY_CLUSTER
+            || name == cluster
+            || name
+                .strip_suffix(ANY_CLUSTER)
+                .and_then(|prefix| prefix.strip_suffix(crate::cluster::NAMESPACE_SEPARATOR))
+                .map_or(false, |namespace| {
+                    crate::cluster::namespace_of(cluster) == Some(namespace)
+                })
+    })
+}
+
+fn reject(role: Option<&Role>, request: Option<(&str, ResourceType)>, reason: &str) {
+    let role = role.map(|role| &*role.name).unwrap_or_default();
+    let (node, resource_type) = request
+        .map(|(node, resource_type)| (node, resource_type.type_url()))
+        .unwrap_or_default();
+    tracing::warn!(%role, %node, r#type = resource_type, %reason, "rejected xDS request");
+    metrics::RBAC_REJECTIONS
+        .with_label_values(&[role, reason])
+        .inc();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rbac() -> Rbac {
+        Rbac::from_reader(
+            "
+roles:
+  - name: us-east
+    tokens: [abc]
+    clusters: [us-east-1, 'studio/*']
+    register: [us-east-1]
+  - name: admin
+    tokens: [xyz]
+    clusters: ['*']
+    filters: true
+    peer: true
+"
+            .as_bytes(),
+        )
+        .unwrap()
+    }
+
+    fn metadata(value: &str) -> tonic::metadata::MetadataMap {
+        let mut metadata = tonic::metadata::MetadataMap::new();
+        metadata.insert(AUTHORIZATION_HEADER, value.parse().unwrap());
+        metadata
+    }
+
+    #[test]
+    fn authenticate() {
+        let rbac = rbac();
+
+        assert_eq!(
+            "us-east",
+            rbac.authenticate(&metadata("Bearer abc")).unwrap().name
+        );
+        assert_eq!(
+            "admin",
+            rbac.authenticate(&metadata("Bearer xyz")).unwrap().name
+        );
+        assert_eq!(
+            tonic::Code::Unauthenticated,
+            rbac.authenticate(&metadata("Bearer nope"))
+                .unwrap_err()
+                .code()
+        );
+        assert_eq!(
+            tonic::Code::Unauthenticated,
+            rbac.authenticate(&metadata("abc")).unwrap_err().code()
+        );
+        assert_eq!(
+            tonic::Code::Unauthenticated,
+            rbac.authenticate(&<_>::default()).unwrap_err().code()
+        );
+    }
+
+    #[test]
+    fn authorize() {
+        let rbac = rbac();
+        let (us_east, admin) = (&rbac.roles[0], &rbac.roles[1]);
+
+        assert!(us_east.authorize("node", ResourceType::Endpoint).is_ok());
+        assert!(us_east.authorize("node", ResourceType::Cluster).is_ok());
+        assert_eq!(
+            tonic::Code::PermissionDenied,
+            us_east
+                .authorize("node", ResourceType::Listener)
+                .unwrap_err()
+                .code()
+        );
+        assert!(admin.authorize("node", ResourceType::Listener).is_ok());
+
+        assert!(us_east.can_read_cluster("us-east-1"));
+        assert!(!us_east.can_read_cluster("eu-west-1"));
+        assert!(us_east.can_read_cluster("studio/eu-west-1"));
+        assert!(!us_east.can_read_cluster("other/eu-west-1"));
+        assert!(admin.can_read_cluster("eu-west-1"));
+
+        assert!(us_east.can_register_cluster("us-east-1"));
+        assert!(!us_east.can_register_cluster("studio/eu-west-1"));
+        assert!(!admin.can_register_cluster("eu-west-1"));
+        assert!(us_east.authorize_registration("a", "us-east-1").is_ok());
+        assert_eq!(
+            tonic::Code::PermissionDenied,
+            us_east
+                .authorize_registration("a", "studio/eu-west-1")
+                .unwrap_err()
+                .code()
+        );
+        assert_eq!("us-east/a", us_east.registration_id("a"));
+
+        assert_eq!(
+            tonic::Code::PermissionDenied,
+            us_east.authorize_peer().unwrap_err().code()
+        );
+        assert!(admin.authorize_peer().is_ok());
+    }
+
+    #[test]
+    fn deny_unknown_fields() {
+        assert!(Rbac::from_reader("roles: [{ name: a, write: [b] }]".as_bytes()).is_err());
+    }
+}
@@ -0,0 +1,852 @@
+/*
+ * Copyright 2023 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Endpoint registration, which lets a proxy running alongside a game server
+//! add the game server to its management server's clusters over the same
+//! connection it receives its configuration on, rather than needing a
+//! separate agent and connection per game server.
+//!
+//! Management servers can also be peered, exchanging the endpoints their own
+//! proxies registered, so that proxies in one region can fail over to the
+//! game servers of another.
+
+mod store;
+
+use std::{
+    collections::BTreeSet,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use dashmap::DashMap;
+use futures::Stream;
+use tokio::sync::watch;
+
+use crate::{
+    cluster::{Cluster, ClusterMap, DEFAULT_CLUSTER_NAME},
+    endpoint::{Endpoint, EndpointAddress, Locality, LocalityEndpoints},
+    xds::client::{BearerToken, Channel},
+    Config,
+};
+
+pub use super::quilkin::registration::v1alpha1::{
+    registration_service_client::RegistrationServiceClient,
+    registration_service_server::{RegistrationService, RegistrationServiceServer},
+    PeerState, Registration, RegistrationResponse,
+};
+#[cfg(feature = "registration-sled")]
+pub use store::Sled;
+pub use store::{Memory, Store, Stored};
+
+/// How often the endpoints of disconnected proxies are checked for expiry.
+const EXPIRY_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How long to wait before registering again after the stream fails.
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+/// Registers endpoints with the management server for as long as it's
+/// connected, until dropped.
+pub struct Registrar(tokio::task::JoinHandle<()>);
+
+impl Registrar {
+    pub(crate) fn spawn(channel: Channel, registration: Registration) -> Self {
+        Self(tokio::spawn(async move {
+            let mut client = RegistrationServiceClient::new(channel);
+            loop {
+                let registration = registration.clone();
+                let stream = async_stream::stream! {
+                    yield registration;
+                    // Endpoints stay registered while the stream is open.
+                    futures::future::pending::<()>().await;
+                };
+
+                match client.register(stream).await {
+                    Ok(_) => tracing::debug!("registration stream closed by management server"),
+                    Err(error) => tracing::warn!(%error, "registration stream failed, retrying"),
+                }
+
+                tokio::time::sleep(RECONNECT_DELAY).await;
+            }
+        }))
+    }
+}
+
+impl Drop for Registrar {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+/// Exchanges endpoints with a peered management server for as long as it's
+/// connected, until dropped.
+pub struct Peer(tokio::task::JoinHandle<()>);
+
+impl Peer {
+    /// Peers with the management server at `endpoint`, sending `token` with
+    /// every request, and checking the states it sends with `authorize`.
+    pub(crate) fn spawn(
+        endpoint: tonic::transport::Endpoint,
+        token: BearerToken,
+        authorize: impl Fn(&PeerState) -> Result<(), tonic::Status> + Send + Sync + 'static,
+        registrations: Registrations,
+        config: Arc<Config>,
+        locality: Option<Locality>,
+    ) -> Self {
+        Self(tokio::spawn(async move {
+            loop {
+                let result = async {
+                    let channel = endpoint.connect().await?;
+                    let mut client =
+                        RegistrationServiceClient::with_interceptor(channel, token.clone());
+                    let states = registrations.states(config.clone(), locality.clone());
+                    let response = client.peer(states).await?;
+                    registrations
+                        .receive(&config, response.into_inner(), &authorize)
+                        .await?;
+                    crate::Result::<()>::Ok(())
+                };
+
+                match result.await {
+                    Ok(()) => {
+                        tracing::debug!(uri = %endpoint.uri(), "peering stream closed by peer")
+                    }
+                    Err(error) => {
+                        tracing::warn!(%error, uri = %endpoint.uri(), "peering stream failed, retrying")
+                    }
+                }
+
+                tokio::time::sleep(RECONNECT_DELAY).await;
+            }
+        }))
+    }
+}
+
+impl Drop for Peer {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+/// Removes the endpoints of disconnected proxies once they expire, until
+/// dropped.
+pub struct Expiry(tokio::task::JoinHandle<()>);
+
+impl Drop for Expiry {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+/// The endpoints registered by a proxy.
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Registered {
+    cluster: String,
+    endpoints: BTreeSet<Endpoint>,
+    /// The locality of the peer the endpoints were registered with, `None`
+    /// for the server's own proxies.
+    locality: Option<Locality>,
+}
+
+impl Registered {
+    fn new(registration: &Registration, locality: Option<Locality>) -> Result<Self, tonic::Status> {
+        let endpoints = registration
+            .endpoints
+            .iter()
+            .map(|address| {
+                address
+                    .parse::<EndpointAddress>()
+                    .map(Endpoint::new)
+                    .map_err(|error| {
+                        tonic::Status::invalid_argument(format!(
+                            "invalid endpoint `{address}`: {error}"
+                        ))
+                    })
+            })
+            .collect::<Result<BTreeSet<_>, _>>()?;
+
+        Ok(Self {
+            cluster: cluster_name(registration.cluster.clone()),
+            endpoints,
+            locality,
+        })
+    }
+
+    fn stored(&self, id: &str, expires_at: Option<SystemTime>) -> Stored {
+        Stored {
+            id: id.into(),
+            cluster: self.cluster.clone(),
+            endpoints: self
+                .endpoints
+                .iter()
+                .map(|endpoint| endpoint.address.to_string())
+                .collect(),
+            expires_at_ms: expires_at.map(store::to_ms),
+        }
+    }
+}
+
+/// The endpoints registered by the proxies connected to the management
+/// server, keyed by the ID of the proxy, and those registered with its peers,
+/// keyed by the ID of the peer. Both are added to the clusters of its config.
+///
+/// The proxies' registrations are persisted in a [`Store`], and outlive the
+/// proxy's connection by a TTL, so that their endpoints survive the proxy
+/// reconnecting, or the server restarting.
+#[derive(Clone, Debug)]
+pub struct Registrations {
+    proxies: Arc<DashMap<String, Registered>>,
+    peers: Arc<DashMap<String, Vec<Registered>>>,
+    /// Notified whenever the proxies' endpoints change.
+    changes: Arc<watch::Sender<()>>,
+    store: store::Writer,
+    /// How long a proxy's endpoints stay registered after it disconnects.
+    ttl: Duration,
+    /// When the endpoints of the disconnected proxies expire, keyed by the ID
+    /// of the proxy.
+    expiries: Arc<DashMap<String, SystemTime>>,
+}
+
+impl Default for Registrations {
+    fn default() -> Self {
+        Self {
+            proxies: <_>::default(),
+            peers: <_>::default(),
+            changes: Arc::new(watch::channel(()).0),
+            store: store::Writer::new(Arc::new(Memory::default())),
+            ttl: Duration::ZERO,
+            expiries: <_>::default(),
+        }
+    }
+}
+
+impl Registrations {
+    /// Persists the proxies' registrations in `store`, keeping their
+    /// endpoints for `ttl` after they disconnect. A zero `ttl` removes them
+    /// as soon as the proxy disconnects.
+    pub fn new(store: Arc<dyn Store>, ttl: Duration) -> Self {
+        Self {
+            store: store::Writer::new(store),
+            ttl,
+            ..<_>::default()
+        }
+    }
+
+    /// Replaces the endpoints registered by the proxy with `registration.id`
+    /// in `config`.
+    pub(crate) fn register(
+        &self,
+        config: &Config,
+        registration: Registration,
+    ) -> Result<(), tonic::Status> {
+        let registered = Registered::new(&registration, None)?;
+        let expiring = self.expiries.remove(&registration.id).is_some();
+        // Proxies registering the same endpoints again, as they do whenever
+        // they reconnect, are only written to the store if they were expiring.
+        if self.insert(config, registration.id.clone(), registered.clone()) || expiring {
+            self.save(registered.stored(&registration.id, None));
+        }
+
+        Ok(())
+    }
+
+    /// Adds the endpoints of the proxy with `id` to `config`, replacing its
+    /// previous ones. Returns whether they changed.
+    fn insert(&self, config: &Config, id: String, registered: Registered) -> bool {
+        let previous = self.proxies.insert(id, registered.clone());
+        if previous.as_ref() == Some(&registered) {
+            return false;
+        }
+
+        config.clusters.modify(|clusters| {
+            if let Some(previous) = &previous {
+                remove(clusters, previous);
+            }
+
+            add(clusters, &registered);
+        });
+        self.changes.send_replace(());
+
+        true
+    }
+
+    /// Removes the endpoints registered by the proxy with `id` from `config`.
+    pub(crate) fn deregister(&self, config: &Config, id: &str) {
+        self.expiries.remove(id);
+        if let Some((_, registered)) = self.proxies.remove(id) {
+            config
+                .clusters
+                .modify(|clusters| remove(clusters, &registered));
+            self.changes.send_replace(());
+            self.store.remove(id);
+        }
+    }
+
+    /// Removes the endpoints registered by the proxy with `id` from `config`
+    /// once the TTL has passed, unless it registers again before then.
+    pub(crate) fn disconnect(&self, config: &Config, id: &str) {
+        if self.ttl.is_zero() {
+            return self.deregister(config, id);
+        }
+
+        let Some(registered) = self.proxies.get(id).map(|registered| registered.clone()) else {
+            return;
+        };
+
+        let expires_at = SystemTime::now() + self.ttl;
+        self.expiries.insert(id.into(), expires_at);
+        self.save(registered.stored(id, Some(expires_at)));
+    }
+
+    /// Removes the endpoints of the disconnected proxies whose TTL has
+    /// passed from `config`, returning how many proxies were removed.
+    pub(crate) fn expire(&self, config: &Config) -> usize {
+        let now = SystemTime::now();
+        let expired = self
+            .expiries
+            .iter()
+            .filter(|entry| *entry.value() <= now)
+            .map(|entry| entry.key().clone())
+            .collect::<Vec<_>>();
+
+        let mut removed = 0;
+        for id in expired {
+            // The proxy may have registered again since.
+            if self
+                .expiries
+                .remove_if(&id, |_, expires_at| *expires_at <= now)
+                .is_some()
+            {
+                tracing::debug!(%id, "registration expired");
+                self.deregister(config, &id);
+                removed += 1;
+            }
+        }
+
+        removed
+    }
+
+    /// Removes the endpoints of disconnected proxies from `config` once they
+    /// expire, until the returned [`Expiry`] is dropped.
+    pub fn spawn_expiry(&self, config: Arc<Config>) -> Expiry {
+        let registrations = self.clone();
+        Expiry(tokio::spawn(async move {
+            let mut interval = tokio::time::interval(EXPIRY_INTERVAL);
+            loop {
+                interval.tick().await;
+                registrations.expire(&config);
+            }
+        }))
+    }
+
+    /// Adds the registrations in the store that haven't expired to `config`,
+    /// returning how many were added. Registrations the server had while it
+    /// stopped are given the TTL to register again, as their proxies were
+    /// disconnected by it stopping. The store is read on the calling thread,
+    /// so this should be called before the server starts serving.
+    pub fn restore(&self, config: &Config) -> crate::Result<usize> {
+        let now = SystemTime::now();
+        let mut restored = 0;
+        for stored in self.store.load()? {
+            let expires_at = stored.expires_at().unwrap_or(now + self.ttl);
+            if expires_at <= now {
+                self.store.remove(&stored.id);
+                continue;
+            }
+
+            let registration = Registration {
+                id: stored.id.clone(),
+                cluster: stored.cluster,
+                endpoints: stored.endpoints,
+            };
+            let registered = match Registered::new(&registration, None) {
+                Ok(registered) => registered,
+                Err(error) => {
+                    tracing::warn!(id = %stored.id, error = %error.message(), "discarding invalid stored registration");
+                    self.store.remove(&stored.id);
+                    continue;
+                }
+            };
+
+            self.expiries.insert(stored.id.clone(), expires_at);
+            self.insert(config, stored.id.clone(), registered.clone());
+            self.save(registered.stored(&stored.id, Some(expires_at)));
+            restored += 1;
+        }
+
+        Ok(restored)
+    }
+
+    fn save(&self, stored: Stored) {
+        self.store.save(stored);
+    }
+
+    /// Blocks until the changes made to the registrations so far are
+    /// written to their [`Store`], which is otherwise done in the
+    /// background.
+    pub fn flush(&self) {
+        self.store.flush();
+    }
+
+    /// The addresses of the endpoints registered by the proxy with `id`.
+    pub fn get(&self, id: &str) -> Option<Vec<EndpointAddress>> {
+        self.proxies.get(id).map(|registered| {
+            registered
+                .endpoints
+                .iter()
+                .map(|endpoint| endpoint.address.clone())
+                .collect()
+        })
+    }
+
+    /// Replaces the endpoints registered with the peer `state.id` in
+    /// `config`. They're added to the peer's locality, or to a locality whose
+    /// region is the peer's ID if it has none.
+    pub(crate) fn apply_peer(
+        &self,
+        config: &Config,
+        state: PeerState,
+    ) -> Result<(), tonic::Status> {
+        let locality = if state.region.is_empty() {
+            Locality {
+                region: state.id.clone(),
+                ..<_>::default()
+            }
+        } else {
+            Locality {
+                region: state.region,
+                zone: state.zone,
+                sub_zone: state.sub_zone,
+            }
+        };
+
+        let registered = state
+            .registrations
+            .iter()
+            .map(|registration| Registered::new(registration, Some(locality.clone())))
+            .collect::<Result<Vec<_>, _>>()?;
+        let previous = self.peers.insert(state.id, registered.clone());
+        if previous.as_ref() == Some(&registered) {
+            return Ok(());
+        }
+
+        config.clusters.modify(|clusters| {
+            for previous in previous.iter().flatten() {
+                remove(clusters, previous);
+            }
+
+            for registered in &registered {
+                add(clusters, registered);
+            }
+        });
+
+        Ok(())
+    }
+
+    /// Removes the endpoints registered with the peer `id` from `config`.
+    pub(crate) fn remove_peer(&self, config: &Config, id: &str) {
+        if let Some((_, registered)) = self.peers.remove(id) {
+            config.clusters.modify(|clusters| {
+                for registered in &registered {
+                    remove(clusters, registered);
+                }
+            });
+        }
+    }
+
+    /// The endpoints registered by the server's own proxies, as sent to its
+    /// peers.
+    fn state(&self, id: String, locality: Option<&Locality>) -> PeerState {
+        let mut registrations = self
+            .proxies
+            .iter()
+            .map(|entry| Registration {
+                id: entry.key().clone(),
+                cluster: entry.cluster.clone(),
+                endpoints: entry
+                    .endpoints
+                    .iter()
+                    .map(|endpoint| endpoint.address.to_string())
+                    .collect(),
+            })
+            .collect::<Vec<_>>();
+        registrations.sort_by(|a, b| a.id.cmp(&b.id));
+
+        let locality = locality.cloned().unwrap_or_default();
+        PeerState {
+            id,
+            region: locality.region,
+            zone: locality.zone,
+            sub_zone: locality.sub_zone,
+            registrations,
+        }
+    }
+
+    /// Streams the server's state to a peer, and again whenever its proxies'
+    /// endpoints change.
+    pub(crate) fn states(
+        &self,
+        config: Arc<Config>,
+        locality: Option<Locality>,
+    ) -> impl Stream<Item = PeerState> + Send + 'static {
+        let registrations = self.clone();
+        let mut changes = self.changes.subscribe();
+        async_stream::stream! {
+            loop {
+                yield registrations.state(config.id.load().to_string(), locality.as_ref());
+                if changes.changed().await.is_err() {
+                    break;
+                }
+            }
+        }
+    }
+
+    /// Applies the states a peer sends on `states` until the stream ends,
+    /// then removes the peer's endpoints. Each state is checked with
+    /// `authorize` first.
+    pub(crate) async fn receive(
+        &self,
+        config: &Config,
+        mut states: tonic::Streaming<PeerState>,
+        authorize: impl Fn(&PeerState) -> Result<(), tonic::Status>,
+    ) -> Result<(), tonic::Status> {
+        let mut peer = Peered {
+            registrations: self,
+            config,
+            id: None,
+        };
+
+        while let Some(state) = states.message().await? {
+            if state.id == **config.id.load() {
+                return Err(tonic::Status::invalid_argument(format!(
+                    "peer has the same ID as this management server: `{}`",
+                    state.id
+                )));
+            }
+
+            authorize(&state)?;
+            tracing::debug!(id = %state.id, registrations = state.registrations.len(), "applying peer state");
+            if let Some(previous) = peer.id.replace(state.id.clone()) {
+                if previous != state.id {
+                    self.remove_peer(config, &previous);
+                }
+            }
+            self.apply_peer(config, state)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Disconnects a proxy's endpoints once its registration stream ends, even if
+/// the stream's handler is cancelled.
+pub(crate) struct Registrant<'a> {
+    pub registrations: &'a Registrations,
+    pub config: &'a Config,
+    pub id: Option<String>,
+}
+
+impl Drop for Registrant<'_> {
+    fn drop(&mut self) {
+        if let Some(id) = &self.id {
+            self.registrations.disconnect(self.config, id);
+        }
+    }
+}
+
+/// Removes a peer's endpoints once its stream ends, even if the stream is
+/// cancelled.
+struct Peered<'a> {
+    registrations: &'a Registrations,
+    config: &'a Config,
+    id: Option<String>,
+}
+
+impl Drop for Peered<'_> {
+    fn drop(&mut self) {
+        if let Some(id) = &self.id {
+            self.registrations.remove_peer(self.config, id);
+        }
+    }
+}
+
+fn cluster_name(cluster: String) -> String {
+    if cluster.is_empty() {
+        DEFAULT_CLUSTER_NAME.into()
+    } else {
+        cluster
+    }
+}
+
+fn add(clusters: &mut ClusterMap, registered: &Registered) {
+    if clusters.get(&registered.cluster).is_none() {
+        clusters.insert(Cluster {
+            name: registered.cluster.clone(),
+            ..Cluster::default()
+        });
+    }
+
+    clusters.get_mut(&registered.cluster).unwrap().insert(
+        LocalityEndpoints::new(registered.endpoints.clone())
+            .with_locality(registered.locality.clone()),
+    );
+}
+
+fn remove(clusters: &mut ClusterMap, registered: &Registered) {
+    if let Some(cluster) = clusters.get_mut(&registered.cluster) {
+        for locality in cluster.localities.iter_mut() {
+            for endpoint in &registered.endpoints {
+                locality.remove(endpoint);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn registration(id: &str, endpoints: &[&str]) -> Registration {
+        Registration {
+            id: id.into(),
+            cluster: String::new(),
+            endpoints: endpoints.iter().map(|&endpoint| endpoint.into()).collect(),
+        }
+    }
+
+    fn addresses(config: &Config) -> Vec<String> {
+        let mut addresses = config
+            .clusters
+            .load()
+            .endpoints()
+            .map(|endpoint| endpoint.address.to_string())
+            .collect::<Vec<_>>();
+        addresses.sort();
+        addresses
+    }
+
+    #[test]
+    fn register() {
+        let config = Config::default();
+        let registrations = Registrations::default();
+
+        registrations
+            .register(&config, registration("a", &["127.0.0.1:7000"]))
+            .unwrap();
+        registrations
+            .register(&config, registration("b", &["127.0.0.1:7001"]))
+            .unwrap();
+        assert_eq!(vec!["127.0.0.1:7000", "127.0.0.1:7001"], addresses(&config));
+
+        // A new registration replaces the proxy's previous endpoints.
+        registrations
+            .register(&config, registration("a", &["127.0.0.1:7002"]))
+            .unwrap();
+        assert_eq!(vec!["127.0.0.1:7001", "127.0.0.1:7002"], addresses(&config));
+
+        registrations.deregister(&config, "a");
+        assert_eq!(vec!["127.0.0.1:7001"], addresses(&config));
+        assert!(registrations.get("a").is_none());
+
+        assert!(registrations
+            .register(&config, registration("c", &["not an address"]))
+            .is_err());
+    }
+
+    #[test]
+    fn register_named_cluster() {
+        let config = Config::default();
+        let registrations = Registrations::default();
+
+        registrations
+            .register(
+                &config,
+                Registration {
+                    cluster: "game".into(),
+                    ..registration("a", &["127.0.0.1:7000"])
+                },
+            )
+            .unwrap();
+
+        let clusters = config.clusters.load();
+        assert_eq!(1, clusters.get("game").unwrap().endpoints().count());
+    }
+
+    #[test]
+    fn apply_peer() {
+        let config = Config::default();
+        let registrations = Registrations::default();
+        registrations
+            .register(&config, registration("local", &["127.0.0.1:7000"]))
+            .unwrap();
+
+        let state = |registrations| PeerState {
+            id: "peer".into(),
+            region: String::new(),
+            zone: String::new(),
+            sub_zone: String::new(),
+            registrations,
+        };
+        registrations
+            .apply_peer(&config, state(vec![registration("a", &["127.0.0.2:7000"])]))
+            .unwrap();
+        assert_eq!(vec!["127.0.0.1:7000", "127.0.0.2:7000"], addresses(&config));
+
+        let clusters = config.clusters.load();
+        let peer = clusters
+            .get_default()
+            .unwrap()
+            .localities
+            .iter()
+            .find(|endpoints| endpoints.locality.is_some())
+            .unwrap();
+        assert_eq!("peer", peer.locality.as_ref().unwrap().region);
+        assert_eq!(1, peer.endpoints.len());
+
+        // Only the endpoints of the server's own proxies are sent to peers.
+        let sent = registrations.state("server".into(), None);
+        assert_eq!(
+            vec![Registration {
+                cluster: DEFAULT_CLUSTER_NAME.into(),
+                ..registration("local", &["127.0.0.1:7000"])
+            }],
+            sent.registrations
+        );
+
+        registrations
+            .apply_peer(&config, state(vec![registration("a", &["127.0.0.3:7000"])]))
+            .unwrap();
+        assert_eq!(vec!["127.0.0.1:7000", "127.0.0.3:7000"], addresses(&config));
+
+        registrations.remove_peer(&config, "peer");
+        assert_eq!(vec!["127.0.0.1:7000"], addresses(&config));
+    }
+
+    #[test]
+    fn restore() {
+        let store = Arc::new(Memory::default());
+        let config = Config::default();
+        let registrations = Registrations::new(store.clone(), Duration::from_secs(60));
+        registrations
+            .register(&config, registration("a", &["127.0.0.1:7000"]))
+            .unwrap();
+        registrations
+            .register(&config, registration("b", &["127.0.0.1:7001"]))
+            .unwrap();
+
+        // Disconnected proxies keep their endpoints until they expire.
+        registrations.disconnect(&config, "b");
+        assert_eq!(vec!["127.0.0.1:7000", "127.0.0.1:7001"], addresses(&config));
+        assert_eq!(0, registrations.expire(&config));
+        registrations.flush();
+
+        // A restarted server restores both, whether or not they were
+        // connected when it stopped.
+        let restarted = Config::default();
+        let restored = Registrations::new(store.clone(), Duration::from_secs(60));
+        assert_eq!(2, restored.restore(&restarted).unwrap());
+        assert_eq!(
+            vec!["127.0.0.1:7000", "127.0.0.1:7001"],
+            addresses(&restarted)
+        );
+
+        // Registering again stops the endpoints from expiring.
+        restored
+            .register(&restarted, registration("a", &["127.0.0.1:7000"]))
+            .unwrap();
+        let expired = SystemTime::now() - Duration::from_secs(1);
+        restored.expiries.insert("b".into(), expired);
+        assert_eq!(1, restored.expire(&restarted));
+        assert_eq!(vec!["127.0.0.1:7000"], addresses(&restarted));
+        restored.flush();
+        assert_eq!(
+            vec![None],
+            store
+                .load()
+                .unwrap()
+                .into_iter()
+                .map(|stored| stored.expires_at_ms)
+                .collect::<Vec<_>>()
+        );
+
+        // Without a TTL nothing outlives the proxy's connection.
+        let registrations = Registrations::new(store.clone(), Duration::ZERO);
+        assert_eq!(0, registrations.restore(&Config::default()).unwrap());
+        registrations.flush();
+        assert!(store.load().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn peering() {
+        let server = Arc::new(Config::default());
+        server.id.store(Arc::new("server".into()));
+        let peer = Arc::new(Config::default());
+        peer.id.store(Arc::new("peer".into()));
+
+        let port = crate::test_utils::available_addr().await.port();
+        let control_plane =
+            crate::xds::ControlPlane::from_arc(server.clone()).with_locality(Some(Locality {
+                region: "us-east-1".into(),
+                ..<_>::default()
+            }));
+        tokio::spawn(crate::xds::server::serve(port, control_plane.clone()));
+
+        let peer_plane = crate::xds::ControlPlane::from_arc(peer.clone());
+        let _peer = peer_plane
+            .peer_with(
+                format!("http://127.0.0.1:{port}").parse().unwrap(),
+                &<_>::default(),
+            )
+            .unwrap();
+
+        control_plane
+            .registrations()
+            .register(&server, registration("a", &["127.0.0.1:7000"]))
+            .unwrap();
+        peer_plane
+            .registrations()
+            .register(&peer, registration("b", &["127.0.0.1:7001"]))
+            .unwrap();
+
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while addresses(&server).len() < 2 || addresses(&peer).len() < 2 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+
+        let region = |config: &Config, address: &str| {
+            config
+                .clusters
+                .load()
+                .get_default()
+                .unwrap()
+                .localities
+                .iter()
+                .find(|endpoints| {
+                    endpoints
+                        .endpoints
+                        .iter()
+                        .any(|endpoint| endpoint.address.to_string() == address)
+                })
+                .and_then(|endpoints| endpoints.locality.clone())
+                .map(|locality| locality.region)
+        };
+        assert_eq!(Some("us-east-1".into()), region(&peer, "127.0.0.1:7000"));
+        assert_eq!(Some("peer".into()), region(&server, "127.0.0.1:7001"));
+        assert_eq!(None, region(&server, "127.0.0.1:7000"));
+    }
+}
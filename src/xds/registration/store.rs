@@ -0,0 +1,272 @@
+/*
+ * Copyright 2023 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Persistence of the endpoints registered by a management server's proxies,
+//! so that restarting the server doesn't drop the whole fleet's endpoints
+//! until every proxy has registered again.
+
+use std::{
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+
+/// Where the registrations of a management server's proxies are persisted.
+/// Other backends can be used by implementing this trait and passing it to
+/// [`Registrations::new`][super::Registrations::new]. Changes are saved and
+/// removed from a thread of their own rather than the async runtime, so
+/// implementations may block on I/O.
+pub trait Store: std::fmt::Debug + Send + Sync + 'static {
+    /// Saves `registration`, replacing the previous one of its proxy.
+    fn save(&self, registration: &Stored) -> crate::Result<()>;
+    /// Removes the registration of the proxy with `id`.
+    fn remove(&self, id: &str) -> crate::Result<()>;
+    /// Returns every saved registration.
+    fn load(&self) -> crate::Result<Vec<Stored>>;
+    /// Makes the changes saved and removed so far durable, called once the
+    /// pending changes have been made, so that a burst of them is flushed
+    /// at once.
+    fn flush(&self) -> crate::Result<()> {
+        Ok(())
+    }
+}
+
+/// A proxy's registration, as persisted.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Stored {
+    pub id: String,
+    pub cluster: String,
+    pub endpoints: Vec<String>,
+    /// When the endpoints expire, in milliseconds since the Unix epoch, once
+    /// the proxy has disconnected. `None` while it's connected.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_at_ms: Option<u64>,
+}
+
+impl Stored {
+    pub fn expires_at(&self) -> Option<SystemTime> {
+        self.expires_at_ms
+            .map(|ms| UNIX_EPOCH + Duration::from_millis(ms))
+    }
+}
+
+pub(super) fn to_ms(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+/// A change made to a [`Store`] by a [`Writer`].
+enum Change {
+    Save(Stored),
+    Remove(String),
+    /// Notifies the sender once the changes before it are flushed.
+    Flush(std::sync::mpsc::Sender<()>),
+}
+
+/// Makes the changes to a [`Store`] from a thread of its own, in the order
+/// they're made, so that stores blocking on I/O don't block the async
+/// runtime, and flushes the store whenever no more changes are pending.
+#[derive(Clone, Debug)]
+pub(super) struct Writer {
+    store: Arc<dyn Store>,
+    changes: mpsc::UnboundedSender<Change>,
+}
+
+impl Writer {
+    pub(super) fn new(store: Arc<dyn Store>) -> Self {
+        let (changes, mut pending) = mpsc::unbounded_channel();
+        let writer = store.clone();
+        std::thread::Builder::new()
+            .name("registration-store".into())
+            .spawn(move || {
+                while let Some(change) = pending.blocking_recv() {
+                    let mut flushed = Vec::new();
+                    let mut next = Some(change);
+                    while let Some(change) = next {
+                        match change {
+                            Change::Save(stored) => {
+                                if let Err(error) = writer.save(&stored) {
+                                    tracing::warn!(%error, id = %stored.id, "failed to save registration to store");
+                                }
+                            }
+                            Change::Remove(id) => {
+                                if let Err(error) = writer.remove(&id) {
+                                    tracing::warn!(%error, %id, "failed to remove registration from store");
+                                }
+                            }
+                            Change::Flush(sender) => flushed.push(sender),
+                        }
+                        next = pending.try_recv().ok();
+                    }
+
+                    if let Err(error) = writer.flush() {
+                        tracing::warn!(%error, "failed to flush registrations to store");
+                    }
+                    for sender in flushed {
+                        let _ = sender.send(());
+                    }
+                }
+            })
+            .expect("failed to spawn the registration store thread");
+
+        Self { store, changes }
+    }
+
+    pub(super) fn save(&self, stored: Stored) {
+        let _ = self.changes.send(Change::Save(stored));
+    }
+
+    pub(super) fn remove(&self, id: &str) {
+        let _ = self.changes.send(Change::Remove(id.into()));
+    }
+
+    /// Blocks until the changes made so far are flushed to the store.
+    pub(super) fn flush(&self) {
+        let (sender, flushed) = std::sync::mpsc::channel();
+        if self.changes.send(Change::Flush(sender)).is_ok() {
+            let _ = flushed.recv();
+        }
+    }
+
+    /// Returns every registration in the store, which only reflects the
+    /// changes made so far once they're flushed.
+    pub(super) fn load(&self) -> crate::Result<Vec<Stored>> {
+        self.store.load()
+    }
+}
+
+/// Keeps registrations in memory, so they only last as long as the process.
+#[derive(Debug, Default)]
+pub struct Memory(DashMap<String, Stored>);
+
+impl Store for Memory {
+    fn save(&self, registration: &Stored) -> crate::Result<()> {
+        self.0.insert(registration.id.clone(), registration.clone());
+        Ok(())
+    }
+
+    fn remove(&self, id: &str) -> crate::Result<()> {
+        self.0.remove(id);
+        Ok(())
+    }
+
+    fn load(&self) -> crate::Result<Vec<Stored>> {
+        Ok(self.0.iter().map(|entry| entry.value().clone()).collect())
+    }
+}
+
+/// Keeps registrations in a [sled](https://docs.rs/sled) database on disk,
+/// as JSON keyed by the proxy's ID.
+#[cfg(feature = "registration-sled")]
+#[derive(Debug)]
+pub struct Sled(sled::Db);
+
+#[cfg(feature = "registration-sled")]
+impl Sled {
+    /// Opens the database at `path`, creating it if it doesn't exist.
+    pub fn open(path: impl AsRef<std::path::Path>) -> crate::Result<Self> {
+        Ok(Self(sled::open(path)?))
+    }
+}
+
+#[cfg(feature = "registration-sled")]
+impl Store for Sled {
+    fn save(&self, registration: &Stored) -> crate::Result<()> {
+        self.0
+            .insert(&registration.id, serde_json::to_vec(registration)?)?;
+        Ok(())
+    }
+
+    fn remove(&self, id: &str) -> crate::Result<()> {
+        self.0.remove(id)?;
+        Ok(())
+    }
+
+    fn load(&self) -> crate::Result<Vec<Stored>> {
+        self.0
+            .iter()
+            .values()
+            .map(|value| Ok(serde_json::from_slice(&value?)?))
+            .collect()
+    }
+
+    fn flush(&self) -> crate::Result<()> {
+        self.0.flush()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn store(store: &dyn Store) {
+        let stored = |id: &str, expires_at_ms| Stored {
+            id: id.into(),
+            cluster: String::new(),
+            endpoints: vec!["127.0.0.1:7000".into()],
+            expires_at_ms,
+        };
+
+        store.save(&stored("a", None)).unwrap();
+        store.save(&stored("b", None)).unwrap();
+        store.save(&stored("a", Some(1000))).unwrap();
+        store.remove("b").unwrap();
+        store.flush().unwrap();
+        assert_eq!(vec![stored("a", Some(1000))], store.load().unwrap());
+    }
+
+    #[test]
+    fn memory() {
+        store(&Memory::default());
+    }
+
+    #[test]
+    fn writer() {
+        let store = Arc::new(Memory::default());
+        let writer = Writer::new(store.clone());
+        let stored = Stored {
+            id: "a".into(),
+            cluster: String::new(),
+            endpoints: vec!["127.0.0.1:7000".into()],
+            expires_at_ms: None,
+        };
+
+        writer.save(stored.clone());
+        writer.remove("b");
+        writer.flush();
+        assert_eq!(vec![stored], writer.load().unwrap());
+        // Changes are made in order.
+        writer.remove("a");
+        writer.flush();
+        assert!(store.load().unwrap().is_empty());
+    }
+
+    #[cfg(feature = "registration-sled")]
+    #[test]
+    fn sled() {
+        let dir = tempdir::TempDir::new("registrations").unwrap();
+        store(&Sled::open(dir.path()).unwrap());
+
+        // Registrations outlive the database being closed.
+        let reopened = Sled::open(dir.path()).unwrap();
+        assert_eq!(1, reopened.load().unwrap().len());
+    }
+}
@@ -14,7 +14,10 @@
  * limitations under the License.
  */
 
-use std::sync::Arc;
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    sync::Arc,
+};
 
 use cached::Cached;
 use futures::Stream;
@@ -22,23 +25,49 @@ use tokio_stream::StreamExt;
 use tracing_futures::Instrument;
 
 use crate::{
-    config::Config,
+    config::{Config, ConfigHash},
     xds::{
+        config::core::v3::Node,
+        faults::{Fault, Faults},
         metrics,
+        rate_limit::{
+            self, FleetRateLimits, FleetUsageStream, RateLimitService, RateLimitServiceServer,
+            UsageReport,
+        },
+        rbac::{Rbac, Role},
+        registration::{
+            Peer, PeerState, Registrant, Registration, RegistrationResponse, RegistrationService,
+            RegistrationServiceServer, Registrations,
+        },
         service::discovery::v3::{
             aggregated_discovery_service_server::{
                 AggregatedDiscoveryService, AggregatedDiscoveryServiceServer,
             },
             DeltaDiscoveryRequest, DeltaDiscoveryResponse, DiscoveryRequest, DiscoveryResponse,
+            Resource as DeltaResource,
         },
-        ResourceType,
+        telemetry::{Report, ReportResponse, Telemetry, TelemetryService, TelemetryServiceServer},
+        ResourceType, SKIPPED_ENDPOINTS_METADATA_KEY,
     },
 };
 
 #[tracing::instrument(skip_all)]
 pub async fn spawn(port: u16, config: std::sync::Arc<crate::Config>) -> crate::Result<()> {
-    let server = AggregatedDiscoveryServiceServer::new(ControlPlane::from_arc(config));
-    let server = tonic::transport::Server::builder().add_service(server);
+    serve(port, ControlPlane::from_arc(config)).await
+}
+
+/// Serves `control_plane` as an xDS management server on `port`.
+#[tracing::instrument(skip_all)]
+pub async fn serve(port: u16, control_plane: ControlPlane) -> crate::Result<()> {
+    let telemetry = TelemetryServiceServer::new(control_plane.clone());
+    let rate_limits = RateLimitServiceServer::new(control_plane.clone());
+    let registration = RegistrationServiceServer::new(control_plane.clone());
+    let server = AggregatedDiscoveryServiceServer::new(control_plane);
+    let server = tonic::transport::Server::builder()
+        .add_service(server)
+        .add_service(telemetry)
+        .add_service(rate_limits)
+        .add_service(registration);
     tracing::info!("Serving management server at {}", port);
     Ok(server
         .serve((std::net::Ipv4Addr::UNSPECIFIED, port).into())
@@ -49,6 +78,12 @@ pub async fn spawn(port: u16, config: std::sync::Arc<crate::Config>) -> crate::R
 pub struct ControlPlane {
     config: Arc<Config>,
     watchers: Arc<crate::xds::resource::ResourceMap<Watchers>>,
+    rbac: Option<Arc<Rbac>>,
+    telemetry: Telemetry,
+    rate_limits: FleetRateLimits,
+    registrations: Registrations,
+    locality: Option<crate::endpoint::Locality>,
+    faults: Option<Faults>,
 }
 
 struct Watchers {
@@ -57,6 +92,50 @@ struct Watchers {
     version: std::sync::atomic::AtomicU64,
 }
 
+/// The resources of one type a delta xDS client is subscribed to, and the
+/// versions of them it has.
+#[derive(Default)]
+struct DeltaSubscription {
+    /// Every resource is subscribed to while empty, or containing `*`.
+    names: BTreeSet<String>,
+    versions: BTreeMap<String, String>,
+    /// The first response is sent even when it's empty, so the client knows
+    /// it's up to date.
+    responded: bool,
+}
+
+impl DeltaSubscription {
+    fn update(&mut self, request: &DeltaDiscoveryRequest) {
+        self.names
+            .extend(request.resource_names_subscribe.iter().cloned());
+        for name in &request.resource_names_unsubscribe {
+            self.names.remove(name);
+            self.versions.remove(name);
+        }
+        if !self.responded {
+            self.versions.extend(
+                request
+                    .initial_resource_versions
+                    .iter()
+                    .map(|(name, version)| (name.clone(), version.clone())),
+            );
+        }
+    }
+
+    fn is_subscribed(&self, name: &str) -> bool {
+        self.names.is_empty() || self.names.contains("*") || self.names.contains(name)
+    }
+}
+
+/// The version of a resource sent over delta xDS, which only changes when
+/// the resource does.
+fn resource_version(any: &prost_types::Any) -> String {
+    openssl::sha::sha256(&any.value)[..8]
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
 impl Default for Watchers {
     fn default() -> Self {
         let (sender, receiver) = tokio::sync::watch::channel(());
@@ -78,6 +157,12 @@ impl ControlPlane {
         let this = Self {
             config,
             watchers: <_>::default(),
+            rbac: None,
+            telemetry: <_>::default(),
+            rate_limits: <_>::default(),
+            registrations: <_>::default(),
+            locality: None,
+            faults: None,
         };
 
         this.config.clusters.watch({
@@ -98,6 +183,82 @@ impl ControlPlane {
         this
     }
 
+    /// Requires clients to authenticate as one of the roles in `rbac`, only
+    /// serving them the resources their role is allowed to access.
+    pub fn with_rbac(mut self, rbac: Rbac) -> Self {
+        self.rbac = Some(Arc::new(rbac));
+        self
+    }
+
+    /// Sets the locality of the server, which its peers add the endpoints
+    /// registered with it to.
+    pub fn with_locality(mut self, locality: Option<crate::endpoint::Locality>) -> Self {
+        self.locality = locality;
+        self
+    }
+
+    /// Uses `registrations` for the endpoints registered by the connected
+    /// proxies, such as ones persisted in a [`Store`][crate::xds::registration::Store].
+    pub fn with_registrations(mut self, registrations: Registrations) -> Self {
+        self.registrations = registrations;
+        self
+    }
+
+    /// Injects `faults` into the streams to clients, for testing how they
+    /// behave while the management server misbehaves.
+    #[cfg(any(test, feature = "failure-injection"))]
+    pub fn with_faults(mut self, faults: Faults) -> Self {
+        self.faults = Some(faults);
+        self
+    }
+
+    /// The latest telemetry reported by the connected proxies.
+    pub fn telemetry(&self) -> &Telemetry {
+        &self.telemetry
+    }
+
+    /// The endpoints registered by the connected proxies.
+    pub fn registrations(&self) -> &Registrations {
+        &self.registrations
+    }
+
+    /// Exchanges the endpoints registered by the connected proxies with the
+    /// management server at `endpoint`, connecting with the TLS config and
+    /// bearer token in `auth`, until the returned [`Peer`] is dropped.
+    ///
+    /// When access control is enabled, the endpoints the peer shares are
+    /// checked against the role of `auth`'s token in this server's roles, as
+    /// they would be if the peer had connected to this server, so the token
+    /// must belong to a role allowed to peer.
+    pub fn peer_with(
+        &self,
+        endpoint: tonic::transport::Endpoint,
+        auth: &crate::xds::ClientAuth,
+    ) -> crate::Result<Peer> {
+        let endpoint = auth.apply_tls(vec![endpoint])?.remove(0);
+        let role = match &self.rbac {
+            Some(rbac) => {
+                let role = auth
+                    .token
+                    .as_deref()
+                    .and_then(|token| rbac.role(token))
+                    .ok_or_else(|| eyre::eyre!("the peering token doesn't belong to any role"))?;
+                role.authorize_peer()?;
+                Some(role)
+            }
+            None => None,
+        };
+
+        Ok(Peer::spawn(
+            endpoint,
+            auth.bearer_token()?,
+            move |state| authorize_peer(role.as_deref(), state),
+            self.registrations.clone(),
+            self.config.clone(),
+            self.locality.clone(),
+        ))
+    }
+
     fn push_update(&self, resource_type: ResourceType) {
         let watchers = &self.watchers[resource_type];
         watchers
@@ -114,11 +275,34 @@ impl ControlPlane {
         id: &str,
         resource_type: ResourceType,
         names: &[String],
+        role: Option<&Role>,
     ) -> Result<DiscoveryResponse, tonic::Status> {
         let mut response = self
             .config
             .discovery_request(id, resource_type, names)
             .map_err(|error| tonic::Status::internal(error.to_string()))?;
+
+        if let Some(role) = role {
+            role.authorize(id, resource_type)?;
+            if matches!(
+                resource_type,
+                ResourceType::Cluster | ResourceType::Endpoint
+            ) {
+                response.resources.retain(|any| {
+                    match crate::xds::Resource::try_from(any.clone()) {
+                        Ok(resource) if role.can_read_cluster(resource.name()) => true,
+                        Ok(resource) => {
+                            role.reject_cluster(id, resource_type, resource.name());
+                            false
+                        }
+                        Err(error) => {
+                            tracing::warn!(%error, "failed to decode resource");
+                            false
+                        }
+                    }
+                });
+            }
+        }
         let watchers = &self.watchers[resource_type];
 
         let nonce = uuid::Uuid::new_v4();
@@ -141,8 +325,80 @@ impl ControlPlane {
         Ok(response)
     }
 
+    /// Returns the hash of the config a client with `role` is served.
+    fn expected_hash(&self, role: Option<&Role>) -> ConfigHash {
+        let clusters = self.config.clusters.load();
+
+        ConfigHash::new(
+            clusters
+                .values()
+                .filter(|cluster| role.map_or(true, |role| role.can_read_cluster(&cluster.name))),
+            &self.config.filters.load(),
+        )
+    }
+
+    /// Compares the hash a proxy reported when acknowledging a response of
+    /// `resource_type` with the config it should have applied. The config
+    /// may have changed after the response was sent, so drift is only
+    /// reported until the next acknowledgement.
+    fn check_drift(
+        &self,
+        id: &str,
+        resource_type: ResourceType,
+        node: Option<&Node>,
+        role: Option<&Role>,
+    ) {
+        let Some(reported) = node.and_then(ConfigHash::from_node) else {
+            return;
+        };
+
+        let expected = self.expected_hash(role);
+        let drifted = match resource_type {
+            ResourceType::Listener => reported.filters != expected.filters,
+            ResourceType::Cluster | ResourceType::Endpoint => {
+                reported.clusters != expected.clusters
+            }
+            _ => return,
+        };
+
+        metrics::PROXY_CONFIG_DRIFT
+            .with_label_values(&[id, resource_type.type_url()])
+            .set(drifted as i64);
+        if drifted {
+            tracing::warn!(
+                %id,
+                r#type = resource_type.type_url(),
+                %reported,
+                %expected,
+                "proxy config diverged from the management server"
+            );
+        }
+    }
+
+    /// Logs the invalid endpoints a proxy reported skipping when acknowledging
+    /// a response of `resource_type`.
+    fn check_skipped(id: &str, resource_type: ResourceType, node: Option<&Node>) {
+        use prost_types::value::Kind;
+
+        let skipped = node
+            .and_then(|node| node.metadata.as_ref())
+            .and_then(|metadata| metadata.fields.get(SKIPPED_ENDPOINTS_METADATA_KEY))
+            .and_then(|value| value.kind.as_ref());
+        if let Some(Kind::StringValue(skipped)) = skipped {
+            tracing::warn!(
+                %id,
+                r#type = resource_type.type_url(),
+                %skipped,
+                "proxy skipped invalid endpoints"
+            );
+        }
+    }
+
+    /// Streams discovery responses to a client, only serving it the resources
+    /// `role` allows when present.
     pub async fn stream_aggregated_resources<S>(
         &self,
+        role: Option<Arc<Role>>,
         mut streaming: S,
     ) -> Result<impl Stream<Item = Result<DiscoveryResponse, tonic::Status>> + Send, tonic::Status>
     where
@@ -171,19 +427,44 @@ impl ControlPlane {
         let mut rx = self.watchers[resource_type].receiver.clone();
         let mut pending_acks = cached::TimedSizedCache::with_size_and_lifespan(50, 1);
         let this = Self::clone(self);
-        let response = this.discovery_response(&node.id, resource_type, &message.resource_names)?;
+        let response = this.discovery_response(
+            &node.id,
+            resource_type,
+            &message.resource_names,
+            role.as_deref(),
+        )?;
         pending_acks.cache_set(response.nonce.clone(), ());
+        let mut faults = self
+            .faults
+            .as_ref()
+            .map(Faults::subscribe)
+            .unwrap_or_default();
+        let initial = response.clone();
 
         let id = node.id.clone();
         Ok(Box::pin(async_stream::try_stream! {
+            faults.delay().await;
             yield response;
 
             let _span = tracing::trace_span!("stream loop");
             loop {
                 tokio::select! {
+                    fault = faults.next() => match fault {
+                        Fault::Reset => {
+                            Err::<(), _>(tonic::Status::unavailable("injected stream reset"))?;
+                        }
+                        Fault::Rollback => {
+                            let mut response = initial.clone();
+                            response.nonce = uuid::Uuid::new_v4().to_string();
+                            pending_acks.cache_set(response.nonce.clone(), ());
+                            faults.delay().await;
+                            yield response;
+                        }
+                    },
                     _ = rx.changed() => {
                         tracing::trace!("sending new discovery response");
-                        yield this.discovery_response(&id, resource_type, &message.resource_names).map(|response| {
+                        faults.delay().await;
+                        yield this.discovery_response(&id, resource_type, &message.resource_names, role.as_deref()).map(|response| {
                             pending_acks.cache_set(response.nonce.clone(), ());
                             response
                         })?;
@@ -210,6 +491,12 @@ impl ControlPlane {
                         tracing::trace!("new request");
                         metrics::DISCOVERY_REQUESTS.with_label_values(&[id, resource_type.type_url()]).inc();
 
+                        if let Some(role) = &role {
+                            if role.authorize(id, resource_type).is_err() {
+                                continue;
+                            }
+                        }
+
                         if let Some(error) = &new_message.error_detail {
                             metrics::NACKS.with_label_values(&[id, resource_type.type_url()]).inc();
                             tracing::error!(nonce = %new_message.response_nonce, ?error, "NACK");
@@ -217,6 +504,8 @@ impl ControlPlane {
                         } else if uuid::Uuid::parse_str(&new_message.response_nonce).is_ok() {
                             if pending_acks.cache_get(&new_message.response_nonce).is_some() {
                                 tracing::info!(nonce = %new_message.response_nonce, "ACK");
+                                this.check_drift(id, resource_type, new_message.node.as_ref(), role.as_deref());
+                                Self::check_skipped(id, resource_type, new_message.node.as_ref());
                                 continue
                             } else {
                                 tracing::trace!(nonce = %new_message.response_nonce, "Unknown nonce: could not be found in cache");
@@ -224,7 +513,8 @@ impl ControlPlane {
                             }
                         }
 
-                        yield this.discovery_response(id, resource_type, &message.resource_names).map(|response| {
+                        faults.delay().await;
+                        yield this.discovery_response(id, resource_type, &message.resource_names, role.as_deref()).map(|response| {
                             pending_acks.cache_set(response.nonce.clone(), ());
                             response
                         }).unwrap();
@@ -235,6 +525,217 @@ impl ControlPlane {
             tracing::info!("terminating stream");
         }.instrument(tracing::info_span!("xds_stream", %node.id, %resource_type))))
     }
+
+    /// Returns the resources of `resource_type` that changed or were removed
+    /// since the client was last sent `subscription`, or `None` when nothing
+    /// changed.
+    fn delta_discovery_response(
+        &self,
+        id: &str,
+        resource_type: ResourceType,
+        subscription: &mut DeltaSubscription,
+        role: Option<&Role>,
+    ) -> Result<Option<DeltaDiscoveryResponse>, tonic::Status> {
+        let response = self.discovery_response(id, resource_type, &[], role)?;
+
+        let mut current = BTreeMap::new();
+        for any in response.resources {
+            let resource = crate::xds::Resource::try_from(any.clone())
+                .map_err(|error| tonic::Status::internal(error.to_string()))?;
+            if subscription.is_subscribed(resource.name()) {
+                current.insert(resource.name().to_owned(), any);
+            }
+        }
+
+        let removed_resources = subscription
+            .versions
+            .keys()
+            .filter(|name| !current.contains_key(*name))
+            .cloned()
+            .collect::<Vec<_>>();
+        let resources = current
+            .into_iter()
+            .map(|(name, any)| DeltaResource {
+                version: resource_version(&any),
+                name,
+                resource: Some(any),
+                ..<_>::default()
+            })
+            .filter(|resource| subscription.versions.get(&resource.name) != Some(&resource.version))
+            .collect::<Vec<_>>();
+
+        if resources.is_empty() && removed_resources.is_empty() && subscription.responded {
+            return Ok(None);
+        }
+
+        for name in &removed_resources {
+            subscription.versions.remove(name);
+        }
+        for resource in &resources {
+            subscription
+                .versions
+                .insert(resource.name.clone(), resource.version.clone());
+        }
+        subscription.responded = true;
+
+        tracing::trace!(
+            r#type = &*response.type_url,
+            nonce = &*response.nonce,
+            resources = resources.len(),
+            removed = removed_resources.len(),
+            "delta discovery response"
+        );
+
+        Ok(Some(DeltaDiscoveryResponse {
+            system_version_info: response.version_info,
+            resources,
+            type_url: response.type_url,
+            removed_resources,
+            nonce: response.nonce,
+            control_plane: response.control_plane,
+            ..<_>::default()
+        }))
+    }
+
+    /// Streams delta discovery responses to a client, only sending the
+    /// resources that changed since its previous response, and serving it
+    /// the resources `role` allows when present.
+    pub async fn delta_aggregated_resources<S>(
+        &self,
+        role: Option<Arc<Role>>,
+        mut streaming: S,
+    ) -> Result<
+        impl Stream<Item = Result<DeltaDiscoveryResponse, tonic::Status>> + Send,
+        tonic::Status,
+    >
+    where
+        S: Stream<Item = Result<DeltaDiscoveryRequest, tonic::Status>>
+            + Send
+            + std::marker::Unpin
+            + 'static,
+    {
+        tracing::trace!("starting delta stream");
+        let message = streaming.next().await.ok_or_else(|| {
+            tracing::error!("No message found");
+            tonic::Status::invalid_argument("No message found")
+        })??;
+
+        let Some(node) = message.node.clone() else {
+            tracing::error!("Node identifier was not found");
+            return Err(tonic::Status::invalid_argument("Node identifier required"));
+        };
+
+        let this = Self::clone(self);
+        let mut faults = self
+            .faults
+            .as_ref()
+            .map(Faults::subscribe)
+            .unwrap_or_default();
+        let id = node.id.clone();
+        Ok(Box::pin(async_stream::try_stream! {
+            let mut subscriptions = HashMap::<ResourceType, DeltaSubscription>::new();
+            // Every subscribed type is sent its resources once subscribed, and
+            // again whenever they change.
+            let mut changes = tokio_stream::StreamMap::new();
+            let mut pending_acks = cached::TimedSizedCache::with_size_and_lifespan(50, 1);
+            let mut next_message = Some(message);
+
+            loop {
+                let message = match next_message.take() {
+                    Some(message) => message,
+                    None => tokio::select! {
+                        fault = faults.next() => match fault {
+                            Fault::Reset => {
+                                Err::<(), _>(tonic::Status::unavailable("injected stream reset"))?;
+                                continue;
+                            }
+                            // Delta responses only hold what changed, so
+                            // there's no previous state to roll back to.
+                            Fault::Rollback => continue,
+                        },
+                        Some((resource_type, ())) = changes.next() => {
+                            let subscription = subscriptions.entry(resource_type).or_default();
+                            let response = this.delta_discovery_response(&id, resource_type, subscription, role.as_deref())?;
+                            if let Some(response) = response {
+                                tracing::trace!("sending new delta discovery response");
+                                pending_acks.cache_set(response.nonce.clone(), ());
+                                faults.delay().await;
+                                yield response;
+                            }
+                            continue;
+                        }
+                        new_message = streaming.next() => match new_message.transpose() {
+                            Ok(Some(value)) => value,
+                            Ok(None) => break,
+                            Err(error) => {
+                                tracing::error!(%error, "error receiving delta request");
+                                continue;
+                            }
+                        },
+                    },
+                };
+
+                let resource_type = match message.type_url.parse::<ResourceType>() {
+                    Ok(value) => value,
+                    Err(error) => {
+                        tracing::error!(%error, "unknown resource type");
+                        continue;
+                    }
+                };
+
+                tracing::trace!("new delta request");
+                metrics::DISCOVERY_REQUESTS.with_label_values(&[&*id, resource_type.type_url()]).inc();
+
+                if let Some(role) = &role {
+                    if role.authorize(&id, resource_type).is_err() {
+                        continue;
+                    }
+                }
+
+                if !message.response_nonce.is_empty() {
+                    if let Some(error) = &message.error_detail {
+                        metrics::NACKS.with_label_values(&[&*id, resource_type.type_url()]).inc();
+                        tracing::error!(nonce = %message.response_nonce, ?error, "NACK");
+                    } else if pending_acks.cache_get(&message.response_nonce).is_some() {
+                        tracing::info!(nonce = %message.response_nonce, "ACK");
+                        metrics::ACKS.with_label_values(&[&*id, resource_type.type_url()]).inc();
+                        this.check_drift(&id, resource_type, message.node.as_ref(), role.as_deref());
+                        Self::check_skipped(&id, resource_type, message.node.as_ref());
+                    } else {
+                        tracing::trace!(nonce = %message.response_nonce, "Unknown nonce: could not be found in cache");
+                    }
+
+                    if message.resource_names_subscribe.is_empty()
+                        && message.resource_names_unsubscribe.is_empty()
+                    {
+                        continue;
+                    }
+                }
+
+                subscriptions.entry(resource_type).or_default().update(&message);
+                if changes.contains_key(&resource_type) {
+                    let subscription = subscriptions.entry(resource_type).or_default();
+                    let response = this.delta_discovery_response(&id, resource_type, subscription, role.as_deref())?;
+                    if let Some(response) = response {
+                        pending_acks.cache_set(response.nonce.clone(), ());
+                        faults.delay().await;
+                        yield response;
+                    }
+                } else {
+                    // A new watch stream yields straight away, sending the
+                    // client its first response.
+                    changes.insert(
+                        resource_type,
+                        tokio_stream::wrappers::WatchStream::new(
+                            this.watchers[resource_type].receiver.clone(),
+                        ),
+                    );
+                }
+            }
+
+            tracing::info!("terminating delta stream");
+        }.instrument(tracing::info_span!("xds_delta_stream", %node.id))))
+    }
 }
 
 #[tonic::async_trait]
@@ -242,30 +743,221 @@ impl AggregatedDiscoveryService for ControlPlane {
     type StreamAggregatedResourcesStream =
         std::pin::Pin<Box<dyn Stream<Item = Result<DiscoveryResponse, tonic::Status>> + Send>>;
     type DeltaAggregatedResourcesStream =
-        tokio_stream::wrappers::ReceiverStream<Result<DeltaDiscoveryResponse, tonic::Status>>;
+        std::pin::Pin<Box<dyn Stream<Item = Result<DeltaDiscoveryResponse, tonic::Status>> + Send>>;
 
     #[tracing::instrument(skip_all)]
     async fn stream_aggregated_resources(
         &self,
         request: tonic::Request<tonic::Streaming<DiscoveryRequest>>,
     ) -> Result<tonic::Response<Self::StreamAggregatedResourcesStream>, tonic::Status> {
+        let role = self
+            .rbac
+            .as_ref()
+            .map(|rbac| rbac.authenticate(request.metadata()))
+            .transpose()?;
+
         Ok(tonic::Response::new(Box::pin(
-            self.stream_aggregated_resources(request.into_inner())
+            self.stream_aggregated_resources(role, request.into_inner())
                 .in_current_span()
                 .await?,
         )))
     }
 
+    #[tracing::instrument(skip_all)]
     async fn delta_aggregated_resources(
         &self,
-        _request: tonic::Request<tonic::Streaming<DeltaDiscoveryRequest>>,
+        request: tonic::Request<tonic::Streaming<DeltaDiscoveryRequest>>,
     ) -> Result<tonic::Response<Self::DeltaAggregatedResourcesStream>, tonic::Status> {
-        Err(tonic::Status::unimplemented(
-            "Quilkin doesn't currently support Delta xDS",
-        ))
+        let role = self
+            .rbac
+            .as_ref()
+            .map(|rbac| rbac.authenticate(request.metadata()))
+            .transpose()?;
+
+        Ok(tonic::Response::new(Box::pin(
+            self.delta_aggregated_resources(role, request.into_inner())
+                .in_current_span()
+                .await?,
+        )))
+    }
+}
+
+#[tonic::async_trait]
+impl TelemetryService for ControlPlane {
+    #[tracing::instrument(skip_all)]
+    async fn stream_reports(
+        &self,
+        request: tonic::Request<tonic::Streaming<Report>>,
+    ) -> Result<tonic::Response<ReportResponse>, tonic::Status> {
+        if let Some(rbac) = &self.rbac {
+            rbac.authenticate(request.metadata())?;
+        }
+
+        let mut reports = request.into_inner();
+        let mut id = None;
+        let result = loop {
+            match reports.message().await {
+                Ok(Some(report)) => {
+                    tracing::trace!(id = %report.id, "received telemetry report");
+                    id = Some(report.id.clone());
+                    self.telemetry.record(report);
+                }
+                Ok(None) => break Ok(tonic::Response::new(ReportResponse {})),
+                Err(status) => break Err(status),
+            }
+        };
+
+        // Reports are only meaningful while the proxy is still sending them.
+        if let Some(id) = id {
+            self.telemetry.remove(&id);
+        }
+
+        result
     }
 }
 
+#[tonic::async_trait]
+impl RateLimitService for ControlPlane {
+    type ShareUsageStream = FleetUsageStream;
+
+    #[tracing::instrument(skip_all)]
+    async fn share_usage(
+        &self,
+        request: tonic::Request<tonic::Streaming<UsageReport>>,
+    ) -> Result<tonic::Response<Self::ShareUsageStream>, tonic::Status> {
+        let role = self
+            .rbac
+            .as_ref()
+            .map(|rbac| rbac.authenticate(request.metadata()))
+            .transpose()?;
+        let id = rate_limit::contributor_id(
+            role.as_ref().map(|role| role.name.as_str()),
+            request.remote_addr(),
+        );
+
+        Ok(tonic::Response::new(rate_limit::share_usage(
+            self.rate_limits.clone(),
+            id,
+            request.into_inner(),
+        )))
+    }
+}
+
+#[tonic::async_trait]
+impl RegistrationService for ControlPlane {
+    #[tracing::instrument(skip_all)]
+    async fn register(
+        &self,
+        request: tonic::Request<tonic::Streaming<Registration>>,
+    ) -> Result<tonic::Response<RegistrationResponse>, tonic::Status> {
+        let role = self
+            .rbac
+            .as_ref()
+            .map(|rbac| rbac.authenticate(request.metadata()))
+            .transpose()?;
+
+        let mut registrations = request.into_inner();
+        let mut registrant = Registrant {
+            registrations: &self.registrations,
+            config: &self.config,
+            id: None,
+        };
+
+        while let Some(mut registration) = registrations.message().await? {
+            if let Some(role) = &role {
+                let cluster = if registration.cluster.is_empty() {
+                    crate::cluster::DEFAULT_CLUSTER_NAME
+                } else {
+                    &registration.cluster
+                };
+
+                role.authorize_registration(&registration.id, cluster)?;
+
+                // The reported ID is only trusted within the client's role.
+                registration.id = role.registration_id(&registration.id);
+            }
+
+            tracing::debug!(id = %registration.id, cluster = %registration.cluster, endpoints = ?registration.endpoints, "registering endpoints");
+            if let Some(previous) = registrant.id.replace(registration.id.clone()) {
+                if previous != registration.id {
+                    self.registrations.deregister(&self.config, &previous);
+                }
+            }
+            self.registrations.register(&self.config, registration)?;
+        }
+
+        Ok(tonic::Response::new(RegistrationResponse {}))
+    }
+
+    type PeerStream =
+        std::pin::Pin<Box<dyn Stream<Item = Result<PeerState, tonic::Status>> + Send>>;
+
+    #[tracing::instrument(skip_all)]
+    async fn peer(
+        &self,
+        request: tonic::Request<tonic::Streaming<PeerState>>,
+    ) -> Result<tonic::Response<Self::PeerStream>, tonic::Status> {
+        let role = self
+            .rbac
+            .as_ref()
+            .map(|rbac| rbac.authenticate(request.metadata()))
+            .transpose()?;
+        if let Some(role) = &role {
+            role.authorize_peer()?;
+        }
+
+        let authorize = move |state: &PeerState| authorize_peer(role.as_deref(), state);
+
+        let this = self.clone();
+        let incoming = request.into_inner();
+        Ok(tonic::Response::new(Box::pin(async_stream::try_stream! {
+            let states = this
+                .registrations
+                .states(this.config.clone(), this.locality.clone());
+            let received = this.registrations.receive(&this.config, incoming, authorize);
+            tokio::pin!(states, received);
+
+            loop {
+                tokio::select! {
+                    result = &mut received => {
+                        result?;
+                        break;
+                    }
+                    Some(state) = states.next() => {
+                        yield state;
+                    }
+                    else => break,
+                }
+            }
+        })))
+    }
+}
+
+/// Checks that the peer authenticated as `role` is allowed to share the
+/// endpoints in `state`.
+fn authorize_peer(role: Option<&Role>, state: &PeerState) -> Result<(), tonic::Status> {
+    let Some(role) = role else {
+        return Ok(());
+    };
+
+    for registration in &state.registrations {
+        let cluster = if registration.cluster.is_empty() {
+            crate::cluster::DEFAULT_CLUSTER_NAME
+        } else {
+            &registration.cluster
+        };
+
+        if !role.can_register_cluster(cluster) {
+            return Err(tonic::Status::permission_denied(format!(
+                "role `{}` can't register endpoints in cluster `{cluster}`",
+                role.name
+            )));
+        }
+    }
+
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     use pretty_assertions::assert_eq;
@@ -345,9 +1037,10 @@ mod tests {
 
         let mut stream = timeout(
             TIMEOUT_DURATION,
-            client.stream_aggregated_resources(Box::pin(
-                tokio_stream::wrappers::ReceiverStream::new(rx),
-            )),
+            client.stream_aggregated_resources(
+                None,
+                Box::pin(tokio_stream::wrappers::ReceiverStream::new(rx)),
+            ),
         )
         .await
         .unwrap()
@@ -392,4 +1085,54 @@ mod tests {
             .unwrap()
             .unwrap();
     }
+
+    #[tokio::test]
+    async fn rbac_filters_clusters() {
+        let config = Arc::new(Config::default());
+        config.clusters.modify(|clusters| {
+            for (name, port) in [("us-east-1", 1000), ("eu-west-1", 2000)] {
+                clusters.insert(crate::cluster::Cluster::new(
+                    name.into(),
+                    vec![crate::endpoint::LocalityEndpoints::from(
+                        crate::endpoint::Endpoint::new(
+                            (std::net::Ipv4Addr::LOCALHOST, port).into(),
+                        ),
+                    )],
+                ));
+            }
+        });
+
+        let role = Role {
+            name: "us-east".into(),
+            tokens: vec!["abc".into()],
+            clusters: vec!["us-east-1".into()],
+            filters: false,
+            register: Vec::new(),
+            peer: false,
+        };
+        let control_plane = ControlPlane::from_arc(config);
+
+        let response = control_plane
+            .discovery_response("node", ResourceType::Endpoint, &[], Some(&role))
+            .unwrap();
+        let names = response
+            .resources
+            .into_iter()
+            .map(|any| {
+                crate::xds::Resource::try_from(any)
+                    .unwrap()
+                    .name()
+                    .to_owned()
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(vec!["us-east-1".to_owned()], names);
+
+        assert_eq!(
+            tonic::Code::PermissionDenied,
+            control_plane
+                .discovery_response("node", ResourceType::Listener, &[], Some(&role))
+                .unwrap_err()
+                .code()
+        );
+    }
 }
@@ -0,0 +1,178 @@
+/*
+ * Copyright 2023 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! The state of the proxy's xDS stream, the management server it's connected
+//! to and the latest response of each type, served by the admin server so
+//! that operators can see why a proxy isn't getting the endpoints they
+//! expect.
+
+use std::{
+    collections::BTreeMap,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use serde::Serialize;
+
+static STATUS: Lazy<Mutex<Status>> = Lazy::new(<_>::default);
+
+/// The state of the proxy's xDS stream.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub(crate) struct Status {
+    /// The management server the proxy is connected to, if it is.
+    pub management_server: Option<String>,
+    /// The identifier of the control plane that sent the latest response.
+    pub control_plane: Option<String>,
+    /// The latest response of each resource type, by type URL.
+    pub resources: BTreeMap<String, Response>,
+    /// The latest error, from the stream or from applying a response.
+    pub last_error: Option<Error>,
+}
+
+/// The latest response of a resource type.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub(crate) struct Response {
+    pub version: String,
+    pub nonce: String,
+    /// The number of resources in the response.
+    pub resources: usize,
+    /// Whether the response was rejected, in which case its error is the
+    /// stream's `last_error`.
+    pub rejected: bool,
+    pub timestamp_ms: u64,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub(crate) struct Error {
+    /// The type URL of the response that was rejected, or `None` for errors
+    /// of the stream itself.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub type_url: Option<String>,
+    pub message: String,
+    pub timestamp_ms: u64,
+}
+
+impl Status {
+    fn received(
+        &mut self,
+        control_plane: &str,
+        type_url: &str,
+        version: &str,
+        nonce: &str,
+        resources: usize,
+        errors: &[String],
+    ) {
+        let timestamp_ms = now_ms();
+        self.control_plane = Some(control_plane.into());
+        self.resources.insert(
+            type_url.into(),
+            Response {
+                version: version.into(),
+                nonce: nonce.into(),
+                resources,
+                rejected: !errors.is_empty(),
+                timestamp_ms,
+            },
+        );
+        if !errors.is_empty() {
+            self.last_error = Some(Error {
+                type_url: Some(type_url.into()),
+                message: errors.join("; "),
+                timestamp_ms,
+            });
+        }
+    }
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+/// Returns the state of the proxy's xDS stream.
+pub(crate) fn get() -> Status {
+    STATUS.lock().clone()
+}
+
+/// Records that the proxy connected to `management_server`.
+pub(crate) fn connected(management_server: String) {
+    STATUS.lock().management_server = Some(management_server);
+}
+
+/// Records that the proxy lost its connection to the management server.
+pub(crate) fn disconnected() {
+    STATUS.lock().management_server = None;
+}
+
+/// Records a response from `control_plane`, with `errors` if some of its
+/// resources were rejected.
+pub(crate) fn received(
+    control_plane: &str,
+    type_url: &str,
+    version: &str,
+    nonce: &str,
+    resources: usize,
+    errors: &[String],
+) {
+    STATUS
+        .lock()
+        .received(control_plane, type_url, version, nonce, resources, errors);
+}
+
+/// Records an error of the stream itself.
+pub(crate) fn failed(error: &impl std::fmt::Display) {
+    STATUS.lock().last_error = Some(Error {
+        type_url: None,
+        message: error.to_string(),
+        timestamp_ms: now_ms(),
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn received() {
+        const TYPE: &str = "type.googleapis.com/status.test";
+
+        let mut status = Status::default();
+        status.received("control-plane", TYPE, "1", "a", 2, &[]);
+        assert_eq!(Some("control-plane".into()), status.control_plane);
+        let response = &status.resources[TYPE];
+        assert_eq!(
+            ("1", "a", 2, false),
+            (
+                &*response.version,
+                &*response.nonce,
+                response.resources,
+                response.rejected
+            )
+        );
+        assert!(status.last_error.is_none());
+
+        status.received("control-plane", TYPE, "2", "b", 1, &["invalid".into()]);
+        assert!(status.resources[TYPE].rejected);
+        let error = status.last_error.unwrap();
+        assert_eq!(
+            (Some(TYPE), "invalid"),
+            (error.type_url.as_deref(), &*error.message)
+        );
+    }
+}
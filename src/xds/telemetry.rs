@@ -0,0 +1,276 @@
+/*
+ * Copyright 2023 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Coarse traffic counters that proxies push to their management server, so
+//! that the control plane can make placement decisions without scraping the
+//! metrics of every proxy.
+
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use dashmap::DashMap;
+
+use crate::{
+    metrics::{DIRECTION_LABEL, READ_DIRECTION_LABEL, WRITE_DIRECTION_LABEL},
+    xds::{client::Channel, metrics},
+};
+
+pub use super::quilkin::telemetry::v1alpha1::{
+    telemetry_service_client::TelemetryServiceClient,
+    telemetry_service_server::{TelemetryService, TelemetryServiceServer},
+    Report, ReportResponse,
+};
+
+const ACTIVE_SESSIONS: &str = "quilkin_session_active";
+const PACKETS_TOTAL: &str = "quilkin_packets_total";
+const BYTES_TOTAL: &str = "quilkin_bytes_total";
+const PACKETS_DROPPED_TOTAL: &str = "quilkin_packets_dropped_total";
+
+/// The totals of the counters included in a [`Report`], which reports are
+/// the difference of.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub(crate) struct Snapshot {
+    active_sessions: u64,
+    packets_read: u64,
+    packets_written: u64,
+    bytes_read: u64,
+    bytes_written: u64,
+    packets_dropped: u64,
+}
+
+impl Snapshot {
+    /// Reads the current totals from the global metrics registry.
+    pub(crate) fn gather() -> Self {
+        let mut snapshot = Self::default();
+
+        for family in crate::metrics::registry().gather() {
+            for metric in family.get_metric() {
+                let value = if metric.has_counter() {
+                    metric.get_counter().get_value()
+                } else {
+                    metric.get_gauge().get_value()
+                } as u64;
+                let direction = metric
+                    .get_label()
+                    .iter()
+                    .find(|label| label.get_name() == DIRECTION_LABEL)
+                    .map(|label| label.get_value());
+
+                let total = match (family.get_name(), direction) {
+                    (ACTIVE_SESSIONS, _) => &mut snapshot.active_sessions,
+                    (PACKETS_TOTAL, Some(READ_DIRECTION_LABEL)) => &mut snapshot.packets_read,
+                    (PACKETS_TOTAL, Some(WRITE_DIRECTION_LABEL)) => &mut snapshot.packets_written,
+                    (BYTES_TOTAL, Some(READ_DIRECTION_LABEL)) => &mut snapshot.bytes_read,
+                    (BYTES_TOTAL, Some(WRITE_DIRECTION_LABEL)) => &mut snapshot.bytes_written,
+                    (PACKETS_DROPPED_TOTAL, _) => &mut snapshot.packets_dropped,
+                    _ => continue,
+                };
+
+                *total += value;
+            }
+        }
+
+        snapshot
+    }
+
+    /// The number of currently active sessions.
+    pub(crate) fn active_sessions(&self) -> u64 {
+        self.active_sessions
+    }
+
+    /// The total number of packets processed in either direction.
+    pub(crate) fn packets(&self) -> u64 {
+        self.packets_read + self.packets_written
+    }
+
+    /// Returns the report of the traffic between `previous` and this
+    /// snapshot, which were taken `interval` apart.
+    fn report(&self, id: &str, previous: &Self, interval: Duration) -> Report {
+        Report {
+            id: id.into(),
+            interval_ms: interval.as_millis() as u64,
+            active_sessions: self.active_sessions,
+            packets_read: self.packets_read.saturating_sub(previous.packets_read),
+            packets_written: self
+                .packets_written
+                .saturating_sub(previous.packets_written),
+            bytes_read: self.bytes_read.saturating_sub(previous.bytes_read),
+            bytes_written: self.bytes_written.saturating_sub(previous.bytes_written),
+            packets_dropped: self
+                .packets_dropped
+                .saturating_sub(previous.packets_dropped),
+        }
+    }
+}
+
+/// Pushes a [`Report`] to the management server every interval, until
+/// dropped.
+pub struct Reporter(tokio::task::JoinHandle<()>);
+
+impl Reporter {
+    pub(crate) fn spawn(id: String, channel: Channel, interval: Duration) -> Self {
+        Self(tokio::spawn(async move {
+            let mut client = TelemetryServiceClient::new(channel);
+            loop {
+                match client.stream_reports(reports(id.clone(), interval)).await {
+                    Ok(_) => tracing::debug!("telemetry stream closed by management server"),
+                    Err(error) => tracing::warn!(%error, "telemetry stream failed, retrying"),
+                }
+
+                tokio::time::sleep(interval).await;
+            }
+        }))
+    }
+}
+
+impl Drop for Reporter {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+fn reports(id: String, interval: Duration) -> impl futures::Stream<Item = Report> {
+    async_stream::stream! {
+        let mut ticker = tokio::time::interval(interval);
+        ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        ticker.tick().await;
+
+        let mut previous = (Snapshot::gather(), Instant::now());
+        loop {
+            ticker.tick().await;
+            let current = (Snapshot::gather(), Instant::now());
+            yield current.0.report(&id, &previous.0, current.1 - previous.1);
+            previous = current;
+        }
+    }
+}
+
+/// The most recent report from a proxy.
+#[derive(Clone, Debug)]
+pub struct NodeTelemetry {
+    pub report: Report,
+    pub received_at: Instant,
+}
+
+impl NodeTelemetry {
+    /// The rate of packets the proxy received from downstream during the
+    /// interval of the report.
+    pub fn packets_per_second(&self) -> f64 {
+        if self.report.interval_ms == 0 {
+            return 0.0;
+        }
+
+        self.report.packets_read as f64 * 1000.0 / self.report.interval_ms as f64
+    }
+}
+
+/// The latest reports of the proxies currently pushing telemetry to the
+/// management server, keyed by their ID.
+#[derive(Clone, Debug, Default)]
+pub struct Telemetry(Arc<DashMap<String, NodeTelemetry>>);
+
+impl Telemetry {
+    /// Returns the most recent report from the proxy with `id`.
+    pub fn get(&self, id: &str) -> Option<NodeTelemetry> {
+        self.0.get(id).map(|entry| entry.clone())
+    }
+
+    /// Returns the most recent report of every proxy.
+    pub fn nodes(&self) -> HashMap<String, NodeTelemetry> {
+        self.0
+            .iter()
+            .map(|entry| (entry.key().clone(), entry.value().clone()))
+            .collect()
+    }
+
+    pub(crate) fn record(&self, report: Report) {
+        let node = NodeTelemetry {
+            report,
+            received_at: Instant::now(),
+        };
+        let id = node.report.id.clone();
+
+        metrics::PROXY_ACTIVE_SESSIONS
+            .with_label_values(&[&*id])
+            .set(node.report.active_sessions as i64);
+        metrics::PROXY_PACKETS_PER_SECOND
+            .with_label_values(&[&*id])
+            .set(node.packets_per_second());
+        metrics::PROXY_PACKETS_DROPPED
+            .with_label_values(&[&*id])
+            .inc_by(node.report.packets_dropped);
+
+        self.0.insert(id, node);
+    }
+
+    pub(crate) fn remove(&self, id: &str) {
+        let _ = metrics::PROXY_ACTIVE_SESSIONS.remove_label_values(&[id]);
+        let _ = metrics::PROXY_PACKETS_PER_SECOND.remove_label_values(&[id]);
+        let _ = metrics::PROXY_PACKETS_DROPPED.remove_label_values(&[id]);
+        self.0.remove(id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn report() {
+        let previous = Snapshot {
+            active_sessions: 10,
+            packets_read: 100,
+            packets_written: 50,
+            bytes_read: 1000,
+            bytes_written: 500,
+            packets_dropped: 1,
+        };
+        let current = Snapshot {
+            active_sessions: 4,
+            packets_read: 300,
+            packets_written: 150,
+            bytes_read: 3000,
+            bytes_written: 1500,
+            packets_dropped: 3,
+        };
+
+        let report = current.report("proxy", &previous, Duration::from_secs(2));
+        assert_eq!(
+            Report {
+                id: "proxy".into(),
+                interval_ms: 2000,
+                active_sessions: 4,
+                packets_read: 200,
+                packets_written: 100,
+                bytes_read: 2000,
+                bytes_written: 1000,
+                packets_dropped: 2,
+            },
+            report
+        );
+
+        let telemetry = Telemetry::default();
+        telemetry.record(report);
+        assert_eq!(100.0, telemetry.get("proxy").unwrap().packets_per_second());
+        assert_eq!(1, telemetry.nodes().len());
+
+        telemetry.remove("proxy");
+        assert!(telemetry.get("proxy").is_none());
+    }
+}
@@ -0,0 +1,74 @@
+/*
+ * Copyright 2023 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::sync::Arc;
+
+use tokio::time::{timeout, Duration};
+
+use quilkin::test_utils::{available_addr, create_socket, TestHelper};
+
+#[tokio::test]
+async fn address_discovery() {
+    let mut t = TestHelper::default();
+    let echo = t.run_echo_server().await;
+
+    let local_addr = available_addr().await;
+    let server_proxy = quilkin::cli::Proxy {
+        port: local_addr.port(),
+        to: vec![echo.to_socket_addr().unwrap()],
+        ..<_>::default()
+    };
+    let server_config = Arc::new(
+        quilkin::Config::from_reader(
+            "
+address_discovery:
+  prefix: V0hPQU1J # base64 for `WHOAMI`
+"
+            .as_bytes(),
+        )
+        .unwrap(),
+    );
+
+    t.run_server(server_config, server_proxy, None);
+
+    let socket = create_socket().await;
+    let client_addr = socket.local_addr().unwrap();
+    let request = [b"WHOAMI".as_slice(), &[0; 19]].concat();
+    socket.send_to(&request, &local_addr).await.unwrap();
+
+    let mut buf = vec![0; 64];
+    let (size, _) = timeout(Duration::from_secs(5), socket.recv_from(&mut buf))
+        .await
+        .unwrap()
+        .unwrap();
+
+    let response = &buf[..size];
+    assert_eq!(b"WHOAMI", &response[..6]);
+    assert_eq!(4, response[6]);
+    assert_eq!(
+        client_addr.port(),
+        u16::from_be_bytes([response[7], response[8]])
+    );
+    assert_eq!([127, 0, 0, 1], response[9..13]);
+
+    // Other packets are still routed.
+    socket.send_to(b"hello", &local_addr).await.unwrap();
+    let (size, _) = timeout(Duration::from_secs(5), socket.recv_from(&mut buf))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(b"hello", &buf[..size]);
+}
@@ -14,6 +14,8 @@
  *  limitations under the License.
  */
 
+#![cfg(feature = "filter-compress")]
+
 use tokio::time::{timeout, Duration};
 
 use quilkin::test_utils::available_addr;
@@ -14,6 +14,8 @@
  * limitations under the License.
  */
 
+#![cfg(feature = "filter-concatenate-bytes")]
+
 use std::net::Ipv4Addr;
 
 use tokio::time::{timeout, Duration};
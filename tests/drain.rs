@@ -0,0 +1,77 @@
+/*
+ * Copyright 2023 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::sync::Arc;
+
+use tokio::{
+    sync::watch,
+    time::{timeout, Duration},
+};
+
+use quilkin::{
+    endpoint::Endpoint,
+    test_utils::{available_addr, TestHelper},
+};
+
+#[tokio::test]
+async fn forwards_existing_sessions_while_draining() {
+    let mut t = TestHelper::default();
+    let echo = t.run_echo_server().await;
+
+    let local_addr = available_addr().await;
+    let proxy = quilkin::cli::Proxy {
+        port: local_addr.port(),
+        drain_timeout_secs: Some(60),
+        ..<_>::default()
+    };
+    let config = Arc::new(quilkin::Config::default());
+    config
+        .clusters
+        .modify(|clusters| clusters.insert_default(vec![Endpoint::new(echo)]));
+
+    let (shutdown_tx, shutdown_rx) = watch::channel(());
+    tokio::spawn(async move { proxy.run(config, shutdown_rx).await });
+
+    let (mut existing_rx, existing) = t.open_socket_and_recv_multiple_packets().await;
+    existing.send_to(b"hello", &local_addr).await.unwrap();
+    assert_eq!(
+        "hello",
+        timeout(Duration::from_secs(5), existing_rx.recv())
+            .await
+            .unwrap()
+            .unwrap()
+    );
+
+    shutdown_tx.send(()).unwrap();
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    // The existing session is still forwarded while the proxy drains...
+    existing.send_to(b"draining", &local_addr).await.unwrap();
+    assert_eq!(
+        "draining",
+        timeout(Duration::from_secs(5), existing_rx.recv())
+            .await
+            .unwrap()
+            .unwrap()
+    );
+
+    // ...while new sessions aren't started.
+    let (mut new_rx, new) = t.open_socket_and_recv_multiple_packets().await;
+    new.send_to(b"hello", &local_addr).await.unwrap();
+    assert!(timeout(Duration::from_millis(500), new_rx.recv())
+        .await
+        .is_err());
+}
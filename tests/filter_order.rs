@@ -14,6 +14,8 @@
  *  limitations under the License.
  */
 
+#![cfg(all(feature = "filter-compress", feature = "filter-concatenate-bytes"))]
+
 ///! Complex integration tests that incorporate multiple elements
 use std::{net::Ipv4Addr, str::from_utf8};
 
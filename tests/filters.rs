@@ -14,6 +14,8 @@
  * limitations under the License.
  */
 
+#![cfg(feature = "filter-debug")]
+
 use std::{
     net::{IpAddr, Ipv4Addr, SocketAddr},
     time::Duration,
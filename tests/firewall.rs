@@ -14,6 +14,8 @@
  * limitations under the License.
  */
 
+#![cfg(feature = "filter-firewall")]
+
 use std::net::SocketAddr;
 
 use tokio::{
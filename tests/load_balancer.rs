@@ -14,6 +14,8 @@
  * limitations under the License.
  */
 
+#![cfg(feature = "filter-load-balancer")]
+
 use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 use std::sync::{Arc, Mutex};
 use std::time::Duration;
@@ -14,6 +14,8 @@
  * limitations under the License.
  */
 
+#![cfg(feature = "filter-local-rate-limit")]
+
 use std::time::Duration;
 
 use tokio::time::timeout;
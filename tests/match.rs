@@ -14,6 +14,12 @@
  *  limitations under the License.
  */
 
+#![cfg(all(
+    feature = "filter-capture",
+    feature = "filter-match",
+    feature = "filter-concatenate-bytes"
+))]
+
 use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 
 use tokio::time::{timeout, Duration};
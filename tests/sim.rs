@@ -0,0 +1,83 @@
+/*
+ * Copyright 2023 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+#![cfg(all(feature = "sim", feature = "filter-local-rate-limit"))]
+
+use std::time::Duration;
+
+use quilkin::sim::{Receiver, Scenario, Simulation, Upstream};
+
+fn config(yaml: &str) -> quilkin::Config {
+    quilkin::Config::from_reader(yaml.as_bytes()).unwrap()
+}
+
+#[test]
+fn echo() {
+    let trace = Simulation::new().unwrap().run(
+        Scenario::new(quilkin::Config::default())
+            .send(Duration::ZERO, 0, "hello")
+            .send(Duration::from_secs(90), 1, "world")
+            .run_for(Duration::from_secs(120)),
+    );
+
+    let received = |receiver| {
+        trace
+            .events
+            .iter()
+            .filter(|event| event.receiver == receiver)
+            .map(|event| event.payload.clone())
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(
+        vec![b"hello".to_vec(), b"world".to_vec()],
+        received(Receiver::Upstream)
+    );
+    assert_eq!(vec![b"hello".to_vec()], received(Receiver::Client(0)));
+    assert_eq!(vec![b"world".to_vec()], received(Receiver::Client(1)));
+
+    let world = trace.client(1).next().unwrap();
+    assert!(world.at >= Duration::from_secs(90), "{:?}", world.at);
+}
+
+#[test]
+fn rate_limit_window() {
+    let config = config(
+        "
+version: v1alpha1
+filters:
+  - name: quilkin.filters.local_rate_limit.v1alpha1.LocalRateLimit
+    config:
+      max_packets: 1
+      period: 1
+",
+    );
+
+    let trace = Simulation::new().unwrap().run(
+        Scenario::new(config)
+            .upstream(Upstream::Silent)
+            .send(Duration::ZERO, 0, "first")
+            .send(Duration::from_millis(100), 0, "limited")
+            .send(Duration::from_millis(2500), 0, "second")
+            .run_for(Duration::from_secs(5)),
+    );
+
+    let upstream = trace.upstream().collect::<Vec<_>>();
+    assert_eq!(2, upstream.len(), "{trace:?}");
+    assert_eq!(b"first".to_vec(), upstream[0].payload);
+    assert_eq!(b"second".to_vec(), upstream[1].payload);
+    assert!(upstream[1].at >= Duration::from_millis(2500));
+    assert_eq!(0, trace.client(0).count());
+}
@@ -14,6 +14,8 @@
  *  limitations under the License.
  */
 
+#![cfg(all(feature = "filter-capture", feature = "filter-token-router"))]
+
 use std::net::{Ipv4Addr, SocketAddr};
 
 use tokio::time::{timeout, Duration};
@@ -0,0 +1,101 @@
+/*
+ * Copyright 2023 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::sync::Arc;
+
+use tokio::time::{timeout, Duration};
+
+use quilkin::{
+    endpoint::Endpoint,
+    test_utils::{available_addr, TestHelper},
+};
+
+#[tokio::test]
+async fn respond() {
+    let mut t = TestHelper::default();
+
+    let local_addr = available_addr().await;
+    let server_proxy = quilkin::cli::Proxy {
+        port: local_addr.port(),
+        ..<_>::default()
+    };
+    let server_config = Arc::new(
+        quilkin::Config::from_reader(
+            "
+unrouted:
+  action: respond
+  payload: bm8gcm91dGU= # base64 for `no route`
+"
+            .as_bytes(),
+        )
+        .unwrap(),
+    );
+
+    t.run_server(server_config, server_proxy, None);
+
+    let (mut recv_chan, socket) = t.open_socket_and_recv_multiple_packets().await;
+    // Packets shorter than the response aren't answered.
+    socket.send_to(b"hi", &local_addr).await.unwrap();
+    socket.send_to(b"hello world", &local_addr).await.unwrap();
+    let value = timeout(Duration::from_secs(5), recv_chan.recv())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!("no route", value);
+    assert!(timeout(Duration::from_millis(200), recv_chan.recv())
+        .await
+        .is_err());
+}
+
+#[tokio::test]
+async fn buffer() {
+    let mut t = TestHelper::default();
+    let echo = t.run_echo_server().await;
+
+    let local_addr = available_addr().await;
+    let server_proxy = quilkin::cli::Proxy {
+        port: local_addr.port(),
+        ..<_>::default()
+    };
+    let server_config = Arc::new(
+        quilkin::Config::from_reader(
+            "
+unrouted:
+  action: buffer
+  timeout_ms: 5000
+"
+            .as_bytes(),
+        )
+        .unwrap(),
+    );
+
+    t.run_server(server_config.clone(), server_proxy, None);
+
+    let (mut recv_chan, socket) = t.open_socket_and_recv_multiple_packets().await;
+    socket.send_to(b"hello", &local_addr).await.unwrap();
+
+    // Give the packet time to reach the buffer before the endpoint arrives.
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    server_config
+        .clusters
+        .modify(|clusters| clusters.insert_default(vec![Endpoint::new(echo.clone())]));
+
+    let value = timeout(Duration::from_secs(5), recv_chan.recv())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!("hello", value);
+}